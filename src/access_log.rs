@@ -0,0 +1,511 @@
+//! Access log: one line per request in a file, for hosts without a log
+//! shipper.
+//!
+//! With `SEMEMBED_ACCESS_LOG` set, every request is written there once its
+//! response is ready: time, request id, key (the tenant id), route, status,
+//! inputs, tokens, duration and W3C trace id, in the `common` or `json`
+//! format of `SEMEMBED_ACCESS_LOG_FORMAT`. Lines go through a bounded channel
+//! to a writer thread, so a request never waits for the disk: when the
+//! channel is full the line is dropped and counted, and write errors are only
+//! logged.
+//! The writer rotates the file daily, hourly or past
+//! `SEMEMBED_ACCESS_LOG_MAX_BYTES`, keeping `SEMEMBED_ACCESS_LOG_KEEP`
+//! rotated files (`access.log.1` the newest).
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, OnceLock};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use axum::{
+    extract::{MatchedPath, Request, State},
+    http::{HeaderMap, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use prometheus::{CounterVec, Opts, Registry};
+use serde::Serialize;
+use tracing::{error, warn};
+
+use crate::queue::Tenant;
+use crate::trace_context::TraceContext;
+use crate::usage::Day;
+use crate::AppState;
+
+// Lines waiting for the writer before new ones are dropped
+const CHANNEL_CAPACITY: usize = 8192;
+
+/// How each line is written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// Space-separated, in the spirit of the combined log format.
+    Common,
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "common" => Ok(Format::Common),
+            "json" => Ok(Format::Json),
+            other => anyhow::bail!("unknown access log format {:?} (expected common or json)", other),
+        }
+    }
+}
+
+/// When the file is rotated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rotation {
+    Daily,
+    Hourly,
+    /// Once it would grow past `SEMEMBED_ACCESS_LOG_MAX_BYTES`.
+    Size,
+    Never,
+}
+
+impl Rotation {
+    // The period `secs` since the epoch falls in; the file rotates when it
+    // changes
+    fn period(self, secs: u64) -> u64 {
+        match self {
+            Rotation::Daily => secs / 86_400,
+            Rotation::Hourly => secs / 3_600,
+            Rotation::Size | Rotation::Never => 0,
+        }
+    }
+}
+
+impl FromStr for Rotation {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "daily" => Ok(Rotation::Daily),
+            "hourly" => Ok(Rotation::Hourly),
+            "size" => Ok(Rotation::Size),
+            "never" => Ok(Rotation::Never),
+            other => anyhow::bail!("unknown access log rotation {:?} (expected daily, hourly, size or never)", other),
+        }
+    }
+}
+
+/// Where and how the access log is written.
+#[derive(Debug, Clone)]
+pub struct AccessLogConfig {
+    pub path: PathBuf,
+    pub format: Format,
+    pub rotation: Rotation,
+    /// Size past which the file rotates, with `Rotation::Size`.
+    pub max_bytes: u64,
+    /// Rotated files kept.
+    pub keep: usize,
+}
+
+/// What a handler learned about a request, for its access log line.
+#[derive(Debug, Default)]
+pub(crate) struct AccessEntry {
+    inputs: AtomicUsize,
+    tokens: AtomicUsize,
+}
+
+impl AccessEntry {
+    /// Count inputs embedded and their tokens.
+    pub fn add(&self, inputs: usize, tokens: usize) {
+        self.inputs.fetch_add(inputs, Ordering::Relaxed);
+        self.tokens.fetch_add(tokens, Ordering::Relaxed);
+    }
+}
+
+#[derive(Serialize)]
+struct Line<'a> {
+    timestamp: String,
+    request_id: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    trace_id: Option<&'a str>,
+    key: &'a str,
+    method: &'a str,
+    route: &'a str,
+    status: u16,
+    inputs: usize,
+    tokens: usize,
+    duration_ms: f64,
+}
+
+impl Line<'_> {
+    fn render(&self, format: Format) -> String {
+        match format {
+            Format::Common => format!(
+                "{} [{}] \"{} {}\" {} {} {} {:.1}ms {} {}\n",
+                self.key,
+                self.timestamp,
+                self.method,
+                self.route,
+                self.status,
+                self.inputs,
+                self.tokens,
+                self.duration_ms,
+                self.request_id,
+                self.trace_id.unwrap_or("-")
+            ),
+            Format::Json => {
+                let mut line = serde_json::to_string(self).unwrap_or_default();
+                line.push('\n');
+                line
+            }
+        }
+    }
+}
+
+/// The sending half of the access log; the writer thread owns the file.
+pub(crate) struct AccessLog {
+    format: Format,
+    lines: SyncSender<String>,
+    outcomes: CounterVec,
+}
+
+impl AccessLog {
+    /// Open the file and start its writer thread.
+    pub fn start(config: AccessLogConfig, registry: &Registry) -> anyhow::Result<Self> {
+        let outcomes = CounterVec::new(
+            Opts::new(
+                "semembed_access_log_lines_total",
+                "Access log lines by outcome (written, dropped with the writer behind, or failed)",
+            ),
+            &["outcome"],
+        )?;
+        registry.register(Box::new(outcomes.clone()))?;
+
+        let writer = RotatingFile::open(&config)?;
+        let (lines, received) = mpsc::sync_channel(CHANNEL_CAPACITY);
+        let thread_outcomes = outcomes.clone();
+        std::thread::Builder::new()
+            .name("access-log".to_string())
+            .spawn(move || write_lines(writer, received, thread_outcomes))?;
+        Ok(Self {
+            format: config.format,
+            lines,
+            outcomes,
+        })
+    }
+
+    fn send(&self, line: String) {
+        match self.lines.try_send(line) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => self.outcomes.with_label_values(&["dropped"]).inc(),
+            Err(TrySendError::Disconnected(_)) => self.outcomes.with_label_values(&["failed"]).inc(),
+        }
+    }
+}
+
+// The log file and when it next rotates
+struct RotatingFile {
+    path: PathBuf,
+    rotation: Rotation,
+    max_bytes: u64,
+    keep: usize,
+    file: BufWriter<File>,
+    written: u64,
+    period: u64,
+}
+
+impl RotatingFile {
+    fn open(config: &AccessLogConfig) -> anyhow::Result<Self> {
+        if let Some(dir) = config.path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)?;
+        }
+        let file = append(&config.path)?;
+        let metadata = file.metadata()?;
+        // A file left from an earlier period rotates with the first line
+        let modified = metadata.modified().ok().and_then(|time| time.duration_since(UNIX_EPOCH).ok());
+        Ok(Self {
+            path: config.path.clone(),
+            rotation: config.rotation,
+            max_bytes: config.max_bytes,
+            keep: config.keep,
+            file: BufWriter::new(file),
+            written: metadata.len(),
+            period: config.rotation.period(modified.map_or_else(unix_secs, |since| since.as_secs())),
+        })
+    }
+
+    fn write(&mut self, line: &str) -> std::io::Result<()> {
+        let period = self.rotation.period(unix_secs());
+        let full = self.rotation == Rotation::Size
+            && self.written > 0
+            && self.written + line.len() as u64 > self.max_bytes;
+        if period != self.period || full {
+            self.rotate()?;
+            self.period = period;
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.written += line.len() as u64;
+        Ok(())
+    }
+
+    // access.log.N is dropped, each older file moves up one, and the current
+    // file becomes access.log.1
+    fn rotate(&mut self) -> std::io::Result<()> {
+        self.file.flush()?;
+        if self.keep == 0 {
+            std::fs::remove_file(&self.path)?;
+        } else {
+            for n in (1..self.keep).rev() {
+                let from = numbered(&self.path, n);
+                if from.exists() {
+                    std::fs::rename(&from, numbered(&self.path, n + 1))?;
+                }
+            }
+            std::fs::rename(&self.path, numbered(&self.path, 1))?;
+        }
+        self.file = BufWriter::new(append(&self.path)?);
+        self.written = 0;
+        Ok(())
+    }
+}
+
+fn append(path: &Path) -> std::io::Result<File> {
+    File::options().create(true).append(true).open(path)
+}
+
+fn numbered(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+fn unix_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs())
+}
+
+// The writer thread: write what has arrived, flushing whenever the channel
+// runs dry, until the server's side is dropped
+fn write_lines(mut file: RotatingFile, lines: Receiver<String>, outcomes: CounterVec) {
+    let mut failing = false;
+    while let Ok(first) = lines.recv() {
+        let mut result = Ok(());
+        let mut count = 0;
+        for line in std::iter::once(first).chain(lines.try_iter()) {
+            count += 1;
+            if result.is_ok() {
+                result = file.write(&line);
+            }
+        }
+        let result = result.and_then(|()| file.file.flush());
+        match result {
+            Ok(()) => {
+                outcomes.with_label_values(&["written"]).inc_by(count as f64);
+                if failing {
+                    warn!("Writing the access log {} works again", file.path.display());
+                    failing = false;
+                }
+            }
+            Err(e) => {
+                outcomes.with_label_values(&["failed"]).inc_by(count as f64);
+                // Once per failure streak, not once per line
+                if !failing {
+                    error!("Couldn't write the access log {}: {}", file.path.display(), e);
+                    failing = true;
+                }
+            }
+        }
+    }
+}
+
+// RFC 3339 in UTC, with milliseconds
+pub(crate) fn timestamp(time: SystemTime) -> String {
+    let millis = time.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_millis() as u64);
+    let secs_of_day = (millis / 1000) % 86_400;
+    format!(
+        "{}T{:02}:{:02}:{:02}.{:03}Z",
+        Day::of(time),
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60,
+        millis % 1000
+    )
+}
+
+/// The id a request is logged under, in its extensions while the access log
+/// or the audit log is on.
+#[derive(Debug, Clone)]
+pub(crate) struct RequestId(pub String);
+
+// splitmix64 state, for request ids the client didn't send
+static SEED: OnceLock<AtomicU64> = OnceLock::new();
+
+fn new_request_id() -> String {
+    let seed = SEED.get_or_init(|| {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_nanos() as u64);
+        AtomicU64::new(now ^ u64::from(std::process::id()))
+    });
+    let mut z = seed.fetch_add(0x9e37_79b9_7f4a_7c15, Ordering::Relaxed).wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^= z >> 31;
+    format!("{:016x}", z)
+}
+
+/// The client's `X-Request-Id`, if it is one that can be logged as it is.
+pub(crate) fn client_request_id(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get("x-request-id")
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= 128 && id.bytes().all(|byte| byte.is_ascii_graphic()))
+        .map(str::to_string)
+}
+
+/// Middleware writing each request's access log line. The request id comes
+/// from `X-Request-Id` or is generated, and is returned in the same header;
+/// with only the audit log on, requests just get their id.
+pub(crate) async fn log_request(State(state): State<Arc<AppState>>, mut req: Request, next: Next) -> Response {
+    if state.access_log.is_none() && state.audit.is_none() {
+        return next.run(req).await;
+    }
+    let started = Instant::now();
+    let time = SystemTime::now();
+    let request_id = client_request_id(req.headers()).unwrap_or_else(new_request_id);
+    let method = req.method().to_string();
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or_else(|| req.uri().path().to_string(), |path| path.as_str().to_string());
+    // As Scheduling identifies it, but for every request
+    let tenant = Tenant::from_headers(req.headers());
+    let trace_id = req.extensions().get::<TraceContext>().map(|context| context.trace_id.clone());
+    let entry = Arc::new(AccessEntry::default());
+    req.extensions_mut().insert(entry.clone());
+    req.extensions_mut().insert(RequestId(request_id.clone()));
+
+    let mut response = next.run(req).await;
+    if let Ok(value) = HeaderValue::from_str(&request_id) {
+        response.headers_mut().insert("x-request-id", value);
+    }
+    let Some(log) = &state.access_log else {
+        return response;
+    };
+    let line = Line {
+        timestamp: timestamp(time),
+        request_id: &request_id,
+        trace_id: trace_id.as_deref(),
+        key: tenant.as_str(),
+        method: &method,
+        route: &route,
+        status: response.status().as_u16(),
+        inputs: entry.inputs.load(Ordering::Relaxed),
+        tokens: entry.tokens.load(Ordering::Relaxed),
+        duration_ms: started.elapsed().as_secs_f64() * 1000.0,
+    };
+    log.send(line.render(log.format));
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(dir: &Path, rotation: Rotation, max_bytes: u64, keep: usize) -> AccessLogConfig {
+        AccessLogConfig {
+            path: dir.join("access.log"),
+            format: Format::Common,
+            rotation,
+            max_bytes,
+            keep,
+        }
+    }
+
+    fn read(path: &Path) -> String {
+        std::fs::read_to_string(path).unwrap_or_default()
+    }
+
+    #[test]
+    fn files_rotate_past_the_size_and_only_the_newest_are_kept() {
+        let dir = std::env::temp_dir().join(format!("semembed-access-log-{}", std::process::id()));
+        let config = config(&dir, Rotation::Size, 10, 2);
+        let mut file = RotatingFile::open(&config).unwrap();
+        for line in ["line 1\n", "line 2\n", "line 3\n", "line 4\n"] {
+            file.write(line).unwrap();
+        }
+        file.file.flush().unwrap();
+
+        assert_eq!(read(&config.path), "line 4\n");
+        assert_eq!(read(&numbered(&config.path, 1)), "line 3\n");
+        assert_eq!(read(&numbered(&config.path, 2)), "line 2\n");
+        assert!(!numbered(&config.path, 3).exists());
+
+        // A line longer than the limit still goes into a file of its own
+        file.write("a line well over ten bytes\n").unwrap();
+        file.file.flush().unwrap();
+        assert_eq!(read(&config.path), "a line well over ten bytes\n");
+        assert_eq!(read(&numbered(&config.path, 1)), "line 4\n");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reopening_counts_what_the_file_already_holds() {
+        let dir = std::env::temp_dir().join(format!("semembed-access-log-reopen-{}", std::process::id()));
+        let config = config(&dir, Rotation::Size, 10, 0);
+        RotatingFile::open(&config).unwrap().write("line 1\n").unwrap();
+        let mut file = RotatingFile::open(&config).unwrap();
+        assert_eq!(file.written, 7);
+        // Without rotated files to keep, the full one is removed
+        file.write("line 2\n").unwrap();
+        file.file.flush().unwrap();
+        assert_eq!(read(&config.path), "line 2\n");
+        assert!(!numbered(&config.path, 1).exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn periods_follow_the_rotation() {
+        assert_eq!(Rotation::Daily.period(86_399), 0);
+        assert_eq!(Rotation::Daily.period(86_400), 1);
+        assert_eq!(Rotation::Hourly.period(7_200), 2);
+        assert_eq!(Rotation::Size.period(7_200), 0);
+        assert_eq!(" HOURLY ".parse::<Rotation>().unwrap(), Rotation::Hourly);
+        assert!("weekly".parse::<Rotation>().is_err());
+        assert!("xml".parse::<Format>().is_err());
+    }
+
+    #[test]
+    fn lines_render_in_both_formats() {
+        let line = Line {
+            timestamp: timestamp(UNIX_EPOCH + std::time::Duration::from_millis(1_711_929_599_250)),
+            request_id: "9c1e5a0f7b3d2e81",
+            trace_id: None,
+            key: "anonymous",
+            method: "POST",
+            route: "/v1/embeddings",
+            status: 200,
+            inputs: 3,
+            tokens: 42,
+            duration_ms: 18.74,
+        };
+        assert_eq!(
+            line.render(Format::Common),
+            "anonymous [2024-03-31T23:59:59.250Z] \"POST /v1/embeddings\" 200 3 42 18.7ms 9c1e5a0f7b3d2e81 -\n"
+        );
+        let json: serde_json::Value = serde_json::from_str(&line.render(Format::Json)).unwrap();
+        assert_eq!(json["timestamp"], "2024-03-31T23:59:59.250Z");
+        assert_eq!(json["tokens"], 42);
+        assert!(json.get("trace_id").is_none());
+    }
+
+    #[test]
+    fn only_loggable_request_ids_are_kept() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-request-id", HeaderValue::from_static("req-42"));
+        assert_eq!(client_request_id(&headers).as_deref(), Some("req-42"));
+        headers.insert("x-request-id", HeaderValue::from_static("two words"));
+        assert_eq!(client_request_id(&headers), None);
+        headers.insert("x-request-id", HeaderValue::from_str(&"x".repeat(129)).unwrap());
+        assert_eq!(client_request_id(&headers), None);
+        assert_ne!(new_request_id(), new_request_id());
+        assert_eq!(new_request_id().len(), 16);
+    }
+}
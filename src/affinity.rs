@@ -0,0 +1,315 @@
+//! CPU pinning for inference: `SEMEMBED_CPU_AFFINITY` core lists, or one NUMA
+//! node per replica with `SEMEMBED_NUMA_AWARE`. NUMA nodes are read from
+//! sysfs, so only core lists work outside Linux, where threads can be pinned.
+//!
+//! A replica is pinned through ONNX Runtime's global thread pool: its intra-op
+//! threads are spawned here and each pinned to one of the replica's cores.
+//! The threads that call into the model stay unpinned; they hand each
+//! operator to the pool and take a share of it.
+
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread::JoinHandle;
+
+use anyhow::{bail, Context};
+use core_affinity::CoreId;
+use ort::environment::{GlobalThreadPoolOptions, ThreadManager};
+use tracing::{info, warn};
+
+use crate::env_flag;
+
+/// A set of CPU cores, written as a list of cores and ranges (`0-7,16-23`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CpuSet(Vec<usize>);
+
+impl CpuSet {
+    pub fn cores(&self) -> &[usize] {
+        &self.0
+    }
+
+    // Split into `parts` contiguous sets of nearly equal size
+    fn split(&self, parts: usize) -> Vec<CpuSet> {
+        let parts = parts.clamp(1, self.0.len().max(1));
+        let (size, extra) = (self.0.len() / parts, self.0.len() % parts);
+        let mut cores = self.0.iter().copied();
+        (0..parts)
+            .map(|part| CpuSet(cores.by_ref().take(size + usize::from(part < extra)).collect()))
+            .collect()
+    }
+}
+
+impl FromStr for CpuSet {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let mut cores = Vec::new();
+        for part in s.split(',').map(str::trim).filter(|part| !part.is_empty()) {
+            let parse = |core: &str| {
+                core.trim()
+                    .parse::<usize>()
+                    .with_context(|| format!("invalid core {:?} in {:?}", core.trim(), s))
+            };
+            match part.split_once('-') {
+                Some((first, last)) => {
+                    let (first, last) = (parse(first)?, parse(last)?);
+                    if first > last {
+                        bail!("invalid core range {:?}: {} is after {}", part, first, last);
+                    }
+                    cores.extend(first..=last);
+                }
+                None => cores.push(parse(part)?),
+            }
+        }
+        if cores.is_empty() {
+            bail!("empty core list");
+        }
+        cores.sort_unstable();
+        cores.dedup();
+        Ok(Self(cores))
+    }
+}
+
+impl fmt::Display for CpuSet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut ranges: Vec<(usize, usize)> = Vec::new();
+        for &core in &self.0 {
+            match ranges.last_mut() {
+                Some((_, last)) if *last + 1 == core => *last = core,
+                _ => ranges.push((core, core)),
+            }
+        }
+        let ranges: Vec<String> = ranges
+            .into_iter()
+            .map(|(first, last)| if first == last { first.to_string() } else { format!("{}-{}", first, last) })
+            .collect();
+        write!(f, "{}", ranges.join(","))
+    }
+}
+
+/// Where one embedder replica runs.
+#[derive(Debug, Clone)]
+pub struct Placement {
+    pub replica: usize,
+    pub cores: CpuSet,
+    /// The NUMA node the cores belong to, with `SEMEMBED_NUMA_AWARE`.
+    pub numa_node: Option<usize>,
+}
+
+/// `;`-separated core lists, one per replica, as in `SEMEMBED_CPU_AFFINITY`.
+pub fn parse_affinity(value: &str) -> anyhow::Result<Vec<CpuSet>> {
+    value.split(';').map(str::parse).collect()
+}
+
+/// The placement of each of `replicas` embedder replicas from the
+/// environment, or an empty list when nothing is pinned.
+pub fn placements(replicas: usize) -> anyhow::Result<Vec<Placement>> {
+    let affinity = std::env::var("SEMEMBED_CPU_AFFINITY").ok().filter(|value| !value.trim().is_empty());
+    let numa_aware = env_flag("SEMEMBED_NUMA_AWARE")?;
+    if affinity.is_none() && !numa_aware {
+        return Ok(Vec::new());
+    }
+    if affinity.is_none() && !cfg!(target_os = "linux") {
+        warn!("SEMEMBED_NUMA_AWARE reads the NUMA topology from sysfs, so it is only supported on Linux; ignored");
+        return Ok(Vec::new());
+    }
+    let allowed = match allowed() {
+        Ok(allowed) => allowed,
+        Err(e) => {
+            warn!("{:#}; SEMEMBED_CPU_AFFINITY and SEMEMBED_NUMA_AWARE are ignored", e);
+            return Ok(Vec::new());
+        }
+    };
+    let placements = match affinity {
+        Some(value) => {
+            let sets = parse_affinity(&value)?;
+            if sets.len() > replicas {
+                bail!(
+                    "SEMEMBED_CPU_AFFINITY has {} core lists, but semembed runs {} embedder replica(s)",
+                    sets.len(),
+                    replicas
+                );
+            }
+            // A single list applies to every replica
+            (0..replicas)
+                .map(|replica| Placement {
+                    replica,
+                    cores: sets.get(replica).unwrap_or(&sets[0]).clone(),
+                    numa_node: None,
+                })
+                .collect::<Vec<_>>()
+        }
+        None => {
+            // Only the cores this process may use, e.g. within a container's cpuset
+            let nodes: Vec<(usize, CpuSet)> = numa_nodes()?
+                .into_iter()
+                .filter_map(|(node, cores)| {
+                    let cores: Vec<usize> =
+                        cores.0.into_iter().filter(|core| allowed.cores().contains(core)).collect();
+                    (!cores.is_empty()).then_some((node, CpuSet(cores)))
+                })
+                .collect();
+            if nodes.len() < 2 {
+                info!("SEMEMBED_NUMA_AWARE: one NUMA node detected, leaving inference unpinned");
+                return Ok(Vec::new());
+            }
+            // Replica i goes to node i mod n; replicas sharing a node split its cores
+            let mut placements = Vec::with_capacity(replicas);
+            let mut by_node: Vec<Vec<usize>> = vec![Vec::new(); nodes.len()];
+            for replica in 0..replicas {
+                by_node[replica % nodes.len()].push(replica);
+            }
+            for ((node, cores), replicas) in nodes.iter().zip(by_node) {
+                for (&replica, cores) in replicas.iter().zip(cores.split(replicas.len())) {
+                    placements.push(Placement {
+                        replica,
+                        cores,
+                        numa_node: Some(*node),
+                    });
+                }
+            }
+            placements.sort_by_key(|placement| placement.replica);
+            placements
+        }
+    };
+
+    for placement in &placements {
+        if let Some(core) = placement.cores.cores().iter().find(|core| !allowed.cores().contains(core)) {
+            bail!(
+                "core {} of replica {} ({}) is not available to this process (allowed: {})",
+                core,
+                placement.replica,
+                placement.cores,
+                allowed
+            );
+        }
+    }
+    for placement in &placements {
+        match placement.numa_node {
+            Some(node) => info!(
+                "Embedder replica {} pinned to cores {} (NUMA node {})",
+                placement.replica, placement.cores, node
+            ),
+            None => info!("Embedder replica {} pinned to cores {}", placement.replica, placement.cores),
+        }
+    }
+    Ok(placements)
+}
+
+// NUMA nodes with CPUs, from sysfs
+fn numa_nodes() -> anyhow::Result<Vec<(usize, CpuSet)>> {
+    let mut nodes = Vec::new();
+    let entries = match std::fs::read_dir("/sys/devices/system/node") {
+        Ok(entries) => entries,
+        // No NUMA support in the kernel: a single node
+        Err(_) => return Ok(nodes),
+    };
+    for entry in entries.flatten() {
+        let name = entry.file_name();
+        let Some(node) = name.to_str().and_then(|name| name.strip_prefix("node")?.parse::<usize>().ok()) else {
+            continue;
+        };
+        let cpulist = std::fs::read_to_string(entry.path().join("cpulist"))
+            .with_context(|| format!("failed to read the CPUs of NUMA node {}", node))?;
+        // Memory-only nodes have no CPUs
+        if let Ok(cores) = cpulist.trim().parse::<CpuSet>() {
+            nodes.push((node, cores));
+        }
+    }
+    nodes.sort_by_key(|(node, _)| *node);
+    Ok(nodes)
+}
+
+/// Pin ONNX Runtime's inference threads to `cores`, one thread per core.
+///
+/// Sessions created after this share one global intra-op pool, so it applies
+/// to every model the process loads, re-created ones included. It must run
+/// before the first model is loaded and only takes effect once per process.
+pub fn pin_inference(cores: &CpuSet) -> anyhow::Result<()> {
+    // ONNX Runtime counts the calling thread as one of the pool's threads;
+    // one more gives every core a pinned thread while the caller coordinates
+    let options = GlobalThreadPoolOptions::default()
+        .with_intra_threads(cores.0.len() + 1)?
+        .with_thread_manager(PinnedThreads {
+            cores: cores.0.clone(),
+            next: AtomicUsize::new(0),
+        })?;
+    if !ort::init().with_global_thread_pool(options).commit() {
+        bail!("ONNX Runtime was already initialized");
+    }
+    Ok(())
+}
+
+// Spawns the global pool's threads, each pinned to the next core in turn
+struct PinnedThreads {
+    cores: Vec<usize>,
+    next: AtomicUsize,
+}
+
+impl ThreadManager for PinnedThreads {
+    type Thread = JoinHandle<()>;
+
+    fn create(&self, work: impl FnOnce() + Send + 'static) -> ort::Result<Self::Thread> {
+        let index = self.next.fetch_add(1, Ordering::Relaxed);
+        let core = self.cores[index % self.cores.len()];
+        std::thread::Builder::new()
+            .name(format!("ort-intra-{}", index))
+            .spawn(move || {
+                if !core_affinity::set_for_current(CoreId { id: core }) {
+                    warn!("Failed to pin inference thread {} to core {}", index, core);
+                }
+                work();
+            })
+            .map_err(|e| ort::Error::new(format!("failed to spawn an inference thread: {}", e)))
+    }
+
+    fn join(thread: Self::Thread) -> ort::Result<()> {
+        thread.join().map_err(|_| ort::Error::new("an inference thread panicked"))
+    }
+}
+
+// The cores the current thread may run on
+fn allowed() -> anyhow::Result<CpuSet> {
+    let cores: Vec<usize> = core_affinity::get_core_ids()
+        .context("failed to read the thread's CPU affinity")?
+        .into_iter()
+        .map(|core| core.id)
+        .collect();
+    if cores.is_empty() {
+        bail!("failed to read the thread's CPU affinity");
+    }
+    Ok(CpuSet(cores))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn core_lists_parse_and_print_as_ranges() {
+        let cores: CpuSet = "16-19, 2,0-3,7".parse().unwrap();
+        assert_eq!(cores.cores(), &[0, 1, 2, 3, 7, 16, 17, 18, 19]);
+        assert_eq!(cores.to_string(), "0-3,7,16-19");
+        assert_eq!("5".parse::<CpuSet>().unwrap().to_string(), "5");
+
+        for invalid in ["", " , ", "3-1", "a", "0-x"] {
+            assert!(invalid.parse::<CpuSet>().is_err(), "{:?} parsed", invalid);
+        }
+    }
+
+    #[test]
+    fn lists_are_per_replica() {
+        let sets = parse_affinity("0-3;4-7").unwrap();
+        assert_eq!(sets.iter().map(CpuSet::to_string).collect::<Vec<_>>(), ["0-3", "4-7"]);
+        assert!(parse_affinity("0-3;").is_err());
+    }
+
+    #[test]
+    fn splits_are_contiguous_and_even() {
+        let cores: CpuSet = "0-9".parse().unwrap();
+        let parts: Vec<String> = cores.split(3).iter().map(CpuSet::to_string).collect();
+        assert_eq!(parts, ["0-3", "4-6", "7-9"]);
+        // Never more parts than cores
+        assert_eq!(CpuSet(vec![0, 1]).split(4).len(), 2);
+    }
+}
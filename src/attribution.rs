@@ -0,0 +1,102 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::bail;
+use hmac_sha256::Hash;
+
+/// Longest `user` value kept; longer values are cut at a character boundary.
+pub const MAX_USER_LEN: usize = 128;
+
+/// Label used for users outside the allowlist.
+const OTHER: &str = "other";
+
+/// Truncate a request's `user` field to `MAX_USER_LEN` bytes.
+pub fn truncate_user(user: &str) -> &str {
+    if user.len() <= MAX_USER_LEN {
+        return user;
+    }
+    let mut end = MAX_USER_LEN;
+    while !user.is_char_boundary(end) {
+        end -= 1;
+    }
+    &user[..end]
+}
+
+/// How the OpenAI `user` field becomes a metric label. Raw values would give
+/// every end user their own time series, so they are either hashed into a
+/// fixed number of buckets or matched against an allowlist.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum UserLabels {
+    /// No per-user metrics.
+    #[default]
+    Off,
+    /// `h0`..`h{n-1}`, from a hash of the value.
+    Hashed(u32),
+    /// Allowlisted values as-is, everything else as `other`.
+    Allowlist(HashSet<String>),
+}
+
+impl UserLabels {
+    /// Label for a user, or `None` when per-user metrics are off.
+    pub fn label(&self, user: &str) -> Option<String> {
+        match self {
+            Self::Off => None,
+            Self::Hashed(buckets) => {
+                let hash = Hash::hash(user.as_bytes());
+                let bucket = u32::from_le_bytes([hash[0], hash[1], hash[2], hash[3]]) % buckets;
+                Some(format!("h{}", bucket))
+            }
+            Self::Allowlist(allowed) => Some(if allowed.contains(user) {
+                user.to_string()
+            } else {
+                OTHER.to_string()
+            }),
+        }
+    }
+}
+
+/// Parses `off`, `hash:<buckets>` or `allow:<user>,<user>,...`.
+impl FromStr for UserLabels {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.is_empty() || s.eq_ignore_ascii_case("off") {
+            return Ok(Self::Off);
+        }
+        if let Some(buckets) = s.strip_prefix("hash:") {
+            let buckets: u32 = buckets.trim().parse()?;
+            if buckets == 0 {
+                bail!("user label hash buckets must be at least 1");
+            }
+            return Ok(Self::Hashed(buckets));
+        }
+        if let Some(users) = s.strip_prefix("allow:") {
+            let users: HashSet<String> = users
+                .split(',')
+                .map(str::trim)
+                .filter(|user| !user.is_empty())
+                .map(|user| truncate_user(user).to_string())
+                .collect();
+            if users.is_empty() {
+                bail!("user label allowlist is empty");
+            }
+            return Ok(Self::Allowlist(users));
+        }
+        bail!(
+            "invalid user label mode {:?} (expected \"off\", \"hash:<buckets>\" or \"allow:<user>,...\")",
+            s
+        )
+    }
+}
+
+impl fmt::Display for UserLabels {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Off => write!(f, "off"),
+            Self::Hashed(buckets) => write!(f, "hashed into {} buckets", buckets),
+            Self::Allowlist(users) => write!(f, "allowlist of {} user(s)", users.len()),
+        }
+    }
+}
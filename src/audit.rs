@@ -0,0 +1,454 @@
+//! Audit log: a tamper-evident record of who embedded what.
+//!
+//! With `SEMEMBED_AUDIT_LOG` set, every embedding request that succeeds is
+//! appended to that file as one JSON line: time, request id, key (the tenant
+//! id), model, inputs, tokens, and the SHA-256 of `SEMEMBED_AUDIT_SALT`
+//! followed by each input, never the text itself. Every record carries the
+//! hash of the one before it and its own, so editing, removing or reordering
+//! records breaks the chain, and so does cutting the file short of a head
+//! noted elsewhere; `semembed audit verify <file>` checks it.
+//!
+//! Like access log lines, records go through a bounded channel to a writer
+//! thread: a request never waits for the disk or fails because of it. When
+//! the channel is full the record is dropped and counted, and a write that
+//! fails is logged; either shows up in the file as a gap in the sequence.
+//! `GET /admin/audit` lists recent records by key and time.
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use anyhow::{anyhow, bail, Context};
+use axum::{
+    extract::{rejection::QueryRejection, Query, State},
+    http::StatusCode,
+    Json,
+};
+use hmac_sha256::Hash;
+use prometheus::{CounterVec, Opts, Registry};
+use serde::{Deserialize, Serialize};
+use tracing::{error, warn};
+
+use crate::access_log::timestamp;
+use crate::error::ApiError;
+use crate::extract::Scheduling;
+use crate::usage::Day;
+use crate::AppState;
+
+// Records waiting for the writer before new ones are dropped
+const CHANNEL_CAPACITY: usize = 8192;
+
+// The previous hash of the first record
+const GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+// Records returned by GET /admin/audit without a `limit`, and at most
+const DEFAULT_LIMIT: usize = 100;
+const MAX_LIMIT: usize = 1000;
+
+const USAGE: &str = "\
+Usage: semembed audit verify FILE
+
+Checks that every record of an audit log (SEMEMBED_AUDIT_LOG) is intact and
+chained to the one before it, from the first record on. Prints the number of
+records and the last one's sequence number and hash; compare them with a head
+noted earlier to tell whether records were cut from the end.
+";
+
+/// One line of the audit log.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Record {
+    seq: u64,
+    timestamp: String,
+    request_id: Option<String>,
+    key: String,
+    model: String,
+    inputs: usize,
+    tokens: usize,
+    input_hashes: Vec<String>,
+    prev_hash: String,
+    hash: String,
+}
+
+impl Record {
+    // SHA-256 of the record without its own hash, as serialized in field order
+    fn chain_hash(&self) -> String {
+        #[derive(Serialize)]
+        struct Chained<'a> {
+            seq: u64,
+            timestamp: &'a str,
+            request_id: Option<&'a str>,
+            key: &'a str,
+            model: &'a str,
+            inputs: usize,
+            tokens: usize,
+            input_hashes: &'a [String],
+            prev_hash: &'a str,
+        }
+        let chained = Chained {
+            seq: self.seq,
+            timestamp: &self.timestamp,
+            request_id: self.request_id.as_deref(),
+            key: &self.key,
+            model: &self.model,
+            inputs: self.inputs,
+            tokens: self.tokens,
+            input_hashes: &self.input_hashes,
+            prev_hash: &self.prev_hash,
+        };
+        hex(&Hash::hash(&serde_json::to_vec(&chained).unwrap_or_default()))
+    }
+}
+
+/// A request's inputs, hashed before they are embedded.
+pub(crate) struct AuditedInputs(Vec<String>);
+
+// What a request hands the writer, which numbers and chains it
+struct Entry {
+    time: SystemTime,
+    request_id: Option<String>,
+    key: String,
+    model: String,
+    tokens: usize,
+    input_hashes: Vec<String>,
+}
+
+/// The sending half of the audit log; the writer thread owns the file.
+pub(crate) struct AuditLog {
+    path: PathBuf,
+    salt: String,
+    entries: SyncSender<Entry>,
+    outcomes: CounterVec,
+}
+
+impl AuditLog {
+    /// Open the file, pick up the chain where it ends and start the writer
+    /// thread.
+    pub fn start(path: PathBuf, salt: String, registry: &Registry) -> anyhow::Result<Self> {
+        if salt.is_empty() {
+            bail!("SEMEMBED_AUDIT_SALT must be set with SEMEMBED_AUDIT_LOG");
+        }
+        let outcomes = CounterVec::new(
+            Opts::new(
+                "semembed_audit_records_total",
+                "Audit log records by outcome (written, dropped with the writer behind, or failed)",
+            ),
+            &["outcome"],
+        )?;
+        registry.register(Box::new(outcomes.clone()))?;
+
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)?;
+        }
+        let mut file = File::options()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("couldn't open the audit log {}", path.display()))?;
+        let head = match last_line(&mut file)? {
+            Some(line) => {
+                let last: Record = serde_json::from_str(&line).with_context(|| {
+                    format!(
+                        "the last record of the audit log {} is unreadable; check it with `semembed audit verify`",
+                        path.display()
+                    )
+                })?;
+                Some((last.seq, last.hash))
+            }
+            None => None,
+        };
+        let (entries, received) = mpsc::sync_channel(CHANNEL_CAPACITY);
+        let writer = Writer {
+            path: path.clone(),
+            file: BufWriter::new(file),
+            next_seq: head.as_ref().map_or(0, |(seq, _)| seq + 1),
+            prev_hash: head.map_or_else(|| GENESIS.to_string(), |(_, hash)| hash),
+        };
+        let thread_outcomes = outcomes.clone();
+        std::thread::Builder::new()
+            .name("audit-log".to_string())
+            .spawn(move || writer.run(received, thread_outcomes))?;
+        Ok(Self {
+            path,
+            salt,
+            entries,
+            outcomes,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Hash each input with the salt, for the request's record.
+    pub fn hash_inputs(&self, texts: &[String]) -> AuditedInputs {
+        AuditedInputs(
+            texts
+                .iter()
+                .map(|text| {
+                    let mut hash = Hash::new();
+                    hash.update(self.salt.as_bytes());
+                    hash.update(text.as_bytes());
+                    hex(&hash.finalize())
+                })
+                .collect(),
+        )
+    }
+
+    /// Queue the record of a request that embedded `inputs` with `model`.
+    pub fn record(&self, scheduling: &Scheduling, model: &str, inputs: AuditedInputs, tokens: usize) {
+        let entry = Entry {
+            time: SystemTime::now(),
+            request_id: scheduling.request_id.clone(),
+            key: scheduling.tenant.as_str().to_string(),
+            model: model.to_string(),
+            tokens,
+            input_hashes: inputs.0,
+        };
+        match self.entries.try_send(entry) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => self.outcomes.with_label_values(&["dropped"]).inc(),
+            Err(TrySendError::Disconnected(_)) => self.outcomes.with_label_values(&["failed"]).inc(),
+        }
+    }
+}
+
+// The file and where the chain stands
+struct Writer {
+    path: PathBuf,
+    file: BufWriter<File>,
+    next_seq: u64,
+    prev_hash: String,
+}
+
+impl Writer {
+    fn write(&mut self, entry: Entry) -> std::io::Result<()> {
+        let mut record = Record {
+            seq: self.next_seq,
+            timestamp: timestamp(entry.time),
+            request_id: entry.request_id,
+            key: entry.key,
+            model: entry.model,
+            inputs: entry.input_hashes.len(),
+            tokens: entry.tokens,
+            input_hashes: entry.input_hashes,
+            prev_hash: std::mem::take(&mut self.prev_hash),
+            hash: String::new(),
+        };
+        record.hash = record.chain_hash();
+        // The chain moves on even if the write fails, which leaves a gap
+        self.next_seq += 1;
+        self.prev_hash = record.hash.clone();
+        let mut line = serde_json::to_string(&record).map_err(std::io::Error::other)?;
+        line.push('\n');
+        self.file.write_all(line.as_bytes())
+    }
+
+    // Write what has arrived, flushing whenever the channel runs dry, until
+    // the server's side is dropped
+    fn run(mut self, entries: Receiver<Entry>, outcomes: CounterVec) {
+        let mut failing = false;
+        while let Ok(first) = entries.recv() {
+            let mut result = Ok(());
+            let mut count = 0;
+            for entry in std::iter::once(first).chain(entries.try_iter()) {
+                count += 1;
+                let written = self.write(entry);
+                if result.is_ok() {
+                    result = written;
+                }
+            }
+            match result.and_then(|()| self.file.flush()) {
+                Ok(()) => {
+                    outcomes.with_label_values(&["written"]).inc_by(count as f64);
+                    if failing {
+                        warn!("Writing the audit log {} works again", self.path.display());
+                        failing = false;
+                    }
+                }
+                Err(e) => {
+                    outcomes.with_label_values(&["failed"]).inc_by(count as f64);
+                    // Once per failure streak, not once per record
+                    if !failing {
+                        error!("Couldn't write the audit log {}: {}", self.path.display(), e);
+                        failing = true;
+                    }
+                }
+            }
+        }
+    }
+}
+
+// The last non-empty line of the file, read from the end
+fn last_line(file: &mut File) -> anyhow::Result<Option<String>> {
+    let len = file.metadata()?.len();
+    let mut window: u64 = 64 * 1024;
+    loop {
+        let start = len.saturating_sub(window);
+        file.seek(SeekFrom::Start(start))?;
+        let mut tail = Vec::new();
+        Read::by_ref(file).take(len - start).read_to_end(&mut tail)?;
+        while tail.last() == Some(&b'\n') {
+            tail.pop();
+        }
+        match tail.iter().rposition(|&byte| byte == b'\n') {
+            Some(newline) => return Ok(Some(String::from_utf8_lossy(&tail[newline + 1..]).into_owned())),
+            None if start == 0 => return Ok((!tail.is_empty()).then(|| String::from_utf8_lossy(&tail).into_owned())),
+            None => window *= 4,
+        }
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Run `semembed audit ...` with the arguments that follow the subcommand.
+pub fn run(args: &[String]) -> anyhow::Result<()> {
+    let path = match args {
+        [verify, path] if verify == "verify" => Path::new(path),
+        [] => {
+            print!("{}", USAGE);
+            return Ok(());
+        }
+        [help] if help == "-h" || help == "--help" => {
+            print!("{}", USAGE);
+            return Ok(());
+        }
+        _ => bail!("expected `semembed audit verify FILE`\n\n{}", USAGE),
+    };
+    let file = File::open(path).with_context(|| format!("couldn't open {}", path.display()))?;
+    let mut head: Option<(u64, String)> = None;
+    for (index, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        let number = index + 1;
+        if line.is_empty() {
+            continue;
+        }
+        let record: Record =
+            serde_json::from_str(&line).map_err(|e| anyhow!("line {}: not an audit record: {}", number, e))?;
+        let (expected_seq, expected_prev) = match &head {
+            Some((seq, hash)) => (seq + 1, hash.as_str()),
+            None => (0, GENESIS),
+        };
+        if record.seq != expected_seq {
+            bail!("line {}: record {} where record {} was expected; records are missing", number, record.seq, expected_seq);
+        }
+        if record.prev_hash != expected_prev {
+            bail!("line {}: record {} isn't chained to the record before it", number, record.seq);
+        }
+        if record.hash != record.chain_hash() {
+            bail!("line {}: record {} was modified after it was written", number, record.seq);
+        }
+        head = Some((record.seq, record.hash));
+    }
+    match head {
+        Some((seq, hash)) => println!("OK: {} records, head {} {}", seq + 1, seq, hash),
+        None => println!("OK: no records"),
+    }
+    Ok(())
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct AuditParams {
+    key: Option<String>,
+    since: Option<String>,
+    until: Option<String>,
+    limit: Option<usize>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct AuditResponse {
+    object: &'static str,
+    data: Vec<Record>,
+}
+
+fn invalid_query(param: &'static str, message: impl Into<String>) -> ApiError {
+    ApiError::new(StatusCode::BAD_REQUEST, "invalid_request_error", message)
+        .param(param)
+        .reason("invalid_query")
+}
+
+// A bound on record timestamps: a UTC date (`2026-10-16`) or a timestamp as
+// records write them (`2026-10-16T09:30:00Z`), compared as text
+fn time_bound(param: &'static str, value: Option<String>) -> Result<Option<String>, ApiError> {
+    let Some(value) = value else {
+        return Ok(None);
+    };
+    let well_formed = value.get(..10).is_some_and(|date| date.parse::<Day>().is_ok())
+        && (value.len() == 10 || (value.as_bytes()[10] == b'T' && value.ends_with('Z')));
+    if !well_formed {
+        return Err(invalid_query(
+            param,
+            format!("{} must be a UTC date or timestamp such as 2026-10-16T09:30:00Z, got {:?}", param, value),
+        ));
+    }
+    Ok(Some(value))
+}
+
+/// `GET /admin/audit?key=&since=&until=&limit=`: the latest records of a key,
+/// a time range (`since` included, `until` not), or both, oldest first.
+pub(crate) async fn recent(
+    State(state): State<Arc<AppState>>,
+    params: Result<Query<AuditParams>, QueryRejection>,
+) -> Result<Json<AuditResponse>, ApiError> {
+    let params = params.map(|Query(params)| params).map_err(|rejection| {
+        ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_request_error",
+            format!("Invalid query string: {}", rejection.body_text()),
+        )
+        .reason("invalid_query")
+    })?;
+    let Some(audit) = &state.audit else {
+        return Err(
+            ApiError::new(StatusCode::NOT_FOUND, "invalid_request_error", "The audit log is not enabled")
+                .reason("not_found"),
+        );
+    };
+    let since = time_bound("since", params.since)?;
+    let until = time_bound("until", params.until)?;
+    let limit = params.limit.unwrap_or(DEFAULT_LIMIT);
+    if !(1..=MAX_LIMIT).contains(&limit) {
+        return Err(invalid_query("limit", format!("limit must be between 1 and {}, got {}", MAX_LIMIT, limit)));
+    }
+    let key = params.key.map(|key| key.trim().to_ascii_lowercase());
+    let path = audit.path().to_path_buf();
+    let records = tokio::task::spawn_blocking(move || -> anyhow::Result<VecDeque<Record>> {
+        let mut records = VecDeque::with_capacity(limit);
+        for line in BufReader::new(File::open(&path)?).lines() {
+            let Ok(record) = serde_json::from_str::<Record>(&line?) else {
+                continue;
+            };
+            let matches = key.as_ref().is_none_or(|key| record.key == *key)
+                && since.as_ref().is_none_or(|since| record.timestamp >= *since)
+                && until.as_ref().is_none_or(|until| record.timestamp < *until);
+            if matches {
+                if records.len() == limit {
+                    records.pop_front();
+                }
+                records.push_back(record);
+            }
+        }
+        Ok(records)
+    })
+    .await
+    .map_err(anyhow::Error::from)
+    .and_then(|records| records)
+    .map_err(|e| {
+        ApiError::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "internal_error",
+            format!("Couldn't read the audit log: {}", e),
+        )
+        .reason("audit_unreadable")
+    })?;
+    Ok(Json(AuditResponse {
+        object: "list",
+        data: records.into(),
+    }))
+}
@@ -0,0 +1,231 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, Query, Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::Response,
+    Json,
+};
+use serde::Serialize;
+
+use crate::error::{map_error_body, ApiError};
+use crate::extract::{ApiJson, Scheduling};
+use crate::{create_embeddings, AppState, EmbeddingRequest, EmbeddingResponse};
+
+/// Azure OpenAI's `POST /openai/deployments/{deployment}/embeddings`. The
+/// deployment selects the model (by name or alias), taking the place of the
+/// body's `model`; the body and response are otherwise those of
+/// `/v1/embeddings`. `api-version` is required, as on Azure, but its value
+/// doesn't change anything.
+pub(crate) async fn create_deployment_embeddings(
+    state: State<Arc<AppState>>,
+    Path(deployment): Path<String>,
+    Query(query): Query<HashMap<String, String>>,
+    scheduling: Scheduling,
+    ApiJson(mut req): ApiJson<EmbeddingRequest>,
+) -> Result<Json<EmbeddingResponse>, ApiError> {
+    check_api_version(query.get("api-version").map(String::as_str))?;
+    req.model = Some(deployment);
+    create_embeddings(state, scheduling, ApiJson(req)).await
+}
+
+// Dated versions, e.g. 2024-02-01 or 2024-05-01-preview
+fn check_api_version(version: Option<&str>) -> Result<(), ApiError> {
+    let Some(version) = version else {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_request_error",
+            "The api-version query parameter (?api-version=) is required for all requests.",
+        )
+        .param("api-version")
+        .code("MissingApiVersionParameter")
+        .reason("invalid_api_version"));
+    };
+    let date = version.strip_suffix("-preview").unwrap_or(version);
+    let valid = date.len() == 10
+        && date.char_indices().all(|(i, c)| if i == 4 || i == 7 { c == '-' } else { c.is_ascii_digit() });
+    if valid {
+        return Ok(());
+    }
+    Err(ApiError::new(
+        StatusCode::BAD_REQUEST,
+        "invalid_request_error",
+        format!(
+            "The api-version {:?} is invalid; expected a date such as 2024-02-01, optionally followed by -preview",
+            version
+        ),
+    )
+    .param("api-version")
+    .code("InvalidApiVersionParameter")
+    .reason("invalid_api_version"))
+}
+
+// Azure's error body: `code` is always a string, and `type` is left out
+#[derive(Debug, Serialize)]
+struct AzureErrorResponse {
+    error: AzureError,
+}
+
+#[derive(Debug, Serialize)]
+struct AzureError {
+    code: String,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    param: Option<String>,
+    status: u16,
+}
+
+/// Rewrite the OpenAI-style error bodies of the Azure routes into Azure's
+/// shape.
+pub(crate) async fn azure_errors(req: Request, next: Next) -> Response {
+    map_error_body(next.run(req).await, |status, error| {
+        let (code, message) = match error.error_type.as_str() {
+            "model_not_found" => (
+                "DeploymentNotFound".to_string(),
+                "The API deployment for this resource does not exist.".to_string(),
+            ),
+            _ => (error.code.unwrap_or_else(|| status_code(status)), error.message),
+        };
+        AzureErrorResponse {
+            error: AzureError {
+                code,
+                message,
+                param: error.param,
+                status: status.as_u16(),
+            },
+        }
+    })
+    .await
+}
+
+// Azure's codes for errors that carry none of their own
+fn status_code(status: StatusCode) -> String {
+    match status {
+        StatusCode::BAD_REQUEST => "BadRequest",
+        StatusCode::UNAUTHORIZED => "Unauthorized",
+        StatusCode::NOT_FOUND => "NotFound",
+        StatusCode::METHOD_NOT_ALLOWED => "MethodNotAllowed",
+        StatusCode::CONFLICT => "Conflict",
+        StatusCode::PAYLOAD_TOO_LARGE => "RequestEntityTooLarge",
+        StatusCode::UNSUPPORTED_MEDIA_TYPE => "UnsupportedMediaType",
+        StatusCode::TOO_MANY_REQUESTS => "TooManyRequests",
+        StatusCode::SERVICE_UNAVAILABLE => "ServiceUnavailable",
+        _ if status.is_server_error() => "InternalServerError",
+        _ => "BadRequest",
+    }
+    .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use axum::{body::Body, http::HeaderMap, middleware, routing::get, Router};
+    use serde_json::{json, Value};
+    use tower::ServiceExt;
+
+    use crate::queue::Tenant;
+
+    // Each route fails the way a real request would, behind the Azure error layer
+    fn app() -> Router {
+        Router::new()
+            .route("/missing-version", get(|| async { check_api_version(None) }))
+            .route("/bad-version", get(|| async { check_api_version(Some("latest")) }))
+            .route(
+                "/deployment",
+                get(|| async {
+                    Err::<(), _>(ApiError::new(
+                        StatusCode::NOT_FOUND,
+                        "model_not_found",
+                        "The model `gpt-embed` does not exist",
+                    )
+                    .param("model"))
+                }),
+            )
+            .route(
+                "/busy",
+                get(|| async {
+                    Err::<(), _>(ApiError::new(StatusCode::TOO_MANY_REQUESTS, "rate_limit_error", "Too many requests"))
+                }),
+            )
+            .route("/ok", get(|| async { Json(json!({"object": "list"})) }))
+            .layer(middleware::from_fn(azure_errors))
+    }
+
+    async fn get_json(path: &str) -> (StatusCode, Value) {
+        let response = app().oneshot(Request::get(path).body(Body::empty()).unwrap()).await.unwrap();
+        let status = response.status();
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&bytes).unwrap())
+    }
+
+    #[test]
+    fn api_version_is_a_date() {
+        for valid in ["2024-02-01", "2023-05-15", "2024-05-01-preview"] {
+            assert!(check_api_version(Some(valid)).is_ok(), "{}", valid);
+        }
+        for invalid in ["", "latest", "2024-2-01", "2024/02/01", "2024-02-01-beta", "v1"] {
+            assert!(check_api_version(Some(invalid)).is_err(), "{}", invalid);
+        }
+        assert!(check_api_version(None).is_err());
+    }
+
+    // Bodies as Azure OpenAI returns them for the same failures
+    #[tokio::test]
+    async fn errors_take_azures_shape() {
+        let (status, body) = get_json("/missing-version").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(
+            body,
+            json!({"error": {
+                "code": "MissingApiVersionParameter",
+                "message": "The api-version query parameter (?api-version=) is required for all requests.",
+                "param": "api-version",
+                "status": 400,
+            }})
+        );
+
+        let (status, body) = get_json("/deployment").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(
+            body,
+            json!({"error": {
+                "code": "DeploymentNotFound",
+                "message": "The API deployment for this resource does not exist.",
+                "param": "model",
+                "status": 404,
+            }})
+        );
+
+        let (_, body) = get_json("/bad-version").await;
+        assert_eq!(body["error"]["code"], "InvalidApiVersionParameter");
+        assert!(body["error"].get("type").is_none());
+
+        // Errors without a code of their own get Azure's code for the status
+        let (status, body) = get_json("/busy").await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(
+            body,
+            json!({"error": {"code": "TooManyRequests", "message": "Too many requests", "status": 429}})
+        );
+
+        let (status, body) = get_json("/ok").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, json!({"object": "list"}));
+    }
+
+    #[test]
+    fn api_key_identifies_the_tenant() {
+        let mut headers = HeaderMap::new();
+        headers.insert("api-key", "key-1".parse().unwrap());
+        let tenant = Tenant::from_headers(&headers);
+        assert_eq!(tenant, Tenant::from_credential(Some(b"key-1")));
+        assert_ne!(tenant, Tenant::from_credential(None));
+
+        // Authorization wins when both are sent
+        headers.insert("authorization", "Bearer key-2".parse().unwrap());
+        assert_eq!(Tenant::from_headers(&headers), Tenant::from_credential(Some(b"Bearer key-2")));
+    }
+}
@@ -0,0 +1,758 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
+use std::time::UNIX_EPOCH;
+
+use anyhow::{anyhow, bail, Context};
+use arrow_array::cast::AsArray;
+use arrow_array::types::{Float32Type, Int64Type};
+use arrow_array::{ArrayRef, Int64Array, ListArray, RecordBatch, StringArray};
+use arrow_schema::{DataType, Field, Schema, SchemaRef};
+use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression;
+use parquet::file::metadata::KeyValue;
+use parquet::file::properties::WriterProperties;
+use prometheus::{IntGauge, IntGaugeVec, Opts, Registry};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+use crate::chunk::Chunker;
+use crate::embedder::Embedder;
+use crate::models::InputKind;
+use crate::preprocess::Preprocess;
+use crate::push::{PushConfig, Pusher};
+use crate::{shorten, standalone_model, StandaloneModel, CHUNK_OVERLAP};
+
+// Chunks embedded together, across files
+const BATCH_CHUNKS: usize = 64;
+// Rows per Parquet row group
+const ROW_GROUP_ROWS: usize = 10_000;
+// Bytes looked at to tell text from binary files
+const SNIFF_BYTES: usize = 8000;
+const DEFAULT_GLOBS: &[&str] = &["**/*.md", "**/*.markdown", "**/*.txt"];
+
+const USAGE: &str = "\
+Usage: semembed batch-dir DIR --out FILE [OPTIONS]
+
+Embeds the text and Markdown files under DIR with the configured model
+(SEMEMBED_MODEL, SEMEMBED_PREPROCESS and the other serving settings apply)
+and writes one Parquet row per chunk: path, mtime_ns, size, chunk_index,
+char_start, char_end, text (with --text) and embedding.
+
+Options:
+  --out FILE                Parquet file to write (required)
+  --glob PATTERN            Files to embed, relative to DIR; `*` and `?` match
+                            within a path segment, `**` across segments. Can be
+                            given more than once (default **/*.md, **/*.markdown,
+                            **/*.txt)
+  --model NAME              Model to use instead of SEMEMBED_MODEL
+  --dimensions N            Truncate embeddings to N dimensions
+  --chunk-tokens N          Tokens per chunk (default: the model's maximum)
+  --chunk-overlap N         Tokens shared by consecutive chunks (default 32)
+  --text                    Store each chunk's text
+  --jobs N                  Files read and chunked in parallel (default: CPUs)
+  --resume                  Keep the rows of files already in FILE with the same
+                            path, mtime and size, and embed only the rest
+  --fail-fast               Stop at the first unreadable or binary file
+";
+
+struct BatchArgs {
+    dir: PathBuf,
+    out: PathBuf,
+    globs: Vec<String>,
+    model: Option<String>,
+    dimensions: Option<usize>,
+    chunk_tokens: Option<usize>,
+    chunk_overlap: usize,
+    text: bool,
+    jobs: usize,
+    resume: bool,
+    fail_fast: bool,
+}
+
+impl BatchArgs {
+    fn parse(args: &[String]) -> anyhow::Result<Option<Self>> {
+        let mut dir = None;
+        let mut out = None;
+        let mut parsed = Self {
+            dir: PathBuf::new(),
+            out: PathBuf::new(),
+            globs: Vec::new(),
+            model: None,
+            dimensions: None,
+            chunk_tokens: None,
+            chunk_overlap: CHUNK_OVERLAP,
+            text: false,
+            jobs: std::thread::available_parallelism().map_or(1, |n| n.get()),
+            resume: false,
+            fail_fast: false,
+        };
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or_else(|| anyhow!("{} needs a value", arg));
+            let number = |value: &String| -> anyhow::Result<usize> {
+                value.parse().map_err(|e| anyhow!("invalid {} {:?}: {}", arg, value, e))
+            };
+            match arg.as_str() {
+                "-h" | "--help" => return Ok(None),
+                "--out" => out = Some(PathBuf::from(value()?)),
+                "--glob" => parsed.globs.push(value()?.clone()),
+                "--model" => parsed.model = Some(value()?.clone()),
+                "--dimensions" => parsed.dimensions = Some(number(value()?)?),
+                "--chunk-tokens" => parsed.chunk_tokens = Some(number(value()?)?),
+                "--chunk-overlap" => parsed.chunk_overlap = number(value()?)?,
+                "--jobs" => parsed.jobs = number(value()?)?.max(1),
+                "--text" => parsed.text = true,
+                "--resume" => parsed.resume = true,
+                "--fail-fast" => parsed.fail_fast = true,
+                other if other.starts_with('-') => bail!("unknown batch-dir option {:?}\n\n{}", other, USAGE),
+                other if dir.is_none() => dir = Some(PathBuf::from(other)),
+                other => bail!("unexpected argument {:?}: only one directory can be given", other),
+            }
+        }
+        parsed.dir = dir.ok_or_else(|| anyhow!("batch-dir needs a directory\n\n{}", USAGE))?;
+        parsed.out = out.ok_or_else(|| anyhow!("batch-dir needs --out FILE\n\n{}", USAGE))?;
+        if parsed.globs.is_empty() {
+            parsed.globs = DEFAULT_GLOBS.iter().map(|glob| glob.to_string()).collect();
+        }
+        Ok(Some(parsed))
+    }
+
+    fn schema(&self) -> SchemaRef {
+        let mut fields = vec![Field::new("path", DataType::Utf8, false)];
+        for name in ["mtime_ns", "size", "chunk_index", "char_start", "char_end"] {
+            fields.push(Field::new(name, DataType::Int64, false));
+        }
+        if self.text {
+            fields.push(Field::new("text", DataType::Utf8, false));
+        }
+        let item = Field::new("item", DataType::Float32, true);
+        fields.push(Field::new("embedding", DataType::List(Arc::new(item)), false));
+        Arc::new(Schema::new(fields))
+    }
+}
+
+// A file to embed, and what identifies its version for --resume
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct Source {
+    // Relative to the directory, with `/` separators
+    path: String,
+    mtime_ns: i64,
+    size: i64,
+}
+
+// A file read and split into chunks: byte and character ranges, and text
+struct Chunked {
+    source: Source,
+    chunks: Vec<(usize, usize, String)>,
+}
+
+// Rows waiting for their row group, one vector per column
+#[derive(Default)]
+struct Rows {
+    paths: Vec<Option<String>>,
+    mtimes: Vec<i64>,
+    sizes: Vec<i64>,
+    indices: Vec<i64>,
+    starts: Vec<i64>,
+    ends: Vec<i64>,
+    texts: Vec<Option<String>>,
+    embeddings: Vec<Vec<f32>>,
+}
+
+impl Rows {
+    fn len(&self) -> usize {
+        self.paths.len()
+    }
+
+    fn into_batch(self, schema: &SchemaRef, text: bool) -> anyhow::Result<RecordBatch> {
+        let mut columns: Vec<ArrayRef> = vec![
+            Arc::new(StringArray::from(self.paths)),
+            Arc::new(Int64Array::from(self.mtimes)),
+            Arc::new(Int64Array::from(self.sizes)),
+            Arc::new(Int64Array::from(self.indices)),
+            Arc::new(Int64Array::from(self.starts)),
+            Arc::new(Int64Array::from(self.ends)),
+        ];
+        if text {
+            columns.push(Arc::new(StringArray::from(self.texts)));
+        }
+        let embeddings = self.embeddings.into_iter().map(|embedding| Some(embedding.into_iter().map(Some)));
+        columns.push(Arc::new(ListArray::from_iter_primitive::<Float32Type, _, _>(embeddings)));
+        Ok(RecordBatch::try_new(schema.clone(), columns)?)
+    }
+}
+
+#[derive(Default)]
+struct Summary {
+    embedded: usize,
+    chunks: usize,
+    unchanged: usize,
+    skipped: usize,
+    failed: usize,
+}
+
+/// Run `semembed batch-dir` with the arguments that follow the subcommand.
+pub fn run(args: &[String]) -> anyhow::Result<()> {
+    let Some(args) = BatchArgs::parse(args)? else {
+        print!("{}", USAGE);
+        return Ok(());
+    };
+    let push = PushConfig::from_env()?;
+    let sources = walk(&args)?;
+    info!("{} files match under {}", sources.len(), args.dir.display());
+
+    let model_name = args
+        .model
+        .clone()
+        .or_else(|| std::env::var("SEMEMBED_MODEL").ok())
+        .unwrap_or_else(|| "BAAI/bge-small-en-v1.5".to_string());
+    let preprocess = Preprocess::from_env()?;
+    let StandaloneModel {
+        spec,
+        metadata,
+        chunker,
+        embedder,
+        registry,
+    } = standalone_model(&model_name)?;
+    let dimensions = args.dimensions.unwrap_or(metadata.dimensions);
+    if dimensions == 0 || dimensions > metadata.dimensions {
+        bail!("--dimensions must be between 1 and {} for model {}, got {}", metadata.dimensions, spec.name, dimensions);
+    }
+    let prefix = spec.prefix(Some(InputKind::Passage)).unwrap_or_default();
+    let budget = spec.max_tokens.saturating_sub(chunker.count(prefix)?);
+    let budget = args.chunk_tokens.map_or(budget, |chunk_tokens| chunk_tokens.min(budget));
+    if budget == 0 || args.chunk_overlap >= budget {
+        bail!("--chunk-overlap must be less than the chunk size ({}), got {}", budget, args.chunk_overlap);
+    }
+
+    // Written next to the output and renamed over it at the end, so the
+    // previous output stays readable (and resumable) until then
+    let temporary = args.out.with_extension("parquet.tmp");
+    let file = std::fs::File::create(&temporary)
+        .with_context(|| format!("failed to create {}", temporary.display()))?;
+    let schema = args.schema();
+    let properties = WriterProperties::builder()
+        .set_compression(Compression::SNAPPY)
+        .set_max_row_group_size(ROW_GROUP_ROWS)
+        .set_key_value_metadata(Some(vec![
+            KeyValue::new("semembed.model".to_string(), spec.name.to_string()),
+            KeyValue::new("semembed.dimensions".to_string(), dimensions.to_string()),
+        ]))
+        .build();
+    let mut writer = ArrowWriter::try_new(std::io::BufWriter::new(file), schema.clone(), Some(properties))?;
+
+    let mut summary = Summary::default();
+    let done = if args.resume && args.out.exists() {
+        keep_unchanged(&args, &schema, spec.name, dimensions, &sources, &mut writer)?
+    } else {
+        HashSet::new()
+    };
+    summary.unchanged = done.len();
+    let pending: Vec<Source> = sources.into_iter().filter(|source| !done.contains(source)).collect();
+    info!("Embedding {} files ({} unchanged)", pending.len(), summary.unchanged);
+
+    let interrupted = interrupt_flag();
+    let embedding = Embedding {
+        args: &args,
+        embedder: &embedder,
+        preprocess,
+        prefix,
+        dimensions,
+    };
+    let next = AtomicUsize::new(0);
+    let stop = AtomicBool::new(false);
+    let result = std::thread::scope(|scope| -> anyhow::Result<()> {
+        let (tx, rx) = mpsc::sync_channel::<anyhow::Result<Chunked>>(args.jobs * 2);
+        for _ in 0..args.jobs.min(pending.len().max(1)) {
+            let tx = tx.clone();
+            let (pending, next, stop, chunker, dir) = (&pending, &next, &stop, &chunker, &args.dir);
+            scope.spawn(move || loop {
+                let index = next.fetch_add(1, Ordering::Relaxed);
+                let Some(source) = pending.get(index) else {
+                    return;
+                };
+                if stop.load(Ordering::Relaxed) {
+                    return;
+                }
+                if tx.send(read_file(dir, source, chunker, budget, args.chunk_overlap)).is_err() {
+                    return;
+                }
+            });
+        }
+        drop(tx);
+
+        let mut batch: Vec<Chunked> = Vec::new();
+        let mut rows = Rows::default();
+        let result = (|| {
+            for chunked in rx.iter() {
+                if interrupted.load(Ordering::Relaxed) {
+                    break;
+                }
+                match chunked {
+                    Ok(chunked) => batch.push(chunked),
+                    Err(e) if args.fail_fast => return Err(e),
+                    Err(e) => {
+                        warn!("Skipping {:#}", e);
+                        summary.skipped += 1;
+                        continue;
+                    }
+                }
+                if batch.iter().map(|chunked| chunked.chunks.len()).sum::<usize>() >= BATCH_CHUNKS {
+                    embedding.embed(std::mem::take(&mut batch), &mut rows, &mut summary)?;
+                }
+                if rows.len() >= ROW_GROUP_ROWS {
+                    writer.write(&std::mem::take(&mut rows).into_batch(&schema, args.text)?)?;
+                }
+            }
+            if !interrupted.load(Ordering::Relaxed) {
+                embedding.embed(std::mem::take(&mut batch), &mut rows, &mut summary)?;
+            }
+            Ok(())
+        })();
+        // Stop the readers, which may be blocked on a full channel
+        stop.store(true, Ordering::Relaxed);
+        drop(rx);
+        // What was embedded is kept even after a failure, for --resume
+        writer.write(&rows.into_batch(&schema, args.text)?)?;
+        result
+    });
+
+    writer.into_inner()?.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    std::fs::rename(&temporary, &args.out)
+        .with_context(|| format!("failed to replace {}", args.out.display()))?;
+    println!(
+        "{} files embedded ({} chunks), {} unchanged, {} skipped, {} failed; wrote {}",
+        summary.embedded,
+        summary.chunks,
+        summary.unchanged,
+        summary.skipped,
+        summary.failed,
+        args.out.display()
+    );
+    if let Some(push) = push {
+        push_summary(push, registry, &summary);
+    }
+    result?;
+    if interrupted.load(Ordering::Relaxed) {
+        bail!("interrupted; run again with --resume to embed the remaining files");
+    }
+    Ok(())
+}
+
+// Push the embedder's metrics and the run's counts; a failure is only logged
+fn push_summary(push: PushConfig, registry: Registry, summary: &Summary) {
+    let pushed = (|| -> anyhow::Result<()> {
+        let files = IntGaugeVec::new(
+            Opts::new("semembed_batch_dir_files", "Files in the last batch-dir run, by outcome"),
+            &["outcome"],
+        )?;
+        registry.register(Box::new(files.clone()))?;
+        for (outcome, count) in [
+            ("embedded", summary.embedded),
+            ("unchanged", summary.unchanged),
+            ("skipped", summary.skipped),
+            ("failed", summary.failed),
+        ] {
+            files.with_label_values(&[outcome]).set(count as i64);
+        }
+        let chunks = IntGauge::new("semembed_batch_dir_chunks", "Chunks embedded in the last batch-dir run")?;
+        registry.register(Box::new(chunks.clone()))?;
+        chunks.set(summary.chunks as i64);
+        let pusher = Pusher::new(push, registry)?;
+        tokio::runtime::Builder::new_current_thread().enable_all().build()?.block_on(pusher.push());
+        Ok(())
+    })();
+    if let Err(e) = pushed {
+        warn!("Failed to push metrics: {:#}", e);
+    }
+}
+
+// Set on Ctrl-C, so the files embedded so far are still written out; a second
+// Ctrl-C exits at once
+fn interrupt_flag() -> Arc<AtomicBool> {
+    let interrupted = Arc::new(AtomicBool::new(false));
+    let flag = interrupted.clone();
+    std::thread::spawn(move || {
+        let Ok(runtime) = tokio::runtime::Builder::new_current_thread().enable_all().build() else {
+            return;
+        };
+        runtime.block_on(async {
+            while tokio::signal::ctrl_c().await.is_ok() {
+                if flag.swap(true, Ordering::Relaxed) {
+                    std::process::exit(130);
+                }
+                warn!("Interrupted; writing what was embedded so far (Ctrl-C again to quit at once)");
+            }
+        });
+    });
+    interrupted
+}
+
+// Copy the rows of files that haven't changed from the previous output, and
+// return those files
+fn keep_unchanged(
+    args: &BatchArgs,
+    schema: &SchemaRef,
+    model: &str,
+    dimensions: usize,
+    sources: &[Source],
+    writer: &mut ArrowWriter<impl std::io::Write + Send>,
+) -> anyhow::Result<HashSet<Source>> {
+    let file = std::fs::File::open(&args.out).with_context(|| format!("failed to open {}", args.out.display()))?;
+    let builder = ParquetRecordBatchReaderBuilder::try_new(file)
+        .with_context(|| format!("can't resume from {}", args.out.display()))?;
+    let layout = |schema: &Schema| -> Vec<(String, DataType)> {
+        schema.fields().iter().map(|field| (field.name().clone(), field.data_type().clone())).collect()
+    };
+    if layout(builder.schema()) != layout(schema) {
+        bail!("can't resume from {} (was it written with the same --text setting?)", args.out.display());
+    }
+    let metadata = |key: &str| {
+        let pairs = builder.metadata().file_metadata().key_value_metadata()?;
+        pairs.iter().find(|pair| pair.key == key)?.value.clone()
+    };
+    let previous = (metadata("semembed.model"), metadata("semembed.dimensions"));
+    if previous != (Some(model.to_string()), Some(dimensions.to_string())) {
+        bail!(
+            "can't resume from {}: it holds {}-dimension embeddings of {}, not {}-dimension ones of {}",
+            args.out.display(),
+            previous.1.as_deref().unwrap_or("?"),
+            previous.0.as_deref().unwrap_or("another model"),
+            dimensions,
+            model
+        );
+    }
+    let current: HashSet<&Source> = sources.iter().collect();
+    let mut kept = HashSet::new();
+    for batch in builder.with_batch_size(ROW_GROUP_ROWS).build()? {
+        let batch = RecordBatch::try_new(schema.clone(), batch?.columns().to_vec())?;
+        let (paths, mtimes, sizes) = (
+            batch.column(0).as_string::<i32>(),
+            batch.column(1).as_primitive::<Int64Type>(),
+            batch.column(2).as_primitive::<Int64Type>(),
+        );
+        // A file's rows are next to each other, so they're copied in runs
+        let mut run = None;
+        for row in 0..=batch.num_rows() {
+            let keep = row < batch.num_rows() && {
+                let source = Source {
+                    path: paths.value(row).to_string(),
+                    mtime_ns: mtimes.value(row),
+                    size: sizes.value(row),
+                };
+                let unchanged = current.contains(&source);
+                if unchanged {
+                    kept.insert(source);
+                }
+                unchanged
+            };
+            match (keep, run) {
+                (true, None) => run = Some(row),
+                (false, Some(start)) => {
+                    writer.write(&batch.slice(start, row - start))?;
+                    run = None;
+                }
+                _ => {}
+            }
+        }
+    }
+    Ok(kept)
+}
+
+// Every file under the directory that matches a glob, in path order
+fn walk(args: &BatchArgs) -> anyhow::Result<Vec<Source>> {
+    let out = std::fs::canonicalize(&args.out).ok();
+    let mut sources = Vec::new();
+    let mut directories = vec![args.dir.clone()];
+    while let Some(directory) = directories.pop() {
+        let entries =
+            std::fs::read_dir(&directory).with_context(|| format!("failed to read {}", directory.display()))?;
+        for entry in entries {
+            let entry = entry?;
+            let path = entry.path();
+            // Symbolic links to directories aren't followed, so walking ends
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                directories.push(path);
+                continue;
+            }
+            let Ok(relative) = path.strip_prefix(&args.dir) else {
+                continue;
+            };
+            let relative: Vec<String> =
+                relative.components().map(|part| part.as_os_str().to_string_lossy().into_owned()).collect();
+            let relative = relative.join("/");
+            if !args.globs.iter().any(|glob| glob_match(glob, &relative)) {
+                continue;
+            }
+            let Ok(metadata) = std::fs::metadata(&path) else {
+                warn!("Skipping {}: it can't be read", relative);
+                continue;
+            };
+            let is_out = || out.is_some() && std::fs::canonicalize(&path).ok() == out;
+            if !metadata.is_file() || is_out() {
+                continue;
+            }
+            let mtime_ns = metadata
+                .modified()
+                .ok()
+                .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                .map_or(0, |since| since.as_nanos() as i64);
+            sources.push(Source {
+                path: relative,
+                mtime_ns,
+                size: metadata.len() as i64,
+            });
+        }
+    }
+    sources.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(sources)
+}
+
+// Match a `/`-separated path against a glob: `*` and `?` within a segment,
+// `**` for any number of segments
+fn glob_match(glob: &str, path: &str) -> bool {
+    fn segments(glob: &[&str], path: &[&str]) -> bool {
+        match (glob.first(), path.first()) {
+            (None, None) => true,
+            (Some(&"**"), _) => segments(&glob[1..], path) || (!path.is_empty() && segments(glob, &path[1..])),
+            (Some(pattern), Some(name)) => {
+                segment(pattern.as_bytes(), name.as_bytes()) && segments(&glob[1..], &path[1..])
+            }
+            _ => false,
+        }
+    }
+    fn segment(pattern: &[u8], name: &[u8]) -> bool {
+        match (pattern.first(), name.first()) {
+            (None, None) => true,
+            (Some(b'*'), _) => segment(&pattern[1..], name) || (!name.is_empty() && segment(pattern, &name[1..])),
+            (Some(b'?'), Some(_)) => segment(&pattern[1..], &name[1..]),
+            (Some(a), Some(b)) => a == b && segment(&pattern[1..], &name[1..]),
+            _ => false,
+        }
+    }
+    let glob: Vec<&str> = glob.trim_start_matches("./").split('/').collect();
+    let path: Vec<&str> = path.split('/').collect();
+    segments(&glob, &path)
+}
+
+// Read a file and split it into chunks; binary and unreadable files fail
+fn read_file(
+    dir: &Path,
+    source: &Source,
+    chunker: &Chunker,
+    budget: usize,
+    overlap: usize,
+) -> anyhow::Result<Chunked> {
+    let bytes = std::fs::read(dir.join(&source.path)).with_context(|| format!("{}: it can't be read", source.path))?;
+    if bytes[..bytes.len().min(SNIFF_BYTES)].contains(&0) {
+        bail!("{}: it looks binary", source.path);
+    }
+    let text = String::from_utf8(bytes).map_err(|_| anyhow!("{}: it isn't UTF-8 text", source.path))?;
+    let spans = if text.trim().is_empty() { Vec::new() } else { chunker.spans(&text, budget, overlap)? };
+    // Spans are in order of their start, so characters are counted once
+    let (mut byte, mut char) = (0, 0);
+    let mut to_chars = |offset: usize| {
+        if offset >= byte {
+            char += text[byte..offset].chars().count();
+        } else {
+            char -= text[offset..byte].chars().count();
+        }
+        byte = offset;
+        char
+    };
+    let chunks = spans
+        .into_iter()
+        .map(|span| {
+            let start = to_chars(span.start);
+            let end = to_chars(span.end);
+            (start, end, text[span].to_string())
+        })
+        .collect();
+    Ok(Chunked {
+        source: source.clone(),
+        chunks,
+    })
+}
+
+// How chunks are turned into embeddings, as the server would for passages
+struct Embedding<'a> {
+    args: &'a BatchArgs,
+    embedder: &'a Arc<Embedder>,
+    preprocess: Preprocess,
+    prefix: &'static str,
+    dimensions: usize,
+}
+
+impl Embedding<'_> {
+    // Embed the chunks of several files at once, falling back to one file at a
+    // time when that fails, and add their rows
+    fn embed(&self, batch: Vec<Chunked>, rows: &mut Rows, summary: &mut Summary) -> anyhow::Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let count = batch.len();
+        let batch = match self.embed_files(&batch) {
+            Ok(embeddings) => vec![(batch, Ok(embeddings))],
+            Err(e) if count == 1 => vec![(batch, Err(e))],
+            Err(e) => {
+                warn!("Batch of {} files failed ({:#}), retrying them one at a time", count, e);
+                batch
+                    .into_iter()
+                    .map(|chunked| {
+                        let chunked = vec![chunked];
+                        let embeddings = self.embed_files(&chunked);
+                        (chunked, embeddings)
+                    })
+                    .collect()
+            }
+        };
+        for (files, embeddings) in batch {
+            let embeddings = match embeddings {
+                Ok(embeddings) => embeddings,
+                Err(e) if self.args.fail_fast => return Err(e),
+                Err(e) => {
+                    warn!("Failed to embed {}: {:#}", files[0].source.path, e);
+                    summary.failed += files.len();
+                    continue;
+                }
+            };
+            let mut embeddings = embeddings.into_iter();
+            for Chunked { source, chunks } in files {
+                summary.embedded += 1;
+                for (index, (start, end, text)) in chunks.into_iter().enumerate() {
+                    let Some(embedding) = embeddings.next().flatten() else {
+                        continue;
+                    };
+                    summary.chunks += 1;
+                    rows.paths.push(Some(source.path.clone()));
+                    rows.mtimes.push(source.mtime_ns);
+                    rows.sizes.push(source.size);
+                    rows.indices.push(index as i64);
+                    rows.starts.push(start as i64);
+                    rows.ends.push(end as i64);
+                    rows.texts.push(self.args.text.then_some(text));
+                    rows.embeddings.push(embedding);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // One embedding per chunk of `files`, `None` for chunks that preprocessing
+    // leaves empty
+    fn embed_files(&self, files: &[Chunked]) -> anyhow::Result<Vec<Option<Vec<f32>>>> {
+        let mut texts = Vec::new();
+        let mut embedded = Vec::new();
+        for chunked in files {
+            for (_, _, text) in &chunked.chunks {
+                let text = self.preprocess.prepare(text.clone()).map_err(|disallowed| {
+                    anyhow!("{} contains a {}, which SEMEMBED_SANITIZE=reject refuses", chunked.source.path, disallowed)
+                })?;
+                embedded.push(!text.trim().is_empty());
+                if !text.trim().is_empty() {
+                    texts.push(format!("{}{}", self.prefix, text));
+                }
+            }
+        }
+        let mut embeddings = if texts.is_empty() {
+            Vec::new()
+        } else {
+            self.embedder
+                .embed(texts.iter().map(String::as_str).collect(), &CancellationToken::new())
+                .map_err(|e| anyhow!("inference failed: {}", e))?
+        }
+        .into_iter();
+        Ok(embedded
+            .into_iter()
+            .map(|embedded| {
+                embedded.then(|| embeddings.next()).flatten().map(|embedding| {
+                    if self.dimensions < embedding.len() {
+                        shorten(embedding, self.dimensions)
+                    } else {
+                        embedding
+                    }
+                })
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn source(path: &str, mtime_ns: i64) -> Source {
+        Source {
+            path: path.to_string(),
+            mtime_ns,
+            size: 10,
+        }
+    }
+
+    fn rows(sources: &[(&Source, usize)]) -> Rows {
+        let mut rows = Rows::default();
+        for (source, chunks) in sources {
+            for index in 0..*chunks {
+                rows.paths.push(Some(source.path.clone()));
+                rows.mtimes.push(source.mtime_ns);
+                rows.sizes.push(source.size);
+                rows.indices.push(index as i64);
+                rows.starts.push(0);
+                rows.ends.push(1);
+                rows.texts.push(Some(format!("{} {}", source.path, index)));
+                rows.embeddings.push(vec![index as f32; 3]);
+            }
+        }
+        rows
+    }
+
+    fn write(args: &BatchArgs, model: &str, rows: Rows) {
+        let properties = WriterProperties::builder()
+            .set_key_value_metadata(Some(vec![
+                KeyValue::new("semembed.model".to_string(), model.to_string()),
+                KeyValue::new("semembed.dimensions".to_string(), "3".to_string()),
+            ]))
+            .build();
+        let file = std::fs::File::create(&args.out).unwrap();
+        let mut writer = ArrowWriter::try_new(file, args.schema(), Some(properties)).unwrap();
+        writer.write(&rows.into_batch(&args.schema(), args.text).unwrap()).unwrap();
+        writer.close().unwrap();
+    }
+
+    #[test]
+    fn resuming_keeps_the_rows_of_unchanged_files() {
+        let dir = std::env::temp_dir().join(format!("semembed-resume-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let out = dir.join("out.parquet").to_string_lossy().into_owned();
+        let args = BatchArgs::parse(&[".".to_string(), "--out".to_string(), out, "--text".to_string()])
+            .unwrap()
+            .unwrap();
+        let (a, b, c) = (source("a.md", 1), source("b.md", 1), source("c.md", 1));
+        write(&args, "model", rows(&[(&a, 2), (&b, 3), (&c, 1)]));
+
+        // b changed and c is gone
+        let current = [a.clone(), source("b.md", 2)];
+        let mut writer = ArrowWriter::try_new(Vec::new(), args.schema(), None).unwrap();
+        let kept = keep_unchanged(&args, &args.schema(), "model", 3, &current, &mut writer).unwrap();
+        assert_eq!(kept, HashSet::from([a]));
+        let written = axum::body::Bytes::from(writer.into_inner().unwrap());
+        let batches: Vec<RecordBatch> = ParquetRecordBatchReaderBuilder::try_new(written)
+            .unwrap()
+            .build()
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        let texts: Vec<&str> =
+            batches.iter().flat_map(|batch| batch.column(6).as_string::<i32>().iter().flatten()).collect();
+        assert_eq!(texts, ["a.md 0", "a.md 1"]);
+
+        let mut writer = ArrowWriter::try_new(Vec::new(), args.schema(), None).unwrap();
+        let e = keep_unchanged(&args, &args.schema(), "other", 3, &current, &mut writer).unwrap_err();
+        assert!(e.to_string().contains("3-dimension embeddings of model, not 3-dimension ones of other"), "{}", e);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,324 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use axum::{
+    extract::{Path, Request, State},
+    http::{HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use hmac_sha256::Hash;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::cluster::normalize;
+use crate::error::{map_error_body, ApiError};
+use crate::extract::{ApiJson, Scheduling};
+use crate::models::InputKind;
+use crate::preprocess::PreprocessOverrides;
+use crate::{
+    create_embeddings, AppState, EmbeddingData, EmbeddingItem, EmbeddingRequest, EncodingFormat, InputType,
+    OutputKind,
+};
+
+// Amazon Titan text embeddings (amazon.titan-embed-text-v1, -v2:0)
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TitanRequest {
+    input_text: String,
+    dimensions: Option<usize>,
+    #[serde(default = "default_normalize")]
+    normalize: bool,
+    embedding_types: Option<Vec<String>>,
+}
+
+fn default_normalize() -> bool {
+    true
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct TitanResponse {
+    embedding: Vec<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    embeddings_by_type: Option<FloatsByType<Vec<f32>>>,
+    input_text_token_count: usize,
+}
+
+// Cohere Embed on Bedrock (cohere.embed-english-v3, cohere.embed-multilingual-v3)
+#[derive(Debug, Deserialize)]
+struct CohereRequest {
+    texts: Vec<String>,
+    input_type: Option<CohereInputType>,
+    truncate: Option<CohereTruncate>,
+    embedding_types: Option<Vec<String>>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum CohereInputType {
+    SearchQuery,
+    SearchDocument,
+    Classification,
+    Clustering,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+enum CohereTruncate {
+    None,
+    Start,
+    End,
+}
+
+#[derive(Debug, Serialize)]
+struct CohereResponse {
+    id: String,
+    response_type: &'static str,
+    embeddings: CohereEmbeddings,
+    texts: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+enum CohereEmbeddings {
+    Floats(Vec<Vec<f32>>),
+    ByType(FloatsByType<Vec<Vec<f32>>>),
+}
+
+#[derive(Debug, Serialize)]
+struct FloatsByType<T> {
+    float: T,
+}
+
+// One embedded input, before it is shaped into a family's response
+struct Embedded {
+    values: Vec<f32>,
+    tokens: usize,
+}
+
+/// Bedrock's `POST /model/{modelId}/invoke` for Titan and Cohere embedding
+/// models. The model family, and so the request and response shapes, comes
+/// from the model id; the id is served by the model it is aliased to, or the
+/// default model if it has no alias.
+pub(crate) async fn invoke(
+    State(state): State<Arc<AppState>>,
+    Path(model_id): Path<String>,
+    scheduling: Scheduling,
+    ApiJson(body): ApiJson<serde_json::Value>,
+) -> Result<Response, ApiError> {
+    let model = state.settings().resolver.resolve(Some(&model_id)).map(|_| model_id.clone());
+    if model_id.starts_with("amazon.titan-embed-text") {
+        let req: TitanRequest = parse(body)?;
+        titan(state, model, scheduling, req).await
+    } else if model_id.starts_with("cohere.embed") {
+        let req: CohereRequest = parse(body)?;
+        cohere(state, model, scheduling, req).await
+    } else {
+        Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            "model_not_found",
+            format!(
+                "The model `{}` is not supported; expected an amazon.titan-embed-text or cohere.embed model id",
+                model_id
+            ),
+        ))
+    }
+}
+
+async fn titan(
+    state: Arc<AppState>,
+    model: Option<String>,
+    scheduling: Scheduling,
+    req: TitanRequest,
+) -> Result<Response, ApiError> {
+    let by_type = float_types(req.embedding_types.as_deref(), "embeddingTypes")?;
+    if let Some(dimensions) = req.dimensions {
+        if dimensions == 0 || dimensions > state.metadata.dimensions {
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "invalid_request_error",
+                format!("dimensions must be between 1 and {}, got {}", state.metadata.dimensions, dimensions),
+            )
+            .param("dimensions")
+            .reason("invalid_dimensions"));
+        }
+    }
+
+    let mut embedded = embed(&state, model, scheduling, vec![req.input_text], None, None).await?;
+    let Embedded { mut values, tokens } = embedded.remove(0);
+    // Truncate first so `normalize` leaves the shortened vector at unit length
+    values.truncate(req.dimensions.unwrap_or(values.len()));
+    if req.normalize {
+        normalize(&mut values);
+    }
+    Ok(Json(TitanResponse {
+        embeddings_by_type: by_type.then(|| FloatsByType { float: values.clone() }),
+        embedding: values,
+        input_text_token_count: tokens,
+    })
+    .into_response())
+}
+
+async fn cohere(
+    state: Arc<AppState>,
+    model: Option<String>,
+    scheduling: Scheduling,
+    req: CohereRequest,
+) -> Result<Response, ApiError> {
+    let by_type = float_types(req.embedding_types.as_deref(), "embedding_types")?;
+    if let Some(CohereTruncate::Start) = req.truncate {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_request_error",
+            "truncate START is not supported; inputs are truncated at the end",
+        )
+        .param("truncate")
+        .reason("invalid_truncate"));
+    }
+    let input_type = req.input_type.and_then(|input_type| match input_type {
+        CohereInputType::SearchQuery => Some(InputKind::Query),
+        CohereInputType::SearchDocument => Some(InputKind::Passage),
+        CohereInputType::Classification | CohereInputType::Clustering => None,
+    });
+
+    let truncate = matches!(req.truncate, Some(CohereTruncate::None)).then_some(false);
+
+    let embedded = embed(&state, model, scheduling, req.texts.clone(), input_type, truncate).await?;
+
+    let vectors: Vec<Vec<f32>> = embedded.into_iter().map(|item| item.values).collect();
+    let (response_type, embeddings) = if by_type {
+        ("embeddings_by_type", CohereEmbeddings::ByType(FloatsByType { float: vectors }))
+    } else {
+        ("embeddings_floats", CohereEmbeddings::Floats(vectors))
+    };
+    Ok(Json(CohereResponse {
+        id: response_id(),
+        response_type,
+        embeddings,
+        texts: req.texts,
+    })
+    .into_response())
+}
+
+// Embed through the `/v1/embeddings` pipeline, at full dimensions
+async fn embed(
+    state: &Arc<AppState>,
+    model: Option<String>,
+    scheduling: Scheduling,
+    texts: Vec<String>,
+    input_type: Option<InputKind>,
+    truncate: Option<bool>,
+) -> Result<Vec<Embedded>, ApiError> {
+    let request = EmbeddingRequest {
+        input: InputType::Batch(texts),
+        model,
+        encoding_format: EncodingFormat::Float,
+        preprocess: PreprocessOverrides::default(),
+        input_type,
+        dimensions: None,
+        return_token_details: true,
+        user: None,
+        output: OutputKind::Dense,
+        partial: false,
+        instruction: None,
+        precision: None,
+        task: None,
+        truncate,
+        late_chunking: None,
+        echo: false,
+        allow_duplicate_ids: false,
+        truncation_side: None,
+        split: false,
+        detect_language: false,
+        dtype: None,
+    };
+    let Json(response) = create_embeddings(State(state.clone()), scheduling, ApiJson(request)).await?;
+    Ok(response
+        .data
+        .into_iter()
+        .filter_map(|item| match item {
+            EmbeddingItem::Embedding(object) => match object.embedding {
+                EmbeddingData::Float(floats) => Some(Embedded {
+                    values: floats.0,
+                    tokens: object.tokens.unwrap_or(0),
+                }),
+                _ => None,
+            },
+            EmbeddingItem::Failed(_) => None,
+        })
+        .collect())
+}
+
+fn parse<T: DeserializeOwned>(body: serde_json::Value) -> Result<T, ApiError> {
+    serde_json::from_value(body).map_err(|e| {
+        ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_request_error",
+            format!("Invalid request body: {}", e),
+        )
+        .reason("invalid_body")
+    })
+}
+
+// Whether the response lists embeddings by type. Only float embeddings are
+// produced.
+fn float_types(types: Option<&[String]>, param: &'static str) -> Result<bool, ApiError> {
+    let Some(types) = types else {
+        return Ok(false);
+    };
+    match types.iter().find(|kind| kind.as_str() != "float") {
+        None => Ok(true),
+        Some(kind) => Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_request_error",
+            format!("Embedding type {:?} is not supported; only \"float\" is", kind),
+        )
+        .param(param)
+        .reason("invalid_embedding_type")),
+    }
+}
+
+// Unique enough to correlate a response with logs, in UUID format like Cohere's
+fn response_id() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let mut hash = Hash::new();
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos());
+    hash.update(nanos.to_le_bytes());
+    hash.update(COUNTER.fetch_add(1, Ordering::Relaxed).to_le_bytes());
+    let hex: String = hash.finalize()[..16].iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!("{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..])
+}
+
+// Bedrock's error body carries only the message; the kind of error is in the
+// `x-amzn-ErrorType` header
+#[derive(Debug, Serialize)]
+struct BedrockError {
+    message: String,
+}
+
+/// Rewrite the OpenAI-style error bodies of the Bedrock routes into Bedrock's
+/// shape.
+pub(crate) async fn bedrock_errors(req: Request, next: Next) -> Response {
+    let mut response =
+        map_error_body(next.run(req).await, |_, error| BedrockError { message: error.message }).await;
+    let status = response.status();
+    if status.is_client_error() || status.is_server_error() {
+        response
+            .headers_mut()
+            .insert("x-amzn-ErrorType", HeaderValue::from_static(error_type(status)));
+    }
+    response
+}
+
+fn error_type(status: StatusCode) -> &'static str {
+    match status {
+        StatusCode::FORBIDDEN | StatusCode::UNAUTHORIZED => "AccessDeniedException",
+        StatusCode::NOT_FOUND => "ResourceNotFoundException",
+        StatusCode::TOO_MANY_REQUESTS => "ThrottlingException",
+        StatusCode::SERVICE_UNAVAILABLE => "ServiceUnavailableException",
+        _ if status.is_server_error() => "InternalServerException",
+        _ => "ValidationException",
+    }
+}
@@ -0,0 +1,982 @@
+use std::collections::BTreeMap;
+use std::process::{Command, Stdio};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::{Client, Url};
+use serde::Serialize;
+use serde_json::{json, Value};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+use crate::cluster::SplitMix64;
+use crate::embedder::Embedder;
+use crate::{env_flag, standalone_model, StandaloneModel};
+
+// The `api-version` sent to the Azure route; any dated version is accepted
+const AZURE_API_VERSION: &str = "2024-02-01";
+// Longest part of an error body kept for the log
+const ERROR_SNIPPET: usize = 200;
+
+const USAGE: &str = "\
+Usage: semembed bench [OPTIONS]
+
+Load-tests embedding, either in process with the configured model
+(SEMEMBED_MODEL and the inference settings apply; requests go straight to the
+model, without HTTP, queueing or preprocessing) or against a running server
+with --url. Inputs are generated from --seed, so runs with the same options
+send the same texts.
+
+Options:
+  --url URL                 Benchmark the server at URL instead of a model
+                            loaded in process
+  --api FLAVOR              API spoken to --url: openai (default), get, azure,
+                            vertex, bedrock-titan or bedrock-cohere
+  --model NAME              Model to load, or to ask the server for (required
+                            for azure and vertex)
+  --concurrency N           Requests in flight (default 4)
+  --batch DIST              Inputs per request (default 1-32; 1 for get and
+                            bedrock-titan, which take one input)
+  --words DIST              Words per input (default 5-200)
+  --duration SECS           How long to measure (default 30)
+  --warmup SECS             How long to run first without measuring (default 0)
+  --seed N                  Seed of the generated inputs (default 42)
+  --timeout SECS            Give up on a request to --url after this long
+                            (default 60)
+  --header 'NAME: VALUE'    Sent with every request to --url, e.g. an
+                            Authorization header; can be repeated
+  --strict                  Exit with an error if any request failed
+  --json                    Print the report as JSON
+  --compare-affinity        Run in process twice, unpinned and then pinned as
+                            SEMEMBED_CPU_AFFINITY or SEMEMBED_NUMA_AWARE say,
+                            and compare the two
+
+A DIST is N (always N), MIN-MAX (uniform) or weighted values, e.g. 1:8,32:2.
+";
+
+// What the generated inputs are made of: common words, so they tokenize the
+// way prose does
+const WORDS: &[&str] = &[
+    "the", "of", "and", "to", "in", "is", "that", "for", "it", "as", "was", "with", "be", "by", "on", "not", "he",
+    "this", "are", "or", "his", "from", "at", "which", "but", "have", "an", "had", "they", "you", "were", "their",
+    "one", "all", "we", "can", "her", "has", "there", "been", "if", "more", "when", "will", "would", "who", "so", "no",
+    "time", "people", "year", "way", "day", "thing", "world", "life", "hand", "part", "child", "eye", "place", "work",
+    "week", "case", "point", "company", "number", "group", "problem", "fact", "system", "program", "question",
+    "government", "water", "room", "market", "service", "report", "model", "data", "search", "document", "query",
+    "result", "network", "energy", "history", "science", "language", "city", "river", "music", "table", "window",
+    "study", "change", "develop", "measure", "return", "describe", "include", "consider", "important", "different",
+    "large", "small", "early", "local", "public", "recent", "simple", "quickly", "often", "however", "because",
+    "between", "through", "during", "without", "against", "several", "embedding", "semantic", "vector",
+];
+
+// Flavors of the API a server can be benchmarked through
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Api {
+    OpenAi,
+    Get,
+    Azure,
+    Vertex,
+    BedrockTitan,
+    BedrockCohere,
+}
+
+impl Api {
+    fn parse(value: &str) -> anyhow::Result<Self> {
+        Ok(match value {
+            "openai" => Self::OpenAi,
+            "get" => Self::Get,
+            "azure" => Self::Azure,
+            "vertex" => Self::Vertex,
+            "bedrock-titan" => Self::BedrockTitan,
+            "bedrock-cohere" => Self::BedrockCohere,
+            other => bail!(
+                "unknown --api {:?} (expected openai, get, azure, vertex, bedrock-titan or bedrock-cohere)",
+                other
+            ),
+        })
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::OpenAi => "openai",
+            Self::Get => "get",
+            Self::Azure => "azure",
+            Self::Vertex => "vertex",
+            Self::BedrockTitan => "bedrock-titan",
+            Self::BedrockCohere => "bedrock-cohere",
+        }
+    }
+
+    // Flavors whose requests carry a single input
+    fn single_input(self) -> bool {
+        matches!(self, Self::Get | Self::BedrockTitan)
+    }
+}
+
+// How many inputs a request has, or words an input has
+#[derive(Debug, Clone)]
+enum Distribution {
+    Fixed(usize),
+    // Inclusive
+    Uniform(usize, usize),
+    // Values with their cumulative weights
+    Weighted(Vec<(usize, f64)>),
+}
+
+impl Distribution {
+    fn parse(option: &str, value: &str) -> anyhow::Result<Self> {
+        let invalid = || anyhow!("invalid {} {:?} (expected N, MIN-MAX or VALUE:WEIGHT,...)", option, value);
+        let positive = |number: &str| number.trim().parse::<usize>().ok().filter(|&number| number > 0);
+        if value.contains(':') {
+            let mut total = 0.0;
+            let mut weighted = Vec::new();
+            for entry in value.split(',') {
+                let (number, weight) = entry.split_once(':').ok_or_else(invalid)?;
+                let weight: f64 = weight.trim().parse().map_err(|_| invalid())?;
+                if !weight.is_finite() || weight <= 0.0 {
+                    return Err(invalid());
+                }
+                total += weight;
+                weighted.push((positive(number).ok_or_else(invalid)?, total));
+            }
+            return Ok(Self::Weighted(weighted));
+        }
+        if let Some((min, max)) = value.split_once('-') {
+            let (min, max) = (positive(min).ok_or_else(invalid)?, positive(max).ok_or_else(invalid)?);
+            if min > max {
+                return Err(invalid());
+            }
+            return Ok(Self::Uniform(min, max));
+        }
+        Ok(Self::Fixed(positive(value).ok_or_else(invalid)?))
+    }
+
+    fn sample(&self, rng: &mut SplitMix64) -> usize {
+        match self {
+            Self::Fixed(value) => *value,
+            Self::Uniform(min, max) => min + rng.below(max - min + 1),
+            Self::Weighted(weighted) => {
+                let total = weighted.last().map_or(0.0, |&(_, total)| total);
+                let point = rng.unit() * total;
+                weighted.iter().find(|&&(_, cumulative)| point < cumulative).unwrap_or(&weighted[0]).0
+            }
+        }
+    }
+
+    fn max(&self) -> usize {
+        match self {
+            Self::Fixed(value) => *value,
+            Self::Uniform(_, max) => *max,
+            Self::Weighted(weighted) => weighted.iter().map(|&(value, _)| value).max().unwrap_or(0),
+        }
+    }
+}
+
+struct BenchArgs {
+    url: Option<Url>,
+    api: Api,
+    model: Option<String>,
+    concurrency: usize,
+    batch: Distribution,
+    words: Distribution,
+    duration: Duration,
+    warmup: Duration,
+    seed: u64,
+    timeout: Duration,
+    headers: HeaderMap,
+    strict: bool,
+    json: bool,
+    compare_affinity: bool,
+}
+
+impl BenchArgs {
+    fn parse(args: &[String]) -> anyhow::Result<Option<Self>> {
+        let mut batch = None;
+        let mut api = None;
+        let mut parsed = Self {
+            url: None,
+            api: Api::OpenAi,
+            model: None,
+            concurrency: 4,
+            batch: Distribution::Uniform(1, 32),
+            words: Distribution::Uniform(5, 200),
+            duration: Duration::from_secs(30),
+            warmup: Duration::ZERO,
+            seed: 42,
+            timeout: Duration::from_secs(60),
+            headers: HeaderMap::new(),
+            strict: false,
+            json: false,
+            compare_affinity: false,
+        };
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or_else(|| anyhow!("{} needs a value", arg));
+            let number = |value: &String| -> anyhow::Result<u64> {
+                value.parse().map_err(|e| anyhow!("invalid {} {:?}: {}", arg, value, e))
+            };
+            let seconds = |value: &String| -> anyhow::Result<Duration> {
+                value
+                    .parse::<f64>()
+                    .ok()
+                    .filter(|secs| secs.is_finite() && *secs >= 0.0)
+                    .map(Duration::from_secs_f64)
+                    .ok_or_else(|| anyhow!("invalid {} {:?} (expected seconds)", arg, value))
+            };
+            match arg.as_str() {
+                "-h" | "--help" => return Ok(None),
+                "--url" => {
+                    let value = value()?;
+                    let mut url = Url::parse(value)
+                        .ok()
+                        .filter(|url| matches!(url.scheme(), "http" | "https"))
+                        .ok_or_else(|| anyhow!("--url must be an http or https URL, got {:?}", value))?;
+                    // Paths are appended to it, so a base path is kept whole
+                    if !url.path().ends_with('/') {
+                        url.set_path(&format!("{}/", url.path()));
+                    }
+                    parsed.url = Some(url);
+                }
+                "--api" => api = Some(Api::parse(value()?)?),
+                "--model" => parsed.model = Some(value()?.clone()),
+                "--concurrency" => parsed.concurrency = number(value()?)?.max(1) as usize,
+                "--batch" => batch = Some(Distribution::parse(arg, value()?)?),
+                "--words" => parsed.words = Distribution::parse(arg, value()?)?,
+                "--duration" => parsed.duration = seconds(value()?)?,
+                "--warmup" => parsed.warmup = seconds(value()?)?,
+                "--seed" => parsed.seed = number(value()?)?,
+                "--timeout" => parsed.timeout = seconds(value()?)?,
+                "--header" => {
+                    let header = value()?;
+                    let (name, value) = header
+                        .split_once(':')
+                        .and_then(|(name, value)| {
+                            Some((HeaderName::try_from(name.trim()).ok()?, HeaderValue::try_from(value.trim()).ok()?))
+                        })
+                        .ok_or_else(|| anyhow!("invalid --header {:?} (expected 'NAME: VALUE')", header))?;
+                    parsed.headers.append(name, value);
+                }
+                "--strict" => parsed.strict = true,
+                "--json" => parsed.json = true,
+                "--compare-affinity" => parsed.compare_affinity = true,
+                other => bail!("unknown bench option {:?}\n\n{}", other, USAGE),
+            }
+        }
+        if parsed.duration.is_zero() {
+            bail!("--duration must be more than 0");
+        }
+        if let Some(api) = api {
+            if parsed.url.is_none() {
+                bail!("--api needs --url; without it the model is benchmarked in process");
+            }
+            parsed.api = api;
+        }
+        if parsed.compare_affinity && parsed.url.is_some() {
+            bail!("--compare-affinity pins the model loaded in process; it can't be used with --url");
+        }
+        if parsed.url.is_none() && !parsed.headers.is_empty() {
+            bail!("--header needs --url");
+        }
+        if parsed.url.is_some() && matches!(parsed.api, Api::Azure | Api::Vertex) && parsed.model.is_none() {
+            bail!("--api {} needs --model, which goes in the request path", parsed.api.name());
+        }
+        parsed.batch = match batch {
+            Some(batch) if parsed.api.single_input() && batch.max() > 1 => {
+                bail!("--api {} sends one input per request; --batch must be 1", parsed.api.name())
+            }
+            Some(batch) => batch,
+            None if parsed.api.single_input() => Distribution::Fixed(1),
+            None => parsed.batch,
+        };
+        Ok(Some(parsed))
+    }
+}
+
+// Generated inputs: every worker draws from its own stream of the seed
+struct Workload {
+    batch: Distribution,
+    words: Distribution,
+    rng: SplitMix64,
+}
+
+impl Workload {
+    fn next(&mut self) -> Vec<String> {
+        let inputs = self.batch.sample(&mut self.rng);
+        (0..inputs)
+            .map(|_| {
+                let words = self.words.sample(&mut self.rng);
+                let mut text = String::new();
+                for position in 0..words {
+                    if position > 0 {
+                        text.push(' ');
+                    }
+                    text.push_str(WORDS[self.rng.below(WORDS.len())]);
+                }
+                text
+            })
+            .collect()
+    }
+}
+
+// What a request did, or why it failed
+struct Outcome {
+    latency: Duration,
+    embeddings: usize,
+    dimensions: usize,
+    // Not every API reports tokens
+    tokens: Option<usize>,
+}
+
+struct Failure {
+    // The error's count is kept under this, e.g. `http_503` or `timeout`
+    kind: String,
+    message: String,
+}
+
+impl Failure {
+    fn new(kind: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            kind: kind.into(),
+            message: message.into(),
+        }
+    }
+
+    fn invalid(message: impl Into<String>) -> Self {
+        Self::new("invalid_response", message)
+    }
+}
+
+enum Backend {
+    InProcess {
+        embedder: Arc<Embedder>,
+        prefix: &'static str,
+    },
+    Remote {
+        client: Client,
+        url: Url,
+        api: Api,
+        model: Option<String>,
+    },
+}
+
+impl Backend {
+    async fn send(&self, texts: Vec<String>) -> Result<Outcome, Failure> {
+        match self {
+            Self::InProcess { embedder, prefix } => {
+                let embedder = embedder.clone();
+                let texts: Vec<String> = texts.into_iter().map(|text| format!("{}{}", prefix, text)).collect();
+                tokio::task::spawn_blocking(move || {
+                    let started = Instant::now();
+                    let embeddings = embedder
+                        .embed(texts.iter().map(String::as_str).collect(), &CancellationToken::new())
+                        .map_err(|e| Failure::new("inference", e.to_string()))?;
+                    let latency = started.elapsed();
+                    // Counted after the clock stops; the server counts them before inference
+                    let tokens = embedder
+                        .count_tokens(&texts)
+                        .ok()
+                        .map(|counts| counts.iter().map(|count| count.tokens).sum());
+                    Ok(Outcome {
+                        latency,
+                        embeddings: embeddings.len(),
+                        dimensions: embeddings.first().map_or(0, Vec::len),
+                        tokens,
+                    })
+                })
+                .await
+                .map_err(|e| Failure::new("panic", e.to_string()))?
+            }
+            Self::Remote { client, url, api, model } => {
+                let request = remote_request(client, url, *api, model.as_deref(), &texts);
+                let started = Instant::now();
+                let response = request.send().await.map_err(request_failure)?;
+                let status = response.status();
+                let body = response.bytes().await.map_err(request_failure)?;
+                let latency = started.elapsed();
+                if !status.is_success() {
+                    let snippet: String = String::from_utf8_lossy(&body).chars().take(ERROR_SNIPPET).collect();
+                    return Err(Failure::new(format!("http_{}", status.as_u16()), snippet));
+                }
+                let body: Value = serde_json::from_slice(&body)
+                    .map_err(|e| Failure::invalid(format!("the response isn't JSON: {}", e)))?;
+                let (lengths, tokens) = parse_response(*api, &body)?;
+                if lengths.len() != texts.len() {
+                    return Err(Failure::invalid(format!(
+                        "{} embeddings for {} inputs",
+                        lengths.len(),
+                        texts.len()
+                    )));
+                }
+                let dimensions = lengths.first().copied().unwrap_or(0);
+                if dimensions == 0 || lengths.iter().any(|&length| length != dimensions) {
+                    return Err(Failure::invalid(format!("embeddings of uneven or zero length: {:?}", lengths)));
+                }
+                Ok(Outcome {
+                    latency,
+                    embeddings: lengths.len(),
+                    dimensions,
+                    tokens,
+                })
+            }
+        }
+    }
+}
+
+fn request_failure(e: reqwest::Error) -> Failure {
+    let kind = if e.is_timeout() {
+        "timeout"
+    } else if e.is_connect() {
+        "connect"
+    } else {
+        "request"
+    };
+    Failure::new(kind, e.to_string())
+}
+
+// `base` with path segments added; a model name's `/` is encoded as `%2F`
+fn endpoint(base: &Url, segments: &[&str]) -> Url {
+    let mut url = base.clone();
+    if let Ok(mut path) = url.path_segments_mut() {
+        path.pop_if_empty().extend(segments);
+    }
+    url
+}
+
+// The request each flavor sends for `texts`
+fn remote_request(
+    client: &Client,
+    base: &Url,
+    api: Api,
+    model: Option<&str>,
+    texts: &[String],
+) -> reqwest::RequestBuilder {
+    let post = |url: Url, body: Value| {
+        client
+            .post(url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body.to_string())
+    };
+    match api {
+        Api::OpenAi => {
+            let mut body = json!({"input": texts});
+            if let Some(model) = model {
+                body["model"] = json!(model);
+            }
+            post(endpoint(base, &["v1", "embeddings"]), body)
+        }
+        Api::Get => {
+            let mut url = endpoint(base, &["v1", "embeddings"]);
+            url.query_pairs_mut().append_pair("input", texts.first().map_or("", String::as_str));
+            if let Some(model) = model {
+                url.query_pairs_mut().append_pair("model", model);
+            }
+            client.get(url)
+        }
+        Api::Azure => {
+            let mut url = endpoint(base, &["openai", "deployments", model.unwrap_or_default(), "embeddings"]);
+            url.query_pairs_mut().append_pair("api-version", AZURE_API_VERSION);
+            post(url, json!({"input": texts}))
+        }
+        Api::Vertex => {
+            let predict = format!("{}:predict", model.unwrap_or_default());
+            let path = [
+                "v1", "projects", "bench", "locations", "us-central1", "publishers", "google", "models", &predict,
+            ];
+            let instances: Vec<Value> = texts.iter().map(|text| json!({"content": text})).collect();
+            post(endpoint(base, &path), json!({"instances": instances}))
+        }
+        Api::BedrockTitan => {
+            let model = model.unwrap_or("amazon.titan-embed-text-v2:0");
+            post(
+                endpoint(base, &["model", model, "invoke"]),
+                json!({"inputText": texts.first().map_or("", String::as_str)}),
+            )
+        }
+        Api::BedrockCohere => {
+            let model = model.unwrap_or("cohere.embed-english-v3");
+            post(
+                endpoint(base, &["model", model, "invoke"]),
+                json!({"texts": texts, "input_type": "search_document"}),
+            )
+        }
+    }
+}
+
+// Each embedding's length, and the tokens the response reports
+fn parse_response(api: Api, body: &Value) -> Result<(Vec<usize>, Option<usize>), Failure> {
+    let length = |value: &Value| value.as_array().map(Vec::len);
+    let missing = |field: &str| Failure::invalid(format!("the response has no {}", field));
+    match api {
+        Api::OpenAi | Api::Get | Api::Azure => {
+            let data = body["data"].as_array().ok_or_else(|| missing("data"))?;
+            let lengths = data
+                .iter()
+                .map(|item| length(&item["embedding"]))
+                .collect::<Option<Vec<_>>>()
+                .ok_or_else(|| missing("float embedding in every item"))?;
+            Ok((lengths, body["usage"]["total_tokens"].as_u64().map(|tokens| tokens as usize)))
+        }
+        Api::Vertex => {
+            let predictions = body["predictions"].as_array().ok_or_else(|| missing("predictions"))?;
+            let lengths = predictions
+                .iter()
+                .map(|prediction| length(&prediction["embeddings"]["values"]))
+                .collect::<Option<Vec<_>>>()
+                .ok_or_else(|| missing("values in every prediction"))?;
+            let tokens = predictions
+                .iter()
+                .map(|prediction| prediction["embeddings"]["statistics"]["token_count"].as_u64())
+                .sum::<Option<u64>>();
+            Ok((lengths, tokens.map(|tokens| tokens as usize)))
+        }
+        Api::BedrockTitan => {
+            let length = length(&body["embedding"]).ok_or_else(|| missing("embedding"))?;
+            Ok((vec![length], body["inputTextTokenCount"].as_u64().map(|tokens| tokens as usize)))
+        }
+        Api::BedrockCohere => {
+            let embeddings = body["embeddings"].as_array().ok_or_else(|| missing("embeddings"))?;
+            let lengths = embeddings
+                .iter()
+                .map(length)
+                .collect::<Option<Vec<_>>>()
+                .ok_or_else(|| missing("float embeddings"))?;
+            Ok((lengths, None))
+        }
+    }
+}
+
+// One worker's measurements
+#[derive(Default)]
+struct Samples {
+    latencies: Vec<Duration>,
+    embeddings: usize,
+    tokens: usize,
+    // Whether every successful request reported its tokens
+    tokens_missing: bool,
+    errors: BTreeMap<String, usize>,
+}
+
+impl Samples {
+    fn merge(&mut self, other: Self) {
+        self.latencies.extend(other.latencies);
+        self.embeddings += other.embeddings;
+        self.tokens += other.tokens;
+        self.tokens_missing |= other.tokens_missing;
+        for (kind, count) in other.errors {
+            *self.errors.entry(kind).or_insert(0) += count;
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Report {
+    target: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    api: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    model: Option<String>,
+    concurrency: usize,
+    seed: u64,
+    duration_secs: f64,
+    warmup_secs: f64,
+    requests: usize,
+    failed: usize,
+    requests_per_sec: f64,
+    embeddings: usize,
+    embeddings_per_sec: f64,
+    // Absent when the API doesn't report them
+    tokens: Option<usize>,
+    tokens_per_sec: Option<f64>,
+    dimensions: Option<usize>,
+    latency_ms: Latency,
+    errors: BTreeMap<String, usize>,
+}
+
+// Of the successful requests
+#[derive(Debug, Serialize)]
+struct Latency {
+    mean: f64,
+    p50: f64,
+    p90: f64,
+    p99: f64,
+    max: f64,
+}
+
+impl Latency {
+    fn of(mut latencies: Vec<Duration>) -> Self {
+        latencies.sort_unstable();
+        let ms = |duration: Duration| duration.as_secs_f64() * 1000.0;
+        // Nearest rank
+        let percentile = |p: f64| {
+            let rank = ((p * latencies.len() as f64).ceil() as usize).clamp(1, latencies.len().max(1));
+            latencies.get(rank - 1).copied().map_or(0.0, ms)
+        };
+        let total: Duration = latencies.iter().sum();
+        Self {
+            mean: if latencies.is_empty() { 0.0 } else { ms(total) / latencies.len() as f64 },
+            p50: percentile(0.5),
+            p90: percentile(0.9),
+            p99: percentile(0.99),
+            max: latencies.last().copied().map_or(0.0, ms),
+        }
+    }
+}
+
+impl Report {
+    fn table(&self) -> String {
+        let mut target = self.target.clone();
+        if let Some(api) = self.api {
+            target = format!("{} ({})", target, api);
+        }
+        if let Some(model) = &self.model {
+            target = format!("{}, model {}", target, model);
+        }
+        let tokens = match (self.tokens, self.tokens_per_sec) {
+            (Some(tokens), Some(rate)) => format!("{} ({:.1}/s)", tokens, rate),
+            _ => "not reported".to_string(),
+        };
+        let latency = &self.latency_ms;
+        let mut rows = vec![
+            ("target", target),
+            ("concurrency", self.concurrency.to_string()),
+            ("duration", format!("{:.1}s (warmup {:.1}s, seed {})", self.duration_secs, self.warmup_secs, self.seed)),
+            ("requests", format!("{} ({:.1}/s, {} failed)", self.requests, self.requests_per_sec, self.failed)),
+            ("embeddings", format!("{} ({:.1}/s)", self.embeddings, self.embeddings_per_sec)),
+            ("tokens", tokens),
+            ("dimensions", self.dimensions.map_or_else(|| "-".to_string(), |dimensions| dimensions.to_string())),
+            ("latency mean", format!("{:.1} ms", latency.mean)),
+            ("latency p50", format!("{:.1} ms", latency.p50)),
+            ("latency p90", format!("{:.1} ms", latency.p90)),
+            ("latency p99", format!("{:.1} ms", latency.p99)),
+            ("latency max", format!("{:.1} ms", latency.max)),
+        ];
+        if !self.errors.is_empty() {
+            let errors: Vec<String> = self.errors.iter().map(|(kind, count)| format!("{} {}", kind, count)).collect();
+            rows.push(("errors", errors.join(", ")));
+        }
+        let width = rows.iter().map(|(name, _)| name.len()).max().unwrap_or(0);
+        rows.iter().map(|(name, value)| format!("{:width$}  {}\n", name, value, width = width)).collect()
+    }
+}
+
+/// Run `semembed bench` with the arguments that follow the subcommand.
+pub fn run(args: &[String]) -> anyhow::Result<()> {
+    let Some(parsed) = BenchArgs::parse(args)? else {
+        print!("{}", USAGE);
+        return Ok(());
+    };
+    if parsed.compare_affinity {
+        return compare_affinity(args, parsed.json);
+    }
+    let args = parsed;
+    let (backend, target, model) = match &args.url {
+        Some(url) => (remote(url, &args)?, url.to_string(), args.model.clone()),
+        None => {
+            let model_name = args
+                .model
+                .clone()
+                .or_else(|| std::env::var("SEMEMBED_MODEL").ok())
+                .unwrap_or_else(|| "BAAI/bge-small-en-v1.5".to_string());
+            let StandaloneModel { spec, embedder, .. } = standalone_model(&model_name)?;
+            let backend = Backend::InProcess {
+                embedder,
+                prefix: spec.prefix(None).unwrap_or_default(),
+            };
+            (backend, "in process".to_string(), Some(spec.name.to_string()))
+        }
+    };
+
+    info!(
+        "Benchmarking {} with {} concurrent request(s) for {:?}{}",
+        target,
+        args.concurrency,
+        args.duration,
+        if args.warmup.is_zero() { String::new() } else { format!(" after {:?} of warmup", args.warmup) }
+    );
+    let runtime = tokio::runtime::Builder::new_multi_thread().enable_all().build()?;
+    let (samples, elapsed, dimensions) = runtime.block_on(drive(Arc::new(backend), &args));
+
+    let requests = samples.latencies.len() + samples.errors.values().sum::<usize>();
+    let failed = requests - samples.latencies.len();
+    let secs = elapsed.as_secs_f64();
+    let tokens = (!samples.tokens_missing && !samples.latencies.is_empty()).then_some(samples.tokens);
+    let report = Report {
+        target,
+        api: args.url.is_some().then(|| args.api.name()),
+        model,
+        concurrency: args.concurrency,
+        seed: args.seed,
+        duration_secs: secs,
+        warmup_secs: args.warmup.as_secs_f64(),
+        requests,
+        failed,
+        requests_per_sec: requests as f64 / secs,
+        embeddings: samples.embeddings,
+        embeddings_per_sec: samples.embeddings as f64 / secs,
+        tokens,
+        tokens_per_sec: tokens.map(|tokens| tokens as f64 / secs),
+        dimensions,
+        latency_ms: Latency::of(samples.latencies),
+        errors: samples.errors,
+    };
+
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        print!("{}", report.table());
+    }
+    if args.strict && report.failed > 0 {
+        bail!("{} of {} requests failed", report.failed, report.requests);
+    }
+    Ok(())
+}
+
+// The server at `url`, spoken to as `args` say
+fn remote(url: &Url, args: &BenchArgs) -> anyhow::Result<Backend> {
+    let client = Client::builder()
+        .timeout(args.timeout)
+        .pool_max_idle_per_host(args.concurrency)
+        .default_headers(args.headers.clone())
+        .build()?;
+    Ok(Backend::Remote {
+        client,
+        url: url.clone(),
+        api: args.api,
+        model: args.model.clone(),
+    })
+}
+
+// Run the benchmark unpinned and then pinned, each in a process of its own:
+// ONNX Runtime's thread pool is set up once per process, so one run can't
+// undo the other's pinning.
+fn compare_affinity(args: &[String], json: bool) -> anyhow::Result<()> {
+    let affinity = std::env::var("SEMEMBED_CPU_AFFINITY").is_ok_and(|value| !value.trim().is_empty());
+    if !affinity && !env_flag("SEMEMBED_NUMA_AWARE")? {
+        bail!("--compare-affinity needs SEMEMBED_CPU_AFFINITY or SEMEMBED_NUMA_AWARE to pin the second run");
+    }
+    let exe = std::env::current_exe()?;
+    let args: Vec<&String> = args.iter().filter(|arg| !matches!(arg.as_str(), "--compare-affinity" | "--json")).collect();
+    let mut reports = Vec::with_capacity(2);
+    for pinned in [false, true] {
+        let mut command = Command::new(&exe);
+        command.arg("bench").args(&args).arg("--json").stderr(Stdio::inherit());
+        if !pinned {
+            command.env_remove("SEMEMBED_CPU_AFFINITY").env_remove("SEMEMBED_NUMA_AWARE");
+        }
+        let run = if pinned { "pinned" } else { "unpinned" };
+        info!("Benchmarking {}", run);
+        let output = command.output()?;
+        if !output.status.success() {
+            bail!("the {} run failed ({})", run, output.status);
+        }
+        let report: Value = serde_json::from_slice(&output.stdout)
+            .map_err(|e| anyhow!("the {} run printed an invalid report: {}", run, e))?;
+        reports.push(report);
+    }
+    let (unpinned, pinned) = (&reports[0], &reports[1]);
+    if json {
+        println!("{}", serde_json::to_string_pretty(&json!({ "unpinned": unpinned, "pinned": pinned }))?);
+    } else {
+        print!("{}", comparison(unpinned, pinned));
+    }
+    Ok(())
+}
+
+// The throughput and latency of two reports side by side
+fn comparison(unpinned: &Value, pinned: &Value) -> String {
+    let rows = [
+        ("embeddings/s", "/embeddings_per_sec"),
+        ("tokens/s", "/tokens_per_sec"),
+        ("latency mean (ms)", "/latency_ms/mean"),
+        ("latency p50 (ms)", "/latency_ms/p50"),
+        ("latency p90 (ms)", "/latency_ms/p90"),
+        ("latency p99 (ms)", "/latency_ms/p99"),
+    ];
+    let width = rows.iter().map(|(name, _)| name.len()).max().unwrap_or(0);
+    let mut table = format!("{:width$}  {:>10}  {:>10}  {:>8}\n", "", "unpinned", "pinned", "change", width = width);
+    for (name, pointer) in rows {
+        let (Some(before), Some(after)) = (
+            unpinned.pointer(pointer).and_then(Value::as_f64),
+            pinned.pointer(pointer).and_then(Value::as_f64),
+        ) else {
+            continue;
+        };
+        let change = if before > 0.0 { format!("{:+.1}%", (after / before - 1.0) * 100.0) } else { "-".to_string() };
+        table += &format!("{:width$}  {:>10.1}  {:>10.1}  {:>8}\n", name, before, after, change, width = width);
+    }
+    table
+}
+
+// Keep `concurrency` requests in flight until the time is up. Returns what
+// was measured after the warmup, over how long, and the embeddings' length.
+async fn drive(backend: Arc<Backend>, args: &BenchArgs) -> (Samples, Duration, Option<usize>) {
+    let measured_from = Instant::now() + args.warmup;
+    let end = measured_from + args.duration;
+    // The first embedding's length, which every later one must match
+    let dimensions: Arc<OnceLock<usize>> = Arc::default();
+    let mut seeds = SplitMix64(args.seed);
+    let workers: Vec<_> = (0..args.concurrency)
+        .map(|_| {
+            let backend = backend.clone();
+            let dimensions = dimensions.clone();
+            let mut workload = Workload {
+                batch: args.batch.clone(),
+                words: args.words.clone(),
+                rng: SplitMix64(seeds.next()),
+            };
+            tokio::spawn(async move {
+                let mut samples = Samples::default();
+                while Instant::now() < end {
+                    let started = Instant::now();
+                    let outcome = backend.send(workload.next()).await.and_then(|outcome| {
+                        let expected = *dimensions.get_or_init(|| outcome.dimensions);
+                        if outcome.embeddings > 0 && outcome.dimensions != expected {
+                            return Err(Failure::invalid(format!(
+                                "embeddings of {} dimensions after earlier ones of {}",
+                                outcome.dimensions, expected
+                            )));
+                        }
+                        Ok(outcome)
+                    });
+                    if started < measured_from {
+                        continue;
+                    }
+                    match outcome {
+                        Ok(outcome) => {
+                            samples.latencies.push(outcome.latency);
+                            samples.embeddings += outcome.embeddings;
+                            match outcome.tokens {
+                                Some(tokens) => samples.tokens += tokens,
+                                None => samples.tokens_missing = true,
+                            }
+                        }
+                        Err(failure) => {
+                            let count = samples.errors.entry(failure.kind.clone()).or_insert(0);
+                            // Each kind of error is logged once per worker
+                            if *count == 0 {
+                                warn!("Request failed ({}): {}", failure.kind, failure.message);
+                            }
+                            *count += 1;
+                        }
+                    }
+                }
+                samples
+            })
+        })
+        .collect();
+
+    let mut samples = Samples::default();
+    for worker in workers {
+        match worker.await {
+            Ok(worker) => samples.merge(worker),
+            Err(e) => warn!("A benchmark worker failed: {}", e),
+        }
+    }
+    let elapsed = Instant::now().saturating_duration_since(measured_from);
+    (samples, elapsed, dimensions.get().copied())
+}
+
+#[cfg(test)]
+mod tests {
+    use semembed::test_util::{spawn_test_server, TestConfig, TestServer};
+
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    // Drive `server` for a moment with `options` on top of a few defaults
+    async fn bench(server: &TestServer, options: &[&str]) -> (Samples, Option<usize>) {
+        let mut all = vec!["--url", &server.base_url, "--concurrency", "2", "--duration", "0.3", "--seed", "7"];
+        all.extend(options);
+        let args = BenchArgs::parse(&args(&all)).unwrap().unwrap();
+        let backend = remote(args.url.as_ref().unwrap(), &args).unwrap();
+        let (samples, _, dimensions) = drive(Arc::new(backend), &args).await;
+        (samples, dimensions)
+    }
+
+    #[tokio::test]
+    async fn servers_are_measured_through_the_api() {
+        let server = spawn_test_server(TestConfig {
+            dimensions: 16,
+            ..TestConfig::default()
+        })
+        .await
+        .unwrap();
+        let (samples, dimensions) =
+            bench(&server, &["--batch", "3", "--model", "test-model", "--header", "Authorization: Bearer k"]).await;
+
+        let requests = server.requests();
+        assert!(!samples.latencies.is_empty() && samples.errors.is_empty(), "{:?}", samples.errors);
+        assert_eq!(dimensions, Some(16));
+        assert_eq!(samples.embeddings, samples.latencies.len() * 3);
+        // Tokens come from the responses' usage
+        assert!(!samples.tokens_missing && samples.tokens > 0);
+        // Requests in flight when the time ran out are sent but not counted
+        assert!(requests.len() >= samples.latencies.len());
+        for request in &requests {
+            assert_eq!(request.inputs.len(), 3);
+            assert_eq!(request.body["model"], "test-model");
+            assert_eq!(request.authorization.as_deref(), Some("Bearer k"));
+        }
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn failures_are_counted_by_status() {
+        let server = spawn_test_server(TestConfig::default()).await.unwrap();
+        server.fail_next(3);
+        let (samples, _) = bench(&server, &["--batch", "1"]).await;
+
+        assert_eq!(samples.errors, BTreeMap::from([("http_503".to_string(), 3)]));
+        assert!(!samples.latencies.is_empty());
+        assert_eq!(server.requests().iter().filter(|request| request.status == 503).count(), 3);
+
+        // A server that doesn't answer in time
+        server.set_latency(Some(Duration::from_millis(300)));
+        let (samples, _) = bench(&server, &["--batch", "1", "--timeout", "0.05"]).await;
+        assert!(samples.latencies.is_empty());
+        assert!(samples.errors["timeout"] > 0, "{:?}", samples.errors);
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn latency_is_measured_per_request() {
+        let server = spawn_test_server(TestConfig {
+            latency: Some(Duration::from_millis(50)),
+            ..TestConfig::default()
+        })
+        .await
+        .unwrap();
+        let (samples, _) = bench(&server, &["--batch", "1"]).await;
+        let latency = Latency::of(samples.latencies);
+        assert!(latency.p50 >= 50.0 && latency.max < 1000.0, "{:?}", latency);
+        server.shutdown().await;
+    }
+
+    #[test]
+    fn affinity_runs_are_compared_side_by_side() {
+        let report = |embeddings: f64, p50: f64| {
+            json!({
+                "embeddings_per_sec": embeddings,
+                "tokens_per_sec": null,
+                "latency_ms": { "mean": p50, "p50": p50, "p90": p50, "p99": p50 },
+            })
+        };
+        let table = comparison(&report(200.0, 40.0), &report(250.0, 30.0));
+        let lines: Vec<&str> = table.lines().collect();
+        assert!(lines[1].starts_with("embeddings/s") && lines[1].ends_with("+25.0%"), "{}", table);
+        // Not reported by either run, so left out
+        assert!(!table.contains("tokens/s"));
+        assert!(lines[3].starts_with("latency p50") && lines[3].ends_with("-25.0%"), "{}", table);
+    }
+
+    #[test]
+    fn affinity_comparison_runs_in_process() {
+        assert!(BenchArgs::parse(&args(&["--compare-affinity"])).unwrap().unwrap().compare_affinity);
+        assert!(BenchArgs::parse(&args(&["--compare-affinity", "--url", "http://localhost:8081"])).is_err());
+    }
+}
@@ -0,0 +1,223 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use prometheus::IntGauge;
+use tracing::{info, warn};
+
+// Inputs per sub-batch however short they are, as fastembed's default batch
+const MAX_BATCH_INPUTS: usize = 256;
+// The budget never shrinks below this, about one input at a typical maximum
+// sequence length; a longer input still runs, alone
+const MIN_BATCH_TOKENS: usize = 512;
+// Share of the ceiling the budget grows back by after each sub-batch that ran
+// without pressure
+const GROWTH_DIVISOR: usize = 32;
+
+/// Sub-batch sizing settings.
+#[derive(Debug, Clone, Copy)]
+pub struct BudgetConfig {
+    /// Largest padded batch, in tokens (inputs times the longest input).
+    pub max_tokens: usize,
+    /// Resident memory growth during one sub-batch that counts as pressure;
+    /// `None` ignores memory growth.
+    pub rss_growth: Option<u64>,
+}
+
+/// How many inputs go into each inference sub-batch: as many as fit a token
+/// budget, counting every input at the length of the longest since the batch
+/// is padded to it.
+///
+/// The budget starts at the configured ceiling, halves whenever a sub-batch
+/// fails to allocate or grows resident memory by more than `rss_growth`, and
+/// grows back by a fraction of the ceiling after each sub-batch that doesn't.
+pub struct BatchBudget {
+    config: BudgetConfig,
+    floor: usize,
+    current: AtomicUsize,
+    gauge: IntGauge,
+}
+
+impl BatchBudget {
+    pub fn new(config: BudgetConfig, gauge: IntGauge) -> Self {
+        let max_tokens = config.max_tokens.max(1);
+        match config.rss_growth {
+            Some(growth) => info!(
+                "Inference sub-batches: up to {} padded tokens, halved after a failed allocation \
+                 or {} bytes of memory growth",
+                max_tokens, growth
+            ),
+            None => info!(
+                "Inference sub-batches: up to {} padded tokens, halved after a failed allocation",
+                max_tokens
+            ),
+        }
+        gauge.set(max_tokens as i64);
+        Self {
+            config: BudgetConfig { max_tokens, ..config },
+            floor: MIN_BATCH_TOKENS.min(max_tokens),
+            current: AtomicUsize::new(max_tokens),
+            gauge,
+        }
+    }
+
+    /// The budget in effect, in padded tokens per sub-batch.
+    pub fn tokens(&self) -> usize {
+        self.current.load(Ordering::Relaxed)
+    }
+
+    /// Whether sub-batches should be bracketed with resident memory readings.
+    pub fn watches_memory(&self) -> bool {
+        self.config.rss_growth.is_some()
+    }
+
+    /// Inputs, from the front of `lengths` (in tokens), that make up the next
+    /// sub-batch; at least one.
+    pub fn next_batch(&self, lengths: &[usize]) -> usize {
+        let budget = self.tokens();
+        let mut longest = 0;
+        let mut count = 0;
+        for &length in lengths.iter().take(MAX_BATCH_INPUTS) {
+            longest = longest.max(length.max(1));
+            if count > 0 && (count + 1) * longest > budget {
+                break;
+            }
+            count += 1;
+        }
+        count.max(1).min(lengths.len())
+    }
+
+    /// Record a sub-batch that ran, with the resident memory before and after
+    /// it when memory is watched.
+    pub fn completed(&self, rss: Option<(u64, u64)>) {
+        if let (Some(limit), Some((before, after))) = (self.config.rss_growth, rss) {
+            let growth = after.saturating_sub(before);
+            if growth > limit {
+                self.shrink(&format!("resident memory grew by {} bytes during one sub-batch", growth));
+                return;
+            }
+        }
+        let ceiling = self.config.max_tokens;
+        let step = (ceiling / GROWTH_DIVISOR).max(1);
+        let previous = self.update(|current| (current + step).min(ceiling));
+        if previous < ceiling && previous + step >= ceiling {
+            info!("Inference sub-batch budget back at {} tokens", ceiling);
+        }
+    }
+
+    /// Halve the budget after a sub-batch failed to allocate memory.
+    pub fn shrink(&self, cause: &str) {
+        let floor = self.floor;
+        let previous = self.update(|current| (current / 2).max(floor));
+        if previous > floor {
+            warn!(
+                "Inference sub-batch budget lowered from {} to {} tokens: {}",
+                previous,
+                (previous / 2).max(floor),
+                cause
+            );
+        }
+    }
+
+    // Apply `f` to the budget, returning the previous value
+    fn update(&self, f: impl Fn(usize) -> usize) -> usize {
+        let previous = self
+            .current
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |current| Some(f(current)))
+            .unwrap_or_else(|current| current);
+        self.gauge.set(f(previous) as i64);
+        previous
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn budget(max_tokens: usize, rss_growth: Option<u64>) -> BatchBudget {
+        let gauge = IntGauge::new("batch_token_budget", "test").unwrap();
+        BatchBudget::new(BudgetConfig { max_tokens, rss_growth }, gauge)
+    }
+
+    // Inputs times the longest of them, which is what a padded batch costs
+    fn padded(lengths: &[usize]) -> usize {
+        lengths.len() * lengths.iter().copied().max().unwrap_or(0)
+    }
+
+    #[test]
+    fn batches_fit_the_budget() {
+        let budget = budget(1000, None);
+        let lengths = [100, 100, 300, 50, 50, 50, 900, 2000, 10];
+        let mut sizes = Vec::new();
+        let mut start = 0;
+        while start < lengths.len() {
+            let count = budget.next_batch(&lengths[start..]);
+            let batch = &lengths[start..start + count];
+            assert!(padded(batch) <= 1000 || count == 1, "{:?}", batch);
+            sizes.push(count);
+            start += count;
+        }
+        // The longest input of each batch sets its cost; one over the budget
+        // still runs, alone
+        assert_eq!(sizes, [3, 3, 1, 1, 1]);
+        assert_eq!(budget.next_batch(&[]), 0);
+        // Short inputs stop at fastembed's batch size
+        assert_eq!(budget.next_batch(&[1; 600]), MAX_BATCH_INPUTS);
+    }
+
+    #[test]
+    fn shrinks_under_pressure_and_grows_back() {
+        let budget = budget(32_768, Some(1 << 30));
+        budget.completed(Some((1 << 30, 3 << 30)));
+        assert_eq!(budget.tokens(), 16_384);
+        assert_eq!(budget.gauge.get(), 16_384);
+        // Growth within the limit is no pressure
+        budget.completed(Some((1 << 30, 3 << 29)));
+        assert_eq!(budget.tokens(), 16_384 + 1024);
+        (0..10).for_each(|_| budget.shrink("allocation failed"));
+        assert_eq!(budget.tokens(), MIN_BATCH_TOKENS);
+        (0..100).for_each(|_| budget.completed(None));
+        assert_eq!(budget.tokens(), 32_768);
+        assert_eq!(budget.gauge.get(), 32_768);
+
+        // Without a growth limit, memory readings are ignored
+        let budget = self::budget(4096, None);
+        assert!(!budget.watches_memory());
+        budget.completed(Some((0, u64::MAX)));
+        assert_eq!(budget.tokens(), 4096);
+    }
+
+    // Bytes a mock model allocates per padded token
+    const BYTES_PER_TOKEN: u64 = 4096;
+
+    // Embeds very long documents against a mock model that fails to allocate
+    // past `limit` bytes and reports its resident memory growing with each
+    // batch's padded size, the way the embedder drives the budget
+    #[test]
+    fn long_documents_stay_within_the_memory_bound() {
+        let limit = 12_000 * BYTES_PER_TOKEN;
+        let budget = budget(32_768, Some(10_000 * BYTES_PER_TOKEN));
+        let lengths: Vec<usize> = (0..500).map(|i| [8192, 30, 4000, 512, 7000, 1][i % 6] + i).collect();
+
+        let mut embeddings: Vec<usize> = Vec::new();
+        let (mut peak, mut failures) = (0, 0);
+        while embeddings.len() < lengths.len() {
+            let start = embeddings.len();
+            let end = start + budget.next_batch(&lengths[start..]);
+            let bytes = padded(&lengths[start..end]) as u64 * BYTES_PER_TOKEN;
+            if bytes > limit && end - start > 1 {
+                failures += 1;
+                budget.shrink("allocation failed");
+                continue;
+            }
+            peak = peak.max(bytes);
+            budget.completed(Some((0, bytes)));
+            embeddings.extend(start..end);
+        }
+
+        // Every input once, in order
+        assert_eq!(embeddings, (0..lengths.len()).collect::<Vec<_>>());
+        assert!(peak <= limit, "{} bytes", peak);
+        assert!(failures > 0);
+        // The budget settled below the ceiling the long inputs can't run at
+        assert!(budget.tokens() < 32_768, "{}", budget.tokens());
+    }
+}
@@ -0,0 +1,468 @@
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::{
+    body::{Body, Bytes},
+    extract::{rejection::QueryRejection, Query, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use tracing::warn;
+
+use crate::embedder::TokenCount;
+use crate::error::ApiError;
+use crate::extract::Scheduling;
+use crate::models::InputKind;
+use crate::{
+    admit, charge, count_tokens, oversized_metadata, run_embedder_partial, shorten, AppState, EmbeddingData,
+    EncodingFormat, ItemError, Truncation,
+};
+
+// Lines embedded together; a partial batch is flushed when the body stalls
+const BATCH_LINES: usize = 64;
+const FLUSH_AFTER: Duration = Duration::from_millis(50);
+// Result lines buffered ahead of a slow reader before embedding pauses
+const OUTPUT_BUFFER: usize = 2 * BATCH_LINES;
+
+/// Limits of `/v1/embeddings/bulk`.
+#[derive(Debug, Clone, Copy)]
+pub struct BulkLimits {
+    pub max_line_bytes: usize,
+    pub max_lines: usize,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct BulkParams {
+    model: Option<String>,
+    // Bulk uploads are documents, so this defaults to passage
+    input_type: Option<InputKind>,
+    dimensions: Option<usize>,
+    #[serde(default)]
+    encoding_format: EncodingFormat,
+}
+
+#[derive(Deserialize)]
+struct BulkLine {
+    id: serde_json::Value,
+    text: String,
+    // Echoed back with the line's result
+    #[serde(default)]
+    metadata: Option<serde_json::Value>,
+}
+
+// One line of the response: an embedding or an error for one input line
+#[derive(Serialize)]
+struct BulkResult {
+    id: serde_json::Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metadata: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    embedding: Option<EmbeddingData>,
+    // How much of an input over the model's maximum length was cut
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    truncation: Option<Truncation>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    line: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<ItemError>,
+}
+
+impl BulkResult {
+    fn failed(id: serde_json::Value, line: usize, message: impl Into<String>, code: &'static str) -> Self {
+        Self {
+            id,
+            metadata: None,
+            embedding: None,
+            truncation: None,
+            line: Some(line),
+            error: Some(ItemError {
+                message: message.into(),
+                code,
+            }),
+        }
+    }
+}
+
+// A parsed line waiting for its batch
+struct Pending {
+    id: serde_json::Value,
+    metadata: Option<serde_json::Value>,
+    line: usize,
+    text: String,
+}
+
+/// `POST /v1/embeddings/bulk`: an `application/x-ndjson` body of `{id, text}`
+/// lines (with optional `metadata`), embedded in rolling batches as it
+/// arrives, with one `{id, embedding}` or `{id, line, error}` line streamed
+/// back per input line, carrying the line's metadata.
+/// Neither side holds the whole dataset, and processing stops when the
+/// client disconnects.
+pub(crate) async fn bulk_embeddings(
+    State(state): State<Arc<AppState>>,
+    params: Result<Query<BulkParams>, QueryRejection>,
+    headers: HeaderMap,
+    scheduling: Scheduling,
+    body: Body,
+) -> Result<Response, ApiError> {
+    let content_type = headers.get(header::CONTENT_TYPE).and_then(|value| value.to_str().ok()).unwrap_or_default();
+    let media_type = content_type.split(';').next().unwrap_or_default().trim();
+    if !["application/x-ndjson", "application/jsonl"].iter().any(|ndjson| media_type.eq_ignore_ascii_case(ndjson)) {
+        return Err(ApiError::new(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "invalid_request_error",
+            "Expected request with `Content-Type: application/x-ndjson`",
+        )
+        .reason("unsupported_media_type"));
+    }
+    let Query(params) = params.map_err(|rejection| {
+        ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_request_error",
+            format!("Invalid query string: {}", rejection.body_text()),
+        )
+        .reason("invalid_query")
+    })?;
+    params.encoding_format.check_per_item()?;
+    let settings = state.settings();
+    if settings.resolver.resolve(params.model.as_deref()).is_none() {
+        return Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            "model_not_found",
+            format!("The model `{}` does not exist", params.model.as_deref().unwrap_or_default()),
+        ));
+    }
+    if let Some(requested) = params.dimensions {
+        if requested == 0 || requested > state.metadata.dimensions {
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "invalid_request_error",
+                format!("dimensions must be between 1 and {}, got {}", state.metadata.dimensions, requested),
+            )
+            .param("dimensions")
+            .reason("invalid_dimensions"));
+        }
+    }
+
+    let (tx, mut rx) = mpsc::channel::<Bytes>(OUTPUT_BUFFER);
+    tokio::spawn(process(state, params, scheduling, body, tx));
+    let stream = futures_util::stream::poll_fn(move |cx| rx.poll_recv(cx).map(|line| line.map(Ok::<_, Infallible>)));
+    Ok((
+        [(header::CONTENT_TYPE, HeaderValue::from_static("application/x-ndjson"))],
+        Body::from_stream(stream),
+    )
+        .into_response())
+}
+
+// Splits body chunks into lines, dropping any line over the limit
+pub(crate) struct Lines {
+    buffer: Vec<u8>,
+    max_bytes: usize,
+    // Set while skipping the rest of an over-long line
+    skipping: bool,
+}
+
+// A line, or `None` for one over the limit
+pub(crate) type Line = Option<Vec<u8>>;
+
+impl Lines {
+    pub(crate) fn new(max_bytes: usize) -> Self {
+        Self {
+            buffer: Vec::new(),
+            max_bytes,
+            skipping: false,
+        }
+    }
+
+    pub(crate) fn push(&mut self, chunk: &[u8]) -> Vec<Line> {
+        let mut lines = Vec::new();
+        for piece in chunk.split_inclusive(|&b| b == b'\n') {
+            let complete = piece.ends_with(b"\n");
+            let piece = piece.strip_suffix(b"\n").unwrap_or(piece);
+            if !self.skipping {
+                self.buffer.extend_from_slice(piece);
+                if self.buffer.len() > self.max_bytes {
+                    self.buffer = Vec::new();
+                    self.skipping = true;
+                    lines.push(None);
+                }
+            }
+            if complete {
+                if self.skipping {
+                    self.skipping = false;
+                } else {
+                    lines.push(Some(std::mem::take(&mut self.buffer)));
+                }
+            }
+        }
+        lines
+    }
+
+    // The last line, if the body doesn't end with a newline
+    pub(crate) fn finish(&mut self) -> Option<Line> {
+        (!self.skipping && !self.buffer.is_empty()).then(|| Some(std::mem::take(&mut self.buffer)))
+    }
+}
+
+// Read lines from the body, embed them a batch at a time and send back the
+// results, until the body ends or the client goes away
+async fn process(state: Arc<AppState>, params: BulkParams, scheduling: Scheduling, body: Body, tx: mpsc::Sender<Bytes>) {
+    let limits = state.bulk_limits;
+    let mut chunks = body.into_data_stream();
+    let mut lines = Lines::new(limits.max_line_bytes);
+    let mut number = 0;
+    let mut batch: Vec<Pending> = Vec::new();
+
+    loop {
+        // Wait for more of the body, embedding a partial batch if it stalls
+        let next = if batch.is_empty() {
+            Some(chunks.next().await)
+        } else {
+            tokio::time::timeout(FLUSH_AFTER, chunks.next()).await.ok()
+        };
+        let ended = !matches!(next, Some(Some(Ok(_))));
+        let received = match next {
+            None => {
+                if !embed_batch(&state, &params, &scheduling, std::mem::take(&mut batch), &tx).await {
+                    return;
+                }
+                continue;
+            }
+            Some(Some(Ok(chunk))) => lines.push(&chunk),
+            Some(Some(Err(e))) => {
+                warn!("Bulk request body failed after {} lines: {}", number, e);
+                Vec::new()
+            }
+            Some(None) => lines.finish().into_iter().collect(),
+        };
+
+        for line in received {
+            number += 1;
+            if number > limits.max_lines {
+                let result = BulkResult::failed(
+                    serde_json::Value::Null,
+                    number,
+                    format!("Too many lines: at most {} are read per request", limits.max_lines),
+                    "too_many_lines",
+                );
+                if embed_batch(&state, &params, &scheduling, batch, &tx).await {
+                    let _ = tx.send(encode(&result)).await;
+                }
+                return;
+            }
+            let error = match line {
+                Some(line) => parse(&state, &line, number, &mut batch),
+                None => Some(BulkResult::failed(
+                    serde_json::Value::Null,
+                    number,
+                    format!("Line {} is longer than the limit of {} bytes", number, limits.max_line_bytes),
+                    "line_too_long",
+                )),
+            };
+            if let Some(error) = error {
+                if tx.send(encode(&error)).await.is_err() {
+                    return;
+                }
+            }
+            if batch.len() >= BATCH_LINES
+                && !embed_batch(&state, &params, &scheduling, std::mem::take(&mut batch), &tx).await
+            {
+                return;
+            }
+        }
+        if ended {
+            embed_batch(&state, &params, &scheduling, batch, &tx).await;
+            return;
+        }
+    }
+}
+
+// Parse one line into the batch; a malformed line returns its error
+fn parse(state: &AppState, raw: &[u8], line: usize, batch: &mut Vec<Pending>) -> Option<BulkResult> {
+    let raw = raw.strip_suffix(b"\r").unwrap_or(raw);
+    if raw.iter().all(u8::is_ascii_whitespace) {
+        return None;
+    }
+    match serde_json::from_slice::<BulkLine>(raw) {
+        Ok(BulkLine { id, text, metadata }) => {
+            if let Some(why) = metadata.as_ref().and_then(|metadata| oversized_metadata(state, metadata)) {
+                let message = format!("Line {}: {}", line, why);
+                return Some(BulkResult::failed(id, line, message, "metadata_too_large"));
+            }
+            batch.push(Pending {
+                id,
+                metadata,
+                line,
+                text,
+            });
+            None
+        }
+        Err(e) => {
+            // Keep the id of a line whose text is the problem
+            let id = serde_json::from_slice::<serde_json::Value>(raw)
+                .ok()
+                .and_then(|value| value.get("id").cloned())
+                .unwrap_or_default();
+            let message = format!("Line {} is not a JSON object with an id and a string text: {}", line, e);
+            Some(BulkResult::failed(id, line, message, "invalid_line"))
+        }
+    }
+}
+
+// Embed one batch and send its results; false once the client is gone
+async fn embed_batch(
+    state: &AppState,
+    params: &BulkParams,
+    scheduling: &Scheduling,
+    batch: Vec<Pending>,
+    tx: &mpsc::Sender<Bytes>,
+) -> bool {
+    if batch.is_empty() {
+        return true;
+    }
+    let (lines, texts): (Vec<_>, Vec<_>) = batch
+        .into_iter()
+        .map(|Pending { id, metadata, line, text }| ((id, metadata, line), text))
+        .unzip();
+    let input_type = params.input_type.or(Some(InputKind::Passage));
+    // Dropping the embedding future when the client goes away cancels it
+    let embedded = tokio::select! {
+        embedded = embed_texts(state, input_type, params.dimensions, scheduling, texts) => embedded,
+        _ = tx.closed() => return false,
+    };
+    for ((id, metadata, line), embedding) in lines.into_iter().zip(embedded) {
+        let result = match embedding {
+            Ok((embedding, count)) => BulkResult {
+                id,
+                metadata,
+                embedding: Some(EmbeddingData::encode(embedding, &params.encoding_format, state.float_precision)),
+                truncation: Truncation::details(&count, true),
+                line: None,
+                error: None,
+            },
+            Err(error) => BulkResult {
+                id,
+                metadata,
+                embedding: None,
+                truncation: None,
+                line: Some(line),
+                error: Some(error),
+            },
+        };
+        if tx.send(encode(&result)).await.is_err() {
+            return false;
+        }
+    }
+    true
+}
+
+/// One input's embedding and token count, or why it failed.
+pub(crate) type Embedded = Result<(Vec<f32>, TokenCount), ItemError>;
+
+/// Embed `texts` as bulk inputs are: each checked against the per-input size
+/// limit, preprocessed, prefixed for `input_type` and shortened to
+/// `dimensions`, with its token count. Every input gets its own result; while
+/// the circuit is open, all of them fail. The inputs are held to the token
+/// limit and the tenant's quota together, as one request's are, and the
+/// tokens of those that embed are counted toward usage.
+pub(crate) async fn embed_texts(
+    state: &AppState,
+    input_type: Option<InputKind>,
+    dimensions: Option<usize>,
+    scheduling: &Scheduling,
+    texts: Vec<String>,
+) -> Vec<Embedded> {
+    let preprocess = state.preprocess;
+    let prefix = state.model_spec.prefix(input_type).unwrap_or_default();
+    let mut results: Vec<Option<Embedded>> = Vec::with_capacity(texts.len());
+    let mut prepared_texts = Vec::new();
+    let mut indices = Vec::new();
+    for (index, text) in texts.into_iter().enumerate() {
+        let prepared = if text.len() > state.limits.max_input_bytes {
+            Err(ItemError {
+                message: format!(
+                    "The text is {} bytes, over the limit of {} bytes per input",
+                    text.len(),
+                    state.limits.max_input_bytes
+                ),
+                code: "input_too_large",
+            })
+        } else {
+            preprocess.prepare(text).map_err(|disallowed| ItemError {
+                message: format!("The text contains a disallowed {}", disallowed),
+                code: "disallowed_character",
+            })
+        };
+        match prepared {
+            Ok(text) if text.trim().is_empty() => results.push(Some(Err(ItemError {
+                message: "Input cannot be empty".to_string(),
+                code: "empty_input",
+            }))),
+            Ok(text) => {
+                prepared_texts.push(format!("{}{}", prefix, text));
+                indices.push(index);
+                results.push(None);
+            }
+            Err(error) => results.push(Some(Err(error))),
+        }
+    }
+
+    // Counted after truncation, prefix included, as a request's inputs are
+    let counted = match count_tokens(state, prepared_texts).await {
+        Ok((texts, counted)) => {
+            let token_count = counted.iter().map(|count| count.tokens).sum();
+            admit(state, scheduling, token_count).map(|()| (texts, counted))
+        }
+        Err(e) => Err(e),
+    };
+    let (prepared_texts, counted) = match counted {
+        Ok(counted) => counted,
+        Err(e) => {
+            let error = ItemError::from(e);
+            for index in indices {
+                results[index] = Some(Err(error.clone()));
+            }
+            return results.into_iter().flatten().collect();
+        }
+    };
+
+    match run_embedder_partial(state, prepared_texts, scheduling).await {
+        Ok(embedded) => {
+            let mut token_count = 0;
+            let mut succeeded = 0;
+            for ((index, embedding), count) in indices.into_iter().zip(embedded).zip(counted) {
+                if embedding.is_ok() {
+                    token_count += count.tokens;
+                    succeeded += 1;
+                }
+                results[index] = Some(embedding.map(|embedding| {
+                    let embedding = match dimensions {
+                        Some(dimensions) if dimensions < embedding.len() => shorten(embedding, dimensions),
+                        _ => embedding,
+                    };
+                    (embedding, count)
+                }));
+            }
+            charge(state, scheduling, succeeded, token_count);
+        }
+        // The circuit is open: the whole batch fails, and later ones are tried
+        Err(_) => {
+            for index in indices {
+                results[index] = Some(Err(ItemError {
+                    message: "The model is being re-initialized after repeated failures".to_string(),
+                    code: "circuit_open",
+                }));
+            }
+        }
+    }
+    results.into_iter().flatten().collect()
+}
+
+fn encode(result: &BulkResult) -> Bytes {
+    let mut line = serde_json::to_vec(result).unwrap_or_default();
+    line.push(b'\n');
+    Bytes::from(line)
+}
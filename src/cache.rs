@@ -0,0 +1,192 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use hmac_sha256::Hash;
+use prometheus::{Counter, IntGauge};
+use serde::{Deserialize, Serialize};
+
+// Share of the entries dropped when the cache is full, so the scan for the
+// least recently used ones is amortized over many inserts
+const EVICT_FRACTION: usize = 8;
+
+/// Metrics the cache reports into.
+pub struct CacheMetrics {
+    pub hits: Counter,
+    pub misses: Counter,
+    pub entries: IntGauge,
+}
+
+/// Embeddings of recently seen model inputs, keyed by model and the exact text
+/// passed to it (prefix and instruction included), bounded by entry count.
+pub struct EmbeddingCache {
+    capacity: usize,
+    model: String,
+    inner: Mutex<Inner>,
+    metrics: CacheMetrics,
+}
+
+#[derive(Default)]
+struct Inner {
+    entries: HashMap<[u8; 32], Entry>,
+    // Logical clock for recency
+    tick: u64,
+}
+
+struct Entry {
+    // Kept so the cache can be dumped as a warm file
+    text: Arc<str>,
+    embedding: Arc<Vec<f32>>,
+    last_used: u64,
+}
+
+/// One line of a warm file (and of `/cache/dump`).
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WarmRecord {
+    /// The exact model input, prefix included.
+    pub text: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub embedding: Option<Vec<f32>>,
+    /// Model that produced `embedding`; an embedding from another model is discarded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+}
+
+impl EmbeddingCache {
+    /// A capacity of zero disables caching.
+    pub fn new(capacity: usize, model: &str, metrics: CacheMetrics) -> Self {
+        metrics.entries.set(0);
+        Self {
+            capacity,
+            model: model.to_string(),
+            inner: Mutex::new(Inner::default()),
+            metrics,
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn model(&self) -> &str {
+        &self.model
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner().entries.len()
+    }
+
+    /// The cached embedding of each text, if any.
+    pub fn get_many(&self, texts: &[String]) -> Vec<Option<Vec<f32>>> {
+        if !self.enabled() {
+            return vec![None; texts.len()];
+        }
+        let mut inner = self.inner();
+        inner.tick += 1;
+        let tick = inner.tick;
+        let found: Vec<Option<Vec<f32>>> = texts
+            .iter()
+            .map(|text| {
+                let entry = inner.entries.get_mut(&self.key(text))?;
+                entry.last_used = tick;
+                Some(entry.embedding.as_ref().clone())
+            })
+            .collect();
+        drop(inner);
+        let hits = found.iter().filter(|embedding| embedding.is_some()).count();
+        self.metrics.hits.inc_by(hits as f64);
+        self.metrics.misses.inc_by((texts.len() - hits) as f64);
+        found
+    }
+
+    /// Whether `text` is cached, without counting a hit or refreshing it.
+    pub fn contains(&self, text: &str) -> bool {
+        self.enabled() && self.inner().entries.contains_key(&self.key(text))
+    }
+
+    pub fn insert_many<'a>(&self, entries: impl IntoIterator<Item = (&'a str, &'a [f32])>) {
+        if !self.enabled() {
+            return;
+        }
+        let mut inner = self.inner();
+        inner.tick += 1;
+        let tick = inner.tick;
+        for (text, embedding) in entries {
+            if inner.entries.len() >= self.capacity {
+                evict(&mut inner.entries, (self.capacity / EVICT_FRACTION).max(1));
+            }
+            inner.entries.insert(
+                self.key(text),
+                Entry {
+                    text: text.into(),
+                    embedding: Arc::new(embedding.to_vec()),
+                    last_used: tick,
+                },
+            );
+        }
+        self.metrics.entries.set(inner.entries.len() as i64);
+    }
+
+    /// Drop the least recently used half of the entries, releasing their
+    /// memory; returns how many were dropped.
+    pub fn evict_half(&self) -> usize {
+        let mut inner = self.inner();
+        let before = inner.entries.len();
+        evict(&mut inner.entries, before.div_ceil(2));
+        inner.entries.shrink_to_fit();
+        self.metrics.entries.set(inner.entries.len() as i64);
+        before - inner.entries.len()
+    }
+
+    /// Every cached entry as a warm-file record, most recently used first.
+    pub fn dump(&self) -> Vec<WarmRecord> {
+        let mut entries: Vec<(u64, Arc<str>, Arc<Vec<f32>>)> = self
+            .inner()
+            .entries
+            .values()
+            .map(|entry| (entry.last_used, entry.text.clone(), entry.embedding.clone()))
+            .collect();
+        entries.sort_by_key(|entry| std::cmp::Reverse(entry.0));
+        entries
+            .into_iter()
+            .map(|(_, text, embedding)| WarmRecord {
+                text: text.to_string(),
+                embedding: Some(embedding.as_ref().clone()),
+                model: Some(self.model.clone()),
+            })
+            .collect()
+    }
+
+    fn key(&self, text: &str) -> [u8; 32] {
+        let mut hash = Hash::new();
+        hash.update(self.model.as_bytes());
+        hash.update([0]);
+        hash.update(text.as_bytes());
+        hash.finalize()
+    }
+
+    fn inner(&self) -> std::sync::MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+// Drop the `count` least recently used entries
+fn evict(entries: &mut HashMap<[u8; 32], Entry>, count: usize) {
+    let count = count.min(entries.len());
+    if count == 0 {
+        return;
+    }
+    let mut ticks: Vec<u64> = entries.values().map(|entry| entry.last_used).collect();
+    let (_, &mut cutoff, _) = ticks.select_nth_unstable(count - 1);
+    let mut dropped = 0;
+    entries.retain(|_, entry| {
+        if dropped < count && entry.last_used <= cutoff {
+            dropped += 1;
+            return false;
+        }
+        true
+    });
+}
@@ -0,0 +1,203 @@
+//! `GET /v1/embeddings`: one input embedded from the query string, for HTTP
+//! caches in front of semembed.
+//!
+//! Off unless `SEMEMBED_GET_EMBEDDINGS` is on, since it puts input text into
+//! URLs, which proxies and CDNs log; semembed's own request spans redact the
+//! `input` parameter (see [`crate::redact::redact_query`]). The query
+//! parameters are the request body's scalar fields and are validated and
+//! embedded by the `POST` handler. The response's strong `ETag` hashes the
+//! serving model and its revision, the input as preprocessing leaves it and
+//! the options, so it is the same across restarts with the same model and
+//! settings, and a matching `If-None-Match` gets `304` without inference.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use axum::{
+    extract::{rejection::QueryRejection, Query, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode, Uri},
+    response::{IntoResponse, Response},
+};
+use hmac_sha256::Hash;
+use serde::Serialize;
+
+use crate::error::ApiError;
+use crate::extract::{ApiJson, Scheduling};
+use crate::{create_embeddings, AppState, EmbeddingRequest};
+
+// Query parameters taken as numbers or booleans rather than strings
+const NUMERIC: &[&str] = &["dimensions", "precision"];
+const BOOLEAN: &[&str] = &["truncate", "return_token_details", "split", "detect_language"];
+// Every parameter accepted; anything else is rejected rather than ignored, so
+// it can't make two different requests share a cached response
+const ACCEPTED: &[&str] = &[
+    "input",
+    "model",
+    "encoding_format",
+    "input_type",
+    "task",
+    "instruction",
+    "dimensions",
+    "precision",
+    "truncate",
+    "truncation_side",
+    "return_token_details",
+    "split",
+    "detect_language",
+    "dtype",
+];
+
+/// How the `GET` variant is served, when it is on.
+#[derive(Debug, Clone)]
+pub(crate) struct CacheableConfig {
+    /// Longest request target (path and query) accepted, in bytes.
+    pub max_uri_bytes: usize,
+    /// Sent with `200` and `304` responses.
+    pub cache_control: HeaderValue,
+}
+
+// What the ETag covers, serialized in field order
+#[derive(Serialize)]
+struct Tagged<'a> {
+    model: &'a str,
+    revision: Option<&'a str>,
+    preprocess: Vec<&'static str>,
+    float_precision: Option<u32>,
+    params: &'a BTreeMap<String, String>,
+}
+
+fn invalid(param: &'static str, message: impl Into<String>) -> ApiError {
+    ApiError::new(StatusCode::BAD_REQUEST, "invalid_request_error", message)
+        .param(param)
+        .reason("invalid_query")
+}
+
+// The body the POST handler would have been sent
+fn request_body(params: &BTreeMap<String, String>) -> Result<EmbeddingRequest, ApiError> {
+    let mut body = serde_json::Map::new();
+    for (name, value) in params {
+        let Some(&param) = ACCEPTED.iter().find(|accepted| *accepted == name) else {
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "invalid_request_error",
+                format!("Unsupported query parameter `{}`", name),
+            )
+            .code("unsupported_parameter")
+            .reason("unsupported_parameter"));
+        };
+        let value = if NUMERIC.contains(&param) {
+            serde_json::Value::from(
+                value
+                    .parse::<u64>()
+                    .map_err(|_| invalid(param, format!("{} must be a non-negative integer, got {:?}", param, value)))?,
+            )
+        } else if BOOLEAN.contains(&param) {
+            serde_json::Value::from(
+                value
+                    .parse::<bool>()
+                    .map_err(|_| invalid(param, format!("{} must be true or false, got {:?}", param, value)))?,
+            )
+        } else {
+            serde_json::Value::from(value.as_str())
+        };
+        body.insert(param.to_string(), value);
+    }
+    if !body.contains_key("input") {
+        return Err(invalid("input", "The input query parameter is required"));
+    }
+    serde_json::from_value(serde_json::Value::Object(body)).map_err(|e| {
+        ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_request_error",
+            format!("Invalid query string: {}", e),
+        )
+        .reason("invalid_query")
+    })
+}
+
+// Strong ETag of the response to `params`, or `None` for a model that isn't
+// served, which the POST handler turns into its 404
+fn etag(state: &AppState, params: &BTreeMap<String, String>) -> Option<String> {
+    let settings = state.settings();
+    // Routing by language depends only on the input, which the tag covers
+    let model = params
+        .get("model")
+        .map(String::as_str)
+        .filter(|model| !state.language_routes.as_ref().is_some_and(|routes| routes.applies(Some(model))));
+    let resolved = settings.resolver.resolve(model)?;
+    let mut params = params.clone();
+    if let Some(input) = params.get_mut("input") {
+        if let Ok(prepared) = state.preprocess.prepare(input.clone()) {
+            *input = prepared;
+        }
+    }
+    let tagged = Tagged {
+        model: resolved.canonical,
+        revision: state.model_revision.as_deref(),
+        preprocess: state.preprocess.steps(),
+        float_precision: state.float_precision,
+        params: &params,
+    };
+    let hash = Hash::hash(&serde_json::to_vec(&tagged).unwrap_or_default());
+    let hex: String = hash[..16].iter().map(|byte| format!("{:02x}", byte)).collect();
+    Some(format!("\"{}\"", hex))
+}
+
+/// Whether `If-None-Match` lists `etag`, or is `*`; weak tags match too.
+pub(crate) fn not_modified(headers: &HeaderMap, etag: &str) -> bool {
+    headers
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .any(|tag| tag == "*" || tag.strip_prefix("W/").unwrap_or(tag) == etag)
+}
+
+/// `GET /v1/embeddings?input=...`: the `POST` response for one input, with
+/// an `ETag` and `Cache-Control`, or `304` when `If-None-Match` matches.
+pub(crate) async fn embeddings(
+    State(state): State<Arc<AppState>>,
+    uri: Uri,
+    headers: HeaderMap,
+    scheduling: Scheduling,
+    params: Result<Query<BTreeMap<String, String>>, QueryRejection>,
+) -> Result<Response, ApiError> {
+    let Some(config) = state.cacheable.clone() else {
+        return Err(ApiError::new(StatusCode::NOT_FOUND, "invalid_request_error", "GET /v1/embeddings is not enabled")
+            .reason("not_found"));
+    };
+    let target_len = uri.path_and_query().map_or(0, |target| target.as_str().len());
+    if target_len > config.max_uri_bytes {
+        return Err(ApiError::new(
+            StatusCode::URI_TOO_LONG,
+            "invalid_request_error",
+            format!(
+                "The URL is {} bytes, over the limit of {} bytes; send longer inputs with POST",
+                target_len, config.max_uri_bytes
+            ),
+        )
+        .param("input")
+        .reason("uri_too_long"));
+    }
+    let Query(params) = params.map_err(|rejection| {
+        ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_request_error",
+            format!("Invalid query string: {}", rejection.body_text()),
+        )
+        .reason("invalid_query")
+    })?;
+    let req = request_body(&params)?;
+    let etag = etag(&state, &params);
+    let cached = [(header::CACHE_CONTROL, config.cache_control.clone())];
+    if let Some(etag) = etag.as_deref().filter(|etag| not_modified(&headers, etag)) {
+        return Ok((StatusCode::NOT_MODIFIED, cached, [(header::ETAG, etag.to_string())]).into_response());
+    }
+    let response = create_embeddings(State(state.clone()), scheduling, ApiJson(req)).await?;
+    let mut response = (cached, response).into_response();
+    if let Some(etag) = etag.and_then(|etag| HeaderValue::from_str(&etag).ok()) {
+        response.headers_mut().insert(header::ETAG, etag);
+    }
+    Ok(response)
+}
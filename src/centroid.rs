@@ -0,0 +1,67 @@
+use crate::cluster::{dot, normalize};
+
+/// Why weights can't be used to average the inputs.
+#[derive(Debug, PartialEq)]
+pub enum WeightError {
+    /// Not one weight per input.
+    Length { weights: usize, inputs: usize },
+    /// A weight that is negative, infinite or NaN, at this index.
+    Invalid(usize),
+    /// Every weight is zero.
+    AllZero,
+}
+
+impl std::fmt::Display for WeightError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Length { weights, inputs } => {
+                write!(f, "weights must have one entry per input: got {} weights for {} inputs", weights, inputs)
+            }
+            Self::Invalid(index) => write!(f, "weights[{}] must be a finite number of at least 0", index),
+            Self::AllZero => f.write_str("weights cannot all be zero"),
+        }
+    }
+}
+
+/// Check `weights` against `inputs` inputs before anything is embedded.
+pub fn check_weights(weights: &[f32], inputs: usize) -> Result<(), WeightError> {
+    if weights.len() != inputs {
+        return Err(WeightError::Length {
+            weights: weights.len(),
+            inputs,
+        });
+    }
+    if let Some(index) = weights.iter().position(|weight| !weight.is_finite() || *weight < 0.0) {
+        return Err(WeightError::Invalid(index));
+    }
+    if weights.iter().all(|weight| *weight == 0.0) {
+        return Err(WeightError::AllZero);
+    }
+    Ok(())
+}
+
+/// The mean of `points`, each counted by its weight (all equal without
+/// `weights`), scaled back to unit length when `unit` is set. Sums are kept
+/// in f64 so long lists of inputs don't lose precision.
+pub fn weighted_mean(points: &[Vec<f32>], weights: Option<&[f32]>, unit: bool) -> Vec<f32> {
+    let dimensions = points.first().map_or(0, Vec::len);
+    let mut sums = vec![0.0f64; dimensions];
+    let mut total = 0.0f64;
+    for (index, point) in points.iter().enumerate() {
+        let weight = weights.map_or(1.0, |weights| f64::from(weights[index]));
+        total += weight;
+        for (sum, value) in sums.iter_mut().zip(point) {
+            *sum += weight * f64::from(*value);
+        }
+    }
+    let mut mean: Vec<f32> = sums.into_iter().map(|sum| (sum / total) as f32).collect();
+    if unit {
+        normalize(&mut mean);
+    }
+    mean
+}
+
+/// The length of each point.
+pub fn norms(points: &[Vec<f32>]) -> Vec<f32> {
+    points.iter().map(|point| dot(point, point).sqrt()).collect()
+}
@@ -0,0 +1,89 @@
+use std::ops::Range;
+
+use tokenizers::Tokenizer;
+
+/// Splits long documents into pieces that fit the model's context.
+///
+/// Chunk boundaries fall on token boundaries of the model's own tokenizer, so
+/// every chunk embeds without truncation.
+pub struct Chunker {
+    // The model's tokenizer without truncation or padding
+    tokenizer: Tokenizer,
+    // Tokens the model adds around every input ([CLS], [SEP], ...)
+    special_tokens: usize,
+}
+
+impl Chunker {
+    pub fn new(tokenizer: &Tokenizer) -> anyhow::Result<Self> {
+        let mut tokenizer = tokenizer.clone();
+        tokenizer
+            .with_truncation(None)
+            .map_err(|e| anyhow::anyhow!("failed to configure tokenizer: {}", e))?
+            .with_padding(None);
+        let special_tokens = tokenizer
+            .encode("", true)
+            .map_err(|e| anyhow::anyhow!("failed to tokenize: {}", e))?
+            .len();
+        Ok(Self {
+            tokenizer,
+            special_tokens,
+        })
+    }
+
+    /// Tokens in `text`, not counting special tokens.
+    pub fn count(&self, text: &str) -> anyhow::Result<usize> {
+        self.tokenizer
+            .encode(text, false)
+            .map(|encoding| encoding.len())
+            .map_err(|e| anyhow::anyhow!("failed to tokenize: {}", e))
+    }
+
+    /// Split `text` into chunks of at most `max_tokens` tokens (special tokens
+    /// included), consecutive chunks sharing `overlap` tokens. Text that fits
+    /// is returned as a single chunk.
+    pub fn chunk(&self, text: &str, max_tokens: usize, overlap: usize) -> anyhow::Result<Vec<String>> {
+        Ok(self
+            .spans(text, max_tokens, overlap)?
+            .into_iter()
+            .map(|span| text[span].to_string())
+            .collect())
+    }
+
+    /// Like [`chunk`](Self::chunk), but returns where each chunk lies in
+    /// `text`, as byte ranges.
+    pub fn spans(&self, text: &str, max_tokens: usize, overlap: usize) -> anyhow::Result<Vec<Range<usize>>> {
+        let window = max_tokens.saturating_sub(self.special_tokens).max(1);
+        let overlap = overlap.min(window - 1);
+
+        let encoding = self
+            .tokenizer
+            .encode(text, false)
+            .map_err(|e| anyhow::anyhow!("failed to tokenize: {}", e))?;
+        let offsets = encoding.get_offsets();
+        if offsets.len() <= window {
+            return Ok(std::iter::once(0..text.len()).collect());
+        }
+
+        let mut spans = Vec::new();
+        let mut start = 0;
+        loop {
+            let end = (start + window).min(offsets.len());
+            // Offsets are byte ranges into `text`; extend each chunk up to the
+            // next token so whitespace between tokens is kept
+            let from = offsets[start].0;
+            let to = offsets.get(end).map_or(text.len(), |next| next.0);
+            let chunk = &text[from..to];
+            let trimmed = chunk.trim_start();
+            let from = from + (chunk.len() - trimmed.len());
+            let to = from + trimmed.trim_end().len();
+            if from < to {
+                spans.push(from..to);
+            }
+            if end == offsets.len() {
+                break;
+            }
+            start = end - overlap;
+        }
+        Ok(spans)
+    }
+}
@@ -0,0 +1,48 @@
+use crate::cluster::{dot, normalize};
+
+/// How similarities to the labels are turned into scores.
+#[derive(Debug, Clone, Copy)]
+pub struct Scoring {
+    /// Independent per-label scores instead of a distribution over labels.
+    pub multi_label: bool,
+    /// Similarity difference that changes a score by a factor of e; smaller
+    /// values give more decisive scores.
+    pub temperature: f32,
+    /// Multi-label only: the similarity that scores exactly 0.5.
+    pub midpoint: f32,
+}
+
+/// Cosine similarity of `input` to each label.
+pub fn similarities(mut input: Vec<f32>, labels: &[Vec<f32>]) -> Vec<f32> {
+    normalize(&mut input);
+    labels.iter().map(|label| dot(&input, label).clamp(-1.0, 1.0)).collect()
+}
+
+/// Scores for one input's similarities to every label: a softmax over the
+/// labels (summing to 1) for single-label classification, or an independent
+/// sigmoid per label for multi-label.
+pub fn scores(similarities: &[f32], scoring: Scoring) -> Vec<f32> {
+    if scoring.multi_label {
+        return similarities
+            .iter()
+            .map(|similarity| 1.0 / (1.0 + (-(similarity - scoring.midpoint) / scoring.temperature).exp()))
+            .collect();
+    }
+    // Shift by the largest logit so the exponentials can't overflow
+    let max = similarities.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let exps: Vec<f32> = similarities
+        .iter()
+        .map(|similarity| ((similarity - max) / scoring.temperature).exp())
+        .collect();
+    let total: f32 = exps.iter().sum();
+    exps.into_iter().map(|exp| exp / total).collect()
+}
+
+/// Index of the highest score (the first one on ties).
+pub fn argmax(scores: &[f32]) -> usize {
+    scores
+        .iter()
+        .enumerate()
+        .fold((0, f32::NEG_INFINITY), |best, (index, &score)| if score > best.1 { (index, score) } else { best })
+        .0
+}
@@ -0,0 +1,297 @@
+use serde::Deserialize;
+
+/// How distances between embeddings are measured.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Metric {
+    /// 1 - cosine similarity; points and centroids are kept at unit length.
+    #[default]
+    Cosine,
+    Euclidean,
+}
+
+impl Metric {
+    pub fn distance(self, a: &[f32], b: &[f32]) -> f32 {
+        match self {
+            Self::Cosine => 1.0 - dot(a, b),
+            Self::Euclidean => squared_distance(a, b).sqrt(),
+        }
+    }
+
+    // Cheaper stand-in for `distance` with the same ordering, used for assignment
+    fn cost(self, a: &[f32], b: &[f32]) -> f32 {
+        match self {
+            Self::Cosine => 1.0 - dot(a, b),
+            Self::Euclidean => squared_distance(a, b),
+        }
+    }
+}
+
+/// Settings for one clustering run.
+#[derive(Debug, Clone, Copy)]
+pub struct KMeansConfig {
+    pub metric: Metric,
+    pub max_iter: usize,
+    pub seed: u64,
+}
+
+/// The outcome of k-means over a set of points.
+#[derive(Debug)]
+pub struct Clustering {
+    /// Cluster of each point, in input order.
+    pub assignments: Vec<usize>,
+    pub centroids: Vec<Vec<f32>>,
+    /// Lloyd iterations run before assignments stopped changing (or `max_iter`).
+    pub iterations: usize,
+    /// Mean silhouette coefficient, in [-1, 1]; `None` with a single cluster.
+    pub silhouette: Option<f32>,
+}
+
+impl Clustering {
+    /// The `n` members of `cluster` closest to its centroid, nearest first,
+    /// with their distances.
+    pub fn representatives(&self, points: &[Vec<f32>], cluster: usize, n: usize, metric: Metric) -> Vec<(usize, f32)> {
+        let centroid = &self.centroids[cluster];
+        let mut members: Vec<(usize, f32)> = self
+            .assignments
+            .iter()
+            .enumerate()
+            .filter(|&(_, &assigned)| assigned == cluster)
+            .map(|(index, _)| (index, metric.distance(&points[index], centroid)))
+            .collect();
+        members.sort_by(|a, b| a.1.total_cmp(&b.1).then(a.0.cmp(&b.0)));
+        members.truncate(n);
+        members
+    }
+}
+
+/// Prepare embeddings for clustering under `metric`.
+pub fn prepare(mut points: Vec<Vec<f32>>, metric: Metric) -> Vec<Vec<f32>> {
+    if metric == Metric::Cosine {
+        points.iter_mut().for_each(|point| normalize(point));
+    }
+    points
+}
+
+/// K-means with k-means++ initialization. Deterministic for a given seed.
+/// Requires `1 <= k <= points.len()`.
+pub fn kmeans(points: &[Vec<f32>], k: usize, config: KMeansConfig) -> Clustering {
+    let distances = (k > 1).then(|| Distances::new(points, config.metric));
+    lloyd(points, k, config, distances.as_ref())
+}
+
+/// Run k-means for every k in `ks` and keep the clustering with the highest
+/// silhouette (ties go to the smaller k). Requires at least 2 points per k.
+pub fn auto_kmeans(points: &[Vec<f32>], ks: std::ops::RangeInclusive<usize>, config: KMeansConfig) -> Clustering {
+    let distances = Distances::new(points, config.metric);
+    let mut best: Option<Clustering> = None;
+    for k in ks {
+        let clustering = lloyd(points, k, config, Some(&distances));
+        let better = match &best {
+            None => true,
+            Some(best) => clustering.silhouette.unwrap_or(-1.0) > best.silhouette.unwrap_or(-1.0),
+        };
+        if better {
+            best = Some(clustering);
+        }
+    }
+    best.expect("k range is not empty")
+}
+
+fn lloyd(points: &[Vec<f32>], k: usize, config: KMeansConfig, distances: Option<&Distances>) -> Clustering {
+    let mut rng = SplitMix64(config.seed);
+    let mut centroids = init_plus_plus(points, k, config.metric, &mut rng);
+    let mut assignments = vec![usize::MAX; points.len()];
+
+    let mut iterations = 0;
+    while iterations < config.max_iter {
+        iterations += 1;
+        let mut changed = false;
+        for (point, assigned) in points.iter().zip(assignments.iter_mut()) {
+            let nearest = nearest(point, &centroids, config.metric).0;
+            if *assigned != nearest {
+                *assigned = nearest;
+                changed = true;
+            }
+        }
+        if !changed {
+            break;
+        }
+        centroids = update_centroids(points, &assignments, &centroids, config.metric);
+    }
+
+    let silhouette = distances
+        .filter(|_| k > 1)
+        .map(|distances| silhouette(distances, &assignments, k));
+    Clustering {
+        assignments,
+        centroids,
+        iterations,
+        silhouette,
+    }
+}
+
+// Pairwise distances between all points, computed once and shared by the
+// silhouettes of every candidate k
+struct Distances {
+    n: usize,
+    values: Vec<f32>,
+}
+
+impl Distances {
+    fn new(points: &[Vec<f32>], metric: Metric) -> Self {
+        let n = points.len();
+        let mut values = vec![0.0; n * n];
+        for i in 0..n {
+            for j in (i + 1)..n {
+                let distance = metric.distance(&points[i], &points[j]);
+                values[i * n + j] = distance;
+                values[j * n + i] = distance;
+            }
+        }
+        Self { n, values }
+    }
+
+    fn row(&self, i: usize) -> &[f32] {
+        &self.values[i * self.n..(i + 1) * self.n]
+    }
+}
+
+// First centroid uniformly at random, each next one with probability
+// proportional to its squared distance from the nearest centroid so far
+fn init_plus_plus(points: &[Vec<f32>], k: usize, metric: Metric, rng: &mut SplitMix64) -> Vec<Vec<f32>> {
+    let mut centroids = vec![points[rng.below(points.len())].clone()];
+    let mut weights: Vec<f64> = points
+        .iter()
+        .map(|point| f64::from(metric.distance(point, &centroids[0])).powi(2))
+        .collect();
+    while centroids.len() < k {
+        let total: f64 = weights.iter().sum();
+        let chosen = if total > 0.0 {
+            let mut target = rng.unit() * total;
+            weights
+                .iter()
+                .position(|&weight| {
+                    target -= weight;
+                    target < 0.0
+                })
+                .unwrap_or(points.len() - 1)
+        } else {
+            // Every point coincides with a centroid already
+            rng.below(points.len())
+        };
+        centroids.push(points[chosen].clone());
+        let added = centroids.last().expect("just pushed");
+        for (point, weight) in points.iter().zip(weights.iter_mut()) {
+            *weight = weight.min(f64::from(metric.distance(point, added)).powi(2));
+        }
+    }
+    centroids
+}
+
+fn nearest(point: &[f32], centroids: &[Vec<f32>], metric: Metric) -> (usize, f32) {
+    centroids
+        .iter()
+        .map(|centroid| metric.cost(point, centroid))
+        .enumerate()
+        .fold((0, f32::INFINITY), |best, (index, cost)| if cost < best.1 { (index, cost) } else { best })
+}
+
+// Mean of each cluster's members. A cluster left empty takes over the point
+// farthest from its own centroid, so k stays as requested.
+fn update_centroids(points: &[Vec<f32>], assignments: &[usize], previous: &[Vec<f32>], metric: Metric) -> Vec<Vec<f32>> {
+    let dimensions = points[0].len();
+    let mut sums = vec![vec![0.0f32; dimensions]; previous.len()];
+    let mut counts = vec![0usize; previous.len()];
+    for (point, &cluster) in points.iter().zip(assignments) {
+        counts[cluster] += 1;
+        sums[cluster].iter_mut().zip(point).for_each(|(sum, x)| *sum += x);
+    }
+    for (cluster, sum) in sums.iter_mut().enumerate() {
+        if counts[cluster] == 0 {
+            let farthest = points
+                .iter()
+                .zip(assignments)
+                .map(|(point, &assigned)| metric.cost(point, &previous[assigned]))
+                .enumerate()
+                .fold((0, f32::NEG_INFINITY), |best, (index, cost)| if cost > best.1 { (index, cost) } else { best })
+                .0;
+            sum.copy_from_slice(&points[farthest]);
+            continue;
+        }
+        sum.iter_mut().for_each(|x| *x /= counts[cluster] as f32);
+        if metric == Metric::Cosine {
+            normalize(sum);
+        }
+    }
+    sums
+}
+
+// Mean over all points of (b - a) / max(a, b), where a is the mean distance to
+// the rest of the point's cluster and b the mean distance to the nearest other
+// cluster. Points alone in their cluster score 0.
+fn silhouette(distances: &Distances, assignments: &[usize], k: usize) -> f32 {
+    let mut sizes = vec![0usize; k];
+    assignments.iter().for_each(|&cluster| sizes[cluster] += 1);
+
+    let mut total = 0.0f64;
+    let mut sums = vec![0.0f64; k];
+    for i in 0..distances.n {
+        sums.iter_mut().for_each(|sum| *sum = 0.0);
+        for (j, &distance) in distances.row(i).iter().enumerate() {
+            if i != j {
+                sums[assignments[j]] += f64::from(distance);
+            }
+        }
+        let own = assignments[i];
+        if sizes[own] <= 1 {
+            continue;
+        }
+        let a = sums[own] / (sizes[own] - 1) as f64;
+        let b = (0..k)
+            .filter(|&cluster| cluster != own && sizes[cluster] > 0)
+            .map(|cluster| sums[cluster] / sizes[cluster] as f64)
+            .fold(f64::INFINITY, f64::min);
+        if b.is_finite() && a.max(b) > 0.0 {
+            total += (b - a) / a.max(b);
+        }
+    }
+    (total / distances.n as f64) as f32
+}
+
+pub fn dot(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(x, y)| x * y).sum()
+}
+
+fn squared_distance(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(x, y)| (x - y) * (x - y)).sum()
+}
+
+pub fn normalize(vector: &mut [f32]) {
+    let norm = dot(vector, vector).sqrt();
+    if norm > 0.0 {
+        vector.iter_mut().for_each(|x| *x /= norm);
+    }
+}
+
+// Small, seedable PRNG so results are reproducible without pulling in `rand`
+pub(crate) struct SplitMix64(pub u64);
+
+impl SplitMix64 {
+    pub fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    // Uniform in [0, 1)
+    pub fn unit(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    pub fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
@@ -0,0 +1,380 @@
+//! `POST /v1/compare`: the same texts embedded by several loaded models.
+//!
+//! The models are the primary one (by name or alias), the shadow model, and
+//! those loaded only for comparison with `SEMEMBED_COMPARE_MODELS`. Each
+//! model embeds with its own query/passage prefix; all of them run at once,
+//! each batch taking its turn on the inference queue, and each within its
+//! `SEMEMBED_MODEL_CONCURRENCY` limit. Under a model budget, the comparison
+//! models are loaded on demand (see [`crate::pool`]).
+
+use std::sync::{Arc, OnceLock};
+
+use axum::{extract::State, http::StatusCode, Json};
+use futures_util::future::try_join_all;
+use prometheus::{CounterVec, Opts, Registry};
+use serde::{Deserialize, Serialize};
+use tokio_util::sync::CancellationToken;
+
+use crate::embedder::{EmbedError, Embedder};
+use crate::error::ApiError;
+use crate::extract::{ApiJson, Scheduling};
+use crate::models::{InputKind, ModelSpec};
+use crate::pool::{ModelPool, PoolError};
+use crate::shadow::cosine;
+use crate::{admit, cap_input_bytes, charge, inference_error, run_embedder, AppState, InputType};
+
+/// A model loaded only to be compared with.
+pub(crate) struct ComparedModel {
+    pub spec: &'static ModelSpec,
+    pub dimensions: usize,
+    pub embedder: Arc<Embedder>,
+}
+
+/// The models `/v1/compare` can use besides the primary and shadow ones,
+/// and the tokens each has embedded.
+pub(crate) struct Comparison {
+    // Set once they have loaded
+    models: OnceLock<Vec<ComparedModel>>,
+    // Instead, under a model budget
+    pool: Option<Arc<ModelPool>>,
+    tokens: CounterVec,
+}
+
+impl Comparison {
+    pub fn new(pool: Option<ModelPool>, registry: &Registry) -> anyhow::Result<Self> {
+        let tokens = CounterVec::new(
+            Opts::new("semembed_compare_tokens_total", "Tokens embedded by /v1/compare, by model"),
+            &["model"],
+        )?;
+        registry.register(Box::new(tokens.clone()))?;
+        Ok(Self {
+            models: OnceLock::new(),
+            pool: pool.map(Arc::new),
+            tokens,
+        })
+    }
+
+    /// Every comparison model with whether it is `resident`, `loading` or
+    /// `available`.
+    pub fn states(&self) -> Vec<(&'static ModelSpec, &'static str)> {
+        let installed = self.models.get().into_iter().flatten();
+        let mut states: Vec<_> = installed.map(|model| (model.spec, "resident")).collect();
+        if let Some(pool) = &self.pool {
+            states.extend(pool.states());
+        }
+        states
+    }
+
+    /// Put the models that loaded into service.
+    pub fn install(&self, models: Vec<ComparedModel>) {
+        let _ = self.models.set(models);
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct CompareRequest {
+    #[serde(default)]
+    input: Option<InputType>,
+    models: Vec<String>,
+    // Texts whose similarity each model reports
+    #[serde(default)]
+    pairs: Vec<(String, String)>,
+    input_type: Option<InputKind>,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct CompareResponse {
+    object: &'static str,
+    data: Vec<ModelResult>,
+}
+
+#[derive(Debug, Serialize)]
+struct ModelResult {
+    model: String,
+    dimensions: usize,
+    // One per input, in order
+    embeddings: Vec<Vec<f32>>,
+    // One per pair, when pairs were given
+    #[serde(skip_serializing_if = "Option::is_none")]
+    similarities: Option<Vec<f32>>,
+    usage: ModelUsage,
+}
+
+#[derive(Debug, Serialize)]
+struct ModelUsage {
+    prompt_tokens: usize,
+}
+
+/// A requested model and how to reach it.
+pub(crate) enum Target<'a> {
+    Primary,
+    Loaded(&'static ModelSpec, usize, &'a Arc<Embedder>),
+    // Loaded on demand, and kept loaded while held
+    Pooled(Arc<ComparedModel>),
+}
+
+/// What became of looking a model up.
+pub(crate) enum Lookup<'a> {
+    Found(Target<'a>),
+    // Still being loaded, at startup or on demand
+    Loading,
+    Missing,
+}
+
+impl Target<'_> {
+    /// The model's spec, dimensions and embedder.
+    pub fn model<'s>(&'s self, state: &'s AppState) -> (&'static ModelSpec, usize, &'s Arc<Embedder>) {
+        match self {
+            Target::Primary => (state.model_spec, state.metadata.dimensions, &state.embedder),
+            Target::Loaded(spec, dimensions, embedder) => (*spec, *dimensions, *embedder),
+            Target::Pooled(model) => (model.spec, model.dimensions, &model.embedder),
+        }
+    }
+
+    /// Embed `texts`, prefixed as the model expects, in batches that take
+    /// their turns on the queue. The primary model's go through its cache.
+    pub async fn embed(
+        &self,
+        state: &AppState,
+        texts: Vec<String>,
+        scheduling: &Scheduling,
+    ) -> Result<Vec<Vec<f32>>, ApiError> {
+        match self {
+            Target::Primary => run_embedder(state, texts, scheduling).await,
+            Target::Loaded(..) | Target::Pooled(_) => {
+                let (_, _, embedder) = self.model(state);
+                embed(state, embedder.clone(), texts, scheduling).await.map_err(inference_error)
+            }
+        }
+    }
+}
+
+/// The model `name` among those this server has loaded: the primary one (by
+/// name or alias), the shadow one or a comparison model, loading a pooled one
+/// if need be.
+pub(crate) async fn lookup<'a>(state: &'a AppState, name: &str) -> Result<Lookup<'a>, ApiError> {
+    if let Some(pool) = state.comparison.pool.as_ref().filter(|pool| pool.contains(name)) {
+        return match pool.get(name).await {
+            Ok(model) => Ok(Lookup::Found(Target::Pooled(model))),
+            Err(PoolError::Loading) => Ok(Lookup::Loading),
+            Err(err @ PoolError::Failed(_)) => Err(ApiError::new(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "service_unavailable",
+                format!("Could not load `{}`: {}", name, err),
+            )
+            .code("model_load_failed")),
+        };
+    }
+    let target = if state.settings().resolver.resolve(Some(name)).is_some() {
+        Some(Target::Primary)
+    } else if let Some(shadow) = state.shadow.get().filter(|shadow| shadow.name() == name) {
+        Some(Target::Loaded(shadow.spec(), shadow.dimensions(), shadow.embedder()))
+    } else {
+        state
+            .comparison
+            .models
+            .get()
+            .into_iter()
+            .flatten()
+            .find(|model| model.spec.name == name)
+            .map(|model| Target::Loaded(model.spec, model.dimensions, &model.embedder))
+    };
+    Ok(match target {
+        Some(target) => Lookup::Found(target),
+        None if state.loading.is_pending(name) => Lookup::Loading,
+        None => Lookup::Missing,
+    })
+}
+
+fn invalid(param: &'static str, message: impl Into<String>) -> ApiError {
+    ApiError::new(StatusCode::BAD_REQUEST, "invalid_request_error", message)
+        .param(param)
+        .reason("invalid_compare_request")
+}
+
+/// `POST /v1/compare`: embed `input` and the texts of `pairs` with every
+/// model in `models`, returning each model's vectors and pair similarities.
+pub(crate) async fn compare(
+    State(state): State<Arc<AppState>>,
+    scheduling: Scheduling,
+    ApiJson(req): ApiJson<CompareRequest>,
+) -> Result<Json<CompareResponse>, ApiError> {
+
+    if req.models.is_empty() {
+        return Err(invalid("models", "models cannot be an empty array"));
+    }
+    let mut targets = Vec::with_capacity(req.models.len());
+    let mut missing = Vec::new();
+    let mut loading = Vec::new();
+    for name in &req.models {
+        match lookup(&state, name).await.map_err(|err| err.param("models"))? {
+            Lookup::Found(target) => targets.push(target),
+            Lookup::Loading => loading.push(format!("`{}`", name)),
+            Lookup::Missing => missing.push(format!("`{}`", name)),
+        }
+    }
+    if !loading.is_empty() {
+        return Err(ApiError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "service_unavailable",
+            format!("These models are still loading; retry shortly: {}", loading.join(", ")),
+        )
+        .code("model_loading")
+        .param("models")
+        .retry_after(5));
+    }
+    if !missing.is_empty() {
+        return Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            "model_not_found",
+            format!("These models are not loaded on this server: {}", missing.join(", ")),
+        )
+        .param("models"));
+    }
+
+    // Results are per model, so `{id, text}` inputs only give their texts
+    let inputs = req.input.map(|input| input.into_texts().0).unwrap_or_default();
+    let input_count = inputs.len();
+    let texts: Vec<String> = inputs
+        .into_iter()
+        .chain(req.pairs.iter().flat_map(|(a, b)| [a.clone(), b.clone()]))
+        .enumerate()
+        .map(|(index, text)| cap_input_bytes(&state, "input", index, text, false))
+        .collect::<Result<_, _>>()?;
+    if texts.is_empty() {
+        return Err(invalid("input", "Provide input, pairs or both").reason("empty_input"));
+    }
+    if texts.iter().any(|text| text.trim().is_empty()) {
+        return Err(invalid("input", "Input cannot be empty").reason("empty_input"));
+    }
+    if texts.len() > state.limits.max_inputs {
+        return Err(invalid(
+            "input",
+            format!(
+                "Too many texts: inputs and pair texts together can be at most {}, got {}",
+                state.limits.max_inputs,
+                texts.len()
+            ),
+        )
+        .code("too_many_inputs")
+        .reason("too_many_inputs"));
+    }
+
+    // A slot on every model before any of them embeds, so one at its limit
+    // fails the request without wasting the others' turns
+    let _model_slots = targets
+        .iter()
+        .map(|target| {
+            state.model_limits.acquire(match target {
+                Target::Primary => &state.model_name,
+                Target::Loaded(spec, ..) => spec.name,
+                Target::Pooled(model) => model.spec.name,
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let (input_type, with_pairs) = (req.input_type, !req.pairs.is_empty());
+    // Every model's tokens are counted first, so the quota sees the whole request
+    let counted = try_join_all(targets.iter().map(|target| {
+        let texts = texts.clone();
+        let state = &state;
+        async move {
+            let (spec, dimensions, embedder) = target.model(state);
+            let texts: Vec<String> = match spec.prefix(input_type) {
+                Some(prefix) => texts.into_iter().map(|text| format!("{}{}", prefix, text)).collect(),
+                None => texts,
+            };
+            let tokens = count_tokens(embedder.clone(), texts.clone()).await?;
+            Ok::<_, ApiError>((target, spec, dimensions, texts, tokens))
+        }
+    }))
+    .await?;
+    admit(&state, &scheduling, counted.iter().map(|(.., tokens)| tokens).sum())?;
+
+    // Every model at once; each call waits for its own turns on the queue
+    let results = try_join_all(counted.into_iter().map(|(target, spec, dimensions, texts, tokens)| {
+        let state = &state;
+        let scheduling = &scheduling;
+        async move {
+            let embeddings = target.embed(state, texts, scheduling).await?;
+            let similarities = with_pairs.then(|| {
+                embeddings[input_count..]
+                    .chunks(2)
+                    .map(|pair| cosine(&pair[0], &pair[1]))
+                    .collect()
+            });
+            let mut embeddings = embeddings;
+            embeddings.truncate(input_count);
+            Ok::<_, ApiError>(ModelResult {
+                model: spec.name.to_string(),
+                dimensions,
+                embeddings,
+                similarities,
+                usage: ModelUsage { prompt_tokens: tokens },
+            })
+        }
+    }))
+    .await?;
+
+    // Charged once every model has embedded
+    for result in &results {
+        let tokens = result.usage.prompt_tokens;
+        state.comparison.tokens.with_label_values(&[&result.model]).inc_by(tokens as f64);
+        charge(&state, &scheduling, texts.len(), tokens);
+    }
+
+    Ok(Json(CompareResponse {
+        object: "list",
+        data: results,
+    }))
+}
+
+async fn count_tokens(embedder: Arc<Embedder>, texts: Vec<String>) -> Result<usize, ApiError> {
+    tokio::task::spawn_blocking(move || embedder.count_tokens(&texts))
+        .await
+        .map_err(|e| anyhow::anyhow!(e))
+        .and_then(|counts| counts)
+        .map(|counts| counts.iter().map(|count| count.tokens).sum())
+        .map_err(|e| {
+            ApiError::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "internal_error",
+                format!("Failed to tokenize inputs: {}", e),
+            )
+            .reason("tokenization_failed")
+        })
+}
+
+// Embed with a model other than the primary, in batches that each take a
+// turn on the queue like the primary's do
+async fn embed(
+    state: &AppState,
+    embedder: Arc<Embedder>,
+    texts: Vec<String>,
+    scheduling: &Scheduling,
+) -> Result<Vec<Vec<f32>>, EmbedError> {
+    let priority = scheduling.priority.unwrap_or(state.default_priority);
+    let batch_size = state.queue.batch_size(priority);
+    let cancel = CancellationToken::new();
+    // Abandoned batches are skipped if the client leaves
+    let _guard = cancel.clone().drop_guard();
+    let mut embeddings = Vec::with_capacity(texts.len());
+    let mut texts = texts.into_iter().peekable();
+    while texts.peek().is_some() {
+        let batch: Vec<String> = texts.by_ref().take(batch_size).collect();
+        let turn = state.queue.acquire(priority, &scheduling.tenant, batch.len()).await;
+        let embedder = embedder.clone();
+        let cancel = cancel.clone();
+        let batch = tokio::task::spawn_blocking(move || {
+            let _turn = turn;
+            if cancel.is_cancelled() {
+                return Ok(Vec::new());
+            }
+            embedder.embed(batch.iter().map(String::as_str).collect(), &cancel)
+        })
+        .await
+        .unwrap_or_else(|e| Err(EmbedError::Inference(e.into())))?;
+        embeddings.extend(batch);
+    }
+    Ok(embeddings)
+}
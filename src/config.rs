@@ -0,0 +1,519 @@
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+
+use tracing::warn;
+
+use crate::access_log;
+use crate::affinity;
+use crate::attribution::UserLabels;
+use crate::embedder::TruncationSide;
+use crate::memory::MemoryLimit;
+use crate::model_limits;
+use crate::multivector::MultiVector;
+use crate::pool;
+use crate::preload;
+use crate::preprocess::{Preprocess, Sanitize};
+use crate::queue::{Priority, TenantShares};
+use crate::quota::TokenQuotas;
+use crate::routing;
+use crate::{listen, models, EMBEDDER_WORKERS, MAX_FLOAT_PRECISION};
+
+// What a variable's value must look like. Unset variables are never checked,
+// and neither are empty ones except where an empty value means something.
+enum Expect {
+    Flag,
+    Port,
+    // An integer between the two bounds, inclusive
+    Integer(u64, u64),
+    ExistingFile,
+    // Validated by the parser that reads it at startup
+    Parsed(fn(&str) -> anyhow::Result<()>),
+    // Any value
+    Text,
+}
+
+impl fmt::Display for Expect {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Flag => write!(f, "a boolean (true/false, 1/0, yes/no, on/off)"),
+            Self::Port => write!(f, "a port between 1 and 65535"),
+            Self::Integer(0, max) if *max == u64::MAX => write!(f, "a non-negative integer"),
+            Self::Integer(min, max) if *max == u64::MAX => write!(f, "an integer of at least {}", min),
+            Self::Integer(min, max) => write!(f, "an integer between {} and {}", min, max),
+            Self::ExistingFile => write!(f, "the path of an existing file"),
+            Self::Parsed(_) | Self::Text => Ok(()),
+        }
+    }
+}
+
+const POSITIVE: Expect = Expect::Integer(1, u64::MAX);
+const NON_NEGATIVE: Expect = Expect::Integer(0, u64::MAX);
+
+// Every variable semembed reads; anything else starting with SEMEMBED_ is
+// reported as a likely typo. Add new variables here.
+const VARIABLES: &[(&str, Expect)] = &[
+    ("SEMEMBED_CONFIG_FILE", Expect::ExistingFile),
+    ("SEMEMBED_MODEL", Expect::Parsed(check_model)),
+    ("SEMEMBED_MODEL_LOAD_CONCURRENCY", POSITIVE),
+    ("SEMEMBED_HF_ENDPOINT", Expect::Text),
+    ("SEMEMBED_MODEL_DOWNLOAD_ATTEMPTS", POSITIVE),
+    ("SEMEMBED_READY_ON", Expect::Parsed(|value| preload::ReadyOn::from_str(value).map(drop))),
+    ("SEMEMBED_LOAD_FAILURE_EXIT_SECS", NON_NEGATIVE),
+    ("SEMEMBED_HOST", Expect::Parsed(check_host)),
+    ("SEMEMBED_PORT", Expect::Port),
+    ("SEMEMBED_REUSEPORT", Expect::Flag),
+    ("SEMEMBED_LISTEN_BACKLOG", Expect::Integer(1, i32::MAX as u64)),
+    ("SEMEMBED_TCP_NODELAY", Expect::Flag),
+    ("SEMEMBED_TOKIO_WORKERS", POSITIVE),
+    ("SEMEMBED_BLOCKING_THREADS", Expect::Integer(EMBEDDER_WORKERS as u64, u64::MAX)),
+    ("SEMEMBED_HTTP_IDLE_TIMEOUT_SECS", NON_NEGATIVE),
+    ("SEMEMBED_HTTP_HEADER_READ_TIMEOUT_SECS", NON_NEGATIVE),
+    ("SEMEMBED_HTTP2_MAX_CONCURRENT_STREAMS", Expect::Integer(1, u32::MAX as u64)),
+    ("SEMEMBED_HTTP2_H2C", Expect::Flag),
+    ("SEMEMBED_DOCS_DISABLED", Expect::Flag),
+    ("SEMEMBED_MODEL_ALIASES", Expect::Parsed(|value| models::parse_aliases(value).map(drop))),
+    ("SEMEMBED_MODEL_ECHO", Expect::Parsed(|value| models::ModelEcho::from_str(value).map(drop))),
+    ("SEMEMBED_INSTRUCTIONS", Expect::Parsed(|value| models::parse_instructions(value).map(drop))),
+    ("SEMEMBED_FLOAT_PRECISION", Expect::Integer(0, MAX_FLOAT_PRECISION as u64)),
+    ("SEMEMBED_TRUNCATION_SIDE", Expect::Parsed(|value| TruncationSide::from_str(value).map(drop))),
+    ("SEMEMBED_DEFAULT_PRIORITY", Expect::Parsed(|value| Priority::from_str(value).map(drop))),
+    ("SEMEMBED_LOW_PRIORITY_BATCH", POSITIVE),
+    ("SEMEMBED_TENANT_SHARES", Expect::Parsed(|value| TenantShares::from_str(value).map(drop))),
+    ("SEMEMBED_MODEL_CONCURRENCY", Expect::Parsed(|value| model_limits::parse(value).map(drop))),
+    ("SEMEMBED_DAILY_TOKEN_QUOTAS", Expect::Parsed(|value| TokenQuotas::from_str(value).map(drop))),
+    ("SEMEMBED_MONTHLY_TOKEN_QUOTAS", Expect::Parsed(|value| TokenQuotas::from_str(value).map(drop))),
+    ("SEMEMBED_CACHE_CAPACITY", NON_NEGATIVE),
+    ("SEMEMBED_CACHE_WARM_FILE", Expect::ExistingFile),
+    ("SEMEMBED_CACHE_WARM_BLOCKING", Expect::Flag),
+    ("SEMEMBED_UPLOAD_MAX_FILE_BYTES", POSITIVE),
+    ("SEMEMBED_UPLOAD_MAX_BYTES", POSITIVE),
+    ("SEMEMBED_URL_FETCH", Expect::Flag),
+    ("SEMEMBED_URL_MAX_URLS", POSITIVE),
+    ("SEMEMBED_URL_MAX_BYTES", POSITIVE),
+    ("SEMEMBED_URL_TIMEOUT_SECS", POSITIVE),
+    ("SEMEMBED_URL_ALLOWLIST", Expect::Text),
+    ("SEMEMBED_COLBERT_MODEL", Expect::Parsed(|value| MultiVector::parse_model(value).map(drop))),
+    ("SEMEMBED_COLBERT_MAX_INPUTS", POSITIVE),
+    ("SEMEMBED_TOKENS_MAX_INPUTS", POSITIVE),
+    ("SEMEMBED_USER_METRICS", Expect::Parsed(|value| UserLabels::from_str(value).map(drop))),
+    ("SEMEMBED_MAX_INPUTS", POSITIVE),
+    ("SEMEMBED_MAX_TOKENS_PER_REQUEST", POSITIVE),
+    ("SEMEMBED_MAX_BODY_BYTES", POSITIVE),
+    ("SEMEMBED_MAX_INPUT_BYTES", POSITIVE),
+    ("SEMEMBED_MAX_METADATA_BYTES", POSITIVE),
+    ("SEMEMBED_MAX_METADATA_TOTAL_BYTES", POSITIVE),
+    ("SEMEMBED_BULK_MAX_LINE_BYTES", POSITIVE),
+    ("SEMEMBED_BULK_MAX_LINES", POSITIVE),
+    ("SEMEMBED_JOBS", Expect::Flag),
+    ("SEMEMBED_JOBS_CONCURRENCY", POSITIVE),
+    ("SEMEMBED_JOBS_RETAINED", NON_NEGATIVE),
+    ("SEMEMBED_PGVECTOR_DSN", Expect::Text),
+    ("SEMEMBED_PGVECTOR_MAX_CONNECTIONS", Expect::Integer(1, u32::MAX as u64)),
+    ("SEMEMBED_PGVECTOR_ACQUIRE_TIMEOUT_SECS", POSITIVE),
+    ("SEMEMBED_PGVECTOR_ID_COLUMN", Expect::Parsed(check_identifier)),
+    ("SEMEMBED_PGVECTOR_VECTOR_COLUMN", Expect::Parsed(check_identifier)),
+    ("SEMEMBED_PGVECTOR_TEXT_COLUMN", Expect::Parsed(check_identifier)),
+    ("SEMEMBED_PGVECTOR_METADATA_COLUMN", Expect::Parsed(check_identifier)),
+    ("SEMEMBED_SHADOW_MODEL", Expect::Parsed(check_model)),
+    ("SEMEMBED_SHADOW_SAMPLE_RATE", Expect::Parsed(check_share)),
+    ("SEMEMBED_SHADOW_MAX_IN_FLIGHT", POSITIVE),
+    ("SEMEMBED_SHADOW_DUMP_FILE", Expect::Text),
+    ("SEMEMBED_TOKENIZER_JSON", Expect::Flag),
+    ("SEMEMBED_COMPARE_MODELS", Expect::Parsed(check_models)),
+    ("SEMEMBED_LANGUAGE_ROUTES", Expect::Parsed(|value| routing::parse_routes(value).map(drop))),
+    ("SEMEMBED_LANGUAGE_ROUTING_ALIAS", Expect::Text),
+    ("SEMEMBED_LANGUAGE_MIN_CONFIDENCE", Expect::Parsed(check_share)),
+    ("SEMEMBED_LANGUAGE_CACHE_CAPACITY", NON_NEGATIVE),
+    ("SEMEMBED_LANGUAGE_METRICS_TOP", NON_NEGATIVE),
+    ("SEMEMBED_MODEL_MEMORY_BUDGET", Expect::Parsed(|value| MemoryLimit::from_str(value).map(drop))),
+    ("SEMEMBED_MAX_RESIDENT_MODELS", POSITIVE),
+    ("SEMEMBED_MODEL_LOAD_POLICY", Expect::Parsed(|value| pool::LoadPolicy::from_str(value).map(drop))),
+    ("SEMEMBED_DRIFT_WINDOW", POSITIVE),
+    ("SEMEMBED_DRIFT_SAMPLE", POSITIVE),
+    ("SEMEMBED_SERVER_TIMING", Expect::Flag),
+    ("SEMEMBED_ACCESS_LOG", Expect::Text),
+    ("SEMEMBED_ACCESS_LOG_FORMAT", Expect::Parsed(|value| access_log::Format::from_str(value).map(drop))),
+    ("SEMEMBED_ACCESS_LOG_ROTATION", Expect::Parsed(|value| access_log::Rotation::from_str(value).map(drop))),
+    ("SEMEMBED_ACCESS_LOG_MAX_BYTES", POSITIVE),
+    ("SEMEMBED_ACCESS_LOG_KEEP", NON_NEGATIVE),
+    ("SEMEMBED_AUDIT_LOG", Expect::Text),
+    ("SEMEMBED_AUDIT_SALT", Expect::Text),
+    ("SEMEMBED_GET_EMBEDDINGS", Expect::Flag),
+    ("SEMEMBED_GET_MAX_URI_BYTES", POSITIVE),
+    ("SEMEMBED_GET_CACHE_CONTROL", Expect::Text),
+    ("SEMEMBED_DEBUG_LOG_PREFIX_CHARS", NON_NEGATIVE),
+    ("SEMEMBED_DEBUG_LOG_MAX_MINUTES", POSITIVE),
+    ("SEMEMBED_USAGE_FILE", Expect::Text),
+    ("SEMEMBED_USAGE_FLUSH_SECS", POSITIVE),
+    ("SEMEMBED_USAGE_MAX_KEYS", POSITIVE),
+    ("SEMEMBED_SINK", Expect::Parsed(check_sink)),
+    ("SEMEMBED_SINK_BATCH_SIZE", POSITIVE),
+    ("SEMEMBED_SINK_RETRIES", Expect::Integer(0, u32::MAX as u64)),
+    ("SEMEMBED_KAFKA_BROKERS", Expect::Text),
+    ("SEMEMBED_KAFKA_GROUP_ID", Expect::Text),
+    ("SEMEMBED_KAFKA_INPUT_TOPIC", Expect::Text),
+    ("SEMEMBED_KAFKA_OUTPUT_TOPIC", Expect::Text),
+    ("SEMEMBED_KAFKA_DLQ_TOPIC", Expect::Text),
+    ("SEMEMBED_KAFKA_TEXT_FIELD", Expect::Parsed(check_field_path)),
+    ("SEMEMBED_KAFKA_BATCH_SIZE", POSITIVE),
+    ("SEMEMBED_KAFKA_LINGER_MS", NON_NEGATIVE),
+    ("SEMEMBED_KAFKA_PRIORITY", Expect::Parsed(|value| Priority::from_str(value).map(drop))),
+    ("SEMEMBED_KAFKA_DIMENSIONS", POSITIVE),
+    ("SEMEMBED_KAFKA_PROPERTIES", Expect::Parsed(check_properties)),
+    ("SEMEMBED_KAFKA_STANDALONE", Expect::Flag),
+    ("SEMEMBED_REDIS_URL", Expect::Text),
+    ("SEMEMBED_REDIS_LIST", Expect::Text),
+    ("SEMEMBED_REDIS_STREAM", Expect::Text),
+    ("SEMEMBED_REDIS_GROUP", Expect::Text),
+    ("SEMEMBED_REDIS_CONSUMER", Expect::Text),
+    ("SEMEMBED_REDIS_RESULTS_STREAM", Expect::Text),
+    ("SEMEMBED_REDIS_RESULT_TTL_SECS", POSITIVE),
+    ("SEMEMBED_REDIS_RECLAIM_IDLE_SECS", POSITIVE),
+    ("SEMEMBED_REDIS_MAX_DELIVERIES", POSITIVE),
+    ("SEMEMBED_REDIS_PRIORITY", Expect::Parsed(|value| Priority::from_str(value).map(drop))),
+    ("SEMEMBED_REDIS_STANDALONE", Expect::Flag),
+    ("SEMEMBED_PUSHGATEWAY_URL", Expect::Parsed(check_http_url)),
+    ("SEMEMBED_REMOTE_WRITE_URL", Expect::Parsed(check_http_url)),
+    ("SEMEMBED_PUSH_JOB", Expect::Text),
+    ("SEMEMBED_PUSH_INSTANCE", Expect::Text),
+    ("SEMEMBED_PUSH_INTERVAL_SECS", POSITIVE),
+    ("SEMEMBED_PUSH_USERNAME", Expect::Text),
+    ("SEMEMBED_PUSH_PASSWORD", Expect::Text),
+    ("SEMEMBED_PUSH_RETRIES", Expect::Integer(0, u32::MAX as u64)),
+    ("SEMEMBED_STATSD_ADDR", Expect::Text),
+    ("SEMEMBED_STATSD_PREFIX", Expect::Text),
+    ("SEMEMBED_STATSD_TAGS", Expect::Text),
+    ("SEMEMBED_STATSD_FLUSH_SECS", POSITIVE),
+    ("SEMEMBED_PREPROCESS", Expect::Parsed(|value| Preprocess::from_str(value).map(drop))),
+    ("SEMEMBED_SANITIZE", Expect::Parsed(|value| Sanitize::from_str(value).map(drop))),
+    ("SEMEMBED_METRICS_TOKEN", Expect::Text),
+    ("SEMEMBED_METRICS_PORT", Expect::Port),
+    ("SEMEMBED_METRICS_HOST", Expect::Parsed(check_host)),
+    ("SEMEMBED_PPROF", Expect::Flag),
+    ("SEMEMBED_CLUSTER_MAX_INPUTS", POSITIVE),
+    ("SEMEMBED_DEDUP_MAX_INPUTS", POSITIVE),
+    ("SEMEMBED_SIMILARITY_MAX_CELLS", POSITIVE),
+    ("SEMEMBED_CIRCUIT_FAILURE_THRESHOLD", NON_NEGATIVE),
+    ("SEMEMBED_CIRCUIT_RETRY_AFTER_SECS", POSITIVE),
+    ("SEMEMBED_INFERENCE_HANG_SECS", NON_NEGATIVE),
+    ("SEMEMBED_INFERENCE_RETRIES", NON_NEGATIVE),
+    ("SEMEMBED_BATCH_MAX_TOKENS", POSITIVE),
+    ("SEMEMBED_CPU_AFFINITY", Expect::Parsed(|value| affinity::parse_affinity(value).map(drop))),
+    ("SEMEMBED_NUMA_AWARE", Expect::Flag),
+    ("SEMEMBED_BATCH_RSS_GROWTH_BYTES", NON_NEGATIVE),
+    ("SEMEMBED_IDEMPOTENCY_CAPACITY", NON_NEGATIVE),
+    ("SEMEMBED_IDEMPOTENCY_TTL_SECS", POSITIVE),
+    ("SEMEMBED_LATENCY_WINDOW_SECS", POSITIVE),
+    ("SEMEMBED_READY_MAX_QUEUE_DEPTH", NON_NEGATIVE),
+    ("SEMEMBED_READY_MAX_P95_MS", POSITIVE),
+    ("SEMEMBED_READY_MAX_ERROR_RATE", Expect::Parsed(check_share)),
+    ("SEMEMBED_READY_FAIL_DEGRADED", Expect::Flag),
+    ("SEMEMBED_HEALTH_CACHE_SECS", NON_NEGATIVE),
+    ("SEMEMBED_SHED_TARGET_P95_MS", POSITIVE),
+    ("SEMEMBED_MAX_RSS_BYTES", Expect::Parsed(|value| MemoryLimit::from_str(value).map(drop))),
+];
+
+fn check_model(value: &str) -> anyhow::Result<()> {
+    if models::model_spec(value).is_some() {
+        return Ok(());
+    }
+    anyhow::bail!("unknown model (supported: {})", models::model_names().join(", "))
+}
+
+fn check_models(value: &str) -> anyhow::Result<()> {
+    value.split(',').map(str::trim).filter(|name| !name.is_empty()).try_for_each(check_model)
+}
+
+fn check_share(value: &str) -> anyhow::Result<()> {
+    match value.trim().parse::<f64>() {
+        Ok(share) if (0.0..=1.0).contains(&share) => Ok(()),
+        _ => anyhow::bail!("expected a number between 0 and 1"),
+    }
+}
+
+fn check_host(value: &str) -> anyhow::Result<()> {
+    listen::parse_addrs(value, 1).map(drop)
+}
+
+fn check_field_path(value: &str) -> anyhow::Result<()> {
+    if value.trim().split('.').any(str::is_empty) {
+        anyhow::bail!("expected field names separated by dots, e.g. document.body");
+    }
+    Ok(())
+}
+
+// A plain SQL identifier, as pgvector table and column names must be
+fn check_identifier(value: &str) -> anyhow::Result<()> {
+    let value = value.trim();
+    let mut chars = value.chars();
+    let valid = value.len() <= 63
+        && chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid {
+        anyhow::bail!("expected an identifier of letters, digits and underscores, at most 63 characters");
+    }
+    Ok(())
+}
+
+fn check_http_url(value: &str) -> anyhow::Result<()> {
+    match reqwest::Url::parse(value.trim()) {
+        Ok(url) if matches!(url.scheme(), "http" | "https") => Ok(()),
+        _ => anyhow::bail!("expected an http or https URL"),
+    }
+}
+
+fn check_sink(value: &str) -> anyhow::Result<()> {
+    match value.trim() {
+        "pgvector" => Ok(()),
+        _ => anyhow::bail!("expected pgvector"),
+    }
+}
+
+fn check_properties(value: &str) -> anyhow::Result<()> {
+    match value.split(',').map(str::trim).find(|entry| !entry.is_empty() && !entry.contains('=')) {
+        Some(entry) => anyhow::bail!("invalid Kafka property {:?} (expected <key>=<value>)", entry),
+        None => Ok(()),
+    }
+}
+
+// One invalid variable
+struct Problem {
+    name: &'static str,
+    value: String,
+    expected: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "  {}={:?}: expected {}", self.name, self.value, self.expected)
+    }
+}
+
+/// Check every `SEMEMBED_*` variable before anything is loaded, failing with
+/// all the problems found at once rather than the first. Variables that
+/// semembed doesn't read are only warned about.
+pub fn validate() -> anyhow::Result<()> {
+    let mut problems = Vec::new();
+    for (name, expect) in VARIABLES {
+        let Ok(value) = std::env::var(name) else {
+            continue;
+        };
+        if let Err(expected) = check(expect, &value) {
+            problems.push(Problem { name, value, expected });
+        }
+    }
+    problems.extend(check_combinations());
+
+    for (name, _) in std::env::vars_os() {
+        let Some(name) = name.to_str().filter(|name| name.starts_with("SEMEMBED_")) else {
+            continue;
+        };
+        if !is_known(name) {
+            warn_unknown("environment variable", name);
+        }
+    }
+
+    if problems.is_empty() {
+        return Ok(());
+    }
+    let lines: Vec<String> = problems.iter().map(Problem::to_string).collect();
+    anyhow::bail!(
+        "invalid configuration ({} problem{}):\n{}",
+        problems.len(),
+        if problems.len() == 1 { "" } else { "s" },
+        lines.join("\n")
+    )
+}
+
+/// Check one variable's value, as `validate` does; the error is what was
+/// expected instead. Unknown names are not checked.
+pub fn check_variable(name: &str, value: &str) -> Result<(), String> {
+    match VARIABLES.iter().find(|(known, _)| *known == name) {
+        Some((_, expect)) => check(expect, value),
+        None => Ok(()),
+    }
+}
+
+/// Whether semembed reads this `SEMEMBED_*` variable.
+pub fn is_known(name: &str) -> bool {
+    VARIABLES.iter().any(|(known, _)| *known == name)
+}
+
+// Why `value` doesn't meet `expect`, as the text after "expected"
+fn check(expect: &Expect, value: &str) -> Result<(), String> {
+    let trimmed = value.trim();
+    let valid = match expect {
+        Expect::Flag => matches!(
+            trimmed.to_ascii_lowercase().as_str(),
+            "1" | "true" | "yes" | "on" | "0" | "false" | "no" | "off" | ""
+        ),
+        _ if trimmed.is_empty() => true,
+        Expect::Port => trimmed.parse::<u16>().is_ok_and(|port| port > 0),
+        Expect::Integer(min, max) => trimmed.parse::<u64>().is_ok_and(|n| (*min..=*max).contains(&n)),
+        Expect::ExistingFile => Path::new(trimmed).is_file(),
+        Expect::Parsed(parse) => return parse(value).map_err(|e| format!("a valid value: {:#}", e)),
+        Expect::Text => true,
+    };
+    if valid {
+        Ok(())
+    } else {
+        Err(expect.to_string())
+    }
+}
+
+// Settings that are valid alone but not together
+fn check_combinations() -> Vec<Problem> {
+    let set = |name: &str| std::env::var(name).is_ok_and(|value| !value.trim().is_empty());
+    let flag = |name: &str| {
+        std::env::var(name).is_ok_and(|value| matches!(value.trim().to_ascii_lowercase().as_str(), "1" | "true" | "yes" | "on"))
+    };
+    let value = |name: &str| std::env::var(name).unwrap_or_default();
+    let mut problems = Vec::new();
+    let cache_capacity = value("SEMEMBED_CACHE_CAPACITY").trim().parse::<u64>().unwrap_or(0);
+    if set("SEMEMBED_CACHE_WARM_FILE") && cache_capacity == 0 {
+        problems.push(Problem {
+            name: "SEMEMBED_CACHE_WARM_FILE",
+            value: value("SEMEMBED_CACHE_WARM_FILE"),
+            expected: "SEMEMBED_CACHE_CAPACITY to be set as well".to_string(),
+        });
+    }
+    if flag("SEMEMBED_PPROF") && !cfg!(feature = "pprof") {
+        problems.push(Problem {
+            name: "SEMEMBED_PPROF",
+            value: value("SEMEMBED_PPROF"),
+            expected: "a build with the `pprof` feature".to_string(),
+        });
+    }
+    if set("SEMEMBED_PGVECTOR_DSN") && !cfg!(feature = "pgvector") {
+        problems.push(Problem {
+            name: "SEMEMBED_PGVECTOR_DSN",
+            // The DSN may carry a password
+            value: "(redacted)".to_string(),
+            expected: "a build with the `pgvector` feature".to_string(),
+        });
+    }
+    if value("SEMEMBED_SINK").trim() == "pgvector" {
+        if !cfg!(feature = "pgvector") {
+            problems.push(Problem {
+                name: "SEMEMBED_SINK",
+                value: value("SEMEMBED_SINK"),
+                expected: "a build with the `pgvector` feature".to_string(),
+            });
+        } else if !set("SEMEMBED_PGVECTOR_DSN") {
+            problems.push(Problem {
+                name: "SEMEMBED_SINK",
+                value: value("SEMEMBED_SINK"),
+                expected: "SEMEMBED_PGVECTOR_DSN to be set as well".to_string(),
+            });
+        }
+    }
+    if set("SEMEMBED_KAFKA_BROKERS") && !cfg!(feature = "kafka") {
+        problems.push(Problem {
+            name: "SEMEMBED_KAFKA_BROKERS",
+            value: value("SEMEMBED_KAFKA_BROKERS"),
+            expected: "a build with the `kafka` feature".to_string(),
+        });
+    }
+    if set("SEMEMBED_KAFKA_BROKERS") {
+        for name in ["SEMEMBED_KAFKA_INPUT_TOPIC", "SEMEMBED_KAFKA_OUTPUT_TOPIC"] {
+            if !set(name) {
+                problems.push(Problem {
+                    name,
+                    value: value(name),
+                    expected: "a topic name, since SEMEMBED_KAFKA_BROKERS is set".to_string(),
+                });
+            }
+        }
+    }
+    if flag("SEMEMBED_KAFKA_STANDALONE") && !set("SEMEMBED_KAFKA_BROKERS") {
+        problems.push(Problem {
+            name: "SEMEMBED_KAFKA_STANDALONE",
+            value: value("SEMEMBED_KAFKA_STANDALONE"),
+            expected: "SEMEMBED_KAFKA_BROKERS to be set as well".to_string(),
+        });
+    }
+    let redis_worker = set("SEMEMBED_REDIS_LIST") || set("SEMEMBED_REDIS_STREAM");
+    if redis_worker && !cfg!(feature = "redis") {
+        let name = if set("SEMEMBED_REDIS_LIST") { "SEMEMBED_REDIS_LIST" } else { "SEMEMBED_REDIS_STREAM" };
+        problems.push(Problem {
+            name,
+            value: value(name),
+            expected: "a build with the `redis` feature".to_string(),
+        });
+    }
+    if set("SEMEMBED_REDIS_LIST") && set("SEMEMBED_REDIS_STREAM") {
+        problems.push(Problem {
+            name: "SEMEMBED_REDIS_LIST",
+            value: value("SEMEMBED_REDIS_LIST"),
+            expected: "SEMEMBED_REDIS_STREAM to be unset; a worker reads a list or a stream".to_string(),
+        });
+    }
+    if set("SEMEMBED_PUSHGATEWAY_URL") && set("SEMEMBED_REMOTE_WRITE_URL") {
+        problems.push(Problem {
+            name: "SEMEMBED_PUSHGATEWAY_URL",
+            // The URL may carry credentials
+            value: "(redacted)".to_string(),
+            expected: "SEMEMBED_REMOTE_WRITE_URL to be unset; metrics are pushed to one of them".to_string(),
+        });
+    }
+    if set("SEMEMBED_PUSH_PASSWORD") && !set("SEMEMBED_PUSH_USERNAME") {
+        problems.push(Problem {
+            name: "SEMEMBED_PUSH_PASSWORD",
+            value: "(redacted)".to_string(),
+            expected: "SEMEMBED_PUSH_USERNAME to be set as well".to_string(),
+        });
+    }
+    if flag("SEMEMBED_REDIS_STANDALONE") && !redis_worker {
+        problems.push(Problem {
+            name: "SEMEMBED_REDIS_STANDALONE",
+            value: value("SEMEMBED_REDIS_STANDALONE"),
+            expected: "SEMEMBED_REDIS_LIST or SEMEMBED_REDIS_STREAM to be set as well".to_string(),
+        });
+    }
+    if flag("SEMEMBED_PPROF") && !set("SEMEMBED_METRICS_TOKEN") {
+        problems.push(Problem {
+            name: "SEMEMBED_PPROF",
+            value: value("SEMEMBED_PPROF"),
+            expected: "SEMEMBED_METRICS_TOKEN to be set as well".to_string(),
+        });
+    }
+    problems
+}
+
+/// Warn that `name` is not a variable semembed reads, suggesting the one it
+/// is most likely a typo of.
+pub fn warn_unknown(source: &str, name: &str) {
+    match closest(name) {
+        Some(known) => warn!("Unknown {} {} is ignored (did you mean {}?)", source, name, known),
+        None => warn!("Unknown {} {} is ignored", source, name),
+    }
+}
+
+// The known variable an unknown one is most likely a typo of
+fn closest(name: &str) -> Option<&'static str> {
+    VARIABLES
+        .iter()
+        .map(|(known, _)| (*known, edit_distance(name, known)))
+        .filter(|&(_, distance)| distance <= 2)
+        .min_by_key(|&(_, distance)| distance)
+        .map(|(known, _)| known)
+}
+
+// Levenshtein distance, counting an adjacent transposition as one edit
+fn edit_distance(a: &str, b: &str) -> usize {
+    let (a, b): (Vec<char>, Vec<char>) = (a.chars().collect(), b.chars().collect());
+    let mut rows = vec![(0..=b.len()).collect::<Vec<usize>>()];
+    for i in 1..=a.len() {
+        let mut row = vec![i; b.len() + 1];
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            row[j] = (rows[i - 1][j] + 1).min(row[j - 1] + 1).min(rows[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                row[j] = row[j].min(rows[i - 2][j - 2] + 1);
+            }
+        }
+        rows.push(row);
+    }
+    rows[a.len()][b.len()]
+}
@@ -0,0 +1,192 @@
+use std::convert::Infallible;
+use std::sync::Arc;
+
+use axum::{
+    body::Body,
+    extract::State,
+    http::{header, HeaderMap, HeaderName, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+
+use crate::error::ApiError;
+use crate::extract::{ApiJson, Scheduling};
+use crate::{
+    create_embeddings, AppState, EmbeddingData, EmbeddingItem, EmbeddingRequest, EncodingFormat, InputType,
+    OutputKind,
+};
+
+const TEXT_CSV: &str = "text/csv";
+const PROMPT_TOKENS: HeaderName = HeaderName::from_static("semembed-prompt-tokens");
+const TOTAL_TOKENS: HeaderName = HeaderName::from_static("semembed-total-tokens");
+const MODEL: HeaderName = HeaderName::from_static("semembed-model");
+
+/// `POST /v1/embeddings`: JSON as usual, or with `Accept: text/csv` one CSV
+/// row per input (`index,token_count,dim_0,…`, with an `id` column after
+/// `index` for `{id, text}` inputs and, with `echo`, `text`).
+/// Usage goes into response headers, since CSV has nowhere else to put it;
+/// errors stay JSON.
+pub(crate) async fn embeddings(
+    state: State<Arc<AppState>>,
+    headers: HeaderMap,
+    scheduling: Scheduling,
+    ApiJson(mut req): ApiJson<EmbeddingRequest>,
+) -> Result<Response, ApiError> {
+    if !accepts_csv(&headers) {
+        return create_embeddings(state, scheduling, ApiJson(req)).await.map(IntoResponse::into_response);
+    }
+    check(&req)?;
+    let texts = req.echo.then(|| match &req.input {
+        InputType::Single(text) => vec![text.clone()],
+        InputType::Batch(texts) => texts.clone(),
+        InputType::Records(records) => records.iter().map(|record| record.text.clone()).collect(),
+    });
+    let with_ids = matches!(req.input, InputType::Records(_));
+    req.return_token_details = true;
+    let Json(response) = create_embeddings(state, scheduling, ApiJson(req)).await?;
+
+    let mut rows = Vec::with_capacity(response.data.len());
+    for item in response.data {
+        let EmbeddingItem::Embedding(item) = item else {
+            continue;
+        };
+        let EmbeddingData::Float(floats) = item.embedding else {
+            continue;
+        };
+        // String ids are written as they are, numbers as JSON writes them
+        let id = item.id.map(|id| match id {
+            serde_json::Value::String(id) => id,
+            other => other.to_string(),
+        });
+        rows.push((item.index, id, item.tokens.unwrap_or_default(), floats));
+    }
+    let dimensions = rows.first().map_or(0, |(_, _, _, floats)| floats.0.len());
+    let header = row(
+        std::iter::once("index".to_string())
+            .chain(with_ids.then(|| "id".to_string()))
+            .chain(std::iter::once("token_count".to_string()))
+            .chain((0..dimensions).map(|dimension| format!("dim_{}", dimension)))
+            .chain(texts.is_some().then(|| "text".to_string())),
+    );
+    // Rows are written as the body is sent rather than into one document
+    let body = std::iter::once(header).chain(rows.into_iter().map(move |(index, id, tokens, floats)| {
+        let text = texts.as_ref().map(|texts| texts.get(index).cloned().unwrap_or_default());
+        row(std::iter::once(index.to_string())
+            .chain(id)
+            .chain(std::iter::once(tokens.to_string()))
+            .chain(floats.rounded().map(|value| value.to_string()))
+            .chain(text))
+    }));
+
+    let mut response_headers = HeaderMap::new();
+    response_headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("text/csv; charset=utf-8; header=present"));
+    response_headers.insert(PROMPT_TOKENS, HeaderValue::from(response.usage.prompt_tokens));
+    response_headers.insert(TOTAL_TOKENS, HeaderValue::from(response.usage.total_tokens));
+    if let Ok(model) = HeaderValue::from_str(&response.model) {
+        response_headers.insert(MODEL, model);
+    }
+    let body = Body::from_stream(futures_util::stream::iter(body.map(Ok::<_, Infallible>)));
+    Ok((response_headers, body).into_response())
+}
+
+// Whether the client asked for CSV; JSON stays the default for anything else
+fn accepts_csv(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|range| {
+            let mut params = range.split(';').map(str::trim);
+            let media_type = params.next().unwrap_or_default();
+            let refused = params.any(|param| {
+                param
+                    .strip_prefix("q=")
+                    .and_then(|q| q.parse::<f32>().ok())
+                    .is_some_and(|q| q <= 0.0)
+            });
+            media_type.eq_ignore_ascii_case(TEXT_CSV) && !refused
+        })
+}
+
+// CSV has one float column per dimension, so only plain dense output fits
+fn check(req: &EmbeddingRequest) -> Result<(), ApiError> {
+    let unsupported = |param: &'static str, message: &str| {
+        Err(ApiError::new(StatusCode::BAD_REQUEST, "invalid_request_error", message)
+            .param(param)
+            .code("unsupported_parameter")
+            .reason("unsupported_parameter"))
+    };
+    match req.encoding_format {
+        EncodingFormat::Float => {}
+        EncodingFormat::Base64 => {
+            return unsupported("encoding_format", "encoding_format \"base64\" can't be returned as CSV");
+        }
+        EncodingFormat::Base64Matrix => {
+            return unsupported("encoding_format", "encoding_format \"base64_matrix\" can't be returned as CSV");
+        }
+    }
+    if req.output != OutputKind::Dense {
+        return unsupported("output", "only dense output can be returned as CSV");
+    }
+    if req.partial {
+        return unsupported("partial", "partial results can't be returned as CSV");
+    }
+    Ok(())
+}
+
+// One RFC 4180 record, terminated by CRLF
+fn row(fields: impl IntoIterator<Item = String>) -> Vec<u8> {
+    let mut writer = csv::WriterBuilder::new()
+        .terminator(csv::Terminator::CRLF)
+        .from_writer(Vec::new());
+    let fields: Vec<String> = fields.into_iter().collect();
+    // Writing into a Vec can't fail
+    let _ = writer.write_record(&fields);
+    writer.into_inner().unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn accept(value: &'static str) -> HeaderMap {
+        HeaderMap::from_iter([(header::ACCEPT, HeaderValue::from_static(value))])
+    }
+
+    #[test]
+    fn csv_is_negotiated_only_when_asked_for() {
+        assert!(accepts_csv(&accept("text/csv")));
+        assert!(accepts_csv(&accept("application/json;q=0.5, Text/CSV; charset=utf-8")));
+        assert!(!accepts_csv(&accept("application/json")));
+        assert!(!accepts_csv(&accept("*/*")));
+        assert!(!accepts_csv(&accept("text/csv;q=0")));
+        assert!(!accepts_csv(&HeaderMap::new()));
+    }
+
+    #[test]
+    fn rows_are_quoted_as_rfc_4180_says() {
+        let fields = ["1", "plain", "a,b", "say \"hi\"", "two\nlines"].map(String::from);
+        assert_eq!(row(fields), b"1,plain,\"a,b\",\"say \"\"hi\"\"\",\"two\nlines\"\r\n");
+    }
+
+    // Errors of a CSV request are JSON, since CSV can't carry them
+    #[tokio::test]
+    async fn unsupported_requests_are_refused_as_json() {
+        for (body, param) in [
+            (r#"{"input": "a", "encoding_format": "base64"}"#, "encoding_format"),
+            (r#"{"input": "a", "partial": true}"#, "partial"),
+        ] {
+            let req: EmbeddingRequest = serde_json::from_str(body).unwrap();
+            let response = check(&req).unwrap_err().into_response();
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+            assert_eq!(response.headers()[header::CONTENT_TYPE], "application/json");
+            let bytes = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            let error: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+            assert_eq!(error["error"]["param"], param);
+            assert_eq!(error["error"]["code"], "unsupported_parameter");
+        }
+        let req: EmbeddingRequest = serde_json::from_str(r#"{"input": ["a", "b"], "echo": true}"#).unwrap();
+        assert!(check(&req).is_ok());
+    }
+}
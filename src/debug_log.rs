@@ -0,0 +1,309 @@
+//! Debug request logging: the bodies of chosen requests, for a limited time.
+//!
+//! User text is never logged by default. While debugging an integration, an
+//! operator can turn this mode on through `POST /admin/debug-logging` for
+//! the requests of one key, or those sending a chosen `X-Debug-Marker`. Their
+//! JSON bodies are then logged under the request id, with every string cut to
+//! `SEMEMBED_DEBUG_LOG_PREFIX_CHARS` characters after emails and card-like
+//! numbers are masked. The mode turns itself off after the minutes it was
+//! given (at most `SEMEMBED_DEBUG_LOG_MAX_MINUTES`). Turning it on or off is
+//! always logged, and `semembed_debug_logging_active` is 1 while it is on.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, PoisonError};
+use std::time::{Duration, Instant};
+
+use axum::{
+    body::{to_bytes, Body},
+    extract::{Request, State},
+    http::{header, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use prometheus::{IntGauge, Registry};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::access_log::{client_request_id, RequestId};
+use crate::error::ApiError;
+use crate::extract::ApiJson;
+use crate::queue::Tenant;
+use crate::AppState;
+
+// Minutes the mode stays on when the request doesn't say
+const DEFAULT_MINUTES: u64 = 15;
+
+/// How much of each request is kept, and for how long the mode may run.
+#[derive(Debug, Clone, Copy)]
+pub struct DebugLogConfig {
+    /// Characters kept of each string in a logged body.
+    pub prefix_chars: usize,
+    pub max_minutes: u64,
+}
+
+// Which requests are logged: those of `key`, and those whose X-Debug-Marker
+// is `marker`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Filter {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    marker: Option<String>,
+}
+
+impl Filter {
+    fn matches(&self, req: &Request) -> bool {
+        let marked = self.marker.as_deref().is_some_and(|marker| {
+            req.headers().get("x-debug-marker").and_then(|value| value.to_str().ok()) == Some(marker)
+        });
+        marked
+            || self.key.as_deref().is_some_and(|key| Tenant::from_headers(req.headers()).as_str() == key)
+    }
+}
+
+struct Session {
+    // Tells a session's expiry timer from the ones after it
+    id: u64,
+    filter: Filter,
+    until: Instant,
+}
+
+/// The debug logging mode, off unless an operator turns it on.
+pub(crate) struct DebugLog {
+    config: DebugLogConfig,
+    session: Mutex<Option<Session>>,
+    sessions: AtomicU64,
+    // Numbers the logged requests that came without an id
+    requests: AtomicU64,
+    active: IntGauge,
+}
+
+impl DebugLog {
+    pub fn new(config: DebugLogConfig, registry: &Registry) -> anyhow::Result<Self> {
+        let active = IntGauge::new("semembed_debug_logging_active", "1 while request bodies are being debug-logged")?;
+        registry.register(Box::new(active.clone()))?;
+        Ok(Self {
+            config,
+            session: Mutex::new(None),
+            sessions: AtomicU64::new(0),
+            requests: AtomicU64::new(0),
+            active,
+        })
+    }
+
+    // Whether the mode is on and its filter matches the request
+    fn matching(&self, req: &Request) -> bool {
+        if self.active.get() == 0 {
+            return false;
+        }
+        let session = self.session.lock().unwrap_or_else(PoisonError::into_inner);
+        session
+            .as_ref()
+            .is_some_and(|session| Instant::now() < session.until && session.filter.matches(req))
+    }
+
+    // Turn the mode off, unless `id` is given and a later session has
+    // replaced that one
+    fn stop(&self, id: Option<u64>, why: &str) {
+        let mut session = self.session.lock().unwrap_or_else(PoisonError::into_inner);
+        match session.as_ref() {
+            Some(current) if id.is_none_or(|id| id == current.id) => {
+                warn!("Debug request logging turned off ({}; filter {:?})", why, current.filter);
+                *session = None;
+                self.active.set(0);
+            }
+            _ => {}
+        }
+    }
+
+    fn status(&self) -> DebugStatus {
+        let session = self.session.lock().unwrap_or_else(PoisonError::into_inner);
+        let now = Instant::now();
+        match session.as_ref().filter(|session| now < session.until) {
+            Some(session) => DebugStatus {
+                object: "debug_logging",
+                active: true,
+                filter: Some(session.filter.clone()),
+                remaining_secs: Some(session.until.duration_since(now).as_secs()),
+                prefix_chars: self.config.prefix_chars,
+            },
+            None => DebugStatus {
+                object: "debug_logging",
+                active: false,
+                filter: None,
+                remaining_secs: None,
+                prefix_chars: self.config.prefix_chars,
+            },
+        }
+    }
+}
+
+// Emails and runs of 13 to 19 digits (optionally grouped by spaces or
+// dashes), which is what card numbers look like
+fn mask(text: &str) -> String {
+    static EMAIL: OnceLock<Regex> = OnceLock::new();
+    static CARD: OnceLock<Regex> = OnceLock::new();
+
+    let email = EMAIL.get_or_init(|| Regex::new(r"[\w.+-]+@[\w-]+(\.[\w-]+)+").expect("valid email pattern"));
+    let card = CARD.get_or_init(|| Regex::new(r"\b\d(?:[ -]?\d){12,18}\b").expect("valid card pattern"));
+    let text = email.replace_all(text, "<email>");
+    card.replace_all(&text, "<card>").into_owned()
+}
+
+// Every string masked, then cut to `prefix` characters
+fn scrub(value: &mut serde_json::Value, prefix: usize) {
+    match value {
+        serde_json::Value::String(text) => {
+            let masked = mask(text);
+            let chars = masked.chars().count();
+            *text = if chars > prefix {
+                format!("{}…(+{} chars)", masked.chars().take(prefix).collect::<String>(), chars - prefix)
+            } else {
+                masked
+            };
+        }
+        serde_json::Value::Array(items) => items.iter_mut().for_each(|item| scrub(item, prefix)),
+        serde_json::Value::Object(fields) => fields.values_mut().for_each(|field| scrub(field, prefix)),
+        _ => {}
+    }
+}
+
+/// Middleware logging the bodies of requests the debug filter matches.
+/// Bodies without a known length (streamed uploads) or over the body limit
+/// pass through unread, and so do bodies that aren't JSON.
+pub(crate) async fn log_body(State(state): State<Arc<AppState>>, req: Request, next: Next) -> Response {
+    let debug = &state.debug_log;
+    if !debug.matching(&req) {
+        return next.run(req).await;
+    }
+    let request_id = req
+        .extensions()
+        .get::<RequestId>()
+        .map(|id| id.0.clone())
+        .or_else(|| client_request_id(req.headers()))
+        .unwrap_or_else(|| format!("debug-{}", debug.requests.fetch_add(1, Ordering::Relaxed) + 1));
+    let length = req
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<usize>().ok());
+    let json = req
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/json"));
+    let (method, path) = (req.method().clone(), req.uri().path().to_string());
+    if !json || length.is_none_or(|length| length > state.limits.max_body_bytes) {
+        info!(target: "semembed::debug_requests", %request_id, %method, %path, ?length, "body not captured");
+        return next.run(req).await;
+    }
+
+    let (parts, body) = req.into_parts();
+    let bytes = match to_bytes(body, state.limits.max_body_bytes).await {
+        Ok(bytes) => bytes,
+        Err(_) => {
+            return ApiError::new(StatusCode::BAD_REQUEST, "invalid_request_error", "Failed to read the request body")
+                .reason("invalid_body")
+                .into_response()
+        }
+    };
+    match serde_json::from_slice::<serde_json::Value>(&bytes) {
+        Ok(mut body) => {
+            scrub(&mut body, debug.config.prefix_chars);
+            info!(target: "semembed::debug_requests", %request_id, %method, %path, %body, "request body");
+        }
+        Err(_) => info!(target: "semembed::debug_requests", %request_id, %method, %path, "body is not valid JSON"),
+    }
+    let mut response = next.run(Request::from_parts(parts, Body::from(bytes))).await;
+    if !response.headers().contains_key("x-request-id") {
+        if let Ok(value) = HeaderValue::from_str(&request_id) {
+            response.headers_mut().insert("x-request-id", value);
+        }
+    }
+    response
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct StartRequest {
+    minutes: Option<u64>,
+    #[serde(flatten)]
+    filter: Filter,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct DebugStatus {
+    object: &'static str,
+    active: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    filter: Option<Filter>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    remaining_secs: Option<u64>,
+    prefix_chars: usize,
+}
+
+fn invalid(param: &'static str, message: impl Into<String>) -> ApiError {
+    ApiError::new(StatusCode::BAD_REQUEST, "invalid_request_error", message)
+        .param(param)
+        .reason("invalid_debug_logging")
+}
+
+/// `GET /admin/debug-logging`: whether the mode is on, and for what.
+pub(crate) async fn status(State(state): State<Arc<AppState>>) -> Json<DebugStatus> {
+    Json(state.debug_log.status())
+}
+
+/// `POST /admin/debug-logging`: log the bodies of the requests matching
+/// `key` or `marker` for `minutes`, replacing any session in progress.
+pub(crate) async fn start(
+    State(state): State<Arc<AppState>>,
+    ApiJson(req): ApiJson<StartRequest>,
+) -> Result<Json<DebugStatus>, ApiError> {
+    let debug = &state.debug_log;
+    // Request bodies must not be one unauthenticated call away
+    if state.settings().metrics_token.is_none() {
+        return Err(ApiError::new(
+            StatusCode::FORBIDDEN,
+            "invalid_request_error",
+            "Debug request logging needs SEMEMBED_METRICS_TOKEN to be set",
+        )
+        .reason("forbidden"));
+    }
+    if req.filter.key.is_none() && req.filter.marker.is_none() {
+        return Err(invalid("key", "Give a key, a marker or both to choose the requests to log"));
+    }
+    let minutes = req.minutes.unwrap_or(DEFAULT_MINUTES);
+    if minutes == 0 || minutes > debug.config.max_minutes {
+        return Err(invalid(
+            "minutes",
+            format!("minutes must be between 1 and {}, got {}", debug.config.max_minutes, minutes),
+        ));
+    }
+    let filter = Filter {
+        key: req.filter.key.map(|key| Tenant::from_id(&key).as_str().to_string()),
+        marker: req.filter.marker,
+    };
+    let id = debug.sessions.fetch_add(1, Ordering::Relaxed) + 1;
+    let duration = Duration::from_secs(minutes * 60);
+    warn!("Debug request logging turned on for {} minutes (filter {:?})", minutes, filter);
+    *debug.session.lock().unwrap_or_else(PoisonError::into_inner) = Some(Session {
+        id,
+        filter,
+        until: Instant::now() + duration,
+    });
+    debug.active.set(1);
+
+    let expiring = state.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep(duration).await;
+        expiring.debug_log.stop(Some(id), "expired");
+    });
+    Ok(Json(debug.status()))
+}
+
+/// `DELETE /admin/debug-logging`: turn the mode off now.
+pub(crate) async fn stop(State(state): State<Arc<AppState>>) -> Json<DebugStatus> {
+    state.debug_log.stop(None, "by request");
+    Json(state.debug_log.status())
+}
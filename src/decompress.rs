@@ -0,0 +1,206 @@
+use std::io::Read;
+
+use axum::{
+    body::{self, Body},
+    extract::{Request, State},
+    http::{header, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use flate2::read::{MultiGzDecoder, ZlibDecoder};
+
+use crate::error::ApiError;
+
+#[derive(Debug, Clone, Copy)]
+enum Encoding {
+    Gzip,
+    Deflate,
+    Zstd,
+}
+
+/// Decompress `Content-Encoding: gzip` / `deflate` / `zstd` request bodies
+/// before they reach the JSON extractor.
+///
+/// The decompressed size is capped at `limit`, the configured body limit (the
+/// same limit the extractor applies to uncompressed bodies), so a small
+/// compressed payload cannot expand without bound. Decoding runs on the
+/// blocking pool, since inflating a large body would stall the async workers.
+pub(crate) async fn decompress_request(State(limit): State<usize>, req: Request, next: Next) -> Response {
+    let Some(encoding) = req.headers().get(header::CONTENT_ENCODING) else {
+        return next.run(req).await;
+    };
+    let encoding = match encoding.to_str().unwrap_or_default().trim().to_ascii_lowercase().as_str() {
+        "" | "identity" => return next.run(req).await,
+        "gzip" | "x-gzip" => Encoding::Gzip,
+        "deflate" => Encoding::Deflate,
+        "zstd" => Encoding::Zstd,
+        other => {
+            return ApiError::new(
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "invalid_request_error",
+                format!(
+                    "Unsupported Content-Encoding {:?}; supported encodings are gzip, deflate and zstd",
+                    other
+                ),
+            )
+            .reason("unsupported_encoding")
+            .into_response();
+        }
+    };
+
+    let (mut parts, body) = req.into_parts();
+    let compressed = match read_compressed(body, limit).await {
+        Ok(compressed) => compressed,
+        Err(error) => return error.into_response(),
+    };
+    let decoded = tokio::task::spawn_blocking(move || decode(encoding, &compressed, limit))
+        .await
+        .unwrap_or_else(|e| {
+            Err(ApiError::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "internal_error",
+                format!("Failed to decompress request body: {}", e),
+            )
+            .reason("decompression_failed"))
+        });
+
+    match decoded {
+        Ok(decoded) => {
+            parts.headers.remove(header::CONTENT_ENCODING);
+            parts.headers.insert(header::CONTENT_LENGTH, HeaderValue::from(decoded.len()));
+            next.run(Request::from_parts(parts, Body::from(decoded))).await
+        }
+        Err(error) => error.into_response(),
+    }
+}
+
+async fn read_compressed(body: Body, limit: usize) -> Result<body::Bytes, ApiError> {
+    body::to_bytes(body, limit).await.map_err(|_| too_large(limit))
+}
+
+fn decode(encoding: Encoding, compressed: &[u8], limit: usize) -> Result<Vec<u8>, ApiError> {
+    match encoding {
+        Encoding::Gzip => inflate(MultiGzDecoder::new(compressed), limit),
+        Encoding::Deflate => inflate(ZlibDecoder::new(compressed), limit),
+        Encoding::Zstd => inflate(zstd::Decoder::new(compressed).map_err(invalid_encoding)?, limit),
+    }
+}
+
+fn inflate(decoder: impl Read, limit: usize) -> Result<Vec<u8>, ApiError> {
+    let mut decoded = Vec::new();
+    // Read one byte past the limit to tell "exactly at the limit" from "over it"
+    decoder
+        .take(limit as u64 + 1)
+        .read_to_end(&mut decoded)
+        .map_err(invalid_encoding)?;
+    if decoded.len() > limit {
+        return Err(too_large(limit));
+    }
+    Ok(decoded)
+}
+
+fn invalid_encoding(e: std::io::Error) -> ApiError {
+    ApiError::new(
+        StatusCode::BAD_REQUEST,
+        "invalid_request_error",
+        format!("Failed to decompress request body: {}", e),
+    )
+    .reason("invalid_encoding")
+}
+
+fn too_large(limit: usize) -> ApiError {
+    ApiError::new(
+        StatusCode::PAYLOAD_TOO_LARGE,
+        "invalid_request_error",
+        format!("Request body exceeds the maximum of {} bytes after decompression", limit),
+    )
+    .reason("body_too_large")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    use axum::{middleware, routing::post, Router};
+    use flate2::{write::GzEncoder, Compression};
+    use tower::ServiceExt;
+
+    use crate::extract::ApiJson;
+
+    const LIMIT: usize = 64 * 1024;
+
+    // Echoes the JSON body the handler received
+    fn app() -> Router {
+        Router::new()
+            .route("/", post(|ApiJson(body): ApiJson<serde_json::Value>| async move { axum::Json(body) }))
+            .layer(middleware::from_fn_with_state(LIMIT, decompress_request))
+    }
+
+    async fn send(encoding: &str, body: Vec<u8>) -> (StatusCode, serde_json::Value) {
+        let request = Request::post("/")
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(header::CONTENT_ENCODING, encoding)
+            .body(Body::from(body))
+            .unwrap();
+        let response = app().oneshot(request).await.unwrap();
+        let status = response.status();
+        let bytes = body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&bytes).unwrap())
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn batch() -> serde_json::Value {
+        let input: Vec<String> = (0..2048).map(|i| format!("document {}", i)).collect();
+        serde_json::json!({ "input": input, "model": "BAAI/bge-small-en-v1.5" })
+    }
+
+    #[tokio::test]
+    async fn gzipped_batch_arrives_intact() {
+        let batch = batch();
+        let (status, body) = send("gzip", gzip(batch.to_string().as_bytes())).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, batch);
+    }
+
+    #[tokio::test]
+    async fn zstd_batch_arrives_intact() {
+        let batch = batch();
+        let compressed = zstd::encode_all(batch.to_string().as_bytes(), 3).unwrap();
+        let (status, body) = send("zstd", compressed).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, batch);
+    }
+
+    #[tokio::test]
+    async fn bombs_stop_at_the_limit() {
+        let bomb = gzip(&vec![b' '; LIMIT * 100]);
+        assert!(bomb.len() < LIMIT);
+        let (status, body) = send("gzip", bomb).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(body["error"]["type"], "invalid_request_error");
+
+        let bomb = zstd::encode_all(&vec![b' '; LIMIT + 1][..], 3).unwrap();
+        assert_eq!(send("zstd", bomb).await.0, StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn corrupt_bodies_are_bad_requests() {
+        let (status, body) = send("zstd", b"not zstd at all".to_vec()).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(body["error"]["message"].as_str().unwrap().starts_with("Failed to decompress"));
+    }
+
+    #[tokio::test]
+    async fn unsupported_encodings_are_415_json() {
+        let (status, body) = send("br", b"{}".to_vec()).await;
+        assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        assert_eq!(body["error"]["type"], "invalid_request_error");
+        assert!(body["error"]["message"].as_str().unwrap().contains("gzip, deflate and zstd"));
+    }
+}
@@ -0,0 +1,118 @@
+use crate::cluster::{dot, normalize};
+
+// Rows and columns compared per tile, so each tile's vectors stay in cache
+const TILE: usize = 64;
+
+/// Which member of a duplicate group represents it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Representative {
+    /// The earliest input.
+    #[default]
+    First,
+    /// The input with the most characters (earliest on ties).
+    Longest,
+}
+
+/// Inputs that are near-duplicates of each other.
+#[derive(Debug)]
+pub struct Group {
+    /// Members in input order.
+    pub indices: Vec<usize>,
+    pub representative: usize,
+    /// Highest similarity between two members.
+    pub max_similarity: f32,
+}
+
+/// Group inputs whose embeddings have a cosine similarity above `threshold`,
+/// transitively: if a~b and b~c, all three form one group. Only groups of two
+/// or more are returned, ordered by their first member.
+///
+/// Every pair is compared, so time is O(n² × dimensions), but similarities are
+/// folded into the groups as they are computed rather than stored, so memory
+/// stays O(n × dimensions).
+pub fn groups(mut points: Vec<Vec<f32>>, threshold: f32, texts: &[String], representative: Representative) -> Vec<Group> {
+    points.iter_mut().for_each(|point| normalize(point));
+    let n = points.len();
+    let mut sets = DisjointSets::new(n);
+    let mut max_similarity = vec![f32::NEG_INFINITY; n];
+
+    for row_start in (0..n).step_by(TILE) {
+        let rows = row_start..(row_start + TILE).min(n);
+        for col_start in (row_start..n).step_by(TILE) {
+            let cols = col_start..(col_start + TILE).min(n);
+            for i in rows.clone() {
+                for j in cols.clone().filter(|&j| j > i) {
+                    // Rounding can put identical unit vectors just above 1
+                    let similarity = dot(&points[i], &points[j]).min(1.0);
+                    if similarity > threshold {
+                        sets.union(i, j);
+                        max_similarity[i] = max_similarity[i].max(similarity);
+                        max_similarity[j] = max_similarity[j].max(similarity);
+                    }
+                }
+            }
+        }
+    }
+
+    let mut members: Vec<Vec<usize>> = vec![Vec::new(); n];
+    for index in 0..n {
+        members[sets.find(index)].push(index);
+    }
+    let mut groups: Vec<Group> = members
+        .into_iter()
+        .filter(|indices| indices.len() > 1)
+        .map(|indices| {
+            let representative = match representative {
+                Representative::First => indices[0],
+                Representative::Longest => indices
+                    .iter()
+                    .copied()
+                    .fold(indices[0], |best, index| {
+                        if texts[index].chars().count() > texts[best].chars().count() { index } else { best }
+                    }),
+            };
+            let max_similarity = indices.iter().map(|&index| max_similarity[index]).fold(f32::NEG_INFINITY, f32::max);
+            Group {
+                indices,
+                representative,
+                max_similarity,
+            }
+        })
+        .collect();
+    groups.sort_by_key(|group| group.indices[0]);
+    groups
+}
+
+// Union-find with path halving and union by size
+struct DisjointSets {
+    parent: Vec<usize>,
+    size: Vec<usize>,
+}
+
+impl DisjointSets {
+    fn new(n: usize) -> Self {
+        Self {
+            parent: (0..n).collect(),
+            size: vec![1; n],
+        }
+    }
+
+    fn find(&mut self, mut x: usize) -> usize {
+        while self.parent[x] != x {
+            self.parent[x] = self.parent[self.parent[x]];
+            x = self.parent[x];
+        }
+        x
+    }
+
+    fn union(&mut self, a: usize, b: usize) {
+        let (a, b) = (self.find(a), self.find(b));
+        if a == b {
+            return;
+        }
+        let (large, small) = if self.size[a] >= self.size[b] { (a, b) } else { (b, a) };
+        self.parent[small] = large;
+        self.size[large] += self.size[small];
+    }
+}
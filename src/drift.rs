@@ -0,0 +1,391 @@
+//! Drift monitoring: statistics over the embeddings the server produces.
+//!
+//! With `SEMEMBED_DRIFT_WINDOW` set, every full-length dense embedding is
+//! folded into the current window: its norm, the running sum behind the
+//! window's centroid, and a reservoir sample of `SEMEMBED_DRIFT_SAMPLE`
+//! vectors whose per-dimension means are kept alongside. Each is O(dim) per
+//! embedding, and memory stays at the sample plus a few vectors. When a
+//! window fills, its statistics are published as gauges and compared with
+//! the baseline, a window captured through `POST /admin/drift/baseline`.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+
+use axum::{extract::State, http::StatusCode, Json};
+use prometheus::{Gauge, IntGauge, Registry};
+use serde::Serialize;
+use tracing::info;
+
+use crate::error::ApiError;
+use crate::shadow::cosine;
+use crate::AppState;
+
+/// Window and sample sizes of drift monitoring.
+#[derive(Debug, Clone, Copy)]
+pub struct DriftConfig {
+    /// Embeddings per window.
+    pub window: u64,
+    /// Embeddings kept in each window's reservoir sample.
+    pub sample: usize,
+}
+
+// Drift gauges, updated as each window completes
+struct DriftMetrics {
+    mean_norm: Gauge,
+    centroid_distance: Gauge,
+    dimension_shift: Gauge,
+    baseline: IntGauge,
+}
+
+impl DriftMetrics {
+    fn register(registry: &Registry) -> anyhow::Result<Self> {
+        let mean_norm = Gauge::new("semembed_drift_mean_norm", "Mean L2 norm of the embeddings in the last window")?;
+        registry.register(Box::new(mean_norm.clone()))?;
+
+        let centroid_distance = Gauge::new(
+            "semembed_drift_centroid_distance",
+            "Cosine distance between the last window's centroid and the baseline's",
+        )?;
+        registry.register(Box::new(centroid_distance.clone()))?;
+
+        let dimension_shift = Gauge::new(
+            "semembed_drift_dimension_shift",
+            "Mean absolute change of the sampled per-dimension means from the baseline's",
+        )?;
+        registry.register(Box::new(dimension_shift.clone()))?;
+
+        let baseline = IntGauge::new("semembed_drift_baseline", "1 while a drift baseline is set")?;
+        registry.register(Box::new(baseline.clone()))?;
+
+        Ok(Self {
+            mean_norm,
+            centroid_distance,
+            dimension_shift,
+            baseline,
+        })
+    }
+}
+
+// The window being filled
+struct Window {
+    embeddings: u64,
+    norm_sum: f64,
+    sum: Vec<f64>,
+    reservoir: Vec<Vec<f32>>,
+    // Sum of the reservoir's vectors, kept as they are replaced
+    reservoir_sum: Vec<f64>,
+}
+
+impl Window {
+    fn new(dimensions: usize, sample: usize) -> Self {
+        Self {
+            embeddings: 0,
+            norm_sum: 0.0,
+            sum: vec![0.0; dimensions],
+            reservoir: Vec::with_capacity(sample),
+            reservoir_sum: vec![0.0; dimensions],
+        }
+    }
+
+    fn clear(&mut self) {
+        self.embeddings = 0;
+        self.norm_sum = 0.0;
+        self.sum.iter_mut().for_each(|x| *x = 0.0);
+        self.reservoir.clear();
+        self.reservoir_sum.iter_mut().for_each(|x| *x = 0.0);
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        let embeddings = self.embeddings.max(1) as f64;
+        let sampled = self.reservoir.len().max(1) as f64;
+        Snapshot {
+            embeddings: self.embeddings,
+            mean_norm: self.norm_sum / embeddings,
+            centroid: self.sum.iter().map(|x| (x / embeddings) as f32).collect(),
+            dimension_means: self.reservoir_sum.iter().map(|x| (x / sampled) as f32).collect(),
+        }
+    }
+}
+
+// A completed window's statistics
+#[derive(Clone)]
+struct Snapshot {
+    embeddings: u64,
+    mean_norm: f64,
+    centroid: Vec<f32>,
+    dimension_means: Vec<f32>,
+}
+
+impl Snapshot {
+    fn centroid_distance(&self, baseline: &Snapshot) -> f64 {
+        1.0 - f64::from(cosine(&self.centroid, &baseline.centroid))
+    }
+
+    fn dimension_shift(&self, baseline: &Snapshot) -> f64 {
+        let total: f64 = self
+            .dimension_means
+            .iter()
+            .zip(&baseline.dimension_means)
+            .map(|(x, y)| f64::from((x - y).abs()))
+            .sum();
+        total / self.dimension_means.len().max(1) as f64
+    }
+}
+
+struct Windows {
+    current: Window,
+    last: Option<Snapshot>,
+    baseline: Option<Snapshot>,
+}
+
+/// Running statistics over produced embeddings, and the baseline they are
+/// compared with.
+pub(crate) struct Drift {
+    dimensions: usize,
+    config: DriftConfig,
+    // splitmix64 state, for reservoir replacement
+    seed: AtomicU64,
+    windows: Mutex<Windows>,
+    metrics: DriftMetrics,
+}
+
+impl Drift {
+    pub fn new(dimensions: usize, config: DriftConfig, registry: &Registry) -> anyhow::Result<Self> {
+        Ok(Self {
+            dimensions,
+            config,
+            seed: AtomicU64::new(std::process::id() as u64),
+            windows: Mutex::new(Windows {
+                current: Window::new(dimensions, config.sample),
+                last: None,
+                baseline: None,
+            }),
+            metrics: DriftMetrics::register(registry)?,
+        })
+    }
+
+    // Uniform in [0, bound)
+    fn random(&self, bound: u64) -> u64 {
+        let mut z = self.seed.fetch_add(0x9e37_79b9_7f4a_7c15, Ordering::Relaxed).wrapping_add(0x9e37_79b9_7f4a_7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^= z >> 31;
+        z % bound
+    }
+
+    /// Fold a request's embeddings into the current window. Vectors that
+    /// aren't the model's full length are left out.
+    pub fn record(&self, embeddings: &[Vec<f32>]) {
+        let mut guard = self.windows.lock().unwrap_or_else(PoisonError::into_inner);
+        let windows = &mut *guard;
+        for embedding in embeddings.iter().filter(|embedding| embedding.len() == self.dimensions) {
+            let window = &mut windows.current;
+            let seen = window.embeddings;
+            window.embeddings += 1;
+            window.norm_sum += f64::from(embedding.iter().map(|x| x * x).sum::<f32>().sqrt());
+            for (sum, x) in window.sum.iter_mut().zip(embedding) {
+                *sum += f64::from(*x);
+            }
+            // Algorithm R: the n-th embedding replaces a random sampled one
+            // with probability sample/n
+            if window.reservoir.len() < self.config.sample {
+                for (sum, x) in window.reservoir_sum.iter_mut().zip(embedding) {
+                    *sum += f64::from(*x);
+                }
+                window.reservoir.push(embedding.clone());
+            } else {
+                let slot = self.random(seen + 1) as usize;
+                if let Some(replaced) = window.reservoir.get_mut(slot) {
+                    for ((sum, old), new) in window.reservoir_sum.iter_mut().zip(replaced.iter()).zip(embedding) {
+                        *sum += f64::from(*new) - f64::from(*old);
+                    }
+                    replaced.copy_from_slice(embedding);
+                }
+            }
+            if window.embeddings >= self.config.window {
+                let snapshot = window.snapshot();
+                window.clear();
+                self.publish(&snapshot, windows.baseline.as_ref());
+                windows.last = Some(snapshot);
+            }
+        }
+    }
+
+    fn publish(&self, last: &Snapshot, baseline: Option<&Snapshot>) {
+        self.metrics.mean_norm.set(last.mean_norm);
+        let (distance, shift) = match baseline {
+            Some(baseline) => (last.centroid_distance(baseline), last.dimension_shift(baseline)),
+            None => (0.0, 0.0),
+        };
+        self.metrics.centroid_distance.set(distance);
+        self.metrics.dimension_shift.set(shift);
+        self.metrics.baseline.set(i64::from(baseline.is_some()));
+    }
+
+    fn status(&self) -> DriftStatus {
+        let windows = self.windows.lock().unwrap_or_else(PoisonError::into_inner);
+        let baseline = windows.baseline.as_ref();
+        DriftStatus {
+            object: "drift",
+            window: self.config.window,
+            sample: self.config.sample,
+            current_embeddings: windows.current.embeddings,
+            last: windows.last.as_ref().map(|last| WindowView {
+                embeddings: last.embeddings,
+                mean_norm: last.mean_norm,
+                centroid_distance: baseline.map(|baseline| last.centroid_distance(baseline)),
+                dimension_shift: baseline.map(|baseline| last.dimension_shift(baseline)),
+            }),
+            baseline: baseline.map(|baseline| WindowView {
+                embeddings: baseline.embeddings,
+                mean_norm: baseline.mean_norm,
+                centroid_distance: None,
+                dimension_shift: None,
+            }),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct DriftStatus {
+    object: &'static str,
+    window: u64,
+    sample: usize,
+    // Embeddings in the window being filled
+    current_embeddings: u64,
+    last: Option<WindowView>,
+    baseline: Option<WindowView>,
+}
+
+#[derive(Debug, Serialize)]
+struct WindowView {
+    embeddings: u64,
+    mean_norm: f64,
+    // Compared with the baseline, when there is one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    centroid_distance: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dimension_shift: Option<f64>,
+}
+
+fn drift(state: &AppState) -> Result<&Drift, ApiError> {
+    state.drift.as_ref().ok_or_else(|| {
+        ApiError::new(StatusCode::NOT_FOUND, "invalid_request_error", "Drift monitoring is not enabled")
+            .reason("not_found")
+    })
+}
+
+/// `GET /admin/drift`: the last window's statistics and the baseline's.
+pub(crate) async fn status(State(state): State<Arc<AppState>>) -> Result<Json<DriftStatus>, ApiError> {
+    Ok(Json(drift(&state)?.status()))
+}
+
+/// `POST /admin/drift/baseline`: make the last completed window the
+/// baseline later windows are compared with.
+pub(crate) async fn capture(State(state): State<Arc<AppState>>) -> Result<Json<DriftStatus>, ApiError> {
+    let drift = drift(&state)?;
+    {
+        let mut windows = drift.windows.lock().unwrap_or_else(PoisonError::into_inner);
+        let Some(last) = windows.last.clone() else {
+            return Err(ApiError::new(
+                StatusCode::CONFLICT,
+                "invalid_request_error",
+                format!(
+                    "No window has completed yet ({} of {} embeddings so far)",
+                    windows.current.embeddings, drift.config.window
+                ),
+            )
+            .reason("no_drift_window"));
+        };
+        info!("Drift baseline captured from a window of {} embeddings", last.embeddings);
+        drift.publish(&last, Some(&last));
+        windows.baseline = Some(last);
+    }
+    Ok(Json(drift.status()))
+}
+
+/// `DELETE /admin/drift/baseline`: forget the baseline.
+pub(crate) async fn clear(State(state): State<Arc<AppState>>) -> Result<Json<DriftStatus>, ApiError> {
+    let drift = drift(&state)?;
+    {
+        let mut windows = drift.windows.lock().unwrap_or_else(PoisonError::into_inner);
+        windows.baseline = None;
+        if let Some(last) = &windows.last {
+            drift.publish(last, None);
+        }
+        drift.metrics.baseline.set(0);
+    }
+    info!("Drift baseline cleared");
+    Ok(Json(drift.status()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn drift(window: u64, sample: usize) -> Drift {
+        Drift::new(2, DriftConfig { window, sample }, &Registry::new()).unwrap()
+    }
+
+    fn current(drift: &Drift) -> u64 {
+        drift.windows.lock().unwrap().current.embeddings
+    }
+
+    #[test]
+    fn full_windows_publish_their_mean_norm() {
+        let drift = drift(4, 2);
+        drift.record(&[vec![3.0, 4.0], vec![1.0, 0.0], vec![0.0, 2.0]]);
+        assert_eq!(current(&drift), 3);
+        assert!(drift.status().last.is_none());
+
+        // Vectors of another length (shortened with `dimensions`) are left out
+        drift.record(&[vec![1.0]]);
+        assert_eq!(current(&drift), 3);
+
+        drift.record(&[vec![0.0, 0.0], vec![1.0, 0.0]]);
+        assert_eq!(current(&drift), 1);
+        let last = drift.status().last.unwrap();
+        assert_eq!((last.embeddings, last.mean_norm), (4, 2.0));
+        assert_eq!(drift.metrics.mean_norm.get(), 2.0);
+        assert_eq!(drift.metrics.baseline.get(), 0);
+    }
+
+    #[test]
+    fn windows_are_compared_with_the_baseline() {
+        // A sample as large as the window holds all of it
+        let drift = drift(2, 2);
+        drift.record(&[vec![1.0, 0.0], vec![1.0, 0.0]]);
+        {
+            let mut windows = drift.windows.lock().unwrap();
+            windows.baseline = windows.last.clone();
+        }
+
+        drift.record(&[vec![0.0, 1.0], vec![0.0, 1.0]]);
+        assert!((drift.metrics.centroid_distance.get() - 1.0).abs() < 1e-6);
+        assert!((drift.metrics.dimension_shift.get() - 1.0).abs() < 1e-6);
+        assert_eq!(drift.metrics.baseline.get(), 1);
+
+        drift.record(&[vec![1.0, 1.0], vec![1.0, 1.0]]);
+        let last = drift.status().last.unwrap();
+        assert!((last.centroid_distance.unwrap() - (1.0 - 0.5f64.sqrt())).abs() < 1e-6);
+        assert!((last.dimension_shift.unwrap() - 0.5).abs() < 1e-6);
+
+        drift.record(&[vec![2.0, 0.0], vec![2.0, 0.0]]);
+        assert!(drift.metrics.centroid_distance.get().abs() < 1e-6);
+        assert!((drift.metrics.dimension_shift.get() - 0.5).abs() < 1e-6);
+    }
+
+    #[test]
+    fn the_sample_stays_bounded_and_its_sum_exact() {
+        let drift = drift(1000, 8);
+        for i in 0..500 {
+            drift.record(&[vec![i as f32, 1.0]]);
+        }
+        let windows = drift.windows.lock().unwrap();
+        let window = &windows.current;
+        assert_eq!((window.embeddings, window.reservoir.len()), (500, 8));
+        let sum: f64 = window.reservoir.iter().map(|embedding| f64::from(embedding[0])).sum();
+        assert_eq!(window.reservoir_sum, [sum, 8.0]);
+        assert_eq!(window.sum, [(0..500).sum::<i32>() as f64, 500.0]);
+    }
+}
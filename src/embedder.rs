@@ -0,0 +1,795 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::time::{Duration, Instant};
+
+use fastembed::{InitOptions, OutputKey, Pooling, TextEmbedding};
+use prometheus::{Counter, CounterVec, IntGauge};
+use serde::{Deserialize, Serialize};
+use tokenizers::Tokenizer;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
+use utoipa::ToSchema;
+
+use crate::budget::{BatchBudget, BudgetConfig};
+use crate::redact::redact_inputs;
+
+// Longest wait between re-initialization attempts
+const MAX_REINIT_BACKOFF: Duration = Duration::from_secs(60);
+
+// Wait before the first retry of a transient inference error, doubled after each
+const RETRY_BACKOFF: Duration = Duration::from_millis(10);
+const MAX_RETRY_BACKOFF: Duration = Duration::from_millis(200);
+
+// ONNX Runtime errors that come from momentary resource pressure rather than a
+// broken session or a bad input, matched case-insensitively
+const TRANSIENT_ERRORS: &[&str] = &[
+    "failed to allocate",
+    "bad_alloc",
+    "bad allocation",
+    "out of memory",
+    "resource exhausted",
+    "temporarily unavailable",
+];
+
+/// Why an embedding call failed.
+#[derive(Debug)]
+pub enum EmbedError {
+    /// The model returned an error.
+    Inference(anyhow::Error),
+    /// The model panicked; the payload message when it was a string.
+    Panicked(String),
+    /// The circuit is open while the model is re-initialized; retry after the given delay.
+    Unavailable(Duration),
+}
+
+impl EmbedError {
+    /// Whether the same call may succeed if simply retried. Panics and
+    /// everything not known to be transient are treated as fatal.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Self::Inference(e) => {
+                let message = format!("{:#}", e).to_ascii_lowercase();
+                TRANSIENT_ERRORS.iter().any(|marker| message.contains(marker))
+            }
+            Self::Panicked(_) | Self::Unavailable(_) => false,
+        }
+    }
+}
+
+impl std::fmt::Display for EmbedError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Inference(e) => write!(f, "{}", e),
+            Self::Panicked(message) => write!(f, "model panicked: {}", message),
+            Self::Unavailable(_) => write!(f, "model is being re-initialized after repeated failures"),
+        }
+    }
+}
+
+/// Tokens one input consumes once tokenized for the model.
+#[derive(Debug, Clone, Copy)]
+pub struct TokenCount {
+    /// Tokens passed to the model, including special tokens.
+    pub tokens: usize,
+    /// Whether the input was cut at the model's maximum sequence length.
+    pub truncated: bool,
+    /// Tokens cut, and from which side of the input.
+    pub dropped: usize,
+    pub side: TruncationSide,
+}
+
+/// Which side of an input over the model's maximum sequence length is cut.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum TruncationSide {
+    /// Keep the head, as the tokenizer does.
+    #[default]
+    End,
+    /// Keep the tail, for inputs whose important part comes last.
+    Start,
+}
+
+impl FromStr for TruncationSide {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "end" => Ok(Self::End),
+            "start" => Ok(Self::Start),
+            other => anyhow::bail!("unknown truncation side {:?} (expected end or start)", other),
+        }
+    }
+}
+
+/// The model's output for one input before pooling, alongside the pooled
+/// embedding `embed` returns for it.
+#[derive(Debug, Clone)]
+pub struct TokenEmbeddings {
+    /// Token ids, including special tokens; padding is left out.
+    pub ids: Vec<u32>,
+    /// The tokenizer's string for each id.
+    pub tokens: Vec<String>,
+    /// One `[dim]` hidden state per token, unnormalized.
+    pub vectors: Vec<Vec<f32>>,
+    /// The pooled, normalized embedding.
+    pub pooled: Vec<f32>,
+}
+
+// Where fastembed looks for a text model's output when the model names none
+const OUTPUT_PRECEDENCE: &[OutputKey] = &[
+    OutputKey::OnlyOne,
+    OutputKey::ByName("text_embeds"),
+    OutputKey::ByName("last_hidden_state"),
+    OutputKey::ByName("sentence_embedding"),
+];
+
+/// Circuit breaker settings.
+#[derive(Debug, Clone, Copy)]
+pub struct CircuitConfig {
+    /// Consecutive failures that open the circuit; 0 never opens it.
+    pub failure_threshold: u32,
+    /// Delay before the first re-initialization attempt, doubled after each failed attempt.
+    pub retry_after: Duration,
+    /// An inference call running longer than this is considered hung; `None` disables the watchdog.
+    pub hang_timeout: Option<Duration>,
+    /// Retries of a transient inference error before the call counts as failed.
+    pub retries: u32,
+}
+
+/// Metrics the embedder reports into.
+pub struct EmbedderMetrics {
+    /// Caught panics and recovered poisonings.
+    pub panics: Counter,
+    /// 1 while the circuit is open, 0 otherwise.
+    pub circuit_open: IntGauge,
+    /// Inference calls the watchdog found hung.
+    pub hung: Counter,
+    /// Retries of transient inference errors, by outcome.
+    pub retries: CounterVec,
+    /// The sub-batch token budget in effect.
+    pub batch_tokens: IntGauge,
+}
+
+#[derive(Default)]
+struct Circuit {
+    consecutive_failures: u32,
+    // Set while open; the current delay between re-initialization attempts
+    open: Option<Duration>,
+}
+
+/// One loaded model instance and the call currently running on it.
+struct Worker {
+    model: Mutex<TextEmbedding>,
+    // Start time of the in-flight call, read by the watchdog
+    started: Mutex<Option<Instant>>,
+    // Set by the watchdog once a call on this worker hung
+    quarantined: AtomicBool,
+}
+
+impl Worker {
+    fn new(model: TextEmbedding) -> Arc<Self> {
+        Arc::new(Self {
+            model: Mutex::new(model),
+            started: Mutex::new(None),
+            quarantined: AtomicBool::new(false),
+        })
+    }
+
+    fn started(&self) -> MutexGuard<'_, Option<Instant>> {
+        self.started.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// The loaded model behind a lock that survives panics, with a circuit breaker
+/// that re-creates the model after repeated failures or a hung call.
+///
+/// A panic inside `embed()` is caught and reported as an error rather than
+/// poisoning the lock, and a lock poisoned some other way is recovered on the
+/// next acquisition: the session holds no per-call state, so a single failed
+/// call leaves it usable. A session that keeps failing is assumed broken: after
+/// `failure_threshold` consecutive failures the circuit opens, requests fail
+/// fast instead of queuing on the lock, and a background thread re-creates the
+/// model until a canary embedding succeeds.
+///
+/// A call that never returns cannot be cancelled, since it is blocked inside
+/// ONNX Runtime. Instead the watchdog quarantines its worker: the circuit opens
+/// and a replacement worker is loaded alongside it. The hung thread keeps the
+/// old worker, which is dropped if the call ever returns; requests that were
+/// already waiting on its lock stay blocked until then.
+pub struct Embedder {
+    worker: Mutex<Arc<Worker>>,
+    // Copy of the model's tokenizer (with its truncation settings) so counting
+    // tokens doesn't wait for the inference lock
+    tokenizer: Tokenizer,
+    // The same without truncation or padding, to tell how much is cut
+    untruncated: Tokenizer,
+    // The model's maximum sequence length, special tokens included
+    max_length: usize,
+    init_options: InitOptions,
+    config: CircuitConfig,
+    circuit: Mutex<Circuit>,
+    budget: BatchBudget,
+    metrics: EmbedderMetrics,
+}
+
+impl Embedder {
+    /// Wrap a loaded model. `init_options` are reused to re-create it.
+    pub fn new(
+        model: TextEmbedding,
+        init_options: InitOptions,
+        config: CircuitConfig,
+        budget: BudgetConfig,
+        metrics: EmbedderMetrics,
+    ) -> Arc<Self> {
+        metrics.circuit_open.set(0);
+        let mut untruncated = model.tokenizer.clone();
+        // Only invalid truncation parameters are rejected, never none
+        let _ = untruncated.with_truncation(None);
+        untruncated.with_padding(None);
+        let embedder = Arc::new(Self {
+            tokenizer: model.tokenizer.clone(),
+            untruncated,
+            max_length: model.tokenizer.get_truncation().map_or(usize::MAX, |truncation| truncation.max_length),
+            worker: Mutex::new(Worker::new(model)),
+            init_options,
+            config,
+            circuit: Mutex::new(Circuit::default()),
+            budget: BatchBudget::new(budget, metrics.batch_tokens.clone()),
+            metrics,
+        });
+        if let Some(hang_timeout) = config.hang_timeout {
+            let watched = Arc::downgrade(&embedder);
+            std::thread::spawn(move || Self::watchdog(watched, hang_timeout));
+        }
+        embedder
+    }
+
+    /// Whether requests are being served, i.e. the circuit is closed.
+    pub fn ready(&self) -> bool {
+        self.circuit().open.is_none()
+    }
+
+    /// How the model pools token states into one embedding: `"mean"` or `"cls"`.
+    pub fn pooling(&self) -> &'static str {
+        match TextEmbedding::get_default_pooling_method(&self.init_options.model_name).unwrap_or_default() {
+            Pooling::Mean => "mean",
+            Pooling::Cls => "cls",
+        }
+    }
+
+    /// The tokenizer inference uses, with its truncation and padding.
+    pub fn tokenizer(&self) -> &Tokenizer {
+        &self.tokenizer
+    }
+
+    /// The model's maximum sequence length, special tokens included.
+    pub fn max_length(&self) -> usize {
+        self.max_length
+    }
+
+    /// The sub-batch token budget in effect.
+    pub fn batch_tokens(&self) -> usize {
+        self.budget.tokens()
+    }
+
+    /// Count the tokens each input will consume, exactly as inference tokenizes
+    /// it, and those inference will cut from its end.
+    pub fn count_tokens(&self, texts: &[String]) -> anyhow::Result<Vec<TokenCount>> {
+        let inputs: Vec<&str> = texts.iter().map(String::as_str).collect();
+        let encodings = self
+            .untruncated
+            .encode_batch(inputs.clone(), true)
+            .map_err(|e| anyhow::anyhow!(redact_inputs(&e.to_string(), &inputs)))?;
+        Ok(encodings
+            .iter()
+            .map(|encoding| {
+                let tokens = encoding.len().min(self.max_length);
+                TokenCount {
+                    tokens,
+                    truncated: encoding.len() > tokens,
+                    dropped: encoding.len() - tokens,
+                    side: TruncationSide::End,
+                }
+            })
+            .collect())
+    }
+
+    /// Cut each input over the model's maximum sequence length from its start
+    /// instead of its end: its first `keep` bytes (the prefix the model expects)
+    /// stay, followed by as much of its end as fits. Returns the texts with the
+    /// tokens each lost.
+    pub fn truncate_start(&self, texts: Vec<String>, keep: usize) -> anyhow::Result<Vec<(String, usize)>> {
+        texts
+            .into_iter()
+            .map(|text| cut_start(&self.untruncated, self.max_length, text, keep))
+            .collect()
+    }
+
+    /// Embed `texts` in sub-batches sized by the token budget, retrying
+    /// transient errors up to `retries` times while holding the model. Retries
+    /// stop early once `cancel` fires, since nobody is waiting for the result
+    /// any more.
+    pub fn embed(self: &Arc<Self>, texts: Vec<&str>, cancel: &CancellationToken) -> Result<Vec<Vec<f32>>, EmbedError> {
+        self.call(|worker, model| self.run_with_retries(worker, model, texts, cancel))
+    }
+
+    /// Per-token hidden states of `texts`, as the model outputs them before
+    /// pooling. Fails for models whose output is already pooled.
+    pub fn embed_tokens(self: &Arc<Self>, texts: Vec<&str>) -> Result<Vec<TokenEmbeddings>, EmbedError> {
+        self.call(|worker, model| {
+            *worker.started() = Some(Instant::now());
+            let inputs = texts.clone();
+            let result = match panic::catch_unwind(AssertUnwindSafe(|| self.run_tokens(model, texts))) {
+                Ok(result) => result
+                    .map_err(|e| EmbedError::Inference(anyhow::anyhow!(redact_inputs(&format!("{:#}", e), &inputs)))),
+                Err(payload) => {
+                    let message = redact_inputs(&panic_message(payload.as_ref()), &inputs);
+                    error!("Embedding model panicked: {}", message);
+                    self.metrics.panics.inc();
+                    Err(EmbedError::Panicked(message))
+                }
+            };
+            *worker.started() = None;
+            result
+        })
+    }
+
+    // Run `f` on the current worker's model, counting its failures toward the
+    // circuit breaker.
+    fn call<T>(
+        self: &Arc<Self>,
+        f: impl FnOnce(&Worker, &mut TextEmbedding) -> Result<T, EmbedError>,
+    ) -> Result<T, EmbedError> {
+        if let Some(backoff) = self.circuit().open {
+            return Err(EmbedError::Unavailable(backoff));
+        }
+
+        let worker = self.worker().clone();
+        let result = {
+            let mut model = self.lock(&worker);
+            // The circuit may have opened while this request waited for the lock
+            if let Some(backoff) = self.circuit().open {
+                return Err(EmbedError::Unavailable(backoff));
+            }
+            f(&worker, &mut model)
+        };
+
+        if worker.quarantined.load(Ordering::Acquire) {
+            warn!("Hung inference call returned on a quarantined worker");
+            return result;
+        }
+        match &result {
+            Ok(_) => self.circuit().consecutive_failures = 0,
+            Err(_) => {
+                let mut circuit = self.circuit();
+                circuit.consecutive_failures += 1;
+                let threshold = self.config.failure_threshold;
+                if threshold > 0 && circuit.consecutive_failures >= threshold {
+                    let failures = circuit.consecutive_failures;
+                    self.open(circuit, &format!("{} consecutive inference failures", failures));
+                }
+            }
+        }
+        result
+    }
+
+    fn run_with_retries(
+        &self,
+        worker: &Worker,
+        model: &mut TextEmbedding,
+        texts: Vec<&str>,
+        cancel: &CancellationToken,
+    ) -> Result<Vec<Vec<f32>>, EmbedError> {
+        let lengths = self.lengths(&texts);
+        let mut embeddings = Vec::with_capacity(texts.len());
+        let mut retries = 0;
+        let mut retrying = false;
+        let mut backoff = RETRY_BACKOFF;
+        while embeddings.len() < texts.len() {
+            let start = embeddings.len();
+            let end = start + self.budget.next_batch(&lengths[start..]);
+            let rss_before = self.budget.watches_memory().then(crate::memory::resident_bytes).flatten();
+            *worker.started() = Some(Instant::now());
+            let result = self.run(model, texts[start..end].to_vec());
+            *worker.started() = None;
+            if retrying {
+                let outcome = if result.is_ok() { "succeeded" } else { "failed" };
+                self.metrics.retries.with_label_values(&[outcome]).inc();
+                retrying = false;
+            }
+
+            let e = match result {
+                Ok(batch) => {
+                    let rss_after = rss_before.and_then(|_| crate::memory::resident_bytes());
+                    self.budget.completed(rss_before.zip(rss_after));
+                    embeddings.extend(batch);
+                    continue;
+                }
+                Err(e) if e.is_transient() => e,
+                Err(e) => return Err(e),
+            };
+            // Most transient errors are failed allocations, so the retry runs smaller
+            self.budget.shrink("transient inference error");
+            // A quarantined worker or an open circuit is handled by the caller
+            if retries >= self.config.retries
+                || cancel.is_cancelled()
+                || worker.quarantined.load(Ordering::Acquire)
+                || self.circuit().open.is_some()
+            {
+                if retries > 0 {
+                    error!("Inference failed after {} attempts: {}", retries + 1, e);
+                }
+                return Err(e);
+            }
+            retries += 1;
+            retrying = true;
+            warn!(
+                "Transient inference error, retrying in {:?} ({} of {}): {}",
+                backoff, retries, self.config.retries, e
+            );
+            std::thread::sleep(backoff);
+            backoff = (backoff * 2).min(MAX_RETRY_BACKOFF);
+        }
+        Ok(embeddings)
+    }
+
+    // Tokens per input as the model sees them, for sizing sub-batches; falls
+    // back to byte lengths, an overestimate, if tokenizing fails
+    fn lengths(&self, texts: &[&str]) -> Vec<usize> {
+        match self.tokenizer.encode_batch_fast(texts.to_vec(), true) {
+            Ok(encodings) => encodings
+                .iter()
+                .map(|encoding| encoding.get_attention_mask().iter().filter(|&&mask| mask != 0).count())
+                .collect(),
+            Err(_) => texts.iter().map(|text| text.len()).collect(),
+        }
+    }
+
+    // Errors and panic messages are scrubbed of input text before they are
+    // logged or returned.
+    fn run(&self, model: &mut TextEmbedding, texts: Vec<&str>) -> Result<Vec<Vec<f32>>, EmbedError> {
+        let inputs = texts.clone();
+        // One session run per sub-batch, however many inputs it holds
+        let batch_size = Some(texts.len().max(1));
+        match panic::catch_unwind(AssertUnwindSafe(|| model.embed(texts, batch_size))) {
+            Ok(result) => {
+                result.map_err(|e| EmbedError::Inference(anyhow::anyhow!(redact_inputs(&format!("{:#}", e), &inputs))))
+            }
+            Err(payload) => {
+                let message = redact_inputs(&panic_message(payload.as_ref()), &inputs);
+                error!("Embedding model panicked: {}", message);
+                self.metrics.panics.inc();
+                Err(EmbedError::Panicked(message))
+            }
+        }
+    }
+
+    // The raw session output is `[batch, tokens, dim]` for models that pool
+    // outside the graph; pooling it the way `embed` does gives the pooled vector.
+    fn run_tokens(&self, model: &mut TextEmbedding, texts: Vec<&str>) -> anyhow::Result<Vec<TokenEmbeddings>> {
+        let model_name = &self.init_options.model_name;
+        let output_key = TextEmbedding::get_model_info(model_name)?.output_key.clone();
+        let pooling = TextEmbedding::get_default_pooling_method(model_name);
+        let encodings = self.tokenizer.encode_batch(texts.clone(), true).map_err(|e| anyhow::anyhow!(e))?;
+
+        let mut results = Vec::with_capacity(texts.len());
+        let mut encodings = encodings.iter();
+        // One batch, so the padding matches the encodings above
+        for batch in model.transform(&texts, Some(texts.len().max(1)))?.into_raw() {
+            let (hidden, pooled) = match &output_key {
+                Some(key) => (batch.select_output(&key)?, batch.select_and_pool_output(&key, pooling.clone())?),
+                None => (
+                    batch.select_output(&OUTPUT_PRECEDENCE)?,
+                    batch.select_and_pool_output(&OUTPUT_PRECEDENCE, pooling.clone())?,
+                ),
+            };
+            let &[inputs, length, dim] = hidden.shape() else {
+                anyhow::bail!("the model's output is already pooled (shape {:?})", hidden.shape());
+            };
+            let values: Vec<f32> = hidden.iter().copied().collect();
+            let mask: Vec<i64> = batch.attention_mask_array.iter().copied().collect();
+            for (input, pooled) in (0..inputs).zip(pooled.rows()) {
+                let encoding = encodings.next().ok_or_else(|| anyhow::anyhow!("model returned extra outputs"))?;
+                let kept: Vec<usize> = (0..length).filter(|&position| mask[input * length + position] != 0).collect();
+                let mut pooled = pooled.to_vec();
+                crate::cluster::normalize(&mut pooled);
+                results.push(TokenEmbeddings {
+                    ids: kept.iter().map(|&position| encoding.get_ids()[position]).collect(),
+                    tokens: kept.iter().map(|&position| encoding.get_tokens()[position].clone()).collect(),
+                    vectors: kept
+                        .iter()
+                        .map(|&position| {
+                            let start = (input * length + position) * dim;
+                            values[start..start + dim].to_vec()
+                        })
+                        .collect(),
+                    pooled,
+                });
+            }
+        }
+        Ok(results)
+    }
+
+    // Open the circuit and start re-initializing, unless already open.
+    fn open(self: &Arc<Self>, mut circuit: MutexGuard<'_, Circuit>, cause: &str) {
+        if circuit.open.is_some() {
+            return;
+        }
+        warn!("Circuit opened after {}; re-initializing the model", cause);
+        circuit.open = Some(self.config.retry_after);
+        self.metrics.circuit_open.set(1);
+
+        let embedder = self.clone();
+        std::thread::spawn(move || embedder.reinitialize());
+    }
+
+    // Runs on its own thread until a fresh model passes the canary.
+    fn reinitialize(&self) {
+        loop {
+            let backoff = self.circuit().open.unwrap_or(self.config.retry_after);
+            std::thread::sleep(backoff);
+
+            match self.load_canaried() {
+                Ok(model) => {
+                    // Swap in a new worker rather than locking the old one, which a
+                    // hung call may still hold
+                    *self.worker() = Worker::new(model);
+                    *self.circuit() = Circuit::default();
+                    self.metrics.circuit_open.set(0);
+                    info!("Model re-initialized; circuit closed");
+                    return;
+                }
+                Err(e) => {
+                    let next = (backoff * 2).min(MAX_REINIT_BACKOFF);
+                    warn!("Model re-initialization failed, retrying in {:?}: {}", next, e);
+                    self.circuit().open = Some(next);
+                }
+            }
+        }
+    }
+
+    fn load_canaried(&self) -> anyhow::Result<TextEmbedding> {
+        let mut model = TextEmbedding::try_new(self.init_options.clone())?;
+        match self.run(&mut model, vec!["semembed canary"]) {
+            Ok(embeddings) if embeddings.len() == 1 => Ok(model),
+            Ok(_) => anyhow::bail!("canary embedding returned no vector"),
+            Err(e) => anyhow::bail!("canary embedding failed: {}", e),
+        }
+    }
+
+    // Runs on its own thread until the embedder is dropped, so an unloaded
+    // model's memory is freed.
+    fn watchdog(watched: Weak<Self>, hang_timeout: Duration) {
+        let interval = (hang_timeout / 10).clamp(Duration::from_millis(100), Duration::from_secs(5));
+        loop {
+            std::thread::sleep(interval);
+            let Some(embedder) = watched.upgrade() else {
+                return;
+            };
+            embedder.check_hung(hang_timeout);
+        }
+    }
+
+    // Quarantine the worker if its inference call has run past the limit
+    fn check_hung(self: &Arc<Self>, hang_timeout: Duration) {
+        let worker = self.worker().clone();
+        let Some(started) = *worker.started() else {
+            return;
+        };
+        let elapsed = started.elapsed();
+        if elapsed < hang_timeout || worker.quarantined.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        error!(
+            "Inference call has been running for {:?} (limit {:?}); quarantining the worker",
+            elapsed, hang_timeout
+        );
+        self.metrics.hung.inc();
+        self.open(self.circuit(), "a hung inference call");
+    }
+
+    fn worker(&self) -> MutexGuard<'_, Arc<Worker>> {
+        self.worker.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn lock<'a>(&self, worker: &'a Worker) -> MutexGuard<'a, TextEmbedding> {
+        recover(&worker.model, &self.metrics.panics)
+    }
+
+    fn circuit(&self) -> MutexGuard<'_, Circuit> {
+        self.circuit.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+// Take the model's lock even if a panic poisoned it, logging and counting
+// the recovery; the session holds no per-call state, so it is still usable
+fn recover<'a, T>(lock: &'a Mutex<T>, panics: &Counter) -> MutexGuard<'a, T> {
+    lock.lock().unwrap_or_else(|poisoned| {
+        warn!("Embedder lock was poisoned by a panic; recovering");
+        panics.inc();
+        lock.clear_poison();
+        poisoned.into_inner()
+    })
+}
+
+fn panic_message(payload: &(dyn std::any::Any + Send)) -> String {
+    payload
+        .downcast_ref::<&str>()
+        .map(|s| s.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "non-string panic payload".to_string())
+}
+
+// Cut `text` from its start until `tokenizer` makes at most `max_length`
+// tokens of it, keeping its first `keep` bytes; returns it with the tokens lost
+fn cut_start(tokenizer: &Tokenizer, max_length: usize, text: String, keep: usize) -> anyhow::Result<(String, usize)> {
+    let encode = |text: &str| {
+        tokenizer
+            .encode(text, true)
+            .map_err(|e| anyhow::anyhow!(redact_inputs(&e.to_string(), &[text])))
+    };
+    let length = encode(&text)?.len();
+    if length <= max_length {
+        return Ok((text, 0));
+    }
+    let mut cut = text;
+    // Tokens can merge differently around the new boundary, so cut again
+    // while the rest is still too long
+    for _ in 0..4 {
+        let encoding = encode(&cut)?;
+        let over = encoding.len().saturating_sub(max_length);
+        if over == 0 {
+            break;
+        }
+        let starts: Vec<usize> = encoding
+            .get_offsets()
+            .iter()
+            .zip(encoding.get_special_tokens_mask())
+            .filter(|(&(start, _), &special)| special == 0 && start >= keep)
+            .map(|(&(start, _), _)| start)
+            .collect();
+        let Some(&from) = starts.get(over) else {
+            break;
+        };
+        let mut from = from.min(cut.len());
+        while !cut.is_char_boundary(from) {
+            from += 1;
+        }
+        let keep = keep.min(from);
+        cut = format!("{}{}", &cut[..keep], &cut[from..]);
+    }
+    let kept = encode(&cut)?.len().min(max_length);
+    Ok((cut, length - kept))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prometheus::Opts;
+
+    // Panic while holding `lock`, from another thread, poisoning it
+    fn poison<T: Send>(lock: &Mutex<T>) {
+        std::thread::scope(|scope| {
+            let result = scope
+                .spawn(|| {
+                    let _guard = lock.lock().unwrap();
+                    panic!("inference blew up");
+                })
+                .join();
+            assert!(result.is_err());
+        });
+        assert!(lock.is_poisoned());
+    }
+
+    fn metrics() -> EmbedderMetrics {
+        EmbedderMetrics {
+            panics: Counter::new("panics", "panics").unwrap(),
+            circuit_open: IntGauge::new("circuit_open", "circuit").unwrap(),
+            hung: Counter::new("hung", "hung").unwrap(),
+            retries: CounterVec::new(Opts::new("retries", "retries"), &["outcome"]).unwrap(),
+            batch_tokens: IntGauge::new("batch_tokens", "budget").unwrap(),
+        }
+    }
+
+    #[test]
+    fn poisoned_locks_are_recovered_and_counted() {
+        let lock = Mutex::new(vec![1.0_f32]);
+        let panics = Counter::new("panics", "panics").unwrap();
+        poison(&lock);
+
+        recover(&lock, &panics).push(2.0);
+        assert!(!lock.is_poisoned());
+        assert_eq!(panics.get(), 1.0);
+        // Later acquisitions see a healthy lock
+        assert_eq!(*recover(&lock, &panics), [1.0, 2.0]);
+        assert_eq!(panics.get(), 1.0);
+    }
+
+    #[test]
+    fn panic_payloads_are_reported() {
+        let payload = std::panic::catch_unwind(|| panic!("out of {}", "memory")).unwrap_err();
+        assert_eq!(panic_message(payload.as_ref()), "out of memory");
+        let payload = std::panic::catch_unwind(|| std::panic::panic_any(42)).unwrap_err();
+        assert_eq!(panic_message(payload.as_ref()), "non-string panic payload");
+    }
+
+    // Whitespace-separated words of `vocab`, without special tokens
+    fn word_tokenizer(vocab: &[&str]) -> Tokenizer {
+        let vocab = vocab.iter().enumerate().map(|(id, word)| (word.to_string(), id as u32)).collect();
+        let model = tokenizers::models::wordlevel::WordLevel::builder()
+            .vocab(vocab)
+            .unk_token("[UNK]".to_string())
+            .build()
+            .unwrap();
+        let mut tokenizer = Tokenizer::new(model);
+        tokenizer.with_pre_tokenizer(Some(tokenizers::pre_tokenizers::whitespace::WhitespaceSplit));
+        tokenizer
+    }
+
+    #[test]
+    fn start_truncation_keeps_the_prefix_and_the_tail() {
+        let tokenizer = word_tokenizer(&["[UNK]", "query:", "a", "b", "c", "d", "e", "f", "g"]);
+        let (cut, dropped) = cut_start(&tokenizer, 5, "query: a b c d e f g".to_string(), 7).unwrap();
+        assert_eq!(cut, "query: d e f g");
+        assert_eq!(dropped, 3);
+        // Without a prefix, only the tail is left
+        let (cut, dropped) = cut_start(&tokenizer, 2, "a b c d".to_string(), 0).unwrap();
+        assert_eq!(cut, "c d");
+        assert_eq!(dropped, 2);
+        // Inputs that fit are left alone
+        assert_eq!(cut_start(&tokenizer, 5, "a b".to_string(), 0).unwrap(), ("a b".to_string(), 0));
+        assert_eq!(" START ".parse::<TruncationSide>().unwrap(), TruncationSide::Start);
+        assert!("middle".parse::<TruncationSide>().is_err());
+    }
+
+    fn embedder(model: fastembed::EmbeddingModel) -> Arc<Embedder> {
+        let init_options = InitOptions::new(model);
+        let text_embedding = TextEmbedding::try_new(init_options.clone()).unwrap();
+        let config = CircuitConfig {
+            failure_threshold: 3,
+            retry_after: Duration::from_secs(1),
+            hang_timeout: None,
+            retries: 0,
+        };
+        let budget = BudgetConfig {
+            max_tokens: 16384,
+            rss_growth: None,
+        };
+        Embedder::new(text_embedding, init_options, config, budget, metrics())
+    }
+
+    #[test]
+    #[ignore = "downloads BAAI/bge-small-en-v1.5 and needs ONNX Runtime (ORT_DYLIB_PATH)"]
+    fn start_truncation_embeds_the_tail_instead_of_the_head() {
+        let embedder = embedder(fastembed::EmbeddingModel::BGESmallENV15);
+        let head = "The quarterly report covers revenue. ".repeat(60);
+        let tail = "How do I reset my password?";
+        let text = format!("{}{}", head, tail);
+        let counts = embedder.count_tokens(std::slice::from_ref(&text)).unwrap();
+        assert!(counts[0].truncated);
+
+        let (cut, dropped) = embedder.truncate_start(vec![text.clone()], 0).unwrap().remove(0);
+        assert!(cut.ends_with(tail));
+        assert!(dropped > 0);
+        let cancel = CancellationToken::new();
+        let embedded = embedder.embed(vec![text.as_str(), cut.as_str(), tail], &cancel).unwrap();
+        let dot = |a: &[f32], b: &[f32]| a.iter().zip(b).map(|(x, y)| x * y).sum::<f32>();
+        // Cutting from the start changes the vector, towards the tail's meaning
+        assert!(dot(&embedded[0], &embedded[1]) < 0.99);
+        assert!(dot(&embedded[1], &embedded[2]) > dot(&embedded[0], &embedded[2]));
+    }
+
+    #[test]
+    #[ignore = "downloads BAAI/bge-small-en-v1.5 and needs ONNX Runtime (ORT_DYLIB_PATH)"]
+    fn embeds_after_the_model_lock_is_poisoned() {
+        let embedder = embedder(fastembed::EmbeddingModel::BGESmallENV15);
+        poison(&embedder.worker().model);
+
+        let embeddings = embedder.embed(vec!["still serving"], &CancellationToken::new()).unwrap();
+        assert_eq!(embeddings.len(), 1);
+        assert_eq!(embedder.metrics.panics.get(), 1.0);
+        assert!(embedder.ready());
+    }
+}
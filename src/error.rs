@@ -0,0 +1,175 @@
+use axum::{
+    body::{self, Body},
+    http::{header, HeaderMap, HeaderName, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+// OpenAI-compatible error body
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct ErrorResponse {
+    pub error: ErrorDetail,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct ErrorDetail {
+    pub message: String,
+    #[serde(rename = "type")]
+    pub error_type: String,
+    pub param: Option<String>,
+    pub code: Option<String>,
+    /// The id the request was logged under, so a client reporting the error
+    /// can quote it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    /// The W3C trace the request was part of.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trace_id: Option<String>,
+}
+
+/// Reason label attached to error responses so `semembed_errors_total` can be
+/// incremented in one place (the `json_errors` middleware) for every error.
+#[derive(Debug, Clone, Copy)]
+pub struct ErrorReason(pub &'static str);
+
+/// An API error rendered as `ErrorResponse` JSON.
+#[derive(Debug)]
+pub struct ApiError {
+    status: StatusCode,
+    error_type: &'static str,
+    reason: &'static str,
+    message: String,
+    param: Option<&'static str>,
+    code: Option<&'static str>,
+    retry_after: Option<u64>,
+    // Boxed, since few errors have any, to keep `Result<_, ApiError>` small
+    headers: Option<Box<HeaderMap>>,
+}
+
+impl ApiError {
+    /// Create an error. The metric reason defaults to the error type.
+    pub fn new(status: StatusCode, error_type: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            error_type,
+            reason: error_type,
+            message: message.into(),
+            param: None,
+            code: None,
+            retry_after: None,
+            headers: None,
+        }
+    }
+
+    /// Name the request parameter the error refers to.
+    pub fn param(mut self, param: &'static str) -> Self {
+        self.param = Some(param);
+        self
+    }
+
+    /// Set a machine-readable error code clients can branch on.
+    pub fn code(mut self, code: &'static str) -> Self {
+        self.code = Some(code);
+        self
+    }
+
+    /// Ask the client to retry after this many seconds (`Retry-After` header).
+    pub fn retry_after(mut self, seconds: u64) -> Self {
+        self.retry_after = Some(seconds);
+        self
+    }
+
+    /// Add a response header, such as the state of a quota. `name` must be
+    /// lowercase.
+    pub fn header(mut self, name: &'static str, value: impl ToString) -> Self {
+        if let Ok(value) = HeaderValue::from_str(&value.to_string()) {
+            self.headers.get_or_insert_with(Default::default).insert(HeaderName::from_static(name), value);
+        }
+        self
+    }
+
+    /// Override the `reason` label recorded in `semembed_errors_total`.
+    pub fn reason(mut self, reason: &'static str) -> Self {
+        self.reason = reason;
+        self
+    }
+
+    /// The message and code (else the reason), for an error reported against
+    /// one item of a batch rather than as the response.
+    pub fn into_item(self) -> (String, &'static str) {
+        (self.message, self.code.unwrap_or(self.reason))
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let mut response = (
+            self.status,
+            Json(ErrorResponse {
+                error: ErrorDetail {
+                    message: self.message,
+                    error_type: self.error_type.to_string(),
+                    param: self.param.map(str::to_string),
+                    code: self.code.map(str::to_string),
+                    request_id: None,
+                    trace_id: None,
+                },
+            }),
+        )
+            .into_response();
+        if let Some(seconds) = self.retry_after {
+            response.headers_mut().insert(header::RETRY_AFTER, HeaderValue::from(seconds));
+        }
+        if let Some(headers) = self.headers {
+            response.headers_mut().extend(*headers);
+        }
+        response.extensions_mut().insert(ErrorReason(self.reason));
+        response
+    }
+}
+
+// Error bodies are small; anything larger is passed through untouched
+const MAX_ERROR_BODY: usize = 64 * 1024;
+
+/// Re-render the body of an error response in another API's error shape, for
+/// compatibility routes. Status, headers and the error reason are kept;
+/// responses that aren't `ErrorResponse` JSON are returned as they are.
+pub async fn map_error_body<T: Serialize>(
+    response: Response,
+    map: impl FnOnce(StatusCode, ErrorDetail) -> T,
+) -> Response {
+    let status = response.status();
+    if !status.is_client_error() && !status.is_server_error() {
+        return response;
+    }
+    let (mut parts, body) = response.into_parts();
+    let Ok(bytes) = body::to_bytes(body, MAX_ERROR_BODY).await else {
+        return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to read error response").into_response();
+    };
+    let Ok(ErrorResponse { error }) = serde_json::from_slice::<ErrorResponse>(&bytes) else {
+        return Response::from_parts(parts, Body::from(bytes));
+    };
+    let Ok(json) = serde_json::to_vec(&map(status, error)) else {
+        return Response::from_parts(parts, Body::from(bytes));
+    };
+    parts.headers.remove(header::CONTENT_LENGTH);
+    Response::from_parts(parts, Body::from(json))
+}
+
+/// Add the request's id and trace id to an `ErrorResponse` body; other
+/// responses are returned as they are.
+pub async fn add_ids(response: Response, request_id: Option<String>, trace_id: Option<String>) -> Response {
+    if request_id.is_none() && trace_id.is_none() {
+        return response;
+    }
+    map_error_body(response, |_, detail| ErrorResponse {
+        error: ErrorDetail {
+            request_id,
+            trace_id,
+            ..detail
+        },
+    })
+    .await
+}
@@ -0,0 +1,463 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, Context};
+use serde::Serialize;
+use tokio_util::sync::CancellationToken;
+
+use crate::cluster::{dot, normalize};
+use crate::embedder::Embedder;
+use crate::models::InputKind;
+use crate::preprocess::Preprocess;
+use crate::{shorten, standalone_model, StandaloneModel};
+
+// Sentence pairs with gold similarity scores (0-5), used when no --sts file is given
+const STS_SAMPLE: &str = include_str!("../data/sts-sample.tsv");
+
+// Inputs per inference call, as a low-priority sub-batch would be
+const EVAL_BATCH: usize = 32;
+
+const USAGE: &str = "\
+Usage: semembed eval [OPTIONS]
+
+Measures embedding quality with the configured model (SEMEMBED_MODEL,
+SEMEMBED_PREPROCESS and the other serving settings apply).
+
+Modes:
+  (default)                 STS: Spearman correlation between cosine similarity
+                            and gold scores, on the bundled sample or --sts
+  --queries FILE --corpus FILE --qrels FILE
+                            Retrieval: recall@k and MRR
+
+Options:
+  --model NAME              Model to evaluate instead of SEMEMBED_MODEL
+  --dimensions N            Truncate embeddings to N dimensions, as a request would
+  --sts FILE                TSV of sentence1, sentence2, gold score
+  --queries FILE            TSV of query id, query text
+  --corpus FILE             TSV of document id, document text
+  --qrels FILE              TSV of query id, document id[, relevance]
+  --k LIST                  Cutoffs for recall@k (default 1,5,10)
+  --json                    Print the report as JSON
+";
+
+struct EvalArgs {
+    model: Option<String>,
+    dimensions: Option<usize>,
+    sts: Option<PathBuf>,
+    queries: Option<PathBuf>,
+    corpus: Option<PathBuf>,
+    qrels: Option<PathBuf>,
+    ks: Vec<usize>,
+    json: bool,
+}
+
+impl EvalArgs {
+    fn parse(args: &[String]) -> anyhow::Result<Option<Self>> {
+        let mut parsed = Self {
+            model: None,
+            dimensions: None,
+            sts: None,
+            queries: None,
+            corpus: None,
+            qrels: None,
+            ks: vec![1, 5, 10],
+            json: false,
+        };
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or_else(|| anyhow!("{} needs a value", arg));
+            match arg.as_str() {
+                "-h" | "--help" => return Ok(None),
+                "--json" => parsed.json = true,
+                "--model" => parsed.model = Some(value()?.clone()),
+                "--dimensions" => {
+                    let dimensions = value()?;
+                    parsed.dimensions = Some(
+                        dimensions
+                            .parse()
+                            .map_err(|e| anyhow!("invalid --dimensions {:?}: {}", dimensions, e))?,
+                    );
+                }
+                "--sts" => parsed.sts = Some(value()?.into()),
+                "--queries" => parsed.queries = Some(value()?.into()),
+                "--corpus" => parsed.corpus = Some(value()?.into()),
+                "--qrels" => parsed.qrels = Some(value()?.into()),
+                "--k" => {
+                    let ks = value()?;
+                    parsed.ks = ks
+                        .split(',')
+                        .map(|k| k.trim().parse::<usize>().ok().filter(|&k| k > 0))
+                        .collect::<Option<_>>()
+                        .ok_or_else(|| anyhow!("invalid --k {:?} (expected positive integers, e.g. 1,5,10)", ks))?;
+                    parsed.ks.sort_unstable();
+                    parsed.ks.dedup();
+                }
+                other => bail!("unknown eval option {:?}\n\n{}", other, USAGE),
+            }
+        }
+        Ok(Some(parsed))
+    }
+}
+
+/// Run `semembed eval` with the arguments that follow the subcommand.
+pub fn run(args: &[String]) -> anyhow::Result<()> {
+    let Some(args) = EvalArgs::parse(args)? else {
+        print!("{}", USAGE);
+        return Ok(());
+    };
+    let retrieval = match (&args.queries, &args.corpus, &args.qrels) {
+        (None, None, None) => None,
+        (Some(queries), Some(corpus), Some(qrels)) => Some((queries, corpus, qrels)),
+        _ => bail!("retrieval mode needs all of --queries, --corpus and --qrels"),
+    };
+    if retrieval.is_some() && args.sts.is_some() {
+        bail!("--sts can't be combined with retrieval mode");
+    }
+
+    let model = EvalModel::load(&args)?;
+    let report = match retrieval {
+        None => {
+            let (dataset, pairs) = match &args.sts {
+                Some(path) => (path.display().to_string(), read_sts(&read(path)?)?),
+                None => ("bundled STS sample".to_string(), read_sts(STS_SAMPLE)?),
+            };
+            Report::Sts(sts(&model, dataset, &pairs)?)
+        }
+        Some((queries, corpus, qrels)) => Report::Retrieval(retrieval_eval(
+            &model,
+            &read_tsv_texts(&read(queries)?),
+            &read_tsv_texts(&read(corpus)?),
+            &read_qrels(&read(qrels)?)?,
+            &args.ks,
+        )?),
+    };
+
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        print!("{}", report.table());
+    }
+    Ok(())
+}
+
+// The model wrapped the way the server runs it: same embedder (circuit,
+// retries), preprocessing, prefixes and dimension truncation
+struct EvalModel {
+    name: &'static str,
+    dimensions: usize,
+    embedder: Arc<Embedder>,
+    spec: &'static crate::models::ModelSpec,
+    preprocess: Preprocess,
+    truncate: Option<usize>,
+}
+
+impl EvalModel {
+    fn load(args: &EvalArgs) -> anyhow::Result<Self> {
+        let model_name = args
+            .model
+            .clone()
+            .or_else(|| std::env::var("SEMEMBED_MODEL").ok())
+            .unwrap_or_else(|| "BAAI/bge-small-en-v1.5".to_string());
+        let preprocess = Preprocess::from_env()?;
+        let StandaloneModel {
+            spec,
+            metadata,
+            embedder,
+            ..
+        } = standalone_model(&model_name)?;
+        if let Some(dimensions) = args.dimensions {
+            if dimensions == 0 || dimensions > metadata.dimensions {
+                bail!(
+                    "--dimensions must be between 1 and {} for model {}, got {}",
+                    metadata.dimensions,
+                    spec.name,
+                    dimensions
+                );
+            }
+        }
+        Ok(Self {
+            name: spec.name,
+            dimensions: args.dimensions.unwrap_or(metadata.dimensions),
+            embedder,
+            spec,
+            preprocess,
+            truncate: args.dimensions,
+        })
+    }
+
+    // Unit-length embeddings of `texts`, so similarities are plain dot products
+    fn embed(&self, texts: &[String], kind: Option<InputKind>) -> anyhow::Result<Vec<Vec<f32>>> {
+        let prefix = self.spec.prefix(kind).unwrap_or_default();
+        let texts: Vec<String> = texts
+            .iter()
+            .map(|text| {
+                let text = self.preprocess.prepare(text.clone()).map_err(|disallowed| {
+                    anyhow!("{:?} contains a {}, which SEMEMBED_SANITIZE=reject refuses", text, disallowed)
+                })?;
+                Ok(format!("{}{}", prefix, text))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        let cancel = CancellationToken::new();
+        let mut embeddings = Vec::with_capacity(texts.len());
+        for batch in texts.chunks(EVAL_BATCH) {
+            let batch = self
+                .embedder
+                .embed(batch.iter().map(String::as_str).collect(), &cancel)
+                .map_err(|e| anyhow!("inference failed: {}", e))?;
+            embeddings.extend(batch.into_iter().map(|embedding| {
+                let mut embedding = match self.truncate {
+                    Some(dimensions) => shorten(embedding, dimensions),
+                    None => embedding,
+                };
+                normalize(&mut embedding);
+                embedding
+            }));
+        }
+        Ok(embeddings)
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "mode", rename_all = "lowercase")]
+enum Report {
+    Sts(StsReport),
+    Retrieval(RetrievalReport),
+}
+
+#[derive(Debug, Serialize)]
+struct StsReport {
+    model: &'static str,
+    dimensions: usize,
+    dataset: String,
+    pairs: usize,
+    spearman: f64,
+}
+
+#[derive(Debug, Serialize)]
+struct RetrievalReport {
+    model: &'static str,
+    dimensions: usize,
+    queries: usize,
+    corpus: usize,
+    // Queries without relevance judgements, left out of the averages
+    skipped_queries: usize,
+    recall: Vec<RecallAtK>,
+    // Mean reciprocal rank of the first relevant document within the largest k
+    mrr: f64,
+    mrr_k: usize,
+}
+
+#[derive(Debug, Serialize)]
+struct RecallAtK {
+    k: usize,
+    recall: f64,
+}
+
+impl Report {
+    fn table(&self) -> String {
+        let rows: Vec<(String, String)> = match self {
+            Self::Sts(report) => vec![
+                ("model".to_string(), format!("{} ({} dimensions)", report.model, report.dimensions)),
+                ("dataset".to_string(), format!("{} ({} pairs)", report.dataset, report.pairs)),
+                ("spearman".to_string(), format!("{:.4}", report.spearman)),
+            ],
+            Self::Retrieval(report) => {
+                let mut rows = vec![
+                    ("model".to_string(), format!("{} ({} dimensions)", report.model, report.dimensions)),
+                    (
+                        "queries".to_string(),
+                        format!("{} ({} without judgements skipped)", report.queries, report.skipped_queries),
+                    ),
+                    ("corpus".to_string(), format!("{} documents", report.corpus)),
+                ];
+                rows.extend(report.recall.iter().map(|recall| (format!("recall@{}", recall.k), format!("{:.4}", recall.recall))));
+                rows.push((format!("mrr@{}", report.mrr_k), format!("{:.4}", report.mrr)));
+                rows
+            }
+        };
+        let width = rows.iter().map(|(name, _)| name.len()).max().unwrap_or(0);
+        rows.iter().map(|(name, value)| format!("{:width$}  {}\n", name, value, width = width)).collect()
+    }
+}
+
+fn sts(model: &EvalModel, dataset: String, pairs: &[(String, String, f64)]) -> anyhow::Result<StsReport> {
+    if pairs.len() < 2 {
+        bail!("STS evaluation needs at least 2 sentence pairs, got {}", pairs.len());
+    }
+    // STS is symmetric, so both sides get the query-side prefix
+    let left: Vec<String> = pairs.iter().map(|(left, _, _)| left.clone()).collect();
+    let right: Vec<String> = pairs.iter().map(|(_, right, _)| right.clone()).collect();
+    let left = model.embed(&left, None)?;
+    let right = model.embed(&right, None)?;
+    let predicted: Vec<f64> = left.iter().zip(&right).map(|(a, b)| f64::from(dot(a, b))).collect();
+    let gold: Vec<f64> = pairs.iter().map(|(_, _, score)| *score).collect();
+    Ok(StsReport {
+        model: model.name,
+        dimensions: model.dimensions,
+        dataset,
+        pairs: pairs.len(),
+        spearman: spearman(&predicted, &gold),
+    })
+}
+
+fn retrieval_eval(
+    model: &EvalModel,
+    queries: &[(String, String)],
+    corpus: &[(String, String)],
+    qrels: &HashMap<String, HashSet<String>>,
+    ks: &[usize],
+) -> anyhow::Result<RetrievalReport> {
+    if corpus.is_empty() {
+        bail!("the corpus is empty");
+    }
+    let judged: Vec<&(String, String)> = queries.iter().filter(|(id, _)| qrels.contains_key(id)).collect();
+    if judged.is_empty() {
+        bail!("no query has relevance judgements in the qrels file");
+    }
+    let query_texts: Vec<String> = judged.iter().map(|(_, text)| text.clone()).collect();
+    let corpus_texts: Vec<String> = corpus.iter().map(|(_, text)| text.clone()).collect();
+    let query_embeddings = model.embed(&query_texts, Some(InputKind::Query))?;
+    let corpus_embeddings = model.embed(&corpus_texts, Some(InputKind::Passage))?;
+
+    let max_k = *ks.last().expect("at least one k");
+    let mut recall = vec![0.0; ks.len()];
+    let mut reciprocal_ranks = 0.0;
+    for ((id, _), query) in judged.iter().zip(&query_embeddings) {
+        let relevant = &qrels[id];
+        let mut ranked: Vec<(usize, f32)> = corpus_embeddings
+            .iter()
+            .enumerate()
+            .map(|(index, document)| (index, dot(query, document)))
+            .collect();
+        ranked.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+        let hits: Vec<bool> = ranked
+            .iter()
+            .take(max_k)
+            .map(|&(index, _)| relevant.contains(&corpus[index].0))
+            .collect();
+        for (recall, &k) in recall.iter_mut().zip(ks) {
+            *recall += hits.iter().take(k).filter(|&&hit| hit).count() as f64 / relevant.len() as f64;
+        }
+        if let Some(rank) = hits.iter().position(|&hit| hit) {
+            reciprocal_ranks += 1.0 / (rank + 1) as f64;
+        }
+    }
+
+    let n = judged.len() as f64;
+    Ok(RetrievalReport {
+        model: model.name,
+        dimensions: model.dimensions,
+        queries: queries.len(),
+        corpus: corpus.len(),
+        skipped_queries: queries.len() - judged.len(),
+        recall: ks
+            .iter()
+            .zip(recall)
+            .map(|(&k, recall)| RecallAtK { k, recall: recall / n })
+            .collect(),
+        mrr: reciprocal_ranks / n,
+        mrr_k: max_k,
+    })
+}
+
+// Spearman's rank correlation: Pearson correlation of the ranks, with tied
+// values sharing their average rank
+fn spearman(a: &[f64], b: &[f64]) -> f64 {
+    pearson(&ranks(a), &ranks(b))
+}
+
+fn ranks(values: &[f64]) -> Vec<f64> {
+    let mut order: Vec<usize> = (0..values.len()).collect();
+    order.sort_by(|&x, &y| values[x].total_cmp(&values[y]));
+    let mut ranks = vec![0.0; values.len()];
+    let mut start = 0;
+    while start < order.len() {
+        let mut end = start + 1;
+        while end < order.len() && values[order[end]] == values[order[start]] {
+            end += 1;
+        }
+        // Ranks are 1-based; a tie over start..end shares their mean
+        let rank = (start + end + 1) as f64 / 2.0;
+        order[start..end].iter().for_each(|&index| ranks[index] = rank);
+        start = end;
+    }
+    ranks
+}
+
+fn pearson(a: &[f64], b: &[f64]) -> f64 {
+    let n = a.len() as f64;
+    let (mean_a, mean_b) = (a.iter().sum::<f64>() / n, b.iter().sum::<f64>() / n);
+    let (mut covariance, mut variance_a, mut variance_b) = (0.0, 0.0, 0.0);
+    for (x, y) in a.iter().zip(b) {
+        covariance += (x - mean_a) * (y - mean_b);
+        variance_a += (x - mean_a).powi(2);
+        variance_b += (y - mean_b).powi(2);
+    }
+    if variance_a == 0.0 || variance_b == 0.0 {
+        return 0.0;
+    }
+    covariance / (variance_a.sqrt() * variance_b.sqrt())
+}
+
+fn read(path: &Path) -> anyhow::Result<String> {
+    std::fs::read_to_string(path).with_context(|| format!("failed to read {}", path.display()))
+}
+
+// Non-empty, non-comment lines split on tabs
+fn tsv_rows(content: &str) -> impl Iterator<Item = (usize, Vec<&str>)> {
+    content
+        .lines()
+        .enumerate()
+        .map(|(index, line)| (index + 1, line.trim_end_matches('\r')))
+        .filter(|(_, line)| !line.trim().is_empty() && !line.starts_with('#'))
+        .map(|(number, line)| (number, line.split('\t').collect()))
+}
+
+// sentence1, sentence2, score; a first row whose score isn't a number is a header
+fn read_sts(content: &str) -> anyhow::Result<Vec<(String, String, f64)>> {
+    let mut pairs = Vec::new();
+    for (position, (number, columns)) in tsv_rows(content).enumerate() {
+        let [left, right, score] = columns[..] else {
+            bail!("line {}: expected 3 tab-separated columns, got {}", number, columns.len());
+        };
+        match score.trim().parse::<f64>() {
+            Ok(score) => pairs.push((left.to_string(), right.to_string(), score)),
+            Err(_) if position == 0 => continue,
+            Err(_) => bail!("line {}: invalid score {:?}", number, score),
+        }
+    }
+    Ok(pairs)
+}
+
+// id, text (further columns, such as a title, are joined into the text)
+fn read_tsv_texts(content: &str) -> Vec<(String, String)> {
+    tsv_rows(content)
+        .filter(|(_, columns)| columns.len() >= 2)
+        .map(|(_, columns)| (columns[0].trim().to_string(), columns[1..].join(" ")))
+        .collect()
+}
+
+// query id, document id[, relevance]; documents with relevance 0 are not
+// relevant, and a first row with a non-numeric relevance is a header
+fn read_qrels(content: &str) -> anyhow::Result<HashMap<String, HashSet<String>>> {
+    let mut qrels: HashMap<String, HashSet<String>> = HashMap::new();
+    for (position, (number, columns)) in tsv_rows(content).enumerate() {
+        let (query, document, relevance) = match columns[..] {
+            [query, document] => (query, document, 1.0),
+            [query, document, relevance] => match relevance.trim().parse::<f64>() {
+                Ok(relevance) => (query, document, relevance),
+                Err(_) if position == 0 => continue,
+                Err(_) => bail!("line {}: invalid relevance {:?}", number, relevance),
+            },
+            _ => bail!("line {}: expected 2 or 3 tab-separated columns, got {}", number, columns.len()),
+        };
+        if relevance > 0.0 {
+            qrels
+                .entry(query.trim().to_string())
+                .or_default()
+                .insert(document.trim().to_string());
+        }
+    }
+    Ok(qrels)
+}
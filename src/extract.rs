@@ -0,0 +1,171 @@
+use axum::{
+    async_trait,
+    extract::{rejection::JsonRejection, FromRequest, FromRequestParts, Request},
+    http::{request::Parts, StatusCode},
+    Json,
+};
+use serde::de::DeserializeOwned;
+use std::sync::Arc;
+
+use crate::access_log::{AccessEntry, RequestId};
+use crate::error::ApiError;
+use crate::queue::{Priority, Tenant};
+use crate::timing::{Measure, Phase, Timing};
+use crate::trace_context::TraceContext;
+use crate::AppState;
+
+/// Drop-in replacement for `axum::Json` that reports malformed bodies using our
+/// OpenAI-style `ErrorResponse` instead of axum's plain-text rejections.
+pub(crate) struct ApiJson<T>(pub T);
+
+#[async_trait]
+impl<T, S> FromRequest<S> for ApiJson<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        match Json::<T>::from_request(req, state).await {
+            Ok(Json(value)) => Ok(Self(value)),
+            Err(rejection) => Err(json_rejection_error(rejection)),
+        }
+    }
+}
+
+fn json_rejection_error(rejection: JsonRejection) -> ApiError {
+    match &rejection {
+        // serde_path_to_error prefixes the message with the offending field, e.g.
+        // "input: input must be a string or an array of strings at line 1 column 12"
+        JsonRejection::JsonDataError(err) => ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_request_error",
+            format!("Invalid request body: {}", strip_axum_prefix(&err.body_text())),
+        )
+        .reason("invalid_body"),
+        JsonRejection::JsonSyntaxError(err) => ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_request_error",
+            format!(
+                "We could not parse the JSON body of your request: {}",
+                strip_axum_prefix(&err.body_text())
+            ),
+        )
+        .reason("invalid_json"),
+        JsonRejection::MissingJsonContentType(_) => ApiError::new(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "invalid_request_error",
+            "Expected request with `Content-Type: application/json`",
+        )
+        .reason("unsupported_media_type"),
+        _ => ApiError::new(
+            rejection.status(),
+            "invalid_request_error",
+            rejection.body_text(),
+        )
+        .reason("invalid_body"),
+    }
+}
+
+fn strip_axum_prefix(text: &str) -> &str {
+    text.split_once(": ").map_or(text, |(_, detail)| detail)
+}
+
+/// How a request is queued for inference: the `X-Priority` header (`high` or
+/// `low`), if the client sent one, and the tenant its `Authorization` header
+/// (or Azure-style `api-key` header) identifies.
+///
+/// Requests are rejected here, before their body is read, while resident
+/// memory is over its limit, and low-priority ones while the load shedder is
+/// shedding.
+#[derive(Clone)]
+pub(crate) struct Scheduling {
+    pub priority: Option<Priority>,
+    pub tenant: Tenant,
+    // Set when the request's phases are being timed
+    pub timing: Option<Arc<Timing>>,
+    // Set when the request goes into the access log
+    pub access: Option<Arc<AccessEntry>>,
+    // Set while the access log or the audit log is on
+    pub request_id: Option<String>,
+    // Set when the request is part of a W3C trace, for outbound requests
+    pub trace: Option<TraceContext>,
+}
+
+impl Scheduling {
+    /// Time a phase of the request until the guard is dropped.
+    pub fn measure(&self, phase: Phase) -> Measure {
+        Measure::start(self.timing.as_ref(), phase)
+    }
+
+    /// Count inputs embedded for the request and their tokens, for its
+    /// access log line.
+    pub fn embedded(&self, inputs: usize, tokens: usize) {
+        if let Some(access) = &self.access {
+            access.add(inputs, tokens);
+        }
+    }
+
+    /// Mark the start of encoding the response, which lasts until its body
+    /// is serialized.
+    pub fn encoding(&self) {
+        if let Some(timing) = &self.timing {
+            timing.encoding();
+        }
+    }
+}
+
+#[async_trait]
+impl FromRequestParts<Arc<AppState>> for Scheduling {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &Arc<AppState>) -> Result<Self, Self::Rejection> {
+        let tenant = Tenant::from_headers(&parts.headers);
+        let priority = parts
+            .headers
+            .get("x-priority")
+            .map(|value| {
+                value.to_str().map_err(anyhow::Error::from).and_then(str::parse).map_err(|e| {
+                    ApiError::new(
+                        StatusCode::BAD_REQUEST,
+                        "invalid_request_error",
+                        format!("Invalid X-Priority header: {}", e),
+                    )
+                    .reason("invalid_priority")
+                })
+            })
+            .transpose()?;
+
+        if state.memory.as_ref().is_some_and(|memory| memory.overloaded()) {
+            return Err(ApiError::new(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "service_unavailable",
+                "The server is low on memory and is not accepting new embedding requests; retry shortly",
+            )
+            .code("overloaded_memory")
+            .reason("overloaded_memory")
+            .retry_after(1));
+        }
+        if let Some(shedder) = &state.shedder {
+            if priority.unwrap_or(state.default_priority) == Priority::Low && shedder.shed() {
+                return Err(ApiError::new(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "service_unavailable",
+                    "The server is shedding low-priority requests to keep latency down; \
+                     retry later or send X-Priority: high",
+                )
+                .reason("load_shed")
+                .retry_after(shedder.retry_after()));
+            }
+        }
+        Ok(Self {
+            priority,
+            tenant,
+            timing: parts.extensions.get::<Arc<Timing>>().cloned(),
+            access: parts.extensions.get::<Arc<AccessEntry>>().cloned(),
+            request_id: parts.extensions.get::<RequestId>().map(|id| id.0.clone()),
+            trace: parts.extensions.get::<TraceContext>().cloned(),
+        })
+    }
+}
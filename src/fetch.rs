@@ -0,0 +1,376 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use reqwest::{header, redirect, Url};
+
+use crate::markup;
+use crate::trace_context::TraceContext;
+
+// Redirect hops followed before giving up
+const MAX_REDIRECTS: usize = 5;
+
+/// Settings for fetching documents on behalf of clients.
+#[derive(Debug, Clone)]
+pub struct FetchConfig {
+    /// URLs accepted in one request.
+    pub max_urls: usize,
+    /// Largest response body accepted, in bytes.
+    pub max_bytes: usize,
+    /// Deadline for the whole fetch, including redirects.
+    pub timeout: Duration,
+    /// Hosts that may be fetched (`example.com`, or `.example.com` for it and its
+    /// subdomains). Empty allows any public host.
+    pub allowlist: Vec<String>,
+}
+
+/// Why a single URL could not be fetched.
+#[derive(Debug)]
+pub struct FetchError {
+    /// Machine-readable code reported to the client.
+    pub code: &'static str,
+    pub message: String,
+}
+
+impl FetchError {
+    fn new(code: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+/// Fetches web pages and returns their visible text.
+///
+/// Requests can only reach public addresses: every name is resolved by a
+/// resolver that drops private, loopback, link-local and other internal
+/// ranges, IP-literal URLs and redirect targets are checked the same way, and
+/// environment proxies are ignored since they would resolve names for us.
+pub struct Fetcher {
+    client: reqwest::Client,
+    config: FetchConfig,
+}
+
+impl Fetcher {
+    pub fn new(config: FetchConfig) -> anyhow::Result<Self> {
+        let allowlist = Arc::new(config.allowlist.clone());
+        let redirect_allowlist = allowlist.clone();
+        let client = reqwest::Client::builder()
+            .user_agent(concat!("semembed/", env!("CARGO_PKG_VERSION")))
+            .timeout(config.timeout)
+            .no_proxy()
+            .dns_resolver(Arc::new(PublicResolver { allowlist }))
+            .redirect(redirect::Policy::custom(move |attempt| {
+                if attempt.previous().len() >= MAX_REDIRECTS {
+                    return attempt.error("too many redirects");
+                }
+                match check_url(attempt.url(), &redirect_allowlist) {
+                    Ok(()) => attempt.follow(),
+                    Err(e) => attempt.error(e.message),
+                }
+            }))
+            .build()?;
+        Ok(Self { client, config })
+    }
+
+    pub fn config(&self) -> &FetchConfig {
+        &self.config
+    }
+
+    /// Fetch `url` and return its visible text. Only `text/html` and
+    /// `text/plain` responses are accepted. The request carries `trace`, the
+    /// W3C trace of the request it is made for.
+    pub async fn fetch_text(&self, url: &str, trace: Option<&TraceContext>) -> Result<String, FetchError> {
+        let url = Url::parse(url).map_err(|e| FetchError::new("invalid_url", format!("Invalid URL: {}", e)))?;
+        check_url(&url, &self.config.allowlist)?;
+
+        let mut request = self.client.get(url);
+        if let Some(trace) = trace {
+            request = trace.inject(request);
+        }
+        let mut response = request.send().await.map_err(request_error)?;
+        if !response.status().is_success() {
+            return Err(FetchError::new(
+                "fetch_failed",
+                format!("Server responded with {}", response.status()),
+            ));
+        }
+
+        let content_type = response
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+        let html = match content_type.split(';').next().unwrap_or_default().trim() {
+            "text/html" | "application/xhtml+xml" => true,
+            "text/plain" => false,
+            other => {
+                return Err(FetchError::new(
+                    "unsupported_content_type",
+                    format!(
+                        "Unsupported content type {:?} (supported: text/html, text/plain)",
+                        if other.is_empty() { "none" } else { other }
+                    ),
+                ));
+            }
+        };
+
+        let too_large = || {
+            FetchError::new(
+                "content_too_large",
+                format!("Document exceeds the maximum of {} bytes", self.config.max_bytes),
+            )
+        };
+        if response.content_length().is_some_and(|len| len > self.config.max_bytes as u64) {
+            return Err(too_large());
+        }
+        let mut body = Vec::new();
+        while let Some(chunk) = response.chunk().await.map_err(request_error)? {
+            if body.len() + chunk.len() > self.config.max_bytes {
+                return Err(too_large());
+            }
+            body.extend_from_slice(&chunk);
+        }
+
+        let text = String::from_utf8_lossy(&body);
+        Ok(if html { markup::html_to_text(&text) } else { text.into_owned() })
+    }
+}
+
+fn request_error(e: reqwest::Error) -> FetchError {
+    if e.is_timeout() {
+        FetchError::new("fetch_timeout", "Timed out fetching the document")
+    } else {
+        // The error chain names the blocked address or failed redirect
+        let mut message = e.to_string();
+        let mut source = std::error::Error::source(&e);
+        while let Some(cause) = source {
+            message = format!("{}: {}", message, cause);
+            source = cause.source();
+        }
+        FetchError::new("fetch_failed", message)
+    }
+}
+
+// Scheme, allowlist and IP-literal checks for a URL about to be requested.
+fn check_url(url: &Url, allowlist: &[String]) -> Result<(), FetchError> {
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(FetchError::new(
+            "invalid_url",
+            format!("Unsupported URL scheme {:?} (expected http or https)", url.scheme()),
+        ));
+    }
+    let host = url
+        .host_str()
+        .ok_or_else(|| FetchError::new("invalid_url", "URL has no host"))?;
+    if !host_allowed(host, allowlist) {
+        return Err(FetchError::new("url_not_allowed", format!("Host {} is not on the allowlist", host)));
+    }
+    // IP literals never reach the resolver, so check them here
+    let literal = host.trim_start_matches('[').trim_end_matches(']');
+    if let Ok(ip) = literal.parse::<IpAddr>() {
+        if !is_public(ip) {
+            return Err(FetchError::new("url_not_allowed", format!("Address {} is not public", ip)));
+        }
+    }
+    Ok(())
+}
+
+fn host_allowed(host: &str, allowlist: &[String]) -> bool {
+    let host = host.to_ascii_lowercase();
+    allowlist.is_empty()
+        || allowlist.iter().any(|allowed| match allowed.strip_prefix('.') {
+            Some(domain) => host == domain || host.ends_with(allowed.as_str()),
+            None => host == *allowed,
+        })
+}
+
+/// Whether an address is publicly routable, i.e. safe to fetch from.
+pub fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || ip.is_documentation()
+                // 0.0.0.0/8, carrier-grade NAT 100.64.0.0/10, benchmarking 198.18.0.0/15, reserved 240.0.0.0/4
+                || a == 0
+                || (a == 100 && (64..128).contains(&b))
+                || (a == 198 && (18..20).contains(&b))
+                || a >= 240)
+        }
+        IpAddr::V6(ip) => {
+            if let Some(v4) = embedded_ipv4(ip) {
+                return is_public(IpAddr::V4(v4));
+            }
+            let segments = ip.segments();
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                // Unique local fc00::/7, link-local fe80::/10, documentation 2001:db8::/32
+                || (segments[0] & 0xfe00) == 0xfc00
+                || (segments[0] & 0xffc0) == 0xfe80
+                || (segments[0] == 0x2001 && segments[1] == 0x0db8)
+                // Local-use NAT64 64:ff9b:1::/48, which embeds IPv4 at varying offsets
+                || (segments[0] == 0x64 && segments[1] == 0xff9b && segments[2] == 1))
+        }
+    }
+}
+
+// The IPv4 address an IPv6 one reaches through a translator or tunnel:
+// IPv4-mapped ::ffff:a.b.c.d, IPv4-compatible ::a.b.c.d, NAT64
+// 64:ff9b::a.b.c.d, 6to4 2002:aabb:ccdd::/48 and Teredo 2001:0::/32, whose
+// client address is stored inverted in the last 32 bits.
+fn embedded_ipv4(ip: Ipv6Addr) -> Option<Ipv4Addr> {
+    if let Some(v4) = ip.to_ipv4_mapped() {
+        return Some(v4);
+    }
+    let octets = ip.octets();
+    let last = Ipv4Addr::new(octets[12], octets[13], octets[14], octets[15]);
+    match ip.segments() {
+        // Leave :: and ::1 to the IPv6 checks
+        [0, 0, 0, 0, 0, 0, ..] if !ip.is_unspecified() && !ip.is_loopback() => Some(last),
+        [0x64, 0xff9b, 0, 0, 0, 0, ..] => Some(last),
+        [0x2002, ..] => Some(Ipv4Addr::new(octets[2], octets[3], octets[4], octets[5])),
+        [0x2001, 0, ..] => Some(Ipv4Addr::from(!u32::from(last))),
+        _ => None,
+    }
+}
+
+// Resolves names like the system resolver but only returns public addresses.
+struct PublicResolver {
+    allowlist: Arc<Vec<String>>,
+}
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let allowlist = self.allowlist.clone();
+        Box::pin(async move {
+            let host = name.as_str().to_string();
+            if !host_allowed(&host, &allowlist) {
+                return Err(format!("host {} is not on the allowlist", host).into());
+            }
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host.as_str(), 0))
+                .await?
+                .filter(|addr| is_public(addr.ip()))
+                .collect();
+            if addrs.is_empty() {
+                return Err(format!("{} does not resolve to a public address", host).into());
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn public(ip: &str) -> bool {
+        is_public(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn private_ipv4_ranges_are_not_public() {
+        for ip in [
+            "10.0.0.1",
+            "172.16.5.4",
+            "192.168.1.1",
+            "127.0.0.1",
+            "169.254.169.254",
+            "0.0.0.0",
+            "0.1.2.3",
+            "100.64.0.1",
+            "198.18.0.1",
+            "192.0.2.1",
+            "224.0.0.1",
+            "240.0.0.1",
+            "255.255.255.255",
+        ] {
+            assert!(!public(ip), "{}", ip);
+        }
+        for ip in ["8.8.8.8", "1.1.1.1", "100.128.0.1", "198.20.0.1"] {
+            assert!(public(ip), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn private_ipv6_ranges_are_not_public() {
+        for ip in ["::", "::1", "fc00::1", "fd12:3456::1", "fe80::1", "2001:db8::1", "ff02::1", "64:ff9b:1::1"] {
+            assert!(!public(ip), "{}", ip);
+        }
+        for ip in ["2606:4700:4700::1111", "2a00:1450:4001::200e"] {
+            assert!(public(ip), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn embedded_ipv4_addresses_get_the_ipv4_checks() {
+        for ip in [
+            // IPv4-mapped
+            "::ffff:127.0.0.1",
+            "::ffff:169.254.169.254",
+            // IPv4-compatible
+            "::127.0.0.1",
+            "::10.0.0.1",
+            "::169.254.169.254",
+            // NAT64
+            "64:ff9b::127.0.0.1",
+            "64:ff9b::a9fe:a9fe",
+            "64:ff9b::192.168.0.1",
+            // 6to4
+            "2002:7f00:1::",
+            "2002:a9fe:a9fe::1",
+            "2002:c0a8:101:1::1",
+            // Teredo, client 127.0.0.1 inverted
+            "2001:0:4136:e378:8000:63bf:80ff:fffe",
+        ] {
+            assert!(!public(ip), "{}", ip);
+        }
+        for ip in ["::ffff:8.8.8.8", "::8.8.8.8", "64:ff9b::8.8.8.8", "2002:808:808::1"] {
+            assert!(public(ip), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn teredo_client_addresses_are_uninverted() {
+        let ip: Ipv6Addr = "2001:0:4136:e378:8000:63bf:3fff:fdd2".parse().unwrap();
+        assert_eq!(embedded_ipv4(ip), Some(Ipv4Addr::new(192, 0, 2, 45)));
+    }
+
+    #[test]
+    fn ip_literal_urls_are_checked_before_fetching() {
+        for url in [
+            "http://127.0.0.1/",
+            "http://[::1]/",
+            "http://[::127.0.0.1]/",
+            "http://[64:ff9b::a9fe:a9fe]/latest/meta-data",
+            "http://[2002:a9fe:a9fe::]/",
+        ] {
+            let error = check_url(&Url::parse(url).unwrap(), &[]).unwrap_err();
+            assert_eq!(error.code, "url_not_allowed", "{}", url);
+        }
+        assert!(check_url(&Url::parse("http://[64:ff9b::808:808]/").unwrap(), &[]).is_ok());
+        let error = check_url(&Url::parse("file:///etc/passwd").unwrap(), &[]).unwrap_err();
+        assert_eq!(error.code, "invalid_url");
+    }
+
+    #[test]
+    fn allowlist_matches_hosts_and_subdomains() {
+        let allowlist = vec!["example.com".to_string(), ".docs.org".to_string()];
+        assert!(host_allowed("example.com", &allowlist));
+        assert!(host_allowed("EXAMPLE.com", &allowlist));
+        assert!(!host_allowed("www.example.com", &allowlist));
+        assert!(host_allowed("docs.org", &allowlist));
+        assert!(host_allowed("api.docs.org", &allowlist));
+        assert!(!host_allowed("evildocs.org", &allowlist));
+        assert!(host_allowed("anything.net", &[]));
+    }
+}
@@ -0,0 +1,318 @@
+//! The component breakdown behind `/health`: whether each part of the
+//! service is healthy, degraded (serving, but with a problem worth flagging)
+//! or unhealthy (not fit to serve), and the last error each reported.
+//!
+//! Probes only read state, so how often orchestrators poll doesn't change it.
+//! The watchdog task records the queue's and the error rate's problems as it
+//! finds them, and reaches dependencies once per `SEMEMBED_HEALTH_CACHE_SECS`
+//! so frequent probes don't turn into load on them.
+
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant, SystemTime};
+
+use semembed::VectorSink;
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::access_log::timestamp;
+use crate::{error_excess, saturation, AppState};
+
+// How often the watchdog looks at in-process state
+const INTERVAL: Duration = Duration::from_secs(1);
+// A dependency check taking longer than this counts as failed
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+// Responses needed in the window before the error rate is judged, so a single
+// failure on an idle instance doesn't degrade it
+const MIN_RESPONSES: usize = 20;
+// Responses remembered for the error rate, however many finish in the window
+const MAX_RESPONSES: usize = 4096;
+
+/// One component's state, or the whole instance's: the worst of its components.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Status {
+    Healthy,
+    Degraded,
+    Unhealthy,
+}
+
+/// A component as `/health` reports it.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub(crate) struct Component {
+    pub status: Status,
+    /// The most recent failure, even once the component has recovered.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+    /// When `last_error` happened.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(format = DateTime)]
+    pub last_error_at: Option<String>,
+}
+
+struct Reported {
+    error: String,
+    at: String,
+    // Until the component reports it recovered
+    failing: bool,
+}
+
+/// Errors components have reported, cached dependency checks, and the recent
+/// responses the error rate is computed over.
+pub(crate) struct Health {
+    cache_for: Duration,
+    reported: Mutex<BTreeMap<&'static str, Reported>>,
+    // Background workers, listed as components once they start
+    workers: Mutex<BTreeSet<&'static str>>,
+    // The sink as the watchdog last found it
+    sink: Mutex<Option<Status>>,
+    // Finish time of each recent response, and whether it was a server error
+    responses: Mutex<VecDeque<(Instant, bool)>>,
+    window: Duration,
+}
+
+impl Health {
+    /// Dependencies are checked every `cache_for`; the error rate covers
+    /// responses that finished within `window`.
+    pub fn new(cache_for: Duration, window: Duration) -> Self {
+        Self {
+            cache_for,
+            reported: Mutex::new(BTreeMap::new()),
+            workers: Mutex::new(BTreeSet::new()),
+            sink: Mutex::new(None),
+            responses: Mutex::new(VecDeque::new()),
+            window,
+        }
+    }
+
+    /// List the background worker `component` under `/health` from now on,
+    /// degraded while it is failing.
+    #[cfg_attr(not(any(feature = "kafka", feature = "redis")), allow(dead_code))]
+    pub fn watch(&self, component: &'static str) {
+        self.workers.lock().unwrap_or_else(PoisonError::into_inner).insert(component);
+    }
+
+    /// The workers being watched, in the state they last reported.
+    pub fn workers(&self) -> Vec<(&'static str, Component)> {
+        let workers = self.workers.lock().unwrap_or_else(PoisonError::into_inner).clone();
+        workers
+            .into_iter()
+            .map(|worker| {
+                let status = if self.failing(worker) { Status::Degraded } else { Status::Healthy };
+                (worker, self.component(worker, status))
+            })
+            .collect()
+    }
+
+    /// Record a failure of `component`; one that reports recovery, such as a
+    /// background worker, stays failing until it does.
+    pub fn report(&self, component: &'static str, error: impl ToString) {
+        self.reported().insert(
+            component,
+            Reported {
+                error: error.to_string(),
+                at: timestamp(SystemTime::now()),
+                failing: true,
+            },
+        );
+    }
+
+    /// `component` works again; its last error is still reported.
+    pub fn recovered(&self, component: &'static str) {
+        if let Some(reported) = self.reported().get_mut(component) {
+            reported.failing = false;
+        }
+    }
+
+    /// Whether `component` reported a failure it hasn't recovered from.
+    pub fn failing(&self, component: &'static str) -> bool {
+        self.reported().get(component).is_some_and(|reported| reported.failing)
+    }
+
+    /// `component` in the given state, with the last error it reported.
+    pub fn component(&self, component: &'static str, status: Status) -> Component {
+        let reported = self.reported();
+        let last = reported.get(component);
+        Component {
+            status,
+            last_error: last.map(|reported| reported.error.clone()),
+            last_error_at: last.map(|reported| reported.at.clone()),
+        }
+    }
+
+    /// The vector-store sink: degraded while the watchdog's last check
+    /// couldn't reach it.
+    pub fn sink(&self) -> Component {
+        let status = self.sink.lock().unwrap_or_else(PoisonError::into_inner).unwrap_or(Status::Healthy);
+        self.component("sink", status)
+    }
+
+    async fn check_sink(&self, sink: &dyn VectorSink) {
+        let status = match tokio::time::timeout(CHECK_TIMEOUT, sink.healthcheck()).await {
+            Ok(Ok(())) => Status::Healthy,
+            Ok(Err(e)) => {
+                self.report("sink", e);
+                Status::Degraded
+            }
+            Err(_) => {
+                self.report("sink", format!("no answer within {:?}", CHECK_TIMEOUT));
+                Status::Degraded
+            }
+        };
+        *self.sink.lock().unwrap_or_else(PoisonError::into_inner) = Some(status);
+    }
+
+    /// Record a finished response to an API request.
+    pub fn record_response(&self, server_error: bool) {
+        let now = Instant::now();
+        let mut responses = self.responses();
+        Self::expire(&mut responses, now, self.window);
+        if responses.len() == MAX_RESPONSES {
+            responses.pop_front();
+        }
+        responses.push_back((now, server_error));
+    }
+
+    /// Share of the responses within the window that were server errors, once
+    /// there are enough of them to tell.
+    pub fn error_rate(&self) -> Option<f64> {
+        let mut responses = self.responses();
+        Self::expire(&mut responses, Instant::now(), self.window);
+        if responses.len() < MIN_RESPONSES {
+            return None;
+        }
+        let errors = responses.iter().filter(|&&(_, server_error)| server_error).count();
+        Some(errors as f64 / responses.len() as f64)
+    }
+
+    fn expire(responses: &mut VecDeque<(Instant, bool)>, now: Instant, window: Duration) {
+        while responses.front().is_some_and(|&(finished, _)| now.duration_since(finished) > window) {
+            responses.pop_front();
+        }
+    }
+
+    // Only plain bookkeeping lives under these locks, so a poisoned one is still consistent
+    fn reported(&self) -> MutexGuard<'_, BTreeMap<&'static str, Reported>> {
+        self.reported.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn responses(&self) -> MutexGuard<'_, VecDeque<(Instant, bool)>> {
+        self.responses.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Keeps the components' states current for `/health`: records the
+/// saturated queue and the error rate as problems while they last, and
+/// checks the sink once per cache period.
+pub(crate) async fn watch(state: Arc<AppState>) {
+    let mut ticks = tokio::time::interval(INTERVAL);
+    ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let mut sink_checked: Option<Instant> = None;
+    loop {
+        ticks.tick().await;
+        match saturation(&state) {
+            Some(saturation) => state.health.report("inference_queue", saturation),
+            None => state.health.recovered("inference_queue"),
+        }
+        match error_excess(&state) {
+            Some(excess) => state.health.report("errors", excess),
+            None => state.health.recovered("errors"),
+        }
+        let Some(sink) = state.sinks.default_sink() else {
+            continue;
+        };
+        if sink_checked.is_none_or(|checked| checked.elapsed() >= state.health.cache_for) {
+            state.health.check_sink(sink.as_ref()).await;
+            sink_checked = Some(Instant::now());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    use futures_util::future::BoxFuture;
+    use semembed::{Distance, Point, SinkError, Written};
+
+    use super::*;
+
+    // Answers checks while `up`, counting them
+    #[derive(Default)]
+    struct StubSink {
+        up: AtomicBool,
+        checks: AtomicUsize,
+    }
+
+    impl VectorSink for StubSink {
+        fn kind(&self) -> &'static str {
+            "stub"
+        }
+
+        fn create_collection<'a>(&'a self, _: &'a str, _: usize, _: Distance) -> BoxFuture<'a, Result<(), SinkError>> {
+            Box::pin(async { Ok(()) })
+        }
+
+        fn upsert<'a>(
+            &'a self,
+            _: &'a str,
+            _: &'a [Point],
+        ) -> BoxFuture<'a, Result<Vec<Result<Written, SinkError>>, SinkError>> {
+            Box::pin(async { Ok(Vec::new()) })
+        }
+
+        fn healthcheck(&self) -> BoxFuture<'_, Result<(), SinkError>> {
+            self.checks.fetch_add(1, Ordering::Relaxed);
+            let up = self.up.load(Ordering::Relaxed);
+            Box::pin(async move {
+                if up {
+                    Ok(())
+                } else {
+                    Err(SinkError::retryable("unreachable", "connection refused"))
+                }
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn probes_read_the_sink_as_last_checked() {
+        let health = Health::new(Duration::from_secs(5), Duration::from_secs(60));
+        let sink = StubSink::default();
+        assert_eq!(health.sink().status, Status::Healthy);
+
+        health.check_sink(&sink).await;
+        for _ in 0..10 {
+            let component = health.sink();
+            assert_eq!(component.status, Status::Degraded);
+            assert!(component.last_error.unwrap().contains("connection refused"));
+        }
+        assert_eq!(sink.checks.load(Ordering::Relaxed), 1);
+
+        sink.up.store(true, Ordering::Relaxed);
+        assert_eq!(health.sink().status, Status::Degraded);
+        health.check_sink(&sink).await;
+        let component = health.sink();
+        assert_eq!(component.status, Status::Healthy);
+        assert!(component.last_error.is_some());
+    }
+
+    #[test]
+    fn components_fail_until_they_recover() {
+        let health = Health::new(Duration::from_secs(5), Duration::from_secs(60));
+        assert!(!health.failing("errors"));
+        health.report("errors", "too many");
+        assert!(health.failing("errors"));
+        health.recovered("errors");
+        assert!(!health.failing("errors"));
+        assert_eq!(health.component("errors", Status::Healthy).last_error.as_deref(), Some("too many"));
+    }
+
+    #[test]
+    fn the_error_rate_waits_for_enough_responses() {
+        let health = Health::new(Duration::from_secs(5), Duration::from_secs(60));
+        (0..MIN_RESPONSES - 1).for_each(|_| health.record_response(true));
+        assert_eq!(health.error_rate(), None);
+        health.record_response(false);
+        assert_eq!(health.error_rate(), Some((MIN_RESPONSES - 1) as f64 / MIN_RESPONSES as f64));
+    }
+}
@@ -0,0 +1,279 @@
+//! Downloading catalog models from the Hugging Face Hub or a mirror of it.
+//!
+//! fastembed fetches a model's files itself when it first loads it, but with
+//! no token and a single try, so one dropped connection failed startup. The
+//! files are fetched here first, into the cache fastembed reads, and
+//! fastembed then finds them all there. They come from `SEMEMBED_HF_ENDPOINT`
+//! (or `HF_ENDPOINT`, or `huggingface.co`), with `HF_TOKEN` for gated
+//! models. A network error, `408`, `429` or `5xx` is retried with backoff up
+//! to `SEMEMBED_MODEL_DOWNLOAD_ATTEMPTS` times; any other answer, such as a
+//! `401` for a missing token or a `404` for a mirror without the model, fails
+//! at once.
+
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use fastembed::{EmbeddingModel, TextEmbedding};
+use hf_hub::api::sync::{ApiBuilder, ApiError};
+use hf_hub::api::Progress;
+use hf_hub::{Cache, Repo};
+use tracing::{info, warn};
+
+use crate::env_parse;
+
+const DEFAULT_ENDPOINT: &str = "https://huggingface.co";
+
+// The files fastembed reads a text model's tokenizer from
+const TOKENIZER_FILES: [&str; 4] = [
+    "tokenizer.json",
+    "config.json",
+    "special_tokens_map.json",
+    "tokenizer_config.json",
+];
+
+// Wait before the first retry, doubled for each one after up to the cap
+const BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Where and how model files are downloaded.
+#[derive(Debug, Clone)]
+pub struct HubConfig {
+    pub endpoint: String,
+    pub token: Option<String>,
+    /// Tries per file, the first included.
+    pub attempts: u32,
+}
+
+impl HubConfig {
+    pub fn from_env() -> anyhow::Result<Self> {
+        let env = |name: &str| std::env::var(name).ok().filter(|value| !value.trim().is_empty());
+        Ok(Self {
+            endpoint: env("SEMEMBED_HF_ENDPOINT")
+                .or_else(|| env("HF_ENDPOINT"))
+                .map_or_else(|| DEFAULT_ENDPOINT.to_string(), |endpoint| endpoint.trim().trim_end_matches('/').into()),
+            token: env("HF_TOKEN").map(|token| token.trim().to_string()),
+            attempts: env_parse("SEMEMBED_MODEL_DOWNLOAD_ATTEMPTS")?.unwrap_or(5),
+        })
+    }
+}
+
+/// Download whatever `model` is missing from the cache fastembed loads it
+/// from, `cache_dir` unless `HF_HOME` is set. Cached files are not checked
+/// against the endpoint, so a model already downloaded loads offline.
+pub fn download(config: &HubConfig, model: &EmbeddingModel, cache_dir: &Path) -> anyhow::Result<()> {
+    let info = TextEmbedding::get_model_info(model)?;
+    let cache_dir = std::env::var_os("HF_HOME").map_or_else(|| cache_dir.to_path_buf(), PathBuf::from);
+    let cache = Cache::new(cache_dir.clone()).repo(Repo::model(info.model_code.clone()));
+    let files = std::iter::once(&info.model_file)
+        .chain(&info.additional_files)
+        .map(String::as_str)
+        .chain(TOKENIZER_FILES)
+        .filter(|file| cache.get(file).is_none())
+        .collect::<Vec<_>>();
+    if files.is_empty() {
+        return Ok(());
+    }
+
+    info!(
+        "Downloading {} file(s) of {} from {}{}",
+        files.len(),
+        info.model_code,
+        config.endpoint,
+        if config.token.is_some() { " with HF_TOKEN" } else { "" }
+    );
+    fetch(config, &info.model_code, &files, cache_dir)
+}
+
+// Download `files` of the model `model_code` into `cache_dir`, retrying each
+// file on its own
+fn fetch(config: &HubConfig, model_code: &str, files: &[&str], cache_dir: PathBuf) -> anyhow::Result<()> {
+    let api = ApiBuilder::new()
+        .with_cache_dir(cache_dir)
+        .with_endpoint(config.endpoint.clone())
+        .with_token(config.token.clone())
+        .with_progress(false)
+        .build()?;
+    let repo = api.model(model_code.to_string());
+    for &file in files {
+        let mut attempt = 1;
+        loop {
+            match repo.download_with_progress(file, LogProgress::default()) {
+                Ok(_) => break,
+                Err(e) if attempt < config.attempts && retryable(&e) => {
+                    let backoff = (BACKOFF * 2u32.saturating_pow(attempt - 1)).min(MAX_BACKOFF);
+                    warn!(
+                        "Downloading {} of {} failed ({}), retrying in {:?} ({}/{})",
+                        file, model_code, e, backoff, attempt, config.attempts
+                    );
+                    std::thread::sleep(backoff);
+                    attempt += 1;
+                }
+                Err(e) => {
+                    anyhow::bail!("couldn't download {} of {} from {}: {}", file, model_code, config.endpoint, e)
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// Whether trying again may succeed: network failures and the statuses of an
+// overloaded or restarting server, but not a refusal or a missing file
+fn retryable(error: &ApiError) -> bool {
+    match error {
+        ApiError::RequestError(e) => match **e {
+            ureq::Error::StatusCode(status) => status == 408 || status == 429 || status >= 500,
+            _ => true,
+        },
+        ApiError::MissingHeader(_) | ApiError::InvalidHeader(_) | ApiError::ParseIntError(_) => false,
+        _ => true,
+    }
+}
+
+// Logs a download's progress every tenth of the file
+#[derive(Default)]
+struct LogProgress {
+    file: String,
+    size: usize,
+    done: usize,
+    logged: usize,
+}
+
+impl Progress for LogProgress {
+    fn init(&mut self, size: usize, filename: &str) {
+        self.file = filename.to_string();
+        self.size = size;
+        info!("Downloading {} ({} bytes)", filename, size);
+    }
+
+    fn update(&mut self, size: usize) {
+        self.done += size;
+        let tenths = (self.done * 10).checked_div(self.size).unwrap_or(0).min(10);
+        if tenths > self.logged && tenths < 10 {
+            self.logged = tenths;
+            info!("Downloading {}: {}%", self.file, tenths * 10);
+        }
+    }
+
+    fn finish(&mut self) {
+        info!("Downloaded {} ({} bytes)", self.file, self.done);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    use axum::{
+        extract::State,
+        http::{header, HeaderMap, StatusCode, Uri},
+        response::{IntoResponse, Response},
+        Router,
+    };
+
+    // A Hub mirror serving every file but `missing.json`, failing the first
+    // `failures` requests with `503`
+    #[derive(Default)]
+    struct Mirror {
+        requests: Mutex<Vec<(String, Option<String>)>>,
+        failures: AtomicUsize,
+    }
+
+    async fn serve(State(mirror): State<Arc<Mirror>>, uri: Uri, headers: HeaderMap) -> Response {
+        let path = uri.path().to_string();
+        let authorization = headers.get(header::AUTHORIZATION).and_then(|value| value.to_str().ok());
+        mirror.requests.lock().unwrap().push((path.clone(), authorization.map(str::to_string)));
+        if mirror.failures.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |left| left.checked_sub(1)).is_ok() {
+            return StatusCode::SERVICE_UNAVAILABLE.into_response();
+        }
+        if path.ends_with("/missing.json") {
+            return StatusCode::NOT_FOUND.into_response();
+        }
+        let body = format!("contents of {}", path).into_bytes();
+        let range = headers.get(header::RANGE).and_then(|value| value.to_str().ok()).unwrap_or("bytes=0-");
+        let (start, end) = match range.trim_start_matches("bytes=").split_once('-') {
+            Some((start, "")) => (start.parse().unwrap(), body.len()),
+            Some((start, end)) => (start.parse().unwrap(), end.parse::<usize>().unwrap() + 1),
+            None => (0, body.len()),
+        };
+        (
+            StatusCode::PARTIAL_CONTENT,
+            [
+                (header::CONTENT_RANGE.as_str(), format!("bytes {}-{}/{}", start, end - 1, body.len())),
+                (header::ETAG.as_str(), format!("\"{}\"", path.replace('/', "-"))),
+                ("x-repo-commit", "0123456789abcdef".to_string()),
+            ],
+            body[start..end].to_vec(),
+        )
+            .into_response()
+    }
+
+    async fn mirror(failures: usize) -> (Arc<Mirror>, String) {
+        let mirror = Arc::new(Mirror {
+            failures: AtomicUsize::new(failures),
+            ..Mirror::default()
+        });
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let app = Router::new().fallback(serve).with_state(mirror.clone());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        (mirror, endpoint)
+    }
+
+    // The cache directory, and how fetching `files` into it went
+    async fn fetch_from(endpoint: String, files: &'static [&'static str], name: &str) -> (PathBuf, anyhow::Result<()>) {
+        let dir = std::env::temp_dir().join(format!("semembed-hub-{}-{}", name, std::process::id()));
+        let config = HubConfig {
+            endpoint,
+            token: Some("hf_test".to_string()),
+            attempts: 3,
+        };
+        let cache_dir = dir.clone();
+        let fetched = tokio::task::spawn_blocking(move || fetch(&config, "org/model", files, cache_dir)).await.unwrap();
+        (dir, fetched)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn files_come_from_the_mirror_after_retries() {
+        let (mirror, endpoint) = mirror(1).await;
+        let (dir, fetched) = fetch_from(endpoint, &["model.onnx", "tokenizer.json"], "mirror").await;
+        fetched.unwrap();
+
+        let requests = mirror.requests.lock().unwrap().clone();
+        // The failed request is tried again, then each file is looked up and fetched
+        assert_eq!(requests.len(), 5, "{:?}", requests);
+        assert!(requests.iter().all(|(_, authorization)| authorization.as_deref() == Some("Bearer hf_test")));
+        assert_eq!(requests[0].0, "/org/model/resolve/main/model.onnx");
+        assert_eq!(requests[1].0, "/org/model/resolve/main/model.onnx");
+        assert_eq!(requests[4].0, "/org/model/resolve/main/tokenizer.json");
+
+        let cache = Cache::new(dir.clone()).repo(Repo::model("org/model".to_string()));
+        let tokenizer = std::fs::read_to_string(cache.get("tokenizer.json").unwrap()).unwrap();
+        assert_eq!(tokenizer, "contents of /org/model/resolve/main/tokenizer.json");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn a_missing_file_fails_without_retrying() {
+        let (mirror, endpoint) = mirror(0).await;
+        let (dir, fetched) = fetch_from(endpoint, &["missing.json"], "missing").await;
+        let error = fetched.unwrap_err();
+        assert!(error.to_string().contains("couldn't download missing.json of org/model"), "{}", error);
+        assert_eq!(mirror.requests.lock().unwrap().len(), 1);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn only_transient_statuses_are_retried() {
+        let status = |code| ApiError::RequestError(Box::new(ureq::Error::StatusCode(code)));
+        for code in [408, 429, 500, 503] {
+            assert!(retryable(&status(code)), "{}", code);
+        }
+        for code in [401, 403, 404] {
+            assert!(!retryable(&status(code)), "{}", code);
+        }
+        assert!(!retryable(&ApiError::MissingHeader("etag")));
+    }
+}
@@ -0,0 +1,351 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use axum::{
+    body::{self, Body, Bytes},
+    extract::{Request, State},
+    http::{header, HeaderMap, HeaderName, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use hmac_sha256::Hash;
+use tokio::sync::watch;
+use tracing::debug;
+
+use crate::error::ApiError;
+use crate::queue::Tenant;
+use crate::AppState;
+
+const IDEMPOTENCY_KEY: HeaderName = HeaderName::from_static("idempotency-key");
+const IDEMPOTENCY_REPLAYED: HeaderName = HeaderName::from_static("idempotency-replayed");
+
+// (caller, Idempotency-Key)
+type CacheKey = (Tenant, String);
+
+/// Responses remembered per `Idempotency-Key`, bounded by entry count and age.
+pub struct IdempotencyCache {
+    capacity: usize,
+    ttl: Duration,
+    entries: Mutex<HashMap<CacheKey, Entry>>,
+}
+
+struct Entry {
+    request_hash: [u8; 32],
+    state: EntryState,
+    last_used: Instant,
+    created: Instant,
+}
+
+enum EntryState {
+    // Duplicates wait for the sender to be dropped, then look again
+    InFlight(watch::Receiver<()>),
+    Done(Arc<CachedResponse>),
+}
+
+struct CachedResponse {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+enum Lookup {
+    Execute(watch::Sender<()>),
+    Wait(watch::Receiver<()>),
+    Replay(Arc<CachedResponse>),
+    Mismatch,
+}
+
+impl IdempotencyCache {
+    /// A capacity of zero disables idempotency handling entirely.
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        Self {
+            capacity,
+            ttl,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    fn lookup(&self, key: &CacheKey, request_hash: [u8; 32]) -> Lookup {
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        let now = Instant::now();
+
+        if let Some(entry) = entries.get_mut(key) {
+            let expired = now.duration_since(entry.created) > self.ttl;
+            if !(expired && matches!(entry.state, EntryState::Done(_))) {
+                if entry.request_hash != request_hash {
+                    return Lookup::Mismatch;
+                }
+                entry.last_used = now;
+                return match &entry.state {
+                    EntryState::InFlight(rx) => Lookup::Wait(rx.clone()),
+                    EntryState::Done(response) => Lookup::Replay(response.clone()),
+                };
+            }
+        }
+
+        if entries.len() >= self.capacity {
+            self.evict(&mut entries, now);
+        }
+        let (tx, rx) = watch::channel(());
+        entries.insert(
+            key.clone(),
+            Entry {
+                request_hash,
+                state: EntryState::InFlight(rx),
+                last_used: now,
+                created: now,
+            },
+        );
+        Lookup::Execute(tx)
+    }
+
+    // Drop expired entries; if still full, drop the least recently used completed one
+    fn evict(&self, entries: &mut HashMap<CacheKey, Entry>, now: Instant) {
+        entries.retain(|_, entry| {
+            matches!(entry.state, EntryState::InFlight(_))
+                || now.duration_since(entry.created) <= self.ttl
+        });
+        if entries.len() < self.capacity {
+            return;
+        }
+        let oldest = entries
+            .iter()
+            .filter(|(_, entry)| matches!(entry.state, EntryState::Done(_)))
+            .min_by_key(|(_, entry)| entry.last_used)
+            .map(|(key, _)| key.clone());
+        if let Some(oldest) = oldest {
+            entries.remove(&oldest);
+        }
+    }
+
+    fn complete(&self, key: &CacheKey, response: Option<CachedResponse>) {
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        match response {
+            Some(response) => {
+                if let Some(entry) = entries.get_mut(key) {
+                    entry.state = EntryState::Done(Arc::new(response));
+                }
+            }
+            None => {
+                entries.remove(key);
+            }
+        }
+    }
+}
+
+// Removes the in-flight entry if the executing request is dropped before it
+// completes (e.g. the client disconnected), so duplicates do not wait forever.
+struct InFlightGuard<'a> {
+    cache: &'a IdempotencyCache,
+    key: CacheKey,
+    done: bool,
+    _tx: watch::Sender<()>,
+}
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        if !self.done {
+            self.cache.complete(&self.key, None);
+        }
+    }
+}
+
+/// Honor `Idempotency-Key`: execute the first request for a key, replay its
+/// response for retries with an identical body, and reject reuse of the key
+/// with a different body. Concurrent duplicates wait for the first execution.
+pub(crate) async fn idempotency(
+    State(state): State<Arc<AppState>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let cache = &state.idempotency;
+    let Some(key) = req
+        .headers()
+        .get(IDEMPOTENCY_KEY)
+        .filter(|_| cache.enabled())
+    else {
+        return next.run(req).await;
+    };
+    let Ok(key) = key.to_str().map(str::to_string) else {
+        return ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_request_error",
+            "Idempotency-Key must be visible ASCII",
+        )
+        .reason("invalid_idempotency_key")
+        .into_response();
+    };
+
+    // Keys are scoped to the tenant, as Scheduling identifies it
+    let cache_key = (Tenant::from_headers(req.headers()), key);
+
+    let (parts, body) = req.into_parts();
+    let body = match body::to_bytes(body, state.limits.max_body_bytes).await {
+        Ok(body) => body,
+        Err(_) => {
+            return ApiError::new(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "invalid_request_error",
+                format!(
+                    "Request body exceeds the maximum of {} bytes",
+                    state.limits.max_body_bytes
+                ),
+            )
+            .reason("body_too_large")
+            .into_response();
+        }
+    };
+    let mut hasher = Hash::new();
+    hasher.update(parts.method.as_str());
+    hasher.update(parts.uri.path());
+    // The same body negotiated as CSV is a different response
+    hasher.update(parts.headers.get(header::ACCEPT).map(|v| v.as_bytes()).unwrap_or_default());
+    hasher.update(&body);
+    let request_hash = hasher.finalize();
+
+    let tx = loop {
+        match cache.lookup(&cache_key, request_hash) {
+            Lookup::Execute(tx) => break tx,
+            Lookup::Wait(mut rx) => {
+                debug!("Waiting for in-flight request with the same Idempotency-Key");
+                // Resolves when the executing request finishes or is dropped
+                let _ = rx.changed().await;
+            }
+            Lookup::Replay(cached) => return replay(&cached),
+            Lookup::Mismatch => {
+                return ApiError::new(
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    "invalid_request_error",
+                    "This Idempotency-Key was already used with a different request",
+                )
+                .code("idempotency_key_reused")
+                .reason("idempotency_mismatch")
+                .into_response();
+            }
+        }
+    };
+
+    let mut guard = InFlightGuard {
+        cache,
+        key: cache_key.clone(),
+        done: false,
+        _tx: tx,
+    };
+    let response = next.run(Request::from_parts(parts, Body::from(body))).await;
+
+    // Server errors are not remembered so a retry gets a fresh attempt
+    if response.status().is_server_error() {
+        return response;
+    }
+    let (parts, body) = response.into_parts();
+    let Ok(body) = body::to_bytes(body, usize::MAX).await else {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    };
+    cache.complete(
+        &guard.key,
+        Some(CachedResponse {
+            status: parts.status,
+            headers: parts.headers.clone(),
+            body: body.clone(),
+        }),
+    );
+    guard.done = true;
+    Response::from_parts(parts, Body::from(body))
+}
+
+fn replay(cached: &CachedResponse) -> Response {
+    let mut response = Response::new(Body::from(cached.body.clone()));
+    *response.status_mut() = cached.status;
+    *response.headers_mut() = cached.headers.clone();
+    response
+        .headers_mut()
+        .insert(IDEMPOTENCY_REPLAYED, HeaderValue::from_static("true"));
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(name: &'static str, value: &'static str) -> HeaderMap {
+        HeaderMap::from_iter([(HeaderName::from_static(name), HeaderValue::from_static(value))])
+    }
+
+    fn done(body: &'static str) -> Option<CachedResponse> {
+        Some(CachedResponse {
+            status: StatusCode::OK,
+            headers: HeaderMap::new(),
+            body: Bytes::from_static(body.as_bytes()),
+        })
+    }
+
+    #[test]
+    fn keys_are_scoped_to_the_tenant_whichever_header_carries_the_key() {
+        let cache = IdempotencyCache::new(16, Duration::from_secs(60));
+        let alice = (Tenant::from_headers(&headers("api-key", "alice-key")), "retry-1".to_string());
+        let bob = (Tenant::from_headers(&headers("api-key", "bob-key")), "retry-1".to_string());
+        let Lookup::Execute(_tx) = cache.lookup(&alice, [1; 32]) else {
+            panic!("the first request must execute");
+        };
+        cache.complete(&alice, done("alice's embeddings"));
+
+        // Another api-key with the same Idempotency-Key and body runs on its own
+        assert!(matches!(cache.lookup(&bob, [1; 32]), Lookup::Execute(_)));
+        // The same credential sent as Authorization is the same tenant
+        let alice_again = (Tenant::from_headers(&headers("authorization", "alice-key")), "retry-1".to_string());
+        assert!(matches!(cache.lookup(&alice_again, [1; 32]), Lookup::Replay(response) if response.body == "alice's embeddings"));
+        // Anonymous callers share one scope, apart from everyone else
+        let anonymous = (Tenant::from_headers(&HeaderMap::new()), "retry-1".to_string());
+        assert!(matches!(cache.lookup(&anonymous, [1; 32]), Lookup::Execute(_)));
+    }
+
+    #[test]
+    fn duplicates_wait_and_different_bodies_are_refused() {
+        let cache = IdempotencyCache::new(16, Duration::from_secs(60));
+        let key = (Tenant::from_headers(&headers("authorization", "Bearer k")), "batch-7".to_string());
+        let Lookup::Execute(tx) = cache.lookup(&key, [1; 32]) else {
+            panic!("the first request must execute");
+        };
+        assert!(matches!(cache.lookup(&key, [1; 32]), Lookup::Wait(_)));
+        assert!(matches!(cache.lookup(&key, [2; 32]), Lookup::Mismatch));
+        cache.complete(&key, done("ok"));
+        drop(tx);
+        assert!(matches!(cache.lookup(&key, [1; 32]), Lookup::Replay(_)));
+        assert!(matches!(cache.lookup(&key, [2; 32]), Lookup::Mismatch));
+    }
+
+    #[test]
+    fn abandoned_executions_let_the_next_request_run() {
+        let cache = IdempotencyCache::new(16, Duration::from_secs(60));
+        let key = (Tenant::system(), "k".to_string());
+        let Lookup::Execute(tx) = cache.lookup(&key, [1; 32]) else {
+            panic!("the first request must execute");
+        };
+        drop(InFlightGuard {
+            cache: &cache,
+            key: key.clone(),
+            done: false,
+            _tx: tx,
+        });
+        assert!(matches!(cache.lookup(&key, [1; 32]), Lookup::Execute(_)));
+    }
+
+    #[test]
+    fn full_caches_evict_the_least_recently_used() {
+        let cache = IdempotencyCache::new(2, Duration::from_secs(60));
+        let key = |name: &str| (Tenant::system(), name.to_string());
+        for name in ["a", "b"] {
+            let _ = cache.lookup(&key(name), [0; 32]);
+            cache.complete(&key(name), done(""));
+        }
+        assert!(matches!(cache.lookup(&key("a"), [0; 32]), Lookup::Replay(_)));
+        let _ = cache.lookup(&key("c"), [0; 32]);
+        assert!(matches!(cache.lookup(&key("a"), [0; 32]), Lookup::Replay(_)));
+        assert!(matches!(cache.lookup(&key("b"), [0; 32]), Lookup::Execute(_)));
+    }
+}
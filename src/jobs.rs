@@ -0,0 +1,1105 @@
+//! Batch jobs over object storage (`--features object-storage`).
+//!
+//! `POST /v1/jobs` names an input object (`s3://` or `gs://`) of `{id, text}`
+//! JSONL lines, or a Parquet file with `id` and `text` columns, and an output
+//! prefix; the job runs in the background at
+//! low priority and writes Parquet part files of `id`, `line`, `metadata` (the
+//! line's metadata, as JSON) and `embedding` columns under the prefix, then an
+//! empty `_SUCCESS` marker. Lines that
+//! can't be embedded are counted and sampled in the job's status rather than
+//! failing it. A job that fails or is cancelled deletes the parts it wrote.
+//!
+//! A job may name a `collection` of the configured vector store sink instead
+//! of an output prefix; its points are then upserted as they're embedded,
+//! and stay written if the job fails later.
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, bail, Context};
+use arrow_array::cast::AsArray;
+use arrow_array::types::{Float32Type, Int32Type, Int64Type};
+use arrow_array::{Int64Array, ListArray, RecordBatch, StringArray};
+use arrow_schema::{DataType, Field, Schema};
+use axum::{
+    body::Bytes,
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    routing::{get, post},
+    Json, Router,
+};
+use futures_util::stream::BoxStream;
+use futures_util::StreamExt;
+use object_store::aws::AmazonS3Builder;
+use object_store::gcp::GoogleCloudStorageBuilder;
+use object_store::path::Path as ObjectPath;
+use object_store::{Attribute, Attributes, GetOptions, GetRange, ObjectStore, PutOptions, PutPayload};
+use parquet::arrow::async_reader::{ParquetObjectReader, ParquetRecordBatchStream};
+use parquet::arrow::{ArrowWriter, ParquetRecordBatchStreamBuilder, ProjectionMask};
+use parquet::basic::Compression;
+use parquet::file::properties::WriterProperties;
+use semembed::Point;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Semaphore;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
+
+use crate::bulk::{embed_texts, Lines};
+use crate::error::ApiError;
+use crate::extract::{ApiJson, Scheduling};
+use crate::models::InputKind;
+use crate::queue::{Priority, Tenant};
+use crate::{oversized_metadata, AppState};
+
+// Lines embedded together
+const BATCH_LINES: usize = 64;
+// Rows per Parquet part file; at 1024 dimensions, about 40 MiB
+const PART_ROWS: usize = 10_000;
+// Line errors kept in a job's status
+const SAMPLE_ERRORS: usize = 20;
+// Reads of a JSONL input that may fail mid-way before the job fails; each
+// read is itself retried by the store client
+const ATTEMPTS: u32 = 5;
+
+/// Settings of the job API.
+#[derive(Debug, Clone, Copy)]
+pub struct JobsConfig {
+    /// Jobs running at once; the rest wait their turn.
+    pub concurrency: usize,
+    /// Finished jobs whose status is kept, oldest forgotten first.
+    pub retained: usize,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct JobRequest {
+    input: String,
+    // An object storage prefix, or else a sink collection
+    output: Option<String>,
+    collection: Option<String>,
+    // Create the collection first if it doesn't exist
+    #[serde(default)]
+    create: bool,
+    model: Option<String>,
+    // Batch inputs are documents, so this defaults to passage
+    input_type: Option<InputKind>,
+    dimensions: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum JobStatus {
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+    Cancelled,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+struct Progress {
+    bytes_read: u64,
+    lines: u64,
+    embedded: u64,
+    failed: u64,
+}
+
+// A line that couldn't be embedded
+#[derive(Debug, Clone, Serialize)]
+struct LineError {
+    line: u64,
+    id: serde_json::Value,
+    message: String,
+    code: &'static str,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct JobState {
+    status: JobStatus,
+    started_at: Option<u64>,
+    finished_at: Option<u64>,
+    progress: Progress,
+    // Part files uploaded so far
+    parts: Vec<String>,
+    errors: Vec<LineError>,
+    error: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Provider {
+    S3,
+    Gcs,
+}
+
+/// An object, or a prefix of objects, from `s3://bucket/key` or `gs://bucket/key`.
+#[derive(Debug, Clone)]
+struct ObjectUrl {
+    provider: Provider,
+    bucket: String,
+    path: ObjectPath,
+}
+
+impl ObjectUrl {
+    fn parse(url: &str) -> anyhow::Result<Self> {
+        let (provider, rest) = if let Some(rest) = url.strip_prefix("s3://") {
+            (Provider::S3, rest)
+        } else if let Some(rest) = url.strip_prefix("gs://") {
+            (Provider::Gcs, rest)
+        } else {
+            bail!("{:?} is not an s3:// or gs:// URL", url);
+        };
+        let (bucket, key) = rest.split_once('/').unwrap_or((rest, ""));
+        if bucket.is_empty() {
+            bail!("{:?} has no bucket", url);
+        }
+        Ok(Self {
+            provider,
+            bucket: bucket.to_string(),
+            path: ObjectPath::parse(key).map_err(|e| anyhow!("{:?} is not a valid object key: {}", url, e))?,
+        })
+    }
+
+    // The object `name` under this URL taken as a prefix
+    fn join(&self, name: &str) -> Self {
+        Self {
+            path: self.path.child(name),
+            ..self.clone()
+        }
+    }
+}
+
+impl fmt::Display for ObjectUrl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let scheme = match self.provider {
+            Provider::S3 => "s3",
+            Provider::Gcs => "gs",
+        };
+        write!(f, "{}://{}/{}", scheme, self.bucket, self.path)
+    }
+}
+
+// Where a job's embeddings go
+enum JobOutput {
+    Prefix(ObjectUrl),
+    Collection { name: String, create: bool },
+}
+
+impl fmt::Display for JobOutput {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JobOutput::Prefix(prefix) => write!(f, "{}", prefix),
+            JobOutput::Collection { name, .. } => write!(f, "collection {}", name),
+        }
+    }
+}
+
+struct Job {
+    id: String,
+    created_at: u64,
+    input: ObjectUrl,
+    output: JobOutput,
+    model: Option<String>,
+    input_type: Option<InputKind>,
+    dimensions: Option<usize>,
+    scheduling: Scheduling,
+    cancel: CancellationToken,
+    state: Mutex<JobState>,
+}
+
+impl Job {
+    fn update<T>(&self, update: impl FnOnce(&mut JobState) -> T) -> T {
+        update(&mut self.state.lock().unwrap_or_else(PoisonError::into_inner))
+    }
+
+    fn finished(&self) -> bool {
+        self.update(|state| matches!(state.status, JobStatus::Succeeded | JobStatus::Failed | JobStatus::Cancelled))
+    }
+
+    fn view(&self) -> JobView {
+        JobView {
+            id: self.id.clone(),
+            object: "embedding_job",
+            created_at: self.created_at,
+            input: self.input.to_string(),
+            output: match &self.output {
+                JobOutput::Prefix(prefix) => Some(prefix.to_string()),
+                JobOutput::Collection { .. } => None,
+            },
+            collection: match &self.output {
+                JobOutput::Prefix(_) => None,
+                JobOutput::Collection { name, .. } => Some(name.clone()),
+            },
+            model: self.model.clone(),
+            state: self.update(|state| state.clone()),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct JobView {
+    id: String,
+    object: &'static str,
+    created_at: u64,
+    input: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    output: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    collection: Option<String>,
+    model: Option<String>,
+    #[serde(flatten)]
+    state: JobState,
+}
+
+#[derive(Serialize)]
+struct JobList {
+    object: &'static str,
+    data: Vec<JobView>,
+}
+
+/// Jobs submitted to this instance. Their status lives in memory, so a
+/// restart forgets them (and stops the ones running).
+pub(crate) struct Jobs {
+    // A client per bucket, made on first use
+    stores: Mutex<HashMap<(Provider, String), Arc<dyn ObjectStore>>>,
+    config: JobsConfig,
+    permits: Semaphore,
+    jobs: Mutex<VecDeque<Arc<Job>>>,
+}
+
+impl Jobs {
+    pub fn new(config: JobsConfig) -> Self {
+        Self {
+            stores: Mutex::new(HashMap::new()),
+            config,
+            permits: Semaphore::new(config.concurrency),
+            jobs: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    // The client of the bucket `url` is in, with credentials, region and
+    // endpoint from the environment
+    fn store(&self, url: &ObjectUrl) -> anyhow::Result<Arc<dyn ObjectStore>> {
+        let mut stores = self.stores.lock().unwrap_or_else(PoisonError::into_inner);
+        let key = (url.provider, url.bucket.clone());
+        if let Some(store) = stores.get(&key) {
+            return Ok(store.clone());
+        }
+        let store: Arc<dyn ObjectStore> = match url.provider {
+            Provider::S3 => Arc::new(AmazonS3Builder::from_env().with_bucket_name(&url.bucket).build()?),
+            Provider::Gcs => Arc::new(GoogleCloudStorageBuilder::from_env().with_bucket_name(&url.bucket).build()?),
+        };
+        stores.insert(key, store.clone());
+        Ok(store)
+    }
+}
+
+/// The `/v1/jobs` routes, mounted when jobs are enabled.
+pub(crate) fn router() -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/v1/jobs", post(create_job).get(list_jobs))
+        .route("/v1/jobs/:id", get(retrieve_job).delete(cancel_job))
+}
+
+fn registry(state: &AppState) -> Result<&Arc<Jobs>, ApiError> {
+    state.jobs.as_ref().ok_or_else(|| {
+        ApiError::new(StatusCode::NOT_FOUND, "invalid_request_error", "Batch jobs are not enabled").reason("not_found")
+    })
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs())
+}
+
+fn job_id() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let mut hash = hmac_sha256::Hash::new();
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos());
+    hash.update(nanos.to_le_bytes());
+    hash.update(COUNTER.fetch_add(1, Ordering::Relaxed).to_le_bytes());
+    let hex: String = hash.finalize()[..12].iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!("job_{}", hex)
+}
+
+fn invalid(param: &'static str, message: impl Into<String>) -> ApiError {
+    ApiError::new(StatusCode::BAD_REQUEST, "invalid_request_error", message)
+        .param(param)
+        .reason("invalid_job")
+}
+
+// `POST /v1/jobs`: validate and queue a job; 202 with its status
+async fn create_job(
+    State(state): State<Arc<AppState>>,
+    scheduling: Scheduling,
+    ApiJson(req): ApiJson<JobRequest>,
+) -> Result<(StatusCode, Json<JobView>), ApiError> {
+    let jobs = registry(&state)?;
+    let input = ObjectUrl::parse(&req.input).map_err(|e| invalid("input", e.to_string()))?;
+    if req.input.ends_with('/') || input.path.as_ref().is_empty() {
+        return Err(invalid("input", "input must name an object, not a prefix"));
+    }
+    let output = match (req.output, req.collection) {
+        (Some(output), None) => {
+            JobOutput::Prefix(ObjectUrl::parse(&output).map_err(|e| invalid("output", e.to_string()))?)
+        }
+        (None, Some(name)) if state.sinks.default_sink().is_some() => JobOutput::Collection {
+            name,
+            create: req.create,
+        },
+        (None, Some(_)) => return Err(invalid("collection", "No vector store sink is configured")),
+        _ => return Err(invalid("output", "Provide either an output prefix or a collection")),
+    };
+    if state.settings().resolver.resolve(req.model.as_deref()).is_none() {
+        return Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            "model_not_found",
+            format!("The model `{}` does not exist", req.model.as_deref().unwrap_or_default()),
+        ));
+    }
+    if let Some(requested) = req.dimensions {
+        if requested == 0 || requested > state.metadata.dimensions {
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "invalid_request_error",
+                format!("dimensions must be between 1 and {}, got {}", state.metadata.dimensions, requested),
+            )
+            .param("dimensions")
+            .reason("invalid_dimensions"));
+        }
+    }
+
+    let job = Arc::new(Job {
+        id: job_id(),
+        created_at: now(),
+        input,
+        output,
+        model: req.model,
+        input_type: req.input_type,
+        dimensions: req.dimensions,
+        scheduling: Scheduling {
+            priority: Some(Priority::Low),
+            // The job outlives the request that created it
+            timing: None,
+            access: None,
+            request_id: None,
+            ..scheduling
+        },
+        cancel: CancellationToken::new(),
+        state: Mutex::new(JobState {
+            status: JobStatus::Queued,
+            started_at: None,
+            finished_at: None,
+            progress: Progress::default(),
+            parts: Vec::new(),
+            errors: Vec::new(),
+            error: None,
+        }),
+    });
+    {
+        let mut registry = jobs.jobs.lock().unwrap_or_else(PoisonError::into_inner);
+        registry.push_back(job.clone());
+        // Forget the oldest finished jobs past the limit
+        let finished = registry.iter().filter(|job| job.finished()).count();
+        let mut excess = finished.saturating_sub(jobs.config.retained);
+        registry.retain(|job| {
+            let forget = excess > 0 && job.finished();
+            excess -= usize::from(forget);
+            !forget
+        });
+    }
+    info!("Job {} queued: {} -> {}", job.id, job.input, job.output);
+    tokio::spawn(run(state.clone(), job.clone()));
+    Ok((StatusCode::ACCEPTED, Json(job.view())))
+}
+
+
+// Jobs are visible only to the tenant (API key) that submitted them
+fn find(jobs: &Jobs, id: &str, tenant: &Tenant) -> Result<Arc<Job>, ApiError> {
+    jobs.jobs
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .iter()
+        .find(|job| job.id == id && job.scheduling.tenant.as_str() == tenant.as_str())
+        .cloned()
+        .ok_or_else(|| {
+            ApiError::new(StatusCode::NOT_FOUND, "invalid_request_error", format!("No job with id `{}`", id))
+                .reason("job_not_found")
+        })
+}
+
+async fn list_jobs(State(state): State<Arc<AppState>>, headers: HeaderMap) -> Result<Json<JobList>, ApiError> {
+    let tenant = Tenant::from_headers(&headers);
+    let data = registry(&state)?
+        .jobs
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .iter()
+        .rev()
+        .filter(|job| job.scheduling.tenant.as_str() == tenant.as_str())
+        .map(|job| job.view())
+        .collect();
+    Ok(Json(JobList { object: "list", data }))
+}
+
+async fn retrieve_job(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> Result<Json<JobView>, ApiError> {
+    Ok(Json(find(registry(&state)?, &id, &Tenant::from_headers(&headers))?.view()))
+}
+
+// `DELETE /v1/jobs/:id`: cancel a queued or running job; a finished job is
+// left as it is
+async fn cancel_job(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> Result<Json<JobView>, ApiError> {
+    let job = find(registry(&state)?, &id, &Tenant::from_headers(&headers))?;
+    if !job.finished() {
+        job.cancel.cancel();
+    }
+    Ok(Json(job.view()))
+}
+
+// Wait for a turn, run the job, and record how it ended
+async fn run(state: Arc<AppState>, job: Arc<Job>) {
+    let Some(jobs) = &state.jobs else {
+        return;
+    };
+    let result = tokio::select! {
+        result = async {
+            let _permit = jobs.permits.acquire().await;
+            job.update(|state| {
+                state.status = JobStatus::Running;
+                state.started_at = Some(now());
+            });
+            execute(&state, jobs, &job).await
+        } => result,
+        _ = job.cancel.cancelled() => Err(anyhow::anyhow!("The job was cancelled")),
+    };
+
+    let status = match &result {
+        Ok(()) => JobStatus::Succeeded,
+        Err(_) if job.cancel.is_cancelled() => JobStatus::Cancelled,
+        Err(_) => JobStatus::Failed,
+    };
+    if let Err(e) = &result {
+        if status == JobStatus::Failed {
+            error!("Job {} failed: {:#}", job.id, e);
+        } else {
+            info!("Job {} cancelled", job.id);
+        }
+        // Don't leave a partial output behind for readers of the prefix
+        let parts = job.update(|state| std::mem::take(&mut state.parts));
+        for part in parts {
+            let Ok(url) = ObjectUrl::parse(&part) else {
+                continue;
+            };
+            let deleted = match jobs.store(&url) {
+                Ok(store) => store.delete(&url.path).await.map_err(anyhow::Error::from),
+                Err(e) => Err(e),
+            };
+            if let Err(e) = deleted {
+                warn!("Job {} couldn't delete its partial output {}: {:#}", job.id, part, e);
+            }
+        }
+    } else {
+        let progress = job.update(|state| state.progress.clone());
+        info!("Job {} succeeded: {} lines embedded, {} failed", job.id, progress.embedded, progress.failed);
+    }
+    job.update(|state| {
+        state.status = status;
+        state.finished_at = Some(now());
+        state.error = result.err().map(|e| format!("{:#}", e));
+    });
+}
+
+#[derive(Deserialize)]
+struct InputLine {
+    id: serde_json::Value,
+    text: String,
+    #[serde(default)]
+    metadata: Option<serde_json::Value>,
+}
+
+// Rows of the part file (or sink batch) being filled
+#[derive(Default)]
+struct Part {
+    ids: Vec<Option<String>>,
+    lines: Vec<i64>,
+    metadata: Vec<Option<serde_json::Value>>,
+    embeddings: Vec<Vec<f32>>,
+    // Only kept for a sink, which may store them
+    texts: Vec<String>,
+}
+
+async fn execute(state: &AppState, jobs: &Jobs, job: &Job) -> anyhow::Result<()> {
+    let mut input = Input::open(jobs.store(&job.input)?, &job.input, state.bulk_limits.max_line_bytes).await?;
+    let mut number: u64 = 0;
+    let mut batch: Vec<Batched> = Vec::new();
+    let mut part = Part::default();
+    let flush_rows = match &job.output {
+        JobOutput::Prefix(_) => PART_ROWS,
+        JobOutput::Collection { name, create } => {
+            let sink = state.sinks.default_sink().context("No vector store sink is configured")?;
+            if *create {
+                let dimensions = job.dimensions.unwrap_or(state.metadata.dimensions);
+                sink.create_collection(name, dimensions, state.metadata.distance)
+                    .await
+                    .with_context(|| format!("Couldn't create the collection {}", name))?;
+            }
+            state.sinks.batch_size()
+        }
+    };
+
+    while let Some(records) = input.next().await? {
+        job.update(|state| state.progress.bytes_read = input.bytes_read());
+        for record in records {
+            number += 1;
+            match record {
+                Ok(InputLine { id, text, metadata }) => {
+                    match metadata.as_ref().and_then(|metadata| oversized_metadata(state, metadata)) {
+                        Some(why) => {
+                            record_error(job, number, id, format!("Line {}: {}", number, why), "metadata_too_large")
+                        }
+                        None => batch.push(Batched {
+                            line: number,
+                            id,
+                            metadata,
+                            text,
+                        }),
+                    }
+                }
+                Err(Malformed::TooLong) => {
+                    let message = format!(
+                        "Line {} is longer than the limit of {} bytes",
+                        number, state.bulk_limits.max_line_bytes
+                    );
+                    record_error(job, number, serde_json::Value::Null, message, "line_too_long")
+                }
+                Err(Malformed::Json(e)) => {
+                    let message = format!("Line {} is not a JSON object with an id and a string text: {}", number, e);
+                    record_error(job, number, serde_json::Value::Null, message, "invalid_line")
+                }
+                Err(Malformed::Row(id, why)) => {
+                    record_error(job, number, id, format!("Row {} {}", number, why), "invalid_line")
+                }
+            }
+            if batch.len() >= BATCH_LINES {
+                embed_batch(state, job, std::mem::take(&mut batch), &mut part).await;
+            }
+            if part.lines.len() >= flush_rows {
+                flush(state, jobs, job, std::mem::take(&mut part)).await?;
+            }
+        }
+    }
+    embed_batch(state, job, batch, &mut part).await;
+    if !part.lines.is_empty() {
+        flush(state, jobs, job, part).await?;
+    }
+    match &job.output {
+        JobOutput::Prefix(prefix) => {
+            put(jobs, &prefix.join("_SUCCESS"), Bytes::new(), "application/octet-stream").await
+        }
+        JobOutput::Collection { .. } => Ok(()),
+    }
+}
+
+// Why a record of the input can't be embedded
+enum Malformed {
+    TooLong,
+    Json(serde_json::Error),
+    // A Parquet row, with its id
+    Row(serde_json::Value, String),
+}
+
+type Record = Result<InputLine, Malformed>;
+
+// The records of a job's input: the lines of a JSONL object, or the rows of a
+// Parquet file (by its `.parquet` extension)
+enum Input {
+    Jsonl {
+        download: Download,
+        lines: Lines,
+        ended: bool,
+    },
+    Parquet {
+        rows: Box<ParquetRecordBatchStream<ParquetObjectReader>>,
+        // Rows and compressed bytes of each row group
+        groups: Vec<(usize, u64)>,
+        read: usize,
+    },
+}
+
+impl Input {
+    async fn open(store: Arc<dyn ObjectStore>, url: &ObjectUrl, max_line_bytes: usize) -> anyhow::Result<Self> {
+        if url.path.extension() != Some("parquet") {
+            return Ok(Input::Jsonl {
+                download: Download {
+                    store,
+                    url: url.clone(),
+                    stream: None,
+                    etag: None,
+                    offset: 0,
+                    failures: 0,
+                },
+                lines: Lines::new(max_line_bytes),
+                ended: false,
+            });
+        }
+        let meta = store.head(&url.path).await.with_context(|| format!("Reading {}", url))?;
+        let builder = ParquetRecordBatchStreamBuilder::new(ParquetObjectReader::new(store, meta))
+            .await
+            .with_context(|| format!("{} is not a Parquet file", url))?;
+        let schema = builder.schema().clone();
+        let column = |name: &str, types: &[DataType]| -> anyhow::Result<Option<usize>> {
+            let Ok(index) = schema.index_of(name) else {
+                return Ok(None);
+            };
+            if !types.contains(schema.field(index).data_type()) {
+                let found = schema.field(index).data_type();
+                bail!("The `{}` column of {} is {}, not one of {:?}", name, url, found, types);
+            }
+            Ok(Some(index))
+        };
+        let strings = [DataType::Utf8, DataType::LargeUtf8, DataType::Utf8View];
+        let text = column("text", &strings)?.with_context(|| format!("{} has no `text` column", url))?;
+        let id = column("id", &[&strings[..], &[DataType::Int32, DataType::Int64]].concat())?;
+        let metadata = column("metadata", &strings)?;
+        let mask = ProjectionMask::roots(builder.parquet_schema(), [Some(text), id, metadata].into_iter().flatten());
+        let groups = builder
+            .metadata()
+            .row_groups()
+            .iter()
+            .map(|group| (group.num_rows() as usize, group.compressed_size() as u64))
+            .collect();
+        let rows = builder
+            .with_projection(mask)
+            .with_batch_size(BATCH_LINES)
+            .build()
+            .with_context(|| format!("Reading {}", url))?;
+        Ok(Input::Parquet {
+            rows: Box::new(rows),
+            groups,
+            read: 0,
+        })
+    }
+
+    // The next records, or `None` at the end of the input
+    async fn next(&mut self) -> anyhow::Result<Option<Vec<Record>>> {
+        match self {
+            Input::Jsonl { ended: true, .. } => Ok(None),
+            Input::Jsonl { download, lines, ended } => {
+                let received = match download.next_chunk().await? {
+                    Some(chunk) => lines.push(&chunk),
+                    None => {
+                        *ended = true;
+                        lines.finish().into_iter().collect()
+                    }
+                };
+                Ok(Some(
+                    received
+                        .into_iter()
+                        .filter_map(|line| match line {
+                            None => Some(Err(Malformed::TooLong)),
+                            Some(raw) if raw.iter().all(u8::is_ascii_whitespace) => None,
+                            Some(raw) => {
+                                let raw = raw.strip_suffix(b"\r").unwrap_or(&raw);
+                                Some(serde_json::from_slice(raw).map_err(Malformed::Json))
+                            }
+                        })
+                        .collect(),
+                ))
+            }
+            Input::Parquet { rows, read, .. } => {
+                let Some(batch) = rows.next().await.transpose()? else {
+                    return Ok(None);
+                };
+                *read += batch.num_rows();
+                Ok(Some(records(&batch)))
+            }
+        }
+    }
+
+    // For JSONL, the bytes downloaded; for Parquet, the compressed size of the
+    // row groups read
+    fn bytes_read(&self) -> u64 {
+        match self {
+            Input::Jsonl { download, .. } => download.offset as u64,
+            Input::Parquet { groups, read, .. } => {
+                let mut rows = 0;
+                groups
+                    .iter()
+                    .take_while(|(group_rows, _)| {
+                        rows += group_rows;
+                        rows <= *read
+                    })
+                    .map(|(_, bytes)| bytes)
+                    .sum()
+            }
+        }
+    }
+}
+
+// The rows of a batch of `id`, `text` and `metadata` columns of the types
+// `Input::open` accepts
+fn records(batch: &RecordBatch) -> Vec<Record> {
+    let strings = |name: &str| -> Option<Vec<Option<String>>> {
+        let column = batch.column_by_name(name)?;
+        let values: Vec<Option<&str>> = if let Some(values) = column.as_string_opt::<i32>() {
+            values.iter().collect()
+        } else if let Some(values) = column.as_string_opt::<i64>() {
+            values.iter().collect()
+        } else {
+            column.as_string_view_opt()?.iter().collect()
+        };
+        Some(values.into_iter().map(|value| value.map(str::to_string)).collect())
+    };
+    let rows = batch.num_rows();
+    let ids: Vec<serde_json::Value> = match batch.column_by_name("id") {
+        None => vec![serde_json::Value::Null; rows],
+        Some(column) => {
+            if let Some(ids) = column.as_primitive_opt::<Int64Type>() {
+                ids.iter().map(|id| id.map_or(serde_json::Value::Null, serde_json::Value::from)).collect()
+            } else if let Some(ids) = column.as_primitive_opt::<Int32Type>() {
+                ids.iter().map(|id| id.map_or(serde_json::Value::Null, serde_json::Value::from)).collect()
+            } else {
+                let ids = strings("id").unwrap_or_default();
+                ids.into_iter().map(|id| id.map_or(serde_json::Value::Null, serde_json::Value::String)).collect()
+            }
+        }
+    };
+    let texts = strings("text").unwrap_or_default();
+    let metadata = strings("metadata").unwrap_or_else(|| vec![None; rows]);
+    ids.into_iter()
+        .zip(texts)
+        .zip(metadata)
+        .map(|((id, text), metadata)| {
+            let Some(text) = text else {
+                return Err(Malformed::Row(id, "has a null text".to_string()));
+            };
+            let metadata = match metadata.map(|metadata| serde_json::from_str(&metadata)).transpose() {
+                Ok(metadata) => metadata,
+                Err(e) => return Err(Malformed::Row(id, format!("has metadata that is not JSON: {}", e))),
+            };
+            Ok(InputLine { id, text, metadata })
+        })
+        .collect()
+}
+
+// A download of an object that resumes where it stopped when the connection
+// fails mid-way, as long as the object stays the same
+struct Download {
+    store: Arc<dyn ObjectStore>,
+    url: ObjectUrl,
+    stream: Option<BoxStream<'static, object_store::Result<Bytes>>>,
+    etag: Option<String>,
+    offset: usize,
+    failures: u32,
+}
+
+impl Download {
+    async fn next_chunk(&mut self) -> anyhow::Result<Option<Bytes>> {
+        loop {
+            let stream = match &mut self.stream {
+                Some(stream) => stream,
+                None => {
+                    let options = GetOptions {
+                        range: (self.offset > 0).then_some(GetRange::Offset(self.offset)),
+                        if_match: self.etag.clone(),
+                        ..GetOptions::default()
+                    };
+                    let result = (self.store.get_opts(&self.url.path, options).await)
+                        .with_context(|| format!("Reading {}", self.url))?;
+                    self.etag = self.etag.take().or(result.meta.e_tag.clone());
+                    self.stream.insert(result.into_stream())
+                }
+            };
+            match stream.next().await {
+                Some(Ok(chunk)) => {
+                    self.offset += chunk.len();
+                    return Ok(Some(chunk));
+                }
+                None => return Ok(None),
+                Some(Err(e)) => {
+                    self.stream = None;
+                    self.failures += 1;
+                    if self.failures >= ATTEMPTS {
+                        return Err(e).with_context(|| format!("Reading {}", self.url));
+                    }
+                    warn!("Reading {} failed at byte {} ({}), resuming", self.url, self.offset, e);
+                }
+            }
+        }
+    }
+}
+
+// Upload `body` as the object at `url`, replacing any object there
+async fn put(jobs: &Jobs, url: &ObjectUrl, body: Bytes, content_type: &'static str) -> anyhow::Result<()> {
+    let options = PutOptions {
+        attributes: Attributes::from_iter([(Attribute::ContentType, content_type)]),
+        ..PutOptions::default()
+    };
+    (jobs.store(url)?.put_opts(&url.path, PutPayload::from(body), options).await)
+        .with_context(|| format!("Writing {}", url))?;
+    Ok(())
+}
+
+fn record_error(job: &Job, line: u64, id: serde_json::Value, message: String, code: &'static str) {
+    job.update(|state| {
+        state.progress.lines += 1;
+        state.progress.failed += 1;
+        if state.errors.len() < SAMPLE_ERRORS {
+            state.errors.push(LineError { line, id, message, code });
+        }
+    });
+}
+
+// A parsed line waiting for its batch
+struct Batched {
+    line: u64,
+    id: serde_json::Value,
+    metadata: Option<serde_json::Value>,
+    text: String,
+}
+
+async fn embed_batch(state: &AppState, job: &Job, batch: Vec<Batched>, part: &mut Part) {
+    if batch.is_empty() {
+        return;
+    }
+    let (lines, texts): (Vec<_>, Vec<_>) = batch
+        .into_iter()
+        .map(|Batched { line, id, metadata, text }| ((line, id, metadata), text))
+        .unzip();
+    let kept = match job.output {
+        JobOutput::Prefix(_) => Vec::new(),
+        JobOutput::Collection { .. } => texts.clone(),
+    };
+    let input_type = job.input_type.or(Some(InputKind::Passage));
+    let embedded = embed_texts(state, input_type, job.dimensions, &job.scheduling, texts).await;
+    let mut kept = kept.into_iter();
+    for ((line, id, metadata), embedding) in lines.into_iter().zip(embedded) {
+        let text = kept.next();
+        match embedding {
+            Ok((embedding, _)) => {
+                part.texts.extend(text);
+                part.ids.push(match id {
+                    serde_json::Value::Null => None,
+                    serde_json::Value::String(id) => Some(id),
+                    id => Some(id.to_string()),
+                });
+                part.lines.push(line as i64);
+                part.metadata.push(metadata);
+                part.embeddings.push(embedding);
+                job.update(|state| {
+                    state.progress.lines += 1;
+                    state.progress.embedded += 1;
+                });
+            }
+            Err(error) => record_error(job, line, id, error.message, error.code),
+        }
+    }
+}
+
+async fn flush(state: &AppState, jobs: &Jobs, job: &Job, part: Part) -> anyhow::Result<()> {
+    match &job.output {
+        JobOutput::Prefix(prefix) => write_part(jobs, job, prefix, part).await,
+        JobOutput::Collection { name, .. } => write_points(state, job, name, part).await,
+    }
+}
+
+// Upsert a batch into the sink; points it rejects become line errors, and a
+// failure of the whole batch fails the job
+async fn write_points(state: &AppState, job: &Job, collection: &str, part: Part) -> anyhow::Result<()> {
+    let sink = state.sinks.default_sink().context("No vector store sink is configured")?;
+    let points: Vec<Point> = part
+        .ids
+        .iter()
+        .zip(&part.lines)
+        .zip(part.embeddings)
+        .zip(part.texts)
+        .zip(part.metadata)
+        .map(|((((id, line), vector), text), metadata)| Point {
+            // Lines without an id are keyed by their number
+            id: id.clone().unwrap_or_else(|| line.to_string()),
+            vector,
+            text: Some(text),
+            metadata,
+        })
+        .collect();
+    let written = state
+        .sinks
+        .upsert(sink.as_ref(), collection, &points)
+        .await
+        .with_context(|| format!("Writing to the {} sink failed", sink.kind()))?;
+    for ((result, line), id) in written.into_iter().zip(part.lines).zip(part.ids) {
+        if let Err(e) = result {
+            job.update(|state| {
+                state.progress.embedded -= 1;
+                state.progress.failed += 1;
+                if state.errors.len() < SAMPLE_ERRORS {
+                    state.errors.push(LineError {
+                        line: line as u64,
+                        id: id.map_or(serde_json::Value::Null, serde_json::Value::String),
+                        message: e.message,
+                        code: e.code,
+                    });
+                }
+            });
+        }
+    }
+    Ok(())
+}
+
+// Encode a part as Parquet and upload it next to the ones before it
+async fn write_part(jobs: &Jobs, job: &Job, prefix: &ObjectUrl, part: Part) -> anyhow::Result<()> {
+    let index = job.update(|state| state.parts.len());
+    let url = prefix.join(&format!("part-{:05}.parquet", index));
+    let body = tokio::task::spawn_blocking(move || -> anyhow::Result<Vec<u8>> {
+        let item = Field::new("item", DataType::Float32, true);
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("id", DataType::Utf8, true),
+            Field::new("line", DataType::Int64, false),
+            Field::new("metadata", DataType::Utf8, true),
+            Field::new("embedding", DataType::List(Arc::new(item)), false),
+        ]));
+        // Metadata is kept as the JSON it came as
+        let metadata: StringArray = part.metadata.iter().map(|m| m.as_ref().map(|m| m.to_string())).collect();
+        let embeddings = part.embeddings.into_iter().map(|embedding| Some(embedding.into_iter().map(Some)));
+        let batch = RecordBatch::try_new(
+            schema.clone(),
+            vec![
+                Arc::new(StringArray::from(part.ids)),
+                Arc::new(Int64Array::from(part.lines)),
+                Arc::new(metadata),
+                Arc::new(ListArray::from_iter_primitive::<Float32Type, _, _>(embeddings)),
+            ],
+        )?;
+        let properties = WriterProperties::builder().set_compression(Compression::SNAPPY).build();
+        let mut writer = ArrowWriter::try_new(Vec::new(), schema, Some(properties))?;
+        writer.write(&batch)?;
+        Ok(writer.into_inner()?)
+    })
+    .await??;
+    // Recorded first, so a cancellation during the upload still cleans it up
+    job.update(|state| state.parts.push(url.to_string()));
+    put(jobs, &url, Bytes::from(body), "application/vnd.apache.parquet").await
+}
+
+#[cfg(test)]
+mod tests {
+    use object_store::memory::InMemory;
+
+    use super::*;
+
+    async fn records(input: &mut Input) -> Vec<Record> {
+        let mut records = Vec::new();
+        while let Some(next) = input.next().await.unwrap() {
+            records.extend(next);
+        }
+        records
+    }
+
+    fn summary(records: Vec<Record>) -> Vec<String> {
+        records
+            .into_iter()
+            .map(|record| match record {
+                Ok(line) => format!("{} {:?} {:?}", line.id, line.text, line.metadata.map(|m| m.to_string())),
+                Err(Malformed::TooLong) => "too long".to_string(),
+                Err(Malformed::Json(_)) => "not json".to_string(),
+                Err(Malformed::Row(id, why)) => format!("{} {}", id, why),
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn jsonl_lines_are_read() {
+        let store: Arc<dyn ObjectStore> = Arc::new(InMemory::new());
+        let url = ObjectUrl::parse("s3://corpus/docs.jsonl").unwrap();
+        let body = "{\"id\": \"a\", \"text\": \"one\"}\r\n\n{\"id\": 2, \"text\": \"two\", \"metadata\": {\"k\": 1}}\n\
+                    {\"id\": 3}\n{\"id\": 4, \"text\": \"a line longer than the fifty byte limit\"}\n\
+                    {\"id\": 5, \"text\": \"last\"}";
+        store.put(&url.path, PutPayload::from(body.as_bytes().to_vec())).await.unwrap();
+
+        let mut input = Input::open(store, &url, 50).await.unwrap();
+        assert_eq!(
+            summary(records(&mut input).await),
+            [
+                "\"a\" \"one\" None",
+                "2 \"two\" Some(\"{\\\"k\\\":1}\")",
+                "not json",
+                "too long",
+                "5 \"last\" None",
+            ]
+        );
+        assert_eq!(input.bytes_read(), body.len() as u64);
+    }
+
+    #[tokio::test]
+    async fn parquet_rows_are_read() {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("id", DataType::Int64, true),
+            Field::new("text", DataType::LargeUtf8, true),
+            Field::new("metadata", DataType::Utf8, true),
+            Field::new("ignored", DataType::Int64, false),
+        ]));
+        let batch = RecordBatch::try_new(
+            schema.clone(),
+            vec![
+                Arc::new(Int64Array::from(vec![Some(1), None, Some(3), Some(4)])),
+                Arc::new(arrow_array::LargeStringArray::from(vec![Some("one"), Some("two"), None, Some("four")])),
+                Arc::new(StringArray::from(vec![Some("{\"k\": 1}"), None, None, Some("{")])),
+                Arc::new(Int64Array::from(vec![0; 4])),
+            ],
+        )
+        .unwrap();
+        let properties = WriterProperties::builder().set_max_row_group_size(2).build();
+        let mut writer = ArrowWriter::try_new(Vec::new(), schema, Some(properties)).unwrap();
+        writer.write(&batch).unwrap();
+        let store: Arc<dyn ObjectStore> = Arc::new(InMemory::new());
+        let url = ObjectUrl::parse("gs://corpus/docs.parquet").unwrap();
+        store.put(&url.path, PutPayload::from(writer.into_inner().unwrap())).await.unwrap();
+
+        let mut input = Input::open(store, &url, 30).await.unwrap();
+        assert_eq!(input.bytes_read(), 0);
+        assert_eq!(
+            summary(records(&mut input).await),
+            [
+                "1 \"one\" Some(\"{\\\"k\\\":1}\")",
+                "null \"two\" None",
+                "3 has a null text",
+                "4 has metadata that is not JSON: EOF while parsing an object at line 1 column 1",
+            ]
+        );
+        assert!(input.bytes_read() > 0);
+    }
+
+    #[tokio::test]
+    async fn parquet_without_a_string_text_column_is_refused() {
+        let schema = Arc::new(Schema::new(vec![Field::new("text", DataType::Int64, false)]));
+        let batch = RecordBatch::try_new(schema.clone(), vec![Arc::new(Int64Array::from(vec![1]))]).unwrap();
+        let mut writer = ArrowWriter::try_new(Vec::new(), schema, None).unwrap();
+        writer.write(&batch).unwrap();
+        let store: Arc<dyn ObjectStore> = Arc::new(InMemory::new());
+        let url = ObjectUrl::parse("s3://corpus/numbers.parquet").unwrap();
+        store.put(&url.path, PutPayload::from(writer.into_inner().unwrap())).await.unwrap();
+
+        let Err(e) = Input::open(store, &url, 30).await else {
+            panic!("a numeric text column was accepted");
+        };
+        assert!(e.to_string().starts_with("The `text` column of s3://corpus/numbers.parquet is Int64"), "{}", e);
+    }
+
+    #[test]
+    fn urls_name_a_bucket_and_a_key() {
+        let prefix = ObjectUrl::parse("s3://corpus/embeddings/run-1/").unwrap();
+        assert_eq!(prefix.join("part-00000.parquet").to_string(), "s3://corpus/embeddings/run-1/part-00000.parquet");
+        assert_eq!(ObjectUrl::parse("gs://corpus").unwrap().join("_SUCCESS").to_string(), "gs://corpus/_SUCCESS");
+        assert!(ObjectUrl::parse("https://corpus/docs.jsonl").is_err());
+        assert!(ObjectUrl::parse("s3:///docs.jsonl").is_err());
+        assert!(ObjectUrl::parse("s3://corpus/a//b").is_err());
+    }
+}
@@ -0,0 +1,451 @@
+//! Stream embedding between Kafka topics (`--features kafka`).
+//!
+//! JSON messages consumed from `SEMEMBED_KAFKA_INPUT_TOPIC` are embedded in
+//! micro-batches and produced to `SEMEMBED_KAFKA_OUTPUT_TOPIC` as the original
+//! object plus `embedding` and `embedding_model` fields, keyed like the input.
+//! Offsets are committed only once every message of a batch has been
+//! delivered, so a crash replays messages rather than losing them. Messages
+//! that can never be embedded (not JSON, no text, over the size limit) go to
+//! the dead-letter topic with the reason in their headers.
+
+use std::collections::HashMap;
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures_util::future::join_all;
+use prometheus::{CounterVec, Histogram, HistogramOpts, IntGaugeVec, Opts, Registry};
+use rdkafka::config::ClientConfig;
+use rdkafka::consumer::{CommitMode, Consumer, ConsumerContext, StreamConsumer};
+use rdkafka::message::{Header, OwnedHeaders, OwnedMessage};
+use rdkafka::producer::{FutureProducer, FutureRecord};
+use rdkafka::statistics::Statistics;
+use rdkafka::{ClientContext, Message, Offset, TopicPartitionList};
+use serde::Serialize;
+use tracing::{info, warn};
+
+use crate::bulk::embed_texts;
+use crate::extract::Scheduling;
+use crate::models::InputKind;
+use crate::queue::{Priority, Tenant};
+use crate::{env_parse, AppState, EmbeddingData, EncodingFormat, ItemError};
+
+// How often librdkafka reports consumer lag
+const STATS_INTERVAL: Duration = Duration::from_secs(15);
+// Wait before retrying a batch while the circuit is open, or a failed delivery
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// Where messages come from and go, from the `SEMEMBED_KAFKA_*` variables.
+#[derive(Debug, Clone)]
+pub struct KafkaConfig {
+    pub brokers: String,
+    pub group_id: String,
+    pub input_topic: String,
+    pub output_topic: String,
+    pub dead_letter_topic: Option<String>,
+    // Dotted path of the text in each message, e.g. `document.body`
+    pub text_field: Vec<String>,
+    pub batch_size: usize,
+    pub linger: Duration,
+    pub priority: Priority,
+    pub dimensions: Option<usize>,
+    // Extra librdkafka properties, for both consumer and producer
+    pub properties: Vec<(String, String)>,
+    // Run without the HTTP listeners
+    pub standalone: bool,
+}
+
+impl KafkaConfig {
+    /// `None` when `SEMEMBED_KAFKA_BROKERS` is unset.
+    pub fn from_env() -> anyhow::Result<Option<Self>> {
+        let Some(brokers) = non_empty("SEMEMBED_KAFKA_BROKERS") else {
+            return Ok(None);
+        };
+        let topic = |name: &str| {
+            non_empty(name).ok_or_else(|| anyhow::anyhow!("{} must be set with SEMEMBED_KAFKA_BROKERS", name))
+        };
+        Ok(Some(Self {
+            brokers,
+            group_id: non_empty("SEMEMBED_KAFKA_GROUP_ID").unwrap_or_else(|| "semembed".to_string()),
+            input_topic: topic("SEMEMBED_KAFKA_INPUT_TOPIC")?,
+            output_topic: topic("SEMEMBED_KAFKA_OUTPUT_TOPIC")?,
+            dead_letter_topic: non_empty("SEMEMBED_KAFKA_DLQ_TOPIC"),
+            text_field: parse_field(&non_empty("SEMEMBED_KAFKA_TEXT_FIELD").unwrap_or_else(|| "text".to_string()))?,
+            batch_size: env_parse("SEMEMBED_KAFKA_BATCH_SIZE")?.unwrap_or(64),
+            linger: Duration::from_millis(env_parse("SEMEMBED_KAFKA_LINGER_MS")?.unwrap_or(50)),
+            priority: std::env::var("SEMEMBED_KAFKA_PRIORITY").map_or(Ok(Priority::Low), |value| value.parse())?,
+            dimensions: env_parse("SEMEMBED_KAFKA_DIMENSIONS")?,
+            properties: parse_properties(&std::env::var("SEMEMBED_KAFKA_PROPERTIES").unwrap_or_default())?,
+            standalone: crate::env_flag("SEMEMBED_KAFKA_STANDALONE")?,
+        }))
+    }
+}
+
+fn non_empty(name: &str) -> Option<String> {
+    std::env::var(name).ok().map(|value| value.trim().to_string()).filter(|value| !value.is_empty())
+}
+
+/// Parse `SEMEMBED_KAFKA_TEXT_FIELD`: field names separated by dots.
+fn parse_field(value: &str) -> anyhow::Result<Vec<String>> {
+    let path: Vec<String> = value.trim().split('.').map(str::to_string).collect();
+    if path.iter().any(String::is_empty) {
+        anyhow::bail!("invalid field path {:?} (expected names separated by dots, e.g. document.body)", value);
+    }
+    Ok(path)
+}
+
+/// Parse `SEMEMBED_KAFKA_PROPERTIES`: comma-separated `key=value` librdkafka
+/// properties, such as `security.protocol=SASL_SSL`.
+fn parse_properties(value: &str) -> anyhow::Result<Vec<(String, String)>> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let (key, value) = entry
+                .split_once('=')
+                .ok_or_else(|| anyhow::anyhow!("invalid Kafka property {:?} (expected <key>=<value>)", entry))?;
+            Ok((key.trim().to_string(), value.trim().to_string()))
+        })
+        .collect()
+}
+
+// Kafka metrics, registered next to the server's
+struct KafkaMetrics {
+    messages: CounterVec,
+    batch_duration: Histogram,
+    lag: IntGaugeVec,
+}
+
+impl KafkaMetrics {
+    fn register(registry: &Registry) -> anyhow::Result<Self> {
+        let messages = CounterVec::new(
+            Opts::new(
+                "semembed_kafka_messages_total",
+                "Kafka messages handled, by outcome (embedded, dead_lettered or dropped)"
+            ),
+            &["outcome"],
+        )?;
+        registry.register(Box::new(messages.clone()))?;
+
+        let batch_duration = Histogram::with_opts(HistogramOpts::new(
+            "semembed_kafka_batch_duration_seconds",
+            "Time from a Kafka batch's first message to its offsets being committed"
+        ))?;
+        registry.register(Box::new(batch_duration.clone()))?;
+
+        let lag = IntGaugeVec::new(
+            Opts::new("semembed_kafka_consumer_lag", "Messages in each assigned partition not yet consumed"),
+            &["topic", "partition"],
+        )?;
+        registry.register(Box::new(lag.clone()))?;
+
+        Ok(Self {
+            messages,
+            batch_duration,
+            lag,
+        })
+    }
+}
+
+// Receives librdkafka's statistics, for the lag gauge
+struct LagContext {
+    lag: IntGaugeVec,
+}
+
+impl ClientContext for LagContext {
+    fn stats(&self, statistics: Statistics) {
+        // Partitions revoked since the last report drop out of the gauge
+        self.lag.reset();
+        for (name, topic) in &statistics.topics {
+            // Partition -1 is librdkafka's placeholder for unassigned messages
+            for partition in topic.partitions.values().filter(|partition| partition.partition >= 0) {
+                if partition.consumer_lag >= 0 {
+                    self.lag
+                        .with_label_values(&[name, &partition.partition.to_string()])
+                        .set(partition.consumer_lag);
+                }
+            }
+        }
+    }
+}
+
+impl ConsumerContext for LagContext {}
+
+// A message to produce, kept so a failed delivery can be retried
+struct Outgoing {
+    // `None` for a failed message dropped for want of a dead-letter topic
+    topic: Option<String>,
+    key: Option<Vec<u8>>,
+    payload: Vec<u8>,
+    headers: Vec<(&'static str, String)>,
+    outcome: &'static str,
+}
+
+#[derive(Serialize)]
+struct ModelInfo<'a> {
+    name: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    revision: Option<&'a str>,
+    dimensions: usize,
+}
+
+/// Consume, embed and produce until the consumer fails to start. Errors
+/// within a batch are retried or dead-lettered rather than returned.
+pub async fn run(state: Arc<AppState>, config: KafkaConfig) -> io::Result<()> {
+    consume(state, config).await.map_err(|e| io::Error::other(format!("Kafka: {:#}", e)))
+}
+
+async fn consume(state: Arc<AppState>, config: KafkaConfig) -> anyhow::Result<()> {
+    if let Some(requested) = config.dimensions {
+        if requested == 0 || requested > state.metadata.dimensions {
+            anyhow::bail!(
+                "SEMEMBED_KAFKA_DIMENSIONS must be between 1 and {}, got {}",
+                state.metadata.dimensions,
+                requested
+            );
+        }
+    }
+    let metrics = KafkaMetrics::register(&state.metrics.registry)?;
+
+    let mut client = ClientConfig::new();
+    client.set("bootstrap.servers", &config.brokers);
+    for (key, value) in &config.properties {
+        client.set(key, value);
+    }
+    let producer: FutureProducer = client.create()?;
+    let consumer: StreamConsumer<LagContext> = client
+        .clone()
+        .set("group.id", &config.group_id)
+        .set("enable.auto.commit", "false")
+        .set("statistics.interval.ms", STATS_INTERVAL.as_millis().to_string())
+        .create_with_context(LagContext {
+            lag: metrics.lag.clone(),
+        })?;
+    consumer.subscribe(&[&config.input_topic])?;
+    info!(
+        "Kafka: embedding {} -> {} as group {} (batches of up to {}, {:?} linger, dead letters: {})",
+        config.input_topic,
+        config.output_topic,
+        config.group_id,
+        config.batch_size,
+        config.linger,
+        config.dead_letter_topic.as_deref().unwrap_or("dropped")
+    );
+
+    let scheduling = Scheduling {
+        priority: Some(config.priority),
+        tenant: Tenant::system(),
+        timing: None,
+        access: None,
+        request_id: None,
+        trace: None,
+    };
+    state.health.watch("kafka");
+    loop {
+        // Wait for a first message, then gather more until the batch fills or lingers too long
+        let mut batch = vec![receive(&consumer).await];
+        let started = Instant::now();
+        let deadline = tokio::time::Instant::now() + config.linger;
+        while batch.len() < config.batch_size {
+            match tokio::time::timeout_at(deadline, consumer.recv()).await {
+                Ok(Ok(message)) => batch.push(message.detach()),
+                Ok(Err(e)) => warn!("Kafka: failed to receive a message: {}", e),
+                Err(_) => break,
+            }
+        }
+
+        let outgoing = embed_batch(&state, &config, &scheduling, &batch).await;
+        deliver(&producer, &outgoing).await;
+        for message in &outgoing {
+            metrics.messages.with_label_values(&[message.outcome]).inc();
+        }
+        match consumer.commit(&offsets(&batch)?, CommitMode::Async) {
+            Ok(()) => state.health.recovered("kafka"),
+            Err(e) => {
+                // Another consumer took the partition over and replays from its last commit
+                warn!("Kafka: failed to commit offsets: {}", e);
+                state.health.report("kafka", &e);
+            }
+        }
+        metrics.batch_duration.observe(started.elapsed().as_secs_f64());
+    }
+}
+
+async fn receive(consumer: &StreamConsumer<LagContext>) -> OwnedMessage {
+    loop {
+        match consumer.recv().await {
+            Ok(message) => return message.detach(),
+            Err(e) => {
+                warn!("Kafka: failed to receive a message: {}", e);
+                tokio::time::sleep(RETRY_DELAY).await;
+            }
+        }
+    }
+}
+
+// The next offset to read in every partition of the batch
+fn offsets(batch: &[OwnedMessage]) -> anyhow::Result<TopicPartitionList> {
+    let mut next: HashMap<(&str, i32), i64> = HashMap::new();
+    for message in batch {
+        let offset = next.entry((message.topic(), message.partition())).or_default();
+        *offset = (*offset).max(message.offset() + 1);
+    }
+    let mut list = TopicPartitionList::new();
+    for ((topic, partition), offset) in next {
+        list.add_partition_offset(topic, partition, Offset::Offset(offset))?;
+    }
+    Ok(list)
+}
+
+// Embed a batch into the messages to produce: one per input message, to the
+// output topic or the dead-letter topic. While the circuit is open the batch
+// is retried, since its messages aren't at fault.
+async fn embed_batch(
+    state: &AppState,
+    config: &KafkaConfig,
+    scheduling: &Scheduling,
+    batch: &[OwnedMessage],
+) -> Vec<Outgoing> {
+    let parsed: Vec<Result<Parsed, ItemError>> =
+        batch.iter().map(|message| parse(message, &config.text_field)).collect();
+    let texts: Vec<String> = parsed.iter().flatten().map(|(_, text)| text.clone()).collect();
+
+    let mut delay = RETRY_DELAY;
+    let mut embedded = loop {
+        if texts.is_empty() {
+            break Vec::new().into_iter();
+        }
+        let embedded = embed_texts(state, Some(InputKind::Passage), config.dimensions, scheduling, texts.clone()).await;
+        if !embedded.iter().any(|result| matches!(result, Err(error) if error.code == "circuit_open")) {
+            break embedded.into_iter();
+        }
+        warn!("Kafka: the model is being re-initialized; retrying the batch in {:?}", delay);
+        tokio::time::sleep(delay).await;
+        delay = (delay * 2).min(MAX_RETRY_DELAY);
+    };
+
+    let model = ModelInfo {
+        name: &state.model_name,
+        revision: state.model_revision.as_deref(),
+        dimensions: config.dimensions.unwrap_or(state.metadata.dimensions),
+    };
+    let model = serde_json::to_value(&model).unwrap_or_default();
+    batch
+        .iter()
+        .zip(parsed)
+        .map(|(message, parsed)| {
+            let result = parsed.and_then(|(object, _)| {
+                let embedding = embedded.next().expect("one result per text");
+                embedding.map(|(embedding, _)| (object, embedding))
+            });
+            match result {
+                Ok((mut object, embedding)) => {
+                    let embedding = EmbeddingData::encode(embedding, &EncodingFormat::Float, state.float_precision);
+                    object.insert("embedding".to_string(), serde_json::to_value(embedding).unwrap_or_default());
+                    object.insert("embedding_model".to_string(), model.clone());
+                    Outgoing {
+                        topic: Some(config.output_topic.clone()),
+                        key: message.key().map(<[u8]>::to_vec),
+                        payload: serde_json::to_vec(&object).unwrap_or_default(),
+                        headers: Vec::new(),
+                        outcome: "embedded",
+                    }
+                }
+                Err(error) => dead_letter(config, message, error),
+            }
+        })
+        .collect()
+}
+
+// A message's JSON object and the text at the configured field
+type Parsed = (serde_json::Map<String, serde_json::Value>, String);
+
+fn parse(message: &OwnedMessage, field: &[String]) -> Result<Parsed, ItemError> {
+    let object = match serde_json::from_slice(message.payload().unwrap_or_default()) {
+        Ok(serde_json::Value::Object(object)) => object,
+        _ => {
+            return Err(ItemError {
+                message: "The message is not a JSON object".to_string(),
+                code: "invalid_message",
+            })
+        }
+    };
+    // The path has at least one name
+    let text = field[1..]
+        .iter()
+        .fold(object.get(&field[0]), |value, name| value.and_then(|value| value.get(name)))
+        .and_then(serde_json::Value::as_str)
+        .ok_or_else(|| ItemError {
+            message: format!("The message has no string field `{}`", field.join(".")),
+            code: "missing_text",
+        })?
+        .to_string();
+    Ok((object, text))
+}
+
+// The message as it arrived, with why it failed, for the dead-letter topic
+fn dead_letter(config: &KafkaConfig, message: &OwnedMessage, error: ItemError) -> Outgoing {
+    warn!(
+        "Kafka: message at {}/{}@{} failed: {}",
+        message.topic(),
+        message.partition(),
+        message.offset(),
+        error.message
+    );
+    Outgoing {
+        topic: config.dead_letter_topic.clone(),
+        key: message.key().map(<[u8]>::to_vec),
+        payload: message.payload().unwrap_or_default().to_vec(),
+        headers: vec![
+            ("semembed-error-code", error.code.to_string()),
+            ("semembed-error", error.message),
+            ("semembed-source-topic", message.topic().to_string()),
+            ("semembed-source-partition", message.partition().to_string()),
+            ("semembed-source-offset", message.offset().to_string()),
+        ],
+        outcome: if config.dead_letter_topic.is_some() { "dead_lettered" } else { "dropped" },
+    }
+}
+
+// Produce every message, retrying failed deliveries until they succeed;
+// offsets are committed only after this returns
+async fn deliver(producer: &FutureProducer, outgoing: &[Outgoing]) {
+    let mut pending: Vec<(&str, &Outgoing)> =
+        outgoing.iter().filter_map(|message| Some((message.topic.as_deref()?, message))).collect();
+    let mut delay = RETRY_DELAY;
+    while !pending.is_empty() {
+        let sends = pending.iter().map(|&(topic, message)| {
+            let headers = message.headers.iter().fold(OwnedHeaders::new(), |headers, (key, value)| {
+                headers.insert(Header {
+                    key,
+                    value: Some(value),
+                })
+            });
+            let mut record = FutureRecord::to(topic).payload(&message.payload).headers(headers);
+            if let Some(key) = &message.key {
+                record = record.key(key);
+            }
+            producer.send(record, Duration::from_secs(5))
+        });
+        let results = join_all(sends).await;
+        let failed: Vec<(&str, &Outgoing)> = pending
+            .into_iter()
+            .zip(results)
+            .filter_map(|(pending, result)| match result {
+                Ok(_) => None,
+                Err((e, _)) => {
+                    warn!("Kafka: failed to produce to {}: {}", pending.0, e);
+                    Some(pending)
+                }
+            })
+            .collect();
+        if !failed.is_empty() {
+            warn!("Kafka: retrying {} message(s) in {:?}", failed.len(), delay);
+            tokio::time::sleep(delay).await;
+            delay = (delay * 2).min(MAX_RETRY_DELAY);
+        }
+        pending = failed;
+    }
+}
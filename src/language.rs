@@ -0,0 +1,351 @@
+//! Language detection for routing and reporting, by whatlang's trigram
+//! profiles, reported as ISO 639-1 codes.
+//!
+//! Only the first [`MAX_SCAN_CHARS`] characters are looked at, so an input
+//! of any length costs at most a few hundred microseconds. Text with too few
+//! letters to tell has no detected language rather than a wrong one; how sure
+//! a detection must be to act on it is up to the caller.
+
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use hmac_sha256::Hash;
+use prometheus::{CounterVec, Opts, Registry};
+use serde::Serialize;
+use whatlang::Lang;
+
+// Characters looked at; the opening of an input tells its language
+const MAX_SCAN_CHARS: usize = 1024;
+// Fewer letters than this are too short to tell
+const MIN_LETTERS: usize = 4;
+// Share of the entries dropped when the cache is full, as in the embedding cache
+const EVICT_FRACTION: usize = 8;
+
+/// Every language [`detect`] can report, as ISO 639-1 codes.
+pub(crate) const LANGUAGES: &[&str] = &[
+    "af", "ak", "am", "ar", "az", "be", "bg", "bn", "ca", "cs", "da", "de", "el", "en", "eo", "es", "et", "fa", "fi",
+    "fr", "gu", "he", "hi", "hr", "hu", "hy", "id", "it", "ja", "jv", "ka", "km", "kn", "ko", "la", "lt", "lv", "mk",
+    "ml", "mr", "my", "nb", "ne", "nl", "or", "pa", "pl", "pt", "ro", "ru", "si", "sk", "sl", "sn", "sr", "sv", "ta",
+    "te", "th", "tk", "tl", "tr", "uk", "ur", "uz", "vi", "yi", "zh", "zu",
+];
+
+/// A detected language and how sure the detector is of it, from 0 to 1.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub(crate) struct Detection {
+    pub language: &'static str,
+    pub confidence: f32,
+}
+
+/// The language of `text`, if it can be told.
+pub(crate) fn detect(text: &str) -> Option<Detection> {
+    let scanned = match text.char_indices().nth(MAX_SCAN_CHARS) {
+        Some((end, _)) => &text[..end],
+        None => text,
+    };
+    if scanned.chars().filter(|c| c.is_alphabetic()).count() < MIN_LETTERS {
+        return None;
+    }
+    let info = whatlang::detect(scanned)?;
+    Some(Detection {
+        language: code(info.lang()),
+        // Rounded to two places, as reported
+        confidence: (info.confidence() as f32 * 100.0).round() / 100.0,
+    })
+}
+
+// whatlang names languages by ISO 639-3; every one it knows has a 639-1 code
+fn code(lang: Lang) -> &'static str {
+    match lang {
+        Lang::Afr => "af",
+        Lang::Aka => "ak",
+        Lang::Amh => "am",
+        Lang::Ara => "ar",
+        Lang::Aze => "az",
+        Lang::Bel => "be",
+        Lang::Bul => "bg",
+        Lang::Ben => "bn",
+        Lang::Cat => "ca",
+        Lang::Ces => "cs",
+        Lang::Dan => "da",
+        Lang::Deu => "de",
+        Lang::Ell => "el",
+        Lang::Eng => "en",
+        Lang::Epo => "eo",
+        Lang::Spa => "es",
+        Lang::Est => "et",
+        Lang::Pes => "fa",
+        Lang::Fin => "fi",
+        Lang::Fra => "fr",
+        Lang::Guj => "gu",
+        Lang::Heb => "he",
+        Lang::Hin => "hi",
+        Lang::Hrv => "hr",
+        Lang::Hun => "hu",
+        Lang::Hye => "hy",
+        Lang::Ind => "id",
+        Lang::Ita => "it",
+        Lang::Jpn => "ja",
+        Lang::Jav => "jv",
+        Lang::Kat => "ka",
+        Lang::Khm => "km",
+        Lang::Kan => "kn",
+        Lang::Kor => "ko",
+        Lang::Lat => "la",
+        Lang::Lit => "lt",
+        Lang::Lav => "lv",
+        Lang::Mkd => "mk",
+        Lang::Mal => "ml",
+        Lang::Mar => "mr",
+        Lang::Mya => "my",
+        Lang::Nob => "nb",
+        Lang::Nep => "ne",
+        Lang::Nld => "nl",
+        Lang::Ori => "or",
+        Lang::Pan => "pa",
+        Lang::Pol => "pl",
+        Lang::Por => "pt",
+        Lang::Ron => "ro",
+        Lang::Rus => "ru",
+        Lang::Sin => "si",
+        Lang::Slk => "sk",
+        Lang::Slv => "sl",
+        Lang::Sna => "sn",
+        Lang::Srp => "sr",
+        Lang::Swe => "sv",
+        Lang::Tam => "ta",
+        Lang::Tel => "te",
+        Lang::Tha => "th",
+        Lang::Tuk => "tk",
+        Lang::Tgl => "tl",
+        Lang::Tur => "tr",
+        Lang::Ukr => "uk",
+        Lang::Urd => "ur",
+        Lang::Uzb => "uz",
+        Lang::Vie => "vi",
+        Lang::Yid => "yi",
+        Lang::Cmn => "zh",
+        Lang::Zul => "zu",
+    }
+}
+
+/// [`detect`] behind a bounded cache of recent texts, so inputs seen again
+/// (retries, templated queries) aren't scanned again, counting the inputs
+/// of each language it detects.
+pub(crate) struct Detector {
+    capacity: usize,
+    inner: Mutex<Inner>,
+    // Languages beyond the `top` most detected so far are counted as `other`
+    top: usize,
+    seen: Mutex<HashMap<&'static str, u64>>,
+    inputs: CounterVec,
+}
+
+#[derive(Default)]
+struct Inner {
+    entries: HashMap<[u8; 32], (Option<Detection>, u64)>,
+    // Logical clock for recency
+    tick: u64,
+}
+
+impl Detector {
+    /// A capacity of zero disables caching.
+    pub fn new(capacity: usize, top: usize, registry: &Registry) -> anyhow::Result<Self> {
+        let inputs = CounterVec::new(
+            Opts::new(
+                "semembed_input_languages_total",
+                "Inputs by detected language: the most common ones, other, or unknown when it can't be told",
+            ),
+            &["language"],
+        )?;
+        registry.register(Box::new(inputs.clone()))?;
+        Ok(Self {
+            capacity,
+            inner: Mutex::new(Inner::default()),
+            top,
+            seen: Mutex::new(HashMap::new()),
+            inputs,
+        })
+    }
+
+    /// The language of each text, from the cache where it has one.
+    pub fn detect_many(&self, texts: &[String]) -> Vec<Option<Detection>> {
+        if self.capacity == 0 {
+            let detections: Vec<Option<Detection>> = texts.iter().map(|text| detect(text)).collect();
+            self.count(&detections);
+            return detections;
+        }
+        let keys: Vec<[u8; 32]> = texts.iter().map(|text| Hash::hash(text.as_bytes())).collect();
+        let mut found: Vec<Option<Option<Detection>>> = {
+            let mut inner = self.inner();
+            inner.tick += 1;
+            let tick = inner.tick;
+            keys.iter()
+                .map(|key| {
+                    let entry = inner.entries.get_mut(key)?;
+                    entry.1 = tick;
+                    Some(entry.0)
+                })
+                .collect()
+        };
+        // Detected outside the lock
+        let detected: Vec<(usize, Option<Detection>)> = found
+            .iter()
+            .enumerate()
+            .filter(|(_, cached)| cached.is_none())
+            .map(|(index, _)| (index, detect(&texts[index])))
+            .collect();
+        if !detected.is_empty() {
+            let mut inner = self.inner();
+            let tick = inner.tick;
+            for &(index, detection) in &detected {
+                if inner.entries.len() >= self.capacity {
+                    evict(&mut inner.entries, (self.capacity / EVICT_FRACTION).max(1));
+                }
+                inner.entries.insert(keys[index], (detection, tick));
+                found[index] = Some(detection);
+            }
+        }
+        let detections: Vec<Option<Detection>> = found.into_iter().map(Option::flatten).collect();
+        self.count(&detections);
+        detections
+    }
+
+    // A language keeps its own label while it is among the `top` detected
+    // most often so far, so the series stay few whatever the traffic
+    fn count(&self, detections: &[Option<Detection>]) {
+        let mut seen = self.seen.lock().unwrap_or_else(PoisonError::into_inner);
+        for detection in detections {
+            let Some(Detection { language, .. }) = detection else {
+                self.inputs.with_label_values(&["unknown"]).inc();
+                continue;
+            };
+            let count = *seen.entry(language).and_modify(|count| *count += 1).or_insert(1);
+            let ahead = seen.values().filter(|&&other| other > count).count();
+            let label = if ahead < self.top { language } else { "other" };
+            self.inputs.with_label_values(&[label]).inc();
+        }
+    }
+
+    // Only plain bookkeeping lives under this lock, so a poisoned one is still consistent
+    fn inner(&self) -> MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+// Drop the `count` least recently used entries
+fn evict(entries: &mut HashMap<[u8; 32], (Option<Detection>, u64)>, count: usize) {
+    let count = count.min(entries.len());
+    if count == 0 {
+        return;
+    }
+    let mut ticks: Vec<u64> = entries.values().map(|&(_, tick)| tick).collect();
+    let (_, &mut cutoff, _) = ticks.select_nth_unstable(count - 1);
+    let mut dropped = 0;
+    entries.retain(|_, &mut (_, tick)| {
+        if dropped < count && tick <= cutoff {
+            dropped += 1;
+            return false;
+        }
+        true
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn language(text: &str) -> Option<&'static str> {
+        detect(text).map(|detection| detection.language)
+    }
+
+    #[test]
+    fn sentences_are_detected_by_their_language() {
+        assert_eq!(language("The quick brown fox jumps over the lazy dog near the river bank."), Some("en"));
+        assert_eq!(language("Der schnelle braune Fuchs springt über den faulen Hund am Flussufer."), Some("de"));
+        assert_eq!(language("Le renard brun saute par-dessus le chien paresseux près de la rivière."), Some("fr"));
+        assert_eq!(language("Быстрая коричневая лиса перепрыгивает через ленивую собаку."), Some("ru"));
+        assert_eq!(language("東京は日本の首都であり、世界で最も人口の多い都市の一つです。"), Some("ja"));
+        assert_eq!(language("서울은 대한민국의 수도이며 가장 큰 도시입니다."), Some("ko"));
+        let detection = detect("Der schnelle braune Fuchs springt über den faulen Hund am Flussufer.").unwrap();
+        assert!(detection.confidence > 0.0 && detection.confidence <= 1.0);
+        assert_eq!(detection.confidence, (detection.confidence * 100.0).round() / 100.0);
+    }
+
+    #[test]
+    fn short_and_letterless_inputs_have_no_language() {
+        assert_eq!(detect(""), None);
+        assert_eq!(detect("ok"), None);
+        assert_eq!(detect("a b c"), None);
+        assert_eq!(detect("12345 67890 !!!"), None);
+        assert_eq!(detect("🚀🔥😀👍🎉"), None);
+        assert_eq!(detect("🚀 🔥 😀 👍 🎉 ✨"), None);
+    }
+
+    #[test]
+    fn mixed_input_is_the_language_most_of_it_is_in() {
+        let english = "The weather today is lovely and we are going for a long walk in the park with the children.";
+        let mixed = format!("{} Guten Morgen.", english);
+        assert_eq!(language(&mixed), Some("en"));
+        let pure = detect(english).unwrap();
+        let diluted = detect(&format!("{} Der Hund schläft und die Katze spielt im Garten.", english)).unwrap();
+        assert_eq!(diluted.language, "en");
+        assert!(diluted.confidence <= pure.confidence);
+    }
+
+    #[test]
+    fn only_the_opening_of_long_input_is_scanned() {
+        let german = "Der Hund schläft und die Katze spielt im Garten. ".repeat(30);
+        let text = format!("{}{}", german, "the cat ".repeat(10_000));
+        assert_eq!(language(&text), Some("de"));
+    }
+
+    #[test]
+    fn every_reported_code_is_listed() {
+        for lang in whatlang::Lang::all() {
+            assert!(LANGUAGES.contains(&code(*lang)), "{:?}", lang);
+        }
+        assert_eq!(LANGUAGES.len(), whatlang::Lang::all().len());
+        assert!(LANGUAGES.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn detections_are_cached_and_counted_by_language() {
+        let registry = Registry::new();
+        let detector = Detector::new(2, 1, &registry).unwrap();
+        let texts: Vec<String> = [
+            "The quick brown fox jumps over the lazy dog near the river bank.",
+            "Der schnelle braune Fuchs springt über den faulen Hund am Flussufer.",
+            "🎉",
+        ]
+        .map(String::from)
+        .to_vec();
+        let detections = detector.detect_many(&texts);
+        let languages: Vec<Option<&str>> = detections.iter().map(|d| d.map(|d| d.language)).collect();
+        assert_eq!(languages, [Some("en"), Some("de"), None]);
+        assert!(detector.inner().entries.len() <= 2);
+        assert_eq!(detector.detect_many(&texts[..1]), detections[..1]);
+        detector.detect_many(&texts[..1]);
+
+        // Tied at first, German kept its label; with English ahead it counts as `other`
+        detector.detect_many(&texts[1..2]);
+        let count = |language: &str| detector.inputs.with_label_values(&[language]).get();
+        assert_eq!((count("en"), count("de"), count("other"), count("unknown")), (3.0, 1.0, 1.0, 1.0));
+    }
+
+    #[test]
+    fn a_zero_capacity_detector_caches_nothing() {
+        let detector = Detector::new(0, 10, &Registry::new()).unwrap();
+        let texts = vec!["The quick brown fox jumps over the lazy dog near the river bank.".to_string()];
+        assert_eq!(detector.detect_many(&texts)[0].map(|d| d.language), Some("en"));
+        assert!(detector.inner().entries.is_empty());
+    }
+
+    #[test]
+    fn eviction_drops_the_least_recently_used() {
+        let mut entries: HashMap<[u8; 32], (Option<Detection>, u64)> =
+            (0..8u8).map(|tick| ([tick; 32], (None, tick as u64))).collect();
+        evict(&mut entries, 3);
+        assert_eq!(entries.len(), 5);
+        assert!((0..3u8).all(|tick| !entries.contains_key(&[tick; 32])));
+    }
+}
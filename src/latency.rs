@@ -0,0 +1,124 @@
+use std::collections::VecDeque;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+/// Latencies of the requests that finished within the last `window`, for
+/// quantiles that follow the current load rather than the process lifetime.
+///
+/// At most `capacity` samples are kept, so under heavy load the window covers
+/// the most recent requests only.
+pub struct LatencyWindow {
+    samples: Mutex<VecDeque<(Instant, Duration)>>,
+    window: Duration,
+    capacity: usize,
+}
+
+impl LatencyWindow {
+    pub fn new(window: Duration, capacity: usize) -> Self {
+        Self {
+            samples: Mutex::new(VecDeque::with_capacity(capacity)),
+            window,
+            capacity: capacity.max(1),
+        }
+    }
+
+    /// Record a request that took `seconds`.
+    pub fn record(&self, seconds: f64) {
+        let now = Instant::now();
+        let mut samples = self.samples();
+        Self::expire(&mut samples, now, self.window);
+        if samples.len() == self.capacity {
+            samples.pop_front();
+        }
+        samples.push_back((now, Duration::from_secs_f64(seconds.max(0.0))));
+    }
+
+    /// Length of the window.
+    pub fn window(&self) -> Duration {
+        self.window
+    }
+
+    /// Requests that finished within the window.
+    pub fn len(&self) -> usize {
+        let mut samples = self.samples();
+        Self::expire(&mut samples, Instant::now(), self.window);
+        samples.len()
+    }
+
+    /// The given quantiles (0.0 to 1.0) of the window, or `None` for each when
+    /// no request finished within it.
+    pub fn quantiles<const N: usize>(&self, quantiles: [f64; N]) -> [Option<Duration>; N] {
+        self.quantiles_within(self.window, quantiles)
+    }
+
+    /// Like `quantiles`, over only the requests that finished within `recent`
+    /// (at most the window), for reacting to load faster than the window does.
+    pub fn quantiles_within<const N: usize>(&self, recent: Duration, quantiles: [f64; N]) -> [Option<Duration>; N] {
+        let now = Instant::now();
+        let mut latencies: Vec<Duration> = {
+            let mut samples = self.samples();
+            Self::expire(&mut samples, now, self.window);
+            samples
+                .iter()
+                .filter(|&&(finished, _)| now.duration_since(finished) <= recent)
+                .map(|&(_, latency)| latency)
+                .collect()
+        };
+        latencies.sort_unstable();
+        quantiles.map(|quantile| {
+            // Nearest rank
+            let rank = (quantile.clamp(0.0, 1.0) * latencies.len() as f64).ceil() as usize;
+            latencies.get(rank.saturating_sub(1)).copied()
+        })
+    }
+
+    fn expire(samples: &mut VecDeque<(Instant, Duration)>, now: Instant, window: Duration) {
+        while samples.front().is_some_and(|&(finished, _)| now.duration_since(finished) > window) {
+            samples.pop_front();
+        }
+    }
+
+    // Only plain bookkeeping lives under this lock, so a poisoned one is still consistent
+    fn samples(&self) -> MutexGuard<'_, VecDeque<(Instant, Duration)>> {
+        self.samples.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn millis(ms: u64) -> Option<Duration> {
+        Some(Duration::from_millis(ms))
+    }
+
+    #[test]
+    fn quantiles_use_the_nearest_rank() {
+        let window = LatencyWindow::new(Duration::from_secs(60), 100);
+        assert_eq!(window.quantiles([0.5, 0.95]), [None, None]);
+        (1..=100).for_each(|ms| window.record(ms as f64 / 1000.0));
+        assert_eq!(window.len(), 100);
+        assert_eq!(window.quantiles([0.0, 0.5, 0.95, 1.0]), [millis(1), millis(50), millis(95), millis(100)]);
+    }
+
+    #[test]
+    fn only_the_latest_samples_are_kept() {
+        let window = LatencyWindow::new(Duration::from_secs(60), 10);
+        (0..10).for_each(|_| window.record(5.0));
+        (0..10).for_each(|_| window.record(0.001));
+        assert_eq!(window.len(), 10);
+        assert_eq!(window.quantiles([1.0]), [millis(1)]);
+    }
+
+    #[test]
+    fn samples_age_out_of_the_window() {
+        let window = LatencyWindow::new(Duration::from_millis(100), 10);
+        window.record(1.0);
+        std::thread::sleep(Duration::from_millis(60));
+        window.record(0.002);
+        assert_eq!(window.quantiles_within(Duration::from_millis(30), [1.0]), [millis(2)]);
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(window.len(), 1);
+        assert_eq!(window.quantiles([1.0]), [millis(2)]);
+    }
+}
@@ -0,0 +1,15 @@
+//! Client-facing types for the semembed HTTP API.
+//!
+//! The service binary serializes these; clients can depend on this crate to
+//! deserialize them instead of re-declaring the shapes.
+
+pub mod metadata;
+pub mod sink;
+pub mod stats;
+
+pub use metadata::{Distance, ModelMetadata, Quantization};
+pub use sink::{Point, SinkError, VectorSink, Written};
+pub use stats::Stats;
+
+#[cfg(feature = "test-util")]
+pub mod test_util;
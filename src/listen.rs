@@ -0,0 +1,153 @@
+use std::net::{IpAddr, SocketAddr};
+
+use anyhow::{bail, Context};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::TcpListener;
+
+use crate::server::Listener;
+
+/// Parse a comma-separated list of listen addresses.
+///
+/// Each entry is either a bare IP (`0.0.0.0`, `::`, `[::1]`), which listens on
+/// `default_port`, or a full socket address (`127.0.0.1:9000`, `[::1]:9000`).
+pub fn parse_addrs(spec: &str, default_port: u16) -> anyhow::Result<Vec<SocketAddr>> {
+    let mut addrs = Vec::new();
+    for entry in spec.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let addr = if let Ok(addr) = entry.parse::<SocketAddr>() {
+            addr
+        } else {
+            let ip = entry
+                .strip_prefix('[')
+                .and_then(|e| e.strip_suffix(']'))
+                .unwrap_or(entry)
+                .parse::<IpAddr>()
+                .with_context(|| format!("invalid listen address {:?}", entry))?;
+            SocketAddr::new(ip, default_port)
+        };
+        if !addrs.contains(&addr) {
+            addrs.push(addr);
+        }
+    }
+    if addrs.is_empty() {
+        bail!("no listen addresses configured");
+    }
+    Ok(addrs)
+}
+
+/// Socket-level options applied to every listener before `bind()`.
+#[derive(Debug, Clone, Copy)]
+pub struct SocketOptions {
+    /// Set `SO_REUSEPORT` so several processes can share a port.
+    pub reuse_port: bool,
+    /// Accept queue length passed to `listen()`.
+    pub backlog: i32,
+}
+
+impl Default for SocketOptions {
+    fn default() -> Self {
+        Self {
+            reuse_port: false,
+            backlog: 1024,
+        }
+    }
+}
+
+/// Bind every address, failing on the first one that cannot be bound.
+///
+/// An unspecified IPv6 address (`::`) is bound dual-stack (accepting IPv4 as
+/// well) unless an unspecified IPv4 address on the same port is also in the
+/// list, in which case it is restricted to IPv6 so the two do not collide.
+pub fn bind_all(addrs: &[SocketAddr], options: SocketOptions) -> anyhow::Result<Vec<Listener>> {
+    addrs
+        .iter()
+        .map(|addr| {
+            let v4_wildcard_too = addrs
+                .iter()
+                .any(|other| other.is_ipv4() && other.ip().is_unspecified() && other.port() == addr.port());
+            let dual_stack = addr.is_ipv6() && addr.ip().is_unspecified() && !v4_wildcard_too;
+            bind(*addr, dual_stack, options)
+                .map(Listener::Tcp)
+                .with_context(|| format!("failed to bind {}", addr))
+        })
+        .collect()
+}
+
+fn bind(addr: SocketAddr, dual_stack: bool, options: SocketOptions) -> anyhow::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        // Not every platform allows clearing IPV6_V6ONLY; fall back to v6-only there
+        if let Err(e) = socket.set_only_v6(!dual_stack) {
+            tracing::warn!("Could not configure dual-stack on {}: {}", addr, e);
+        }
+    }
+    socket.set_reuse_address(true)?;
+    if options.reuse_port {
+        set_reuse_port(&socket)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(options.backlog)?;
+    Ok(TcpListener::from_std(socket.into())?)
+}
+
+#[cfg(all(
+    unix,
+    not(any(target_os = "solaris", target_os = "illumos", target_os = "cygwin"))
+))]
+fn set_reuse_port(socket: &Socket) -> anyhow::Result<()> {
+    socket.set_reuse_port(true).context("failed to set SO_REUSEPORT")
+}
+
+#[cfg(not(all(
+    unix,
+    not(any(target_os = "solaris", target_os = "illumos", target_os = "cygwin"))
+)))]
+fn set_reuse_port(_socket: &Socket) -> anyhow::Result<()> {
+    bail!("SEMEMBED_REUSEPORT is not supported on this platform")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(target_os = "linux")]
+    fn local_addr(listener: &Listener) -> SocketAddr {
+        match listener {
+            Listener::Tcp(listener) => listener.local_addr().unwrap(),
+            _ => unreachable!("bind_all only makes TCP listeners"),
+        }
+    }
+
+    #[test]
+    fn parses_bare_ips_and_socket_addresses() {
+        let addrs = parse_addrs("0.0.0.0, [::1], 127.0.0.1:9000, [::]:9001, 0.0.0.0", 8081).unwrap();
+        let expected: Vec<SocketAddr> = ["0.0.0.0:8081", "[::1]:8081", "127.0.0.1:9000", "[::]:9001"]
+            .iter()
+            .map(|addr| addr.parse().unwrap())
+            .collect();
+        assert_eq!(addrs, expected);
+        assert!(parse_addrs("localhost", 8081).is_err());
+        assert!(parse_addrs(" , ", 8081).is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn reuse_port_lets_two_listeners_share_a_port() {
+        let options = SocketOptions {
+            reuse_port: true,
+            ..SocketOptions::default()
+        };
+        let first = bind_all(&["127.0.0.1:0".parse().unwrap()], options).unwrap();
+        let addr = local_addr(&first[0]);
+        let second = bind_all(&[addr], options).unwrap();
+        assert_eq!(local_addr(&second[0]), addr);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn without_reuse_port_the_port_is_taken() {
+        let first = bind_all(&["127.0.0.1:0".parse().unwrap()], SocketOptions::default()).unwrap();
+        let addr = local_addr(&first[0]);
+        assert!(bind_all(&[addr], SocketOptions::default()).is_err());
+    }
+}
@@ -1,306 +1,5806 @@
 use axum::{
-    extract::State,
-    http::StatusCode,
-    response::IntoResponse,
+    extract::{multipart::MultipartRejection, DefaultBodyLimit, MatchedPath, Multipart, Path, Request, State},
+    http::{header, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
     routing::{get, post},
     Json, Router,
 };
-use fastembed::{EmbeddingModel, InitOptions, TextEmbedding};
-use prometheus::{Encoder, TextEncoder, Counter, Histogram, Registry, HistogramOpts, Opts};
+use base64::Engine;
+use fastembed::{InitOptions, TextEmbedding};
+use prometheus::core::Collector;
+use prometheus::{Encoder, TextEncoder, Counter, CounterVec, Gauge, HistogramVec, IntGauge, IntGaugeVec, Registry, HistogramOpts, Opts};
 use serde::{Deserialize, Serialize};
-use std::sync::{Arc, Mutex};
-use tokio::net::TcpListener;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Arc, OnceLock, PoisonError, RwLock};
+use std::time::{Duration, Instant};
+use tokio::task::JoinSet;
+use tokio_util::sync::CancellationToken;
 use tower_http::{cors::CorsLayer, trace::TraceLayer};
 use tracing::{info, error, warn};
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+use tracing_subscriber::{fmt::writer::BoxMakeWriter, layer::SubscriberExt, util::SubscriberInitExt};
+use utoipa::openapi::schema::{
+    AdditionalProperties, ArrayBuilder, ObjectBuilder, OneOfBuilder, Schema, SchemaType, Type,
+};
+use utoipa::openapi::RefOr;
+use utoipa::ToSchema;
+
+mod access_log;
+mod affinity;
+mod attribution;
+mod audit;
+mod azure;
+mod batch_dir;
+mod bench;
+mod bedrock;
+mod budget;
+mod bulk;
+mod cache;
+mod cacheable;
+mod centroid;
+mod chunk;
+mod classify;
+mod cluster;
+mod compare;
+mod config;
+mod csv_format;
+mod debug_log;
+mod decompress;
+mod dedup;
+mod drift;
+mod embedder;
+mod error;
+mod eval;
+mod extract;
+mod fetch;
+mod health;
+mod hub;
+mod idempotency;
+#[cfg(feature = "object-storage")]
+mod jobs;
+#[cfg(feature = "kafka")]
+mod kafka;
+mod language;
+mod latency;
+mod listen;
+mod markup;
+mod memory;
+mod model_limits;
+mod models;
+mod multivector;
+mod openapi;
+#[cfg(feature = "pgvector")]
+mod pgvector;
+mod preprocess;
+#[cfg(feature = "pprof")]
+mod profiling;
+mod push;
+mod queue;
+mod pool;
+mod preload;
+mod quota;
+mod redact;
+#[cfg(feature = "redis")]
+mod redis_worker;
+mod reload;
+#[cfg(feature = "sentry")]
+mod reporting;
+mod routing;
+mod server;
+mod shadow;
+mod shed;
+mod startup;
+mod statsd;
+mod similarity;
+mod sinks;
+mod systemd;
+mod timing;
+mod tokenizer;
+mod trace_context;
+mod upload;
+mod usage;
+mod vertex;
+
+use affinity::Placement;
+use attribution::UserLabels;
+use budget::BudgetConfig;
+use bulk::BulkLimits;
+use cache::{CacheMetrics, EmbeddingCache, WarmRecord};
+use chunk::Chunker;
+use classify::Scoring;
+use cluster::{KMeansConfig, Metric};
+use dedup::Representative;
+use embedder::{CircuitConfig, EmbedError, Embedder, EmbedderMetrics, TokenCount, TruncationSide};
+use error::{ApiError, ErrorReason, ErrorResponse};
+use extract::{ApiJson, Scheduling};
+use fetch::{FetchConfig, Fetcher};
+use health::{Component, Health, Status};
+use language::Detection;
+use latency::LatencyWindow;
+use memory::{MemoryGuard, MemoryLimit, MemoryMetrics};
+use models::{InputKind, ModelEcho, ModelResolver, ModelSpec, ResolvedModel};
+use multivector::{MultiVector, MultiVectorError};
+use semembed::stats::{CacheStats, LatencyStats, MemoryStats, ModelStats, PlacementStats, RequestStats};
+use semembed::{Distance, ModelMetadata, Stats};
+use preprocess::{Disallowed, Preprocess, PreprocessOverrides};
+use queue::{InferenceQueue, Priority, QueueMetrics, Tenant, TenantShares};
+use timing::Phase;
+use trace_context::TraceContext;
+use reload::{ConfigFile, LogFilter};
+use shed::LoadShedder;
+use upload::{FileKind, UploadForm, UploadLimits};
 
 // OpenAI-compatible request/response types
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, ToSchema)]
 struct EmbeddingRequest {
     input: InputType,
     model: Option<String>,
     #[serde(default)]
     encoding_format: EncodingFormat,
+    #[serde(default)]
+    preprocess: PreprocessOverrides,
+    /// Selects the query/passage prefix for models trained with one (e5).
+    input_type: Option<InputKind>,
+    /// Shorten embeddings to this many dimensions (truncate + re-normalize).
+    #[schema(minimum = 1)]
+    dimensions: Option<usize>,
+    /// Report per-input token counts and truncation (non-standard, off by default).
+    #[serde(default)]
+    return_token_details: bool,
+    /// OpenAI end-user attribution; never affects the embeddings.
+    user: Option<String>,
+    /// One vector per input (default), one per token from the late-interaction
+    /// model, or the dense model's token states before pooling (non-standard).
+    #[serde(default)]
+    output: OutputKind,
+    /// Report problems with individual inputs per item instead of failing the request.
+    #[serde(default)]
+    partial: bool,
+    /// Task instruction for instruct-tuned models, applied to queries; "" disables the default.
+    instruction: Option<String>,
+    /// Decimal places kept in float output, overriding SEMEMBED_FLOAT_PRECISION.
+    #[schema(maximum = 9)]
+    precision: Option<u32>,
+    /// Jina's task name, selecting the input type.
+    task: Option<String>,
+    /// Jina's truncation switch: `false` rejects inputs longer than the model's context.
+    truncate: Option<bool>,
+    /// Jina's late chunking, which isn't supported; accepted only so `false` works.
+    late_chunking: Option<bool>,
+    /// Adds each input's text to CSV output (non-standard; JSON responses ignore it).
+    #[serde(default)]
+    echo: bool,
+    /// Accept `{id, text}` inputs sharing an id (non-standard).
+    #[serde(default)]
+    allow_duplicate_ids: bool,
+    /// Which side of inputs too long for the model is cut, overriding
+    /// SEMEMBED_TRUNCATION_SIDE (non-standard).
+    truncation_side: Option<TruncationSide>,
+    /// With language routing, send each input to its own language's model
+    /// instead of the whole batch to the majority language's (non-standard).
+    #[serde(default)]
+    split: bool,
+    /// Report each input's detected language and the detector's confidence (non-standard).
+    #[serde(default)]
+    detect_language: bool,
+    /// Element type of the `base64_matrix` output (non-standard).
+    dtype: Option<MatrixDtype>,
 }
 
-#[derive(Debug, Deserialize)]
-#[serde(untagged)]
+#[derive(Debug, Deserialize, Default, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+enum OutputKind {
+    #[default]
+    Dense,
+    MultiVector,
+    Tokens,
+}
+
+#[derive(Debug)]
 enum InputType {
     Single(String),
     Batch(Vec<String>),
+    // `[{"id": ..., "text": ..., "metadata": ...}]`, each result carrying its
+    // input's id and metadata
+    Records(Vec<InputRecord>),
 }
 
-#[derive(Debug, Deserialize, Default)]
-#[serde(rename_all = "lowercase")]
+#[derive(Debug)]
+struct InputRecord {
+    text: String,
+    echo: Echo,
+}
+
+/// What an `{id, text}` input gets back in its result, untouched. None of
+/// it reaches the model, the cache or usage.
+#[derive(Debug)]
+struct Echo {
+    // A string or a number
+    id: serde_json::Value,
+    metadata: Option<serde_json::Value>,
+}
+
+impl InputType {
+    /// The texts in order, and what `{id, text}` inputs echo.
+    fn into_texts(self) -> (Vec<String>, Option<Vec<Echo>>) {
+        match self {
+            InputType::Single(text) => (vec![text], None),
+            InputType::Batch(texts) => (texts, None),
+            InputType::Records(records) => {
+                let (texts, echoes) = records.into_iter().map(|record| (record.text, record.echo)).unzip();
+                (texts, Some(echoes))
+            }
+        }
+    }
+}
+
+// One `{id, text}` input, with optional metadata; anything else in the
+// object is an error
+fn input_record(index: usize, item: serde_json::Value) -> Result<InputRecord, String> {
+    let serde_json::Value::Object(mut fields) = item else {
+        return Err(format!(
+            "input[{}] must be an object like input[0] (got {})",
+            index,
+            json_type_name(&item)
+        ));
+    };
+    let id = match fields.remove("id") {
+        Some(id @ (serde_json::Value::String(_) | serde_json::Value::Number(_))) => id,
+        Some(other) => {
+            return Err(format!("input[{}].id must be a string or a number (got {})", index, json_type_name(&other)))
+        }
+        None => return Err(format!("input[{}] is missing id", index)),
+    };
+    let text = match fields.remove("text") {
+        Some(serde_json::Value::String(text)) => text,
+        Some(other) => return Err(format!("input[{}].text must be a string (got {})", index, json_type_name(&other))),
+        None => return Err(format!("input[{}] is missing text", index)),
+    };
+    let metadata = fields.remove("metadata");
+    if let Some(field) = fields.keys().next() {
+        return Err(format!("input[{}] has an unknown field `{}`", index, field));
+    }
+    Ok(InputRecord {
+        text,
+        echo: Echo { id, metadata },
+    })
+}
+
+impl<'de> Deserialize<'de> for InputType {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        use serde::de::Error;
+
+        // Inspecting the value rather than deriving an untagged enum, whose only
+        // error is "data did not match any variant", so the message can say
+        // exactly what is wrong
+        match serde_json::Value::deserialize(deserializer)? {
+            serde_json::Value::String(text) => Ok(InputType::Single(text)),
+            serde_json::Value::Array(items) if items.is_empty() => {
+                Err(D::Error::custom("input array may not be empty"))
+            }
+            serde_json::Value::Array(items) if items[0].is_object() => items
+                .into_iter()
+                .enumerate()
+                .map(|(index, item)| input_record(index, item).map_err(D::Error::custom))
+                .collect::<Result<_, _>>()
+                .map(InputType::Records),
+            serde_json::Value::Array(items) => items
+                .into_iter()
+                .enumerate()
+                .map(|(index, item)| match item {
+                    serde_json::Value::String(text) => Ok(text),
+                    other => Err(D::Error::custom(format!(
+                        "input[{}] must be a string (got {})",
+                        index,
+                        json_type_name(&other)
+                    ))),
+                })
+                .collect::<Result<_, _>>()
+                .map(InputType::Batch),
+            other => Err(D::Error::custom(format!(
+                "input must be a string, an array of strings or an array of {{id, text}} objects (got {})",
+                json_type_name(&other)
+            ))),
+        }
+    }
+}
+
+// The shapes the Deserialize impl above accepts
+impl utoipa::PartialSchema for InputType {
+    fn schema() -> RefOr<Schema> {
+        let text = || ObjectBuilder::new().schema_type(Type::String);
+        let record = ObjectBuilder::new()
+            .property("id", ObjectBuilder::new().schema_type(SchemaType::from_iter([Type::String, Type::Number])))
+            .property("text", text())
+            .property(
+                "metadata",
+                ObjectBuilder::new()
+                    .schema_type(SchemaType::AnyValue)
+                    .description(Some("Any JSON value, echoed untouched in the input's item")),
+            )
+            .required("id")
+            .required("text")
+            .additional_properties(Some(AdditionalProperties::FreeForm(false)));
+        OneOfBuilder::new()
+            .item(text())
+            .item(ArrayBuilder::new().items(text()).min_items(Some(1)))
+            .item(ArrayBuilder::new().items(record).min_items(Some(1)))
+            .description(Some(
+                "A text, an array of texts, or an array of `{id, text}` objects whose ids are echoed in the results",
+            ))
+            .into()
+    }
+}
+
+impl ToSchema for InputType {}
+
+fn json_type_name(value: &serde_json::Value) -> &'static str {
+    match value {
+        serde_json::Value::Null => "null",
+        serde_json::Value::Bool(_) => "a boolean",
+        serde_json::Value::Number(_) => "a number",
+        serde_json::Value::String(_) => "a string",
+        serde_json::Value::Array(_) => "an array",
+        serde_json::Value::Object(_) => "an object",
+    }
+}
+
+#[derive(Debug, Deserialize, Default, ToSchema)]
+#[serde(rename_all = "snake_case")]
 enum EncodingFormat {
     #[default]
     Float,
     Base64,
+    /// The whole batch as one matrix (non-standard, /v1/embeddings only).
+    Base64Matrix,
 }
 
-#[derive(Debug, Serialize)]
+impl EncodingFormat {
+    // Other endpoints have no place for a batch-wide matrix
+    fn check_per_item(&self) -> Result<(), ApiError> {
+        if matches!(self, Self::Base64Matrix) {
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "invalid_request_error",
+                "encoding_format \"base64_matrix\" is only supported by /v1/embeddings",
+            )
+            .param("encoding_format")
+            .reason("invalid_encoding_format"));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize, ToSchema)]
 struct EmbeddingResponse {
     object: String,
-    data: Vec<EmbeddingObject>,
+    data: Vec<EmbeddingItem>,
     model: String,
     usage: Usage,
+    /// Non-standard extension: the model that actually served the request.
+    semembed_model: ServedModel,
+    /// Non-standard extension: preprocessing steps applied to every input, in order.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    semembed_preprocess: Vec<&'static str>,
+    /// Non-standard extension, only present with `partial`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    semembed_summary: Option<Summary>,
+    /// Non-standard extension: the task instruction prepended to every input.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    semembed_instruction: Option<String>,
+    /// Non-standard extension, only present with `encoding_format: "base64_matrix"`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    semembed_matrix: Option<EmbeddingMatrix>,
 }
 
-#[derive(Debug, Serialize)]
-struct EmbeddingObject {
+#[derive(Debug, Serialize, ToSchema)]
+struct Summary {
+    succeeded: usize,
+    failed: usize,
+}
+
+// An input's embedding or, with `partial`, why it has none
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(untagged)]
+enum EmbeddingItem {
+    Embedding(EmbeddingObject),
+    Failed(FailedItem),
+}
+
+// The `object` of each kind of item, which tells them apart in the schema
+fn embedding_object() -> ObjectBuilder {
+    ObjectBuilder::new().schema_type(Type::String).enum_values(Some(["embedding"]))
+}
+
+fn error_object() -> ObjectBuilder {
+    ObjectBuilder::new().schema_type(Type::String).enum_values(Some(["error"]))
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+struct FailedItem {
+    #[schema(schema_with = error_object)]
     object: String,
-    embedding: Vec<f32>,
     index: usize,
+    /// The input's id and metadata, for `{id, text}` inputs.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metadata: Option<serde_json::Value>,
+    error: ItemError,
 }
 
-#[derive(Debug, Serialize)]
-struct Usage {
-    prompt_tokens: usize,
-    total_tokens: usize,
+#[derive(Debug, Serialize, ToSchema)]
+struct ServedModel {
+    id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    revision: Option<String>,
 }
 
-#[derive(Debug, Serialize)]
-struct ErrorResponse {
-    error: ErrorDetail,
+#[derive(Debug, Serialize, ToSchema)]
+struct EmbeddingObject {
+    #[schema(schema_with = embedding_object)]
+    object: String,
+    /// Left out with `base64_matrix`, which has it as a row of the matrix.
+    #[serde(skip_serializing_if = "EmbeddingData::in_matrix")]
+    embedding: EmbeddingData,
+    index: usize,
+    /// The input's id and metadata, for `{id, text}` inputs.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metadata: Option<serde_json::Value>,
+    /// Non-standard: the model that embedded the input, when it was routed by language.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    model: Option<String>,
+    /// Non-standard, only present with `detect_language`.
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = DetectedLanguage)]
+    detected: Option<DetectedLanguage>,
+    /// Non-standard extensions, only present with `return_token_details`
+    /// (`tokens` is always present for multi-vector output).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tokens: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    truncated: Option<bool>,
+    /// With `return_token_details`, how many tokens a truncated input lost and
+    /// from which side.
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Truncation)]
+    truncation: Option<Truncation>,
+    /// Non-standard: `[vectors, dimensions]` of a multi-vector or token embedding.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<Vec<usize>>, min_items = 2, max_items = 2)]
+    shape: Option<[usize; 2]>,
+    /// Non-standard, only present with `output: "tokens"`.
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = TokenOutput)]
+    token_output: Option<TokenOutput>,
 }
 
-#[derive(Debug, Serialize)]
-struct ErrorDetail {
-    message: String,
-    #[serde(rename = "type")]
-    error_type: String,
+// Flattened into an embedding only when there is one, so its fields are all
+// optional there
+#[derive(Debug, Serialize, ToSchema)]
+struct Truncation {
+    #[schema(required = false)]
+    truncated_tokens: usize,
+    #[schema(required = false)]
+    truncation_side: TruncationSide,
 }
 
-#[derive(Debug, Serialize)]
-struct HealthResponse {
-    status: String,
-    model: String,
+impl Truncation {
+    // What `return_token_details` reports for an input's count; nothing for an
+    // input that wasn't cut
+    fn details(count: &TokenCount, return_token_details: bool) -> Option<Self> {
+        (return_token_details && count.truncated).then_some(Self {
+            truncated_tokens: count.dropped,
+            truncation_side: count.side,
+        })
+    }
 }
 
-#[derive(Debug, Serialize)]
-struct ModelsResponse {
-    models: Vec<String>,
+// What `detect_language` reports for an input; both null when its language
+// can't be told
+#[derive(Debug, Serialize, ToSchema)]
+struct DetectedLanguage {
+    language: Option<&'static str>,
+    confidence: Option<f32>,
 }
 
-// Application state
-struct AppState {
-    embedder: Mutex<TextEmbedding>,
-    model_name: String,
-    metrics: Arc<Metrics>,
+impl DetectedLanguage {
+    // For input `index`, when languages were asked for
+    fn of(languages: Option<&[Option<Detection>]>, index: usize) -> Option<Self> {
+        let detection = languages?.get(index).copied().flatten();
+        Some(Self {
+            language: detection.map(|detection| detection.language),
+            confidence: detection.map(|detection| detection.confidence),
+        })
+    }
 }
 
-// Prometheus metrics
-struct Metrics {
-    registry: Registry,
-    requests_total: Counter,
-    request_duration: Histogram,
-    tokens_processed: Counter,
-    errors_total: Counter,
+// What `output: "tokens"` returns besides the `[tokens, dimensions]` matrix;
+// like `Truncation`, only flattened into an embedding when asked for
+#[derive(Debug, Serialize, ToSchema)]
+struct TokenOutput {
+    #[schema(required = false)]
+    token_ids: Vec<u32>,
+    #[schema(required = false)]
+    token_strings: Vec<String>,
+    /// The dense embedding of the same input: the rows of `embedding` pooled
+    /// this way, then normalized.
+    #[schema(required = false)]
+    pooled: EmbeddingData,
+    #[schema(required = false)]
+    pooling: &'static str,
 }
 
-impl Metrics {
-    fn new() -> anyhow::Result<Self> {
-        let registry = Registry::new();
-
-        let requests_total = Counter::with_opts(Opts::new(
-            "semembed_requests_total",
-            "Total number of embedding requests"
-        ))?;
-        registry.register(Box::new(requests_total.clone()))?;
+// Most decimal places a client can ask for; f32 carries no more than that
+const MAX_FLOAT_PRECISION: u32 = 9;
 
-        let request_duration = Histogram::with_opts(HistogramOpts::new(
-            "semembed_request_duration_seconds",
-            "Request duration in seconds"
-        ))?;
-        registry.register(Box::new(request_duration.clone()))?;
+// An embedding as a JSON array of floats, or base64 of its little-endian f32
+// bytes (OpenAI's `encoding_format: "base64"`)
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+enum EmbeddingData {
+    Float(Floats),
+    Matrix(Vec<Floats>),
+    Base64(String),
+    // Moved into the response's `base64_matrix`
+    InMatrix,
+}
 
-        let tokens_processed = Counter::with_opts(Opts::new(
-            "semembed_tokens_processed_total",
-            "Total number of tokens processed"
-        ))?;
-        registry.register(Box::new(tokens_processed.clone()))?;
+impl EmbeddingData {
+    // Precision only shortens float output; base64 always carries the exact
+    // bits. `base64_matrix` rows are kept whole until they are gathered.
+    fn encode(embedding: Vec<f32>, format: &EncodingFormat, precision: Option<u32>) -> Self {
+        match format {
+            EncodingFormat::Float => Self::Float(Floats(embedding, precision)),
+            EncodingFormat::Base64 => Self::Base64(pack(embedding.iter())),
+            EncodingFormat::Base64Matrix => Self::Float(Floats(embedding, None)),
+        }
+    }
 
-        let errors_total = Counter::with_opts(Opts::new(
-            "semembed_errors_total",
-            "Total number of errors"
-        ))?;
-        registry.register(Box::new(errors_total.clone()))?;
+    // Multi-vector base64 is the matrix packed row by row
+    fn encode_matrix(matrix: Vec<Vec<f32>>, format: &EncodingFormat, precision: Option<u32>) -> Self {
+        match format {
+            EncodingFormat::Float => Self::Matrix(matrix.into_iter().map(|row| Floats(row, precision)).collect()),
+            EncodingFormat::Base64 | EncodingFormat::Base64Matrix => Self::Base64(pack(matrix.iter().flatten())),
+        }
+    }
 
-        Ok(Self {
-            registry,
-            requests_total,
-            request_duration,
-            tokens_processed,
-            errors_total,
-        })
+    fn in_matrix(&self) -> bool {
+        matches!(self, Self::InMatrix)
     }
 }
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    // Initialize tracing
-    tracing_subscriber::registry()
-        .with(
-            tracing_subscriber::EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| "semembed=info,tower_http=debug".into()),
-        )
-        .with(tracing_subscriber::fmt::layer())
-        .init();
+// What clients see; `InMatrix` is never serialized
+impl utoipa::PartialSchema for EmbeddingData {
+    fn schema() -> RefOr<Schema> {
+        let floats = || ArrayBuilder::new().items(ObjectBuilder::new().schema_type(Type::Number));
+        OneOfBuilder::new()
+            .item(floats())
+            .item(
+                ArrayBuilder::new()
+                    .items(floats())
+                    .description(Some("Multi-vector and token output: one vector per token")),
+            )
+            .item(
+                ObjectBuilder::new()
+                    .schema_type(Type::String)
+                    .content_encoding("base64")
+                    .description(Some("The little-endian f32 values, with `encoding_format: \"base64\"`")),
+            )
+            .into()
+    }
+}
 
-    info!("Starting semembed service");
+impl ToSchema for EmbeddingData {}
 
-    // Get configuration from environment
-    let model_name = std::env::var("SEMEMBED_MODEL")
-        .unwrap_or_else(|_| "BAAI/bge-small-en-v1.5".to_string());
-    let port = std::env::var("SEMEMBED_PORT")
-        .unwrap_or_else(|_| "8081".to_string())
-        .parse::<u16>()?;
+// `encoding_format: "base64_matrix"`: every embedding of the batch in one
+// row-major matrix, row `i` for input `i`, instead of one per item
+#[derive(Debug, Serialize, ToSchema)]
+struct EmbeddingMatrix {
+    /// Base64 of the little-endian values.
+    #[schema(content_encoding = "base64")]
+    data: String,
+    /// `[inputs, dimensions]`.
+    #[schema(value_type = Vec<usize>, min_items = 2, max_items = 2)]
+    shape: [usize; 2],
+    dtype: MatrixDtype,
+    /// With int8, what a stored integer is multiplied by to get the value back.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    scale: Option<f32>,
+    /// With `partial`, the rows left as zeros because their input failed.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    failed: Vec<usize>,
+}
 
-    info!("Loading embedding model: {}", model_name);
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+enum MatrixDtype {
+    #[default]
+    Float32,
+    Float16,
+    /// Symmetric, with one scale for the whole matrix.
+    Int8,
+}
 
-    // Initialize fastembed model
-    let model = match model_name.as_str() {
-        "BAAI/bge-small-en-v1.5" => EmbeddingModel::BGESmallENV15,
-        "BAAI/bge-base-en-v1.5" => EmbeddingModel::BGEBaseENV15,
-        "sentence-transformers/all-MiniLM-L6-v2" => EmbeddingModel::AllMiniLML6V2,
-        _ => {
-            warn!("Unknown model {}, defaulting to BGESmallENV15", model_name);
-            EmbeddingModel::BGESmallENV15
+impl EmbeddingMatrix {
+    // Move each item's embedding into the matrix. `dimensions` is the row
+    // length when no input succeeded.
+    fn gather(data: &mut [EmbeddingItem], dtype: MatrixDtype, dimensions: usize) -> Result<Self, ApiError> {
+        let rows: Vec<Option<Vec<f32>>> = data
+            .iter_mut()
+            .map(|item| match item {
+                EmbeddingItem::Embedding(object) => {
+                    match std::mem::replace(&mut object.embedding, EmbeddingData::InMatrix) {
+                        EmbeddingData::Float(Floats(row, _)) => Some(row),
+                        _ => None,
+                    }
+                }
+                EmbeddingItem::Failed(_) => None,
+            })
+            .collect();
+        let width = rows.iter().flatten().next().map_or(dimensions, Vec::len);
+        if rows.iter().flatten().any(|row| row.len() != width) {
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "invalid_request_error",
+                "encoding_format \"base64_matrix\" needs every embedding to have the same dimensions, but the inputs \
+                 were routed to models of different sizes; set dimensions or split the request",
+            )
+            .param("encoding_format")
+            .reason("invalid_encoding_format"));
         }
-    };
+        let failed: Vec<usize> = (0..rows.len()).filter(|&index| rows[index].is_none()).collect();
+        let zeros = vec![0.0; width];
+        let values = rows.iter().flat_map(|row| row.as_deref().unwrap_or(&zeros));
+        let (bytes, scale): (Vec<u8>, Option<f32>) = match dtype {
+            MatrixDtype::Float32 => (values.flat_map(|value| value.to_le_bytes()).collect(), None),
+            MatrixDtype::Float16 => {
+                (values.flat_map(|&value| half::f16::from_f32(value).to_le_bytes()).collect(), None)
+            }
+            MatrixDtype::Int8 => {
+                let largest = values.clone().fold(0f32, |largest, value| largest.max(value.abs()));
+                let scale = if largest > 0.0 { largest / 127.0 } else { 1.0 };
+                let quantized = values.map(|value| (value / scale).round().clamp(-127.0, 127.0) as i8 as u8);
+                (quantized.collect(), Some(scale))
+            }
+        };
+        Ok(Self {
+            data: base64::engine::general_purpose::STANDARD.encode(bytes),
+            shape: [rows.len(), width],
+            dtype,
+            scale,
+            failed,
+        })
+    }
+}
 
-    // fastembed v5 API - InitOptions builder pattern
-    let embedder = TextEmbedding::try_new(
-        InitOptions::new(model).with_show_download_progress(true)
-    )?;
+// A JSON array of floats, rounded to a number of decimal places if one is set.
+// Without one, every component is written at full precision as before.
+#[derive(Debug)]
+struct Floats(Vec<f32>, Option<u32>);
 
-    info!("Model loaded successfully");
+impl Floats {
+    // The components as they are written out
+    fn rounded(&self) -> impl Iterator<Item = f32> + '_ {
+        // Round in f64 so the scaling adds no error of its own; the nearest f32
+        // then prints with at most `precision` decimals
+        let scale = self.1.map(|precision| 10f64.powi(precision as i32));
+        self.0.iter().map(move |&value| match scale {
+            Some(scale) => ((f64::from(value) * scale).round() / scale) as f32,
+            None => value,
+        })
+    }
+}
 
-    // Initialize metrics
-    let metrics = Arc::new(Metrics::new()?);
+impl Serialize for Floats {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        if self.1.is_none() {
+            return self.0.serialize(serializer);
+        }
+        serializer.collect_seq(self.rounded())
+    }
+}
 
-    // Create shared state
-    let state = Arc::new(AppState {
-        embedder: Mutex::new(embedder),
-        model_name: model_name.clone(),
-        metrics: metrics.clone(),
-    });
+fn pack<'a>(values: impl Iterator<Item = &'a f32>) -> String {
+    let bytes: Vec<u8> = values.flat_map(|value| value.to_le_bytes()).collect();
+    base64::engine::general_purpose::STANDARD.encode(bytes)
+}
 
-    // Build router
-    let app = Router::new()
-        .route("/v1/embeddings", post(create_embeddings))
-        .route("/health", get(health_check))
-        .route("/models", get(list_models))
-        .route("/metrics", get(metrics_handler))
-        .layer(CorsLayer::permissive())
-        .layer(TraceLayer::new_for_http())
-        .with_state(state);
+#[derive(Debug, Serialize, ToSchema)]
+struct Usage {
+    prompt_tokens: usize,
+    total_tokens: usize,
+    /// Non-standard: floats returned by multi-vector output (tokens × dimensions).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    vector_values: Option<usize>,
+}
 
-    // Start server
-    let addr = format!("0.0.0.0:{}", port);
-    info!("Listening on {}", addr);
+// /v1/embeddings/url: embed documents the server fetches itself
+#[derive(Debug, Deserialize)]
+struct UrlEmbeddingRequest {
+    input_url: UrlInput,
+    model: Option<String>,
+    #[serde(default)]
+    encoding_format: EncodingFormat,
+    #[serde(default)]
+    preprocess: PreprocessOverrides,
+    // Fetched pages are documents, so this defaults to passage
+    input_type: Option<InputKind>,
+}
 
-    let listener = TcpListener::bind(&addr).await?;
-    axum::serve(listener, app).await?;
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum UrlInput {
+    Single(String),
+    Batch(Vec<String>),
+}
 
-    Ok(())
+#[derive(Debug, Serialize)]
+struct UrlEmbeddingResponse {
+    object: String,
+    data: Vec<UrlEmbeddings>,
+    model: String,
+    usage: Usage,
+    semembed_model: ServedModel,
 }
 
-async fn create_embeddings(
-    State(state): State<Arc<AppState>>,
-    Json(req): Json<EmbeddingRequest>,
-) -> Result<Json<EmbeddingResponse>, (StatusCode, Json<ErrorResponse>)> {
-    let timer = state.metrics.request_duration.start_timer();
-    state.metrics.requests_total.inc();
+// Chunks of one URL, or why it could not be embedded
+#[derive(Debug, Serialize)]
+struct UrlEmbeddings {
+    index: usize,
+    url: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    chunks: Vec<EmbeddingObject>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<ItemError>,
+}
 
-    // Extract texts from input
-    let texts: Vec<String> = match req.input {
-        InputType::Single(text) => vec![text],
-        InputType::Batch(texts) => texts,
-    };
+// Why one input (or URL) could not be embedded
+#[derive(Debug, Clone, Serialize, ToSchema)]
+struct ItemError {
+    message: String,
+    code: &'static str,
+}
 
-    if texts.is_empty() {
-        state.metrics.errors_total.inc();
-        return Err((
-            StatusCode::BAD_REQUEST,
-            Json(ErrorResponse {
-                error: ErrorDetail {
-                    message: "Input cannot be empty".to_string(),
-                    error_type: "invalid_request_error".to_string(),
-                },
-            }),
-        ));
+impl ItemError {
+    fn inference(e: &EmbedError) -> Self {
+        Self {
+            message: format!("Failed to generate embeddings: {}", e),
+            code: "inference_failed",
+        }
     }
+}
 
-    // Count tokens (approximate - count words for now)
-    let token_count: usize = texts.iter().map(|t| t.split_whitespace().count()).sum();
-    state.metrics.tokens_processed.inc_by(token_count as f64);
+impl From<ApiError> for ItemError {
+    fn from(e: ApiError) -> Self {
+        let (message, code) = e.into_item();
+        Self { message, code }
+    }
+}
 
-    // Generate embeddings (lock the mutex for mutable access)
-    let text_refs: Vec<&str> = texts.iter().map(|s| s.as_str()).collect();
-    let embeddings = {
-        let mut embedder = state.embedder.lock().unwrap();
-        match embedder.embed(text_refs, None) {
-            Ok(emb) => emb,
-            Err(e) => {
-                error!("Failed to generate embeddings: {}", e);
-                state.metrics.errors_total.inc();
-                return Err((
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    Json(ErrorResponse {
-                        error: ErrorDetail {
-                            message: format!("Failed to generate embeddings: {}", e),
-                            error_type: "internal_error".to_string(),
-                        },
-                    }),
-                ));
-            }
-        }
-    };
+// /v1/embeddings/file: embeddings of uploaded files, one item per chunk
+#[derive(Debug, Serialize)]
+struct FileEmbeddingResponse {
+    object: String,
+    data: Vec<FileEmbeddingObject>,
+    model: String,
+    usage: Usage,
+    semembed_model: ServedModel,
+}
 
-    // Build response
-    let data: Vec<EmbeddingObject> = embeddings
-        .into_iter()
-        .enumerate()
-        .map(|(index, embedding)| EmbeddingObject {
-            object: "embedding".to_string(),
-            embedding,
-            index,
-        })
-        .collect();
+// An embedding and where its text came from
+#[derive(Debug, Serialize)]
+struct FileEmbeddingObject {
+    #[serde(flatten)]
+    embedding: EmbeddingObject,
+    filename: String,
+    // 1-based data row, when a CSV column was embedded
+    #[serde(skip_serializing_if = "Option::is_none")]
+    row: Option<usize>,
+    // Position of the chunk within its file (or row)
+    chunk: usize,
+}
 
-    let response = EmbeddingResponse {
-        object: "list".to_string(),
-        data,
-        model: state.model_name.clone(),
-        usage: Usage {
-            prompt_tokens: token_count,
-            total_tokens: token_count,
-        },
-    };
+// /v1/cluster: k-means over the embeddings of the submitted texts
+#[derive(Debug, Deserialize)]
+struct ClusterRequest {
+    input: Vec<String>,
+    model: Option<String>,
+    // A number of clusters, or "auto" to pick one by silhouette
+    #[serde(default)]
+    k: ClusterCount,
+    #[serde(default)]
+    metric: Metric,
+    max_iter: Option<usize>,
+    // Seeds k-means++ initialization; the same seed gives the same clusters
+    #[serde(default)]
+    seed: u64,
+    // Representative texts returned per cluster
+    examples: Option<usize>,
+    #[serde(default)]
+    preprocess: PreprocessOverrides,
+    input_type: Option<InputKind>,
+}
 
-    timer.observe_duration();
-    Ok(Json(response))
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum ClusterCount {
+    Fixed(usize),
+    Auto(AutoCount),
 }
 
-async fn health_check(State(state): State<Arc<AppState>>) -> impl IntoResponse {
-    Json(HealthResponse {
-        status: "healthy".to_string(),
-        model: state.model_name.clone(),
-    })
+impl Default for ClusterCount {
+    fn default() -> Self {
+        Self::Auto(AutoCount::Auto)
+    }
 }
 
-async fn list_models(State(state): State<Arc<AppState>>) -> impl IntoResponse {
-    Json(ModelsResponse {
-        models: vec![state.model_name.clone()],
-    })
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum AutoCount {
+    Auto,
 }
 
-async fn metrics_handler(State(state): State<Arc<AppState>>) -> impl IntoResponse {
-    let encoder = TextEncoder::new();
-    let metric_families = state.metrics.registry.gather();
+#[derive(Debug, Serialize)]
+struct ClusterResponse {
+    object: String,
+    k: usize,
+    // Cluster of each input, in input order
+    assignments: Vec<usize>,
+    clusters: Vec<ClusterObject>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    silhouette: Option<f32>,
+    iterations: usize,
+    model: String,
+    usage: Usage,
+    semembed_model: ServedModel,
+}
 
-    let mut buffer = Vec::new();
+#[derive(Debug, Serialize)]
+struct ClusterObject {
+    index: usize,
+    size: usize,
+    centroid: Vec<f32>,
+    // The members closest to the centroid, nearest first
+    examples: Vec<ClusterExample>,
+}
+
+#[derive(Debug, Serialize)]
+struct ClusterExample {
+    index: usize,
+    text: String,
+    distance: f32,
+}
+
+// /v1/dedup: groups of near-duplicate texts
+#[derive(Debug, Deserialize)]
+struct DedupRequest {
+    input: Vec<String>,
+    model: Option<String>,
+    // Cosine similarity above which two inputs are duplicates
+    threshold: Option<f32>,
+    #[serde(default)]
+    representative: Representative,
+    #[serde(default)]
+    preprocess: PreprocessOverrides,
+    input_type: Option<InputKind>,
+}
+
+#[derive(Debug, Serialize)]
+struct DedupResponse {
+    object: String,
+    groups: Vec<DedupGroup>,
+    // Inputs left once every group is reduced to its representative
+    unique: usize,
+    model: String,
+    usage: Usage,
+    semembed_model: ServedModel,
+}
+
+#[derive(Debug, Serialize)]
+struct DedupGroup {
+    representative: usize,
+    indices: Vec<usize>,
+    max_similarity: f32,
+}
+
+// /v1/centroid: one vector averaged from the embeddings of several inputs
+#[derive(Debug, Deserialize)]
+struct CentroidRequest {
+    inputs: Vec<String>,
+    // One per input; equal when absent
+    weights: Option<Vec<f32>>,
+    // Scale the mean back to unit length (default true)
+    normalize: Option<bool>,
+    model: Option<String>,
+    input_type: Option<InputKind>,
+    #[serde(default)]
+    encoding_format: EncodingFormat,
+    precision: Option<u32>,
+    #[serde(default)]
+    preprocess: PreprocessOverrides,
+}
+
+#[derive(Debug, Serialize)]
+struct CentroidResponse {
+    object: String,
+    embedding: EmbeddingData,
+    count: usize,
+    // Length of each input's embedding, in input order
+    norms: Vec<f32>,
+    model: String,
+    usage: Usage,
+    semembed_model: ServedModel,
+}
+
+// /v1/similarity_matrix: every text of `a` scored against every text of `b`
+#[derive(Debug, Deserialize)]
+struct SimilarityRequest {
+    a: Vec<String>,
+    b: Vec<String>,
+    // The model's own distance when absent
+    metric: Option<Distance>,
+    model: Option<String>,
+    // Queries against passages unless told otherwise
+    a_type: Option<InputKind>,
+    b_type: Option<InputKind>,
+    // Base64 past SIMILARITY_PACK_CELLS when absent
+    encoding_format: Option<EncodingFormat>,
+    precision: Option<u32>,
+    #[serde(default)]
+    preprocess: PreprocessOverrides,
+}
+
+#[derive(Debug, Serialize)]
+struct SimilarityResponse {
+    object: String,
+    metric: Distance,
+    // Rows (|a|) and columns (|b|)
+    shape: [usize; 2],
+    // Nested rows, or the row-major f32 matrix in base64
+    data: EmbeddingData,
+    model: String,
+    usage: Usage,
+    semembed_model: ServedModel,
+}
+
+// /v1/classify: zero-shot labels by similarity to label descriptions
+#[derive(Debug, Deserialize)]
+struct ClassifyRequest {
+    inputs: Vec<String>,
+    labels: Vec<LabelSpec>,
+    model: Option<String>,
+    // Score labels independently instead of as a distribution over them
+    #[serde(default)]
+    multi_label: bool,
+    temperature: Option<f32>,
+    // Multi-label only: the similarity that scores 0.5
+    midpoint: Option<f32>,
+    #[serde(default)]
+    preprocess: PreprocessOverrides,
+}
+
+#[derive(Debug, Deserialize)]
+struct LabelSpec {
+    name: String,
+    // Embedded in place of the name when given
+    description: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct ClassifyResponse {
+    object: String,
+    data: Vec<Classification>,
+    model: String,
+    usage: Usage,
+    semembed_model: ServedModel,
+}
+
+#[derive(Debug, Serialize)]
+struct Classification {
+    index: usize,
+    // The highest-scoring label
+    label: String,
+    // One per label, in request order
+    scores: Vec<LabelScore>,
+}
+
+#[derive(Debug, Serialize)]
+struct LabelScore {
+    label: String,
+    score: f32,
+    similarity: f32,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+struct HealthResponse {
+    status: Status,
+    model: String,
+    dimensions: usize,
+    components: BTreeMap<&'static str, Component>,
+    /// Only present when a readiness threshold tripped.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    saturation: Option<Saturation>,
+    /// Models still loading in the background.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    loading: Vec<String>,
+}
+
+// The readiness threshold that took this instance out of rotation
+#[derive(Debug, Serialize, ToSchema)]
+struct Saturation {
+    threshold: &'static str,
+    value: u64,
+    limit: u64,
+}
+
+impl std::fmt::Display for Saturation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} is {}, over the limit of {}", self.threshold, self.value, self.limit)
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct ModelsResponse {
+    models: Vec<String>,
+    metadata: Vec<ModelDetails>,
+}
+
+#[derive(Debug, Serialize)]
+struct ModelDetails {
+    id: String,
+    #[serde(flatten)]
+    metadata: ModelMetadata,
+}
+
+// OpenAI-compatible model listing (/v1/models)
+#[derive(Debug, Serialize, ToSchema)]
+struct ModelList {
+    object: String,
+    data: Vec<ModelObject>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+struct ModelObject {
+    id: String,
+    object: String,
+    owned_by: String,
+    metadata: ModelMetadata,
+    limits: Limits,
+    /// `resident`, or for on-demand models `loading` or `available`.
+    state: &'static str,
+    /// The only endpoint serving a model loaded for comparison.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    endpoint: Option<&'static str>,
+}
+
+impl ModelObject {
+    fn new(id: &str, metadata: &ModelMetadata, limits: Limits) -> Self {
+        Self {
+            id: id.to_string(),
+            object: "model".to_string(),
+            owned_by: "semembed".to_string(),
+            metadata: metadata.clone(),
+            limits,
+            state: "resident",
+            endpoint: None,
+        }
+    }
+}
+
+// Per-request limits, advertised in /v1/models so clients can size their batches
+#[derive(Debug, Clone, Copy, Serialize, ToSchema)]
+struct Limits {
+    max_inputs: usize,
+    max_tokens_per_request: usize,
+    /// Applies to the decompressed size of compressed bodies.
+    max_body_bytes: usize,
+    /// Checked on every input before it is preprocessed or tokenized.
+    max_input_bytes: usize,
+    /// Of the metadata echoed with `{id, text}` inputs, as JSON.
+    max_metadata_bytes: usize,
+    max_metadata_total_bytes: usize,
+}
+
+// Default tokens shared by consecutive chunks of a long document
+const CHUNK_OVERLAP: usize = 32;
+
+// Bounds on /v1/cluster requests. Auto k tries every k from 2 up to
+// AUTO_MAX_CLUSTERS.
+const MAX_CLUSTERS: usize = 100;
+const AUTO_MAX_CLUSTERS: usize = 10;
+const MAX_CLUSTER_ITERATIONS: usize = 1000;
+const MAX_CLUSTER_EXAMPLES: usize = 20;
+
+// Similarity matrices larger than this are packed as base64 unless the
+// request asks for floats
+const SIMILARITY_PACK_CELLS: usize = 65_536;
+
+// Endpoints left out of the request total in /stats and the error rate in /health
+const NOT_TRAFFIC: &[&str] = &["/health", "/health/live", "/metrics", "/stats"];
+
+// Endpoints whose successful requests feed the latency window behind /stats,
+// readiness and load shedding
+const TIMED: &[&str] = &[
+    "/v1/embeddings",
+    "/v1/embeddings/url",
+    "/v1/embeddings/file",
+    "/openai/deployments/:deployment/embeddings",
+    "/v1/projects/:project/locations/:location/publishers/google/models/:model",
+    "/model/:model_id/invoke",
+    "/v1/cluster",
+    "/v1/dedup",
+    "/v1/centroid",
+    "/v1/similarity_matrix",
+    "/v1/classify",
+];
+
+// Labels accepted by /v1/classify
+const MAX_LABELS: usize = 256;
+
+// Texts embedded per inference call while warming the cache
+const CACHE_WARM_BATCH: usize = 64;
+
+// Tokio's own default for the blocking pool
+const DEFAULT_BLOCKING_THREADS: usize = 512;
+
+// Loaded model instances that can run inference concurrently
+const EMBEDDER_WORKERS: usize = 1;
+
+// Effective runtime sizing, reported by /version
+#[derive(Debug, Clone, Copy, Serialize)]
+struct RuntimeConfig {
+    worker_threads: usize,
+    blocking_threads: usize,
+}
+
+impl RuntimeConfig {
+    fn build(self) -> std::io::Result<tokio::runtime::Runtime> {
+        tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(self.worker_threads)
+            .max_blocking_threads(self.blocking_threads)
+            .enable_all()
+            .build()
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct VersionResponse {
+    version: &'static str,
+    model: String,
+    model_revision: Option<String>,
+    runtime: RuntimeConfig,
+    // Bumped by every successful reload
+    config_generation: u64,
+}
+
+// Application state
+struct AppState {
+    embedder: Arc<Embedder>,
+    model_name: String,
+    model_spec: &'static ModelSpec,
+    metadata: ModelMetadata,
+    model_revision: Option<String>,
+    settings: RwLock<Arc<Settings>>,
+    config_file: Option<ConfigFile>,
+    log_filter: LogFilter,
+    model_echo: ModelEcho,
+    // Default task instruction for queries, by canonical model name
+    instructions: HashMap<String, String>,
+    // Decimal places kept in float output by default; `None` is full precision
+    float_precision: Option<u32>,
+    // Which end of an over-long input is cut when a request doesn't say
+    truncation_side: TruncationSide,
+    chunker: Chunker,
+    fetcher: Option<Arc<Fetcher>>,
+    #[cfg(feature = "object-storage")]
+    jobs: Option<Arc<jobs::Jobs>>,
+    #[cfg(feature = "pgvector")]
+    pgvector: Option<pgvector::PgVectorSink>,
+    // The vector store `/v1/index` and batch jobs write into
+    sinks: sinks::Sinks,
+    // Daily requests, tokens and errors per tenant, for usage exports
+    usage: Arc<usage::UsageLedger>,
+    upload: UploadLimits,
+    cache: EmbeddingCache,
+    // Inputs accepted by /v1/cluster, whose silhouette is quadratic in them
+    cluster_max_inputs: usize,
+    // Inputs accepted by /v1/dedup, which compares every pair
+    dedup_max_inputs: usize,
+    // Scores returned by /v1/similarity_matrix, |a| × |b|
+    similarity_max_cells: usize,
+    // GET /v1/embeddings, when it is on
+    cacheable: Option<cacheable::CacheableConfig>,
+    // Serve each model's tokenizer.json, which runs to megabytes
+    tokenizer_json: bool,
+    tokenizer_files: tokenizer::TokenizerFiles,
+    queue: Arc<InferenceQueue>,
+    // Calls each model may run at once, within the queue's turns
+    model_limits: model_limits::ModelLimits,
+    // Priority of requests without an X-Priority header
+    default_priority: Priority,
+    multi_vector: Option<Arc<MultiVector>>,
+    // Candidate model a sample of requests is also embedded with
+    // Set once it has loaded
+    shadow: OnceLock<shadow::Shadow>,
+    // Models loaded only for /v1/compare
+    comparison: compare::Comparison,
+    // The model for each input language, for requests leaving the model to the server
+    language_routes: Option<routing::LanguageRoutes>,
+    // Detects the languages of inputs, remembering recent ones
+    languages: language::Detector,
+    // Statistics over produced embeddings, when drift monitoring is on
+    drift: Option<drift::Drift>,
+    // Time every request's phases for Server-Timing, not only those asking
+    server_timing: bool,
+    // One line per request, written by a background thread
+    access_log: Option<access_log::AccessLog>,
+    // A chained record of each embedding request, written by a background thread
+    audit: Option<audit::AuditLog>,
+    // Logs the bodies of chosen requests while an operator has it turned on
+    debug_log: debug_log::DebugLog,
+    // Models still loading after the server started (`SEMEMBED_READY_ON=default`)
+    loading: preload::Pending,
+    // Inputs accepted with `output: "tokens"`, whose responses grow with every token
+    tokens_max_inputs: usize,
+    user_labels: UserLabels,
+    limits: Limits,
+    bulk_limits: BulkLimits,
+    preprocess: Preprocess,
+    runtime: RuntimeConfig,
+    idempotency: idempotency::IdempotencyCache,
+    // Recent request latencies, for quantiles over the current load
+    latency: LatencyWindow,
+    // Component states and last errors for /health
+    health: Health,
+    // Cores each embedder replica is pinned to; empty when unpinned
+    placements: Vec<Placement>,
+    // Rejects embedding requests while resident memory is over its limit
+    memory: Option<MemoryGuard>,
+    // Rejects low-priority requests while recent latency is over target
+    shedder: Option<LoadShedder>,
+    started: Instant,
+    metrics: Arc<Metrics>,
+}
+
+impl AppState {
+    // The settings in effect; a request keeps the ones it started with even
+    // if a reload replaces them meanwhile
+    fn settings(&self) -> Arc<Settings> {
+        self.settings.read().unwrap_or_else(PoisonError::into_inner).clone()
+    }
+}
+
+// Settings that SIGHUP or POST /admin/reload can change while serving, swapped
+// as a whole
+struct Settings {
+    generation: u64,
+    resolver: ModelResolver,
+    metrics_token: Option<String>,
+    readiness: Readiness,
+    // Daily and monthly token caps per tenant
+    quotas: quota::Quotas,
+}
+
+// Load past which /health reports the instance not ready, so load balancers
+// stop sending it traffic; unset thresholds are never checked
+#[derive(Debug, Clone, Copy)]
+struct Readiness {
+    max_queue_depth: Option<usize>,
+    max_p95: Option<Duration>,
+    // Share of recent responses that may be server errors before the
+    // instance is degraded
+    max_error_rate: Option<f64>,
+    // Whether degraded is not ready either
+    fail_degraded: bool,
+}
+
+// Samples kept for the latency window, however many requests finish within it
+const LATENCY_SAMPLES: usize = 4096;
+
+// Prometheus metrics
+struct Metrics {
+    registry: Registry,
+    requests_total: CounterVec,
+    request_duration: HistogramVec,
+    tokens_processed: Counter,
+    errors_total: CounterVec,
+    alias_requests_total: CounterVec,
+    user_requests_total: CounterVec,
+    user_tokens_total: CounterVec,
+    model_info: IntGaugeVec,
+    config_generation: IntGauge,
+    shed_probability: Gauge,
+    resident_memory: IntGauge,
+    memory_overloaded: IntGauge,
+    inference_panics: Counter,
+    circuit_open: IntGauge,
+    batch_tokens: IntGauge,
+    inference_hung: Counter,
+    inference_retries: CounterVec,
+    queue_depth: IntGaugeVec,
+    tenant_queue_depth: IntGaugeVec,
+    queue_wait: HistogramVec,
+    inference_duration: HistogramVec,
+    inference_cancelled: CounterVec,
+    cache_hits: Counter,
+    cache_misses: Counter,
+    cache_entries: IntGauge,
+}
+
+impl Metrics {
+    fn new() -> anyhow::Result<Self> {
+        let registry = Registry::new();
+
+        // By route pattern, never the raw path, so each label has one value per route
+        let requests_total = CounterVec::new(
+            Opts::new("semembed_requests_total", "Total number of requests, by endpoint"),
+            &["endpoint"],
+        )?;
+        registry.register(Box::new(requests_total.clone()))?;
+
+        let request_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "semembed_request_duration_seconds",
+                "Request duration in seconds until the response headers, by endpoint",
+            ),
+            &["endpoint"],
+        )?;
+        registry.register(Box::new(request_duration.clone()))?;
+
+        let tokens_processed = Counter::with_opts(Opts::new(
+            "semembed_tokens_processed_total",
+            "Total number of tokens processed"
+        ))?;
+        registry.register(Box::new(tokens_processed.clone()))?;
+
+        let errors_total = CounterVec::new(
+            Opts::new(
+                "semembed_errors_total",
+                "Total number of error responses, by endpoint, status class (4xx or 5xx) and reason",
+            ),
+            &["endpoint", "status_class", "reason"],
+        )?;
+        registry.register(Box::new(errors_total.clone()))?;
+
+        let alias_requests_total = CounterVec::new(
+            Opts::new(
+                "semembed_alias_requests_total",
+                "Total number of requests that selected a model through an alias"
+            ),
+            &["alias", "model"],
+        )?;
+        registry.register(Box::new(alias_requests_total.clone()))?;
+
+        let user_requests_total = CounterVec::new(
+            Opts::new(
+                "semembed_user_requests_total",
+                "Total number of embedding requests by end user (see SEMEMBED_USER_METRICS)"
+            ),
+            &["user"],
+        )?;
+        registry.register(Box::new(user_requests_total.clone()))?;
+
+        let user_tokens_total = CounterVec::new(
+            Opts::new(
+                "semembed_user_tokens_total",
+                "Total number of tokens processed by end user (see SEMEMBED_USER_METRICS)"
+            ),
+            &["user"],
+        )?;
+        registry.register(Box::new(user_tokens_total.clone()))?;
+
+        let model_info = IntGaugeVec::new(
+            Opts::new("semembed_model_info", "Loaded model and its shape (always 1)"),
+            &["model", "dimensions", "max_tokens"],
+        )?;
+        registry.register(Box::new(model_info.clone()))?;
+
+        let config_generation = IntGauge::with_opts(Opts::new(
+            "semembed_config_generation",
+            "Configuration generation in effect, starting at 1 and bumped by every successful reload"
+        ))?;
+        config_generation.set(1);
+        registry.register(Box::new(config_generation.clone()))?;
+
+        let shed_probability = Gauge::with_opts(Opts::new(
+            "semembed_shed_probability",
+            "Share of low-priority requests currently rejected by load shedding"
+        ))?;
+        registry.register(Box::new(shed_probability.clone()))?;
+
+        let resident_memory = IntGauge::with_opts(Opts::new(
+            "semembed_resident_memory_bytes",
+            "Resident memory at the last sample (with SEMEMBED_MAX_RSS_BYTES)"
+        ))?;
+        registry.register(Box::new(resident_memory.clone()))?;
+
+        let memory_overloaded = IntGauge::with_opts(Opts::new(
+            "semembed_memory_overloaded",
+            "1 while embedding requests are rejected because resident memory is over SEMEMBED_MAX_RSS_BYTES"
+        ))?;
+        registry.register(Box::new(memory_overloaded.clone()))?;
+
+        let inference_panics = Counter::with_opts(Opts::new(
+            "semembed_inference_panics_total",
+            "Total number of panics caught during inference, including recovered lock poisonings"
+        ))?;
+        registry.register(Box::new(inference_panics.clone()))?;
+
+        let circuit_open = IntGauge::with_opts(Opts::new(
+            "semembed_circuit_open",
+            "1 while the inference circuit is open and the model is being re-initialized"
+        ))?;
+        registry.register(Box::new(circuit_open.clone()))?;
+
+        let batch_tokens = IntGauge::with_opts(Opts::new(
+            "semembed_batch_token_budget",
+            "Padded tokens per inference sub-batch currently allowed, lowered under memory pressure"
+        ))?;
+        registry.register(Box::new(batch_tokens.clone()))?;
+
+        let inference_hung = Counter::with_opts(Opts::new(
+            "semembed_inference_hung_total",
+            "Total number of inference calls the watchdog found hung"
+        ))?;
+        registry.register(Box::new(inference_hung.clone()))?;
+
+        let inference_retries = CounterVec::new(
+            Opts::new(
+                "semembed_inference_retries_total",
+                "Retries of transient inference errors, by outcome (succeeded or failed)"
+            ),
+            &["outcome"],
+        )?;
+        registry.register(Box::new(inference_retries.clone()))?;
+
+        let queue_depth = IntGaugeVec::new(
+            Opts::new("semembed_queue_depth", "Inference calls waiting for the model, by priority"),
+            &["priority"],
+        )?;
+        registry.register(Box::new(queue_depth.clone()))?;
+
+        let tenant_queue_depth = IntGaugeVec::new(
+            Opts::new("semembed_tenant_queue_depth", "Inference calls waiting for the model, by tenant"),
+            &["tenant"],
+        )?;
+        registry.register(Box::new(tenant_queue_depth.clone()))?;
+
+        let queue_wait = HistogramVec::new(
+            HistogramOpts::new(
+                "semembed_queue_wait_seconds",
+                "Time inference calls waited for the model, by priority"
+            ),
+            &["priority"],
+        )?;
+        registry.register(Box::new(queue_wait.clone()))?;
+
+        let inference_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "semembed_inference_duration_seconds",
+                "Time to embed a request's inputs, queueing included, by priority"
+            ),
+            &["priority"],
+        )?;
+        registry.register(Box::new(inference_duration.clone()))?;
+
+        let inference_cancelled = CounterVec::new(
+            Opts::new(
+                "semembed_inference_cancelled_total",
+                "Requests whose client disconnected before inference finished, by stage (queued or running)"
+            ),
+            &["stage"],
+        )?;
+        registry.register(Box::new(inference_cancelled.clone()))?;
+
+        let cache_hits = Counter::with_opts(Opts::new(
+            "semembed_cache_hits_total",
+            "Inputs whose embedding was served from the embedding cache"
+        ))?;
+        registry.register(Box::new(cache_hits.clone()))?;
+
+        let cache_misses = Counter::with_opts(Opts::new(
+            "semembed_cache_misses_total",
+            "Inputs looked up in the embedding cache and not found"
+        ))?;
+        registry.register(Box::new(cache_misses.clone()))?;
+
+        let cache_entries = IntGauge::with_opts(Opts::new(
+            "semembed_cache_entries",
+            "Embeddings held in the embedding cache"
+        ))?;
+        registry.register(Box::new(cache_entries.clone()))?;
+
+        Ok(Self {
+            registry,
+            requests_total,
+            request_duration,
+            tokens_processed,
+            errors_total,
+            alias_requests_total,
+            user_requests_total,
+            user_tokens_total,
+            model_info,
+            config_generation,
+            shed_probability,
+            resident_memory,
+            memory_overloaded,
+            inference_panics,
+            circuit_open,
+            batch_tokens,
+            inference_hung,
+            inference_retries,
+            queue_depth,
+            tenant_queue_depth,
+            queue_wait,
+            inference_duration,
+            inference_cancelled,
+            cache_hits,
+            cache_misses,
+            cache_entries,
+        })
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    // `semembed eval ...` measures model quality, `semembed batch-dir ...`
+    // embeds a directory of files, `semembed audit verify ...` checks an
+    // audit log and `semembed bench ...` load-tests, instead of serving
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let subcommand = match args.first().map(String::as_str) {
+        None => None,
+        Some(name @ ("eval" | "batch-dir" | "audit" | "bench")) => Some(name),
+        Some(other) => anyhow::bail!(
+            "unknown subcommand {:?} (expected none, \"eval\", \"batch-dir\", \"audit\" or \"bench\")",
+            other
+        ),
+    };
+
+    // The configuration file is applied to the environment before anything reads it
+    let config_file = if subcommand.is_some() { None } else { ConfigFile::load()? };
+
+    // Error reporting comes first so panics during startup are captured too
+    #[cfg(feature = "sentry")]
+    let sentry_guard = reporting::init();
+
+    // Initialize tracing. Subcommands log to stderr so their output can be piped.
+    let writer = if subcommand.is_some() {
+        BoxMakeWriter::new(std::io::stderr)
+    } else {
+        BoxMakeWriter::new(std::io::stdout)
+    };
+    let (filter, log_filter) = tracing_subscriber::reload::Layer::new(
+        tracing_subscriber::EnvFilter::try_from_default_env().unwrap_or_else(|_| DEFAULT_LOG_FILTER.into()),
+    );
+    let subscriber = tracing_subscriber::registry()
+        .with(filter)
+        .with(tracing_subscriber::fmt::layer().with_writer(writer));
+    #[cfg(feature = "sentry")]
+    let subscriber = subscriber.with(sentry_guard.is_some().then(sentry::integrations::tracing::layer));
+    subscriber.init();
+
+    match subcommand {
+        Some("eval") => return eval::run(&args[1..]),
+        Some("audit") => return audit::run(&args[1..]),
+        Some("bench") => return bench::run(&args[1..]),
+        Some(_) => return batch_dir::run(&args[1..]),
+        None => {}
+    }
+
+    info!("Starting semembed service");
+    if let Some(file) = &config_file {
+        info!("Configuration file: {}", file.path().display());
+    }
+    #[cfg(feature = "sentry")]
+    if sentry_guard.is_some() {
+        info!("Sentry error reporting enabled");
+    }
+
+    // Every problem with the environment is reported here, before the model download
+    config::validate()?;
+
+    // Size the runtime explicitly so semembed can share a host with other services
+    let runtime_config = RuntimeConfig {
+        worker_threads: env_parse("SEMEMBED_TOKIO_WORKERS")?
+            .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get())),
+        blocking_threads: env_parse("SEMEMBED_BLOCKING_THREADS")?.unwrap_or(DEFAULT_BLOCKING_THREADS),
+    };
+    info!(
+        "Tokio runtime: {} worker thread(s), up to {} blocking thread(s)",
+        runtime_config.worker_threads, runtime_config.blocking_threads
+    );
+
+    runtime_config.build()?.block_on(run(runtime_config, config_file, log_filter))
+}
+
+// Log filter used when RUST_LOG is unset
+const DEFAULT_LOG_FILTER: &str = "semembed=info,tower_http=debug";
+
+async fn run(
+    runtime_config: RuntimeConfig,
+    config_file: Option<ConfigFile>,
+    log_filter: LogFilter,
+) -> anyhow::Result<()> {
+    // Get configuration from environment
+    let model_name = std::env::var("SEMEMBED_MODEL")
+        .ok()
+        .filter(|name| !name.trim().is_empty())
+        .unwrap_or_else(|| "BAAI/bge-small-en-v1.5".to_string());
+    let port: u16 = env_parse("SEMEMBED_PORT")?.unwrap_or(8081);
+    let socket_activated = systemd::listeners()?;
+    if let Some(listeners) = &socket_activated {
+        info!("Socket-activated by systemd with {} listener(s)", listeners.len());
+    }
+    let listen_addrs = listen::parse_addrs(
+        &std::env::var("SEMEMBED_HOST").unwrap_or_else(|_| "0.0.0.0".to_string()),
+        port,
+    )?;
+    let socket_options = listen::SocketOptions {
+        reuse_port: env_flag("SEMEMBED_REUSEPORT")?,
+        backlog: env_parse("SEMEMBED_LISTEN_BACKLOG")?.unwrap_or(1024),
+    };
+    let tcp_nodelay = env_flag("SEMEMBED_TCP_NODELAY")?;
+    let docs_disabled = env_flag("SEMEMBED_DOCS_DISABLED")?;
+    let aliases = models::parse_aliases(
+        &std::env::var("SEMEMBED_MODEL_ALIASES").unwrap_or_default(),
+    )?;
+    let instructions = models::parse_instructions(
+        &std::env::var("SEMEMBED_INSTRUCTIONS").unwrap_or_default(),
+    )?;
+    let model_concurrency = model_limits::parse(
+        &std::env::var("SEMEMBED_MODEL_CONCURRENCY").unwrap_or_default(),
+    )?;
+    let float_precision: Option<u32> = env_parse("SEMEMBED_FLOAT_PRECISION")?;
+    let latency_window = Duration::from_secs(env_parse::<u64>("SEMEMBED_LATENCY_WINDOW_SECS")?.unwrap_or(60).max(1));
+    let truncation_side: TruncationSide = env_parse("SEMEMBED_TRUNCATION_SIDE")?.unwrap_or_default();
+    let model_echo = std::env::var("SEMEMBED_MODEL_ECHO")
+        .map(|v| v.parse::<ModelEcho>())
+        .unwrap_or(Ok(ModelEcho::default()))?;
+    let default_priority = std::env::var("SEMEMBED_DEFAULT_PRIORITY")
+        .map_or(Ok(Priority::High), |value| value.parse::<Priority>())?;
+    let low_priority_batch: usize = env_parse("SEMEMBED_LOW_PRIORITY_BATCH")?.unwrap_or(32);
+    info!(
+        "Default priority: {}, low-priority sub-batches of {} inputs",
+        default_priority, low_priority_batch
+    );
+
+    let tenant_shares = std::env::var("SEMEMBED_TENANT_SHARES")
+        .unwrap_or_default()
+        .parse::<TenantShares>()?;
+    if !tenant_shares.is_empty() {
+        info!("Tenant shares: {}", tenant_shares);
+    }
+    let quotas = quota::Quotas {
+        daily: std::env::var("SEMEMBED_DAILY_TOKEN_QUOTAS").unwrap_or_default().parse()?,
+        monthly: std::env::var("SEMEMBED_MONTHLY_TOKEN_QUOTAS").unwrap_or_default().parse()?,
+    };
+    if !quotas.is_empty() {
+        info!("Token quotas: daily {}; monthly {}", quotas.daily, quotas.monthly);
+    }
+
+    let cache_capacity: usize = env_parse("SEMEMBED_CACHE_CAPACITY")?.unwrap_or(0);
+    let cache_warm_file = std::env::var("SEMEMBED_CACHE_WARM_FILE").ok().filter(|path| !path.is_empty());
+    let cache_warm_blocking = env_flag("SEMEMBED_CACHE_WARM_BLOCKING")?;
+    if cache_capacity > 0 {
+        info!("Embedding cache enabled ({} entries)", cache_capacity);
+    }
+
+    let upload = UploadLimits {
+        max_file_bytes: env_parse("SEMEMBED_UPLOAD_MAX_FILE_BYTES")?.unwrap_or(10 * 1024 * 1024),
+        max_total_bytes: env_parse("SEMEMBED_UPLOAD_MAX_BYTES")?.unwrap_or(25 * 1024 * 1024),
+    };
+
+    // Fetching URLs on behalf of clients is opt-in
+    let fetcher = if env_flag("SEMEMBED_URL_FETCH")? {
+        let config = FetchConfig {
+            max_urls: env_parse("SEMEMBED_URL_MAX_URLS")?.unwrap_or(16),
+            max_bytes: env_parse("SEMEMBED_URL_MAX_BYTES")?.unwrap_or(5 * 1024 * 1024),
+            timeout: Duration::from_secs(env_parse("SEMEMBED_URL_TIMEOUT_SECS")?.unwrap_or(10)),
+            allowlist: std::env::var("SEMEMBED_URL_ALLOWLIST")
+                .unwrap_or_default()
+                .split(',')
+                .map(|host| host.trim().to_ascii_lowercase())
+                .filter(|host| !host.is_empty())
+                .collect(),
+        };
+        info!(
+            "URL fetching enabled (max {} bytes, {:?} timeout, allowlist: {})",
+            config.max_bytes,
+            config.timeout,
+            if config.allowlist.is_empty() { "any public host".to_string() } else { config.allowlist.join(", ") }
+        );
+        Some(Arc::new(Fetcher::new(config)?))
+    } else {
+        None
+    };
+
+    // Batch jobs read and write the buckets of whoever runs the server, so they're opt-in
+    #[cfg(feature = "object-storage")]
+    let jobs = if env_flag("SEMEMBED_JOBS")? {
+        let config = jobs::JobsConfig {
+            concurrency: env_parse("SEMEMBED_JOBS_CONCURRENCY")?.unwrap_or(1),
+            retained: env_parse("SEMEMBED_JOBS_RETAINED")?.unwrap_or(100),
+        };
+        info!("Batch jobs enabled ({} running at a time)", config.concurrency);
+        Some(Arc::new(jobs::Jobs::new(config)))
+    } else {
+        None
+    };
+    #[cfg(not(feature = "object-storage"))]
+    if env_flag("SEMEMBED_JOBS")? {
+        warn!("SEMEMBED_JOBS is set, but batch jobs need a build with `--features object-storage`");
+    }
+
+    // Writing into Postgres is opt-in: configuring a database enables it
+    #[cfg(feature = "pgvector")]
+    let pgvector = match std::env::var("SEMEMBED_PGVECTOR_DSN").ok().filter(|dsn| !dsn.trim().is_empty()) {
+        Some(dsn) => {
+            let config = pgvector::PgVectorConfig {
+                dsn,
+                max_connections: env_parse("SEMEMBED_PGVECTOR_MAX_CONNECTIONS")?.unwrap_or(5),
+                acquire_timeout: Duration::from_secs(env_parse("SEMEMBED_PGVECTOR_ACQUIRE_TIMEOUT_SECS")?.unwrap_or(5)),
+                columns: pgvector::Columns {
+                    id: env_parse("SEMEMBED_PGVECTOR_ID_COLUMN")?.unwrap_or_else(|| "id".to_string()),
+                    vector: env_parse("SEMEMBED_PGVECTOR_VECTOR_COLUMN")?.unwrap_or_else(|| "embedding".to_string()),
+                    text: env_parse("SEMEMBED_PGVECTOR_TEXT_COLUMN")?,
+                    metadata: env_parse("SEMEMBED_PGVECTOR_METADATA_COLUMN")?,
+                },
+            };
+            info!("pgvector indexing enabled (up to {} connections)", config.max_connections);
+            Some(pgvector::PgVectorSink::new(&config)?)
+        }
+        None => None,
+    };
+
+    // The store `/v1/index` and batch jobs write into is named explicitly
+    let sink: Option<Arc<dyn semembed::VectorSink>> =
+        match std::env::var("SEMEMBED_SINK").ok().filter(|kind| !kind.trim().is_empty()) {
+            None => None,
+            #[cfg(feature = "pgvector")]
+            Some(kind) if kind.trim() == "pgvector" => match &pgvector {
+                Some(sink) => Some(Arc::new(sink.clone())),
+                None => anyhow::bail!("SEMEMBED_SINK=pgvector needs SEMEMBED_PGVECTOR_DSN"),
+            },
+            Some(kind) => anyhow::bail!("SEMEMBED_SINK={:?} is not a sink this build supports", kind),
+        };
+    let sink_options = sinks::SinkOptions {
+        batch_size: env_parse("SEMEMBED_SINK_BATCH_SIZE")?.unwrap_or(100),
+        retries: env_parse("SEMEMBED_SINK_RETRIES")?.unwrap_or(3),
+    };
+    if let Some(sink) = &sink {
+        info!("Writing to the {} sink in batches of {}", sink.kind(), sink_options.batch_size);
+        // Unreachable now doesn't mean unreachable by the first write
+        let sink = sink.clone();
+        tokio::spawn(async move {
+            if let Err(e) = sink.healthcheck().await {
+                warn!("The {} sink is not reachable yet: {}", sink.kind(), e);
+            }
+        });
+    }
+
+    // Usage is always counted; keeping it across restarts needs a file
+    let usage = Arc::new(usage::UsageLedger::open(
+        std::env::var_os("SEMEMBED_USAGE_FILE").filter(|path| !path.is_empty()).map(std::path::PathBuf::from),
+        env_parse("SEMEMBED_USAGE_MAX_KEYS")?.unwrap_or(1000),
+    )?);
+    usage.set_quota_keys(quotas.tenants());
+    let usage_flush = Duration::from_secs(env_parse("SEMEMBED_USAGE_FLUSH_SECS")?.unwrap_or(60));
+
+    // Stream embedding between Kafka topics and pulling jobs from Redis, alongside
+    // the HTTP server or instead of it
+    #[allow(unused_mut)]
+    let mut serve_http = true;
+    #[cfg(feature = "kafka")]
+    let kafka = kafka::KafkaConfig::from_env()?;
+    #[cfg(feature = "kafka")]
+    if kafka.as_ref().is_some_and(|kafka| kafka.standalone) {
+        serve_http = false;
+    }
+    #[cfg(feature = "redis")]
+    let redis_worker = redis_worker::RedisConfig::from_env()?;
+    #[cfg(feature = "redis")]
+    if redis_worker.as_ref().is_some_and(|worker| worker.standalone) {
+        serve_http = false;
+    }
+    // Metrics pushed for deployments nothing scrapes
+    let push = push::PushConfig::from_env()?;
+    let statsd = statsd::StatsdConfig::from_env()?;
+
+    // Late-interaction model, loaded on first use
+    let multi_vector = match std::env::var("SEMEMBED_COLBERT_MODEL").ok().filter(|name| !name.is_empty()) {
+        Some(name) => {
+            let max_inputs = env_parse("SEMEMBED_COLBERT_MAX_INPUTS")?.unwrap_or(32);
+            let multi_vector = MultiVector::new(&name, max_inputs, circuit_config()?)?;
+            info!(
+                "Multi-vector output enabled with {} ({} dimensions per token, up to {} inputs)",
+                multi_vector.name(),
+                multi_vector.dimensions(),
+                max_inputs
+            );
+            Some(Arc::new(multi_vector))
+        }
+        None => None,
+    };
+    let user_labels = std::env::var("SEMEMBED_USER_METRICS")
+        .unwrap_or_default()
+        .parse::<UserLabels>()?;
+    info!("Per-user metrics: {}", user_labels);
+    let limits = Limits {
+        max_inputs: env_parse("SEMEMBED_MAX_INPUTS")?.unwrap_or(2048),
+        max_tokens_per_request: env_parse("SEMEMBED_MAX_TOKENS_PER_REQUEST")?.unwrap_or(300000),
+        // axum's default body limit
+        max_body_bytes: env_parse::<usize>("SEMEMBED_MAX_BODY_BYTES")?.unwrap_or(2 * 1024 * 1024),
+        max_input_bytes: env_parse("SEMEMBED_MAX_INPUT_BYTES")?.unwrap_or(256 * 1024),
+        max_metadata_bytes: env_parse("SEMEMBED_MAX_METADATA_BYTES")?.unwrap_or(4 * 1024),
+        max_metadata_total_bytes: env_parse("SEMEMBED_MAX_METADATA_TOTAL_BYTES")?.unwrap_or(256 * 1024),
+    };
+    let bulk_limits = BulkLimits {
+        max_line_bytes: env_parse("SEMEMBED_BULK_MAX_LINE_BYTES")?.unwrap_or(1024 * 1024),
+        max_lines: env_parse("SEMEMBED_BULK_MAX_LINES")?.unwrap_or(1_000_000),
+    };
+    let preprocess = Preprocess::from_env()?;
+    info!("Input preprocessing: {}; control characters: {}", preprocess, preprocess.sanitize);
+    let metrics_token = std::env::var("SEMEMBED_METRICS_TOKEN")
+        .ok()
+        .filter(|token| !token.is_empty());
+    // Profiling exposes process internals, so it is opt-in and always behind the token
+    let profiling = env_flag("SEMEMBED_PPROF")?;
+    let metrics_port: Option<u16> = env_parse("SEMEMBED_METRICS_PORT")?;
+    let metrics_addrs = metrics_port
+        .map(|metrics_port| {
+            listen::parse_addrs(
+                &std::env::var("SEMEMBED_METRICS_HOST").unwrap_or_else(|_| "0.0.0.0".to_string()),
+                metrics_port,
+            )
+        })
+        .transpose()?;
+
+    // Validate aliases before spending time on the model download
+    let resolver = ModelResolver::new(vec![model_name.clone()], aliases)?;
+    for (alias, target) in resolver.aliases() {
+        info!("Model alias: {} -> {}", alias, target);
+    }
+    for (model, instruction) in &instructions {
+        if !resolver.loaded().contains(model) {
+            anyhow::bail!("SEMEMBED_INSTRUCTIONS names {:?}, which is not loaded", model);
+        }
+        info!("Default query instruction for {}: {:?}", model, instruction);
+    }
+
+    // A candidate model compared against on a sample of real traffic
+    let shadow_name = std::env::var("SEMEMBED_SHADOW_MODEL").ok().filter(|name| !name.is_empty());
+    let shadow_setup = shadow_name
+        .as_ref()
+        .map(|_| -> anyhow::Result<ShadowSetup> {
+            let sample_rate: f64 = env_parse("SEMEMBED_SHADOW_SAMPLE_RATE")?.unwrap_or(0.01);
+            if !(0.0..=1.0).contains(&sample_rate) {
+                anyhow::bail!("SEMEMBED_SHADOW_SAMPLE_RATE must be between 0 and 1, got {}", sample_rate);
+            }
+            Ok(ShadowSetup {
+                config: shadow::ShadowConfig {
+                    sample_rate,
+                    max_in_flight: env_parse("SEMEMBED_SHADOW_MAX_IN_FLIGHT")?.unwrap_or(1),
+                },
+                dump: std::env::var_os("SEMEMBED_SHADOW_DUMP_FILE").filter(|path| !path.is_empty()),
+            })
+        })
+        .transpose()?;
+    // Further models /v1/compare can embed with, next to the primary and shadow
+    let mut compare_names: Vec<String> = std::env::var("SEMEMBED_COMPARE_MODELS")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(str::to_string)
+        .collect();
+    // Models inputs are routed to by language load with the comparison models
+    let language_routes = routing::LanguageRoutes::from_env()?;
+    if let Some(routes) = &language_routes {
+        if resolver.resolve(Some(routes.alias())).is_some() {
+            anyhow::bail!(
+                "SEMEMBED_LANGUAGE_ROUTING_ALIAS {:?} is already a model or model alias",
+                routes.alias()
+            );
+        }
+        for model in routes.models() {
+            let loaded = model == model_name || shadow_name.as_deref() == Some(model);
+            if !loaded && !compare_names.iter().any(|name| name == model) {
+                compare_names.push(model.to_string());
+            }
+        }
+        for (language, model) in routes.routed(&model_name) {
+            info!("Routing {} inputs to {}", language, model);
+        }
+        info!("Other inputs, and those too short to tell, go to {}", model_name);
+    }
+    for (model, limit) in &model_concurrency {
+        let served = *model == model_name
+            || compare_names.contains(model)
+            || multi_vector.as_ref().is_some_and(|multi_vector| multi_vector.name() == model);
+        if !served {
+            anyhow::bail!("SEMEMBED_MODEL_CONCURRENCY names {:?}, which is not loaded", model);
+        }
+        info!("At most {} concurrent request(s) for {}", limit, model);
+    }
+
+    // Serve before loading, so a server that is starting can be told from one
+    // that crashed; the gates answer until the API is built
+    let metrics = Arc::new(Metrics::new()?);
+    let model_status = startup::ModelStatus::new(&metrics.registry, metrics_token.clone())?;
+    let api_gate = startup::Gate::new(model_status.clone(), metrics_port.is_none());
+    let admin_gate = startup::Gate::new(model_status.clone(), true);
+    let load_failure_exit = env_parse::<u64>("SEMEMBED_LOAD_FAILURE_EXIT_SECS")?.map(Duration::from_secs);
+    let server_options = server::ServerOptions {
+        tcp_nodelay,
+        idle_timeout: env_parse::<u64>("SEMEMBED_HTTP_IDLE_TIMEOUT_SECS")?.map(Duration::from_secs),
+        header_read_timeout: env_parse::<u64>("SEMEMBED_HTTP_HEADER_READ_TIMEOUT_SECS")?
+            .map(Duration::from_secs),
+        max_concurrent_streams: env_parse::<u32>("SEMEMBED_HTTP2_MAX_CONCURRENT_STREAMS")?,
+        h2c: env_flag("SEMEMBED_HTTP2_H2C")?,
+    };
+    // One listener per address, all serving the same router. Under systemd
+    // socket activation the passed sockets replace SEMEMBED_HOST/PORT.
+    let listeners = match socket_activated {
+        Some(listeners) => listeners,
+        None if serve_http => listen::bind_all(&listen_addrs, socket_options)?,
+        None => Vec::new(),
+    };
+    let mut servers = JoinSet::new();
+    for listener in listeners {
+        info!("Listening on {}", listener);
+        servers.spawn(server::serve(listener, api_gate.router(), server_options));
+    }
+    if !serve_http {
+        info!("Standalone worker mode: the HTTP API is not served");
+    }
+    if let Some(metrics_addrs) = &metrics_addrs {
+        for listener in listen::bind_all(metrics_addrs, socket_options)? {
+            info!("Serving metrics on {}", listener);
+            servers.spawn(server::serve(listener, admin_gate.router(), server_options));
+        }
+    }
+
+    // Pinned before loading, since every model's session joins the pinned thread pool
+    let placements = affinity::placements(EMBEDDER_WORKERS)?;
+    // One replica: the embedder
+    if let Some(placement) = placements.first() {
+        affinity::pin_inference(&placement.cores)?;
+    }
+
+    // Under a model budget, the comparison models are loaded on demand instead
+    let model_budget = env_parse::<MemoryLimit>("SEMEMBED_MODEL_MEMORY_BUDGET")?
+        .map(MemoryLimit::bytes)
+        .transpose()?;
+    let max_resident_models = env_parse::<usize>("SEMEMBED_MAX_RESIDENT_MODELS")?;
+    let (compare_names, on_demand) = if model_budget.is_some() || max_resident_models.is_some() {
+        (Vec::new(), compare_names)
+    } else {
+        (compare_names, Vec::new())
+    };
+
+    // The primary model first, so it is never waiting behind the others
+    let model_names: Vec<String> = std::iter::once(model_name.clone())
+        .chain(shadow_name)
+        .chain(compare_names)
+        .collect();
+    let ready_on = env_parse("SEMEMBED_READY_ON")?.unwrap_or(preload::ReadyOn::All);
+    let concurrency = env_parse("SEMEMBED_MODEL_LOAD_CONCURRENCY")?.unwrap_or(2);
+    info!("Loading {} model(s), {} at a time; ready once {}", model_names.len(), concurrency, ready_on);
+    let mut preload = preload::Preload::start(model_names.clone(), concurrency, load_model)?;
+    // Waited for off the runtime, which is serving meanwhile
+    let primary = model_name.clone();
+    let loaded = tokio::task::spawn_blocking(move || {
+        match ready_on {
+            preload::ReadyOn::All => {
+                preload.wait_all();
+                if let Some(err) = preload.error() {
+                    return Err(err);
+                }
+            }
+            preload::ReadyOn::Default => preload.wait_for(0)?,
+        }
+        let loaded = preload.take(0).ok_or_else(|| anyhow::anyhow!("{} did not load", primary))?;
+        Ok((preload, loaded))
+    })
+    .await?;
+    let (mut preload, loaded) = match loaded {
+        Ok(loaded) => loaded,
+        Err(err) => return load_failed(&model_status, err, load_failure_exit, servers).await,
+    };
+    let state = Arc::new(app_state(
+        StateConfig {
+            model_name,
+            runtime_config,
+            config_file,
+            log_filter,
+            resolver,
+            metrics_token,
+            quotas,
+            model_echo,
+            instructions,
+            float_precision,
+            truncation_side,
+            fetcher,
+            #[cfg(feature = "object-storage")]
+            jobs,
+            #[cfg(feature = "pgvector")]
+            pgvector,
+            sink,
+            sink_options,
+            usage: usage.clone(),
+            upload,
+            cache_capacity,
+            tenant_shares,
+            low_priority_batch,
+            default_priority,
+            model_concurrency,
+            multi_vector,
+            language_routes,
+            user_labels,
+            limits,
+            bulk_limits,
+            preprocess,
+            latency_window,
+            placements,
+            on_demand,
+            model_budget,
+            max_resident_models,
+        },
+        loaded,
+        metrics.clone(),
+    )?);
+
+    // The shadow and comparison models, now or once the rest have loaded
+    match ready_on {
+        preload::ReadyOn::All => install_models(&state, &mut preload, shadow_setup)?,
+        preload::ReadyOn::Default if model_names.len() > 1 => {
+            state.loading.set(model_names[1..].to_vec());
+            let state = state.clone();
+            tokio::task::spawn_blocking(move || {
+                preload.wait_all();
+                if let Some(err) = preload.error() {
+                    error!("{:#}", err);
+                }
+                if let Err(err) = install_models(&state, &mut preload, shadow_setup) {
+                    error!("Failed to put the loaded models into service: {:#}", err);
+                }
+                state.loading.set(Vec::new());
+            });
+        }
+        preload::ReadyOn::Default => {}
+    }
+
+    tokio::spawn(health::watch(state.clone()));
+    if state.memory.is_some() {
+        tokio::spawn(memory::sample(state.clone()));
+    }
+
+    if let Some(shedder) = &state.shedder {
+        info!("Load shedding: low-priority requests shed while p95 latency exceeds {:?}", shedder.target());
+        tokio::spawn(shed::control(state.clone()));
+    }
+
+    #[cfg(unix)]
+    if state.config_file.is_some() {
+        tokio::spawn(reload::on_sighup(state.clone()));
+    }
+    if usage.persistent() {
+        tokio::spawn(usage::persist(usage.clone(), usage_flush));
+    }
+
+    // Warm the cache before serving, or alongside it
+    if let Some(path) = cache_warm_file {
+        if cache_warm_blocking {
+            warm_cache(state.clone(), path).await?;
+        } else {
+            let state = state.clone();
+            tokio::spawn(async move {
+                if let Err(e) = warm_cache(state, path).await {
+                    error!("Cache warm-up failed: {:#}", e);
+                }
+            });
+        }
+    }
+
+    // /metrics (and other admin endpoints) either live on the main router or,
+    // with SEMEMBED_METRICS_PORT, on a separate listener
+    let routes = Routes {
+        docs: !docs_disabled,
+        admin: metrics_port.is_none(),
+        profiling,
+    };
+    let app = router(state.clone(), &routes);
+
+    // The listeners are already up; from now on they serve the API
+    api_gate.open(app);
+    #[cfg(feature = "kafka")]
+    if let Some(kafka) = kafka {
+        servers.spawn(kafka::run(state.clone(), kafka));
+    }
+    #[cfg(feature = "redis")]
+    if let Some(worker) = redis_worker {
+        servers.spawn(redis_worker::run(state.clone(), worker));
+    }
+    if let Some(push) = push {
+        servers.spawn(push::Pusher::new(push, state.metrics.registry.clone())?.run());
+    }
+    if let Some(statsd) = statsd {
+        servers.spawn(statsd::StatsdExporter::new(statsd, state.metrics.registry.clone()).await?.run());
+    }
+
+    if metrics_addrs.is_some() {
+        let admin = admin_router(state.clone(), profiling)
+            .layer(middleware::from_fn_with_state(state.clone(), json_errors))
+            .layer(middleware::from_fn_with_state(state.clone(), count_requests))
+            .layer(TraceLayer::new_for_http())
+            .with_state(state);
+        admin_gate.open(admin);
+    }
+    model_status.set(startup::ModelState::Ready);
+
+    systemd::notify_ready();
+
+    // With a usage file, stop on SIGTERM or Ctrl-C and write it out first
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+    loop {
+        tokio::select! {
+            result = servers.join_next() => match result {
+                Some(result) => result??,
+                None => break,
+            },
+            _ = &mut shutdown, if usage.persistent() => {
+                info!("Shutting down");
+                break;
+            }
+        }
+    }
+    usage::flush(usage).await;
+
+    Ok(())
+}
+
+// What the shared state is built from besides the primary model, read
+// before the models load so a misconfiguration fails before a download
+struct StateConfig {
+    model_name: String,
+    runtime_config: RuntimeConfig,
+    config_file: Option<ConfigFile>,
+    log_filter: LogFilter,
+    resolver: ModelResolver,
+    metrics_token: Option<String>,
+    quotas: quota::Quotas,
+    model_echo: ModelEcho,
+    instructions: HashMap<String, String>,
+    float_precision: Option<u32>,
+    truncation_side: TruncationSide,
+    fetcher: Option<Arc<Fetcher>>,
+    #[cfg(feature = "object-storage")]
+    jobs: Option<Arc<jobs::Jobs>>,
+    #[cfg(feature = "pgvector")]
+    pgvector: Option<pgvector::PgVectorSink>,
+    sink: Option<Arc<dyn semembed::VectorSink>>,
+    sink_options: sinks::SinkOptions,
+    usage: Arc<usage::UsageLedger>,
+    upload: UploadLimits,
+    cache_capacity: usize,
+    tenant_shares: TenantShares,
+    low_priority_batch: usize,
+    default_priority: Priority,
+    model_concurrency: HashMap<String, usize>,
+    multi_vector: Option<Arc<MultiVector>>,
+    language_routes: Option<routing::LanguageRoutes>,
+    user_labels: UserLabels,
+    limits: Limits,
+    bulk_limits: BulkLimits,
+    preprocess: Preprocess,
+    latency_window: Duration,
+    placements: Vec<Placement>,
+    // Comparison models loaded on demand, and the limits they load under
+    on_demand: Vec<String>,
+    model_budget: Option<u64>,
+    max_resident_models: Option<usize>,
+}
+
+// The shared state every handler reads, around the loaded primary model
+fn app_state(config: StateConfig, loaded: LoadedModel, metrics: Arc<Metrics>) -> anyhow::Result<AppState> {
+    let StateConfig {
+        model_name,
+        runtime_config,
+        config_file,
+        log_filter,
+        resolver,
+        metrics_token,
+        quotas,
+        model_echo,
+        instructions,
+        float_precision,
+        truncation_side,
+        fetcher,
+        #[cfg(feature = "object-storage")]
+        jobs,
+        #[cfg(feature = "pgvector")]
+        pgvector,
+        sink,
+        sink_options,
+        usage,
+        upload,
+        cache_capacity,
+        tenant_shares,
+        low_priority_batch,
+        default_priority,
+        model_concurrency,
+        multi_vector,
+        language_routes,
+        user_labels,
+        limits,
+        bulk_limits,
+        preprocess,
+        latency_window,
+        placements,
+        on_demand,
+        model_budget,
+        max_resident_models,
+    } = config;
+    let LoadedModel {
+        spec: model_spec,
+        metadata,
+        init_options,
+        model: embedder,
+        revision: model_revision,
+    } = loaded;
+    let chunker = Chunker::new(&embedder.tokenizer)?;
+    let circuit = circuit_config()?;
+
+    metrics
+        .model_info
+        .with_label_values(&[
+            &model_name,
+            &metadata.dimensions.to_string(),
+            &metadata.max_tokens.to_string(),
+        ])
+        .set(1);
+
+    // Create shared state
+    let pool = if on_demand.is_empty() {
+        None
+    } else {
+        let config = pool::PoolConfig {
+            budget: model_budget,
+            max_resident: max_resident_models,
+            policy: env_parse("SEMEMBED_MODEL_LOAD_POLICY")?.unwrap_or(pool::LoadPolicy::Wait),
+        };
+        info!(
+            "Loading {} comparison model(s) on demand (budget: {}, at most {} resident)",
+            on_demand.len(),
+            config.budget.map_or("none".to_string(), |bytes| format!("{} bytes", bytes)),
+            config.max_resident.map_or("any number".to_string(), |max| max.to_string())
+        );
+        Some(pool::ModelPool::new(
+            &on_demand,
+            config,
+            move |name: &str| {
+                let StandaloneModel {
+                    spec, metadata, embedder, ..
+                } = standalone(load_model(name)?)?;
+                Ok(compare::ComparedModel {
+                    spec,
+                    dimensions: metadata.dimensions,
+                    embedder,
+                })
+            },
+            &metrics.registry,
+        )?)
+    };
+    let comparison = compare::Comparison::new(pool, &metrics.registry)?;
+
+    // Puts input text into URLs, so only on request
+    let cacheable = if env_flag("SEMEMBED_GET_EMBEDDINGS")? {
+        let cache_control = std::env::var("SEMEMBED_GET_CACHE_CONTROL")
+            .ok()
+            .filter(|value| !value.trim().is_empty())
+            .unwrap_or_else(|| "public, max-age=86400".to_string());
+        let config = cacheable::CacheableConfig {
+            max_uri_bytes: env_parse("SEMEMBED_GET_MAX_URI_BYTES")?.unwrap_or(4096),
+            cache_control: header::HeaderValue::from_str(&cache_control)
+                .map_err(|_| anyhow::anyhow!("invalid SEMEMBED_GET_CACHE_CONTROL={:?}", cache_control))?,
+        };
+        info!(
+            "GET /v1/embeddings enabled (URLs up to {} bytes, Cache-Control: {})",
+            config.max_uri_bytes, cache_control
+        );
+        Some(config)
+    } else {
+        None
+    };
+
+    let access_log = std::env::var_os("SEMEMBED_ACCESS_LOG")
+        .filter(|path| !path.is_empty())
+        .map(|path| {
+            let config = access_log::AccessLogConfig {
+                path: path.into(),
+                format: env_parse("SEMEMBED_ACCESS_LOG_FORMAT")?.unwrap_or(access_log::Format::Common),
+                rotation: env_parse("SEMEMBED_ACCESS_LOG_ROTATION")?.unwrap_or(access_log::Rotation::Daily),
+                max_bytes: env_parse("SEMEMBED_ACCESS_LOG_MAX_BYTES")?.unwrap_or(100 * 1024 * 1024),
+                keep: env_parse("SEMEMBED_ACCESS_LOG_KEEP")?.unwrap_or(7),
+            };
+            info!("Writing the access log to {}", config.path.display());
+            access_log::AccessLog::start(config, &metrics.registry)
+        })
+        .transpose()?;
+    let audit = std::env::var_os("SEMEMBED_AUDIT_LOG")
+        .filter(|path| !path.is_empty())
+        .map(|path| {
+            let path = std::path::PathBuf::from(path);
+            info!("Writing the audit log to {}", path.display());
+            audit::AuditLog::start(path, std::env::var("SEMEMBED_AUDIT_SALT").unwrap_or_default(), &metrics.registry)
+        })
+        .transpose()?;
+
+    let debug_log = debug_log::DebugLog::new(
+        debug_log::DebugLogConfig {
+            prefix_chars: env_parse("SEMEMBED_DEBUG_LOG_PREFIX_CHARS")?.unwrap_or(64),
+            max_minutes: env_parse("SEMEMBED_DEBUG_LOG_MAX_MINUTES")?.unwrap_or(60),
+        },
+        &metrics.registry,
+    )?;
+
+    let drift = env_parse::<u64>("SEMEMBED_DRIFT_WINDOW")?
+        .map(|window| {
+            let config = drift::DriftConfig {
+                window,
+                sample: env_parse("SEMEMBED_DRIFT_SAMPLE")?.unwrap_or(256),
+            };
+            info!("Monitoring drift over windows of {} embeddings", config.window);
+            drift::Drift::new(metadata.dimensions, config, &metrics.registry)
+        })
+        .transpose()?;
+
+    Ok(AppState {
+        embedder: Embedder::new(
+            embedder,
+            init_options,
+            circuit,
+            budget_config()?,
+            EmbedderMetrics {
+                panics: metrics.inference_panics.clone(),
+                circuit_open: metrics.circuit_open.clone(),
+                hung: metrics.inference_hung.clone(),
+                retries: metrics.inference_retries.clone(),
+                batch_tokens: metrics.batch_tokens.clone(),
+            },
+        ),
+        model_name: model_name.clone(),
+        model_spec,
+        metadata,
+        model_revision,
+        settings: RwLock::new(Arc::new(Settings {
+            generation: 1,
+            resolver,
+            metrics_token,
+            readiness: Readiness {
+                max_queue_depth: env_parse("SEMEMBED_READY_MAX_QUEUE_DEPTH")?,
+                max_p95: env_parse("SEMEMBED_READY_MAX_P95_MS")?.map(Duration::from_millis),
+                max_error_rate: env_parse("SEMEMBED_READY_MAX_ERROR_RATE")?,
+                fail_degraded: env_flag("SEMEMBED_READY_FAIL_DEGRADED")?,
+            },
+            quotas,
+        })),
+        config_file,
+        log_filter,
+        model_echo,
+        instructions,
+        float_precision,
+        truncation_side,
+        chunker,
+        fetcher,
+        #[cfg(feature = "object-storage")]
+        jobs,
+        #[cfg(feature = "pgvector")]
+        pgvector,
+        sinks: sinks::Sinks::new(sink, sink_options, &metrics.registry)?,
+        usage: usage.clone(),
+        upload,
+        cache: EmbeddingCache::new(
+            cache_capacity,
+            model_spec.name,
+            CacheMetrics {
+                hits: metrics.cache_hits.clone(),
+                misses: metrics.cache_misses.clone(),
+                entries: metrics.cache_entries.clone(),
+            },
+        ),
+        cluster_max_inputs: env_parse("SEMEMBED_CLUSTER_MAX_INPUTS")?.unwrap_or(1000),
+        dedup_max_inputs: env_parse("SEMEMBED_DEDUP_MAX_INPUTS")?.unwrap_or(2048),
+        similarity_max_cells: env_parse("SEMEMBED_SIMILARITY_MAX_CELLS")?.unwrap_or(1_000_000),
+        queue: Arc::new(InferenceQueue::new(
+            low_priority_batch,
+            tenant_shares,
+            QueueMetrics {
+                depth: metrics.queue_depth.clone(),
+                tenant_depth: metrics.tenant_queue_depth.clone(),
+                wait: metrics.queue_wait.clone(),
+            },
+        )),
+        model_limits: model_limits::ModelLimits::new(model_concurrency, &metrics.registry)?,
+        cacheable,
+        tokenizer_json: env_flag("SEMEMBED_TOKENIZER_JSON")?,
+        tokenizer_files: tokenizer::TokenizerFiles::default(),
+        default_priority,
+        multi_vector,
+        shadow: OnceLock::new(),
+        comparison,
+        language_routes,
+        languages: language::Detector::new(
+            env_parse("SEMEMBED_LANGUAGE_CACHE_CAPACITY")?.unwrap_or(10_000),
+            env_parse("SEMEMBED_LANGUAGE_METRICS_TOP")?.unwrap_or(10),
+            &metrics.registry,
+        )?,
+        drift,
+        server_timing: env_flag("SEMEMBED_SERVER_TIMING")?,
+        access_log,
+        audit,
+        debug_log,
+        loading: preload::Pending::default(),
+        tokens_max_inputs: env_parse("SEMEMBED_TOKENS_MAX_INPUTS")?.unwrap_or(8),
+        user_labels,
+        limits,
+        bulk_limits,
+        preprocess,
+        runtime: runtime_config,
+        idempotency: idempotency::IdempotencyCache::new(
+            env_parse::<usize>("SEMEMBED_IDEMPOTENCY_CAPACITY")?.unwrap_or(1024),
+            Duration::from_secs(env_parse::<u64>("SEMEMBED_IDEMPOTENCY_TTL_SECS")?.unwrap_or(86400)),
+        ),
+        latency: LatencyWindow::new(latency_window, LATENCY_SAMPLES),
+        health: Health::new(
+            Duration::from_secs(env_parse("SEMEMBED_HEALTH_CACHE_SECS")?.unwrap_or(5)),
+            latency_window,
+        ),
+        placements,
+        memory: MemoryGuard::from_env(MemoryMetrics {
+            resident: metrics.resident_memory.clone(),
+            overloaded: metrics.memory_overloaded.clone(),
+        })?,
+        shedder: env_parse::<u64>("SEMEMBED_SHED_TARGET_P95_MS")?
+            .map(|target| LoadShedder::new(Duration::from_millis(target), metrics.shed_probability.clone())),
+        started: Instant::now(),
+        metrics: metrics.clone(),
+    })
+}
+
+// Keep answering with the error the models failed to load with, so the
+// failure can be seen, until SIGTERM or SIGINT, a server fails, or
+// SEMEMBED_LOAD_FAILURE_EXIT_SECS pass
+async fn load_failed(
+    status: &startup::ModelStatus,
+    err: anyhow::Error,
+    exit_after: Option<Duration>,
+    mut servers: JoinSet<std::io::Result<()>>,
+) -> anyhow::Result<()> {
+    error!("Failed to load the models: {:#}", err);
+    status.set(startup::ModelState::Failed(format!("{:#}", err)));
+    let grace = async {
+        match exit_after {
+            Some(after) => {
+                info!("Exiting in {:?}", after);
+                tokio::time::sleep(after).await;
+            }
+            None => std::future::pending().await,
+        }
+    };
+    tokio::select! {
+        _ = grace => Err(err),
+        _ = shutdown_signal() => {
+            info!("Shutting down");
+            Ok(())
+        }
+        Some(result) = servers.join_next() => {
+            result??;
+            Err(err)
+        }
+    }
+}
+
+// Resolves on SIGTERM or SIGINT
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        let Ok(mut terminate) = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) else {
+            return std::future::pending().await;
+        };
+        tokio::select! {
+            _ = terminate.recv() => {}
+            _ = tokio::signal::ctrl_c() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+// A catalog model loaded into memory
+struct LoadedModel {
+    spec: &'static ModelSpec,
+    metadata: ModelMetadata,
+    // Kept to re-create the model when the circuit opens
+    init_options: InitOptions,
+    model: TextEmbedding,
+    revision: Option<String>,
+}
+
+// Load a catalog model, downloading it on first use. Unknown names fall back to
+// the default model.
+fn load_model(model_name: &str) -> anyhow::Result<LoadedModel> {
+    info!("Loading embedding model: {}", model_name);
+
+    let spec = models::model_spec(model_name).ok_or_else(|| {
+        anyhow::anyhow!(
+            "unknown model {:?} (supported: {})",
+            model_name,
+            models::model_names().join(", ")
+        )
+    })?;
+    let metadata = models::model_metadata(spec)
+        .ok_or_else(|| anyhow::anyhow!("fastembed has no model info for {}", spec.name))?;
+
+    // fastembed v5 API - InitOptions builder pattern
+    let init_options = InitOptions::new(spec.model.clone())
+        .with_max_length(spec.max_tokens)
+        .with_show_download_progress(false);
+    hub::download(&hub::HubConfig::from_env()?, &spec.model, &init_options.cache_dir)?;
+    let model = TextEmbedding::try_new(init_options.clone())?;
+
+    let revision = models::model_revision(&spec.model, &init_options.cache_dir);
+    info!(
+        "Model loaded successfully ({} dimensions, {} max tokens, revision: {})",
+        metadata.dimensions,
+        metadata.max_tokens,
+        revision.as_deref().unwrap_or("unknown")
+    );
+    Ok(LoadedModel {
+        spec,
+        metadata,
+        init_options,
+        model,
+        revision,
+    })
+}
+
+// How to run the shadow model once it has loaded
+struct ShadowSetup {
+    config: shadow::ShadowConfig,
+    dump: Option<std::ffi::OsString>,
+}
+
+// Put the shadow and comparison models that loaded into service. They follow
+// the primary in `preload`, the shadow model first.
+fn install_models(
+    state: &AppState,
+    preload: &mut preload::Preload<LoadedModel>,
+    shadow_setup: Option<ShadowSetup>,
+) -> anyhow::Result<()> {
+    let mut index = 1;
+    if let Some(setup) = shadow_setup {
+        if let Some(loaded) = preload.take(index) {
+            let StandaloneModel {
+                spec, metadata, embedder, ..
+            } = standalone(loaded)?;
+            info!(
+                "Shadowing {:.1}% of requests with {} ({} dimensions)",
+                setup.config.sample_rate * 100.0,
+                spec.name,
+                metadata.dimensions
+            );
+            let shadow = shadow::Shadow::new(
+                spec,
+                metadata.dimensions,
+                embedder,
+                (state.model_spec.name, state.metadata.dimensions),
+                setup.config,
+                setup.dump.as_deref().map(std::path::Path::new),
+                &state.metrics.registry,
+            )?;
+            let _ = state.shadow.set(shadow);
+        }
+        index += 1;
+    }
+    let mut compared = Vec::new();
+    for index in index..preload.len() {
+        let Some(loaded) = preload.take(index) else {
+            continue;
+        };
+        let StandaloneModel {
+            spec, metadata, embedder, ..
+        } = standalone(loaded)?;
+        info!("Serving {} ({} dimensions) for /v1/compare", spec.name, metadata.dimensions);
+        compared.push(compare::ComparedModel {
+            spec,
+            dimensions: metadata.dimensions,
+            embedder,
+        });
+    }
+    state.comparison.install(compared);
+    Ok(())
+}
+
+// A model with an embedder of its own, for the subcommands that run without
+// the server
+struct StandaloneModel {
+    spec: &'static ModelSpec,
+    metadata: ModelMetadata,
+    chunker: Chunker,
+    embedder: Arc<Embedder>,
+    // Where the embedder's metrics are registered
+    registry: Registry,
+}
+
+// Load a model as the server would (placement, circuit, batch budget)
+fn standalone_model(model_name: &str) -> anyhow::Result<StandaloneModel> {
+    if let Some(placement) = affinity::placements(EMBEDDER_WORKERS)?.first() {
+        affinity::pin_inference(&placement.cores)?;
+    }
+    standalone(load_model(model_name)?)
+}
+
+// Give a loaded model an embedder of its own
+fn standalone(loaded: LoadedModel) -> anyhow::Result<StandaloneModel> {
+    let LoadedModel {
+        spec,
+        metadata,
+        init_options,
+        model,
+        ..
+    } = loaded;
+    let chunker = Chunker::new(&model.tokenizer)?;
+    let metrics = Metrics::new()?;
+    let embedder = Embedder::new(
+        model,
+        init_options,
+        circuit_config()?,
+        budget_config()?,
+        EmbedderMetrics {
+            panics: metrics.inference_panics,
+            circuit_open: metrics.circuit_open,
+            hung: metrics.inference_hung,
+            retries: metrics.inference_retries,
+            batch_tokens: metrics.batch_tokens,
+        },
+    );
+    Ok(StandaloneModel {
+        spec,
+        metadata,
+        chunker,
+        embedder,
+        registry: metrics.registry,
+    })
+}
+
+fn circuit_config() -> anyhow::Result<CircuitConfig> {
+    Ok(CircuitConfig {
+        failure_threshold: env_parse("SEMEMBED_CIRCUIT_FAILURE_THRESHOLD")?.unwrap_or(5),
+        retry_after: Duration::from_secs(env_parse("SEMEMBED_CIRCUIT_RETRY_AFTER_SECS")?.unwrap_or(5)),
+        hang_timeout: match env_parse::<u64>("SEMEMBED_INFERENCE_HANG_SECS")?.unwrap_or(300) {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        },
+        retries: env_parse("SEMEMBED_INFERENCE_RETRIES")?.unwrap_or(2),
+    })
+}
+
+fn budget_config() -> anyhow::Result<BudgetConfig> {
+    Ok(BudgetConfig {
+        max_tokens: env_parse("SEMEMBED_BATCH_MAX_TOKENS")?.unwrap_or(32768),
+        rss_growth: match env_parse::<u64>("SEMEMBED_BATCH_RSS_GROWTH_BYTES")?.unwrap_or(256 * 1024 * 1024) {
+            0 => None,
+            bytes => Some(bytes),
+        },
+    })
+}
+
+// Parse a boolean environment variable; unset means false
+fn env_flag(name: &str) -> anyhow::Result<bool> {
+    match std::env::var(name) {
+        Err(_) => Ok(false),
+        Ok(value) => match value.trim().to_ascii_lowercase().as_str() {
+            "1" | "true" | "yes" | "on" => Ok(true),
+            "0" | "false" | "no" | "off" | "" => Ok(false),
+            other => anyhow::bail!("{} must be a boolean, got {:?}", name, other),
+        },
+    }
+}
+
+// Parse an optional environment variable; unset or empty means None
+fn env_parse<T>(name: &str) -> anyhow::Result<Option<T>>
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    match std::env::var(name) {
+        Ok(value) if !value.trim().is_empty() => value
+            .trim()
+            .parse::<T>()
+            .map(Some)
+            .map_err(|e| anyhow::anyhow!("invalid {}={:?}: {}", name, value, e)),
+        _ => Ok(None),
+    }
+}
+
+// TraceLayer's default span plus an empty `user` field that the embeddings
+// handler fills in from the request body, the ids of a request's W3C trace,
+// and the phase durations of timed requests. The URI's `input` parameter, sent
+// by GET /v1/embeddings, is redacted.
+fn request_span(req: &Request) -> tracing::Span {
+    tracing::debug_span!(
+        "request",
+        method = %req.method(),
+        uri = %redact::redact_query(&req.uri().to_string()),
+        version = ?req.version(),
+        trace_id = tracing::field::Empty,
+        span_id = tracing::field::Empty,
+        user = tracing::field::Empty,
+        queue_ms = tracing::field::Empty,
+        tokenize_ms = tracing::field::Empty,
+        infer_ms = tracing::field::Empty,
+        encode_ms = tracing::field::Empty,
+        total_ms = tracing::field::Empty,
+    )
+}
+
+// What the API's listeners serve besides the API
+struct Routes {
+    docs: bool,
+    // The admin endpoints, unless they have a listener of their own
+    admin: bool,
+    profiling: bool,
+}
+
+// The API and its middleware, as the listeners serve it
+fn router(state: Arc<AppState>, routes: &Routes) -> Router {
+    let embeddings = post(csv_format::embeddings)
+        .route_layer(middleware::from_fn_with_state(state.clone(), idempotency::idempotency));
+    let embeddings = if state.cacheable.is_some() {
+        embeddings.get(cacheable::embeddings)
+    } else {
+        embeddings
+    };
+    let mut app = Router::new()
+        .route("/v1/embeddings", embeddings)
+        // Uploads get their own body limit; option fields are covered by the usual one
+        .route(
+            "/v1/embeddings/file",
+            post(create_file_embeddings)
+                .layer(DefaultBodyLimit::max(state.upload.max_total_bytes + state.limits.max_body_bytes)),
+        )
+        // Azure OpenAI clients, which address models by deployment and expect Azure's error body
+        .route(
+            "/openai/deployments/:deployment/embeddings",
+            post(azure::create_deployment_embeddings).route_layer(middleware::from_fn(azure::azure_errors)),
+        )
+        // Vertex AI clients; `{model}` is followed by `:predict` in the same segment
+        .route(
+            "/v1/projects/:project/locations/:location/publishers/google/models/:model",
+            post(vertex::predict).route_layer(middleware::from_fn(vertex::vertex_errors)),
+        )
+        // Amazon Bedrock clients of Titan and Cohere embedding models
+        .route(
+            "/model/:model_id/invoke",
+            post(bedrock::invoke).route_layer(middleware::from_fn(bedrock::bedrock_errors)),
+        )
+        // Streams its body, so the body limit doesn't apply
+        .route("/v1/embeddings/bulk", post(bulk::bulk_embeddings).layer(DefaultBodyLimit::disable()))
+        .route("/v1/cluster", post(create_clusters))
+        .route("/v1/dedup", post(find_duplicates))
+        .route("/v1/centroid", post(create_centroid))
+        .route("/v1/similarity_matrix", post(similarity_matrix))
+        .route("/v1/compare", post(compare::compare))
+        .route("/v1/classify", post(classify_texts))
+        .route("/health", get(health_check))
+        .route("/health/live", get(liveness))
+        .route("/version", get(version))
+        .route("/openapi.json", get(openapi_document))
+        .route("/models", get(list_models))
+        .route("/v1/models", get(list_models_openai))
+        .route("/v1/models/:model", get(retrieve_model))
+        .route("/v1/models/:model/tokenizer", get(tokenizer::describe))
+        .route("/v1/models/:model/tokenizer.json", get(tokenizer::download));
+    if state.fetcher.is_some() {
+        app = app.route("/v1/embeddings/url", post(create_url_embeddings));
+    }
+    #[cfg(feature = "object-storage")]
+    if state.jobs.is_some() {
+        app = app.merge(jobs::router());
+    }
+    #[cfg(feature = "pgvector")]
+    if state.pgvector.is_some() {
+        app = app.route("/v1/index/pgvector", post(pgvector::index));
+    }
+    if state.sinks.default_sink().is_some() {
+        app = app.route("/v1/index", post(sinks::index));
+    }
+    if routes.docs {
+        app = app.merge(openapi::docs());
+    }
+    if routes.admin {
+        app = app.merge(admin_router(state.clone(), routes.profiling));
+    }
+    let app = app
+        .layer(DefaultBodyLimit::max(state.limits.max_body_bytes))
+        .layer(middleware::from_fn_with_state(state.clone(), debug_log::log_body))
+        .layer(middleware::from_fn_with_state(state.limits.max_body_bytes, decompress::decompress_request))
+        .layer(middleware::from_fn_with_state(state.clone(), json_errors))
+        .layer(middleware::from_fn_with_state(state.clone(), count_requests))
+        .layer(middleware::from_fn_with_state(state.clone(), usage::count))
+        .layer(middleware::from_fn_with_state(state.clone(), timing::server_timing))
+        .layer(middleware::from_fn_with_state(state.clone(), access_log::log_request))
+        .layer(middleware::from_fn(trace_context::propagate));
+    #[cfg(feature = "sentry")]
+    let app = if reporting::enabled() {
+        app.layer(middleware::from_fn(reporting::capture_server_errors))
+    } else {
+        app
+    };
+    app.layer(CorsLayer::permissive())
+        .layer(TraceLayer::new_for_http().make_span_with(request_span))
+        .with_state(state)
+
+}
+
+// Routes that must not be exposed publicly without protection
+#[cfg_attr(not(feature = "pprof"), allow(unused_variables))]
+fn admin_router(state: Arc<AppState>, profiling: bool) -> Router<Arc<AppState>> {
+    let router = Router::new()
+        .route("/metrics", get(metrics_handler))
+        .route("/stats", get(stats))
+        .route("/cache/dump", get(dump_cache))
+        .route("/admin/reload", post(reload::reload_handler))
+        .route("/v1/usage/export", get(usage::export))
+        .route("/admin/usage/reset", post(usage::reset));
+    let router = router
+        .route("/admin/quotas", get(quota::list))
+        .route("/admin/audit", get(audit::recent))
+        .route("/admin/quotas/:key", post(quota::adjust))
+        .route(
+            "/admin/debug-logging",
+            get(debug_log::status).post(debug_log::start).delete(debug_log::stop),
+        );
+    let router = if state.drift.is_some() {
+        router
+            .route("/admin/drift", get(drift::status))
+            .route("/admin/drift/baseline", post(drift::capture).delete(drift::clear))
+    } else {
+        router
+    };
+    #[cfg(feature = "pprof")]
+    let router = if profiling { router.merge(profiling::router()) } else { router };
+    router.route_layer(middleware::from_fn_with_state(state, require_metrics_token))
+}
+
+// What one kind of output came back with, before it is echoed, accounted
+// for and answered
+struct Embedded<'a> {
+    data: Vec<EmbeddingItem>,
+    usage: Usage,
+    // The model named in the response
+    model: String,
+    served: ServedModel,
+    summary: Option<Summary>,
+    instruction: Option<&'a str>,
+}
+
+/// Embed one or more texts
+#[utoipa::path(
+    post,
+    path = "/v1/embeddings",
+    operation_id = "createEmbeddings",
+    params(
+        ("X-Priority" = Option<String>, Header, description = "`high` or `low`; low-priority requests yield to high-priority ones"),
+        ("Idempotency-Key" = Option<String>, Header, description = "Replays the stored response of an earlier request with the same key"),
+    ),
+    request_body = EmbeddingRequest,
+    responses(
+        (
+            status = 200,
+            description = "Embeddings, in input order. With `Accept: text/csv`, one CSV row per input and the usage in \
+                           `semembed-prompt-tokens` and `semembed-total-tokens` headers",
+            content((EmbeddingResponse = "application/json"), (String = "text/csv")),
+        ),
+        (status = 400, description = "Invalid request, or inputs over the configured limits", body = ErrorResponse),
+        (status = 404, description = "Unknown model", body = ErrorResponse),
+        (status = 413, description = "Request body too large", body = ErrorResponse),
+        (status = 500, description = "Inference failed", body = ErrorResponse),
+        (
+            status = 503,
+            description = "The model is being re-initialized, the server is over its memory limit, or a low-priority \
+                           request was shed under load; retry after `Retry-After` seconds",
+            body = ErrorResponse,
+        ),
+    ),
+)]
+async fn create_embeddings(
+    State(state): State<Arc<AppState>>,
+    scheduling: Scheduling,
+    ApiJson(req): ApiJson<EmbeddingRequest>,
+) -> Result<Json<EmbeddingResponse>, ApiError> {
+    // `output: "multi_vector"` is served by the late-interaction model instead
+    let multi_vector = match req.output {
+        OutputKind::Dense | OutputKind::Tokens => None,
+        OutputKind::MultiVector => Some(state.multi_vector.clone().ok_or_else(|| {
+            ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "invalid_request_error",
+                "multi_vector output is not enabled on this server",
+            )
+            .param("output")
+            .reason("multi_vector_disabled")
+        })?),
+    };
+
+    check_output(&req)?;
+
+    // Leaving the model to the server, or naming the routing alias, routes
+    // dense embeddings by the inputs' language. Options only the default model
+    // supports keep a request without a model on it, and fail one naming the alias.
+    let routes = state.language_routes.as_ref().filter(|routes| {
+        multi_vector.is_none() && req.output == OutputKind::Dense && routes.applies(req.model.as_deref())
+    });
+    let unroutable = [
+        (req.partial, "partial"),
+        (req.instruction.is_some(), "instruction"),
+        (req.truncation_side == Some(TruncationSide::Start), "truncation_side"),
+    ]
+    .into_iter()
+    .find_map(|(set, param)| set.then_some(param));
+    let routes = match (routes, unroutable) {
+        (Some(_), Some(param)) if req.model.is_some() => {
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "invalid_request_error",
+                format!("{} is not supported when routing by language; name a model instead", param),
+            )
+            .param(param)
+            .reason("unsupported_with_routing"));
+        }
+        (Some(_), Some(_)) => None,
+        (routes, _) => routes,
+    };
+
+    // Resolve the requested model (canonical name or alias)
+    let settings = state.settings();
+    let resolved = match &multi_vector {
+        None if routes.is_some() => settings.resolver.resolve(None),
+        Some(multi_vector) => req
+            .model
+            .as_deref()
+            .is_none_or(|model| model == multi_vector.name())
+            .then(|| ResolvedModel {
+                canonical: multi_vector.name(),
+                alias: None,
+                requested: req.model.as_deref(),
+            }),
+        None => settings.resolver.resolve(req.model.as_deref()),
+    };
+    let Some(resolved) = resolved else {
+        return Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            "model_not_found",
+            format!(
+                "The model `{}` does not exist",
+                req.model.as_deref().unwrap_or_default()
+            ),
+        ));
+    };
+    if let Some(alias) = resolved.alias {
+        state
+            .metrics
+            .alias_requests_total
+            .with_label_values(&[alias, resolved.canonical])
+            .inc();
+    }
+    // Held until the response is built
+    let _model_slot = state.model_limits.acquire(resolved.canonical)?;
+
+    // End-user attribution: recorded on the request span (and so the access log)
+    // and, when enabled, as a bounded metric label
+    let user = req.user.as_deref().map(attribution::truncate_user);
+    if let Some(user) = user {
+        tracing::Span::current().record("user", user);
+    }
+    let user_label = user.and_then(|user| state.user_labels.label(user));
+    if let Some(label) = &user_label {
+        state.metrics.user_requests_total.with_label_values(&[label]).inc();
+    }
+
+    // Extract texts from input and apply the preprocessing pipeline
+    let preprocess = state.preprocess.with_overrides(&req.preprocess);
+    let (texts, echoes) = req.input.into_texts();
+    if let Some(echoes) = echoes.as_deref() {
+        if !req.allow_duplicate_ids {
+            check_unique_ids(echoes)?;
+        }
+        check_metadata(&state, "input", echoes.iter().enumerate().map(|(index, echo)| (index, &echo.metadata)))?;
+    }
+    let truncate = req.truncate == Some(true);
+    let texts: Vec<String> = texts
+        .into_iter()
+        .enumerate()
+        .map(|(index, text)| {
+            let text = cap_input_bytes(&state, "input", index, text, truncate)?;
+            preprocess.prepare(text).map_err(|disallowed| disallowed_input("input", index, disallowed))
+        })
+        .collect::<Result<_, _>>()?;
+    #[cfg(feature = "sentry")]
+    reporting::tag_embedding_request(resolved.canonical, &texts);
+
+    if texts.is_empty() {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_request_error",
+            "Input cannot be empty",
+        )
+        .reason("empty_input"));
+    }
+    // Hashed as preprocessed, before any prefix or instruction is added
+    let audited = state.audit.as_ref().map(|audit| audit.hash_inputs(&texts));
+
+    let max_inputs = match &multi_vector {
+        Some(multi_vector) => multi_vector.max_inputs().min(state.limits.max_inputs),
+        None if req.output == OutputKind::Tokens => state.tokens_max_inputs.min(state.limits.max_inputs),
+        None => state.limits.max_inputs,
+    };
+    if texts.len() > max_inputs {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_request_error",
+            format!(
+                "Too many inputs: you can submit at most {} items per request, got {}",
+                max_inputs,
+                texts.len()
+            ),
+        )
+        .param("input")
+        .code("too_many_inputs")
+        .reason("too_many_inputs"));
+    }
+
+    // Detected once, for routing and for `detect_language`; never otherwise
+    let detections = (routes.is_some() || req.detect_language).then(|| state.languages.detect_many(&texts));
+    let languages = detections.as_deref().filter(|_| req.detect_language);
+    // The model for each input, when routed; the default model's requests
+    // go on as any other
+    let routed = routes
+        .zip(detections.as_deref())
+        .map(|(routes, detections)| routes.route(detections, resolved.canonical, req.split));
+    let leaves_default = routed.as_ref().is_some_and(|models| models.iter().any(|model| *model != resolved.canonical));
+
+    if let (None, Some(requested), false) = (&multi_vector, req.dimensions, leaves_default) {
+        if requested == 0 || requested > state.metadata.dimensions {
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "invalid_request_error",
+                format!(
+                    "dimensions must be between 1 and {} for model {}, got {}",
+                    state.metadata.dimensions, resolved.canonical, requested
+                ),
+            )
+            .param("dimensions")
+            .reason("invalid_dimensions"));
+        }
+    }
+
+    check_late_chunking(req.late_chunking)?;
+
+    // `task` selects the input type and prefix, as long as it doesn't
+    // contradict an explicit input_type
+    let selection = state
+        .model_spec
+        .select(req.task.as_deref(), req.input_type)
+        .map_err(|e| {
+            ApiError::new(StatusCode::BAD_REQUEST, "invalid_request_error", e.to_string())
+                .param("task")
+                .reason("invalid_task")
+        })?;
+    let input_type = selection.input_type;
+
+    // A task instruction only makes sense for queries. The request's own
+    // instruction wins over the model's default, which needs an explicit query.
+    let instruction = match (req.instruction.as_deref(), input_type) {
+        (Some(_), Some(InputKind::Passage)) => {
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "invalid_request_error",
+                "instruction applies to queries and cannot be combined with input_type passage",
+            )
+            .param("instruction")
+            .reason("invalid_instruction"));
+        }
+        (Some(instruction), _) => Some(instruction).filter(|instruction| !instruction.is_empty()),
+        (None, Some(InputKind::Query)) => state.instructions.get(resolved.canonical).map(String::as_str),
+        (None, _) => None,
+    };
+
+    let precision = req.precision.or(state.float_precision);
+    let matrix = matches!(req.encoding_format, EncodingFormat::Base64Matrix);
+
+    // Each kind of output admits its own token count before inference
+    let embedded = if let Some(multi_vector) = &multi_vector {
+        let (data, usage) = multi_vector_embeddings(
+            &state,
+            multi_vector.clone(),
+            texts,
+            &req.encoding_format,
+            precision,
+            req.return_token_details,
+            &scheduling,
+        )
+        .await?;
+        Embedded {
+            data: data.into_iter().map(EmbeddingItem::Embedding).collect(),
+            usage,
+            model: resolved.response_name(state.model_echo).to_string(),
+            served: ServedModel {
+                id: multi_vector.name().to_string(),
+                revision: None,
+            },
+            summary: None,
+            instruction: None,
+        }
+    } else if let Some(models) = routed.as_deref().filter(|_| leaves_default) {
+        let options = routing::Options {
+            dimensions: req.dimensions,
+            task: req.task.as_deref(),
+            input_type: req.input_type,
+            truncate: req.truncate,
+            encoding_format: &req.encoding_format,
+            precision,
+            return_token_details: req.return_token_details,
+        };
+        let (data, token_count) =
+            routing::embed(&state, texts, models, echoes.as_deref(), &options, &scheduling).await?;
+        // Reported as the model that embedded the most inputs
+        let mut counts: HashMap<&str, usize> = HashMap::new();
+        for model in models {
+            *counts.entry(model).or_insert(0) += 1;
+        }
+        let served = models.iter().copied().max_by_key(|model| counts[model]).unwrap_or(resolved.canonical);
+        Embedded {
+            data,
+            usage: Usage {
+                prompt_tokens: token_count,
+                total_tokens: token_count,
+                vector_values: None,
+            },
+            model: served.to_string(),
+            served: ServedModel {
+                id: served.to_string(),
+                revision: (served == resolved.canonical).then(|| state.model_revision.clone()).flatten(),
+            },
+            summary: None,
+            instruction: None,
+        }
+    } else {
+        let options = DenseOptions {
+            resolved: &resolved,
+            input_type,
+            prefix: selection.prefix,
+            instruction,
+            tokens: req.output == OutputKind::Tokens,
+            partial: req.partial,
+            truncate: req.truncate,
+            truncation_side: req.truncation_side,
+            dimensions: req.dimensions,
+            encoding_format: &req.encoding_format,
+            precision,
+            return_token_details: req.return_token_details,
+            routed: routed.is_some(),
+        };
+        dense_embeddings(&state, texts, &options, &scheduling).await?
+    };
+
+    // Every output is echoed, accounted for and answered the same way
+    let Embedded {
+        mut data,
+        usage,
+        model,
+        served,
+        summary,
+        instruction,
+    } = embedded;
+    for item in &mut data {
+        match item {
+            EmbeddingItem::Embedding(object) => {
+                let (id, metadata) = echoed(echoes.as_deref(), object.index);
+                object.id = id;
+                object.metadata = metadata;
+                object.detected = DetectedLanguage::of(languages, object.index);
+            }
+            EmbeddingItem::Failed(failed) => {
+                let (id, metadata) = echoed(echoes.as_deref(), failed.index);
+                failed.id = id;
+                failed.metadata = metadata;
+            }
+        }
+    }
+    // Rows of failed inputs are zeros, as wide as the request's embeddings
+    let semembed_matrix = matrix
+        .then(|| {
+            let dimensions = req.dimensions.unwrap_or(state.metadata.dimensions).min(state.metadata.dimensions);
+            EmbeddingMatrix::gather(&mut data, req.dtype.unwrap_or_default(), dimensions)
+        })
+        .transpose()?;
+
+    let token_count = usage.prompt_tokens;
+    let succeeded = data.iter().filter(|item| matches!(item, EmbeddingItem::Embedding(_))).count();
+    charge(&state, &scheduling, succeeded, token_count);
+    if let Some((audit, inputs)) = state.audit.as_ref().zip(audited) {
+        audit.record(&scheduling, &served.id, inputs, token_count);
+    }
+    if let Some(label) = &user_label {
+        state.metrics.user_tokens_total.with_label_values(&[label]).inc_by(token_count as f64);
+    }
+
+    Ok(Json(EmbeddingResponse {
+        object: "list".to_string(),
+        data,
+        model,
+        usage,
+        semembed_model: served,
+        semembed_preprocess: preprocess.steps(),
+        semembed_summary: summary,
+        semembed_instruction: instruction.map(str::to_string),
+        semembed_matrix,
+    }))
+}
+
+// What the resolved model embeds a request's inputs with
+struct DenseOptions<'a> {
+    resolved: &'a ResolvedModel<'a>,
+    input_type: Option<InputKind>,
+    prefix: Option<&'static str>,
+    instruction: Option<&'a str>,
+    // Per-token output instead of one embedding per input
+    tokens: bool,
+    partial: bool,
+    truncate: Option<bool>,
+    truncation_side: Option<TruncationSide>,
+    dimensions: Option<usize>,
+    encoding_format: &'a EncodingFormat,
+    precision: Option<u32>,
+    return_token_details: bool,
+    // Items name their model, as in a routed request
+    routed: bool,
+}
+
+// A request's inputs embedded by the model it resolved to, one embedding per
+// input or per token
+async fn dense_embeddings<'a>(
+    state: &Arc<AppState>,
+    texts: Vec<String>,
+    options: &DenseOptions<'a>,
+    scheduling: &Scheduling,
+) -> Result<Embedded<'a>, ApiError> {
+    // With `partial`, an empty input fails on its own instead of being embedded
+    let empty: Vec<bool> = texts.iter().map(|text| options.partial && text.trim().is_empty()).collect();
+
+    // Apply the instruction, or else the model's query/passage prefix if it was
+    // trained with one; never both
+    // A sampled request is embedded again by the shadow model, from the
+    // inputs without this model's prefix
+    let shadow_texts = state
+        .shadow
+        .get()
+        .filter(|shadow| !options.tokens && !options.partial && shadow.sampled())
+        .map(|_| texts.clone());
+    let (texts, prefix_len) = apply_prefix(texts, options.instruction, options.prefix);
+
+    // Count tokens with the model's own tokenizer (prefix included, after
+    // truncation). Inputs cut from the start are cut here, keeping the prefix,
+    // unless too-long inputs are to be rejected instead.
+    let tokenize = scheduling.measure(Phase::Tokenize);
+    let side = options.truncation_side.unwrap_or(state.truncation_side);
+    let (texts, counted) = if side == TruncationSide::Start && options.truncate != Some(false) {
+        truncate_start(state, texts, prefix_len).await?
+    } else {
+        count_tokens(state, texts).await?
+    };
+    drop(tokenize);
+    let token_count: usize = counted.iter().map(|count| count.tokens).sum();
+    let reject_truncated = options.truncate == Some(false);
+    let served = ServedModel {
+        id: options.resolved.canonical.to_string(),
+        revision: state.model_revision.clone(),
+    };
+    if options.tokens {
+        check_truncation(state, &counted, reject_truncated)?;
+        admit(state, scheduling, token_count)?;
+        let (data, usage) =
+            token_embeddings(state, texts, counted, options.return_token_details, scheduling).await?;
+        return Ok(Embedded {
+            data: data.into_iter().map(EmbeddingItem::Embedding).collect(),
+            usage,
+            model: options.resolved.response_name(state.model_echo).to_string(),
+            served,
+            summary: None,
+            instruction: options.instruction,
+        });
+    }
+
+    let embedded: Vec<Result<(Vec<f32>, TokenCount), ItemError>> = if options.partial {
+        // Inputs over the request limit fail one by one instead
+        quota::check(state, &scheduling.tenant, token_count)?;
+        embed_partial(state, texts, counted, empty, reject_truncated, scheduling).await?
+    } else {
+        check_truncation(state, &counted, reject_truncated)?;
+        admit(state, scheduling, token_count)?;
+        let started = Instant::now();
+        let embeddings = run_embedder(state, texts, scheduling).await?;
+        if let Some(drift) = &state.drift {
+            drift.record(&embeddings);
+        }
+        if let Some(texts) = shadow_texts {
+            shadow::Shadow::compare(state.clone(), texts, options.input_type, embeddings.clone(), started.elapsed());
+        }
+        embeddings.into_iter().zip(counted).map(Ok).collect()
+    };
+
+    // Usage covers the inputs that were embedded
+    let token_count: usize = embedded
+        .iter()
+        .filter_map(|item| item.as_ref().ok())
+        .map(|(_, count)| count.tokens)
+        .sum();
+    let summary = options.partial.then(|| {
+        let failed = embedded.iter().filter(|item| item.is_err()).count();
+        Summary {
+            succeeded: embedded.len() - failed,
+            failed,
+        }
+    });
+    scheduling.encoding();
+    let data = embedded
+        .into_iter()
+        .enumerate()
+        .map(|(index, item)| match item {
+            Ok((embedding, count)) => {
+                let embedding = match options.dimensions {
+                    Some(dimensions) if dimensions < embedding.len() => shorten(embedding, dimensions),
+                    _ => embedding,
+                };
+                EmbeddingItem::Embedding(EmbeddingObject {
+                    object: "embedding".to_string(),
+                    embedding: EmbeddingData::encode(embedding, options.encoding_format, options.precision),
+                    index,
+                    id: None,
+                    metadata: None,
+                    model: options.routed.then(|| options.resolved.canonical.to_string()),
+                    detected: None,
+                    tokens: options.return_token_details.then_some(count.tokens),
+                    truncated: options.return_token_details.then_some(count.truncated),
+                    truncation: Truncation::details(&count, options.return_token_details),
+                    shape: None,
+                    token_output: None,
+                })
+            }
+            Err(error) => EmbeddingItem::Failed(FailedItem {
+                object: "error".to_string(),
+                index,
+                id: None,
+                metadata: None,
+                error,
+            }),
+        })
+        .collect();
+    Ok(Embedded {
+        data,
+        usage: Usage {
+            prompt_tokens: token_count,
+            total_tokens: token_count,
+            vector_values: None,
+        },
+        model: options.resolved.response_name(state.model_echo).to_string(),
+        served,
+        summary,
+        instruction: options.instruction,
+    })
+}
+
+// Output options out of range, or that the requested output doesn't support
+fn check_output(req: &EmbeddingRequest) -> Result<(), ApiError> {
+    if let Some(precision) = req.precision.filter(|&precision| precision > MAX_FLOAT_PRECISION) {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_request_error",
+            format!("precision must be between 0 and {}, got {}", MAX_FLOAT_PRECISION, precision),
+        )
+        .param("precision")
+        .reason("invalid_precision"));
+    }
+    if req.dtype.is_some() && !matches!(req.encoding_format, EncodingFormat::Base64Matrix) {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_request_error",
+            "dtype applies to encoding_format \"base64_matrix\"",
+        )
+        .param("dtype")
+        .reason("invalid_dtype"));
+    }
+    match req.output {
+        OutputKind::Dense => {}
+        OutputKind::Tokens => {
+            // A float matrix per input would be several times the size of the base64
+            if !matches!(req.encoding_format, EncodingFormat::Base64) {
+                return Err(ApiError::new(
+                    StatusCode::BAD_REQUEST,
+                    "invalid_request_error",
+                    "tokens output requires encoding_format \"base64\"",
+                )
+                .param("encoding_format")
+                .reason("invalid_encoding_format"));
+            }
+            if req.partial {
+                return Err(ApiError::new(
+                    StatusCode::BAD_REQUEST,
+                    "invalid_request_error",
+                    "partial is not supported with tokens output",
+                )
+                .param("partial")
+                .reason("invalid_partial"));
+            }
+            if req.dimensions.is_some() {
+                return Err(ApiError::new(
+                    StatusCode::BAD_REQUEST,
+                    "invalid_request_error",
+                    "dimensions is not supported with tokens output",
+                )
+                .param("dimensions")
+                .reason("invalid_dimensions"));
+            }
+        }
+        OutputKind::MultiVector => {
+            if matches!(req.encoding_format, EncodingFormat::Base64Matrix) {
+                return Err(ApiError::new(
+                    StatusCode::BAD_REQUEST,
+                    "invalid_request_error",
+                    "encoding_format \"base64_matrix\" is not supported with multi_vector output",
+                )
+                .param("encoding_format")
+                .reason("invalid_encoding_format"));
+            }
+            if req.instruction.is_some() {
+                return Err(ApiError::new(
+                    StatusCode::BAD_REQUEST,
+                    "invalid_request_error",
+                    "instruction is not supported with multi_vector output",
+                )
+                .param("instruction")
+                .reason("invalid_instruction"));
+            }
+            if req.partial {
+                return Err(ApiError::new(
+                    StatusCode::BAD_REQUEST,
+                    "invalid_request_error",
+                    "partial is not supported with multi_vector output",
+                )
+                .param("partial")
+                .reason("invalid_partial"));
+            }
+            if req.dimensions.is_some() {
+                return Err(ApiError::new(
+                    StatusCode::BAD_REQUEST,
+                    "invalid_request_error",
+                    "dimensions is not supported with multi_vector output",
+                )
+                .param("dimensions")
+                .reason("invalid_dimensions"));
+            }
+            if req.truncate == Some(false) {
+                return Err(ApiError::new(
+                    StatusCode::BAD_REQUEST,
+                    "invalid_request_error",
+                    "truncate is not supported with multi_vector output",
+                )
+                .param("truncate")
+                .reason("invalid_truncate"));
+            }
+            if req.truncation_side == Some(TruncationSide::Start) {
+                return Err(ApiError::new(
+                    StatusCode::BAD_REQUEST,
+                    "invalid_request_error",
+                    "truncation_side start is not supported with multi_vector output",
+                )
+                .param("truncation_side")
+                .reason("invalid_truncation_side"));
+            }
+        }
+    }
+    Ok(())
+}
+
+// `{id, text}` inputs must not share an id, unless the request allows it
+fn check_unique_ids(echoes: &[Echo]) -> Result<(), ApiError> {
+    let mut seen = HashSet::with_capacity(echoes.len());
+    let mut duplicates: Vec<String> = Vec::new();
+    for echo in echoes {
+        let id = echo.id.to_string();
+        if !seen.insert(id.clone()) && !duplicates.contains(&id) {
+            duplicates.push(id);
+        }
+    }
+    if duplicates.is_empty() {
+        return Ok(());
+    }
+    let more = duplicates.len().saturating_sub(10);
+    duplicates.truncate(10);
+    Err(ApiError::new(
+        StatusCode::BAD_REQUEST,
+        "invalid_request_error",
+        format!(
+            "Input ids must be unique; these appear more than once: {}{}. Set allow_duplicate_ids to accept them",
+            duplicates.join(", "),
+            if more > 0 { format!(" and {} more", more) } else { String::new() }
+        ),
+    )
+    .param("input")
+    .code("duplicate_ids")
+    .reason("duplicate_ids"))
+}
+
+// The id and metadata of input `index`, for `{id, text}` inputs
+fn echoed(echoes: Option<&[Echo]>, index: usize) -> (Option<serde_json::Value>, Option<serde_json::Value>) {
+    match echoes.and_then(|echoes| echoes.get(index)) {
+        Some(echo) => (Some(echo.id.clone()), echo.metadata.clone()),
+        None => (None, None),
+    }
+}
+
+// The size of metadata as it is echoed back
+fn metadata_bytes(metadata: &serde_json::Value) -> usize {
+    serde_json::to_vec(metadata).map_or(0, |bytes| bytes.len())
+}
+
+/// Why one item's metadata is refused: over SEMEMBED_MAX_METADATA_BYTES.
+fn oversized_metadata(state: &AppState, metadata: &serde_json::Value) -> Option<String> {
+    let bytes = metadata_bytes(metadata);
+    let limit = state.limits.max_metadata_bytes;
+    (bytes > limit).then(|| format!("metadata is {} bytes, over the limit of {} bytes per input", bytes, limit))
+}
+
+/// Check the metadata of a request's items against the per-item and
+/// per-request limits, naming the item that goes over.
+fn check_metadata<'a>(
+    state: &AppState,
+    param: &'static str,
+    items: impl Iterator<Item = (usize, &'a Option<serde_json::Value>)>,
+) -> Result<(), ApiError> {
+    let too_large = |message: String| {
+        ApiError::new(StatusCode::BAD_REQUEST, "invalid_request_error", message)
+            .param(param)
+            .code("metadata_too_large")
+            .reason("metadata_too_large")
+    };
+    let mut total = 0;
+    for (index, metadata) in items {
+        let Some(metadata) = metadata else {
+            continue;
+        };
+        if let Some(why) = oversized_metadata(state, metadata) {
+            return Err(too_large(format!("{}[{}].{}", param, index, why)));
+        }
+        total += metadata_bytes(metadata);
+        if total > state.limits.max_metadata_total_bytes {
+            return Err(too_large(format!(
+                "{}[{}].metadata takes the request's metadata to {} bytes, over the limit of {} bytes per request",
+                param, index, total, state.limits.max_metadata_total_bytes
+            )));
+        }
+    }
+    Ok(())
+}
+
+// Embed the inputs of a `partial` request. Inputs are admitted in order while
+// they fit the request's token budget; an input that is empty, doesn't fit or
+// fails inference gets an error in its place.
+async fn embed_partial(
+    state: &AppState,
+    texts: Vec<String>,
+    counted: Vec<TokenCount>,
+    empty: Vec<bool>,
+    reject_truncated: bool,
+    scheduling: &Scheduling,
+) -> Result<Vec<Result<(Vec<f32>, TokenCount), ItemError>>, ApiError> {
+    let max_tokens = state.limits.max_tokens_per_request;
+    let mut budget = max_tokens;
+    let admitted: Vec<Result<TokenCount, ItemError>> = counted
+        .into_iter()
+        .zip(empty)
+        .map(|(count, empty)| {
+            if empty {
+                return Err(ItemError {
+                    message: "Input cannot be empty".to_string(),
+                    code: "empty_input",
+                });
+            }
+            if reject_truncated && count.truncated {
+                return Err(ItemError {
+                    message: format!("Input is {}", too_long(state)),
+                    code: "input_too_long",
+                });
+            }
+            if count.tokens > budget {
+                return Err(ItemError {
+                    message: format!(
+                        "Too many tokens: the input has {} tokens but only {} of the request's {} remain",
+                        count.tokens, budget, max_tokens
+                    ),
+                    code: "max_tokens_per_request",
+                });
+            }
+            budget -= count.tokens;
+            Ok(count)
+        })
+        .collect();
+    let texts = texts
+        .into_iter()
+        .zip(&admitted)
+        .filter_map(|(text, count)| count.is_ok().then_some(text))
+        .collect();
+    let mut embeddings = run_embedder_partial(state, texts, scheduling).await?.into_iter();
+    Ok(admitted
+        .into_iter()
+        .map(|count| {
+            let count = count?;
+            let embedding = embeddings.next().unwrap_or_else(|| Ok(Vec::new()))?;
+            Ok((embedding, count))
+        })
+        .collect())
+}
+
+// Why an input is rejected when truncation is disabled
+fn too_long(state: &AppState) -> String {
+    format!(
+        "longer than the model's maximum of {} tokens and truncation is disabled",
+        state.metadata.max_tokens
+    )
+}
+
+// Fetch each URL, extract its text, chunk it to the model's context and embed
+// every chunk. A URL that can't be fetched or has no text is reported in its
+// own entry; only request-level problems fail the whole call.
+async fn create_url_embeddings(
+    State(state): State<Arc<AppState>>,
+    scheduling: Scheduling,
+    ApiJson(req): ApiJson<UrlEmbeddingRequest>,
+) -> Result<Json<UrlEmbeddingResponse>, ApiError> {
+    let Some(fetcher) = state.fetcher.clone() else {
+        return Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            "invalid_request_error",
+            "URL fetching is not enabled on this server",
+        ));
+    };
+
+    req.encoding_format.check_per_item()?;
+    let settings = state.settings();
+
+    let resolved = resolve_model(&state, &settings, req.model.as_deref())?;
+    let _model_slot = state.model_limits.acquire(resolved.canonical)?;
+
+    let urls: Vec<String> = match req.input_url {
+        UrlInput::Single(url) => vec![url],
+        UrlInput::Batch(urls) => urls,
+    };
+    if urls.is_empty() {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_request_error",
+            "input_url cannot be empty",
+        )
+        .param("input_url")
+        .reason("empty_input"));
+    }
+    let max_urls = fetcher.config().max_urls;
+    if urls.len() > max_urls {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_request_error",
+            format!(
+                "Too many URLs: you can submit at most {} per request, got {}",
+                max_urls,
+                urls.len()
+            ),
+        )
+        .param("input_url")
+        .code("too_many_inputs")
+        .reason("too_many_inputs"));
+    }
+
+    // Fetch concurrently; the fetcher's own timeout bounds each one
+    let mut fetches = JoinSet::new();
+    for (index, url) in urls.iter().enumerate() {
+        let fetcher = fetcher.clone();
+        let url = url.clone();
+        let trace = scheduling.trace.clone();
+        fetches.spawn(async move { (index, fetcher.fetch_text(&url, trace.as_ref()).await) });
+    }
+    let mut documents: Vec<Result<String, ItemError>> = urls
+        .iter()
+        .map(|_| {
+            Err(ItemError {
+                message: "fetch did not complete".to_string(),
+                code: "fetch_failed",
+            })
+        })
+        .collect();
+    while let Some(joined) = fetches.join_next().await {
+        if let Ok((index, fetched)) = joined {
+            documents[index] = fetched.map_err(|e| ItemError {
+                message: e.message,
+                code: e.code,
+            });
+        }
+    }
+
+    // Preprocess, then split into chunks that fit alongside the model's prefix
+    let preprocess = state.preprocess.with_overrides(&req.preprocess);
+    let prefix = state.model_spec.prefix(req.input_type.or(Some(InputKind::Passage)));
+    let mut documents: Vec<Result<String, ItemError>> = documents
+        .into_iter()
+        .map(|document| {
+            let document = document?;
+            if document.len() > state.limits.max_input_bytes {
+                return Err(ItemError {
+                    message: format!(
+                        "The document's text is {} bytes, over the limit of {} bytes",
+                        document.len(),
+                        state.limits.max_input_bytes
+                    ),
+                    code: "input_too_large",
+                });
+            }
+            let text = preprocess.prepare(document).map_err(|disallowed| ItemError {
+                message: format!("The document contains a disallowed {}", disallowed),
+                code: "disallowed_character",
+            })?;
+            if text.trim().is_empty() {
+                return Err(ItemError {
+                    message: "The document contains no text".to_string(),
+                    code: "empty_document",
+                });
+            }
+            Ok(text)
+        })
+        .collect();
+    let texts = documents
+        .iter_mut()
+        .filter_map(|document| document.as_mut().ok().map(std::mem::take))
+        .collect();
+    let mut chunked = chunk_texts(&state, texts, None, CHUNK_OVERLAP, prefix).await?.into_iter();
+    let documents: Vec<Result<Vec<String>, ItemError>> = documents
+        .into_iter()
+        .map(|document| document.map(|_| chunked.next().unwrap_or_default()))
+        .collect();
+
+    let chunks = documents.iter().filter_map(|document| document.as_ref().ok()).flatten().cloned().collect();
+    let (embeddings, token_count) = embed_chunks(&state, chunks, prefix, "input_url", &scheduling).await?;
+    let mut embeddings = embeddings.into_iter();
+
+    // Regroup the flat list of embeddings by the URL each chunk came from
+    let data = urls
+        .into_iter()
+        .zip(documents)
+        .enumerate()
+        .map(|(index, (url, document))| match document {
+            Ok(chunks) => UrlEmbeddings {
+                index,
+                url,
+                chunks: chunks
+                    .iter()
+                    .zip(embeddings.by_ref())
+                    .enumerate()
+                    .map(|(index, (_, embedding))| EmbeddingObject {
+                        object: "embedding".to_string(),
+                        embedding: EmbeddingData::encode(embedding, &req.encoding_format, state.float_precision),
+                        index,
+                        id: None,
+                        metadata: None,
+                        model: None,
+                        detected: None,
+                        tokens: None,
+                        truncated: None,
+                        truncation: None,
+                        shape: None,
+                        token_output: None,
+                    })
+                    .collect(),
+                error: None,
+            },
+            Err(error) => UrlEmbeddings {
+                index,
+                url,
+                chunks: Vec::new(),
+                error: Some(error),
+            },
+        })
+        .collect();
+
+    Ok(Json(UrlEmbeddingResponse {
+        object: "list".to_string(),
+        data,
+        model: resolved.response_name(state.model_echo).to_string(),
+        usage: Usage {
+            prompt_tokens: token_count,
+            total_tokens: token_count,
+            vector_values: None,
+        },
+        semembed_model: ServedModel {
+            id: resolved.canonical.to_string(),
+            revision: state.model_revision.clone(),
+        },
+    }))
+}
+
+// Embed uploaded text files: each file (or each value of the chosen CSV
+// column) is chunked to the model's context and every chunk is returned with
+// its filename, row and chunk position.
+async fn create_file_embeddings(
+    State(state): State<Arc<AppState>>,
+    scheduling: Scheduling,
+    multipart: Result<Multipart, MultipartRejection>,
+) -> Result<Json<FileEmbeddingResponse>, ApiError> {
+    let multipart = multipart.map_err(|_| {
+        ApiError::new(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "invalid_request_error",
+            "Expected request with `Content-Type: multipart/form-data`",
+        )
+        .reason("unsupported_media_type")
+    })?;
+    let form = UploadForm::read(multipart, state.upload).await?;
+    if form.files.is_empty() {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_request_error",
+            "Upload at least one file",
+        )
+        .param("file")
+        .reason("empty_input"));
+    }
+
+    let settings = state.settings();
+
+    let resolved = resolve_model(&state, &settings, form.text("model"))?;
+    let _model_slot = state.model_limits.acquire(resolved.canonical)?;
+
+    let encoding_format: EncodingFormat = form.field_enum("encoding_format")?.unwrap_or_default();
+    encoding_format.check_per_item()?;
+    let input_type: Option<InputKind> = form.field_enum("input_type")?;
+    let latin1: bool = form.field("latin1")?.unwrap_or(false);
+    let csv_column = form.text("csv_column");
+    let chunk_tokens: Option<usize> = form.field("chunk_tokens")?;
+    let chunk_overlap: usize = form.field("chunk_overlap")?.unwrap_or(CHUNK_OVERLAP);
+    if let Some(chunk_tokens) = chunk_tokens {
+        if chunk_tokens == 0 || chunk_tokens > state.model_spec.max_tokens {
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "invalid_request_error",
+                format!(
+                    "chunk_tokens must be between 1 and {} for model {}, got {}",
+                    state.model_spec.max_tokens, resolved.canonical, chunk_tokens
+                ),
+            )
+            .param("chunk_tokens")
+            .reason("invalid_chunking"));
+        }
+    }
+    let chunk_size = chunk_tokens.unwrap_or(state.model_spec.max_tokens);
+    if chunk_overlap >= chunk_size {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_request_error",
+            format!("chunk_overlap must be less than the chunk size ({}), got {}", chunk_size, chunk_overlap),
+        )
+        .param("chunk_overlap")
+        .reason("invalid_chunking"));
+    }
+
+    // Decode every file and split CSVs into rows; each piece is chunked on its own
+    let preprocess = state.preprocess;
+    let mut sources: Vec<(usize, Option<usize>)> = Vec::new();
+    let mut texts = Vec::new();
+    for (file_index, file) in form.files.iter().enumerate() {
+        let text = upload::decode(file, latin1)?;
+        let pieces = match (file.kind, csv_column) {
+            (FileKind::Csv, Some(column)) => upload::csv_column(&file.filename, &text, column)?
+                .into_iter()
+                .map(|(row, value)| (Some(row), value))
+                .collect(),
+            _ => vec![(None, text)],
+        };
+        for (row, piece) in pieces {
+            let location = || match row {
+                Some(row) => format!("row {} of {}", row, file.filename),
+                None => file.filename.clone(),
+            };
+            if piece.len() > state.limits.max_input_bytes {
+                return Err(ApiError::new(
+                    StatusCode::BAD_REQUEST,
+                    "invalid_request_error",
+                    format!(
+                        "The text of {} is {} bytes, over the limit of {} bytes per input",
+                        location(),
+                        piece.len(),
+                        state.limits.max_input_bytes
+                    ),
+                )
+                .param("file")
+                .code("input_too_large")
+                .reason("input_too_large"));
+            }
+            let piece = preprocess.prepare(piece).map_err(|disallowed| {
+                ApiError::new(
+                    StatusCode::BAD_REQUEST,
+                    "invalid_request_error",
+                    format!("{} contains a disallowed {}", location(), disallowed),
+                )
+                .param("file")
+                .code("disallowed_character")
+                .reason("disallowed_character")
+            })?;
+            if !piece.trim().is_empty() {
+                sources.push((file_index, row));
+                texts.push(piece);
+            }
+        }
+    }
+    if texts.is_empty() {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_request_error",
+            "The uploaded files contain no text",
+        )
+        .param("file")
+        .reason("empty_input"));
+    }
+
+    let prefix = state.model_spec.prefix(input_type.or(Some(InputKind::Passage)));
+    let chunked = chunk_texts(&state, texts, chunk_tokens, chunk_overlap, prefix).await?;
+    let provenance: Vec<(usize, Option<usize>, usize)> = sources
+        .iter()
+        .zip(&chunked)
+        .flat_map(|(&(file_index, row), chunks)| (0..chunks.len()).map(move |chunk| (file_index, row, chunk)))
+        .collect();
+    let (embeddings, token_count) =
+        embed_chunks(&state, chunked.into_iter().flatten().collect(), prefix, "file", &scheduling).await?;
+
+    let data = embeddings
+        .into_iter()
+        .zip(provenance)
+        .enumerate()
+        .map(|(index, (embedding, (file_index, row, chunk)))| FileEmbeddingObject {
+            embedding: EmbeddingObject {
+                object: "embedding".to_string(),
+                embedding: EmbeddingData::encode(embedding, &encoding_format, state.float_precision),
+                index,
+                id: None,
+                metadata: None,
+                model: None,
+                detected: None,
+                tokens: None,
+                truncated: None,
+                truncation: None,
+                shape: None,
+                token_output: None,
+            },
+            filename: form.files[file_index].filename.clone(),
+            row,
+            chunk,
+        })
+        .collect();
+
+    Ok(Json(FileEmbeddingResponse {
+        object: "list".to_string(),
+        data,
+        model: resolved.response_name(state.model_echo).to_string(),
+        usage: Usage {
+            prompt_tokens: token_count,
+            total_tokens: token_count,
+            vector_values: None,
+        },
+        semembed_model: ServedModel {
+            id: resolved.canonical.to_string(),
+            revision: state.model_revision.clone(),
+        },
+    }))
+}
+
+// Cluster texts by their embeddings with k-means, for finding the themes in a
+// set of snippets. Each cluster comes with the texts closest to its centroid.
+async fn create_clusters(
+    State(state): State<Arc<AppState>>,
+    scheduling: Scheduling,
+    ApiJson(req): ApiJson<ClusterRequest>,
+) -> Result<Json<ClusterResponse>, ApiError> {
+    let settings = state.settings();
+
+    let resolved = resolve_model(&state, &settings, req.model.as_deref())?;
+    let _model_slot = state.model_limits.acquire(resolved.canonical)?;
+
+    let inputs = req.input;
+    if inputs.is_empty() {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_request_error",
+            "Input cannot be empty",
+        )
+        .param("input")
+        .reason("empty_input"));
+    }
+    let max_inputs = state.cluster_max_inputs.min(state.limits.max_inputs);
+    if inputs.len() > max_inputs {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_request_error",
+            format!(
+                "Too many inputs: you can cluster at most {} texts per request, got {}",
+                max_inputs,
+                inputs.len()
+            ),
+        )
+        .param("input")
+        .code("too_many_inputs")
+        .reason("too_many_inputs"));
+    }
+    let k = match req.k {
+        ClusterCount::Fixed(k) if k == 0 || k > inputs.len().min(MAX_CLUSTERS) => {
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "invalid_request_error",
+                format!(
+                    "k must be between 1 and {} for {} inputs, got {}",
+                    inputs.len().min(MAX_CLUSTERS),
+                    inputs.len(),
+                    k
+                ),
+            )
+            .param("k")
+            .reason("invalid_clustering"));
+        }
+        ClusterCount::Fixed(k) => Some(k),
+        ClusterCount::Auto(_) => None,
+    };
+    let max_iter = req.max_iter.unwrap_or(100);
+    if max_iter == 0 || max_iter > MAX_CLUSTER_ITERATIONS {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_request_error",
+            format!("max_iter must be between 1 and {}, got {}", MAX_CLUSTER_ITERATIONS, max_iter),
+        )
+        .param("max_iter")
+        .reason("invalid_clustering"));
+    }
+    let examples = req.examples.unwrap_or(3).min(MAX_CLUSTER_EXAMPLES);
+    let config = KMeansConfig {
+        metric: req.metric,
+        max_iter,
+        seed: req.seed,
+    };
+
+    let preprocess = state.preprocess.with_overrides(&req.preprocess);
+    let texts = inputs
+        .iter()
+        .enumerate()
+        .map(|(index, input)| {
+            let input = cap_input_bytes(&state, "input", index, input.clone(), false)?;
+            preprocess.prepare(input).map_err(|disallowed| disallowed_input("input", index, disallowed))
+        })
+        .collect::<Result<_, _>>()?;
+    let prefix = state.model_spec.prefix(req.input_type);
+    let (embeddings, token_count) = embed_chunks(&state, texts, prefix, "input", &scheduling).await?;
+
+    // Auto picks k from a small range by silhouette, which needs 2 <= k < n
+    let (points, clustering) = tokio::task::spawn_blocking(move || {
+        let points = cluster::prepare(embeddings, config.metric);
+        let clustering = match k {
+            Some(k) => cluster::kmeans(&points, k, config),
+            None if points.len() < 3 => cluster::kmeans(&points, 1, config),
+            None => cluster::auto_kmeans(&points, 2..=AUTO_MAX_CLUSTERS.min(points.len() - 1), config),
+        };
+        (points, clustering)
+    })
+    .await
+    .map_err(|e| {
+        error!("Clustering failed: {}", e);
+        ApiError::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "internal_error",
+            "Failed to cluster the embeddings",
+        )
+        .reason("clustering_failed")
+    })?;
+
+    let clusters = (0..clustering.centroids.len())
+        .map(|index| ClusterObject {
+            index,
+            size: clustering.assignments.iter().filter(|&&cluster| cluster == index).count(),
+            examples: clustering
+                .representatives(&points, index, examples, config.metric)
+                .into_iter()
+                .map(|(index, distance)| ClusterExample {
+                    index,
+                    text: inputs[index].clone(),
+                    distance,
+                })
+                .collect(),
+            centroid: clustering.centroids[index].clone(),
+        })
+        .collect();
+
+    Ok(Json(ClusterResponse {
+        object: "cluster_result".to_string(),
+        k: clustering.centroids.len(),
+        assignments: clustering.assignments,
+        clusters,
+        silhouette: clustering.silhouette,
+        iterations: clustering.iterations,
+        model: resolved.response_name(state.model_echo).to_string(),
+        usage: Usage {
+            prompt_tokens: token_count,
+            total_tokens: token_count,
+            vector_values: None,
+        },
+        semembed_model: ServedModel {
+            id: resolved.canonical.to_string(),
+            revision: state.model_revision.clone(),
+        },
+    }))
+}
+
+// Find groups of near-duplicate texts by the cosine similarity of their
+// embeddings.
+async fn find_duplicates(
+    State(state): State<Arc<AppState>>,
+    scheduling: Scheduling,
+    ApiJson(req): ApiJson<DedupRequest>,
+) -> Result<Json<DedupResponse>, ApiError> {
+    let settings = state.settings();
+
+    let resolved = resolve_model(&state, &settings, req.model.as_deref())?;
+    let _model_slot = state.model_limits.acquire(resolved.canonical)?;
+
+    let inputs = req.input;
+    if inputs.is_empty() {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_request_error",
+            "Input cannot be empty",
+        )
+        .param("input")
+        .reason("empty_input"));
+    }
+    let max_inputs = state.dedup_max_inputs.min(state.limits.max_inputs);
+    if inputs.len() > max_inputs {
+        let n = inputs.len();
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_request_error",
+            format!(
+                "Too many inputs: deduplication compares every pair of inputs, so its cost grows with the square \
+                 of the input count ({} inputs would be {} comparisons). Submit at most {} per request, got {}",
+                n,
+                n * (n - 1) / 2,
+                max_inputs,
+                n
+            ),
+        )
+        .param("input")
+        .code("too_many_inputs")
+        .reason("too_many_inputs"));
+    }
+    let threshold = req.threshold.unwrap_or(0.95);
+    if !(0.0..1.0).contains(&threshold) {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_request_error",
+            format!("threshold must be at least 0 and less than 1, got {}", threshold),
+        )
+        .param("threshold")
+        .reason("invalid_threshold"));
+    }
+
+    let preprocess = state.preprocess.with_overrides(&req.preprocess);
+    let texts = inputs
+        .iter()
+        .enumerate()
+        .map(|(index, input)| {
+            let input = cap_input_bytes(&state, "input", index, input.clone(), false)?;
+            preprocess.prepare(input).map_err(|disallowed| disallowed_input("input", index, disallowed))
+        })
+        .collect::<Result<_, _>>()?;
+    let prefix = state.model_spec.prefix(req.input_type);
+    let (embeddings, token_count) = embed_chunks(&state, texts, prefix, "input", &scheduling).await?;
+
+    let representative = req.representative;
+    let (inputs, groups) = tokio::task::spawn_blocking(move || {
+        let groups = dedup::groups(embeddings, threshold, &inputs, representative);
+        (inputs, groups)
+    })
+    .await
+    .map_err(|e| {
+        error!("Deduplication failed: {}", e);
+        ApiError::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "internal_error",
+            "Failed to compare the embeddings",
+        )
+        .reason("dedup_failed")
+    })?;
+
+    let duplicates: usize = groups.iter().map(|group| group.indices.len() - 1).sum();
+    Ok(Json(DedupResponse {
+        object: "dedup_result".to_string(),
+        unique: inputs.len() - duplicates,
+        groups: groups
+            .into_iter()
+            .map(|group| DedupGroup {
+                representative: group.representative,
+                indices: group.indices,
+                max_similarity: group.max_similarity,
+            })
+            .collect(),
+        model: resolved.response_name(state.model_echo).to_string(),
+        usage: Usage {
+            prompt_tokens: token_count,
+            total_tokens: token_count,
+            vector_values: None,
+        },
+        semembed_model: ServedModel {
+            id: resolved.canonical.to_string(),
+            revision: state.model_revision.clone(),
+        },
+    }))
+}
+
+// The weighted mean of the inputs' embeddings, such as a profile vector
+// from a user's recent queries, without sending the vectors back
+async fn create_centroid(
+    State(state): State<Arc<AppState>>,
+    scheduling: Scheduling,
+    ApiJson(req): ApiJson<CentroidRequest>,
+) -> Result<Json<CentroidResponse>, ApiError> {
+    req.encoding_format.check_per_item()?;
+    let settings = state.settings();
+
+    let resolved = resolve_model(&state, &settings, req.model.as_deref())?;
+    let _model_slot = state.model_limits.acquire(resolved.canonical)?;
+
+    if req.inputs.is_empty() {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_request_error",
+            "inputs cannot be empty",
+        )
+        .param("inputs")
+        .reason("empty_input"));
+    }
+    if req.inputs.len() > state.limits.max_inputs {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_request_error",
+            format!("At most {} inputs are averaged per request, got {}", state.limits.max_inputs, req.inputs.len()),
+        )
+        .param("inputs")
+        .code("too_many_inputs")
+        .reason("too_many_inputs"));
+    }
+    if let Some(weights) = &req.weights {
+        centroid::check_weights(weights, req.inputs.len()).map_err(|e| {
+            ApiError::new(StatusCode::BAD_REQUEST, "invalid_request_error", e.to_string())
+                .param("weights")
+                .reason("invalid_weights")
+        })?;
+    }
+    if let Some(precision) = req.precision.filter(|&precision| precision > MAX_FLOAT_PRECISION) {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_request_error",
+            format!("precision must be between 0 and {}, got {}", MAX_FLOAT_PRECISION, precision),
+        )
+        .param("precision")
+        .reason("invalid_precision"));
+    }
+
+    let preprocess = state.preprocess.with_overrides(&req.preprocess);
+    let texts = req
+        .inputs
+        .into_iter()
+        .enumerate()
+        .map(|(index, input)| {
+            let input = cap_input_bytes(&state, "inputs", index, input, false)?;
+            let input = preprocess.prepare(input).map_err(|disallowed| disallowed_input("inputs", index, disallowed))?;
+            if input.trim().is_empty() {
+                return Err(ApiError::new(
+                    StatusCode::BAD_REQUEST,
+                    "invalid_request_error",
+                    format!("inputs[{}] cannot be empty", index),
+                )
+                .param("inputs")
+                .reason("empty_input"));
+            }
+            Ok(input)
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let count = texts.len();
+    let prefix = state.model_spec.prefix(req.input_type);
+    // Embedded as one batch, under the usual token limit
+    let (embeddings, token_count) = embed_chunks(&state, texts, prefix, "inputs", &scheduling).await?;
+
+    let norms = centroid::norms(&embeddings);
+    let mean = centroid::weighted_mean(&embeddings, req.weights.as_deref(), req.normalize.unwrap_or(true));
+    let precision = req.precision.or(state.float_precision);
+    Ok(Json(CentroidResponse {
+        object: "centroid".to_string(),
+        embedding: EmbeddingData::encode(mean, &req.encoding_format, precision),
+        count,
+        norms,
+        model: resolved.response_name(state.model_echo).to_string(),
+        usage: Usage {
+            prompt_tokens: token_count,
+            total_tokens: token_count,
+            vector_values: None,
+        },
+        semembed_model: ServedModel {
+            id: resolved.canonical.to_string(),
+            revision: state.model_revision.clone(),
+        },
+    }))
+}
+
+// Score every text of `a` against every text of `b`, such as queries against
+// documents for an evaluation, embedding both sets in one request
+async fn similarity_matrix(
+    State(state): State<Arc<AppState>>,
+    scheduling: Scheduling,
+    ApiJson(req): ApiJson<SimilarityRequest>,
+) -> Result<Json<SimilarityResponse>, ApiError> {
+    if let Some(format) = &req.encoding_format {
+        format.check_per_item()?;
+    }
+    let settings = state.settings();
+
+    let resolved = resolve_model(&state, &settings, req.model.as_deref())?;
+    let _model_slot = state.model_limits.acquire(resolved.canonical)?;
+
+    for (param, texts) in [("a", &req.a), ("b", &req.b)] {
+        if texts.is_empty() {
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "invalid_request_error",
+                format!("{} cannot be empty", param),
+            )
+            .param(param)
+            .reason("empty_input"));
+        }
+    }
+    let shape = [req.a.len(), req.b.len()];
+    let cells = shape[0].saturating_mul(shape[1]);
+    if cells > state.similarity_max_cells {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_request_error",
+            format!(
+                "The matrix would have {} × {} = {} scores, over the limit of {}; split a or b into smaller requests",
+                shape[0], shape[1], cells, state.similarity_max_cells
+            ),
+        )
+        .param("b")
+        .code("matrix_too_large")
+        .reason("matrix_too_large"));
+    }
+    if let Some(precision) = req.precision.filter(|&precision| precision > MAX_FLOAT_PRECISION) {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_request_error",
+            format!("precision must be between 0 and {}, got {}", MAX_FLOAT_PRECISION, precision),
+        )
+        .param("precision")
+        .reason("invalid_precision"));
+    }
+
+    // Each set gets its own prefix, so both are prefixed here and embedded
+    // together as one batch
+    let preprocess = state.preprocess.with_overrides(&req.preprocess);
+    let sets = [
+        ("a", req.a, req.a_type.unwrap_or(InputKind::Query)),
+        ("b", req.b, req.b_type.unwrap_or(InputKind::Passage)),
+    ];
+    let mut texts = Vec::with_capacity(shape[0] + shape[1]);
+    for (param, set, kind) in sets {
+        let prefix = state.model_spec.prefix(Some(kind)).unwrap_or_default();
+        for (index, text) in set.into_iter().enumerate() {
+            let text = cap_input_bytes(&state, param, index, text, false)?;
+            let text = preprocess.prepare(text).map_err(|disallowed| disallowed_input(param, index, disallowed))?;
+            if text.trim().is_empty() {
+                return Err(ApiError::new(
+                    StatusCode::BAD_REQUEST,
+                    "invalid_request_error",
+                    format!("{}[{}] cannot be empty", param, index),
+                )
+                .param(param)
+                .reason("empty_input"));
+            }
+            texts.push(format!("{}{}", prefix, text));
+        }
+    }
+    if texts.len() > state.limits.max_inputs {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_request_error",
+            format!(
+                "Too many inputs: a and b together can be at most {} per request, got {}",
+                state.limits.max_inputs,
+                texts.len()
+            ),
+        )
+        .param("b")
+        .code("too_many_inputs")
+        .reason("too_many_inputs"));
+    }
+    let (mut embeddings, token_count) = embed_chunks(&state, texts, None, "b", &scheduling).await?;
+
+    let metric = req.metric.unwrap_or(state.metadata.distance);
+    let b = embeddings.split_off(shape[0]);
+    let rows = tokio::task::spawn_blocking(move || similarity::matrix(embeddings, b, metric))
+        .await
+        .map_err(|e| {
+            error!("Similarity matrix failed: {}", e);
+            ApiError::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "internal_error",
+                "Failed to compare the embeddings",
+            )
+            .reason("similarity_failed")
+        })?;
+    let format = req.encoding_format.unwrap_or(if cells > SIMILARITY_PACK_CELLS {
+        EncodingFormat::Base64
+    } else {
+        EncodingFormat::Float
+    });
+    let precision = req.precision.or(state.float_precision);
+    Ok(Json(SimilarityResponse {
+        object: "similarity_matrix".to_string(),
+        metric,
+        shape,
+        data: EmbeddingData::encode_matrix(rows, &format, precision),
+        model: resolved.response_name(state.model_echo).to_string(),
+        usage: Usage {
+            prompt_tokens: token_count,
+            total_tokens: token_count,
+            vector_values: None,
+        },
+        semembed_model: ServedModel {
+            id: resolved.canonical.to_string(),
+            revision: state.model_revision.clone(),
+        },
+    }))
+}
+
+// Zero-shot classification: score every input against every label by the
+// similarity of their embeddings. Inputs are embedded as passages and labels
+// (their description, or else their name) as queries looking for them.
+async fn classify_texts(
+    State(state): State<Arc<AppState>>,
+    scheduling: Scheduling,
+    ApiJson(req): ApiJson<ClassifyRequest>,
+) -> Result<Json<ClassifyResponse>, ApiError> {
+    let settings = state.settings();
+
+    let resolved = resolve_model(&state, &settings, req.model.as_deref())?;
+    let _model_slot = state.model_limits.acquire(resolved.canonical)?;
+
+    if req.inputs.is_empty() {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_request_error",
+            "inputs cannot be empty",
+        )
+        .param("inputs")
+        .reason("empty_input"));
+    }
+    if req.labels.is_empty() || req.labels.len() > MAX_LABELS {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_request_error",
+            format!("labels must have between 1 and {} entries, got {}", MAX_LABELS, req.labels.len()),
+        )
+        .param("labels")
+        .reason("invalid_labels"));
+    }
+    let mut names = HashSet::new();
+    if let Some(label) = req.labels.iter().find(|label| label.name.trim().is_empty() || !names.insert(&label.name)) {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_request_error",
+            format!("label names must be non-empty and unique, got {:?}", label.name),
+        )
+        .param("labels")
+        .reason("invalid_labels"));
+    }
+    let scoring = Scoring {
+        multi_label: req.multi_label,
+        temperature: req.temperature.unwrap_or(0.05),
+        midpoint: req.midpoint.unwrap_or(0.5),
+    };
+    if !(scoring.temperature > 0.0 && scoring.temperature.is_finite()) {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_request_error",
+            format!("temperature must be a positive number, got {}", scoring.temperature),
+        )
+        .param("temperature")
+        .reason("invalid_temperature"));
+    }
+
+    // One embedding per distinct label text, even if several labels share it
+    let preprocess = state.preprocess.with_overrides(&req.preprocess);
+    let mut label_texts: Vec<String> = Vec::new();
+    let label_slots: Vec<usize> = req
+        .labels
+        .iter()
+        .enumerate()
+        .map(|(index, label)| {
+            let text = label.description.clone().unwrap_or_else(|| label.name.clone());
+            let text = cap_input_bytes(&state, "labels", index, text, false)?;
+            let text = preprocess.prepare(text).map_err(|disallowed| disallowed_input("labels", index, disallowed))?;
+            let text = format!("{}{}", state.model_spec.prefix(Some(InputKind::Query)).unwrap_or_default(), text);
+            Ok(label_texts.iter().position(|known| *known == text).unwrap_or_else(|| {
+                label_texts.push(text);
+                label_texts.len() - 1
+            }))
+        })
+        .collect::<Result<_, ApiError>>()?;
+    let input_count = req.inputs.len();
+    if input_count + label_texts.len() > state.limits.max_inputs {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_request_error",
+            format!(
+                "Too many inputs: inputs and distinct labels together can be at most {} per request, got {}",
+                state.limits.max_inputs,
+                input_count + label_texts.len()
+            ),
+        )
+        .param("inputs")
+        .code("too_many_inputs")
+        .reason("too_many_inputs"));
+    }
+    let passage_prefix = state.model_spec.prefix(Some(InputKind::Passage)).unwrap_or_default();
+    let texts = req
+        .inputs
+        .into_iter()
+        .enumerate()
+        .map(|(index, input)| {
+            let text = cap_input_bytes(&state, "inputs", index, input, false)?;
+            let text = preprocess.prepare(text).map_err(|disallowed| disallowed_input("inputs", index, disallowed))?;
+            Ok(format!("{}{}", passage_prefix, text))
+        })
+        .chain(label_texts.into_iter().map(Ok))
+        .collect::<Result<_, ApiError>>()?;
+    let (mut embeddings, token_count) = embed_chunks(&state, texts, None, "inputs", &scheduling).await?;
+    let mut label_embeddings = embeddings.split_off(input_count);
+    label_embeddings.iter_mut().for_each(|embedding| cluster::normalize(embedding));
+
+    let data = embeddings
+        .into_iter()
+        .enumerate()
+        .map(|(index, embedding)| {
+            let similarities = classify::similarities(embedding, &label_embeddings);
+            let similarities: Vec<f32> = label_slots.iter().map(|&slot| similarities[slot]).collect();
+            let scores = classify::scores(&similarities, scoring);
+            Classification {
+                index,
+                label: req.labels[classify::argmax(&scores)].name.clone(),
+                scores: req
+                    .labels
+                    .iter()
+                    .zip(scores.into_iter().zip(similarities))
+                    .map(|(label, (score, similarity))| LabelScore {
+                        label: label.name.clone(),
+                        score,
+                        similarity,
+                    })
+                    .collect(),
+            }
+        })
+        .collect();
+
+    Ok(Json(ClassifyResponse {
+        object: "list".to_string(),
+        data,
+        model: resolved.response_name(state.model_echo).to_string(),
+        usage: Usage {
+            prompt_tokens: token_count,
+            total_tokens: token_count,
+            vector_values: None,
+        },
+        semembed_model: ServedModel {
+            id: resolved.canonical.to_string(),
+            revision: state.model_revision.clone(),
+        },
+    }))
+}
+
+// Fill the embedding cache from a JSONL warm file of `{text}` or
+// `{text, embedding, model}` records, such as one written by /cache/dump.
+// Stored embeddings are loaded as they are; texts without one (or with one
+// from a different model) are embedded at low priority.
+async fn warm_cache(state: Arc<AppState>, path: String) -> anyhow::Result<()> {
+    let content = tokio::fs::read_to_string(&path)
+        .await
+        .map_err(|e| anyhow::anyhow!("failed to read cache warm file {}: {}", path, e))?;
+    let dimensions = state.metadata.dimensions;
+    let (mut loaded, mut invalid, mut other_model) = (0, 0, 0);
+    let mut pending = Vec::new();
+    for (number, line) in content.lines().enumerate().filter(|(_, line)| !line.trim().is_empty()) {
+        // Dumps list the most recently used entries first; the rest would only evict them
+        if loaded + pending.len() >= state.cache.capacity() {
+            info!("Cache warm file has more entries than the cache holds; ignoring the rest");
+            break;
+        }
+        let record: WarmRecord = match serde_json::from_str(line) {
+            Ok(record) => record,
+            Err(e) => {
+                if invalid == 0 {
+                    warn!("Skipping invalid line {} of {}: {}", number + 1, path, e);
+                }
+                invalid += 1;
+                continue;
+            }
+        };
+        if state.cache.contains(&record.text) {
+            continue;
+        }
+        match record.embedding {
+            Some(embedding)
+                if record.model.as_deref() == Some(state.cache.model()) && embedding.len() == dimensions =>
+            {
+                state.cache.insert_many([(record.text.as_str(), embedding.as_slice())]);
+                loaded += 1;
+            }
+            Some(_) => {
+                other_model += 1;
+                pending.push(record.text);
+            }
+            None => pending.push(record.text),
+        }
+    }
+    if other_model > 0 {
+        warn!(
+            "{} cache warm entries were embedded by a different model (or have the wrong dimensions); re-embedding their texts",
+            other_model
+        );
+    }
+    info!(
+        "Cache warm-up: {} entries loaded from {}, {} texts to embed",
+        loaded,
+        path,
+        pending.len()
+    );
+
+    let scheduling = Scheduling {
+        priority: Some(Priority::Low),
+        tenant: Tenant::system(),
+        timing: None,
+        access: None,
+        request_id: None,
+        trace: None,
+    };
+    let total = pending.len();
+    let mut embedded = 0;
+    for batch in pending.chunks(CACHE_WARM_BATCH) {
+        embed_cached(&state, batch.to_vec(), &scheduling)
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to embed cache warm entries: {}", e))?;
+        embedded += batch.len();
+        if embedded % (CACHE_WARM_BATCH * 16) == 0 {
+            info!("Cache warm-up: embedded {}/{}", embedded, total);
+        }
+    }
+    info!(
+        "Cache warm-up finished: {} loaded, {} embedded, {} invalid lines skipped; cache holds {} entries",
+        loaded,
+        embedded,
+        invalid,
+        state.cache.len()
+    );
+    Ok(())
+}
+
+// The embedding cache as a JSONL warm file, most recently used entries first
+async fn dump_cache(State(state): State<Arc<AppState>>) -> Response {
+    let mut body = String::new();
+    for record in state.cache.dump() {
+        match serde_json::to_string(&record) {
+            Ok(line) => {
+                body.push_str(&line);
+                body.push('\n');
+            }
+            Err(e) => error!("Failed to serialize cache entry: {}", e),
+        }
+    }
+    ([(header::CONTENT_TYPE, "application/x-ndjson")], body).into_response()
+}
+
+// Resolve a request's `model` (canonical name or alias), counting alias use
+fn resolve_model<'a>(
+    state: &AppState,
+    settings: &'a Settings,
+    requested: Option<&'a str>,
+) -> Result<ResolvedModel<'a>, ApiError> {
+    let Some(resolved) = settings.resolver.resolve(requested) else {
+        return Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            "model_not_found",
+            format!("The model `{}` does not exist", requested.unwrap_or_default()),
+        ));
+    };
+    if let Some(alias) = resolved.alias {
+        state
+            .metrics
+            .alias_requests_total
+            .with_label_values(&[alias, resolved.canonical])
+            .inc();
+    }
+    Ok(resolved)
+}
+
+// Split each text into chunks that fit the model's context once its prefix is
+// added, on the blocking pool. `chunk_tokens` lowers the chunk size below the
+// model's limit.
+async fn chunk_texts(
+    state: &Arc<AppState>,
+    texts: Vec<String>,
+    chunk_tokens: Option<usize>,
+    overlap: usize,
+    prefix: Option<&'static str>,
+) -> Result<Vec<Vec<String>>, ApiError> {
+    let state = state.clone();
+    tokio::task::spawn_blocking(move || -> anyhow::Result<Vec<Vec<String>>> {
+        let chunker = &state.chunker;
+        let budget = state
+            .model_spec
+            .max_tokens
+            .saturating_sub(prefix.map_or(Ok(0), |prefix| chunker.count(prefix))?);
+        let budget = chunk_tokens.map_or(budget, |chunk_tokens| chunk_tokens.min(budget));
+        texts.iter().map(|text| chunker.chunk(text, budget, overlap)).collect()
+    })
+    .await
+    .map_err(|e| anyhow::anyhow!(e))
+    .and_then(|chunked| chunked)
+    .map_err(|e| {
+        error!("Failed to chunk documents: {}", e);
+        ApiError::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "internal_error",
+            format!("Failed to tokenize inputs: {}", e),
+        )
+        .reason("tokenization_failed")
+    })
+}
+
+// Embed the chunks of one or more documents, with the request limits applied
+// to the chunk count. Returns the embeddings and the tokens used.
+async fn embed_chunks(
+    state: &AppState,
+    chunks: Vec<String>,
+    prefix: Option<&'static str>,
+    param: &'static str,
+    scheduling: &Scheduling,
+) -> Result<(Vec<Vec<f32>>, usize), ApiError> {
+    if chunks.len() > state.limits.max_inputs {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_request_error",
+            format!(
+                "Too many chunks: the documents split into {} chunks, at most {} can be embedded per request",
+                chunks.len(),
+                state.limits.max_inputs
+            ),
+        )
+        .param(param)
+        .code("too_many_inputs")
+        .reason("too_many_inputs"));
+    }
+    if chunks.is_empty() {
+        return Ok((Vec::new(), 0));
+    }
+    let texts: Vec<String> = match prefix {
+        Some(prefix) => chunks.into_iter().map(|chunk| format!("{}{}", prefix, chunk)).collect(),
+        None => chunks,
+    };
+    let (texts, counted) = count_tokens(state, texts).await?;
+    let token_count: usize = counted.iter().map(|count| count.tokens).sum();
+    admit(state, scheduling, token_count)?;
+    let embeddings = run_embedder(state, texts, scheduling).await?;
+    charge(state, scheduling, embeddings.len(), token_count);
+    Ok((embeddings, token_count))
+}
+
+// Token counts for each input, computed on the blocking pool. Returns the
+// texts back alongside their counts.
+async fn count_tokens(state: &AppState, texts: Vec<String>) -> Result<(Vec<String>, Vec<TokenCount>), ApiError> {
+    count_model_tokens(state.embedder.clone(), texts).await
+}
+
+// The same with another model's tokenizer
+async fn count_model_tokens(
+    embedder: Arc<Embedder>,
+    texts: Vec<String>,
+) -> Result<(Vec<String>, Vec<TokenCount>), ApiError> {
+    tokio::task::spawn_blocking(move || {
+        let counts = embedder.count_tokens(&texts);
+        (texts, counts)
+    })
+    .await
+    .map_err(|e| anyhow::anyhow!(e))
+    .and_then(|(texts, counts)| counts.map(|counts| (texts, counts)))
+    .map_err(|e| {
+        error!("Failed to tokenize inputs: {}", e);
+        ApiError::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "internal_error",
+            format!("Failed to tokenize inputs: {}", e),
+        )
+        .reason("tokenization_failed")
+    })
+}
+
+// Cut inputs too long for the model from their start, keeping their first
+// `keep` bytes, and count what is left; each count reports what was cut
+async fn truncate_start(
+    state: &AppState,
+    texts: Vec<String>,
+    keep: usize,
+) -> Result<(Vec<String>, Vec<TokenCount>), ApiError> {
+    let embedder = state.embedder.clone();
+    tokio::task::spawn_blocking(move || {
+        let cut = embedder.truncate_start(texts, keep)?;
+        let (texts, dropped): (Vec<String>, Vec<usize>) = cut.into_iter().unzip();
+        let counts = embedder.count_tokens(&texts)?;
+        let counts = counts
+            .into_iter()
+            .zip(dropped)
+            .map(|(count, dropped)| match dropped {
+                0 => count,
+                dropped => TokenCount {
+                    truncated: true,
+                    dropped,
+                    side: TruncationSide::Start,
+                    ..count
+                },
+            })
+            .collect();
+        Ok::<_, anyhow::Error>((texts, counts))
+    })
+    .await
+    .map_err(|e| anyhow::anyhow!(e))
+    .and_then(|cut| cut)
+    .map_err(|e| {
+        error!("Failed to tokenize inputs: {}", e);
+        ApiError::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "internal_error",
+            format!("Failed to tokenize inputs: {}", e),
+        )
+        .reason("tokenization_failed")
+    })
+}
+
+// The texts the model embeds: each input behind the instruction, or else the
+// prefix its task selected, and the length of what was put in front
+fn apply_prefix(texts: Vec<String>, instruction: Option<&str>, prefix: Option<&str>) -> (Vec<String>, usize) {
+    let prefix = match instruction {
+        Some(instruction) => Some(models::instruction_prefix(instruction)),
+        None => prefix.map(str::to_string),
+    };
+    let prefix_len = prefix.as_ref().map_or(0, String::len);
+    let texts = match prefix {
+        Some(prefix) => texts.into_iter().map(|t| format!("{}{}", prefix, t)).collect(),
+        None => texts,
+    };
+    (texts, prefix_len)
+}
+
+// Jina's late chunking is refused rather than ignored; `false` is what every
+// request gets anyway
+fn check_late_chunking(late_chunking: Option<bool>) -> Result<(), ApiError> {
+    if late_chunking == Some(true) {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_request_error",
+            "late_chunking is not supported; send documents pre-chunked, or through /v1/embeddings/url to have them chunked",
+        )
+        .param("late_chunking")
+        .code("unsupported_parameter")
+        .reason("unsupported_parameter"));
+    }
+    Ok(())
+}
+
+// With `truncate: false`, the first input that was cut fails the request
+fn check_truncation(state: &AppState, counted: &[TokenCount], reject_truncated: bool) -> Result<(), ApiError> {
+    match counted.iter().position(|count| reject_truncated && count.truncated) {
+        Some(index) => Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_request_error",
+            format!("Input {} is {}", index, too_long(state)),
+        )
+        .param("input")
+        .code("input_too_long")
+        .reason("input_too_long")),
+        None => Ok(()),
+    }
+}
+
+fn check_token_limit(state: &AppState, token_count: usize) -> Result<(), ApiError> {
+    if token_count > state.limits.max_tokens_per_request {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_request_error",
+            format!(
+                "Too many tokens: requests can contain at most {} tokens in total, got {}",
+                state.limits.max_tokens_per_request, token_count
+            ),
+        )
+        .param("input")
+        .code("max_tokens_per_request")
+        .reason("too_many_tokens"));
+    }
+    Ok(())
+}
+
+// Admit a batch of `token_count` tokens before it is embedded: within the
+// request limit and the tenant's quota
+fn admit(state: &AppState, scheduling: &Scheduling, token_count: usize) -> Result<(), ApiError> {
+    check_token_limit(state, token_count)?;
+    quota::check(state, &scheduling.tenant, token_count)
+}
+
+// Charge `inputs` embedded with `token_count` tokens to the processed-tokens
+// metric, the tenant's usage and the request's access log line
+fn charge(state: &AppState, scheduling: &Scheduling, inputs: usize, token_count: usize) {
+    state.metrics.tokens_processed.inc_by(token_count as f64);
+    state.usage.add_tokens(&scheduling.tenant, token_count);
+    scheduling.embedded(inputs, token_count);
+}
+
+// Generate embeddings on the blocking pool so inference doesn't stall the async
+// workers. Each batch first waits (asynchronously) for its turn in the queue.
+//
+// When the client disconnects, the server drops the handler and with it this
+// future: a batch still waiting leaves the queue, no further sub-batches are
+// started, and a batch whose turn came up just as the client left is skipped.
+// Only a batch already running on the model finishes.
+async fn run_embedder(
+    state: &AppState,
+    texts: Vec<String>,
+    scheduling: &Scheduling,
+) -> Result<Vec<Vec<f32>>, ApiError> {
+    embed_cached(state, texts, scheduling).await.map_err(inference_error)
+}
+
+// Like run_embedder, but a failing batch only fails its own inputs: its inputs
+// are retried one at a time so the ones that can be embedded still are. An open
+// circuit still fails the whole request.
+async fn run_embedder_partial(
+    state: &AppState,
+    texts: Vec<String>,
+    scheduling: &Scheduling,
+) -> Result<Vec<Result<Vec<f32>, ItemError>>, ApiError> {
+    if texts.is_empty() {
+        return Ok(Vec::new());
+    }
+    let count = texts.len();
+    match embed_cached(state, texts.clone(), scheduling).await {
+        Ok(embeddings) => return Ok(embeddings.into_iter().map(Ok).collect()),
+        Err(e @ EmbedError::Unavailable(_)) => return Err(inference_error(e)),
+        Err(e) if count == 1 => return Ok(vec![Err(ItemError::inference(&e))]),
+        Err(e) => warn!("Batch of {} inputs failed ({}), retrying them one at a time", count, e),
+    }
+    let mut results = Vec::with_capacity(count);
+    for text in texts {
+        match embed_cached(state, vec![text], scheduling).await {
+            // Empty only when the client left, so the result is never sent
+            Ok(mut embedding) => results.push(Ok(embedding.pop().unwrap_or_default())),
+            Err(e @ EmbedError::Unavailable(_)) => return Err(inference_error(e)),
+            Err(e) => results.push(Err(ItemError::inference(&e))),
+        }
+    }
+    Ok(results)
+}
+
+// Answer what the cache holds, queue the rest for inference and remember it
+async fn embed_cached(
+    state: &AppState,
+    texts: Vec<String>,
+    scheduling: &Scheduling,
+) -> Result<Vec<Vec<f32>>, EmbedError> {
+    if !state.cache.enabled() {
+        return embed_queued(state, texts, scheduling).await;
+    }
+    let cached = state.cache.get_many(&texts);
+    let missing: Vec<String> = texts
+        .into_iter()
+        .zip(&cached)
+        .filter(|(_, cached)| cached.is_none())
+        .map(|(text, _)| text)
+        .collect();
+    let embedded = if missing.is_empty() {
+        Vec::new()
+    } else {
+        embed_queued(state, missing.clone(), scheduling).await?
+    };
+    if embedded.len() == missing.len() {
+        state
+            .cache
+            .insert_many(missing.iter().map(String::as_str).zip(embedded.iter().map(Vec::as_slice)));
+    }
+    let mut embedded = embedded.into_iter();
+    Ok(cached
+        .into_iter()
+        .map(|cached| cached.or_else(|| embedded.next()).unwrap_or_default())
+        .collect())
+}
+
+async fn embed_queued(
+    state: &AppState,
+    texts: Vec<String>,
+    scheduling: &Scheduling,
+) -> Result<Vec<Vec<f32>>, EmbedError> {
+    let priority = scheduling.priority.unwrap_or(state.default_priority);
+    let timer = state.metrics.inference_duration.with_label_values(&[priority.as_str()]).start_timer();
+    let batch_size = state.queue.batch_size(priority);
+    let cancel = CancellationToken::new();
+    let mut abandoned = Abandoned {
+        cancel: cancel.clone(),
+        cancelled: &state.metrics.inference_cancelled,
+        stage: "queued",
+        finished: false,
+    };
+    let embedded = async {
+        let mut embeddings = Vec::with_capacity(texts.len());
+        let mut texts = texts.into_iter().peekable();
+        while texts.peek().is_some() {
+            let batch: Vec<String> = texts.by_ref().take(batch_size).collect();
+            abandoned.stage = "queued";
+            let queued = scheduling.measure(Phase::Queue);
+            let turn = state.queue.acquire(priority, &scheduling.tenant, batch.len()).await;
+            drop(queued);
+            abandoned.stage = "running";
+            let embedder = state.embedder.clone();
+            let cancel = cancel.clone();
+            let infer = scheduling.measure(Phase::Infer);
+            let batch = tokio::task::spawn_blocking(move || {
+                let _turn = turn;
+                let _infer = infer;
+                if cancel.is_cancelled() {
+                    // Nobody is waiting for the result
+                    return Ok(Vec::new());
+                }
+                embedder.embed(batch.iter().map(String::as_str).collect(), &cancel)
+            })
+            .await
+            .unwrap_or_else(|e| Err(EmbedError::Inference(e.into())))?;
+            embeddings.extend(batch);
+        }
+        Ok(embeddings)
+    }
+    .await;
+    abandoned.finished = true;
+    timer.observe_duration();
+    if let Err(e @ (EmbedError::Inference(_) | EmbedError::Panicked(_))) = &embedded {
+        state.health.report("model", e);
+    }
+    embedded
+}
+
+// An input over SEMEMBED_MAX_INPUT_BYTES is rejected, or with `truncate` cut
+// at the last character boundary within the limit
+fn cap_input_bytes(
+    state: &AppState,
+    param: &'static str,
+    index: usize,
+    mut text: String,
+    truncate: bool,
+) -> Result<String, ApiError> {
+    let limit = state.limits.max_input_bytes;
+    if text.len() <= limit {
+        return Ok(text);
+    }
+    if truncate {
+        let mut end = limit;
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        text.truncate(end);
+        return Ok(text);
+    }
+    Err(ApiError::new(
+        StatusCode::BAD_REQUEST,
+        "invalid_request_error",
+        format!("{}[{}] is {} bytes, over the limit of {} bytes per input", param, index, text.len(), limit),
+    )
+    .param(param)
+    .code("input_too_large")
+    .reason("input_too_large"))
+}
+
+// An input the `reject` sanitization policy refuses; `param` names the field
+fn disallowed_input(param: &'static str, index: usize, disallowed: Disallowed) -> ApiError {
+    ApiError::new(
+        StatusCode::BAD_REQUEST,
+        "invalid_request_error",
+        format!("{}[{}] contains a disallowed {}", param, index, disallowed),
+    )
+    .param(param)
+    .code("disallowed_character")
+    .reason("disallowed_character")
+}
+
+fn inference_error(e: EmbedError) -> ApiError {
+    match e {
+        EmbedError::Unavailable(retry_after) => ApiError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "service_unavailable",
+            "The model is being re-initialized after repeated failures; retry shortly",
+        )
+        .retry_after(retry_after.as_secs().max(1))
+        .reason("circuit_open"),
+        e => {
+            error!("Failed to generate embeddings: {}", e);
+            let reason = if matches!(e, EmbedError::Panicked(_)) {
+                "inference_panicked"
+            } else {
+                "inference_failed"
+            };
+            ApiError::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "internal_error",
+                format!("Failed to generate embeddings: {}", e),
+            )
+            .reason(reason)
+        }
+    }
+}
+
+// Cancels the rest of a request's inference if it is dropped before finishing
+struct Abandoned<'a> {
+    cancel: CancellationToken,
+    cancelled: &'a CounterVec,
+    stage: &'static str,
+    finished: bool,
+}
+
+impl Drop for Abandoned<'_> {
+    fn drop(&mut self) {
+        if self.finished {
+            return;
+        }
+        self.cancel.cancel();
+        self.cancelled.with_label_values(&[self.stage]).inc();
+    }
+}
+
+// The dense model's token states before pooling, with the pooled embedding
+// of each input. Bypasses the cache, which only holds pooled embeddings, and
+// runs as one batch: tokens_max_inputs keeps it small.
+async fn token_embeddings(
+    state: &AppState,
+    texts: Vec<String>,
+    counted: Vec<TokenCount>,
+    return_token_details: bool,
+    scheduling: &Scheduling,
+) -> Result<(Vec<EmbeddingObject>, Usage), ApiError> {
+    let priority = scheduling.priority.unwrap_or(state.default_priority);
+    let timer = state.metrics.inference_duration.with_label_values(&[priority.as_str()]).start_timer();
+    let turn = state.queue.acquire(priority, &scheduling.tenant, texts.len()).await;
+    let embedder = state.embedder.clone();
+    let embedded = tokio::task::spawn_blocking(move || {
+        let _turn = turn;
+        embedder.embed_tokens(texts.iter().map(String::as_str).collect())
+    })
+    .await
+    .unwrap_or_else(|e| Err(EmbedError::Inference(e.into())))
+    .map_err(inference_error)?;
+    timer.observe_duration();
+
+    let pooling = state.embedder.pooling();
+    let mut usage = Usage {
+        prompt_tokens: 0,
+        total_tokens: 0,
+        vector_values: Some(0),
+    };
+    let data = embedded
+        .into_iter()
+        .zip(counted)
+        .enumerate()
+        .map(|(index, (output, count))| {
+            let shape = [output.vectors.len(), output.vectors.first().map_or(0, Vec::len)];
+            usage.prompt_tokens += count.tokens;
+            usage.vector_values = usage.vector_values.map(|values| values + shape[0] * shape[1]);
+            EmbeddingObject {
+                object: "embedding".to_string(),
+                embedding: EmbeddingData::encode_matrix(output.vectors, &EncodingFormat::Base64, None),
+                index,
+                id: None,
+                metadata: None,
+                model: None,
+                detected: None,
+                tokens: Some(count.tokens),
+                truncated: return_token_details.then_some(count.truncated),
+                truncation: Truncation::details(&count, return_token_details),
+                shape: Some(shape),
+                token_output: Some(TokenOutput {
+                    token_ids: output.ids,
+                    token_strings: output.tokens,
+                    pooled: EmbeddingData::encode(output.pooled, &EncodingFormat::Base64, None),
+                    pooling,
+                }),
+            }
+        })
+        .collect();
+    usage.total_tokens = usage.prompt_tokens;
+    Ok((data, usage))
+}
+
+// Per-token embeddings from the late-interaction model. Token limits are
+// enforced against its own tokenizer, which differs from the dense model's.
+// The batch waits for its turn on the inference queue like dense ones.
+async fn multi_vector_embeddings(
+    state: &AppState,
+    multi_vector: Arc<MultiVector>,
+    texts: Vec<String>,
+    encoding_format: &EncodingFormat,
+    precision: Option<u32>,
+    return_token_details: bool,
+    scheduling: &Scheduling,
+) -> Result<(Vec<EmbeddingObject>, Usage), ApiError> {
+    // The inputs are counted with the model's own tokenizer once it has them,
+    // so the quota caps the batch along with the request limit
+    let max_tokens = state.limits.max_tokens_per_request;
+    let allowance = quota::allowance(state, &scheduling.tenant);
+    let budget = allowance
+        .remaining()
+        .map_or(max_tokens, |remaining| max_tokens.min(usize::try_from(remaining).unwrap_or(usize::MAX)));
+    let priority = scheduling.priority.unwrap_or(state.default_priority);
+    let timer = state.metrics.inference_duration.with_label_values(&[priority.as_str()]).start_timer();
+    let cancel = CancellationToken::new();
+    let mut abandoned = Abandoned {
+        cancel: cancel.clone(),
+        cancelled: &state.metrics.inference_cancelled,
+        stage: "queued",
+        finished: false,
+    };
+    let queued = scheduling.measure(Phase::Queue);
+    let turn = state.queue.acquire(priority, &scheduling.tenant, texts.len()).await;
+    drop(queued);
+    abandoned.stage = "running";
+    let infer = scheduling.measure(Phase::Infer);
+    let embedded = tokio::task::spawn_blocking(move || {
+        let _turn = turn;
+        let _infer = infer;
+        if cancel.is_cancelled() {
+            // Nobody is waiting for the result
+            return Err(MultiVectorError::Failed(anyhow::anyhow!("request abandoned")));
+        }
+        multi_vector.embed(&texts, budget)
+    })
+    .await
+    .unwrap_or_else(|e| Err(MultiVectorError::Failed(e.into())));
+    abandoned.finished = true;
+    timer.observe_duration();
+    let output = match embedded {
+        Ok(output) => output,
+        Err(MultiVectorError::Unavailable(retry_after)) => {
+            return Err(ApiError::new(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "service_unavailable",
+                "The multi-vector model is being reloaded after repeated failures; retry shortly",
+            )
+            .retry_after(retry_after.as_secs().max(1))
+            .reason("circuit_open"));
+        }
+        Err(MultiVectorError::TooManyTokens(token_count)) => {
+            if token_count <= max_tokens {
+                allowance.check(token_count)?;
+            }
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "invalid_request_error",
+                format!(
+                    "Too many tokens: requests can contain at most {} tokens in total, got {}",
+                    max_tokens, token_count
+                ),
+            )
+            .param("input")
+            .code("max_tokens_per_request")
+            .reason("too_many_tokens"));
+        }
+        Err(MultiVectorError::Failed(e)) => {
+            error!("Failed to generate multi-vector embeddings: {}", e);
+            return Err(ApiError::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "internal_error",
+                format!("Failed to generate embeddings: {}", e),
+            )
+            .reason("inference_failed"));
+        }
+    };
+
+    let mut usage = Usage {
+        prompt_tokens: 0,
+        total_tokens: 0,
+        vector_values: Some(0),
+    };
+    let data = output
+        .vectors
+        .into_iter()
+        .zip(output.counts)
+        .enumerate()
+        .map(|(index, (matrix, count))| {
+            let shape = [matrix.len(), matrix.first().map_or(0, Vec::len)];
+            usage.prompt_tokens += count.tokens;
+            usage.vector_values = usage.vector_values.map(|values| values + shape[0] * shape[1]);
+            EmbeddingObject {
+                object: "embedding".to_string(),
+                embedding: EmbeddingData::encode_matrix(matrix, encoding_format, precision),
+                index,
+                id: None,
+                metadata: None,
+                model: None,
+                detected: None,
+                tokens: Some(count.tokens),
+                truncated: return_token_details.then_some(count.truncated),
+                truncation: Truncation::details(&count, return_token_details),
+                shape: Some(shape),
+                token_output: None,
+            }
+        })
+        .collect();
+    usage.total_tokens = usage.prompt_tokens;
+    Ok((data, usage))
+}
+
+// The route a request matched, as its pattern (`/v1/models/:model`) rather
+// than the raw path, so metric labels take one value per route. Requests
+// matching none share `unmatched`.
+fn endpoint_label(req: &Request) -> String {
+    req.extensions()
+        .get::<MatchedPath>()
+        .map_or_else(|| "unmatched".to_string(), |path| path.as_str().to_string())
+}
+
+// Counts and times every request by endpoint, so a new route is covered
+// without its handler doing anything. It is also the only place request
+// latency enters the window, once per request even when an adapter embeds
+// in several calls.
+async fn count_requests(State(state): State<Arc<AppState>>, req: Request, next: Next) -> Response {
+    let endpoint = endpoint_label(&req);
+    state.metrics.requests_total.with_label_values(&[&endpoint]).inc();
+    let timer = state.metrics.request_duration.with_label_values(&[&endpoint]).start_timer();
+    let response = next.run(req).await;
+    let elapsed = timer.stop_and_record();
+    if response.status().is_success() && TIMED.contains(&endpoint.as_str()) {
+        state.latency.record(elapsed);
+    }
+    if !NOT_TRAFFIC.contains(&endpoint.as_str()) {
+        state.health.record_response(response.status().is_server_error());
+    }
+    response
+}
+
+fn count_error(state: &AppState, endpoint: &str, status: StatusCode, reason: &str) {
+    let status_class = if status.is_server_error() { "5xx" } else { "4xx" };
+    state.metrics.errors_total.with_label_values(&[endpoint, status_class, reason]).inc();
+}
+
+// Single place where error responses are counted, given the request and
+// trace ids, and where bare router rejections (unknown path, wrong method)
+// are turned into ErrorResponse JSON.
+async fn json_errors(State(state): State<Arc<AppState>>, req: Request, next: Next) -> Response {
+    let method = req.method().clone();
+    let path = req.uri().path().to_string();
+    let endpoint = endpoint_label(&req);
+    let request_id = req
+        .extensions()
+        .get::<access_log::RequestId>()
+        .map(|id| id.0.clone())
+        .or_else(|| access_log::client_request_id(req.headers()));
+    let trace_id = req.extensions().get::<TraceContext>().map(|context| context.trace_id.clone());
+    let response = next.run(req).await;
+
+    if let Some(ErrorReason(reason)) = response.extensions().get::<ErrorReason>().copied() {
+        count_error(&state, &endpoint, response.status(), reason);
+        return error::add_ids(response, request_id, trace_id).await;
+    }
+
+    let error = match response.status() {
+        StatusCode::NOT_FOUND => ApiError::new(
+            StatusCode::NOT_FOUND,
+            "invalid_request_error",
+            format!("Unknown request URL: {} {}", method, path),
+        )
+        .reason("not_found"),
+        StatusCode::METHOD_NOT_ALLOWED => ApiError::new(
+            StatusCode::METHOD_NOT_ALLOWED,
+            "invalid_request_error",
+            format!("Method {} is not allowed for {}", method, path),
+        )
+        .reason("method_not_allowed"),
+        StatusCode::UNSUPPORTED_MEDIA_TYPE => ApiError::new(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "invalid_request_error",
+            "Expected request with `Content-Type: application/json`",
+        )
+        .reason("unsupported_media_type"),
+        _ => return response,
+    };
+
+    let mut rewritten = error.into_response();
+    if let Some(allow) = response.headers().get(header::ALLOW) {
+        rewritten.headers_mut().insert(header::ALLOW, allow.clone());
+    }
+    if let Some(ErrorReason(reason)) = rewritten.extensions().get::<ErrorReason>() {
+        count_error(&state, &endpoint, rewritten.status(), reason);
+    }
+    error::add_ids(rewritten, request_id, trace_id).await
+}
+
+// Truncate an embedding and re-normalize it to unit length
+fn shorten(mut embedding: Vec<f32>, dimensions: usize) -> Vec<f32> {
+    embedding.truncate(dimensions);
+    let norm = embedding.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        embedding.iter_mut().for_each(|x| *x /= norm);
+    }
+    embedding
+}
+
+// Require `Authorization: Bearer <SEMEMBED_METRICS_TOKEN>` when a token is configured
+async fn require_metrics_token(
+    State(state): State<Arc<AppState>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let settings = state.settings();
+    let Some(expected) = settings.metrics_token.as_deref() else {
+        return next.run(req).await;
+    };
+
+    let provided = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match provided {
+        Some(token) if constant_time_eq(token.as_bytes(), expected.as_bytes()) => {
+            next.run(req).await
+        }
+        _ => {
+            let mut response = ApiError::new(
+                StatusCode::UNAUTHORIZED,
+                "invalid_request_error",
+                "A valid bearer token is required to access this endpoint",
+            )
+            .reason("unauthorized")
+            .into_response();
+            response
+                .headers_mut()
+                .insert(header::WWW_AUTHENTICATE, header::HeaderValue::from_static("Bearer"));
+            response
+        }
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Readiness
+#[utoipa::path(
+    get,
+    path = "/health",
+    operation_id = "health",
+    responses(
+        (status = 200, description = "Serving", body = HealthResponse),
+        (status = 503, description = "The model is being re-initialized, or a load threshold tripped", body = HealthResponse),
+    ),
+)]
+async fn health_check(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    // The instance is as healthy as its worst component. Unhealthy (the
+    // circuit is open, or the instance is saturated) takes it out of rotation
+    // so load balancers route around it; degraded only flags the problem,
+    // unless SEMEMBED_READY_FAIL_DEGRADED is set. Only reads: the health
+    // watchdog records errors and checks the sink
+    let readiness = state.settings().readiness;
+    let mut components = BTreeMap::new();
+    let model = if state.embedder.ready() { Status::Healthy } else { Status::Unhealthy };
+    components.insert("model", state.health.component("model", model));
+    let saturation = saturation(&state);
+    let queue = if saturation.is_some() { Status::Unhealthy } else { Status::Healthy };
+    components.insert("inference_queue", state.health.component("inference_queue", queue));
+    let errors = if error_excess(&state).is_some() { Status::Degraded } else { Status::Healthy };
+    components.insert("errors", state.health.component("errors", errors));
+    if state.sinks.default_sink().is_some() {
+        components.insert("sink", state.health.sink());
+    }
+    components.extend(state.health.workers());
+
+    let status = components.values().map(|component| component.status).max().unwrap_or(Status::Healthy);
+    let code = match status {
+        Status::Healthy => StatusCode::OK,
+        Status::Degraded if !readiness.fail_degraded => StatusCode::OK,
+        Status::Degraded | Status::Unhealthy => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    (
+        code,
+        Json(HealthResponse {
+            status,
+            model: state.model_name.clone(),
+            dimensions: state.metadata.dimensions,
+            components,
+            saturation,
+            loading: state.loading.names(),
+        }),
+    )
+}
+
+// How far the recent server-error rate is over SEMEMBED_READY_MAX_ERROR_RATE
+fn error_excess(state: &AppState) -> Option<String> {
+    let limit = state.settings().readiness.max_error_rate?;
+    let rate = state.health.error_rate().filter(|&rate| rate > limit)?;
+    Some(format!(
+        "{:.1}% of recent responses were server errors, over the limit of {:.1}%",
+        rate * 100.0,
+        limit * 100.0
+    ))
+}
+
+// The first readiness threshold the current load exceeds
+fn saturation(state: &AppState) -> Option<Saturation> {
+    if let Some(memory) = state.memory.as_ref().filter(|memory| memory.overloaded()) {
+        return Some(Saturation {
+            threshold: "memory_bytes",
+            value: memory.usage(),
+            limit: memory.limit(),
+        });
+    }
+    state.settings().readiness.exceeded(state.queue.depth(), &state.latency)
+}
+
+impl Readiness {
+    // The first load threshold that `depth` calls waiting for the model, or
+    // the recent latencies, exceed
+    fn exceeded(&self, depth: usize, latency: &LatencyWindow) -> Option<Saturation> {
+        if let Some(limit) = self.max_queue_depth.filter(|&limit| depth > limit) {
+            return Some(Saturation {
+                threshold: "queue_depth",
+                value: depth as u64,
+                limit: limit as u64,
+            });
+        }
+        let limit = self.max_p95?;
+        let [p95] = latency.quantiles([0.95]);
+        p95.filter(|&p95| p95 > limit).map(|p95| Saturation {
+            threshold: "p95_latency_ms",
+            value: p95.as_millis() as u64,
+            limit: limit.as_millis() as u64,
+        })
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+struct Liveness {
+    #[schema(example = "alive")]
+    status: &'static str,
+}
+
+/// Liveness
+///
+/// The process is up and serving HTTP, whatever its load.
+#[utoipa::path(
+    get,
+    path = "/health/live",
+    operation_id = "liveness",
+    responses((status = 200, description = "The process is serving HTTP", body = Liveness)),
+)]
+async fn liveness() -> impl IntoResponse {
+    Json(Liveness { status: "alive" })
+}
+
+async fn version(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    Json(VersionResponse {
+        version: env!("CARGO_PKG_VERSION"),
+        model: state.model_name.clone(),
+        model_revision: state.model_revision.clone(),
+        runtime: state.runtime,
+        config_generation: state.settings().generation,
+    })
+}
+
+async fn openapi_document() -> impl IntoResponse {
+    Json(openapi::document())
+}
+
+async fn list_models(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    Json(ModelsResponse {
+        models: vec![state.model_name.clone()],
+        metadata: vec![ModelDetails {
+            id: state.model_name.clone(),
+            metadata: state.metadata.clone(),
+        }],
+    })
+}
+
+/// List the loaded models and their aliases
+#[utoipa::path(
+    get,
+    path = "/v1/models",
+    operation_id = "listModels",
+    responses((status = 200, description = "Models", body = ModelList)),
+)]
+async fn list_models_openai(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    let settings = state.settings();
+    let mut data: Vec<ModelObject> = settings
+        .resolver
+        .loaded()
+        .iter()
+        .map(|name| ModelObject::new(name, &state.metadata, state.limits))
+        .collect();
+    let mut aliases: Vec<&String> = settings.resolver.aliases().keys().collect();
+    aliases.sort();
+    data.extend(aliases.into_iter().map(|alias| ModelObject::new(alias, &state.metadata, state.limits)));
+    // Then the comparison models, loaded or not
+    for (spec, residency) in state.comparison.states() {
+        if let Some(metadata) = models::model_metadata(spec) {
+            data.push(ModelObject {
+                state: residency,
+                endpoint: Some("/v1/compare"),
+                ..ModelObject::new(spec.name, &metadata, state.limits)
+            });
+        }
+    }
+
+    Json(ModelList {
+        object: "list".to_string(),
+        data,
+    })
+}
+
+/// Describe one model or alias
+#[utoipa::path(
+    get,
+    path = "/v1/models/{model}",
+    operation_id = "retrieveModel",
+    params(("model" = String, Path, description = "A loaded model or alias")),
+    responses(
+        (status = 200, description = "The model", body = ModelObject),
+        (status = 404, description = "Unknown model", body = ErrorResponse),
+    ),
+)]
+async fn retrieve_model(
+    State(state): State<Arc<AppState>>,
+    Path(model): Path<String>,
+) -> Result<Json<ModelObject>, ApiError> {
+    match state.settings().resolver.resolve(Some(&model)) {
+        Some(resolved) => Ok(Json(ModelObject::new(
+            resolved.response_name(state.model_echo),
+            &state.metadata,
+            state.limits,
+        ))),
+        None => Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            "model_not_found",
+            format!("The model `{}` does not exist", model),
+        )),
+    }
+}
+
+// Everything here is read from counters and small bookkeeping locks, never the
+// model, so it answers promptly under load
+async fn stats(State(state): State<Arc<AppState>>) -> Json<Stats> {
+    let metrics = &state.metrics;
+    // Summed over endpoints and status classes
+    let mut errors: BTreeMap<String, u64> = BTreeMap::new();
+    let mut requests = 0;
+    for family in metrics.errors_total.collect().iter().chain(&metrics.requests_total.collect()) {
+        for metric in family.get_metric() {
+            let label = |name: &str| metric.get_label().iter().find(|label| label.get_name() == name);
+            let count = metric.get_counter().get_value() as u64;
+            match (label("reason"), label("endpoint")) {
+                (Some(reason), _) => *errors.entry(reason.get_value().to_string()).or_default() += count,
+                // Probes and scrapes aren't traffic
+                (None, Some(endpoint)) if !NOT_TRAFFIC.contains(&endpoint.get_value()) => requests += count,
+                _ => {}
+            }
+        }
+    }
+    let to_ms = |latency: Option<Duration>| latency.map(|latency| latency.as_secs_f64() * 1000.0);
+    let [p50, p95, p99] = state.latency.quantiles([0.5, 0.95, 0.99]);
+    let (hits, misses) = (metrics.cache_hits.get() as u64, metrics.cache_misses.get() as u64);
+    let mut models: Vec<ModelStats> = state
+        .settings()
+        .resolver
+        .loaded()
+        .iter()
+        .map(|id| ModelStats {
+            id: id.clone(),
+            dimensions: state.metadata.dimensions,
+        })
+        .collect();
+    if let Some(multi_vector) = &state.multi_vector {
+        models.push(ModelStats {
+            id: multi_vector.name().to_string(),
+            dimensions: multi_vector.dimensions(),
+        });
+    }
+    Json(Stats {
+        uptime_secs: state.started.elapsed().as_secs(),
+        requests: RequestStats {
+            total: requests,
+            recent: state.latency.len() as u64,
+            window_secs: state.latency.window().as_secs(),
+        },
+        errors,
+        latency: LatencyStats {
+            p50_ms: to_ms(p50),
+            p95_ms: to_ms(p95),
+            p99_ms: to_ms(p99),
+        },
+        cache: CacheStats {
+            enabled: state.cache.enabled(),
+            entries: metrics.cache_entries.get().max(0) as u64,
+            hits,
+            misses,
+            hit_rate: (hits + misses > 0).then(|| hits as f64 / (hits + misses) as f64),
+        },
+        queue_depth: state.queue.depth(),
+        batch_token_budget: state.embedder.batch_tokens(),
+        placement: state
+            .placements
+            .iter()
+            .map(|placement| PlacementStats {
+                replica: placement.replica,
+                cores: placement.cores.to_string(),
+                numa_node: placement.numa_node,
+            })
+            .collect(),
+        shed_probability: state.shedder.as_ref().map_or(0.0, LoadShedder::probability),
+        models,
+        memory: MemoryStats {
+            resident_bytes: memory::resident_bytes(),
+            limit_bytes: state.memory.as_ref().map(MemoryGuard::limit),
+            overloaded: state.memory.as_ref().is_some_and(MemoryGuard::overloaded),
+        },
+    })
+}
+
+async fn metrics_handler(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    encode_metrics(&state.metrics.registry)
+}
+
+// The registry in Prometheus' text format
+fn encode_metrics(registry: &Registry) -> (StatusCode, String) {
+    let encoder = TextEncoder::new();
+    let metric_families = registry.gather();
+
+    let mut buffer = Vec::new();
     if let Err(e) = encoder.encode(&metric_families, &mut buffer) {
         error!("Failed to encode metrics: {}", e);
         return (
@@ -320,3 +5820,503 @@ async fn metrics_handler(State(state): State<Arc<AppState>>) -> impl IntoRespons
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, extract::FromRequest};
+
+    // The status and error body a request body is rejected with
+    async fn rejection(body: &'static str) -> (StatusCode, ErrorResponse) {
+        let request = Request::builder()
+            .method("POST")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body))
+            .unwrap();
+        let Err(error) = ApiJson::<EmbeddingRequest>::from_request(request, &()).await else {
+            panic!("{} was accepted", body);
+        };
+        let response = error.into_response();
+        let status = response.status();
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&bytes).unwrap())
+    }
+
+    #[tokio::test]
+    async fn missing_input_names_the_field() {
+        let (status, body) = rejection(r#"{"model": "bge-small"}"#).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body.error.error_type, "invalid_request_error");
+        assert!(body.error.message.contains("missing field `input`"), "{}", body.error.message);
+    }
+
+    #[tokio::test]
+    async fn numeric_input_says_what_input_must_be() {
+        let (status, body) = rejection(r#"{"input": 42}"#).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(
+            body.error.message.contains("input must be a string, an array of strings or an array of {id, text} objects"),
+            "{}",
+            body.error.message
+        );
+        assert!(body.error.message.contains("got a number"), "{}", body.error.message);
+    }
+
+    #[tokio::test]
+    async fn mixed_array_names_the_offending_item() {
+        let (status, body) = rejection(r#"{"input": ["a", 2, "c"]}"#).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(body.error.message.contains("input[1] must be a string (got a number)"), "{}", body.error.message);
+    }
+
+    #[tokio::test]
+    async fn truncated_json_is_a_syntax_error() {
+        let (status, body) = rejection(r#"{"input": ["a", "b""#).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body.error.error_type, "invalid_request_error");
+        assert!(body.error.message.starts_with("We could not parse the JSON body"), "{}", body.error.message);
+    }
+
+    // Every malformed shape of `input`, with the whole message it gets
+    #[test]
+    fn malformed_inputs_get_exact_messages() {
+        let whole = "input must be a string, an array of strings or an array of {id, text} objects";
+        let cases = [
+            ("42", format!("{} (got a number)", whole)),
+            ("true", format!("{} (got a boolean)", whole)),
+            ("null", format!("{} (got null)", whole)),
+            (r#"{"text": "a"}"#, format!("{} (got an object)", whole)),
+            ("[]", "input array may not be empty".to_string()),
+            ("[1]", "input[0] must be a string (got a number)".to_string()),
+            (r#"["a", "b", "c", {"x": 1}]"#, "input[3] must be a string (got an object)".to_string()),
+            (r#"["a", ["b"]]"#, "input[1] must be a string (got an array)".to_string()),
+            (r#"["a", null]"#, "input[1] must be a string (got null)".to_string()),
+            (r#"["a", false]"#, "input[1] must be a string (got a boolean)".to_string()),
+            // An array of arrays (token ids) isn't accepted yet
+            ("[[1, 2], [3]]", "input[0] must be a string (got an array)".to_string()),
+        ];
+        for (json, message) in cases {
+            let error = serde_json::from_str::<InputType>(json).err().map(|e| e.to_string());
+            assert_eq!(error.as_deref(), Some(message.as_str()), "{}", json);
+        }
+    }
+
+    // Rejections describe what is wrong with the body, never the inputs it carries
+    #[tokio::test]
+    async fn rejections_do_not_echo_inputs() {
+        for body in [
+            r#"{"input": ["PRIVATE-7f3a", 2]}"#,
+            r#"{"input": "PRIVATE-7f3a", "dimensions": "eight"}"#,
+            r#"{"input": [{"id": "PRIVATE-7f3a", "txt": "PRIVATE-7f3a"}]}"#,
+            r#"{"input": "PRIVATE-7f3a"#,
+        ] {
+            let (status, body) = rejection(body).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+            assert!(!body.error.message.contains("PRIVATE-7f3a"), "{}", body.error.message);
+        }
+    }
+
+    // A deterministic unit vector with components spread like an embedding's
+    fn unit_vector(dimensions: usize) -> Vec<f32> {
+        let mut state = 0x2545_f491_u32;
+        let mut vector: Vec<f32> = (0..dimensions)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as f32 / u32::MAX as f32 - 0.5
+            })
+            .collect();
+        cluster::normalize(&mut vector);
+        vector
+    }
+
+    #[test]
+    fn full_precision_is_byte_for_byte_unchanged() {
+        let vector = unit_vector(384);
+        let full = serde_json::to_string(&Floats(vector.clone(), None)).unwrap();
+        assert_eq!(full, serde_json::to_string(&vector).unwrap());
+    }
+
+    #[test]
+    fn rounding_shrinks_payloads_and_keeps_similarity() {
+        let vector = unit_vector(384);
+        let full = serde_json::to_string(&Floats(vector.clone(), None)).unwrap();
+        let rounded = serde_json::to_string(&Floats(vector.clone(), Some(5))).unwrap();
+        assert!(rounded.len() * 10 < full.len() * 8, "{} bytes rounded, {} in full", rounded.len(), full.len());
+
+        let parsed: Vec<f32> = serde_json::from_str(&rounded).unwrap();
+        assert!(shadow::cosine(&vector, &parsed) > 0.9999);
+        for value in rounded.trim_matches(|c| c == '[' || c == ']').split(',') {
+            let decimals = value.split_once('.').map_or(0, |(_, decimals)| decimals.len());
+            assert!(decimals <= 5, "{}", value);
+        }
+    }
+
+    #[test]
+    fn base64_is_never_rounded() {
+        let vector = unit_vector(16);
+        let EmbeddingData::Base64(packed) = EmbeddingData::encode(vector.clone(), &EncodingFormat::Base64, Some(2))
+        else {
+            panic!("base64 output was not packed");
+        };
+        let bytes = base64::engine::general_purpose::STANDARD.decode(packed).unwrap();
+        let unpacked: Vec<f32> =
+            bytes.chunks_exact(4).map(|chunk| f32::from_le_bytes(chunk.try_into().unwrap())).collect();
+        assert_eq!(unpacked, vector);
+    }
+
+    #[test]
+    fn zero_precision_rounds_to_integers() {
+        let json = serde_json::to_string(&Floats(vec![0.4, -0.6, 1.5], Some(0))).unwrap();
+        assert_eq!(json, "[0.0,-1.0,2.0]");
+    }
+
+    #[tokio::test]
+    async fn unknown_encoding_format_is_rejected() {
+        let (status, body) = rejection(r#"{"input": "a", "encoding_format": "hex"}"#).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(body.error.message.contains("encoding_format"), "{}", body.error.message);
+    }
+
+    // The smallest runtime SEMEMBED_TOKIO_WORKERS and SEMEMBED_BLOCKING_THREADS
+    // allow still serves concurrent requests, each taking turns on the model
+    // as embed_queued does
+    #[test]
+    fn one_worker_thread_serves_concurrent_requests() {
+        let runtime = RuntimeConfig {
+            worker_threads: 1,
+            blocking_threads: EMBEDDER_WORKERS,
+        }
+        .build()
+        .unwrap();
+        let queue = Arc::new(queue::tests::queue(8));
+        let results = runtime.block_on(async {
+            let mut requests = JoinSet::new();
+            for request in 0..200_usize {
+                let queue = queue.clone();
+                requests.spawn(async move {
+                    let priority = if request % 3 == 0 { Priority::High } else { Priority::Low };
+                    let tenant = Tenant::from_id(&format!("tenant-{}", request % 4));
+                    let turn = queue.acquire(priority, &tenant, 1).await;
+                    let embedded = tokio::task::spawn_blocking(move || {
+                        let _turn = turn;
+                        std::thread::sleep(Duration::from_micros(200));
+                        request * 2
+                    });
+                    (request, embedded.await.unwrap())
+                });
+            }
+            tokio::time::timeout(Duration::from_secs(30), async {
+                let mut results = Vec::new();
+                while let Some(result) = requests.join_next().await {
+                    results.push(result.unwrap());
+                }
+                results
+            })
+            .await
+            .expect("requests stalled")
+        });
+        assert_eq!(results.len(), 200);
+        assert!(results.iter().all(|&(request, embedded)| embedded == request * 2));
+        assert_eq!(queue.depth(), 0);
+    }
+
+    // Bodies as Jina's own client and API examples send them, with fields
+    // semembed doesn't know (`embedding_type`) ignored
+    #[test]
+    fn jina_requests_deserialize() {
+        let req: EmbeddingRequest = serde_json::from_str(
+            r#"{
+                "model": "jina-embeddings-v3",
+                "task": "text-matching",
+                "late_chunking": false,
+                "dimensions": 1024,
+                "embedding_type": "float",
+                "input": [
+                    "Organic skincare for sensitive skin with aloe vera and chamomile.",
+                    "Bio-Hautpflege für empfindliche Haut mit Aloe Vera und Kamille."
+                ]
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(req.model.as_deref(), Some("jina-embeddings-v3"));
+        assert_eq!(req.task.as_deref(), Some("text-matching"));
+        assert_eq!(req.late_chunking, Some(false));
+        assert_eq!(req.dimensions, Some(1024));
+        assert!(matches!(&req.input, InputType::Batch(texts) if texts.len() == 2));
+        assert!(check_late_chunking(req.late_chunking).is_ok());
+
+        let req: EmbeddingRequest = serde_json::from_str(
+            r#"{"model": "jina-embeddings-v3", "task": "retrieval.query", "truncate": true, "input": ["What is TSNE?"]}"#,
+        )
+        .unwrap();
+        assert_eq!(req.task.as_deref(), Some("retrieval.query"));
+        assert_eq!(req.truncate, Some(true));
+        assert_eq!(req.late_chunking, None);
+        assert!(check_late_chunking(None).is_ok());
+    }
+
+    #[tokio::test]
+    async fn late_chunking_is_refused_not_ignored() {
+        let req: EmbeddingRequest =
+            serde_json::from_str(r#"{"input": ["a", "b"], "task": "retrieval.passage", "late_chunking": true}"#).unwrap();
+        let response = check_late_chunking(req.late_chunking).unwrap_err().into_response();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: ErrorResponse = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(body.error.param.as_deref(), Some("late_chunking"));
+        assert_eq!(body.error.code.as_deref(), Some("unsupported_parameter"));
+    }
+
+    #[tokio::test]
+    async fn refused_inputs_name_their_index_and_class() {
+        let reject = Preprocess {
+            sanitize: preprocess::Sanitize::Reject,
+            ..Preprocess::default()
+        };
+        let disallowed = reject.prepare("right-to-left \u{202E}override".to_string()).unwrap_err();
+        let response = disallowed_input("input", 2, disallowed).into_response();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: ErrorResponse = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(body.error.message, "input[2] contains a disallowed bidi control character (U+202E)");
+        assert_eq!(body.error.code.as_deref(), Some("disallowed_character"));
+    }
+
+    // What nomic-embed-text is given for each task, down to the separator
+    #[test]
+    fn nomic_inputs_carry_their_task_prefix() {
+        let spec = models::model_spec("nomic-ai/nomic-embed-text-v1.5").unwrap();
+        let embedded = |task: Option<&str>, input_type| {
+            let selection = spec.select(task, input_type).unwrap();
+            apply_prefix(vec!["What is TSNE?".to_string()], None, selection.prefix).0.remove(0)
+        };
+        assert_eq!(embedded(None, None), "search_document: What is TSNE?");
+        assert_eq!(embedded(None, Some(InputKind::Query)), "search_query: What is TSNE?");
+        assert_eq!(embedded(None, Some(InputKind::Passage)), "search_document: What is TSNE?");
+        assert_eq!(embedded(Some("search_query"), None), "search_query: What is TSNE?");
+        assert_eq!(embedded(Some("search_document"), None), "search_document: What is TSNE?");
+        assert_eq!(embedded(Some("classification"), None), "classification: What is TSNE?");
+        assert_eq!(embedded(Some("clustering"), Some(InputKind::Query)), "clustering: What is TSNE?");
+
+        let (texts, prefix_len) = apply_prefix(vec!["a".to_string(), "b".to_string()], None, Some("search_query: "));
+        assert_eq!(texts, ["search_query: a", "search_query: b"]);
+        assert_eq!(prefix_len, "search_query: ".len());
+        // An instruction replaces the prefix
+        let (texts, _) = apply_prefix(vec!["a".to_string()], Some("Find the answer"), Some("search_query: "));
+        assert_eq!(texts, ["Instruct: Find the answer\nQuery: a"]);
+        assert_eq!(apply_prefix(vec!["a".to_string()], None, None), (vec!["a".to_string()], 0));
+    }
+
+    fn readiness(max_queue_depth: Option<usize>, max_p95: Option<Duration>) -> Readiness {
+        Readiness {
+            max_queue_depth,
+            max_p95,
+            max_error_rate: None,
+            fail_degraded: false,
+        }
+    }
+
+    #[test]
+    fn load_thresholds_are_off_by_default() {
+        let latency = LatencyWindow::new(Duration::from_secs(60), 64);
+        (0..10).for_each(|_| latency.record(30.0));
+        assert!(readiness(None, None).exceeded(100_000, &latency).is_none());
+    }
+
+    #[test]
+    fn queue_depth_takes_the_instance_out_and_back() {
+        let readiness = readiness(Some(8), None);
+        let latency = LatencyWindow::new(Duration::from_secs(60), 64);
+        assert!(readiness.exceeded(8, &latency).is_none());
+        let saturation = readiness.exceeded(9, &latency).unwrap();
+        assert_eq!(
+            serde_json::to_value(&saturation).unwrap(),
+            serde_json::json!({"threshold": "queue_depth", "value": 9, "limit": 8})
+        );
+        assert!(readiness.exceeded(3, &latency).is_none());
+    }
+
+    #[test]
+    fn slow_requests_take_the_instance_out_until_they_age_out() {
+        let readiness = readiness(Some(8), Some(Duration::from_millis(500)));
+        let latency = LatencyWindow::new(Duration::from_millis(200), 64);
+        assert!(readiness.exceeded(0, &latency).is_none());
+        (0..20).for_each(|_| latency.record(0.01));
+        assert!(readiness.exceeded(0, &latency).is_none());
+
+        // One request in five taking 2s puts the p95 over the limit
+        (0..5).for_each(|_| latency.record(2.0));
+        let saturation = readiness.exceeded(0, &latency).unwrap();
+        assert_eq!((saturation.threshold, saturation.value, saturation.limit), ("p95_latency_ms", 2000, 500));
+        // The queue is checked first
+        assert_eq!(readiness.exceeded(9, &latency).unwrap().threshold, "queue_depth");
+
+        std::thread::sleep(Duration::from_millis(250));
+        assert!(readiness.exceeded(0, &latency).is_none());
+        latency.record(0.02);
+        assert!(readiness.exceeded(0, &latency).is_none());
+    }
+
+    #[test]
+    fn records_need_an_id_and_text() {
+        let parse = |json: &str| serde_json::from_str::<InputType>(json).map_err(|e| e.to_string());
+        assert!(matches!(parse(r#"[{"id": 1, "text": "a"}]"#), Ok(InputType::Records(records)) if records.len() == 1));
+        assert!(parse(r#"[{"text": "a"}]"#).unwrap_err().contains("input[0] is missing id"));
+        assert!(parse(r#"[{"id": 1, "text": "a"}, "b"]"#).unwrap_err().contains("input[1] must be an object"));
+        assert!(parse(r#"[{"id": 1, "text": "a", "extra": 1}]"#).unwrap_err().contains("unknown field `extra`"));
+        assert_eq!(parse("[]").unwrap_err(), "input array may not be empty");
+    }
+
+    #[test]
+    fn truncation_is_reported_with_its_side_when_asked() {
+        let count = TokenCount {
+            tokens: 512,
+            truncated: true,
+            dropped: 88,
+            side: TruncationSide::Start,
+        };
+        let details = serde_json::to_value(Truncation::details(&count, true)).unwrap();
+        assert_eq!(details, serde_json::json!({"truncated_tokens": 88, "truncation_side": "start"}));
+        assert!(Truncation::details(&count, false).is_none());
+        let whole = TokenCount {
+            truncated: false,
+            dropped: 0,
+            ..count
+        };
+        assert!(Truncation::details(&whole, true).is_none());
+    }
+
+    #[test]
+    fn outputs_reject_the_options_they_dont_support() {
+        let reason = |json: &str| {
+            let req: EmbeddingRequest = serde_json::from_str(json).unwrap();
+            check_output(&req).err().map(|e| e.into_item().1)
+        };
+        let matrix = r#"{"input": "a", "precision": 4, "dtype": "float16", "encoding_format": "base64_matrix"}"#;
+        assert_eq!(reason(matrix), None);
+        assert_eq!(reason(r#"{"input": "a", "precision": 99}"#), Some("invalid_precision"));
+        assert_eq!(reason(r#"{"input": "a", "dtype": "float16"}"#), Some("invalid_dtype"));
+        assert_eq!(reason(r#"{"input": "a", "output": "tokens", "encoding_format": "base64"}"#), None);
+        assert_eq!(reason(r#"{"input": "a", "output": "tokens"}"#), Some("invalid_encoding_format"));
+        assert_eq!(reason(r#"{"input": "a", "output": "multi_vector", "dimensions": 8}"#), Some("invalid_dimensions"));
+        let start = r#"{"input": "a", "output": "multi_vector", "truncation_side": "start"}"#;
+        assert_eq!(reason(start), Some("invalid_truncation_side"));
+    }
+
+    // The state `run` builds around the default model when nothing is configured
+    fn default_state() -> Arc<AppState> {
+        let model_name = "BAAI/bge-small-en-v1.5".to_string();
+        let loaded = load_model(&model_name).unwrap();
+        let (_, log_filter) = tracing_subscriber::reload::Layer::new(tracing_subscriber::EnvFilter::new("off"));
+        let config = StateConfig {
+            model_name: model_name.clone(),
+            runtime_config: RuntimeConfig {
+                worker_threads: 1,
+                blocking_threads: 4,
+            },
+            config_file: None,
+            log_filter,
+            resolver: ModelResolver::new(vec![model_name], HashMap::new()).unwrap(),
+            metrics_token: None,
+            quotas: quota::Quotas::default(),
+            model_echo: ModelEcho::default(),
+            instructions: HashMap::new(),
+            float_precision: None,
+            truncation_side: TruncationSide::default(),
+            fetcher: None,
+            #[cfg(feature = "object-storage")]
+            jobs: None,
+            #[cfg(feature = "pgvector")]
+            pgvector: None,
+            sink: None,
+            sink_options: sinks::SinkOptions {
+                batch_size: 100,
+                retries: 3,
+            },
+            usage: Arc::new(usage::UsageLedger::open(None, 1000).unwrap()),
+            upload: UploadLimits {
+                max_file_bytes: 1024 * 1024,
+                max_total_bytes: 1024 * 1024,
+            },
+            cache_capacity: 0,
+            tenant_shares: TenantShares::default(),
+            low_priority_batch: 32,
+            default_priority: Priority::High,
+            model_concurrency: HashMap::new(),
+            multi_vector: None,
+            language_routes: None,
+            user_labels: UserLabels::default(),
+            limits: Limits {
+                max_inputs: 2048,
+                max_tokens_per_request: 300000,
+                max_body_bytes: 2 * 1024 * 1024,
+                max_input_bytes: 256 * 1024,
+                max_metadata_bytes: 4 * 1024,
+                max_metadata_total_bytes: 256 * 1024,
+            },
+            bulk_limits: BulkLimits {
+                max_line_bytes: 1024 * 1024,
+                max_lines: 1000,
+            },
+            preprocess: Preprocess::default(),
+            latency_window: Duration::from_secs(60),
+            placements: Vec::new(),
+            on_demand: Vec::new(),
+            model_budget: None,
+            max_resident_models: None,
+        };
+        Arc::new(app_state(config, loaded, Arc::new(Metrics::new().unwrap())).unwrap())
+    }
+
+    #[tokio::test]
+    #[ignore = "downloads BAAI/bge-small-en-v1.5 and needs ONNX Runtime (ORT_DYLIB_PATH)"]
+    async fn a_request_is_one_latency_sample() {
+        use tower::ServiceExt;
+
+        let state = default_state();
+        let routes = Routes {
+            docs: false,
+            admin: false,
+            profiling: false,
+        };
+        // The model call inside it is timed by its histogram, not the window
+        let inputs = ["The first sentence", "The second sentence"];
+        let request = Request::post("/v1/embeddings")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(axum::body::Body::from(serde_json::json!({ "input": inputs }).to_string()))
+            .unwrap();
+        let before = state.latency.len();
+        let response = router(state.clone(), &routes).oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(state.latency.len(), before + 1);
+    }
+
+    #[tokio::test]
+    #[ignore = "downloads BAAI/bge-small-en-v1.5 and needs ONNX Runtime (ORT_DYLIB_PATH)"]
+    async fn recent_requests_count_requests_not_batches() {
+        use tower::ServiceExt;
+
+        let state = default_state();
+        let routes = Routes {
+            docs: false,
+            admin: false,
+            profiling: false,
+        };
+        let app = router(state, &routes);
+        let inputs: Vec<String> = (0..100).map(|i| format!("Sentence number {i}")).collect();
+        let request = Request::post("/v1/embeddings")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(axum::body::Body::from(serde_json::json!({ "input": inputs }).to_string()))
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let request = Request::get("/stats").body(axum::body::Body::empty()).unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let stats: Stats = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(stats.requests.recent, 1);
+    }
+}
@@ -0,0 +1,324 @@
+use std::sync::OnceLock;
+
+use regex::Regex;
+
+// Tags whose content is never visible text
+const HIDDEN_TAGS: &[&str] = &["script", "style", "noscript", "template", "head"];
+
+// Tags that separate blocks of text
+const BLOCK_TAGS: &[&str] = &[
+    "address", "article", "aside", "blockquote", "br", "dd", "div", "dl", "dt", "figcaption",
+    "figure", "footer", "form", "h1", "h2", "h3", "h4", "h5", "h6", "header", "hr", "li", "main",
+    "nav", "ol", "p", "pre", "section", "table", "td", "th", "tr", "ul",
+];
+
+/// Convert HTML to its visible text.
+///
+/// Drops comments and script/style content, keeps image alt text, decodes
+/// common entities and turns block-level tags into line breaks. This is a
+/// tolerant scanner rather than a full parser: anything it cannot make sense
+/// of (an unterminated tag, a stray `<`) is passed through as text.
+pub fn html_to_text(html: &str) -> String {
+    let mut out = String::with_capacity(html.len());
+    let mut rest = html;
+
+    while let Some(pos) = rest.find(['<', '&']) {
+        out.push_str(&rest[..pos]);
+        rest = &rest[pos..];
+
+        if rest.starts_with('&') {
+            let (decoded, consumed) = decode_entity(rest);
+            out.push_str(&decoded);
+            rest = &rest[consumed..];
+            continue;
+        }
+
+        if let Some(comment) = rest.strip_prefix("<!--") {
+            rest = comment.find("-->").map_or("", |end| &comment[end + 3..]);
+            continue;
+        }
+
+        // A `<` that cannot start a tag ("a < b") is plain text
+        if !rest[1..].starts_with(|c: char| c.is_ascii_alphabetic() || matches!(c, '/' | '!' | '?')) {
+            out.push('<');
+            rest = &rest[1..];
+            continue;
+        }
+
+        let Some(end) = tag_end(rest) else {
+            // Unterminated tag: keep the rest verbatim
+            out.push_str(rest);
+            return finish(out);
+        };
+        let tag = &rest[1..end];
+        rest = &rest[end + 1..];
+
+        let closing = tag.starts_with('/');
+        let name: String = tag
+            .trim_start_matches('/')
+            .chars()
+            .take_while(|c| c.is_ascii_alphanumeric())
+            .collect::<String>()
+            .to_ascii_lowercase();
+
+        if name.is_empty() {
+            // `<!DOCTYPE ...>`, `<?xml ...?>` and friends
+            continue;
+        }
+
+        if !closing && HIDDEN_TAGS.contains(&name.as_str()) && !tag.ends_with('/') {
+            rest = skip_past_closing_tag(rest, &name);
+            continue;
+        }
+
+        if name == "img" {
+            if let Some(alt) = attribute(tag, "alt") {
+                out.push(' ');
+                out.push_str(&html_to_text(alt));
+                out.push(' ');
+            }
+        } else if BLOCK_TAGS.contains(&name.as_str()) {
+            out.push('\n');
+        }
+    }
+
+    out.push_str(rest);
+    finish(out)
+}
+
+/// Strip markdown formatting, keeping link and image text.
+pub fn strip_markdown(markdown: &str) -> String {
+    static LINE_RULES: OnceLock<Vec<(Regex, &'static str)>> = OnceLock::new();
+    static INLINE_RULES: OnceLock<Vec<(Regex, &'static str)>> = OnceLock::new();
+
+    let line_rules = LINE_RULES.get_or_init(|| {
+        [
+            // Link reference definitions: [id]: https://example.com "title"
+            (r"^\s{0,3}\[[^\]]+\]:\s+\S+.*$", ""),
+            // Horizontal rules
+            (r"^\s{0,3}(?:(?:-\s*){3,}|(?:\*\s*){3,}|(?:_\s*){3,})$", ""),
+            // Code fences (content is kept)
+            (r"^\s{0,3}(?:```|~~~).*$", ""),
+            // Headings, blockquotes, list markers
+            (r"^\s{0,3}#{1,6}\s+", ""),
+            (r"^(?:\s{0,3}>\s?)+", ""),
+            (r"^\s*(?:[-*+]|\d+[.)])\s+(?:\[[ xX]\]\s+)?", ""),
+        ]
+        .into_iter()
+        .map(|(pattern, replacement)| (Regex::new(pattern).expect("valid markdown rule"), replacement))
+        .collect()
+    });
+    let inline_rules = INLINE_RULES.get_or_init(|| {
+        [
+            // Images and links keep their text
+            (r"!\[([^\]]*)\]\([^)]*\)", "$1"),
+            (r"\[([^\]]*)\]\([^)]*\)", "$1"),
+            (r"\[([^\]]*)\]\[[^\]]*\]", "$1"),
+            (r"<((?:https?|mailto):[^>\s]+)>", "$1"),
+            // Emphasis, strikethrough, inline code
+            (r"\*\*([^*]+)\*\*", "$1"),
+            (r"__([^_]+)__", "$1"),
+            (r"\*([^*\s][^*]*)\*", "$1"),
+            (r"\b_([^_]+)_\b", "$1"),
+            (r"~~([^~]+)~~", "$1"),
+            (r"`([^`]+)`", "$1"),
+        ]
+        .into_iter()
+        .map(|(pattern, replacement)| (Regex::new(pattern).expect("valid markdown rule"), replacement))
+        .collect()
+    });
+
+    let lines: Vec<String> = markdown
+        .lines()
+        .map(|line| {
+            let mut line = line.to_string();
+            for (rule, replacement) in line_rules {
+                line = rule.replace(&line, *replacement).into_owned();
+            }
+            for (rule, replacement) in inline_rules {
+                line = rule.replace_all(&line, *replacement).into_owned();
+            }
+            line
+        })
+        .collect();
+    finish(lines.join("\n"))
+}
+
+// Index of the `>` closing the tag that starts at `s[0] == '<'`, honouring quoted
+// attribute values.
+fn tag_end(s: &str) -> Option<usize> {
+    let mut quote = None;
+    for (i, c) in s.char_indices().skip(1) {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), _) => {}
+            (None, '"' | '\'') => quote = Some(c),
+            (None, '>') => return Some(i),
+            (None, '<') => return None,
+            _ => {}
+        }
+    }
+    None
+}
+
+fn skip_past_closing_tag<'a>(s: &'a str, name: &str) -> &'a str {
+    let needle = format!("</{}", name);
+    let lower = s.to_ascii_lowercase();
+    match lower.find(&needle) {
+        Some(start) => match s[start..].find('>') {
+            Some(end) => &s[start + end + 1..],
+            None => "",
+        },
+        None => "",
+    }
+}
+
+fn attribute<'a>(tag: &'a str, name: &str) -> Option<&'a str> {
+    let lower = tag.to_ascii_lowercase();
+    let mut search = 0;
+    while let Some(found) = lower[search..].find(name) {
+        let start = search + found;
+        search = start + name.len();
+        let preceded_by_space = lower[..start].ends_with(|c: char| c.is_ascii_whitespace());
+        let value = tag[search..].trim_start();
+        let Some(value) = value.strip_prefix('=') else {
+            continue;
+        };
+        if !preceded_by_space {
+            continue;
+        }
+        let value = value.trim_start();
+        return Some(match value.chars().next() {
+            Some(q @ ('"' | '\'')) => {
+                let value = &value[1..];
+                value.find(q).map_or(value, |end| &value[..end])
+            }
+            _ => value
+                .split(|c: char| c.is_ascii_whitespace() || c == '/')
+                .next()
+                .unwrap_or_default(),
+        });
+    }
+    None
+}
+
+// Decode the entity at the start of `s`, returning the text and bytes consumed.
+// Unknown or malformed entities are left as-is.
+fn decode_entity(s: &str) -> (String, usize) {
+    let Some(end) = s.char_indices().take(12).find(|(_, c)| *c == ';').map(|(i, _)| i) else {
+        return ("&".to_string(), 1);
+    };
+    let entity = &s[1..end];
+    let decoded = match entity {
+        "amp" => Some('&'),
+        "lt" => Some('<'),
+        "gt" => Some('>'),
+        "quot" => Some('"'),
+        "apos" => Some('\''),
+        "nbsp" => Some(' '),
+        "ndash" => Some('\u{2013}'),
+        "mdash" => Some('\u{2014}'),
+        "lsquo" => Some('\u{2018}'),
+        "rsquo" => Some('\u{2019}'),
+        "ldquo" => Some('\u{201C}'),
+        "rdquo" => Some('\u{201D}'),
+        "hellip" => Some('\u{2026}'),
+        "copy" => Some('\u{A9}'),
+        "reg" => Some('\u{AE}'),
+        "trade" => Some('\u{2122}'),
+        _ => entity
+            .strip_prefix("#x")
+            .or_else(|| entity.strip_prefix("#X"))
+            .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+            .or_else(|| entity.strip_prefix('#').and_then(|dec| dec.parse().ok()))
+            .and_then(char::from_u32),
+    };
+    match decoded {
+        Some(c) => (c.to_string(), end + 1),
+        None => ("&".to_string(), 1),
+    }
+}
+
+// Collapse runs of spaces within lines and drop blank lines.
+fn finish(text: String) -> String {
+    text.lines()
+        .map(|line| line.split_whitespace().collect::<Vec<_>>().join(" "))
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::preprocess::Preprocess;
+    use proptest::prelude::*;
+    use tokenizers::{models::wordlevel::WordLevel, pre_tokenizers::whitespace::Whitespace, Tokenizer};
+
+    const ARTICLE: &str = include_str!("../tests/fixtures/scraped_article.html");
+    const BROKEN: &str = include_str!("../tests/fixtures/broken_markup.html");
+    const MARKDOWN: &str = include_str!("../tests/fixtures/cms_export.md");
+
+    #[test]
+    fn scraped_article_keeps_visible_text() {
+        assert_eq!(
+            html_to_text(ARTICLE),
+            "Home\nBlog\nTides & the Moon\n\
+             The Moon\u{2019}s pull raises two bulges of water\u{2014}one facing it, one opposite.\n\
+             Spring tides come at new and full moon (roughly every 14.8 days).\n\
+             Diagram of the Earth & Moon\n\
+             Neap tides fall in between.\n\
+             \u{A9} 2024 Example Co. All rights reserved."
+        );
+    }
+
+    #[test]
+    fn broken_markup_passes_through() {
+        let text = html_to_text(BROKEN);
+        assert!(text.starts_with("Unclosed paragraph bold nested wrong order\n"), "{}", text);
+        // Stray `<`, `>` and `&` and unknown entities are text
+        assert!(text.contains("Price: 5 < 7 and 9 > 3 &unknown; &#xZZ; &"), "{}", text);
+        // From the unterminated attribute on, nothing is dropped
+        assert!(text.ends_with(r#"<a href="x" title="unterminated quote>link text</a>
+<p>trailing tag that never ends <span class="x"#), "{}", text);
+    }
+
+    #[test]
+    fn markdown_keeps_link_and_image_text() {
+        assert_eq!(
+            strip_markdown(MARKDOWN),
+            "Release notes\nNote: see the migration guide first.\nFaster startup with lazy_load\n\
+             Removed the legacy API\nTested on Linux logo Linux\nsemembed --help"
+        );
+    }
+
+    #[test]
+    fn token_counts_reflect_the_cleaned_text() {
+        // Every word an unknown token, so the count is of the words the model sees
+        let vocab = [("[UNK]".to_string(), 0)].into_iter().collect();
+        let model = WordLevel::builder().vocab(vocab).unk_token("[UNK]".to_string()).build().unwrap();
+        let mut tokenizer = Tokenizer::new(model);
+        tokenizer.with_pre_tokenizer(Some(Whitespace));
+        let count = |text: &str| tokenizer.encode(text, false).unwrap().len();
+
+        let preprocess: Preprocess = "strip_html".parse().unwrap();
+        let cleaned = preprocess.apply(ARTICLE.to_string());
+        assert_eq!(count(&cleaned), count(&html_to_text(ARTICLE)));
+        assert!(count(&cleaned) * 3 < count(ARTICLE), "{} of {}", count(&cleaned), count(ARTICLE));
+        assert_eq!(count(&"strip_html".parse::<Preprocess>().unwrap().apply("<p>two <b>words</b></p>".into())), 2);
+    }
+
+    proptest! {
+        #[test]
+        fn never_panics(text in "(<[a-z/!?]{0,3}|&#?[a-z0-9]{0,4};?|[\"'=> a-z]|<!--|-->){0,40}") {
+            html_to_text(&text);
+            strip_markdown(&text);
+        }
+
+        #[test]
+        fn never_panics_on_any_string(text in any::<String>()) {
+            html_to_text(&text);
+            strip_markdown(&text);
+        }
+    }
+}
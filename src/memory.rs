@@ -0,0 +1,197 @@
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{bail, Context};
+use prometheus::IntGauge;
+use tracing::{info, warn};
+
+use crate::AppState;
+
+// How often resident memory is sampled
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(500);
+// Requests are admitted again once usage falls below this share of the limit
+const RESUME_FRACTION: f64 = 0.9;
+// Cgroup v1 reports "no limit" as a huge page-aligned number rather than "max"
+const UNLIMITED: u64 = 1 << 60;
+
+/// `SEMEMBED_MAX_RSS_BYTES`: a byte count, or a percentage of the cgroup's
+/// memory limit (of physical memory outside a limited cgroup).
+#[derive(Debug, Clone, Copy)]
+pub enum MemoryLimit {
+    Bytes(u64),
+    Percent(f64),
+}
+
+impl FromStr for MemoryLimit {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let s = s.trim();
+        match s.strip_suffix('%') {
+            Some(percent) => {
+                let percent: f64 = percent.trim().parse().with_context(|| format!("invalid percentage {:?}", s))?;
+                if !(percent > 0.0 && percent <= 100.0) {
+                    bail!("percentage must be above 0 and at most 100, got {}", percent);
+                }
+                Ok(Self::Percent(percent))
+            }
+            None => match s.parse::<u64>() {
+                Ok(0) | Err(_) => bail!("expected a positive number of bytes or a percentage such as 80%, got {:?}", s),
+                Ok(bytes) => Ok(Self::Bytes(bytes)),
+            },
+        }
+    }
+}
+
+impl MemoryLimit {
+    /// The limit in bytes, resolving a percentage against the memory available.
+    pub fn bytes(self) -> anyhow::Result<u64> {
+        match self {
+            Self::Bytes(bytes) => Ok(bytes),
+            Self::Percent(percent) => {
+                let (total, source) = match cgroup_limit() {
+                    Some(limit) => (limit, "cgroup memory limit"),
+                    None => (
+                        physical_memory().context("no cgroup memory limit or physical memory size found")?,
+                        "physical memory",
+                    ),
+                };
+                let bytes = (total as f64 * percent / 100.0) as u64;
+                info!("Memory limit: {}% of the {} ({} bytes) is {} bytes", percent, source, total, bytes);
+                Ok(bytes)
+            }
+        }
+    }
+}
+
+/// Metrics the memory guard reports into.
+pub struct MemoryMetrics {
+    pub resident: IntGauge,
+    pub overloaded: IntGauge,
+}
+
+/// Stops admitting embedding requests while resident memory is above a
+/// limit, so the process answers `503` instead of being OOM-killed with every
+/// request in flight.
+///
+/// A background sampler reads resident memory twice a second. Above the
+/// limit, it first drops the least recently used half of the embedding cache;
+/// only if that doesn't bring usage back under the limit are requests
+/// rejected, until usage falls below 90% of the limit.
+pub struct MemoryGuard {
+    limit: u64,
+    resume: u64,
+    usage: AtomicU64,
+    overloaded: AtomicBool,
+    metrics: MemoryMetrics,
+}
+
+impl MemoryGuard {
+    /// The guard configured by `SEMEMBED_MAX_RSS_BYTES`, or `None` when it is
+    /// unset or resident memory can't be read on this platform.
+    pub fn from_env(metrics: MemoryMetrics) -> anyhow::Result<Option<Self>> {
+        let Some(limit) = crate::env_parse::<MemoryLimit>("SEMEMBED_MAX_RSS_BYTES")? else {
+            return Ok(None);
+        };
+        let Some(usage) = resident_bytes() else {
+            warn!("SEMEMBED_MAX_RSS_BYTES is ignored: resident memory can't be read on this platform");
+            return Ok(None);
+        };
+        let limit = limit.bytes()?;
+        info!("Rejecting embedding requests while resident memory is above {} bytes", limit);
+        metrics.resident.set(usage as i64);
+        metrics.overloaded.set(0);
+        Ok(Some(Self {
+            limit,
+            resume: (limit as f64 * RESUME_FRACTION) as u64,
+            usage: AtomicU64::new(usage),
+            overloaded: AtomicBool::new(false),
+            metrics,
+        }))
+    }
+
+    pub fn limit(&self) -> u64 {
+        self.limit
+    }
+
+    /// Resident memory at the last sample.
+    pub fn usage(&self) -> u64 {
+        self.usage.load(Ordering::Relaxed)
+    }
+
+    /// Whether new embedding requests are being rejected.
+    pub fn overloaded(&self) -> bool {
+        self.overloaded.load(Ordering::Relaxed)
+    }
+
+    fn sample(&self, state: &AppState) {
+        let Some(mut usage) = resident_bytes() else {
+            return;
+        };
+        if usage > self.limit && state.cache.len() > 0 {
+            let dropped = state.cache.evict_half();
+            let before = usage;
+            usage = resident_bytes().unwrap_or(usage);
+            warn!(
+                "Resident memory {} bytes is above the limit of {}; dropped {} cache entries ({} bytes now)",
+                before, self.limit, dropped, usage
+            );
+        }
+        self.usage.store(usage, Ordering::Relaxed);
+        self.metrics.resident.set(usage as i64);
+
+        let was = self.overloaded();
+        let overloaded = if was { usage > self.resume } else { usage > self.limit };
+        if overloaded != was {
+            self.overloaded.store(overloaded, Ordering::Relaxed);
+            self.metrics.overloaded.set(i64::from(overloaded));
+            if overloaded {
+                warn!(
+                    "Resident memory {} bytes is above the limit of {}; rejecting new embedding requests",
+                    usage, self.limit
+                );
+            } else {
+                info!("Resident memory {} bytes is below {}; accepting embedding requests again", usage, self.resume);
+            }
+        }
+    }
+}
+
+/// Sample resident memory for as long as the service runs.
+pub async fn sample(state: Arc<AppState>) {
+    let Some(guard) = &state.memory else {
+        return;
+    };
+    let mut interval = tokio::time::interval(SAMPLE_INTERVAL);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        guard.sample(&state);
+    }
+}
+
+/// Resident set size from procfs; `None` off Linux.
+pub fn resident_bytes() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+    let kilobytes: u64 = line.trim_start_matches("VmRSS:").trim().trim_end_matches("kB").trim().parse().ok()?;
+    Some(kilobytes * 1024)
+}
+
+// The memory limit of the cgroup the process runs in (v2, then v1), if any
+fn cgroup_limit() -> Option<u64> {
+    ["/sys/fs/cgroup/memory.max", "/sys/fs/cgroup/memory/memory.limit_in_bytes"]
+        .iter()
+        .find_map(|path| std::fs::read_to_string(path).ok())
+        .and_then(|limit| limit.trim().parse::<u64>().ok())
+        .filter(|&limit| limit < UNLIMITED)
+}
+
+fn physical_memory() -> Option<u64> {
+    let meminfo = std::fs::read_to_string("/proc/meminfo").ok()?;
+    let line = meminfo.lines().find(|line| line.starts_with("MemTotal:"))?;
+    let kilobytes: u64 = line.trim_start_matches("MemTotal:").trim().trim_end_matches("kB").trim().parse().ok()?;
+    Some(kilobytes * 1024)
+}
@@ -0,0 +1,45 @@
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+/// Per-model metadata advertised by `/v1/models`, `/models` and `/health`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct ModelMetadata {
+    /// Embedding dimension (vector DB column size).
+    pub dimensions: usize,
+    /// Maximum sequence length in tokens; longer inputs are truncated.
+    pub max_tokens: usize,
+    /// ISO 639-1 codes, or `"multilingual"` for models trained on many languages.
+    pub languages: Vec<String>,
+    pub quantization: Quantization,
+    /// Whether semembed prepends the model's query/passage prefixes itself.
+    pub prefixes_applied: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub query_prefix: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub passage_prefix: Option<String>,
+    /// Similarity measure the model was trained for.
+    pub distance: Distance,
+    /// Values the model accepts in a request's `task`.
+    #[serde(default)]
+    pub tasks: Vec<String>,
+    #[serde(default)]
+    pub description: String,
+}
+
+/// Weight quantization of the ONNX model.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Quantization {
+    None,
+    Static,
+    Dynamic,
+}
+
+/// Similarity measure an embedding space is meant to be queried with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Distance {
+    Cosine,
+    Dot,
+    Euclidean,
+}
@@ -0,0 +1,157 @@
+//! Per-model concurrency limits.
+//!
+//! `SEMEMBED_MODEL_CONCURRENCY` caps the embedding calls each model runs at
+//! once (`model=limit,...`; models not listed are unlimited), so a burst of
+//! requests for a large, slow model can't fill the inference queue and starve
+//! a small, fast one. A call over its model's limit is refused at once with a
+//! `429` naming the model; other models are unaffected. A call within its
+//! model's limit still waits for turns on the shared inference queue, which
+//! bounds every model together: the per-model limits only decide which calls
+//! get to wait for it.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail};
+use axum::http::StatusCode;
+use prometheus::{CounterVec, IntGauge, IntGaugeVec, Opts, Registry};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::error::ApiError;
+
+/// Parse `model=limit` pairs, comma-separated.
+pub fn parse(spec: &str) -> anyhow::Result<HashMap<String, usize>> {
+    let mut limits = HashMap::new();
+    for entry in spec.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let (model, limit) = entry
+            .rsplit_once('=')
+            .ok_or_else(|| anyhow!("invalid model concurrency {:?} (expected model=limit)", entry))?;
+        let model = model.trim();
+        let limit: usize = limit
+            .trim()
+            .parse()
+            .ok()
+            .filter(|limit| *limit > 0)
+            .ok_or_else(|| anyhow!("invalid limit in {:?} (expected an integer of at least 1)", entry))?;
+        if model.is_empty() {
+            bail!("invalid model concurrency {:?} (expected model=limit)", entry);
+        }
+        if limits.insert(model.to_string(), limit).is_some() {
+            bail!("model concurrency for {:?} is set more than once", model);
+        }
+    }
+    Ok(limits)
+}
+
+/// The limit of each model and the calls each has in flight.
+pub(crate) struct ModelLimits {
+    limits: HashMap<String, (usize, Arc<Semaphore>)>,
+    in_flight: IntGaugeVec,
+    rejected: CounterVec,
+}
+
+impl ModelLimits {
+    pub fn new(limits: HashMap<String, usize>, registry: &Registry) -> anyhow::Result<Self> {
+        let in_flight = IntGaugeVec::new(
+            Opts::new("semembed_model_in_flight", "Embedding calls running or queued, by model"),
+            &["model"],
+        )?;
+        let rejected = CounterVec::new(
+            Opts::new("semembed_model_rejected_total", "Embedding calls refused over their model's concurrency limit"),
+            &["model"],
+        )?;
+        registry.register(Box::new(in_flight.clone()))?;
+        registry.register(Box::new(rejected.clone()))?;
+        Ok(Self {
+            limits: limits
+                .into_iter()
+                .map(|(model, limit)| (model, (limit, Arc::new(Semaphore::new(limit)))))
+                .collect(),
+            in_flight,
+            rejected,
+        })
+    }
+
+    /// A slot for one call to `model` until the permit is dropped, or a `429`
+    /// if its limit is reached.
+    pub fn acquire(&self, model: &str) -> Result<ModelPermit, ApiError> {
+        let permit = match self.limits.get(model) {
+            Some((limit, slots)) => match slots.clone().try_acquire_owned() {
+                Ok(permit) => Some(permit),
+                Err(_) => {
+                    self.rejected.with_label_values(&[model]).inc();
+                    return Err(ApiError::new(
+                        StatusCode::TOO_MANY_REQUESTS,
+                        "rate_limit_error",
+                        format!(
+                            "The model `{}` is at its limit of {} concurrent requests; retry shortly or use another model",
+                            model, limit
+                        ),
+                    )
+                    .code("model_concurrency_exceeded")
+                    .reason("model_concurrency_exceeded")
+                    .retry_after(1));
+                }
+            },
+            None => None,
+        };
+        let in_flight = self.in_flight.with_label_values(&[model]);
+        in_flight.inc();
+        Ok(ModelPermit {
+            _permit: permit,
+            in_flight,
+        })
+    }
+}
+
+/// A call's slot on its model, released when dropped.
+pub(crate) struct ModelPermit {
+    _permit: Option<OwnedSemaphorePermit>,
+    in_flight: IntGauge,
+}
+
+impl Drop for ModelPermit {
+    fn drop(&mut self) {
+        self.in_flight.dec();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::response::IntoResponse;
+
+    #[test]
+    fn limits_parse() {
+        let limits = parse(" BAAI/bge-large-en-v1.5=4, colbert-ir/colbertv2.0 = 2 ,").unwrap();
+        assert_eq!(limits["BAAI/bge-large-en-v1.5"], 4);
+        assert_eq!(limits["colbert-ir/colbertv2.0"], 2);
+        assert!(parse("").unwrap().is_empty());
+        for invalid in ["model", "model=0", "model=x", "=2", "a=1,a=2"] {
+            assert!(parse(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn a_model_at_its_limit_leaves_the_others_alone() {
+        let limits = ModelLimits::new(parse("slow=2").unwrap(), &Registry::new()).unwrap();
+        let first = limits.acquire("slow").unwrap();
+        let _second = limits.acquire("slow").unwrap();
+        let refused = limits.acquire("slow").err().unwrap().into_response();
+        assert_eq!(refused.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(refused.headers()["retry-after"], "1");
+        assert_eq!(limits.rejected.with_label_values(&["slow"]).get(), 1.0);
+
+        // Unlisted models have no limit
+        let fast: Vec<ModelPermit> = (0..10).map(|_| limits.acquire("fast").unwrap()).collect();
+        assert_eq!(limits.in_flight.with_label_values(&["fast"]).get(), 10);
+        assert_eq!(limits.in_flight.with_label_values(&["slow"]).get(), 2);
+
+        // A released slot can be taken again
+        drop(first);
+        assert_eq!(limits.in_flight.with_label_values(&["slow"]).get(), 1);
+        assert!(limits.acquire("slow").is_ok());
+        drop(fast);
+        assert_eq!(limits.in_flight.with_label_values(&["fast"]).get(), 0);
+    }
+}
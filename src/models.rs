@@ -0,0 +1,715 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail};
+use fastembed::{EmbeddingModel, QuantizationMode, TextEmbedding};
+use semembed::{Distance, ModelMetadata, Quantization};
+use serde::Deserialize;
+use utoipa::openapi::schema::{ObjectBuilder, Schema, Type};
+use utoipa::openapi::RefOr;
+use utoipa::{PartialSchema, ToSchema};
+
+/// A model semembed knows how to serve.
+#[derive(Debug, Clone)]
+pub struct ModelSpec {
+    pub name: &'static str,
+    pub model: EmbeddingModel,
+    /// Maximum sequence length in tokens passed to the tokenizer.
+    pub max_tokens: usize,
+    /// Prefixes the model was trained with, applied according to `input_type`.
+    pub query_prefix: Option<&'static str>,
+    pub passage_prefix: Option<&'static str>,
+    /// Input type assumed for requests that don't give one.
+    pub default_input: InputKind,
+    /// The model's own task vocabulary, for models trained with a prefix per
+    /// task; empty for the rest, which accept Jina's task names.
+    pub tasks: &'static [ModelTask],
+    pub languages: &'static [&'static str],
+    pub distance: Distance,
+}
+
+/// A task a model was trained with and the prefix that selects it.
+#[derive(Debug, Clone, Copy)]
+pub struct ModelTask {
+    pub name: &'static str,
+    pub prefix: &'static str,
+    /// Input type the task amounts to, for checking it against `input_type`.
+    pub kind: Option<InputKind>,
+}
+
+// nomic-embed-text needs one of these in front of every input
+const NOMIC_TASKS: &[ModelTask] = &[
+    ModelTask {
+        name: "search_query",
+        prefix: "search_query: ",
+        kind: Some(InputKind::Query),
+    },
+    ModelTask {
+        name: "search_document",
+        prefix: "search_document: ",
+        kind: Some(InputKind::Passage),
+    },
+    ModelTask {
+        name: "classification",
+        prefix: "classification: ",
+        kind: None,
+    },
+    ModelTask {
+        name: "clustering",
+        prefix: "clustering: ",
+        kind: None,
+    },
+];
+
+const ENGLISH: &[&str] = &["en"];
+const MULTILINGUAL: &[&str] = &["multilingual"];
+
+static CATALOG: &[ModelSpec] = &[
+    ModelSpec {
+        name: "BAAI/bge-small-en-v1.5",
+        model: EmbeddingModel::BGESmallENV15,
+        max_tokens: 512,
+        query_prefix: None,
+        passage_prefix: None,
+        default_input: InputKind::Query,
+        tasks: &[],
+        languages: ENGLISH,
+        distance: Distance::Cosine,
+    },
+    ModelSpec {
+        name: "BAAI/bge-base-en-v1.5",
+        model: EmbeddingModel::BGEBaseENV15,
+        max_tokens: 512,
+        query_prefix: None,
+        passage_prefix: None,
+        default_input: InputKind::Query,
+        tasks: &[],
+        languages: ENGLISH,
+        distance: Distance::Cosine,
+    },
+    ModelSpec {
+        name: "Qdrant/bge-small-en-v1.5-onnx-Q",
+        model: EmbeddingModel::BGESmallENV15Q,
+        max_tokens: 512,
+        query_prefix: None,
+        passage_prefix: None,
+        default_input: InputKind::Query,
+        tasks: &[],
+        languages: ENGLISH,
+        distance: Distance::Cosine,
+    },
+    ModelSpec {
+        name: "Qdrant/bge-base-en-v1.5-onnx-Q",
+        model: EmbeddingModel::BGEBaseENV15Q,
+        max_tokens: 512,
+        query_prefix: None,
+        passage_prefix: None,
+        default_input: InputKind::Query,
+        tasks: &[],
+        languages: ENGLISH,
+        distance: Distance::Cosine,
+    },
+    ModelSpec {
+        name: "sentence-transformers/all-MiniLM-L6-v2",
+        model: EmbeddingModel::AllMiniLML6V2,
+        max_tokens: 256,
+        query_prefix: None,
+        passage_prefix: None,
+        default_input: InputKind::Query,
+        tasks: &[],
+        languages: ENGLISH,
+        distance: Distance::Cosine,
+    },
+    ModelSpec {
+        name: "BAAI/bge-m3",
+        model: EmbeddingModel::BGEM3,
+        max_tokens: 8192,
+        query_prefix: None,
+        passage_prefix: None,
+        default_input: InputKind::Query,
+        tasks: &[],
+        languages: MULTILINGUAL,
+        distance: Distance::Cosine,
+    },
+    ModelSpec {
+        name: "nomic-ai/nomic-embed-text-v1",
+        model: EmbeddingModel::NomicEmbedTextV1,
+        max_tokens: 8192,
+        query_prefix: Some("search_query: "),
+        passage_prefix: Some("search_document: "),
+        default_input: InputKind::Passage,
+        tasks: NOMIC_TASKS,
+        languages: ENGLISH,
+        distance: Distance::Cosine,
+    },
+    ModelSpec {
+        name: "nomic-ai/nomic-embed-text-v1.5",
+        model: EmbeddingModel::NomicEmbedTextV15,
+        max_tokens: 8192,
+        query_prefix: Some("search_query: "),
+        passage_prefix: Some("search_document: "),
+        default_input: InputKind::Passage,
+        tasks: NOMIC_TASKS,
+        languages: ENGLISH,
+        distance: Distance::Cosine,
+    },
+    ModelSpec {
+        name: "intfloat/multilingual-e5-small",
+        model: EmbeddingModel::MultilingualE5Small,
+        max_tokens: 512,
+        query_prefix: Some("query: "),
+        passage_prefix: Some("passage: "),
+        default_input: InputKind::Query,
+        tasks: &[],
+        languages: MULTILINGUAL,
+        distance: Distance::Cosine,
+    },
+    ModelSpec {
+        name: "intfloat/multilingual-e5-base",
+        model: EmbeddingModel::MultilingualE5Base,
+        max_tokens: 512,
+        query_prefix: Some("query: "),
+        passage_prefix: Some("passage: "),
+        default_input: InputKind::Query,
+        tasks: &[],
+        languages: MULTILINGUAL,
+        distance: Distance::Cosine,
+    },
+    ModelSpec {
+        name: "intfloat/multilingual-e5-large",
+        model: EmbeddingModel::MultilingualE5Large,
+        max_tokens: 512,
+        query_prefix: Some("query: "),
+        passage_prefix: Some("passage: "),
+        default_input: InputKind::Query,
+        tasks: &[],
+        languages: MULTILINGUAL,
+        distance: Distance::Cosine,
+    },
+];
+
+/// Names of every catalog model, in catalog order.
+pub fn model_names() -> Vec<&'static str> {
+    CATALOG.iter().map(|spec| spec.name).collect()
+}
+
+/// Look up a configured model name in the catalog.
+pub fn model_spec(name: &str) -> Option<&'static ModelSpec> {
+    CATALOG.iter().find(|spec| spec.name == name)
+}
+
+/// Metadata for a catalog model, combining fastembed's model description with
+/// our own table. `None` if fastembed does not know the model.
+pub fn model_metadata(spec: &ModelSpec) -> Option<ModelMetadata> {
+    let info = TextEmbedding::get_model_info(&spec.model).ok()?;
+    let quantization = match TextEmbedding::get_quantization_mode(&spec.model) {
+        QuantizationMode::None => Quantization::None,
+        QuantizationMode::Static => Quantization::Static,
+        QuantizationMode::Dynamic => Quantization::Dynamic,
+    };
+    Some(ModelMetadata {
+        dimensions: info.dim,
+        max_tokens: spec.max_tokens,
+        languages: spec.languages.iter().map(|l| l.to_string()).collect(),
+        quantization,
+        prefixes_applied: spec.query_prefix.is_some() || spec.passage_prefix.is_some(),
+        query_prefix: spec.query_prefix.map(str::to_string),
+        passage_prefix: spec.passage_prefix.map(str::to_string),
+        distance: spec.distance,
+        tasks: spec.task_names().into_iter().map(str::to_string).collect(),
+        description: info.description.clone(),
+    })
+}
+
+/// Whether an input is a search query or a document/passage being indexed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InputKind {
+    Query,
+    #[serde(alias = "document")]
+    Passage,
+}
+
+// Written by hand so the schema accepts the `document` alias too
+impl PartialSchema for InputKind {
+    fn schema() -> RefOr<Schema> {
+        ObjectBuilder::new()
+            .schema_type(Type::String)
+            .description(Some("Whether an input is a search query or a document/passage being indexed."))
+            .enum_values(Some(["query", "passage", "document"]))
+            .into()
+    }
+}
+
+impl ToSchema for InputKind {}
+
+/// The input type and prefix a request's `task` and `input_type` select.
+#[derive(Debug, Clone, Copy)]
+pub struct TaskSelection {
+    pub input_type: Option<InputKind>,
+    pub prefix: Option<&'static str>,
+}
+
+impl ModelSpec {
+    /// Prefix to prepend for the given input type. Without one, e5 models get
+    /// the query prefix, which is what the e5 authors recommend for symmetric
+    /// tasks, and nomic models the document prefix, as nomic recommends.
+    pub fn prefix(&self, kind: Option<InputKind>) -> Option<&'static str> {
+        match kind.unwrap_or(self.default_input) {
+            InputKind::Passage => self.passage_prefix,
+            InputKind::Query => self.query_prefix,
+        }
+    }
+
+    /// Tasks a request can name for this model.
+    pub fn task_names(&self) -> Vec<&'static str> {
+        if self.tasks.is_empty() {
+            Task::ALL.iter().map(|task| task.name()).collect()
+        } else {
+            self.tasks.iter().map(|task| task.name).collect()
+        }
+    }
+
+    /// Resolve a request's `task` against the model's vocabulary (or Jina's
+    /// task names, for models without one). The task must not contradict an
+    /// explicit `input_type`; a task of the model's own picks its prefix even
+    /// when it amounts to no particular input type.
+    pub fn select(&self, task: Option<&str>, input_type: Option<InputKind>) -> anyhow::Result<TaskSelection> {
+        let Some(name) = task else {
+            return Ok(TaskSelection {
+                input_type,
+                prefix: self.prefix(input_type),
+            });
+        };
+        let (kind, prefix) = if self.tasks.is_empty() {
+            let task = name
+                .parse::<Task>()
+                .map_err(|_| self.unsupported_task(name))?
+                .input_kind();
+            (task, None)
+        } else {
+            let task = self
+                .tasks
+                .iter()
+                .find(|task| task.name == name)
+                .ok_or_else(|| self.unsupported_task(name))?;
+            (task.kind, Some(task.prefix))
+        };
+        if let (Some(kind), Some(requested)) = (kind, input_type) {
+            if kind != requested {
+                bail!("task {} contradicts the request's input_type", name);
+            }
+        }
+        let input_type = input_type.or(kind);
+        Ok(TaskSelection {
+            input_type,
+            prefix: prefix.or_else(|| self.prefix(input_type)),
+        })
+    }
+
+    fn unsupported_task(&self, name: &str) -> anyhow::Error {
+        anyhow!(
+            "unsupported task {:?} for model {}; supported tasks are {}",
+            name,
+            self.name,
+            self.task_names().join(", ")
+        )
+    }
+}
+
+/// A request's `task`, as named by Jina's embeddings API.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Task {
+    RetrievalQuery,
+    RetrievalPassage,
+    TextMatching,
+    Classification,
+    Separation,
+}
+
+impl Task {
+    pub const ALL: [Task; 5] = [
+        Self::RetrievalQuery,
+        Self::RetrievalPassage,
+        Self::TextMatching,
+        Self::Classification,
+        Self::Separation,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::RetrievalQuery => "retrieval.query",
+            Self::RetrievalPassage => "retrieval.passage",
+            Self::TextMatching => "text-matching",
+            Self::Classification => "classification",
+            Self::Separation => "separation",
+        }
+    }
+
+    /// The input type the task selects. Symmetric tasks have none, so they get
+    /// the model's default prefix.
+    pub fn input_kind(self) -> Option<InputKind> {
+        match self {
+            Self::RetrievalQuery => Some(InputKind::Query),
+            Self::RetrievalPassage => Some(InputKind::Passage),
+            Self::TextMatching | Self::Classification | Self::Separation => None,
+        }
+    }
+}
+
+impl FromStr for Task {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|task| task.name() == s)
+            .ok_or_else(|| anyhow!("unknown task {:?}", s))
+    }
+}
+
+/// Prefix for a query embedded with a task instruction, in the format
+/// instruct-tuned models (e5-mistral, gte-Qwen) were trained with. It takes the
+/// place of the model's query prefix rather than being added to it.
+pub fn instruction_prefix(instruction: &str) -> String {
+    format!("Instruct: {}\nQuery: ", instruction)
+}
+
+/// Parse default instructions: a JSON object mapping model names to the
+/// instruction applied to their queries, e.g.
+/// `{"intfloat/e5-mistral-7b-instruct": "Given a web search query, retrieve relevant passages"}`.
+pub fn parse_instructions(spec: &str) -> anyhow::Result<HashMap<String, String>> {
+    if spec.trim().is_empty() {
+        return Ok(HashMap::new());
+    }
+    let instructions: HashMap<String, String> = serde_json::from_str(spec)
+        .map_err(|e| anyhow!("invalid default instructions (expected a JSON object of model to instruction): {}", e))?;
+    if let Some((model, _)) = instructions.iter().find(|(_, instruction)| instruction.trim().is_empty()) {
+        bail!("default instruction for {:?} is empty", model);
+    }
+    Ok(instructions)
+}
+
+/// Best-effort lookup of the Hugging Face commit a cached model was downloaded at.
+///
+/// hf-hub records the resolved commit in `models--{org}--{repo}/refs/main` under
+/// the fastembed cache directory.
+pub fn model_revision(model: &EmbeddingModel, cache_dir: &Path) -> Option<String> {
+    let info = TextEmbedding::get_model_info(model).ok()?;
+    let repo_dir = format!("models--{}", info.model_code.replace('/', "--"));
+    let revision = std::fs::read_to_string(cache_dir.join(repo_dir).join("refs").join("main")).ok()?;
+    let revision = revision.trim();
+    (!revision.is_empty()).then(|| revision.to_string())
+}
+
+/// Size of a cached model's files, as an estimate of the memory it takes once
+/// loaded. `None` until it has been downloaded.
+pub fn model_bytes(model: &EmbeddingModel, cache_dir: &Path) -> Option<u64> {
+    fn size(path: &Path) -> u64 {
+        // Snapshot entries are symlinks into the blob store; metadata follows them
+        match std::fs::metadata(path) {
+            Ok(metadata) if metadata.is_dir() => std::fs::read_dir(path)
+                .map(|entries| entries.flatten().map(|entry| size(&entry.path())).sum())
+                .unwrap_or(0),
+            Ok(metadata) => metadata.len(),
+            Err(_) => 0,
+        }
+    }
+
+    let revision = model_revision(model, cache_dir)?;
+    let info = TextEmbedding::get_model_info(model).ok()?;
+    let repo_dir = format!("models--{}", info.model_code.replace('/', "--"));
+    let bytes = size(&cache_dir.join(repo_dir).join("snapshots").join(revision));
+    (bytes > 0).then_some(bytes)
+}
+
+/// Which name the response's `model` field reports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ModelEcho {
+    /// Echo the model string the client sent (canonical name when omitted).
+    #[default]
+    Requested,
+    /// Always report the canonical name of the model that served the request.
+    Canonical,
+}
+
+impl FromStr for ModelEcho {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "requested" => Ok(Self::Requested),
+            "canonical" => Ok(Self::Canonical),
+            other => bail!("invalid model echo mode {:?} (expected \"requested\" or \"canonical\")", other),
+        }
+    }
+}
+
+/// Parse an alias table of the form `alias=model,alias=model`.
+pub fn parse_aliases(spec: &str) -> anyhow::Result<HashMap<String, String>> {
+    let mut aliases = HashMap::new();
+    for entry in spec.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let (alias, target) = entry
+            .split_once('=')
+            .ok_or_else(|| anyhow!("invalid model alias {:?} (expected alias=model)", entry))?;
+        let (alias, target) = (alias.trim(), target.trim());
+        if alias.is_empty() || target.is_empty() {
+            bail!("invalid model alias {:?} (expected alias=model)", entry);
+        }
+        if aliases.insert(alias.to_string(), target.to_string()).is_some() {
+            bail!("model alias {:?} is defined more than once", alias);
+        }
+    }
+    Ok(aliases)
+}
+
+/// Outcome of resolving a request's `model` field.
+#[derive(Debug, Clone, Copy)]
+pub struct ResolvedModel<'a> {
+    /// Canonical name of the loaded model that will serve the request.
+    pub canonical: &'a str,
+    /// The alias the client used, if any.
+    pub alias: Option<&'a str>,
+    /// The model string exactly as the client sent it.
+    pub requested: Option<&'a str>,
+}
+
+impl<'a> ResolvedModel<'a> {
+    /// Name to report in the response's `model` field.
+    pub fn response_name(&self, echo: ModelEcho) -> &'a str {
+        match (echo, self.requested) {
+            (ModelEcho::Requested, Some(requested)) => requested,
+            _ => self.canonical,
+        }
+    }
+}
+
+/// Resolves requested model names (canonical or aliased) to loaded models.
+pub struct ModelResolver {
+    loaded: Vec<String>,
+    aliases: HashMap<String, String>,
+}
+
+impl ModelResolver {
+    /// Build a resolver over the loaded models. The first model is the default
+    /// used when a request omits `model`. Every alias must target a loaded model.
+    pub fn new(loaded: Vec<String>, aliases: HashMap<String, String>) -> anyhow::Result<Self> {
+        if loaded.is_empty() {
+            bail!("at least one model must be loaded");
+        }
+        for (alias, target) in &aliases {
+            if !loaded.contains(target) {
+                bail!(
+                    "model alias {:?} points at {:?}, which is not loaded (loaded: {})",
+                    alias,
+                    target,
+                    loaded.join(", ")
+                );
+            }
+            if loaded.contains(alias) {
+                bail!("model alias {:?} shadows a loaded model of the same name", alias);
+            }
+        }
+        Ok(Self { loaded, aliases })
+    }
+
+    /// Resolve a requested model name. `None` selects the default model.
+    pub fn resolve<'a>(&'a self, requested: Option<&'a str>) -> Option<ResolvedModel<'a>> {
+        let Some(requested) = requested else {
+            return Some(ResolvedModel {
+                canonical: &self.loaded[0],
+                alias: None,
+                requested: None,
+            });
+        };
+
+        if let Some(name) = self.loaded.iter().find(|m| *m == requested) {
+            return Some(ResolvedModel {
+                canonical: name,
+                alias: None,
+                requested: Some(requested),
+            });
+        }
+
+        self.aliases.get_key_value(requested).map(|(alias, target)| ResolvedModel {
+            canonical: target,
+            alias: Some(alias),
+            requested: Some(requested),
+        })
+    }
+
+    /// Canonical names of all loaded models.
+    pub fn loaded(&self) -> &[String] {
+        &self.loaded
+    }
+
+    /// Configured aliases and their targets.
+    pub fn aliases(&self) -> &HashMap<String, String> {
+        &self.aliases
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resolver(loaded: &[&str], aliases: &[(&str, &str)]) -> ModelResolver {
+        ModelResolver::new(
+            loaded.iter().map(|name| name.to_string()).collect(),
+            aliases.iter().map(|(alias, target)| (alias.to_string(), target.to_string())).collect(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn single_model_echoes_the_request() {
+        let resolver = resolver(&["BAAI/bge-small-en-v1.5"], &[]);
+
+        let omitted = resolver.resolve(None).unwrap();
+        assert_eq!(omitted.canonical, "BAAI/bge-small-en-v1.5");
+        assert_eq!(omitted.response_name(ModelEcho::Requested), "BAAI/bge-small-en-v1.5");
+
+        let named = resolver.resolve(Some("BAAI/bge-small-en-v1.5")).unwrap();
+        assert_eq!(named.alias, None);
+        assert_eq!(named.response_name(ModelEcho::Requested), "BAAI/bge-small-en-v1.5");
+        assert!(resolver.resolve(Some("bge-small")).is_none());
+    }
+
+    #[test]
+    fn alias_echoes_the_alias_unless_canonical() {
+        let resolver = resolver(&["BAAI/bge-small-en-v1.5"], &[("bge-small", "BAAI/bge-small-en-v1.5")]);
+        let resolved = resolver.resolve(Some("bge-small")).unwrap();
+        assert_eq!(resolved.canonical, "BAAI/bge-small-en-v1.5");
+        assert_eq!(resolved.alias, Some("bge-small"));
+        assert_eq!(resolved.response_name(ModelEcho::Requested), "bge-small");
+        assert_eq!(resolved.response_name(ModelEcho::Canonical), "BAAI/bge-small-en-v1.5");
+    }
+
+    #[test]
+    fn several_models_resolve_to_their_own_names() {
+        let resolver = resolver(
+            &["BAAI/bge-small-en-v1.5", "intfloat/multilingual-e5-small"],
+            &[("e5", "intfloat/multilingual-e5-small")],
+        );
+        assert_eq!(resolver.resolve(None).unwrap().canonical, "BAAI/bge-small-en-v1.5");
+        let second = resolver.resolve(Some("intfloat/multilingual-e5-small")).unwrap();
+        assert_eq!(second.canonical, "intfloat/multilingual-e5-small");
+        let aliased = resolver.resolve(Some("e5")).unwrap();
+        assert_eq!(aliased.canonical, "intfloat/multilingual-e5-small");
+        assert_eq!(aliased.response_name(ModelEcho::Requested), "e5");
+    }
+
+    #[test]
+    fn aliases_must_target_loaded_models() {
+        let loaded = vec!["BAAI/bge-small-en-v1.5".to_string()];
+        let missing = HashMap::from([("e5".to_string(), "intfloat/multilingual-e5-small".to_string())]);
+        assert!(ModelResolver::new(loaded.clone(), missing).is_err());
+        let shadowing = HashMap::from([("BAAI/bge-small-en-v1.5".to_string(), "BAAI/bge-small-en-v1.5".to_string())]);
+        assert!(ModelResolver::new(loaded, shadowing).is_err());
+    }
+
+    #[test]
+    fn e5_models_get_their_prefixes() {
+        for name in ["intfloat/multilingual-e5-small", "intfloat/multilingual-e5-base", "intfloat/multilingual-e5-large"] {
+            let spec = model_spec(name).unwrap();
+            assert_eq!(spec.languages, MULTILINGUAL);
+            assert_eq!(spec.prefix(None), Some("query: "));
+            assert_eq!(spec.prefix(Some(InputKind::Passage)), Some("passage: "));
+            let retrieval = spec.select(Some("retrieval.passage"), None).unwrap();
+            assert_eq!(retrieval.prefix, Some("passage: "));
+            assert!(spec.select(Some("retrieval.query"), Some(InputKind::Passage)).is_err());
+        }
+        let bge_m3 = model_spec("BAAI/bge-m3").unwrap();
+        assert_eq!(bge_m3.max_tokens, 8192);
+        assert_eq!(bge_m3.prefix(Some(InputKind::Passage)), None);
+    }
+
+    #[test]
+    fn multilingual_metadata_has_their_shape() {
+        let e5 = model_metadata(model_spec("intfloat/multilingual-e5-large").unwrap()).unwrap();
+        assert_eq!((e5.dimensions, e5.max_tokens), (1024, 512));
+        assert!(e5.prefixes_applied);
+        let bge_m3 = model_metadata(model_spec("BAAI/bge-m3").unwrap()).unwrap();
+        assert_eq!((bge_m3.dimensions, bge_m3.max_tokens), (1024, 8192));
+        assert_eq!(bge_m3.languages, ["multilingual"]);
+    }
+
+    // Guards against prefix and pooling mistakes: a sentence and its
+    // translation must land close together
+    #[test]
+    #[ignore = "downloads intfloat/multilingual-e5-small and needs ONNX Runtime (ORT_DYLIB_PATH)"]
+    fn e5_embeds_translations_close_together() {
+        let spec = model_spec("intfloat/multilingual-e5-small").unwrap();
+        let mut model = TextEmbedding::try_new(fastembed::InitOptions::new(spec.model.clone())).unwrap();
+        let prefix = spec.prefix(None).unwrap_or_default();
+        let texts: Vec<String> = [
+            "The library opens at nine in the morning.",
+            "La biblioteca abre a las nueve de la mañana.",
+            "Volcanic eruptions can disrupt air travel for weeks.",
+        ]
+        .iter()
+        .map(|text| format!("{}{}", prefix, text))
+        .collect();
+        let embeddings = model.embed(texts, None).unwrap();
+        let translated = crate::shadow::cosine(&embeddings[0], &embeddings[1]);
+        let unrelated = crate::shadow::cosine(&embeddings[0], &embeddings[2]);
+        assert!(translated > 0.85, "translation similarity {}", translated);
+        assert!(translated > unrelated + 0.05, "translation {} vs unrelated {}", translated, unrelated);
+    }
+
+    #[test]
+    fn nomic_models_take_their_own_tasks() {
+        for name in ["nomic-ai/nomic-embed-text-v1", "nomic-ai/nomic-embed-text-v1.5"] {
+            let spec = model_spec(name).unwrap();
+            assert_eq!(spec.task_names(), ["search_query", "search_document", "classification", "clustering"]);
+            let metadata = model_metadata(spec).unwrap();
+            assert_eq!(metadata.tasks, ["search_query", "search_document", "classification", "clustering"]);
+
+            let query = spec.select(Some("search_query"), None).unwrap();
+            assert_eq!(query.input_type, Some(InputKind::Query));
+            let clustering = spec.select(Some("clustering"), None).unwrap();
+            assert_eq!(clustering.input_type, None);
+            assert!(spec.select(Some("search_query"), Some(InputKind::Passage)).is_err());
+
+            // Jina's names aren't nomic's
+            let error = spec.select(Some("retrieval.query"), None).unwrap_err().to_string();
+            assert!(error.contains(name), "{}", error);
+            assert!(
+                error.contains("supported tasks are search_query, search_document, classification, clustering"),
+                "{}",
+                error
+            );
+        }
+    }
+
+    #[test]
+    fn jina_tasks_select_input_types() {
+        let bge = model_spec("BAAI/bge-small-en-v1.5").unwrap();
+        let kind = |task| bge.select(Some(task), None).unwrap().input_type;
+        assert_eq!(kind("retrieval.query"), Some(InputKind::Query));
+        assert_eq!(kind("retrieval.passage"), Some(InputKind::Passage));
+        for symmetric in ["text-matching", "classification", "separation"] {
+            assert_eq!(kind(symmetric), None, "{}", symmetric);
+        }
+        // A symmetric task leaves an explicit input_type alone
+        let selection = bge.select(Some("text-matching"), Some(InputKind::Query)).unwrap();
+        assert_eq!(selection.input_type, Some(InputKind::Query));
+        assert!(bge.select(Some("retrieval.passage"), Some(InputKind::Query)).is_err());
+
+        let error = bge.select(Some("code.query"), None).unwrap_err().to_string();
+        assert!(error.contains("unsupported task \"code.query\""), "{}", error);
+        assert!(
+            error.contains("retrieval.query, retrieval.passage, text-matching, classification, separation"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn parses_echo_modes() {
+        assert_eq!(" Canonical ".parse::<ModelEcho>().unwrap(), ModelEcho::Canonical);
+        assert_eq!("requested".parse::<ModelEcho>().unwrap(), ModelEcho::Requested);
+        assert!("alias".parse::<ModelEcho>().is_err());
+    }
+}
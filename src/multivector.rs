@@ -0,0 +1,266 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::str::FromStr;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use fastembed::{Bgem3Embedding, Bgem3InitOptions, Bgem3Model};
+use tracing::{info, warn};
+
+use crate::embedder::{CircuitConfig, TokenCount, TruncationSide};
+use crate::redact::redact_inputs;
+
+// BGE-M3's context length
+const MAX_TOKENS: usize = 8192;
+
+// Longest the circuit stays open after repeated failures
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Why a multi-vector request failed.
+#[derive(Debug)]
+pub enum MultiVectorError {
+    /// The inputs exceed the per-request token budget; carries the total.
+    TooManyTokens(usize),
+    /// Loading the model or inference failed.
+    Failed(anyhow::Error),
+    /// The circuit is open after repeated failures; retry after the given delay.
+    Unavailable(Duration),
+}
+
+/// Per-token vectors for each input, with the token counts they came from.
+pub struct MultiVectorOutput {
+    /// One `[tokens, dim]` matrix per input.
+    pub vectors: Vec<Vec<Vec<f32>>>,
+    pub counts: Vec<TokenCount>,
+}
+
+/// Late-interaction (ColBERT-style) embeddings: one vector per token.
+///
+/// fastembed exposes these through BGE-M3's ColBERT head. The model is large
+/// and rarely used, so it is loaded on the first request rather than at startup.
+///
+/// After `failure_threshold` consecutive failures the model is unloaded and
+/// requests fail fast until the circuit's backoff expires; the next request
+/// then loads it again.
+pub struct MultiVector {
+    model: Bgem3Model,
+    name: String,
+    dimensions: usize,
+    max_inputs: usize,
+    loaded: Mutex<Option<Bgem3Embedding>>,
+    breaker: Mutex<Breaker>,
+}
+
+impl MultiVector {
+    /// Configure a model by its Hugging Face name; nothing is downloaded yet.
+    /// `max_inputs` caps the batch size, since responses grow with every token.
+    pub fn new(name: &str, max_inputs: usize, circuit: CircuitConfig) -> anyhow::Result<Self> {
+        let model = Self::parse_model(name)?;
+        let dimensions = Bgem3Embedding::get_model_info(&model).dim;
+        Ok(Self {
+            name: model.to_string(),
+            model,
+            dimensions,
+            max_inputs,
+            loaded: Mutex::new(None),
+            breaker: Mutex::new(Breaker::new(circuit.failure_threshold, circuit.retry_after)),
+        })
+    }
+
+    /// The supported model a Hugging Face name refers to.
+    pub fn parse_model(name: &str) -> anyhow::Result<Bgem3Model> {
+        Bgem3Model::from_str(name).map_err(|_| {
+            anyhow::anyhow!(
+                "unsupported multi-vector model {:?} (supported: {})",
+                name,
+                Bgem3Embedding::list_supported_models()
+                    .iter()
+                    .map(|info| info.model_code.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )
+        })
+    }
+
+    /// Canonical model name.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Width of each per-token vector.
+    pub fn dimensions(&self) -> usize {
+        self.dimensions
+    }
+
+    /// Maximum number of inputs per request.
+    pub fn max_inputs(&self) -> usize {
+        self.max_inputs
+    }
+
+    /// Embed `texts`, refusing batches over `max_tokens` tokens in total. Blocks
+    /// while the model loads on first use.
+    pub fn embed(&self, texts: &[String], max_tokens: usize) -> Result<MultiVectorOutput, MultiVectorError> {
+        self.breaker().check(Instant::now()).map_err(MultiVectorError::Unavailable)?;
+        let mut loaded = self.loaded.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let result = Self::run(&self.model, &self.name, &mut loaded, texts, max_tokens);
+        match &result {
+            // Too many tokens is the caller's fault, not the model's
+            Ok(_) | Err(MultiVectorError::TooManyTokens(_)) => self.breaker().succeeded(),
+            Err(_) => {
+                if let Some(backoff) = self.breaker().failed(Instant::now()) {
+                    warn!("Unloading the multi-vector model after repeated failures; retrying in {:?}", backoff);
+                    *loaded = None;
+                }
+            }
+        }
+        result
+    }
+
+    fn breaker(&self) -> MutexGuard<'_, Breaker> {
+        self.breaker.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn run(
+        model: &Bgem3Model,
+        name: &str,
+        loaded: &mut Option<Bgem3Embedding>,
+        texts: &[String],
+        max_tokens: usize,
+    ) -> Result<MultiVectorOutput, MultiVectorError> {
+        if loaded.is_none() {
+            info!("Loading multi-vector model: {}", name);
+            let options = Bgem3InitOptions::new(model.clone()).with_max_length(MAX_TOKENS);
+            *loaded = Some(Bgem3Embedding::try_new(options).map_err(MultiVectorError::Failed)?);
+        }
+        let model = loaded.as_mut().expect("model was just loaded");
+
+        let inputs: Vec<&str> = texts.iter().map(String::as_str).collect();
+        let scrub = |e: &dyn std::fmt::Display| anyhow::anyhow!(redact_inputs(&e.to_string(), &inputs));
+
+        let counts: Vec<TokenCount> = model
+            .tokenizer
+            .encode_batch(inputs.clone(), true)
+            .map_err(|e| MultiVectorError::Failed(scrub(&e)))?
+            .iter()
+            .map(|encoding| TokenCount {
+                tokens: encoding.get_attention_mask().iter().filter(|&&mask| mask != 0).count(),
+                truncated: !encoding.get_overflowing().is_empty(),
+                // Overflowing pieces repeat the special tokens, which weren't cut
+                dropped: encoding
+                    .get_overflowing()
+                    .iter()
+                    .flat_map(|overflow| overflow.get_special_tokens_mask())
+                    .filter(|&&special| special == 0)
+                    .count(),
+                side: TruncationSide::End,
+            })
+            .collect();
+        let total: usize = counts.iter().map(|count| count.tokens).sum();
+        if total > max_tokens {
+            return Err(MultiVectorError::TooManyTokens(total));
+        }
+
+        let output = panic::catch_unwind(AssertUnwindSafe(|| model.embed(&inputs, None)))
+            .map_err(|_| MultiVectorError::Failed(anyhow::anyhow!("multi-vector model panicked")))?
+            .map_err(|e| MultiVectorError::Failed(scrub(&format!("{:#}", e))))?;
+        Ok(MultiVectorOutput {
+            vectors: output.colbert,
+            counts,
+        })
+    }
+}
+
+// Consecutive failures of the multi-vector model, and how long requests fail
+// fast once they reach the threshold
+struct Breaker {
+    threshold: u32,
+    retry_after: Duration,
+    failures: u32,
+    backoff: Duration,
+    open_until: Option<Instant>,
+}
+
+impl Breaker {
+    fn new(threshold: u32, retry_after: Duration) -> Self {
+        Self {
+            threshold,
+            retry_after,
+            failures: 0,
+            backoff: retry_after,
+            open_until: None,
+        }
+    }
+
+    // How much longer the circuit stays open, if it is
+    fn check(&mut self, now: Instant) -> Result<(), Duration> {
+        match self.open_until {
+            Some(until) if until > now => Err(until - now),
+            _ => Ok(()),
+        }
+    }
+
+    fn succeeded(&mut self) {
+        self.failures = 0;
+        self.backoff = self.retry_after;
+        self.open_until = None;
+    }
+
+    // The backoff when this failure opens the circuit; each time it opens
+    // again without a success in between, it stays open twice as long
+    fn failed(&mut self, now: Instant) -> Option<Duration> {
+        self.failures += 1;
+        if self.threshold == 0 || self.failures < self.threshold {
+            return None;
+        }
+        let backoff = self.backoff;
+        self.failures = 0;
+        self.backoff = (backoff * 2).min(MAX_BACKOFF);
+        self.open_until = Some(now + backoff);
+        Some(backoff)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn circuit_opens_after_consecutive_failures() {
+        let now = Instant::now();
+        let mut breaker = Breaker::new(3, Duration::from_secs(5));
+        assert_eq!(breaker.failed(now), None);
+        assert_eq!(breaker.failed(now), None);
+        assert_eq!(breaker.check(now), Ok(()));
+        assert_eq!(breaker.failed(now), Some(Duration::from_secs(5)));
+        assert_eq!(breaker.check(now + Duration::from_secs(2)), Err(Duration::from_secs(3)));
+        assert_eq!(breaker.check(now + Duration::from_secs(5)), Ok(()));
+    }
+
+    #[test]
+    fn successes_reset_the_count_and_backoff() {
+        let now = Instant::now();
+        let mut breaker = Breaker::new(2, Duration::from_secs(5));
+        breaker.failed(now);
+        breaker.succeeded();
+        assert_eq!(breaker.failed(now), None);
+        assert_eq!(breaker.failed(now), Some(Duration::from_secs(5)));
+        assert_eq!(breaker.failed(now), None);
+        assert_eq!(breaker.failed(now), Some(Duration::from_secs(10)));
+        breaker.succeeded();
+        assert_eq!(breaker.check(now), Ok(()));
+        breaker.failed(now);
+        assert_eq!(breaker.failed(now), Some(Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn backoff_is_capped_and_a_zero_threshold_never_opens() {
+        let now = Instant::now();
+        let mut breaker = Breaker::new(1, Duration::from_secs(40));
+        assert_eq!(breaker.failed(now), Some(Duration::from_secs(40)));
+        assert_eq!(breaker.failed(now), Some(MAX_BACKOFF));
+        let mut never = Breaker::new(0, Duration::from_secs(5));
+        for _ in 0..100 {
+            assert_eq!(never.failed(now), None);
+        }
+        assert_eq!(never.check(now), Ok(()));
+    }
+}
@@ -0,0 +1,380 @@
+use axum::Router;
+use utoipa::openapi::security::{HttpAuthScheme, HttpBuilder, SecurityRequirement, SecurityScheme};
+use utoipa::{Modify, OpenApi};
+use utoipa_swagger_ui::{Config, SwaggerUi};
+
+/// The OpenAPI 3.1 description of the core API served at `/openapi.json`.
+///
+/// Generated from the handlers' `#[utoipa::path]` attributes and the
+/// `ToSchema` derives on the request and response types, so a field added to
+/// one of them shows up here without further changes.
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "semembed", description = "OpenAI-compatible text embedding service"),
+    paths(
+        crate::create_embeddings,
+        crate::list_models_openai,
+        crate::retrieve_model,
+        crate::tokenizer::describe,
+        crate::tokenizer::download,
+        crate::health_check,
+        crate::liveness,
+    ),
+    modifiers(&BearerToken)
+)]
+struct ApiDoc;
+
+// Instances behind an authenticating proxy take a bearer token; the server
+// itself doesn't ask for one, so it is optional
+struct BearerToken;
+
+impl Modify for BearerToken {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let scheme = HttpBuilder::new()
+            .scheme(HttpAuthScheme::Bearer)
+            .description(Some("For instances behind an authenticating proxy"))
+            .build();
+        openapi.components.get_or_insert_with(Default::default).add_security_scheme("bearer", SecurityScheme::Http(scheme));
+        openapi.security = Some(vec![SecurityRequirement::default(), SecurityRequirement::new("bearer", Vec::<String>::new())]);
+    }
+}
+
+pub fn document() -> utoipa::openapi::OpenApi {
+    ApiDoc::openapi()
+}
+
+/// Routes for `/docs`, Swagger UI for trying requests against this instance,
+/// reading the document served at `/openapi.json`. Its assets are embedded in
+/// the binary, so the page works without network access.
+pub fn docs<S>() -> Router<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    let config = Config::from("/openapi.json").persist_authorization(true).try_it_out_enabled(true);
+    SwaggerUi::new("/docs").config(config).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use axum::body::{self, Body};
+    use axum::http::{header, Request, StatusCode};
+    use serde::Serialize;
+    use serde_json::{json, Value};
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::error::{ErrorDetail, ErrorResponse};
+    use crate::health::{Component, Status};
+    use crate::*;
+
+    fn spec() -> Value {
+        serde_json::to_value(document()).unwrap()
+    }
+
+    // Validates instances against one of the document's component schemas,
+    // resolving `$ref`s inside the document
+    fn validator(spec: &Value, schema: &str) -> jsonschema::Validator {
+        let root = json!({
+            "$ref": format!("#/components/schemas/{}", schema),
+            "components": spec["components"],
+        });
+        jsonschema::draft202012::new(&root).unwrap()
+    }
+
+    fn assert_valid(spec: &Value, schema: &str, instance: &impl Serialize) {
+        let instance = serde_json::to_value(instance).unwrap();
+        let validator = validator(spec, schema);
+        let errors: Vec<String> = validator.iter_errors(&instance).map(|e| format!("{} at {}", e, e.instance_path)).collect();
+        assert!(errors.is_empty(), "{} doesn't match {}: {:?}", instance, schema, errors);
+    }
+
+    fn refs<'a>(value: &'a Value, found: &mut Vec<&'a str>) {
+        match value {
+            Value::Object(fields) => {
+                if let Some(Value::String(target)) = fields.get("$ref") {
+                    found.push(target);
+                }
+                fields.values().for_each(|value| refs(value, found));
+            }
+            Value::Array(items) => items.iter().for_each(|value| refs(value, found)),
+            _ => {}
+        }
+    }
+
+    #[test]
+    fn document_is_a_valid_openapi_3_1_description() {
+        let spec = spec();
+        assert_eq!(spec["openapi"], "3.1.0");
+        assert_eq!(spec["info"]["title"], "semembed");
+        assert_eq!(spec["info"]["version"], env!("CARGO_PKG_VERSION"));
+
+        let schemas = spec["components"]["schemas"].as_object().unwrap();
+        for (name, schema) in schemas {
+            if let Err(e) = jsonschema::meta::validate(schema) {
+                panic!("{} is not a valid JSON Schema: {}", name, e);
+            }
+        }
+        let mut found = Vec::new();
+        refs(&spec, &mut found);
+        assert!(!found.is_empty());
+        for target in found {
+            let name = target.strip_prefix("#/components/schemas/").unwrap_or_else(|| panic!("{}", target));
+            assert!(schemas.contains_key(name), "{} is not defined", target);
+        }
+
+        let mut operations = Vec::new();
+        for (path, item) in spec["paths"].as_object().unwrap() {
+            for (method, operation) in item.as_object().unwrap() {
+                assert!(operation["responses"].as_object().is_some_and(|responses| !responses.is_empty()));
+                operations.push((path.as_str(), method.as_str(), operation["operationId"].as_str().unwrap()));
+            }
+        }
+        operations.sort();
+        assert_eq!(
+            operations,
+            [
+                ("/health", "get", "health"),
+                ("/health/live", "get", "liveness"),
+                ("/v1/embeddings", "post", "createEmbeddings"),
+                ("/v1/models", "get", "listModels"),
+                ("/v1/models/{model}", "get", "retrieveModel"),
+                ("/v1/models/{model}/tokenizer", "get", "retrieveTokenizer"),
+                ("/v1/models/{model}/tokenizer.json", "get", "downloadTokenizer"),
+            ]
+        );
+    }
+
+    #[test]
+    fn embedding_request_lists_its_fields() {
+        let spec = spec();
+        let request = &spec["components"]["schemas"]["EmbeddingRequest"];
+        assert_eq!(request["required"], json!(["input"]));
+        let properties = request["properties"].as_object().unwrap();
+        for field in ["input", "model", "encoding_format", "dimensions", "input_type", "precision", "output", "dtype"] {
+            assert!(properties.contains_key(field), "{} is missing", field);
+        }
+        assert_eq!(properties["input"]["$ref"], "#/components/schemas/InputType");
+        assert_eq!(spec["components"]["schemas"]["InputType"]["oneOf"].as_array().unwrap().len(), 3);
+        assert_eq!(properties["precision"]["maximum"], MAX_FLOAT_PRECISION);
+        let formats = &spec["components"]["schemas"]["EncodingFormat"]["enum"];
+        assert_eq!(formats, &json!(["float", "base64", "base64_matrix"]));
+        let post = &spec["paths"]["/v1/embeddings"]["post"];
+        assert!(post["responses"]["200"]["content"]["text/csv"].is_object());
+        assert_eq!(post["responses"]["400"]["content"]["application/json"]["schema"]["$ref"], "#/components/schemas/ErrorResponse");
+    }
+
+    #[test]
+    fn request_bodies_are_checked_like_the_server_checks_them() {
+        let spec = spec();
+        let validator = validator(&spec, "EmbeddingRequest");
+        for valid in [
+            json!({"input": "hello"}),
+            json!({"input": ["a", "b"], "model": "bge-small", "encoding_format": "base64", "dimensions": 64}),
+            json!({"input": [{"id": 1, "text": "a", "metadata": {"k": [1]}}, {"id": "b", "text": "b"}]}),
+            json!({"input": "a", "input_type": "document", "output": "multi_vector", "truncation_side": "start"}),
+            json!({"input": "a", "preprocess": {"normalize": "nfkc", "lowercase": true}, "precision": 9}),
+        ] {
+            assert!(validator.is_valid(&valid), "{} was refused", valid);
+            serde_json::from_value::<EmbeddingRequest>(valid).unwrap();
+        }
+        for invalid in [
+            json!({"model": "bge-small"}),
+            json!({"input": 42}),
+            json!({"input": []}),
+            json!({"input": ["a", 2]}),
+            json!({"input": [{"id": 1, "txt": "a"}]}),
+            json!({"input": "a", "encoding_format": "hex"}),
+            json!({"input": "a", "precision": 10}),
+            json!({"input": "a", "preprocess": {"stem": true}}),
+        ] {
+            assert!(!validator.is_valid(&invalid), "{} was accepted", invalid);
+        }
+    }
+
+    fn embedding(index: usize, embedding: EmbeddingData) -> EmbeddingObject {
+        EmbeddingObject {
+            object: "embedding".to_string(),
+            embedding,
+            index,
+            id: None,
+            metadata: None,
+            model: None,
+            detected: None,
+            tokens: None,
+            truncated: None,
+            truncation: None,
+            shape: None,
+            token_output: None,
+        }
+    }
+
+    fn response(data: Vec<EmbeddingItem>) -> EmbeddingResponse {
+        EmbeddingResponse {
+            object: "list".to_string(),
+            data,
+            model: "bge-small".to_string(),
+            usage: Usage {
+                prompt_tokens: 12,
+                total_tokens: 12,
+                vector_values: None,
+            },
+            semembed_model: ServedModel {
+                id: "BAAI/bge-small-en-v1.5".to_string(),
+                revision: Some("5c38ec7c405ec4b44b94cc5a9bb96e735b38267a".to_string()),
+            },
+            semembed_preprocess: vec!["nfc"],
+            semembed_summary: None,
+            semembed_instruction: None,
+            semembed_matrix: None,
+        }
+    }
+
+    #[test]
+    fn responses_match_their_schemas() {
+        let spec = spec();
+        let floats = EmbeddingData::encode(vec![0.25, -0.5], &EncodingFormat::Float, Some(3));
+        let base64 = EmbeddingData::encode(vec![0.25, -0.5], &EncodingFormat::Base64, None);
+        let detailed = EmbeddingObject {
+            id: Some(json!("doc-1")),
+            metadata: Some(json!({"source": "wiki"})),
+            detected: Some(DetectedLanguage {
+                language: Some("en"),
+                confidence: Some(0.9),
+            }),
+            tokens: Some(7),
+            truncated: Some(true),
+            truncation: Some(Truncation {
+                truncated_tokens: 3,
+                truncation_side: TruncationSide::Start,
+            }),
+            ..embedding(1, base64)
+        };
+        let failed = FailedItem {
+            object: "error".to_string(),
+            index: 2,
+            id: Some(json!(3)),
+            metadata: None,
+            error: ItemError {
+                message: "input[2] is empty".to_string(),
+                code: "empty_input",
+            },
+        };
+        let mut partial = response(vec![
+            EmbeddingItem::Embedding(embedding(0, floats)),
+            EmbeddingItem::Embedding(detailed),
+            EmbeddingItem::Failed(failed),
+        ]);
+        partial.semembed_summary = Some(Summary { succeeded: 2, failed: 1 });
+        assert_valid(&spec, "EmbeddingResponse", &partial);
+
+        let mut data = vec![EmbeddingItem::Embedding(embedding(0, EmbeddingData::encode(
+            vec![0.25, -0.5],
+            &EncodingFormat::Base64Matrix,
+            None,
+        )))];
+        let mut matrix = response(Vec::new());
+        matrix.semembed_matrix = Some(EmbeddingMatrix::gather(&mut data, MatrixDtype::Int8, 2).unwrap());
+        matrix.data = data;
+        assert_valid(&spec, "EmbeddingResponse", &matrix);
+
+        let tokens = EmbeddingObject {
+            shape: Some([2, 2]),
+            token_output: Some(TokenOutput {
+                token_ids: vec![101, 102],
+                token_strings: vec!["[CLS]".to_string(), "[SEP]".to_string()],
+                pooled: EmbeddingData::encode(vec![0.6, 0.8], &EncodingFormat::Base64, None),
+                pooling: "mean",
+            }),
+            ..embedding(0, EmbeddingData::encode_matrix(vec![vec![0.1, 0.2]; 2], &EncodingFormat::Float, None))
+        };
+        assert_valid(&spec, "EmbeddingResponse", &response(vec![EmbeddingItem::Embedding(tokens)]));
+
+        let error = ErrorResponse {
+            error: ErrorDetail {
+                message: "The model `x` does not exist".to_string(),
+                error_type: "model_not_found".to_string(),
+                param: None,
+                code: None,
+                request_id: Some("req-1".to_string()),
+                trace_id: None,
+            },
+        };
+        assert_valid(&spec, "ErrorResponse", &error);
+
+        let metadata = models::model_metadata(models::model_spec("BAAI/bge-small-en-v1.5").unwrap()).unwrap();
+        let limits = Limits {
+            max_inputs: 2048,
+            max_tokens_per_request: 300_000,
+            max_body_bytes: 1 << 20,
+            max_input_bytes: 1 << 16,
+            max_metadata_bytes: 1024,
+            max_metadata_total_bytes: 1 << 16,
+        };
+        let models = ModelList {
+            object: "list".to_string(),
+            data: vec![ModelObject::new("bge-small", &metadata, limits)],
+        };
+        assert_valid(&spec, "ModelList", &models);
+
+        let health = HealthResponse {
+            status: Status::Degraded,
+            model: "BAAI/bge-small-en-v1.5".to_string(),
+            dimensions: 384,
+            components: BTreeMap::from([(
+                "errors",
+                Component {
+                    status: Status::Degraded,
+                    last_error: Some("12% of recent responses were server errors".to_string()),
+                    last_error_at: Some("2026-10-16T09:30:00Z".to_string()),
+                },
+            )]),
+            saturation: Some(Saturation {
+                threshold: "queue_depth",
+                value: 300,
+                limit: 256,
+            }),
+            loading: vec!["intfloat/multilingual-e5-large".to_string()],
+        };
+        assert_valid(&spec, "HealthResponse", &health);
+    }
+
+    async fn get(path: &str) -> (StatusCode, String, String) {
+        let request = Request::get(path).body(Body::empty()).unwrap();
+        let response = docs::<()>().oneshot(request).await.unwrap();
+        let status = response.status();
+        let content_type = response.headers().get(header::CONTENT_TYPE).map_or("", |value| value.to_str().unwrap());
+        let content_type = content_type.to_string();
+        let bytes = body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, content_type, String::from_utf8_lossy(&bytes).into_owned())
+    }
+
+    #[tokio::test]
+    async fn docs_page_and_its_assets_are_served() {
+        let request = Request::get("/docs").body(Body::empty()).unwrap();
+        let response = docs::<()>().oneshot(request).await.unwrap();
+        assert!(response.status().is_redirection());
+        assert_eq!(response.headers()[header::LOCATION], "/docs/");
+
+        let (status, content_type, page) = get("/docs/").await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(content_type.starts_with("text/html"), "{}", content_type);
+        // Every stylesheet and script the page links to is embedded
+        let assets: Vec<&str> = page
+            .split(['"', '\''])
+            .filter(|link| link.starts_with("./") && (link.ends_with(".js") || link.ends_with(".css")))
+            .collect();
+        assert!(assets.len() >= 3, "{:?}", assets);
+        for asset in assets {
+            let (status, content_type, _) = get(&format!("/docs/{}", &asset[2..])).await;
+            assert_eq!(status, StatusCode::OK, "{}", asset);
+            assert!(content_type.contains("css") || content_type.contains("javascript"), "{}: {}", asset, content_type);
+        }
+        // It reads this instance's document
+        let (status, _, initializer) = get("/docs/swagger-initializer.js").await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(initializer.contains("/openapi.json"), "{}", initializer);
+        assert_eq!(get("/docs/missing.js").await.0, StatusCode::NOT_FOUND);
+    }
+}
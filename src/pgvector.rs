@@ -0,0 +1,482 @@
+//! Embedding straight into Postgres with pgvector (`--features pgvector`).
+//!
+//! [`PgVectorSink`] writes points as rows of a table with a `vector` column,
+//! keyed by an id column with a unique constraint, one upsert per row so each
+//! row succeeds or fails on its own. `POST /v1/index/pgvector` names the table
+//! and columns per request; as the `SEMEMBED_SINK` store, the columns come from
+//! the `SEMEMBED_PGVECTOR_*_COLUMN` variables. Table and column names are
+//! checked against a plain identifier pattern and quoted; every value is a
+//! bound parameter.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::{extract::State, http::StatusCode, Json};
+use futures_util::future::BoxFuture;
+use semembed::{Distance, Point, SinkError, VectorSink, Written};
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::{PgPool, PgPoolOptions};
+use sqlx::Row;
+
+use crate::error::ApiError;
+use crate::extract::{ApiJson, Scheduling};
+use crate::models::InputKind;
+use crate::sinks::{index_documents, Document, IndexJob, RowResult};
+use crate::{AppState, Summary};
+
+// Longest identifier Postgres keeps without truncating
+const MAX_IDENTIFIER: usize = 63;
+// SQLSTATE of ON CONFLICT without a matching unique constraint
+const NO_UNIQUE_CONSTRAINT: &str = "42P10";
+
+/// Connection pool settings and default columns, from the
+/// `SEMEMBED_PGVECTOR_*` variables.
+#[derive(Debug, Clone)]
+pub struct PgVectorConfig {
+    pub dsn: String,
+    pub max_connections: u32,
+    pub acquire_timeout: Duration,
+    pub columns: Columns,
+}
+
+/// The columns points are written into.
+#[derive(Debug, Clone)]
+pub struct Columns {
+    pub id: String,
+    pub vector: String,
+    // Where the text itself is stored; left alone when absent
+    pub text: Option<String>,
+    // Where each document's metadata is stored (`jsonb`); likewise
+    pub metadata: Option<String>,
+}
+
+/// Rows written through a pool. Connections are opened on first use, so an
+/// unreachable database doesn't stop the server from starting.
+#[derive(Clone)]
+pub struct PgVectorSink {
+    pool: PgPool,
+    columns: Columns,
+}
+
+impl PgVectorSink {
+    pub fn new(config: &PgVectorConfig) -> anyhow::Result<Self> {
+        let pool = PgPoolOptions::new()
+            .max_connections(config.max_connections)
+            .acquire_timeout(config.acquire_timeout)
+            .connect_lazy(&config.dsn)?;
+        Ok(Self {
+            pool,
+            columns: config.columns.clone(),
+        })
+    }
+
+    // The same pool, writing into other columns
+    fn with_columns(&self, columns: Columns) -> Self {
+        Self {
+            pool: self.pool.clone(),
+            columns,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct PgVectorRequest {
+    table: String,
+    id_column: String,
+    text_column: Option<String>,
+    metadata_column: Option<String>,
+    vector_column: String,
+    documents: Vec<Document>,
+    model: Option<String>,
+    input_type: Option<InputKind>,
+    dimensions: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct PgVectorResponse {
+    object: &'static str,
+    data: Vec<RowResult>,
+    model: String,
+    table: String,
+    summary: Summary,
+}
+
+// A column's type as Postgres spells it (`vector(384)`, `bigint`, ...), plus
+// the base type name and modifier the dimension check needs
+struct ColumnType {
+    formatted: String,
+    name: String,
+    modifier: i32,
+}
+
+fn unavailable(e: sqlx::Error) -> SinkError {
+    SinkError::retryable("database_unavailable", format!("The database is unavailable: {}", e))
+}
+
+// A plain identifier: a letter or underscore, then letters, digits or
+// underscores. Anything else (quotes, spaces, dots) is refused, not escaped.
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    name.len() <= MAX_IDENTIFIER
+        && chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+// The identifier quoted, so it keeps its case and may be a keyword
+fn quote(name: &str) -> String {
+    format!("\"{}\"", name)
+}
+
+// `table` or `schema.table`, quoted
+fn table_name(table: &str) -> Result<String, SinkError> {
+    let parts: Vec<&str> = table.split('.').collect();
+    if parts.len() > 2 || !parts.iter().all(|part| is_identifier(part)) {
+        return Err(SinkError::fatal(
+            "invalid_collection",
+            format!(
+                "table must be an identifier or schema.identifier of letters, digits and underscores, got {:?}",
+                table
+            ),
+        ));
+    }
+    Ok(parts.iter().map(|part| quote(part)).collect::<Vec<_>>().join("."))
+}
+
+fn column_name(column: &str) -> Result<String, SinkError> {
+    if !is_identifier(column) {
+        return Err(SinkError::fatal(
+            "invalid_column",
+            format!("Column names must be identifiers of letters, digits and underscores, got {:?}", column),
+        ));
+    }
+    Ok(quote(column))
+}
+
+// The text form of a pgvector value, `[0.1,0.2,...]`
+fn vector_literal(embedding: &[f32]) -> String {
+    let values: Vec<String> = embedding.iter().map(f32::to_string).collect();
+    format!("[{}]", values.join(","))
+}
+
+impl PgVectorSink {
+    // The upsert statement for `table`, with casts to its column types. The
+    // types come from the catalog, so the casts never carry client input, and
+    // a vector column of the wrong size fails before any row is written.
+    async fn statement(
+        &self,
+        conn: &mut sqlx::PgConnection,
+        table: &str,
+        dimensions: usize,
+    ) -> Result<String, SinkError> {
+        let quoted = table_name(table)?;
+        let id_column = column_name(&self.columns.id)?;
+        let vector_column = column_name(&self.columns.vector)?;
+        // Text, then metadata, after the id and vector, when configured
+        let optional: Vec<&String> = self.columns.text.iter().chain(&self.columns.metadata).collect();
+
+        let mut names = vec![self.columns.id.clone(), self.columns.vector.clone()];
+        names.extend(optional.iter().map(|name| name.to_string()));
+        let rows = sqlx::query(
+            "SELECT a.attname::text, format_type(a.atttypid, a.atttypmod), t.typname::text, a.atttypmod \
+             FROM pg_attribute a JOIN pg_type t ON t.oid = a.atttypid \
+             WHERE a.attrelid = to_regclass($1) AND a.attnum > 0 AND NOT a.attisdropped \
+             AND a.attname::text = ANY($2)",
+        )
+        .bind(&quoted)
+        .bind(&names)
+        .fetch_all(&mut *conn)
+        .await
+        .map_err(unavailable)?;
+        if rows.is_empty() {
+            return Err(SinkError::fatal(
+                "collection_not_found",
+                format!("The table {} does not exist or has none of the named columns", table),
+            ));
+        }
+        let column_type = |name: &str| -> Result<ColumnType, SinkError> {
+            rows.iter()
+                .find(|row| row.get::<String, _>(0) == name)
+                .map(|row| ColumnType {
+                    formatted: row.get(1),
+                    name: row.get(2),
+                    modifier: row.get(3),
+                })
+                .ok_or_else(|| {
+                    SinkError::fatal("column_not_found", format!("The table {} has no column {}", table, name))
+                })
+        };
+        let id_type = column_type(&self.columns.id)?;
+        let vector_type = column_type(&self.columns.vector)?;
+        if !matches!(vector_type.name.as_str(), "vector" | "halfvec") {
+            return Err(SinkError::fatal(
+                "invalid_vector_column",
+                format!("The column {} is {}, not a pgvector vector", self.columns.vector, vector_type.formatted),
+            ));
+        }
+        // Without a declared size (modifier -1) any length is accepted
+        if vector_type.modifier >= 0 && vector_type.modifier as usize != dimensions {
+            return Err(SinkError::fatal(
+                "dimension_mismatch",
+                format!(
+                    "The column {} is {}, but the embeddings have {} dimensions",
+                    self.columns.vector, vector_type.formatted, dimensions
+                ),
+            ));
+        }
+
+        let mut columns = vec![id_column.clone(), vector_column.clone()];
+        let mut values = vec![
+            format!("CAST($1 AS {})", id_type.formatted),
+            format!("CAST($2 AS {})", vector_type.formatted),
+        ];
+        let mut updates = vec![format!("{0} = EXCLUDED.{0}", vector_column)];
+        for name in optional {
+            let column = column_name(name)?;
+            values.push(format!("CAST(${} AS {})", values.len() + 1, column_type(name)?.formatted));
+            updates.push(format!("{0} = EXCLUDED.{0}", column));
+            columns.push(column);
+        }
+        // xmax is zero only for a row this statement inserted
+        Ok(format!(
+            "INSERT INTO {} ({}) VALUES ({}) ON CONFLICT ({}) DO UPDATE SET {} RETURNING (xmax = 0) AS inserted",
+            quoted,
+            columns.join(", "),
+            values.join(", "),
+            id_column,
+            updates.join(", ")
+        ))
+    }
+
+    async fn write(&self, table: &str, points: &[Point]) -> Result<Vec<Result<Written, SinkError>>, SinkError> {
+        let Some(first) = points.first() else {
+            return Ok(Vec::new());
+        };
+        let mut conn = self.pool.acquire().await.map_err(unavailable)?;
+        let statement = self.statement(&mut conn, table, first.vector.len()).await?;
+        let mut results = Vec::with_capacity(points.len());
+        for point in points {
+            let mut query = sqlx::query(&statement).bind(&point.id).bind(vector_literal(&point.vector));
+            if self.columns.text.is_some() {
+                query = query.bind(point.text.as_deref());
+            }
+            // Sent as JSON text and cast to the column's type
+            if self.columns.metadata.is_some() {
+                query = query.bind(point.metadata.as_ref().map(|metadata| metadata.to_string()));
+            }
+            results.push(match query.fetch_one(&mut *conn).await {
+                Ok(row) => Ok(if row.get::<bool, _>(0) { Written::Inserted } else { Written::Updated }),
+                Err(sqlx::Error::Database(e)) if e.code().as_deref() == Some(NO_UNIQUE_CONSTRAINT) => {
+                    return Err(SinkError::fatal(
+                        "missing_unique_constraint",
+                        format!("The column {} needs a unique constraint or index to upsert on", self.columns.id),
+                    ));
+                }
+                // The row's own problem, such as an id that doesn't fit the column
+                Err(sqlx::Error::Database(e)) => Err(SinkError::fatal("database_error", e.message())),
+                Err(e) => return Err(unavailable(e)),
+            });
+        }
+        Ok(results)
+    }
+
+    // A table of the configured columns: a text id, the vector and, if
+    // configured, the text and metadata
+    async fn create(&self, table: &str, dimensions: usize) -> Result<(), SinkError> {
+        let mut columns = vec![
+            format!("{} text PRIMARY KEY", column_name(&self.columns.id)?),
+            format!("{} vector({})", column_name(&self.columns.vector)?, dimensions),
+        ];
+        if let Some(text) = &self.columns.text {
+            columns.push(format!("{} text", column_name(text)?));
+        }
+        if let Some(metadata) = &self.columns.metadata {
+            columns.push(format!("{} jsonb", column_name(metadata)?));
+        }
+        let statement = format!("CREATE TABLE IF NOT EXISTS {} ({})", table_name(table)?, columns.join(", "));
+        match sqlx::query(&statement).execute(&self.pool).await {
+            Ok(_) => Ok(()),
+            // Such as the vector extension not being installed
+            Err(sqlx::Error::Database(e)) => Err(SinkError::fatal("create_failed", e.message())),
+            Err(e) => Err(unavailable(e)),
+        }
+    }
+}
+
+impl VectorSink for PgVectorSink {
+    fn kind(&self) -> &'static str {
+        "pgvector"
+    }
+
+    // Postgres compares with whichever operator a query uses, so the
+    // distance only matters to the indexes created for it
+    fn create_collection<'a>(
+        &'a self,
+        collection: &'a str,
+        dimensions: usize,
+        _distance: Distance,
+    ) -> BoxFuture<'a, Result<(), SinkError>> {
+        Box::pin(self.create(collection, dimensions))
+    }
+
+    fn upsert<'a>(
+        &'a self,
+        collection: &'a str,
+        points: &'a [Point],
+    ) -> BoxFuture<'a, Result<Vec<Result<Written, SinkError>>, SinkError>> {
+        Box::pin(self.write(collection, points))
+    }
+
+    fn healthcheck(&self) -> BoxFuture<'_, Result<(), SinkError>> {
+        Box::pin(async move {
+            sqlx::query("SELECT 1").execute(&self.pool).await.map_err(unavailable)?;
+            Ok(())
+        })
+    }
+}
+
+fn invalid(param: &'static str, message: impl Into<String>) -> ApiError {
+    ApiError::new(StatusCode::BAD_REQUEST, "invalid_request_error", message)
+        .param(param)
+        .reason("invalid_index_request")
+}
+
+/// `POST /v1/index/pgvector`: embed `documents` and upsert them into `table`,
+/// reporting per row whether it was inserted, updated or failed.
+pub(crate) async fn index(
+    State(state): State<Arc<AppState>>,
+    scheduling: Scheduling,
+    ApiJson(req): ApiJson<PgVectorRequest>,
+) -> Result<Json<PgVectorResponse>, ApiError> {
+    let sink = state.pgvector.as_ref().ok_or_else(|| {
+        ApiError::new(StatusCode::NOT_FOUND, "invalid_request_error", "pgvector indexing is not enabled")
+            .reason("not_found")
+    })?;
+
+    // Names are checked here too, so the error points at the field
+    table_name(&req.table).map_err(|e| invalid("table", e.message))?;
+    for (param, column) in [
+        ("id_column", Some(&req.id_column)),
+        ("vector_column", Some(&req.vector_column)),
+        ("text_column", req.text_column.as_ref()),
+        ("metadata_column", req.metadata_column.as_ref()),
+    ] {
+        if let Some(column) = column {
+            column_name(column).map_err(|_| {
+                invalid(
+                    param,
+                    format!("{} must be an identifier of letters, digits and underscores, got {:?}", param, column),
+                )
+            })?;
+        }
+    }
+    let sink = sink.with_columns(Columns {
+        id: req.id_column,
+        vector: req.vector_column,
+        text: req.text_column,
+        metadata: req.metadata_column,
+    });
+
+    let indexed = index_documents(
+        &state,
+        &scheduling,
+        IndexJob {
+            sink: &sink,
+            collection: &req.table,
+            documents: req.documents,
+            model: req.model,
+            input_type: req.input_type,
+            dimensions: req.dimensions,
+            create: false,
+        },
+    )
+    .await?;
+    Ok(Json(PgVectorResponse {
+        object: "list",
+        data: indexed.data,
+        model: indexed.model,
+        table: req.table,
+        summary: indexed.summary,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_plain_identifiers_are_accepted() {
+        for name in ["docs", "_private", "Docs2", "a_b_c", &"x".repeat(MAX_IDENTIFIER)] {
+            assert!(is_identifier(name), "{:?} was refused", name);
+        }
+        let too_long = "x".repeat(MAX_IDENTIFIER + 1);
+        for name in ["", "2docs", "docs-v2", "doc s", "docs\"; DROP TABLE x; --", "dócs", &too_long] {
+            assert!(!is_identifier(name), "{:?} was accepted", name);
+        }
+    }
+
+    #[test]
+    fn names_are_quoted() {
+        assert_eq!(table_name("docs").unwrap(), "\"docs\"");
+        assert_eq!(table_name("search.Docs").unwrap(), "\"search\".\"Docs\"");
+        assert_eq!(table_name("a.b.c").unwrap_err().code, "invalid_collection");
+        assert_eq!(table_name("search.").unwrap_err().code, "invalid_collection");
+        assert_eq!(column_name("embedding").unwrap(), "\"embedding\"");
+        assert_eq!(column_name("embedding)").unwrap_err().code, "invalid_column");
+    }
+
+    #[test]
+    fn vectors_are_written_as_pgvector_text() {
+        assert_eq!(vector_literal(&[0.5, -1.0, 0.125]), "[0.5,-1,0.125]");
+        assert_eq!(vector_literal(&[]), "[]");
+    }
+
+    fn point(id: &str, vector: Vec<f32>) -> Point {
+        Point {
+            id: id.to_string(),
+            vector,
+            text: Some(format!("text of {}", id)),
+            metadata: Some(serde_json::json!({"source": id})),
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Postgres with pgvector at SEMEMBED_TEST_PGVECTOR_DSN (docker compose --profile pgvector up)"]
+    async fn rows_are_inserted_then_updated() {
+        let sink = PgVectorSink::new(&PgVectorConfig {
+            dsn: std::env::var("SEMEMBED_TEST_PGVECTOR_DSN").unwrap(),
+            max_connections: 2,
+            acquire_timeout: Duration::from_secs(5),
+            columns: Columns {
+                id: "id".to_string(),
+                vector: "embedding".to_string(),
+                text: Some("body".to_string()),
+                metadata: Some("metadata".to_string()),
+            },
+        })
+        .unwrap();
+        let table = format!("semembed_test_{}", std::process::id());
+        sqlx::query("CREATE EXTENSION IF NOT EXISTS vector").execute(&sink.pool).await.unwrap();
+        sink.create(&table, 3).await.unwrap();
+
+        let written = sink.write(&table, &[point("a", vec![1.0, 0.0, 0.0]), point("b", vec![0.0, 1.0, 0.0])]).await;
+        assert!(matches!(written.unwrap()[..], [Ok(Written::Inserted), Ok(Written::Inserted)]));
+        let written = sink.write(&table, &[point("a", vec![0.0, 0.0, 1.0]), point("c", vec![1.0, 1.0, 0.0])]).await;
+        assert!(matches!(written.unwrap()[..], [Ok(Written::Updated), Ok(Written::Inserted)]));
+        let row = sqlx::query(&format!("SELECT embedding::text, metadata::text FROM {} WHERE id = 'a'", table))
+            .fetch_one(&sink.pool)
+            .await
+            .unwrap();
+        assert_eq!(row.get::<String, _>(0), "[0,0,1]");
+        assert_eq!(row.get::<String, _>(1), "{\"source\": \"a\"}");
+
+        let Err(e) = sink.write(&table, &[point("d", vec![1.0, 0.0])]).await else {
+            panic!("a 2-dimension vector was written into a vector(3) column");
+        };
+        assert_eq!(e.code, "dimension_mismatch");
+        let Err(e) = sink.write("semembed_test_missing", &[point("d", vec![1.0, 0.0, 0.0])]).await else {
+            panic!("a missing table was written to");
+        };
+        assert_eq!(e.code, "collection_not_found");
+        sqlx::query(&format!("DROP TABLE {}", table)).execute(&sink.pool).await.unwrap();
+    }
+}
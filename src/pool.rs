@@ -0,0 +1,346 @@
+//! Comparison models loaded on demand and evicted under a memory budget.
+//!
+//! With `SEMEMBED_MODEL_MEMORY_BUDGET` or `SEMEMBED_MAX_RESIDENT_MODELS` set,
+//! the models in `SEMEMBED_COMPARE_MODELS` aren't loaded at startup. Each is
+//! loaded the first time a request names it, and when loading one would take
+//! the resident models over the budget, the least recently used ones are
+//! unloaded first. A model with requests in flight is never unloaded. The
+//! memory of a model is estimated from the size of its files, which is
+//! roughly what its ONNX session takes.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::Instant;
+
+use fastembed::InitOptions;
+use prometheus::{IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry};
+use tokio::sync::Notify;
+use tracing::{error, info, warn};
+
+use crate::compare::ComparedModel;
+use crate::models::{self, ModelSpec};
+
+/// What a request naming a model that isn't resident does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoadPolicy {
+    /// Wait for the model to load.
+    Wait,
+    /// Fail with `503` (`model_loading`) at once, and let the client retry.
+    Reject,
+}
+
+impl FromStr for LoadPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "wait" => Ok(LoadPolicy::Wait),
+            "reject" => Ok(LoadPolicy::Reject),
+            other => anyhow::bail!("unknown model load policy {:?} (expected wait or reject)", other),
+        }
+    }
+}
+
+/// Limits on the resident on-demand models.
+#[derive(Debug, Clone, Copy)]
+pub struct PoolConfig {
+    /// Estimated bytes the resident models may take together.
+    pub budget: Option<u64>,
+    pub max_resident: Option<usize>,
+    pub policy: LoadPolicy,
+}
+
+/// Why a model couldn't be handed out.
+#[derive(Debug)]
+pub(crate) enum PoolError {
+    /// It is loading; the request should be retried.
+    Loading,
+    /// Loading it failed.
+    Failed(String),
+}
+
+impl fmt::Display for PoolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PoolError::Loading => f.write_str("the model is loading"),
+            PoolError::Failed(err) => write!(f, "the model failed to load: {}", err),
+        }
+    }
+}
+
+// Residency metrics
+struct PoolMetrics {
+    loads: IntCounterVec,
+    evictions: IntCounterVec,
+    resident: IntGaugeVec,
+    resident_bytes: IntGauge,
+}
+
+impl PoolMetrics {
+    fn register(registry: &Registry) -> anyhow::Result<Self> {
+        let loads = IntCounterVec::new(
+            Opts::new("semembed_model_loads_total", "On-demand model loads by model and outcome (loaded or failed)"),
+            &["model", "outcome"],
+        )?;
+        registry.register(Box::new(loads.clone()))?;
+
+        let evictions = IntCounterVec::new(
+            Opts::new("semembed_model_evictions_total", "On-demand models unloaded to stay within the budget"),
+            &["model"],
+        )?;
+        registry.register(Box::new(evictions.clone()))?;
+
+        let resident = IntGaugeVec::new(
+            Opts::new("semembed_model_resident", "1 while an on-demand model is loaded"),
+            &["model"],
+        )?;
+        registry.register(Box::new(resident.clone()))?;
+
+        let resident_bytes = IntGauge::new(
+            "semembed_model_resident_bytes",
+            "Estimated memory of the loaded on-demand models",
+        )?;
+        registry.register(Box::new(resident_bytes.clone()))?;
+
+        Ok(Self {
+            loads,
+            evictions,
+            resident,
+            resident_bytes,
+        })
+    }
+}
+
+struct Resident {
+    // Cloned by every request using the model, so a count above one means
+    // requests are in flight
+    model: Arc<ComparedModel>,
+    bytes: u64,
+    last_used: Instant,
+}
+
+struct Slot {
+    spec: &'static ModelSpec,
+    resident: Option<Resident>,
+    loading: bool,
+    // Why the last load failed
+    failure: Option<String>,
+}
+
+type Loader = dyn Fn(&str) -> anyhow::Result<ComparedModel> + Send + Sync;
+
+/// The on-demand models, loaded or not.
+pub(crate) struct ModelPool {
+    config: PoolConfig,
+    // By canonical name, in configuration order
+    names: Vec<&'static str>,
+    slots: Mutex<HashMap<&'static str, Slot>>,
+    // Wakes the requests waiting for a load
+    loaded: Notify,
+    // One load at a time, so each is checked against settled usage
+    loading: Mutex<()>,
+    load: Box<Loader>,
+    metrics: PoolMetrics,
+}
+
+impl ModelPool {
+    pub fn new<F>(names: &[String], config: PoolConfig, load: F, registry: &Registry) -> anyhow::Result<Self>
+    where
+        F: Fn(&str) -> anyhow::Result<ComparedModel> + Send + Sync + 'static,
+    {
+        let mut slots = HashMap::new();
+        let mut canonical = Vec::new();
+        for name in names {
+            let spec = models::model_spec(name).ok_or_else(|| anyhow::anyhow!("unknown model {:?}", name))?;
+            canonical.push(spec.name);
+            slots.insert(
+                spec.name,
+                Slot {
+                    spec,
+                    resident: None,
+                    loading: false,
+                    failure: None,
+                },
+            );
+        }
+        Ok(Self {
+            config,
+            names: canonical,
+            slots: Mutex::new(slots),
+            loaded: Notify::new(),
+            loading: Mutex::new(()),
+            load: Box::new(load),
+            metrics: PoolMetrics::register(registry)?,
+        })
+    }
+
+    fn slots(&self) -> MutexGuard<'_, HashMap<&'static str, Slot>> {
+        self.slots.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.names.contains(&name)
+    }
+
+    /// Every model with whether it is `resident`, `loading` or `available`,
+    /// in configuration order.
+    pub fn states(&self) -> Vec<(&'static ModelSpec, &'static str)> {
+        let slots = self.slots();
+        self.names
+            .iter()
+            .filter_map(|name| slots.get(name))
+            .map(|slot| {
+                let state = match (&slot.resident, slot.loading) {
+                    (Some(_), _) => "resident",
+                    (None, true) => "loading",
+                    (None, false) => "available",
+                };
+                (slot.spec, state)
+            })
+            .collect()
+    }
+
+    /// The model `name`, loading it first if it isn't resident. Holding the
+    /// returned model keeps it from being unloaded.
+    pub async fn get(self: &Arc<Self>, name: &str) -> Result<Arc<ComparedModel>, PoolError> {
+        let mut waited = false;
+        loop {
+            let notified = self.loaded.notified();
+            tokio::pin!(notified);
+            // Registered before the lock is released, so a load finishing in
+            // between still wakes this request
+            notified.as_mut().enable();
+            {
+                let mut slots = self.slots();
+                let Some(slot) = slots.get_mut(name) else {
+                    return Err(PoolError::Failed(format!("{} is not an on-demand model", name)));
+                };
+                if let Some(resident) = &mut slot.resident {
+                    resident.last_used = Instant::now();
+                    return Ok(resident.model.clone());
+                }
+                if !slot.loading {
+                    // The load this request waited for failed; a later
+                    // request tries again
+                    if let (true, Some(failure)) = (waited, &slot.failure) {
+                        return Err(PoolError::Failed(failure.clone()));
+                    }
+                    slot.loading = true;
+                    slot.failure = None;
+                    let (pool, spec) = (self.clone(), slot.spec);
+                    tokio::task::spawn_blocking(move || pool.load(spec));
+                }
+                if self.config.policy == LoadPolicy::Reject {
+                    return Err(PoolError::Loading);
+                }
+            }
+            waited = true;
+            notified.await;
+        }
+    }
+
+    fn load(&self, spec: &'static ModelSpec) {
+        let _loading = self.loading.lock().unwrap_or_else(PoisonError::into_inner);
+        let cache_dir = InitOptions::new(spec.model.clone()).cache_dir;
+        // Room is made before loading when the files are already cached, and
+        // checked again once the real size is known
+        let estimate = models::model_bytes(&spec.model, &cache_dir).unwrap_or(0);
+        self.make_room(spec.name, estimate);
+
+        let started = Instant::now();
+        let result = (self.load)(spec.name);
+        let bytes = models::model_bytes(&spec.model, &cache_dir).unwrap_or(estimate);
+        {
+            let mut slots = self.slots();
+            let Some(slot) = slots.get_mut(spec.name) else {
+                return;
+            };
+            slot.loading = false;
+            match result {
+                Ok(model) => {
+                    info!(
+                        "Loaded {} on demand in {:.1}s (about {} MiB)",
+                        spec.name,
+                        started.elapsed().as_secs_f64(),
+                        bytes / (1024 * 1024)
+                    );
+                    self.metrics.loads.with_label_values(&[spec.name, "loaded"]).inc();
+                    slot.resident = Some(Resident {
+                        model: Arc::new(model),
+                        bytes,
+                        last_used: Instant::now(),
+                    });
+                }
+                Err(err) => {
+                    error!("Failed to load {} on demand: {:#}", spec.name, err);
+                    self.metrics.loads.with_label_values(&[spec.name, "failed"]).inc();
+                    slot.failure = Some(format!("{:#}", err));
+                }
+            }
+        }
+        if bytes > estimate {
+            self.make_room(spec.name, 0);
+        }
+        self.publish();
+        self.loaded.notify_waiters();
+    }
+
+    // Unload the least recently used idle models until `incoming` more bytes
+    // and one more model (besides `keep`) fit
+    fn make_room(&self, keep: &str, incoming: u64) {
+        let mut unloaded = Vec::new();
+        {
+            let mut slots = self.slots();
+            loop {
+                let resident: Vec<&Slot> = slots
+                    .values()
+                    .filter(|slot| slot.resident.is_some() && slot.spec.name != keep)
+                    .collect();
+                let bytes: u64 = resident.iter().filter_map(|slot| slot.resident.as_ref()).map(|r| r.bytes).sum();
+                let over_count = self.config.max_resident.is_some_and(|max| resident.len() + 1 > max);
+                let over_budget = self.config.budget.is_some_and(|budget| bytes + incoming > budget);
+                if !over_count && !over_budget {
+                    break;
+                }
+                let idle = resident
+                    .iter()
+                    .filter_map(|slot| slot.resident.as_ref().map(|resident| (slot.spec.name, resident)))
+                    .filter(|(_, resident)| Arc::strong_count(&resident.model) == 1)
+                    .min_by_key(|(_, resident)| resident.last_used)
+                    .map(|(name, _)| name);
+                let Some(name) = idle else {
+                    warn!("Loading {} goes over the model budget: every other loaded model is in use", keep);
+                    break;
+                };
+                let Some(evicted) = slots.get_mut(name).and_then(|slot| slot.resident.take()) else {
+                    break;
+                };
+                info!(
+                    "Unloaded {} (about {} MiB, idle for {:.0}s) to make room for {}",
+                    name,
+                    evicted.bytes / (1024 * 1024),
+                    evicted.last_used.elapsed().as_secs_f64(),
+                    keep
+                );
+                self.metrics.evictions.with_label_values(&[name]).inc();
+                unloaded.push(evicted);
+            }
+        }
+        // Freed outside the lock: dropping a session takes a moment
+        drop(unloaded);
+        self.publish();
+    }
+
+    fn publish(&self) {
+        let slots = self.slots();
+        let mut bytes = 0;
+        for slot in slots.values() {
+            let resident = slot.resident.as_ref();
+            bytes += resident.map_or(0, |resident| resident.bytes);
+            self.metrics.resident.with_label_values(&[slot.spec.name]).set(i64::from(resident.is_some()));
+        }
+        self.metrics.resident_bytes.set(bytes as i64);
+    }
+}
@@ -0,0 +1,188 @@
+//! Loading the configured models at startup, several at once.
+//!
+//! The primary, shadow and comparison models download and build their ONNX
+//! sessions independently, so they are loaded by a small pool of threads
+//! (`SEMEMBED_MODEL_LOAD_CONCURRENCY`, 2 by default since each load briefly
+//! needs a model's worth of memory on top). With `SEMEMBED_READY_ON=all` the
+//! API is served once every model has loaded; with `default` it is served as
+//! soon as the primary model has, and the others are put into service as
+//! they finish. Either way, every model that failed is listed in one error.
+
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex, PoisonError};
+use std::thread;
+use std::time::Instant;
+
+use tracing::info;
+
+/// What the server waits for before it starts serving.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadyOn {
+    /// Every configured model.
+    All,
+    /// The primary model; the others load in the background.
+    Default,
+}
+
+impl FromStr for ReadyOn {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "all" => Ok(ReadyOn::All),
+            "default" => Ok(ReadyOn::Default),
+            other => anyhow::bail!("unknown readiness condition {:?} (expected all or default)", other),
+        }
+    }
+}
+
+impl fmt::Display for ReadyOn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ReadyOn::All => "all models loaded",
+            ReadyOn::Default => "default model loaded",
+        })
+    }
+}
+
+/// Names of the models still loading in the background, for `/health`.
+#[derive(Debug, Default)]
+pub(crate) struct Pending(Mutex<Vec<String>>);
+
+impl Pending {
+    pub fn names(&self) -> Vec<String> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner).clone()
+    }
+
+    pub fn is_pending(&self, name: &str) -> bool {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner).iter().any(|pending| pending == name)
+    }
+
+    pub fn set(&self, names: Vec<String>) {
+        *self.0.lock().unwrap_or_else(PoisonError::into_inner) = names;
+    }
+}
+
+/// Models being loaded by a pool of threads, collected as they finish.
+pub(crate) struct Preload<T> {
+    names: Vec<String>,
+    models: Vec<Option<T>>,
+    failures: Vec<(usize, anyhow::Error)>,
+    // Results not received yet
+    outstanding: usize,
+    results: mpsc::Receiver<(usize, anyhow::Result<T>)>,
+}
+
+impl<T: Send + 'static> Preload<T> {
+    /// Start loading `names` with `load`, at most `concurrency` at a time and
+    /// in order, so the first (the primary) is never left waiting.
+    pub fn start<F>(names: Vec<String>, concurrency: usize, load: F) -> anyhow::Result<Self>
+    where
+        F: Fn(&str) -> anyhow::Result<T> + Send + Sync + 'static,
+    {
+        let (sender, results) = mpsc::channel();
+        let queue = Arc::new((names.clone(), AtomicUsize::new(0)));
+        let load = Arc::new(load);
+        for worker in 0..concurrency.clamp(1, names.len().max(1)) {
+            let (queue, load, sender) = (queue.clone(), load.clone(), sender.clone());
+            thread::Builder::new().name(format!("model-loader-{}", worker)).spawn(move || {
+                let (names, next) = &*queue;
+                loop {
+                    let index = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(name) = names.get(index) else {
+                        break;
+                    };
+                    let started = Instant::now();
+                    let result = load(name);
+                    if result.is_ok() {
+                        info!("Loaded {} in {:.1}s", name, started.elapsed().as_secs_f64());
+                    }
+                    if sender.send((index, result)).is_err() {
+                        break;
+                    }
+                }
+            })?;
+        }
+        Ok(Self {
+            models: names.iter().map(|_| None).collect(),
+            outstanding: names.len(),
+            names,
+            failures: Vec::new(),
+            results,
+        })
+    }
+
+    // Take in the next result; false once there are none left
+    fn receive(&mut self) -> bool {
+        if self.outstanding == 0 {
+            return false;
+        }
+        let Ok((index, result)) = self.results.recv() else {
+            // A loader thread panicked; its models never arrive
+            for index in 0..self.names.len() {
+                if self.models[index].is_none() && !self.failed(index) {
+                    self.failures.push((index, anyhow::anyhow!("the loader thread panicked")));
+                }
+            }
+            self.outstanding = 0;
+            return false;
+        };
+        self.outstanding -= 1;
+        match result {
+            Ok(model) => self.models[index] = Some(model),
+            Err(err) => self.failures.push((index, err)),
+        }
+        true
+    }
+
+    /// Wait for every model.
+    pub fn wait_all(&mut self) {
+        while self.receive() {}
+    }
+
+    /// Wait for the model at `index`. If it failed, wait for the rest too so
+    /// the error lists every failure.
+    pub fn wait_for(&mut self, index: usize) -> anyhow::Result<()> {
+        while self.models[index].is_none() && !self.failed(index) && self.receive() {}
+        if self.models[index].is_some() {
+            return Ok(());
+        }
+        self.wait_all();
+        Err(self.error().unwrap_or_else(|| anyhow::anyhow!("{} did not load", self.names[index])))
+    }
+
+    fn failed(&self, index: usize) -> bool {
+        self.failures.iter().any(|(failed, _)| *failed == index)
+    }
+
+    /// Every failure so far, as one error.
+    pub fn error(&self) -> Option<anyhow::Error> {
+        if self.failures.is_empty() {
+            return None;
+        }
+        let mut failures: Vec<&(usize, anyhow::Error)> = self.failures.iter().collect();
+        failures.sort_by_key(|(index, _)| *index);
+        let lines: Vec<String> = failures
+            .iter()
+            .map(|(index, err)| format!("  {}: {:#}", self.names[*index], err))
+            .collect();
+        Some(anyhow::anyhow!(
+            "{} of {} models failed to load:\n{}",
+            failures.len(),
+            self.names.len(),
+            lines.join("\n")
+        ))
+    }
+
+    /// Models loaded or loading, the first one included.
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    /// The model at `index`, if it has loaded and wasn't taken yet.
+    pub fn take(&mut self, index: usize) -> Option<T> {
+        self.models[index].take()
+    }
+}
@@ -0,0 +1,389 @@
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::bail;
+use icu_normalizer::ComposingNormalizerBorrowed;
+use serde::Deserialize;
+use utoipa::ToSchema;
+
+use crate::markup;
+
+/// Unicode normalization form applied before embedding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Normalization {
+    None,
+    Nfc,
+    Nfkc,
+}
+
+/// What happens to inputs containing characters that never carry meaning in
+/// text: C0 and C1 controls other than tab and line breaks (NUL among them)
+/// and bidi embedding, override and isolate controls.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sanitize {
+    /// Pass them to the tokenizer.
+    Off,
+    /// Remove them.
+    Strip,
+    /// Reject the input.
+    Reject,
+}
+
+impl FromStr for Sanitize {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "off" => Ok(Self::Off),
+            "strip" => Ok(Self::Strip),
+            "reject" => Ok(Self::Reject),
+            other => bail!("unknown sanitization policy {:?} (expected off, strip or reject)", other),
+        }
+    }
+}
+
+impl fmt::Display for Sanitize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Off => "off",
+            Self::Strip => "strip",
+            Self::Reject => "reject",
+        })
+    }
+}
+
+/// A character that `Sanitize::Reject` refused.
+#[derive(Debug, Clone, Copy)]
+pub struct Disallowed {
+    pub class: &'static str,
+    pub character: char,
+}
+
+impl fmt::Display for Disallowed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (U+{:04X})", self.class, self.character as u32)
+    }
+}
+
+// The class of a character sanitization removes or rejects
+fn disallowed(c: char) -> Option<&'static str> {
+    match c {
+        '\0' => Some("NUL"),
+        '\n' | '\r' | '\t' => None,
+        c if c.is_control() => Some("control character"),
+        '\u{202A}'..='\u{202E}' | '\u{2066}'..='\u{2069}' => Some("bidi control character"),
+        _ => None,
+    }
+}
+
+/// Text cleaning applied to every input before tokenization.
+///
+/// Steps always run in the same order: HTML stripping, markdown stripping,
+/// normalization, zero-width stripping, whitespace collapsing, lowercasing,
+/// and last sanitization, so nothing an earlier step decodes (such as an
+/// `&#0;` entity) reaches the tokenizer. With every step disabled and
+/// sanitization off, inputs pass through byte-for-byte.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Preprocess {
+    pub strip_html: bool,
+    pub strip_markdown: bool,
+    pub normalize: Normalization,
+    pub strip_zero_width: bool,
+    pub collapse_whitespace: bool,
+    pub lowercase: bool,
+    /// Server-wide only; requests can't override it.
+    pub sanitize: Sanitize,
+}
+
+impl Default for Preprocess {
+    fn default() -> Self {
+        Self {
+            strip_html: false,
+            strip_markdown: false,
+            normalize: Normalization::None,
+            strip_zero_width: false,
+            collapse_whitespace: false,
+            lowercase: false,
+            sanitize: Sanitize::Strip,
+        }
+    }
+}
+
+/// Per-request `preprocess` object; fields that are present override the
+/// server-wide configuration.
+#[derive(Debug, Default, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct PreprocessOverrides {
+    strip_html: Option<bool>,
+    strip_markdown: Option<bool>,
+    normalize: Option<Normalization>,
+    strip_zero_width: Option<bool>,
+    collapse_whitespace: Option<bool>,
+    lowercase: Option<bool>,
+}
+
+impl Preprocess {
+    /// Apply per-request overrides on top of this configuration.
+    pub fn with_overrides(self, overrides: &PreprocessOverrides) -> Self {
+        Self {
+            strip_html: overrides.strip_html.unwrap_or(self.strip_html),
+            strip_markdown: overrides.strip_markdown.unwrap_or(self.strip_markdown),
+            normalize: overrides.normalize.unwrap_or(self.normalize),
+            strip_zero_width: overrides.strip_zero_width.unwrap_or(self.strip_zero_width),
+            collapse_whitespace: overrides.collapse_whitespace.unwrap_or(self.collapse_whitespace),
+            lowercase: overrides.lowercase.unwrap_or(self.lowercase),
+            sanitize: self.sanitize,
+        }
+    }
+
+    /// The pipeline configured by `SEMEMBED_PREPROCESS` and `SEMEMBED_SANITIZE`,
+    /// shared by the server and the CLI so both treat inputs alike.
+    pub fn from_env() -> anyhow::Result<Self> {
+        let mut preprocess = std::env::var("SEMEMBED_PREPROCESS").unwrap_or_default().parse::<Self>()?;
+        if let Some(sanitize) = crate::env_parse("SEMEMBED_SANITIZE")? {
+            preprocess.sanitize = sanitize;
+        }
+        Ok(preprocess)
+    }
+
+    /// Names of the enabled steps, in the order they are applied.
+    pub fn steps(&self) -> Vec<&'static str> {
+        let mut steps = Vec::new();
+        if self.strip_html {
+            steps.push("strip_html");
+        }
+        if self.strip_markdown {
+            steps.push("strip_markdown");
+        }
+        match self.normalize {
+            Normalization::None => {}
+            Normalization::Nfc => steps.push("nfc"),
+            Normalization::Nfkc => steps.push("nfkc"),
+        }
+        if self.strip_zero_width {
+            steps.push("strip_zero_width");
+        }
+        if self.collapse_whitespace {
+            steps.push("collapse_whitespace");
+        }
+        if self.lowercase {
+            steps.push("lowercase");
+        }
+        steps
+    }
+
+    /// Run the pipeline over one input, failing if it holds a character the
+    /// `reject` policy refuses.
+    pub fn prepare(&self, text: String) -> Result<String, Disallowed> {
+        let text = self.apply(text);
+        if self.sanitize == Sanitize::Reject {
+            if let Some((class, character)) = text.chars().find_map(|c| Some((disallowed(c)?, c))) {
+                return Err(Disallowed { class, character });
+            }
+        }
+        Ok(text)
+    }
+
+    /// Run the pipeline over one input. Under the `reject` policy disallowed
+    /// characters are left in place; see `prepare`.
+    pub fn apply(&self, mut text: String) -> String {
+        if self.strip_html {
+            text = markup::html_to_text(&text);
+        }
+        if self.strip_markdown {
+            text = markup::strip_markdown(&text);
+        }
+        let mut text = match self.normalize {
+            Normalization::None => text,
+            Normalization::Nfc => ComposingNormalizerBorrowed::new_nfc().normalize(&text).into_owned(),
+            Normalization::Nfkc => ComposingNormalizerBorrowed::new_nfkc().normalize(&text).into_owned(),
+        };
+        if self.strip_zero_width && text.chars().any(is_zero_width) {
+            text.retain(|c| !is_zero_width(c));
+        }
+        if self.collapse_whitespace {
+            text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+        }
+        if self.lowercase {
+            text = text.to_lowercase();
+        }
+        if self.sanitize == Sanitize::Strip && text.chars().any(|c| disallowed(c).is_some()) {
+            text.retain(|c| disallowed(c).is_none());
+        }
+        text
+    }
+}
+
+fn is_zero_width(c: char) -> bool {
+    matches!(
+        c,
+        '\u{200B}' // zero width space
+            | '\u{200C}' // zero width non-joiner
+            | '\u{200D}' // zero width joiner
+            | '\u{2060}' // word joiner
+            | '\u{FEFF}' // zero width no-break space / BOM
+    )
+}
+
+/// Parses the `SEMEMBED_PREPROCESS` list, e.g. `strip_html,nfkc,collapse_whitespace`.
+impl FromStr for Preprocess {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut preprocess = Self::default();
+        for step in s.split(',').map(str::trim).filter(|step| !step.is_empty()) {
+            match step.to_ascii_lowercase().as_str() {
+                "strip_html" => preprocess.strip_html = true,
+                "strip_markdown" => preprocess.strip_markdown = true,
+                "nfc" => preprocess.normalize = Normalization::Nfc,
+                "nfkc" => preprocess.normalize = Normalization::Nfkc,
+                "strip_zero_width" => preprocess.strip_zero_width = true,
+                "collapse_whitespace" => preprocess.collapse_whitespace = true,
+                "lowercase" => preprocess.lowercase = true,
+                other => bail!(
+                    "unknown preprocessing step {:?} (expected strip_html, strip_markdown, nfc, nfkc, strip_zero_width, collapse_whitespace or lowercase)",
+                    other
+                ),
+            }
+        }
+        Ok(preprocess)
+    }
+}
+
+impl fmt::Display for Preprocess {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let steps = self.steps();
+        if steps.is_empty() {
+            f.write_str("none")
+        } else {
+            f.write_str(&steps.join(","))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    // Every combination of the steps that only rewrite characters; markup
+    // stripping decodes entities, so it isn't idempotent
+    fn text_steps() -> impl Strategy<Value = Preprocess> {
+        (
+            prop_oneof![Just(Normalization::None), Just(Normalization::Nfc), Just(Normalization::Nfkc)],
+            any::<bool>(),
+            any::<bool>(),
+            any::<bool>(),
+            prop_oneof![Just(Sanitize::Off), Just(Sanitize::Strip)],
+        )
+            .prop_map(|(normalize, strip_zero_width, collapse_whitespace, lowercase, sanitize)| Preprocess {
+                normalize,
+                strip_zero_width,
+                collapse_whitespace,
+                lowercase,
+                sanitize,
+                ..Preprocess::default()
+            })
+    }
+
+    // Text dense in what sanitization looks for, next to its exceptions
+    fn hostile_text() -> impl Strategy<Value = String> {
+        let suspects = prop::sample::select(vec![
+            '\0', '\u{1}', '\u{1B}', '\u{7F}', '\u{85}', '\u{9F}', '\u{202A}', '\u{202E}', '\u{2066}', '\u{2069}',
+            '\u{200D}', '\u{FEFF}', '\u{FFFD}', '\n', '\r', '\t', ' ',
+        ]);
+        prop::collection::vec(prop_oneof![any::<char>(), suspects], 0..64).prop_map(String::from_iter)
+    }
+
+    proptest! {
+        #[test]
+        fn strip_removes_exactly_the_disallowed(text in hostile_text()) {
+            let stripped = Preprocess::default().apply(text.clone());
+            let kept: String = text.chars().filter(|&c| disallowed(c).is_none()).collect();
+            prop_assert_eq!(stripped, kept);
+        }
+
+        #[test]
+        fn reject_names_the_first_disallowed(text in hostile_text()) {
+            let reject = Preprocess {
+                sanitize: Sanitize::Reject,
+                ..Preprocess::default()
+            };
+            match (reject.prepare(text.clone()), text.chars().find(|&c| disallowed(c).is_some())) {
+                (Ok(prepared), None) => prop_assert_eq!(prepared, text),
+                (Err(refused), Some(first)) => {
+                    prop_assert_eq!(refused.character, first);
+                    prop_assert_eq!(Some(refused.class), disallowed(first));
+                }
+                (result, first) => prop_assert!(false, "{:?} for {:?} (first disallowed {:?})", result, text, first),
+            }
+        }
+
+        // Every step on, markup decoding included, never panics or lets a
+        // disallowed character through
+        #[test]
+        fn nothing_disallowed_survives_the_pipeline(text in hostile_text(), entity in 0u32..0x2070) {
+            let all: Preprocess =
+                "strip_html,strip_markdown,nfkc,strip_zero_width,collapse_whitespace,lowercase".parse().unwrap();
+            let text = format!("<p>{}&#{};</p>", text, entity);
+            prop_assert!(all.apply(text).chars().all(|c| disallowed(c).is_none()));
+        }
+
+        #[test]
+        fn applying_twice_changes_nothing(preprocess in text_steps(), text in any::<String>()) {
+            let once = preprocess.apply(text);
+            prop_assert_eq!(preprocess.apply(once.clone()), once);
+        }
+
+        #[test]
+        fn disabled_passes_through(text in any::<String>()) {
+            let off = Preprocess {
+                sanitize: Sanitize::Off,
+                ..Preprocess::default()
+            };
+            prop_assert_eq!(off.apply(text.clone()), text);
+        }
+    }
+
+    #[test]
+    fn unifies_unicode_forms() {
+        let preprocess: Preprocess = "nfkc,strip_zero_width,collapse_whitespace,lowercase".parse().unwrap();
+        let composed = preprocess.apply("Caf\u{e9}  Ｃｏｆｆｅｅ".to_string());
+        let decomposed = preprocess.apply("Cafe\u{301} \u{200B}Coffee".to_string());
+        assert_eq!(composed, "café coffee");
+        assert_eq!(decomposed, composed);
+    }
+
+    #[test]
+    fn reports_steps_in_order() {
+        let preprocess: Preprocess = "lowercase, nfc ,strip_html".parse().unwrap();
+        assert_eq!(preprocess.steps(), ["strip_html", "nfc", "lowercase"]);
+        assert_eq!(preprocess.to_string(), "strip_html,nfc,lowercase");
+        assert_eq!(Preprocess::default().to_string(), "none");
+        assert!("nfd".parse::<Preprocess>().is_err());
+    }
+
+    #[test]
+    fn overrides_leave_sanitization_alone() {
+        let overrides: PreprocessOverrides = serde_json::from_str(r#"{"lowercase": true, "normalize": "nfc"}"#).unwrap();
+        let preprocess = Preprocess::default().with_overrides(&overrides);
+        assert!(preprocess.lowercase);
+        assert_eq!(preprocess.normalize, Normalization::Nfc);
+        assert_eq!(preprocess.sanitize, Sanitize::Strip);
+        assert!(serde_json::from_str::<PreprocessOverrides>(r#"{"sanitize": "off"}"#).is_err());
+    }
+
+    #[test]
+    fn sanitizes_control_characters() {
+        let strip = Preprocess::default();
+        assert_eq!(strip.apply("a\0b\u{202E}c\td".to_string()), "abc\td");
+        let reject = Preprocess {
+            sanitize: Sanitize::Reject,
+            ..Preprocess::default()
+        };
+        let disallowed = reject.prepare("a\0b".to_string()).unwrap_err();
+        assert_eq!(disallowed.to_string(), "NUL (U+0000)");
+        assert_eq!(reject.prepare("a b".to_string()).unwrap(), "a b");
+    }
+}
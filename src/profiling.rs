@@ -0,0 +1,98 @@
+use std::time::Duration;
+
+use axum::{
+    extract::Query,
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use pprof::protos::Message;
+use serde::Deserialize;
+use tokio::sync::Semaphore;
+use tracing::info;
+
+use crate::error::ApiError;
+
+const DEFAULT_SECONDS: u64 = 30;
+const MAX_SECONDS: u64 = 300;
+// Samples per second; a prime avoids lining up with periodic work
+const FREQUENCY: i32 = 99;
+
+// One profiling session at a time: the profiler is process-wide
+static SESSION: Semaphore = Semaphore::const_new(1);
+
+#[derive(Debug, Deserialize)]
+struct ProfileParams {
+    seconds: Option<u64>,
+}
+
+/// `/debug/pprof/*` routes, mounted on the admin router when profiling is enabled.
+pub(crate) fn router<S: Clone + Send + Sync + 'static>() -> Router<S> {
+    Router::new().route("/debug/pprof/profile", get(profile))
+}
+
+// Collect a CPU profile of the whole process (async workers and the blocking
+// pool that runs inference) for `seconds`, as a flamegraph SVG or, when the
+// client accepts protobuf, a pprof profile for `go tool pprof`.
+async fn profile(Query(params): Query<ProfileParams>, headers: HeaderMap) -> Result<Response, ApiError> {
+    let seconds = params.seconds.unwrap_or(DEFAULT_SECONDS);
+    if seconds == 0 || seconds > MAX_SECONDS {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_request_error",
+            format!("seconds must be between 1 and {}, got {}", MAX_SECONDS, seconds),
+        )
+        .param("seconds")
+        .reason("invalid_profile_duration"));
+    }
+
+    let Ok(_session) = SESSION.try_acquire() else {
+        return Err(ApiError::new(
+            StatusCode::CONFLICT,
+            "invalid_request_error",
+            "A profiling session is already running",
+        )
+        .reason("profile_in_progress"));
+    };
+
+    let protobuf = headers
+        .get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains("application/x-protobuf") || accept.contains("application/octet-stream"));
+
+    info!("Collecting a {}s CPU profile", seconds);
+    // The profiler guard is not Send, so the whole session runs on a blocking thread
+    let collected = tokio::task::spawn_blocking(move || -> anyhow::Result<Vec<u8>> {
+        let guard = pprof::ProfilerGuardBuilder::default()
+            .frequency(FREQUENCY)
+            .blocklist(&["libc", "libgcc", "pthread", "vdso"])
+            .build()?;
+        std::thread::sleep(Duration::from_secs(seconds));
+        let report = guard.report().build()?;
+
+        let mut body = Vec::new();
+        if protobuf {
+            report.pprof()?.encode(&mut body)?;
+        } else {
+            report.flamegraph(&mut body)?;
+        }
+        Ok(body)
+    })
+    .await
+    .map_err(anyhow::Error::from)
+    .and_then(|result| result);
+
+    match collected {
+        Ok(body) => {
+            let content_type = if protobuf { "application/x-protobuf" } else { "image/svg+xml" };
+            Ok(([(header::CONTENT_TYPE, content_type)], body).into_response())
+        }
+        Err(e) => Err(ApiError::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "internal_error",
+            format!("Failed to collect profile: {}", e),
+        )
+        .reason("profile_failed")),
+    }
+}
@@ -0,0 +1,326 @@
+//! Pushing metrics for runs nothing scrapes: `semembed batch-dir` pushes once
+//! it's done, and the server (typically a Kafka or Redis worker without
+//! HTTP) every `SEMEMBED_PUSH_INTERVAL_SECS`.
+//!
+//! Metrics go either to a Prometheus Pushgateway, grouped by job and
+//! instance, or to a Prometheus remote-write endpoint, with the same `job`
+//! and `instance` labels a scrape would have added, so the series match
+//! those of a scraped instance. A failed push is retried a few times and
+//! then logged; it never fails the run.
+
+use std::io;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::bail;
+use prometheus::proto::{MetricFamily, MetricType};
+use prometheus::{CounterVec, Encoder, Opts, Registry, TextEncoder};
+use reqwest::{header, Client, StatusCode, Url};
+use tracing::{info, warn};
+
+use crate::env_parse;
+
+// Delay before the first retry, doubled after each
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+const PUSH_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone)]
+enum Target {
+    // `PUT` of the text format to the job and instance's group
+    Pushgateway(Url),
+    // Snappy-compressed protobuf `WriteRequest`s
+    RemoteWrite(Url),
+}
+
+/// Where and how often metrics are pushed.
+#[derive(Debug, Clone)]
+pub struct PushConfig {
+    target: Target,
+    job: String,
+    instance: String,
+    /// Between pushes of a long-running server.
+    pub interval: Duration,
+    basic_auth: Option<(String, String)>,
+    retries: u32,
+}
+
+impl PushConfig {
+    /// `None` when neither `SEMEMBED_PUSHGATEWAY_URL` nor
+    /// `SEMEMBED_REMOTE_WRITE_URL` is set.
+    pub fn from_env() -> anyhow::Result<Option<Self>> {
+        let target = match (non_empty("SEMEMBED_PUSHGATEWAY_URL"), non_empty("SEMEMBED_REMOTE_WRITE_URL")) {
+            (None, None) => return Ok(None),
+            (Some(_), Some(_)) => bail!("set SEMEMBED_PUSHGATEWAY_URL or SEMEMBED_REMOTE_WRITE_URL, not both"),
+            (Some(url), None) => Target::Pushgateway(parse_url("SEMEMBED_PUSHGATEWAY_URL", &url)?),
+            (None, Some(url)) => Target::RemoteWrite(parse_url("SEMEMBED_REMOTE_WRITE_URL", &url)?),
+        };
+        let basic_auth = match (non_empty("SEMEMBED_PUSH_USERNAME"), std::env::var("SEMEMBED_PUSH_PASSWORD").ok()) {
+            (Some(username), password) => Some((username, password.unwrap_or_default())),
+            (None, Some(_)) => bail!("SEMEMBED_PUSH_PASSWORD needs SEMEMBED_PUSH_USERNAME"),
+            (None, None) => None,
+        };
+        Ok(Some(Self {
+            target,
+            job: non_empty("SEMEMBED_PUSH_JOB").unwrap_or_else(|| "semembed".to_string()),
+            instance: non_empty("SEMEMBED_PUSH_INSTANCE")
+                .or_else(|| non_empty("HOSTNAME"))
+                .unwrap_or_else(|| format!("semembed-{}", std::process::id())),
+            interval: Duration::from_secs(env_parse::<u64>("SEMEMBED_PUSH_INTERVAL_SECS")?.unwrap_or(15).max(1)),
+            basic_auth,
+            retries: env_parse("SEMEMBED_PUSH_RETRIES")?.unwrap_or(3),
+        }))
+    }
+}
+
+fn non_empty(name: &str) -> Option<String> {
+    std::env::var(name).ok().map(|value| value.trim().to_string()).filter(|value| !value.is_empty())
+}
+
+fn parse_url(name: &str, value: &str) -> anyhow::Result<Url> {
+    match Url::parse(value) {
+        Ok(url) if matches!(url.scheme(), "http" | "https") => Ok(url),
+        _ => bail!("{} must be an http or https URL, got {:?}", name, value),
+    }
+}
+
+/// Pushes a registry's metrics to the configured target.
+pub struct Pusher {
+    config: PushConfig,
+    client: Client,
+    registry: Registry,
+    pushes: CounterVec,
+}
+
+impl Pusher {
+    /// Push what `registry` holds; pushes are counted in it as well.
+    pub fn new(config: PushConfig, registry: Registry) -> anyhow::Result<Self> {
+        let pushes = CounterVec::new(
+            Opts::new("semembed_metrics_pushes_total", "Metrics pushes, by outcome (succeeded or failed)"),
+            &["outcome"],
+        )?;
+        registry.register(Box::new(pushes.clone()))?;
+        let client = Client::builder().timeout(PUSH_TIMEOUT).build()?;
+        match &config.target {
+            Target::Pushgateway(url) => info!("Pushing metrics to the Pushgateway at {}", redacted(url)),
+            Target::RemoteWrite(url) => info!("Pushing metrics by remote write to {}", redacted(url)),
+        }
+        Ok(Self {
+            config,
+            client,
+            registry,
+            pushes,
+        })
+    }
+
+    /// Push now, retrying a failed push a few times. Failures are only logged.
+    pub async fn push(&self) {
+        let mut delay = RETRY_DELAY;
+        let mut attempt = 0;
+        loop {
+            match self.send().await {
+                Ok(()) => {
+                    self.pushes.with_label_values(&["succeeded"]).inc();
+                    return;
+                }
+                Err(e) if e.retryable && attempt < self.config.retries => {
+                    attempt += 1;
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                }
+                Err(e) => {
+                    self.pushes.with_label_values(&["failed"]).inc();
+                    warn!("Failed to push metrics: {}", e.message);
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Push every interval, for as long as the server runs.
+    pub async fn run(self) -> io::Result<()> {
+        let mut interval = tokio::time::interval(self.config.interval);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            self.push().await;
+        }
+    }
+
+    async fn send(&self) -> Result<(), PushError> {
+        let families = self.registry.gather();
+        let request = match &self.config.target {
+            Target::Pushgateway(url) => {
+                let mut url = url.clone();
+                url.path_segments_mut()
+                    .map_err(|()| PushError::fatal("the Pushgateway URL can't take a path"))?
+                    .pop_if_empty()
+                    .extend(["metrics", "job", &self.config.job, "instance", &self.config.instance]);
+                let mut body = Vec::new();
+                let encoder = TextEncoder::new();
+                encoder
+                    .encode(&families, &mut body)
+                    .map_err(|e| PushError::fatal(format!("failed to encode metrics: {}", e)))?;
+                self.client.put(url).header(header::CONTENT_TYPE, encoder.format_type()).body(body)
+            }
+            Target::RemoteWrite(url) => {
+                let labels = [("instance", self.config.instance.as_str()), ("job", self.config.job.as_str())];
+                let body = snappy(&write_request(&families, &labels, now_ms()));
+                self.client
+                    .post(url.clone())
+                    .header(header::CONTENT_TYPE, "application/x-protobuf")
+                    .header(header::CONTENT_ENCODING, "snappy")
+                    .header("X-Prometheus-Remote-Write-Version", "0.1.0")
+                    .body(body)
+            }
+        };
+        let request = match &self.config.basic_auth {
+            Some((username, password)) => request.basic_auth(username, Some(password)),
+            None => request,
+        };
+        let response = request.send().await.map_err(|e| PushError {
+            message: e.without_url().to_string(),
+            retryable: true,
+        })?;
+        let status = response.status();
+        if status.is_success() {
+            return Ok(());
+        }
+        Err(PushError {
+            message: format!("the endpoint answered {}", status),
+            // Rate limits and server errors may pass; a bad request won't
+            retryable: status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS,
+        })
+    }
+}
+
+struct PushError {
+    message: String,
+    retryable: bool,
+}
+
+impl PushError {
+    fn fatal(message: impl Into<String>) -> Self {
+        Self {
+            message: message.into(),
+            retryable: false,
+        }
+    }
+}
+
+// The URL without credentials, for logs
+fn redacted(url: &Url) -> String {
+    let mut url = url.clone();
+    let _ = url.set_username("");
+    let _ = url.set_password(None);
+    url.to_string()
+}
+
+fn now_ms() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_millis() as i64)
+}
+
+// A remote-write `WriteRequest` of every sample in `families` at `timestamp`,
+// with `labels` (sorted by name) added to each series. Histograms and
+// summaries are split into the series the text format has: `_bucket` with
+// `le` or the quantiles, then `_sum` and `_count`.
+fn write_request(families: &[MetricFamily], labels: &[(&str, &str)], timestamp: i64) -> Vec<u8> {
+    let mut request = Vec::new();
+    for family in families {
+        let name = family.get_name();
+        for metric in family.get_metric() {
+            let own: Vec<(&str, &str)> =
+                metric.get_label().iter().map(|label| (label.get_name(), label.get_value())).collect();
+            let mut series = |suffix: &str, extra: Option<(&str, String)>, value: f64| {
+                let mut all: Vec<(&str, String)> = own
+                    .iter()
+                    .chain(labels)
+                    .map(|&(name, value)| (name, value.to_string()))
+                    .chain(extra)
+                    .collect();
+                all.push(("__name__", format!("{}{}", name, suffix)));
+                all.sort_by(|a, b| a.0.cmp(b.0));
+                message(&mut request, 1, &time_series(&all, value, timestamp));
+            };
+            match family.get_field_type() {
+                MetricType::COUNTER => series("", None, metric.get_counter().get_value()),
+                MetricType::GAUGE => series("", None, metric.get_gauge().get_value()),
+                MetricType::UNTYPED => series("", None, metric.get_untyped().get_value()),
+                MetricType::HISTOGRAM => {
+                    let histogram = metric.get_histogram();
+                    for bucket in histogram.get_bucket() {
+                        let le = bucket.get_upper_bound().to_string();
+                        series("_bucket", Some(("le", le)), bucket.get_cumulative_count() as f64);
+                    }
+                    let count = histogram.get_sample_count() as f64;
+                    series("_bucket", Some(("le", "+Inf".to_string())), count);
+                    series("_sum", None, histogram.get_sample_sum());
+                    series("_count", None, count);
+                }
+                MetricType::SUMMARY => {
+                    let summary = metric.get_summary();
+                    for quantile in summary.get_quantile() {
+                        let at = quantile.get_quantile().to_string();
+                        series("", Some(("quantile", at)), quantile.get_value());
+                    }
+                    series("_sum", None, summary.get_sample_sum());
+                    series("_count", None, summary.get_sample_count() as f64);
+                }
+            }
+        }
+    }
+    request
+}
+
+// A `TimeSeries` of one sample
+fn time_series(labels: &[(&str, String)], value: f64, timestamp: i64) -> Vec<u8> {
+    let mut series = Vec::new();
+    for (name, label_value) in labels {
+        let mut label = Vec::new();
+        message(&mut label, 1, name.as_bytes());
+        message(&mut label, 2, label_value.as_bytes());
+        message(&mut series, 1, &label);
+    }
+    let mut sample = Vec::new();
+    // Field 1, a double; field 2, a varint
+    sample.push(1 << 3 | 1);
+    sample.extend_from_slice(&value.to_le_bytes());
+    sample.push(2 << 3);
+    varint(&mut sample, timestamp as u64);
+    message(&mut series, 2, &sample);
+    series
+}
+
+// A length-delimited protobuf field: a message, string or bytes
+fn message(out: &mut Vec<u8>, field: u8, bytes: &[u8]) {
+    out.push(field << 3 | 2);
+    varint(out, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+fn varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+// `bytes` in the Snappy block format, as literals only: valid for any
+// decoder, if no smaller, and nothing to depend on for a few kilobytes
+fn snappy(bytes: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(bytes.len() + bytes.len() / 4096 * 3 + 8);
+    varint(&mut out, bytes.len() as u64);
+    for literal in bytes.chunks(1 << 16) {
+        let length = literal.len() - 1;
+        if length < 60 {
+            out.push((length as u8) << 2);
+        } else if length < 1 << 8 {
+            out.push(60 << 2);
+            out.push(length as u8);
+        } else {
+            out.push(61 << 2);
+            out.extend_from_slice(&(length as u16).to_le_bytes());
+        }
+        out.extend_from_slice(literal);
+    }
+    out
+}
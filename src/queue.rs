@@ -0,0 +1,563 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::Instant;
+
+use axum::http::{header, HeaderMap};
+use hmac_sha256::Hash;
+use prometheus::{HistogramVec, IntGaugeVec};
+use tokio::sync::oneshot;
+
+/// How urgently a request wants the model, from the `X-Priority` header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Priority {
+    /// Interactive traffic such as search queries.
+    High,
+    /// Bulk work such as ingest batches; yields to `High`.
+    Low,
+}
+
+impl Priority {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::High => "high",
+            Self::Low => "low",
+        }
+    }
+}
+
+impl FromStr for Priority {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "high" => Ok(Self::High),
+            "low" => Ok(Self::Low),
+            other => anyhow::bail!("unknown priority {:?} (expected high or low)", other),
+        }
+    }
+}
+
+impl fmt::Display for Priority {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Who a request is scheduled as: the hex SHA-256 of its `Authorization`
+/// header, or `anonymous` without one. The credential itself never appears in
+/// metrics or configuration.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Tenant(String);
+
+impl Tenant {
+    pub fn from_credential(credential: Option<&[u8]>) -> Self {
+        match credential {
+            Some(credential) => Self(Hash::hash(credential).iter().map(|byte| format!("{:02x}", byte)).collect()),
+            None => Self("anonymous".to_string()),
+        }
+    }
+
+    /// The tenant a request's `Authorization` header (or Azure-style
+    /// `api-key` header) identifies.
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let credential = headers.get(header::AUTHORIZATION).or_else(|| headers.get("api-key"));
+        Self::from_credential(credential.map(|value| value.as_bytes()))
+    }
+
+    /// The tenant with this id, as listed in settings and usage exports.
+    pub fn from_id(id: &str) -> Self {
+        Self(id.trim().to_ascii_lowercase())
+    }
+
+    /// Work semembed schedules for itself, such as warming the cache.
+    pub fn system() -> Self {
+        Self("semembed".to_string())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+/// Relative shares of the model per tenant, from `SEMEMBED_TENANT_SHARES`
+/// (`<tenant>=<weight>,...`). Unlisted tenants get a share of 1.
+#[derive(Debug, Clone, Default)]
+pub struct TenantShares(HashMap<String, f64>);
+
+impl TenantShares {
+    fn weight(&self, tenant: &Tenant) -> f64 {
+        self.0.get(tenant.as_str()).copied().unwrap_or(1.0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl FromStr for TenantShares {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut shares = HashMap::new();
+        for entry in s.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+            let (tenant, weight) = entry
+                .split_once('=')
+                .ok_or_else(|| anyhow::anyhow!("invalid tenant share {:?} (expected <tenant>=<weight>)", entry))?;
+            let weight: f64 = weight
+                .trim()
+                .parse()
+                .map_err(|_| anyhow::anyhow!("invalid weight in tenant share {:?}", entry))?;
+            if !weight.is_finite() || weight <= 0.0 {
+                anyhow::bail!("tenant share weights must be positive, got {:?}", entry);
+            }
+            shares.insert(tenant.trim().to_ascii_lowercase(), weight);
+        }
+        Ok(Self(shares))
+    }
+}
+
+impl fmt::Display for TenantShares {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut shares: Vec<_> = self.0.iter().collect();
+        shares.sort_by(|a, b| a.0.cmp(b.0));
+        let shares: Vec<String> = shares.iter().map(|(tenant, weight)| format!("{}={}", tenant, weight)).collect();
+        f.write_str(&shares.join(","))
+    }
+}
+
+/// Queue metrics.
+pub struct QueueMetrics {
+    /// Inference calls waiting for the model, by priority.
+    pub depth: IntGaugeVec,
+    /// Inference calls waiting for the model, by tenant.
+    pub tenant_depth: IntGaugeVec,
+    /// Time spent waiting for each turn on the model, by priority.
+    pub wait: HistogramVec,
+}
+
+/// Admission to the model: two priority lanes, with fair queuing across
+/// tenants inside each lane.
+///
+/// Every inference call takes a turn. When the model frees up, waiting
+/// high-priority calls go first; low-priority calls only run when no
+/// high-priority call is waiting. Low-priority requests are embedded in
+/// sub-batches of `low_batch` inputs, each taking its own turn, so a
+/// high-priority request waits for at most one sub-batch rather than a whole
+/// ingest batch.
+///
+/// Within a lane, turns are handed out by weighted fair queuing: each call is
+/// stamped with a virtual finish time (its tenant's previous finish, or the
+/// lane's clock if later, plus inputs / share) and the smallest stamp goes
+/// next. A tenant with thousands of calls queued only delays the others by
+/// its share, instead of occupying the whole queue as it would under FIFO.
+///
+/// Calls wait here asynchronously and only move to the blocking pool once
+/// admitted, so the pool's own FIFO queue can't undo the ordering. A call
+/// whose request goes away while it waits (the client disconnected and its
+/// handler was dropped) leaves the queue immediately.
+pub struct InferenceQueue {
+    scheduler: Mutex<Scheduler>,
+    low_batch: usize,
+    metrics: QueueMetrics,
+}
+
+#[derive(Default)]
+struct Scheduler {
+    busy: bool,
+    next_id: u64,
+    // Kept under the scheduler's lock so a reload swaps them between calls
+    shares: TenantShares,
+    high: Lane,
+    low: Lane,
+}
+
+#[derive(Default)]
+struct Lane {
+    // Virtual start of the call admitted last
+    clock: f64,
+    tenants: HashMap<Tenant, TenantQueue>,
+}
+
+#[derive(Default)]
+struct TenantQueue {
+    // Virtual finish of the tenant's last queued call
+    finish: f64,
+    waiting: VecDeque<Waiter>,
+}
+
+struct Waiter {
+    id: u64,
+    start: f64,
+    finish: f64,
+    priority: Priority,
+    tx: oneshot::Sender<Turn>,
+}
+
+impl Scheduler {
+    fn lane(&mut self, priority: Priority) -> &mut Lane {
+        match priority {
+            Priority::High => &mut self.high,
+            Priority::Low => &mut self.low,
+        }
+    }
+}
+
+impl Lane {
+    fn enqueue(&mut self, id: u64, tenant: Tenant, cost: f64, priority: Priority, tx: oneshot::Sender<Turn>) {
+        let clock = self.clock;
+        let queue = self.tenants.entry(tenant).or_default();
+        let start = queue.finish.max(clock);
+        queue.finish = start + cost;
+        queue.waiting.push_back(Waiter {
+            id,
+            start,
+            finish: queue.finish,
+            priority,
+            tx,
+        });
+    }
+
+    // The waiter with the smallest virtual finish across tenants
+    fn dequeue(&mut self) -> Option<(Tenant, Waiter)> {
+        let tenant = self
+            .tenants
+            .iter()
+            .filter_map(|(tenant, queue)| queue.waiting.front().map(|waiter| (tenant, waiter.finish)))
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+            .map(|(tenant, _)| tenant.clone())?;
+        let queue = self.tenants.get_mut(&tenant)?;
+        let waiter = queue.waiting.pop_front()?;
+        if queue.waiting.is_empty() {
+            self.tenants.remove(&tenant);
+        }
+        self.clock = waiter.start;
+        Some((tenant, waiter))
+    }
+
+    // Take out a waiter that is no longer wanted; false if it was already admitted
+    fn remove(&mut self, tenant: &Tenant, id: u64) -> bool {
+        let Some(queue) = self.tenants.get_mut(tenant) else {
+            return false;
+        };
+        let Some(position) = queue.waiting.iter().position(|waiter| waiter.id == id) else {
+            return false;
+        };
+        queue.waiting.remove(position);
+        if queue.waiting.is_empty() {
+            self.tenants.remove(tenant);
+        }
+        true
+    }
+
+    fn is_empty(&self) -> bool {
+        self.tenants.is_empty()
+    }
+
+    fn len(&self) -> usize {
+        self.tenants.values().map(|queue| queue.waiting.len()).sum()
+    }
+}
+
+impl InferenceQueue {
+    pub fn new(low_batch: usize, shares: TenantShares, metrics: QueueMetrics) -> Self {
+        Self {
+            scheduler: Mutex::new(Scheduler {
+                shares,
+                ..Scheduler::default()
+            }),
+            low_batch: low_batch.max(1),
+            metrics,
+        }
+    }
+
+    /// Replace the tenant shares. Calls already queued keep their place.
+    pub fn set_shares(&self, shares: TenantShares) {
+        self.scheduler().shares = shares;
+    }
+
+    /// Inputs embedded per turn for `priority`; high priority is never split.
+    pub fn batch_size(&self, priority: Priority) -> usize {
+        match priority {
+            Priority::High => usize::MAX,
+            Priority::Low => self.low_batch,
+        }
+    }
+
+    /// Calls waiting for a turn, in both lanes.
+    pub fn depth(&self) -> usize {
+        let scheduler = self.scheduler();
+        scheduler.high.len() + scheduler.low.len()
+    }
+
+    /// Wait until it is this call's turn on the model. `inputs` is the size of
+    /// the batch the turn will embed; the turn lasts until the returned guard
+    /// is dropped.
+    pub async fn acquire(self: &Arc<Self>, priority: Priority, tenant: &Tenant, inputs: usize) -> Turn {
+        let started = Instant::now();
+        let queued = {
+            let mut scheduler = self.scheduler();
+            if !scheduler.busy && scheduler.high.is_empty() && scheduler.low.is_empty() {
+                scheduler.busy = true;
+                None
+            } else {
+                let (tx, rx) = oneshot::channel();
+                let id = scheduler.next_id;
+                scheduler.next_id += 1;
+                let cost = inputs.max(1) as f64 / scheduler.shares.weight(tenant);
+                scheduler.lane(priority).enqueue(id, tenant.clone(), cost, priority, tx);
+                self.metrics.depth.with_label_values(&[priority.as_str()]).inc();
+                self.metrics.tenant_depth.with_label_values(&[tenant.as_str()]).inc();
+                Some((id, rx))
+            }
+        };
+
+        let turn = match queued {
+            None => Turn { queue: Some(self.clone()) },
+            Some((id, rx)) => {
+                let mut waiting = Waiting {
+                    queue: self,
+                    priority,
+                    tenant,
+                    id,
+                    admitted: false,
+                };
+                // The sender is only dropped after handing over a turn or when
+                // the queue itself goes away, which can't happen while we hold it
+                let turn = rx.await.unwrap_or(Turn { queue: None });
+                waiting.admitted = true;
+                turn
+            }
+        };
+        self.metrics
+            .wait
+            .with_label_values(&[priority.as_str()])
+            .observe(started.elapsed().as_secs_f64());
+        turn
+    }
+
+    // Hand the model to the next waiter, or mark it idle
+    fn release(self: &Arc<Self>) {
+        let mut scheduler = self.scheduler();
+        loop {
+            let next = match scheduler.high.dequeue() {
+                Some(next) => Some(next),
+                None => scheduler.low.dequeue(),
+            };
+            let Some((tenant, waiter)) = next else {
+                scheduler.busy = false;
+                return;
+            };
+            self.left_queue(waiter.priority, &tenant);
+            match waiter.tx.send(Turn { queue: Some(self.clone()) }) {
+                Ok(()) => return,
+                // The request was cancelled while waiting; defuse the turn so
+                // dropping it doesn't re-enter this lock, and try the next one
+                Err(mut turn) => turn.queue = None,
+            }
+        }
+    }
+
+    fn left_queue(&self, priority: Priority, tenant: &Tenant) {
+        self.metrics.depth.with_label_values(&[priority.as_str()]).dec();
+        let tenant_depth = self.metrics.tenant_depth.with_label_values(&[tenant.as_str()]);
+        tenant_depth.dec();
+        if tenant_depth.get() <= 0 {
+            // Keep the label set to tenants with queued work
+            let _ = self.metrics.tenant_depth.remove_label_values(&[tenant.as_str()]);
+        }
+    }
+
+    // Only plain bookkeeping lives under this lock, so a poisoned one is still consistent
+    fn scheduler(&self) -> MutexGuard<'_, Scheduler> {
+        self.scheduler.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// A turn on the model, released on drop.
+pub struct Turn {
+    queue: Option<Arc<InferenceQueue>>,
+}
+
+impl Drop for Turn {
+    fn drop(&mut self) {
+        if let Some(queue) = self.queue.take() {
+            queue.release();
+        }
+    }
+}
+
+// A call waiting in the queue; dropping it before it is admitted takes it out
+struct Waiting<'a> {
+    queue: &'a InferenceQueue,
+    priority: Priority,
+    tenant: &'a Tenant,
+    id: u64,
+    admitted: bool,
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        if self.admitted {
+            return;
+        }
+        let removed = self.queue.scheduler().lane(self.priority).remove(self.tenant, self.id);
+        if removed {
+            self.queue.left_queue(self.priority, self.tenant);
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use prometheus::core::Collector;
+    use prometheus::{HistogramOpts, Opts};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+    use tokio::task::JoinSet;
+
+    /// A queue splitting low-priority work into batches of `low_batch`.
+    pub(crate) fn queue(low_batch: usize) -> InferenceQueue {
+        InferenceQueue::new(
+            low_batch,
+            TenantShares::default(),
+            QueueMetrics {
+                depth: IntGaugeVec::new(Opts::new("depth", "depth"), &["priority"]).unwrap(),
+                tenant_depth: IntGaugeVec::new(Opts::new("tenant_depth", "depth"), &["tenant"]).unwrap(),
+                wait: HistogramVec::new(HistogramOpts::new("wait", "wait"), &["priority"]).unwrap(),
+            },
+        )
+    }
+
+    // Yield until `count` calls are waiting for a turn
+    async fn until_queued(queue: &InferenceQueue, count: usize) {
+        while queue.depth() != count {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    // The order tenants are admitted in once the turn held while `calls`
+    // queued up is released; each call gives its turn straight back
+    async fn admission_order(queue: Arc<InferenceQueue>, calls: Vec<(Priority, &str)>) -> Vec<String> {
+        let held = queue.acquire(Priority::High, &Tenant::system(), 1).await;
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let mut tasks = JoinSet::new();
+        for (count, (priority, tenant)) in calls.into_iter().enumerate() {
+            let (queued, order, tenant) = (queue.clone(), order.clone(), Tenant::from_id(tenant));
+            tasks.spawn(async move {
+                let _turn = queued.acquire(priority, &tenant, 1).await;
+                order.lock().unwrap().push(tenant.as_str().to_string());
+            });
+            // Queue them in order
+            until_queued(&queue, count + 1).await;
+        }
+        drop(held);
+        while tasks.join_next().await.is_some() {}
+        Arc::try_unwrap(order).unwrap().into_inner().unwrap()
+    }
+
+    #[tokio::test]
+    async fn high_priority_goes_first() {
+        let calls = vec![
+            (Priority::Low, "a"),
+            (Priority::Low, "a"),
+            (Priority::High, "b"),
+            (Priority::Low, "a"),
+            (Priority::High, "b"),
+        ];
+        let order = admission_order(Arc::new(queue(8)), calls).await;
+        assert_eq!(order, ["b", "b", "a", "a", "a"]);
+    }
+
+    #[tokio::test]
+    async fn tenants_take_turns_instead_of_fifo() {
+        let mut calls = vec![(Priority::Low, "bulk"); 6];
+        calls.extend([(Priority::Low, "search"); 2]);
+        let order = admission_order(Arc::new(queue(8)), calls).await;
+        // Each pair ties on virtual finish time, so either tenant may go first
+        for pair in order[..4].chunks(2) {
+            assert!(pair.contains(&"bulk".to_string()) && pair.contains(&"search".to_string()), "{:?}", order);
+        }
+        assert!(order[4..].iter().all(|tenant| tenant == "bulk"), "{:?}", order);
+    }
+
+    #[tokio::test]
+    async fn turns_follow_the_tenant_shares() {
+        let queue = Arc::new(queue(8));
+        queue.set_shares("a=3".parse().unwrap());
+        let mut calls = vec![(Priority::Low, "a"); 30];
+        calls.extend([(Priority::Low, "b"); 30]);
+        let order = admission_order(queue, calls).await;
+        let first: Vec<&String> = order.iter().take(20).collect();
+        assert_eq!(first.iter().filter(|tenant| tenant.as_str() == "a").count(), 15, "{:?}", first);
+    }
+
+    // A tenant sending one request at a time waits for at most the turn in
+    // progress, while another keeps thousands queued on a mock backend
+    #[tokio::test]
+    async fn a_light_tenant_keeps_its_latency_behind_a_flood() {
+        let queue = Arc::new(queue(8));
+        let served = Arc::new(AtomicUsize::new(0));
+        let infer = |tenant: &'static str| {
+            let (queue, served) = (queue.clone(), served.clone());
+            async move {
+                let before = served.load(Ordering::SeqCst);
+                let turn = queue.acquire(Priority::Low, &Tenant::from_id(tenant), 1).await;
+                let waited = served.load(Ordering::SeqCst) - before;
+                tokio::time::sleep(Duration::from_micros(200)).await;
+                served.fetch_add(1, Ordering::SeqCst);
+                drop(turn);
+                waited
+            }
+        };
+
+        let held = queue.acquire(Priority::High, &Tenant::system(), 1).await;
+        let mut flood = JoinSet::new();
+        for _ in 0..2000 {
+            flood.spawn(infer("heavy"));
+        }
+        until_queued(&queue, 2000).await;
+        let tenant_depth = queue.metrics.tenant_depth.with_label_values(&["heavy"]).get();
+        assert_eq!(tenant_depth, 2000);
+        drop(held);
+
+        for _ in 0..20 {
+            let waited = infer("light").await;
+            assert!(waited <= 2, "the light tenant waited for {} turns", waited);
+        }
+        assert!(queue.depth() > 1000, "the flood drained before the light tenant finished");
+
+        // Cancelled callers leave the queue and its metrics
+        flood.shutdown().await;
+        assert_eq!(queue.depth(), 0);
+        assert!(queue.metrics.tenant_depth.collect()[0].get_metric().is_empty());
+        assert_eq!(queue.metrics.depth.with_label_values(&["low"]).get(), 0);
+    }
+
+    #[test]
+    fn tenant_shares_parse_and_print() {
+        let shares: TenantShares = " Team-A=2.5, b=1 ,".parse().unwrap();
+        assert_eq!(shares.weight(&Tenant::from_id("team-a")), 2.5);
+        assert_eq!(shares.weight(&Tenant::from_id("unlisted")), 1.0);
+        assert_eq!(shares.to_string(), "b=1,team-a=2.5");
+        for invalid in ["a", "a=x", "a=0", "a=-1", "a=inf"] {
+            assert!(invalid.parse::<TenantShares>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn tenants_are_the_full_hash_of_the_credential() {
+        let tenant = Tenant::from_credential(Some(b"Bearer sk-example"));
+        assert_eq!(tenant.as_str(), "ac3a082afe3387dc2178486acff3da122d6f0fe990defc2d0268c01baeac34cf");
+        assert_eq!(Tenant::from_credential(None).as_str(), "anonymous");
+    }
+
+    #[test]
+    fn priorities_parse_case_insensitively() {
+        assert_eq!(" HIGH ".parse::<Priority>().unwrap(), Priority::High);
+        assert_eq!("low".parse::<Priority>().unwrap(), Priority::Low);
+        assert!("urgent".parse::<Priority>().is_err());
+    }
+}
@@ -0,0 +1,389 @@
+//! Daily and monthly token quotas per API key.
+//!
+//! `SEMEMBED_DAILY_TOKEN_QUOTAS` and `SEMEMBED_MONTHLY_TOKEN_QUOTAS` cap the
+//! tokens a tenant may embed per UTC day or calendar month. Usage is read
+//! from the usage ledger, so it survives restarts with `SEMEMBED_USAGE_FILE`
+//! and periods reset at UTC boundaries on their own. A request is checked
+//! against its real token count before inference and rejected with `429`
+//! (`quota_exceeded`) if it would go over. `/admin/quotas` shows what is left
+//! and adjusts it for the current period.
+//!
+//! Keys aren't authenticated, so a client can make up a new one at will. Only
+//! the listed keys have quotas of their own; every other key counts against
+//! the one `*` quota, when there is one.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::error::ApiError;
+use crate::extract::ApiJson;
+use crate::queue::Tenant;
+use crate::usage::{flush, Day};
+use crate::AppState;
+
+/// The quota key every tenant without a quota of its own shares.
+const OTHERS: &str = "*";
+
+/// Tokens per tenant, from `<tenant>=<tokens>,...`; `*` is shared by the
+/// tenants not listed.
+#[derive(Debug, Clone, Default)]
+pub struct TokenQuotas(HashMap<String, u64>);
+
+impl TokenQuotas {
+    fn get(&self, tenant: &Tenant) -> Option<u64> {
+        self.0.get(tenant.as_str()).copied()
+    }
+}
+
+impl FromStr for TokenQuotas {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut quotas = HashMap::new();
+        for entry in s.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+            let (tenant, tokens) = entry
+                .split_once('=')
+                .ok_or_else(|| anyhow::anyhow!("invalid token quota {:?} (expected <tenant>=<tokens>)", entry))?;
+            let tokens: u64 = tokens
+                .trim()
+                .parse()
+                .map_err(|_| anyhow::anyhow!("invalid token count in quota {:?}", entry))?;
+            quotas.insert(tenant.trim().to_ascii_lowercase(), tokens);
+        }
+        Ok(Self(quotas))
+    }
+}
+
+impl fmt::Display for TokenQuotas {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut quotas: Vec<_> = self.0.iter().collect();
+        quotas.sort_by(|a, b| a.0.cmp(b.0));
+        let quotas: Vec<String> = quotas.iter().map(|(tenant, tokens)| format!("{}={}", tenant, tokens)).collect();
+        f.write_str(&quotas.join(","))
+    }
+}
+
+/// The quotas in effect; part of the reloadable settings.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Quotas {
+    pub daily: TokenQuotas,
+    pub monthly: TokenQuotas,
+}
+
+impl Quotas {
+    pub fn is_empty(&self) -> bool {
+        self.daily.0.is_empty() && self.monthly.0.is_empty()
+    }
+
+    fn limit(&self, period: Period, tenant: &Tenant) -> Option<u64> {
+        match period {
+            Period::Daily => self.daily.get(tenant),
+            Period::Monthly => self.monthly.get(tenant),
+        }
+    }
+
+    /// Every tenant with a quota, sorted.
+    pub fn tenants(&self) -> Vec<String> {
+        let mut tenants: Vec<String> = self.daily.0.keys().chain(self.monthly.0.keys()).cloned().collect();
+        tenants.sort();
+        tenants.dedup();
+        tenants
+    }
+}
+
+/// The span a quota covers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Period {
+    Daily,
+    Monthly,
+}
+
+impl Period {
+    const ALL: [Period; 2] = [Period::Daily, Period::Monthly];
+
+    /// The first day of the period `today` is in.
+    pub fn start(self, today: Day) -> Day {
+        match self {
+            Period::Daily => today,
+            Period::Monthly => today.month_start(),
+        }
+    }
+
+    // The first day of the next period, when usage starts over
+    fn reset(self, today: Day) -> Day {
+        match self {
+            Period::Daily => today.next(),
+            Period::Monthly => today.next_month_start(),
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Period::Daily => "daily",
+            Period::Monthly => "monthly",
+        }
+    }
+}
+
+// Where a tenant stands against one of its quotas
+#[derive(Debug, Serialize)]
+struct Standing {
+    limit: u64,
+    used: u64,
+    // Tokens added at runtime for this period; negative when taken away
+    adjustment: i64,
+    remaining: u64,
+    // When usage starts over, as an RFC 3339 time
+    resets_at: String,
+    #[serde(skip)]
+    reset: Day,
+}
+
+fn standing(state: &AppState, quotas: &Quotas, tenant: &Tenant, period: Period, today: Day) -> Option<Standing> {
+    let start = period.start(today);
+    // Keys without quotas of their own share the `*` quota, and its usage
+    let listed = quotas.tenants();
+    let (tenant, used) = if tenant.as_str() != OTHERS && listed.iter().any(|key| key == tenant.as_str()) {
+        (tenant.clone(), state.usage.tokens_between(tenant, start, today))
+    } else {
+        (Tenant::from_id(OTHERS), state.usage.tokens_except(&listed, start, today))
+    };
+    let limit = quotas.limit(period, &tenant)?;
+    let adjustment = state.usage.quota_adjustment(&tenant, period, start);
+    let allowed = (i128::from(limit) + i128::from(adjustment)).max(0) as u64;
+    let reset = period.reset(today);
+    Some(Standing {
+        limit,
+        used,
+        adjustment,
+        remaining: allowed.saturating_sub(used),
+        resets_at: format!("{}T00:00:00Z", reset),
+        reset,
+    })
+}
+
+/// Where a tenant stands against its quotas as a request starts.
+pub(crate) struct Allowance(Vec<(Period, Standing)>);
+
+impl Allowance {
+    /// The most tokens a request can use without going over a quota, or
+    /// `None` without one.
+    pub fn remaining(&self) -> Option<u64> {
+        self.0.iter().map(|(_, standing)| standing.remaining).min()
+    }
+
+    /// Reject a request of `tokens` that would go over a daily or monthly
+    /// quota.
+    pub fn check(&self, tokens: usize) -> Result<(), ApiError> {
+        // The period that frees up last is the one the client has to wait for
+        let Some((period, exceeded)) = self
+            .0
+            .iter()
+            .filter(|(_, standing)| tokens as u64 > standing.remaining)
+            .max_by_key(|(_, standing)| standing.reset)
+        else {
+            return Ok(());
+        };
+        let mut error = ApiError::new(
+            StatusCode::TOO_MANY_REQUESTS,
+            "rate_limit_error",
+            format!(
+                "This request needs {} tokens but only {} remain in this key's {} quota of {}; it resets at {}",
+                tokens,
+                exceeded.remaining,
+                period.as_str(),
+                exceeded.limit,
+                exceeded.resets_at
+            ),
+        )
+        .code("quota_exceeded")
+        .reason("quota_exceeded")
+        .retry_after(exceeded.reset.secs_until(SystemTime::now()).max(1))
+        .header("x-quota-reset", &exceeded.resets_at);
+        for (period, standing) in &self.0 {
+            let (limit, remaining) = match period {
+                Period::Daily => ("x-quota-limit-daily", "x-quota-remaining-daily"),
+                Period::Monthly => ("x-quota-limit-monthly", "x-quota-remaining-monthly"),
+            };
+            error = error.header(limit, standing.limit).header(remaining, standing.remaining);
+        }
+        Err(error)
+    }
+}
+
+/// What `tenant` has left of its quotas, or of the shared `*` quotas when it
+/// has none of its own.
+pub(crate) fn allowance(state: &AppState, tenant: &Tenant) -> Allowance {
+    let settings = state.settings();
+    if settings.quotas.is_empty() {
+        return Allowance(Vec::new());
+    }
+    let today = Day::today();
+    Allowance(
+        Period::ALL
+            .into_iter()
+            .filter_map(|period| {
+                standing(state, &settings.quotas, tenant, period, today).map(|standing| (period, standing))
+            })
+            .collect(),
+    )
+}
+
+/// Reject a request of `tokens` that would take `tenant` over its daily or
+/// monthly quota. Requests running at once are checked against the same
+/// usage, so together they can go over by at most their own tokens.
+pub(crate) fn check(state: &AppState, tenant: &Tenant, tokens: usize) -> Result<(), ApiError> {
+    allowance(state, tenant).check(tokens)
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct TenantQuotas {
+    key: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    daily: Option<Standing>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    monthly: Option<Standing>,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct QuotaList {
+    object: &'static str,
+    data: Vec<TenantQuotas>,
+}
+
+fn tenant_quotas(state: &AppState, quotas: &Quotas, key: &str, today: Day) -> TenantQuotas {
+    let tenant = Tenant::from_id(key);
+    TenantQuotas {
+        key: tenant.as_str().to_string(),
+        daily: standing(state, quotas, &tenant, Period::Daily, today),
+        monthly: standing(state, quotas, &tenant, Period::Monthly, today),
+    }
+}
+
+/// `GET /admin/quotas`: every key with a quota and what it has left.
+pub(crate) async fn list(State(state): State<Arc<AppState>>) -> Json<QuotaList> {
+    let settings = state.settings();
+    let today = Day::today();
+    Json(QuotaList {
+        object: "list",
+        data: settings
+            .quotas
+            .tenants()
+            .iter()
+            .map(|key| tenant_quotas(&state, &settings.quotas, key, today))
+            .collect(),
+    })
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct Adjustment {
+    period: Period,
+    // Set what is left for the rest of the period...
+    remaining: Option<u64>,
+    // ...or add to it (negative to take away)
+    add: Option<i64>,
+}
+
+/// `POST /admin/quotas/:key`: change what a key has left of its quota for
+/// the current day or month. The change lapses when the period ends.
+pub(crate) async fn adjust(
+    State(state): State<Arc<AppState>>,
+    Path(key): Path<String>,
+    ApiJson(adjustment): ApiJson<Adjustment>,
+) -> Result<Json<TenantQuotas>, ApiError> {
+    let settings = state.settings();
+    let tenant = Tenant::from_id(&key);
+    let today = Day::today();
+    let period = adjustment.period;
+    let Some(current) = standing(&state, &settings.quotas, &tenant, period, today) else {
+        return Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            "invalid_request_error",
+            format!("The key `{}` has no {} token quota", tenant.as_str(), period.as_str()),
+        )
+        .reason("not_found"));
+    };
+    let tokens = match (adjustment.remaining, adjustment.add) {
+        // remaining = limit + adjustment - used
+        (Some(remaining), None) => {
+            (i128::from(remaining) + i128::from(current.used) - i128::from(current.limit)).clamp(
+                i128::from(i64::MIN),
+                i128::from(i64::MAX),
+            ) as i64
+        }
+        (None, Some(add)) => current.adjustment.saturating_add(add),
+        _ => {
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "invalid_request_error",
+                "Set exactly one of remaining and add",
+            )
+            .reason("invalid_quota_adjustment"))
+        }
+    };
+    state.usage.set_quota_adjustment(&tenant, period, period.start(today), tokens);
+    flush(state.usage.clone()).await;
+    info!("{} token quota of {} adjusted by {} for this period", period.as_str(), tenant.as_str(), tokens);
+    Ok(Json(tenant_quotas(&state, &settings.quotas, tenant.as_str(), today)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::response::IntoResponse;
+
+    fn standing(limit: u64, used: u64, reset: &str) -> Standing {
+        let reset: Day = reset.parse().unwrap();
+        Standing {
+            limit,
+            used,
+            adjustment: 0,
+            remaining: limit.saturating_sub(used),
+            resets_at: format!("{}T00:00:00Z", reset),
+            reset,
+        }
+    }
+
+    #[test]
+    fn quotas_parse_and_print() {
+        let quotas: TokenQuotas = " Team-A=500, b=7 ,".parse().unwrap();
+        assert_eq!(quotas.get(&Tenant::from_id("team-a")), Some(500));
+        assert_eq!(quotas.get(&Tenant::from_id("unlisted")), None);
+        assert_eq!(quotas.to_string(), "b=7,team-a=500");
+        for invalid in ["a", "a=x", "a=-1", "a=1.5"] {
+            assert!(invalid.parse::<TokenQuotas>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn a_request_fits_what_every_quota_has_left() {
+        let allowance = Allowance(vec![
+            (Period::Daily, standing(1000, 900, "2025-03-02")),
+            (Period::Monthly, standing(5000, 4950, "2025-04-01")),
+        ]);
+        assert_eq!(allowance.remaining(), Some(50));
+        assert!(allowance.check(50).is_ok());
+        assert!(Allowance(Vec::new()).check(usize::MAX).is_ok());
+        assert_eq!(Allowance(Vec::new()).remaining(), None);
+
+        let response = allowance.check(60).unwrap_err().into_response();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        let headers = response.headers();
+        assert_eq!(headers["x-quota-reset"], "2025-04-01T00:00:00Z");
+        assert_eq!(headers["x-quota-remaining-daily"], "100");
+        assert_eq!(headers["x-quota-remaining-monthly"], "50");
+        assert_eq!(headers["retry-after"], "1");
+    }
+}
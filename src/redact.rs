@@ -0,0 +1,126 @@
+use std::borrow::Cow;
+use std::fmt;
+use std::sync::OnceLock;
+
+use hmac_sha256::Hash;
+use regex::Regex;
+
+/// User-provided text that must never reach logs, metrics or error reports.
+///
+/// `Display` and `Debug` print only the length and a short hash, which is
+/// enough to tell whether two log lines concern the same input.
+#[derive(Clone, Copy)]
+pub struct Redacted<'a>(pub &'a str);
+
+impl fmt::Display for Redacted<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let hash = Hash::hash(self.0.as_bytes());
+        write!(
+            f,
+            "<redacted {} bytes sha256:{:02x}{:02x}{:02x}{:02x}>",
+            self.0.len(),
+            hash[0],
+            hash[1],
+            hash[2],
+            hash[3]
+        )
+    }
+}
+
+impl fmt::Debug for Redacted<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+/// Scrub an error message produced while processing `inputs`.
+///
+/// Tokenizer and runtime errors may echo the text they choked on, in full or
+/// in part. Every occurrence of an input is replaced, and so is every quoted
+/// span, since that is how partial values usually appear in error messages.
+pub fn redact_inputs(message: &str, inputs: &[&str]) -> String {
+    static QUOTED: OnceLock<Regex> = OnceLock::new();
+
+    let quoted = QUOTED.get_or_init(|| Regex::new(r#""[^"]+"|'[^']+'|`[^`]+`"#).expect("valid quote pattern"));
+    let mut message = quoted
+        .replace_all(message, |caps: &regex::Captures| {
+            let span = &caps[0];
+            Redacted(&span[1..span.len() - 1]).to_string()
+        })
+        .into_owned();
+    for input in inputs.iter().filter(|input| !input.trim().is_empty()) {
+        if message.contains(input) {
+            message = message.replace(input, &Redacted(input).to_string());
+        }
+    }
+    message
+}
+
+/// A request target with the value of its `input` query parameter redacted,
+/// for logging the URLs of `GET /v1/embeddings`.
+pub fn redact_query(target: &str) -> Cow<'_, str> {
+    let Some((path, query)) = target.split_once('?') else {
+        return Cow::Borrowed(target);
+    };
+    if !query.split('&').any(|pair| pair.starts_with("input=")) {
+        return Cow::Borrowed(target);
+    }
+    let query: Vec<String> = query
+        .split('&')
+        .map(|pair| match pair.strip_prefix("input=") {
+            Some(value) => format!("input={}", Redacted(value)),
+            None => pair.to_string(),
+        })
+        .collect();
+    Cow::Owned(format!("{}?{}", path, query.join("&")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SENTINEL: &str = "PRIVATE-7f3a-do-not-log";
+
+    #[test]
+    fn redacted_text_prints_only_its_length_and_hash() {
+        let shown = format!("{} {:?}", Redacted(SENTINEL), Redacted(SENTINEL));
+        assert!(!shown.contains(SENTINEL), "{}", shown);
+        assert!(shown.starts_with(&format!("<redacted {} bytes sha256:", SENTINEL.len())), "{}", shown);
+        assert_eq!(Redacted(SENTINEL).to_string(), Redacted(SENTINEL).to_string());
+        assert_ne!(Redacted(SENTINEL).to_string(), Redacted("other text").to_string());
+    }
+
+    #[test]
+    fn inputs_echoed_by_errors_are_redacted() {
+        let input = format!("some words around {} and more", SENTINEL);
+        let errors = [
+            format!("unable to encode {}", input),
+            format!("token not found: \"{}\"", SENTINEL),
+            format!("bad span '{}' at 3", &SENTINEL[..12]),
+            format!("panicked at `{}`", SENTINEL),
+        ];
+        for error in &errors {
+            let message = redact_inputs(error, &[&input, "unrelated"]);
+            assert!(!message.contains(&SENTINEL[..12]), "{}", message);
+            assert!(message.contains("<redacted "), "{}", message);
+        }
+    }
+
+    #[test]
+    fn messages_without_inputs_are_unchanged() {
+        assert_eq!(redact_inputs("sequence too long", &[SENTINEL]), "sequence too long");
+        // Blank inputs would otherwise redact every space in the message
+        assert_eq!(redact_inputs("sequence too long", &[" ", ""]), "sequence too long");
+    }
+
+    #[test]
+    fn only_the_input_query_parameter_is_redacted() {
+        assert_eq!(redact_query("/v1/embeddings"), "/v1/embeddings");
+        assert_eq!(redact_query("/v1/embeddings?model=bge"), "/v1/embeddings?model=bge");
+        let target = format!("/v1/embeddings?model=bge&input={}&dimensions=8", SENTINEL);
+        let redacted = redact_query(&target);
+        assert!(!redacted.contains(SENTINEL), "{}", redacted);
+        assert!(redacted.starts_with("/v1/embeddings?model=bge&input=<redacted "), "{}", redacted);
+        assert!(redacted.ends_with("&dimensions=8"), "{}", redacted);
+    }
+}
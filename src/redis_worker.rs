@@ -0,0 +1,496 @@
+//! Embedding worker pulling jobs from Redis (`--features redis`).
+//!
+//! Jobs are JSON objects `{id, texts, reply_key}`, popped from a list with
+//! `BRPOP` or read from a stream through a consumer group. Each is embedded
+//! like a bulk request and its result written to `reply_key` with a TTL, or
+//! added to a results stream. Stream entries are acknowledged only once the
+//! result is written; entries left pending by a crashed worker are reclaimed
+//! after an idle time, and given up on as poisoned after too many deliveries.
+
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use prometheus::{Counter, CounterVec, Opts, Registry};
+use redis::aio::{ConnectionManager, ConnectionManagerConfig};
+use redis::streams::{StreamClaimReply, StreamId, StreamPendingCountReply, StreamReadReply};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::bulk::embed_texts;
+use crate::extract::Scheduling;
+use crate::models::InputKind;
+use crate::queue::{Priority, Tenant};
+use crate::{env_parse, AppState, EmbeddingData, EncodingFormat, ItemError};
+
+// How long one BRPOP or XREADGROUP waits for a job
+const BLOCK: Duration = Duration::from_secs(5);
+// Wait after a Redis error, or before retrying a job while the circuit is open
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+// Pending entries looked at per reclaim pass
+const RECLAIM_BATCH: usize = 16;
+// Stream entry field holding the job
+const JOB_FIELD: &str = "job";
+
+/// Where jobs come from.
+#[derive(Debug, Clone)]
+pub enum Source {
+    List(String),
+    Stream {
+        key: String,
+        group: String,
+        consumer: String,
+    },
+}
+
+/// The worker's settings, from the `SEMEMBED_REDIS_*` variables.
+#[derive(Debug, Clone)]
+pub struct RedisConfig {
+    pub url: String,
+    pub source: Source,
+    // Stream results are added to when a job has no reply key
+    pub results_stream: Option<String>,
+    pub result_ttl: Duration,
+    pub reclaim_idle: Duration,
+    pub max_deliveries: usize,
+    pub priority: Priority,
+    // Run without the HTTP listeners
+    pub standalone: bool,
+}
+
+impl RedisConfig {
+    /// `None` when neither `SEMEMBED_REDIS_LIST` nor `SEMEMBED_REDIS_STREAM`
+    /// is set.
+    pub fn from_env() -> anyhow::Result<Option<Self>> {
+        let source = match (non_empty("SEMEMBED_REDIS_LIST"), non_empty("SEMEMBED_REDIS_STREAM")) {
+            (None, None) => return Ok(None),
+            (Some(_), Some(_)) => anyhow::bail!("set SEMEMBED_REDIS_LIST or SEMEMBED_REDIS_STREAM, not both"),
+            (Some(key), None) => Source::List(key),
+            (None, Some(key)) => Source::Stream {
+                key,
+                group: non_empty("SEMEMBED_REDIS_GROUP").unwrap_or_else(|| "semembed".to_string()),
+                consumer: non_empty("SEMEMBED_REDIS_CONSUMER")
+                    .or_else(|| non_empty("HOSTNAME"))
+                    .unwrap_or_else(|| format!("semembed-{}", std::process::id())),
+            },
+        };
+        Ok(Some(Self {
+            url: non_empty("SEMEMBED_REDIS_URL").unwrap_or_else(|| "redis://127.0.0.1:6379".to_string()),
+            source,
+            results_stream: non_empty("SEMEMBED_REDIS_RESULTS_STREAM"),
+            result_ttl: Duration::from_secs(env_parse("SEMEMBED_REDIS_RESULT_TTL_SECS")?.unwrap_or(3600)),
+            reclaim_idle: Duration::from_secs(env_parse("SEMEMBED_REDIS_RECLAIM_IDLE_SECS")?.unwrap_or(60)),
+            max_deliveries: env_parse("SEMEMBED_REDIS_MAX_DELIVERIES")?.unwrap_or(5),
+            priority: std::env::var("SEMEMBED_REDIS_PRIORITY").map_or(Ok(Priority::Low), |value| value.parse())?,
+            standalone: crate::env_flag("SEMEMBED_REDIS_STANDALONE")?,
+        }))
+    }
+}
+
+fn non_empty(name: &str) -> Option<String> {
+    std::env::var(name).ok().map(|value| value.trim().to_string()).filter(|value| !value.is_empty())
+}
+
+/// Connect to Redis at `url`. The connection reconnects by itself after
+/// errors; `response_timeout` must outlast any blocking command sent on it.
+pub async fn connect(url: &str, response_timeout: Duration) -> anyhow::Result<ConnectionManager> {
+    let client = redis::Client::open(url)?;
+    let config = ConnectionManagerConfig::new().set_response_timeout(Some(response_timeout));
+    Ok(client.get_connection_manager_with_config(config).await?)
+}
+
+// Redis worker metrics, registered next to the server's
+struct RedisMetrics {
+    jobs: CounterVec,
+    reclaimed: Counter,
+}
+
+impl RedisMetrics {
+    fn register(registry: &Registry) -> anyhow::Result<Self> {
+        let jobs = CounterVec::new(
+            Opts::new(
+                "semembed_redis_jobs_total",
+                "Redis jobs handled, by outcome (completed, invalid or poisoned)"
+            ),
+            &["outcome"],
+        )?;
+        registry.register(Box::new(jobs.clone()))?;
+
+        let reclaimed = Counter::with_opts(Opts::new(
+            "semembed_redis_reclaimed_total",
+            "Stream entries reclaimed from a consumer that left them pending too long"
+        ))?;
+        registry.register(Box::new(reclaimed.clone()))?;
+
+        Ok(Self { jobs, reclaimed })
+    }
+}
+
+#[derive(Deserialize)]
+struct Job {
+    id: serde_json::Value,
+    texts: Vec<String>,
+    reply_key: Option<String>,
+    // Jobs are documents, so this defaults to passage
+    input_type: Option<InputKind>,
+    dimensions: Option<usize>,
+}
+
+// What's written back for a job: an embedding or error per text, or an error
+// for the whole job
+#[derive(Serialize)]
+struct JobResult<'a> {
+    id: serde_json::Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    model: Option<&'a str>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    data: Vec<ResultItem>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<ItemError>,
+}
+
+#[derive(Serialize)]
+struct ResultItem {
+    index: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    embedding: Option<EmbeddingData>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<ItemError>,
+}
+
+struct Worker {
+    state: Arc<AppState>,
+    config: RedisConfig,
+    scheduling: Scheduling,
+    metrics: RedisMetrics,
+    conn: ConnectionManager,
+}
+
+/// Pull and embed jobs until the worker fails to start. Redis errors while
+/// running are logged and retried rather than returned.
+pub async fn run(state: Arc<AppState>, config: RedisConfig) -> io::Result<()> {
+    work(state, config).await.map_err(|e| io::Error::other(format!("Redis worker: {:#}", e)))
+}
+
+async fn work(state: Arc<AppState>, config: RedisConfig) -> anyhow::Result<()> {
+    let metrics = RedisMetrics::register(&state.metrics.registry)?;
+    let conn = connect(&config.url, BLOCK + Duration::from_secs(5)).await?;
+    let mut worker = Worker {
+        scheduling: Scheduling {
+            priority: Some(config.priority),
+            tenant: Tenant::system(),
+            timing: None,
+            access: None,
+            request_id: None,
+            trace: None,
+        },
+        state,
+        config,
+        metrics,
+        conn,
+    };
+    worker.state.health.watch("redis");
+    match worker.config.source.clone() {
+        Source::List(key) => {
+            info!("Redis worker: popping jobs from list {}", key);
+            worker.pop(&key).await
+        }
+        Source::Stream { key, group, consumer } => {
+            info!("Redis worker: reading jobs from stream {} as {} in group {}", key, consumer, group);
+            worker.read(&key, &group, &consumer).await
+        }
+    }
+}
+
+impl Worker {
+    // List mode: a job popped is gone from Redis, so one in progress when the
+    // worker dies is lost
+    async fn pop(&mut self, key: &str) -> anyhow::Result<()> {
+        loop {
+            let popped: redis::RedisResult<Option<(String, String)>> =
+                redis::cmd("BRPOP").arg(key).arg(BLOCK.as_secs()).query_async(&mut self.conn).await;
+            let result = match popped {
+                Ok(Some((_, job))) => self.handle(job.as_bytes(), "completed").await,
+                // Nothing queued within the block time
+                Ok(None) => Ok(()),
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(()) => self.state.health.recovered("redis"),
+                Err(e) => {
+                    warn!("Redis worker: {}", e);
+                    self.state.health.report("redis", &e);
+                    tokio::time::sleep(RETRY_DELAY).await;
+                }
+            }
+        }
+    }
+
+    // Stream mode: entries stay pending in the group until their result is
+    // written and they're acknowledged
+    async fn read(&mut self, key: &str, group: &str, consumer: &str) -> anyhow::Result<()> {
+        // Starting at 0 picks up jobs queued before the group existed
+        let created: redis::RedisResult<()> = redis::cmd("XGROUP")
+            .arg("CREATE")
+            .arg(key)
+            .arg(group)
+            .arg("0")
+            .arg("MKSTREAM")
+            .query_async(&mut self.conn)
+            .await;
+        match created {
+            Err(e) if e.code() != Some("BUSYGROUP") => return Err(e.into()),
+            _ => {}
+        }
+
+        let mut last_reclaim: Option<Instant> = None;
+        loop {
+            if last_reclaim.is_none_or(|last| last.elapsed() >= self.config.reclaim_idle / 2) {
+                last_reclaim = Some(Instant::now());
+                if let Err(e) = self.reclaim(key, group, consumer).await {
+                    warn!("Redis worker: failed to reclaim pending jobs: {}", e);
+                }
+            }
+            let read: redis::RedisResult<Option<StreamReadReply>> = redis::cmd("XREADGROUP")
+                .arg("GROUP")
+                .arg(group)
+                .arg(consumer)
+                .arg("COUNT")
+                .arg(1)
+                .arg("BLOCK")
+                .arg(BLOCK.as_millis() as u64)
+                .arg("STREAMS")
+                .arg(key)
+                .arg(">")
+                .query_async(&mut self.conn)
+                .await;
+            let result = match read {
+                Ok(reply) => {
+                    let entries = reply.into_iter().flat_map(|reply| reply.keys).flat_map(|key| key.ids);
+                    let mut result = Ok(());
+                    for entry in entries {
+                        result = self.process(key, group, entry, "completed").await;
+                        if result.is_err() {
+                            break;
+                        }
+                    }
+                    result
+                }
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(()) => self.state.health.recovered("redis"),
+                Err(e) => {
+                    warn!("Redis worker: {}", e);
+                    self.state.health.report("redis", &e);
+                    tokio::time::sleep(RETRY_DELAY).await;
+                }
+            }
+        }
+    }
+
+    // Claim entries other consumers (or an earlier run of this one) left
+    // pending past the idle time: embed them again, or give up on those
+    // delivered too often
+    async fn reclaim(&mut self, key: &str, group: &str, consumer: &str) -> redis::RedisResult<()> {
+        let idle = self.config.reclaim_idle.as_millis() as u64;
+        let pending: StreamPendingCountReply = redis::cmd("XPENDING")
+            .arg(key)
+            .arg(group)
+            .arg("IDLE")
+            .arg(idle)
+            .arg("-")
+            .arg("+")
+            .arg(RECLAIM_BATCH)
+            .query_async(&mut self.conn)
+            .await?;
+        for entry in pending.ids {
+            let claimed: StreamClaimReply = redis::cmd("XCLAIM")
+                .arg(key)
+                .arg(group)
+                .arg(consumer)
+                .arg(idle)
+                .arg(&entry.id)
+                .query_async(&mut self.conn)
+                .await?;
+            // Empty when another consumer claimed it first, or it was deleted
+            for claimed in claimed.ids {
+                self.metrics.reclaimed.inc();
+                if entry.times_delivered >= self.config.max_deliveries {
+                    warn!(
+                        "Redis worker: giving up on {} after {} deliveries",
+                        claimed.id, entry.times_delivered
+                    );
+                    self.process(key, group, claimed, "poisoned").await?;
+                } else {
+                    info!("Redis worker: reclaimed {} from {}", claimed.id, entry.consumer);
+                    self.process(key, group, claimed, "completed").await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Handle one stream entry, then acknowledge it
+    async fn process(
+        &mut self,
+        key: &str,
+        group: &str,
+        entry: StreamId,
+        outcome: &'static str,
+    ) -> redis::RedisResult<()> {
+        let job: Vec<u8> = entry.get(JOB_FIELD).unwrap_or_default();
+        self.handle(&job, outcome).await?;
+        redis::cmd("XACK").arg(key).arg(group).arg(&entry.id).query_async::<()>(&mut self.conn).await
+    }
+
+    // Embed a job and write its result. `poisoned` jobs aren't embedded again;
+    // their reply says why. Returns an error only when Redis fails, in which
+    // case the job isn't done.
+    async fn handle(&mut self, raw: &[u8], outcome: &'static str) -> redis::RedisResult<()> {
+        let job = match serde_json::from_slice::<Job>(raw) {
+            Ok(job) => job,
+            Err(e) => {
+                // Keep what can be salvaged for the reply
+                let value = serde_json::from_slice::<serde_json::Value>(raw).unwrap_or_default();
+                let job = Job {
+                    id: value.get("id").cloned().unwrap_or_default(),
+                    texts: Vec::new(),
+                    reply_key: value.get("reply_key").and_then(|key| key.as_str()).map(str::to_string),
+                    input_type: None,
+                    dimensions: None,
+                };
+                let error = ItemError {
+                    message: format!("The job is not a JSON object with an id, texts and a reply_key: {}", e),
+                    code: "invalid_job",
+                };
+                return self.fail(job, error, "invalid").await;
+            }
+        };
+        if outcome == "poisoned" {
+            let error = ItemError {
+                message: format!("The job was abandoned after {} deliveries", self.config.max_deliveries),
+                code: "poisoned",
+            };
+            return self.fail(job, error, outcome).await;
+        }
+        if let Some(error) = self.check(&job) {
+            return self.fail(job, error, "invalid").await;
+        }
+
+        let input_type = job.input_type.or(Some(InputKind::Passage));
+        let mut delay = RETRY_DELAY;
+        let embedded = loop {
+            let texts = job.texts.clone();
+            let embedded = embed_texts(&self.state, input_type, job.dimensions, &self.scheduling, texts).await;
+            if !embedded.iter().any(|result| matches!(result, Err(error) if error.code == "circuit_open")) {
+                break embedded;
+            }
+            warn!("Redis worker: the model is being re-initialized; retrying the job in {:?}", delay);
+            tokio::time::sleep(delay).await;
+            delay = (delay * 2).min(MAX_RETRY_DELAY);
+        };
+        let precision = self.state.float_precision;
+        let data = embedded
+            .into_iter()
+            .enumerate()
+            .map(|(index, result)| match result {
+                Ok((embedding, _)) => ResultItem {
+                    index,
+                    embedding: Some(EmbeddingData::encode(embedding, &EncodingFormat::Float, precision)),
+                    error: None,
+                },
+                Err(error) => ResultItem {
+                    index,
+                    embedding: None,
+                    error: Some(error),
+                },
+            })
+            .collect();
+        let state = self.state.clone();
+        let result = JobResult {
+            id: job.id,
+            model: Some(&state.model_name),
+            data,
+            error: None,
+        };
+        self.reply(job.reply_key.as_deref(), &result).await?;
+        self.metrics.jobs.with_label_values(&[outcome]).inc();
+        Ok(())
+    }
+
+    // Why a job can't be embedded at all
+    fn check(&self, job: &Job) -> Option<ItemError> {
+        let invalid = |message: String| {
+            Some(ItemError {
+                message,
+                code: "invalid_job",
+            })
+        };
+        if job.reply_key.is_none() && self.config.results_stream.is_none() {
+            return invalid("The job has no reply_key".to_string());
+        }
+        if job.texts.is_empty() {
+            return invalid("texts cannot be an empty array".to_string());
+        }
+        if job.texts.len() > self.state.limits.max_inputs {
+            let limit = self.state.limits.max_inputs;
+            return invalid(format!("The job has {} texts, over the limit of {}", job.texts.len(), limit));
+        }
+        match job.dimensions {
+            Some(requested) if requested == 0 || requested > self.state.metadata.dimensions => invalid(format!(
+                "dimensions must be between 1 and {}, got {}",
+                self.state.metadata.dimensions, requested
+            )),
+            _ => None,
+        }
+    }
+
+    async fn fail(&mut self, job: Job, error: ItemError, outcome: &'static str) -> redis::RedisResult<()> {
+        warn!("Redis worker: job {} failed: {}", job.id, error.message);
+        self.metrics.jobs.with_label_values(&[outcome]).inc();
+        let result = JobResult {
+            id: job.id,
+            model: None,
+            data: Vec::new(),
+            error: Some(error),
+        };
+        // A job without anywhere to reply to is only logged
+        if job.reply_key.is_none() && self.config.results_stream.is_none() {
+            return Ok(());
+        }
+        self.reply(job.reply_key.as_deref(), &result).await
+    }
+
+    // Write a result to the job's reply key, or else to the results stream,
+    // expiring after the result TTL
+    async fn reply(&mut self, reply_key: Option<&str>, result: &JobResult<'_>) -> redis::RedisResult<()> {
+        let payload = serde_json::to_string(result).unwrap_or_default();
+        let ttl = self.config.result_ttl.as_secs().max(1);
+        match (reply_key, &self.config.results_stream) {
+            (Some(reply_key), _) => {
+                redis::cmd("SET").arg(reply_key).arg(payload).arg("EX").arg(ttl).query_async(&mut self.conn).await
+            }
+            (None, Some(stream)) => {
+                redis::pipe()
+                    .atomic()
+                    .cmd("XADD")
+                    .arg(stream)
+                    .arg("*")
+                    .arg("id")
+                    .arg(result.id.to_string())
+                    .arg("result")
+                    .arg(payload)
+                    .ignore()
+                    .cmd("EXPIRE")
+                    .arg(stream)
+                    .arg(ttl)
+                    .ignore()
+                    .query_async(&mut self.conn)
+                    .await
+            }
+            (None, None) => Ok(()),
+        }
+    }
+}
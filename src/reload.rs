@@ -0,0 +1,249 @@
+use std::collections::{BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+
+use anyhow::{bail, Context};
+use axum::{extract::State, http::StatusCode, Json};
+use serde::Serialize;
+use tracing::{error, info, warn};
+use tracing_subscriber::{reload, EnvFilter, Registry};
+
+use crate::error::ApiError;
+use crate::models::{self, ModelResolver};
+use crate::queue::TenantShares;
+use crate::quota::Quotas;
+use crate::{config, env_flag, AppState, Readiness, Settings, DEFAULT_LOG_FILTER};
+
+/// Handle for swapping the log filter of the running subscriber.
+pub type LogFilter = reload::Handle<EnvFilter, Registry>;
+
+// Settings a reload applies; changes to any other variable are ignored with
+// a warning until the next restart
+const RELOADABLE: &[&str] = &[
+    "SEMEMBED_MODEL_ALIASES",
+    "SEMEMBED_METRICS_TOKEN",
+    "SEMEMBED_TENANT_SHARES",
+    "SEMEMBED_DAILY_TOKEN_QUOTAS",
+    "SEMEMBED_MONTHLY_TOKEN_QUOTAS",
+    "SEMEMBED_READY_MAX_QUEUE_DEPTH",
+    "SEMEMBED_READY_MAX_P95_MS",
+    "SEMEMBED_READY_MAX_ERROR_RATE",
+    "SEMEMBED_READY_FAIL_DEGRADED",
+    "RUST_LOG",
+];
+
+/// The file named by `SEMEMBED_CONFIG_FILE`: `KEY=VALUE` lines whose values
+/// take precedence over the environment.
+pub struct ConfigFile {
+    path: PathBuf,
+    // The environment before the file was applied, which a setting falls
+    // back to once it is removed from the file
+    base: HashMap<String, String>,
+    // The values in effect; also serializes reloads
+    current: Mutex<HashMap<String, String>>,
+}
+
+impl ConfigFile {
+    /// Read `SEMEMBED_CONFIG_FILE`, if set, into the environment so startup
+    /// sees its values like any other variable. Runs before any other thread
+    /// is started, since it sets environment variables.
+    pub fn load() -> anyhow::Result<Option<Self>> {
+        let Some(path) = std::env::var_os("SEMEMBED_CONFIG_FILE").filter(|path| !path.is_empty()) else {
+            return Ok(None);
+        };
+        let path = PathBuf::from(path);
+        let base = settings_in(std::env::vars());
+        let entries = read(&path)?;
+        for (name, value) in &entries {
+            std::env::set_var(name, value);
+        }
+        let mut current = base.clone();
+        current.extend(entries);
+        Ok(Some(Self {
+            path,
+            base,
+            current: Mutex::new(current),
+        }))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+// The variables a configuration file may set
+fn is_setting(name: &str) -> bool {
+    name.starts_with("SEMEMBED_") || name == "RUST_LOG"
+}
+
+fn settings_in(vars: impl Iterator<Item = (String, String)>) -> HashMap<String, String> {
+    vars.filter(|(name, _)| is_setting(name)).collect()
+}
+
+// Parse the file: `KEY=VALUE` per line, `#` comments and blank lines skipped,
+// an optional `export ` prefix, and values optionally in matching quotes
+fn read(path: &Path) -> anyhow::Result<HashMap<String, String>> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read configuration file {}", path.display()))?;
+    let mut entries = HashMap::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let Some((name, value)) = line.split_once('=') else {
+            bail!("{}:{}: expected KEY=VALUE", path.display(), number + 1);
+        };
+        let name = name.trim();
+        if !is_setting(name) {
+            bail!("{}:{}: {} is not a semembed setting", path.display(), number + 1, name);
+        }
+        if name == "SEMEMBED_CONFIG_FILE" {
+            bail!("{}:{}: a configuration file can't name another", path.display(), number + 1);
+        }
+        if name != "RUST_LOG" && !config::is_known(name) {
+            config::warn_unknown("setting", name);
+        }
+        let value = value.trim();
+        let value = ['"', '\'']
+            .iter()
+            .find_map(|&quote| value.strip_prefix(quote)?.strip_suffix(quote))
+            .unwrap_or(value);
+        entries.insert(name.to_string(), value.to_string());
+    }
+    Ok(entries)
+}
+
+/// What a reload changed.
+#[derive(Debug, Serialize)]
+pub struct ReloadOutcome {
+    /// Configuration generation now in effect.
+    pub generation: u64,
+    /// Reloadable settings that changed.
+    pub changed: Vec<String>,
+    /// Settings that changed but need a restart, left as they were.
+    pub ignored: Vec<String>,
+}
+
+/// Re-read the configuration file and apply its reloadable settings. Every
+/// setting is checked before any is applied, so on error the previous
+/// configuration stays in effect as a whole.
+pub fn reload(state: &AppState) -> anyhow::Result<ReloadOutcome> {
+    let Some(file) = &state.config_file else {
+        bail!("no configuration file to reload; set SEMEMBED_CONFIG_FILE");
+    };
+    let mut current = file.current.lock().unwrap_or_else(PoisonError::into_inner);
+    let mut next = file.base.clone();
+    next.extend(read(&file.path)?);
+
+    let names: BTreeSet<&String> = current.keys().chain(next.keys()).collect();
+    let (mut changed, mut ignored) = (Vec::new(), Vec::new());
+    for name in names {
+        if current.get(name) == next.get(name) {
+            continue;
+        }
+        if RELOADABLE.contains(&name.as_str()) {
+            changed.push(name.clone());
+        } else {
+            ignored.push(name.clone());
+        }
+    }
+    for name in &ignored {
+        warn!("{} changed in {}, but only takes effect on restart", name, file.path.display());
+    }
+
+    let value = |name: &str| next.get(name).map(String::as_str).filter(|value| !value.trim().is_empty());
+    let mut problems = Vec::new();
+    for name in &changed {
+        if let Err(expected) = config::check_variable(name, next.get(name).map_or("", String::as_str)) {
+            problems.push(format!("{}: expected {}", name, expected));
+        }
+    }
+    if !problems.is_empty() {
+        bail!("invalid configuration: {}", problems.join("; "));
+    }
+
+    let previous = state.settings();
+    let aliases = models::parse_aliases(value("SEMEMBED_MODEL_ALIASES").unwrap_or_default())?;
+    let resolver = ModelResolver::new(previous.resolver.loaded().to_vec(), aliases)?;
+    let metrics_token = value("SEMEMBED_METRICS_TOKEN").map(str::to_string);
+    if metrics_token.is_none() && env_flag("SEMEMBED_PPROF")? {
+        bail!("SEMEMBED_METRICS_TOKEN can't be removed while SEMEMBED_PPROF is enabled");
+    }
+    let readiness = Readiness {
+        max_queue_depth: value("SEMEMBED_READY_MAX_QUEUE_DEPTH").map(|value| value.trim().parse()).transpose()?,
+        max_p95: value("SEMEMBED_READY_MAX_P95_MS")
+            .map(|value| value.trim().parse().map(Duration::from_millis))
+            .transpose()?,
+        max_error_rate: value("SEMEMBED_READY_MAX_ERROR_RATE").map(|value| value.trim().parse()).transpose()?,
+        // Already checked to be a boolean
+        fail_degraded: value("SEMEMBED_READY_FAIL_DEGRADED")
+            .is_some_and(|value| matches!(value.trim().to_ascii_lowercase().as_str(), "1" | "true" | "yes" | "on")),
+    };
+    let shares: TenantShares = value("SEMEMBED_TENANT_SHARES").unwrap_or_default().parse()?;
+    let quotas = Quotas {
+        daily: value("SEMEMBED_DAILY_TOKEN_QUOTAS").unwrap_or_default().parse()?,
+        monthly: value("SEMEMBED_MONTHLY_TOKEN_QUOTAS").unwrap_or_default().parse()?,
+    };
+    let filter = EnvFilter::try_new(value("RUST_LOG").unwrap_or(DEFAULT_LOG_FILTER))
+        .context("invalid RUST_LOG")?;
+
+    // Nothing below can fail but the log filter, so it goes first
+    if changed.iter().any(|name| name == "RUST_LOG") {
+        state.log_filter.reload(filter).context("failed to replace the log filter")?;
+    }
+    let generation = previous.generation + 1;
+    state.usage.set_quota_keys(quotas.tenants());
+    *state.settings.write().unwrap_or_else(PoisonError::into_inner) = Arc::new(Settings {
+        generation,
+        resolver,
+        metrics_token,
+        readiness,
+        quotas,
+    });
+    state.queue.set_shares(shares);
+    state.metrics.config_generation.set(generation as i64);
+    *current = next;
+
+    info!(
+        "Configuration generation {} loaded from {}{}",
+        generation,
+        file.path.display(),
+        if changed.is_empty() { String::new() } else { format!(" (changed: {})", changed.join(", ")) }
+    );
+    Ok(ReloadOutcome {
+        generation,
+        changed,
+        ignored,
+    })
+}
+
+/// Reload on every SIGHUP, logging failures.
+#[cfg(unix)]
+pub async fn on_sighup(state: Arc<AppState>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            error!("Failed to listen for SIGHUP: {}", e);
+            return;
+        }
+    };
+    while hangups.recv().await.is_some() {
+        info!("SIGHUP received, reloading configuration");
+        if let Err(e) = reload(&state) {
+            error!("Configuration reload failed, keeping the previous configuration: {:#}", e);
+        }
+    }
+}
+
+/// `POST /admin/reload`: reload as on SIGHUP and report what changed.
+pub(crate) async fn reload_handler(State(state): State<Arc<AppState>>) -> Result<Json<ReloadOutcome>, ApiError> {
+    reload(&state).map(Json).map_err(|e| {
+        error!("Configuration reload failed, keeping the previous configuration: {:#}", e);
+        ApiError::new(StatusCode::BAD_REQUEST, "invalid_request_error", format!("{:#}", e)).reason("reload_failed")
+    })
+}
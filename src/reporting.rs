@@ -0,0 +1,87 @@
+use std::borrow::Cow;
+use std::sync::Arc;
+
+use axum::{extract::Request, middleware::Next, response::Response};
+use sentry::protocol::{Event, Level};
+use sentry::{Hub, SentryFutureExt};
+
+use crate::error::ErrorReason;
+
+// Extras the handlers attach; anything else is dropped before sending
+const ALLOWED_EXTRAS: &[&str] = &["batch_size", "input_chars", "max_input_chars"];
+
+/// Initialize Sentry from `SENTRY_DSN`. Returns `None` (and installs nothing)
+/// when the DSN is unset; the guard flushes pending events when dropped.
+/// Called before tracing is set up so the tracing integration can be added.
+pub fn init() -> Option<sentry::ClientInitGuard> {
+    let dsn = std::env::var("SENTRY_DSN").ok().filter(|dsn| !dsn.is_empty())?;
+    let guard = sentry::init((
+        dsn,
+        sentry::ClientOptions {
+            release: sentry::release_name!(),
+            environment: std::env::var("SENTRY_ENVIRONMENT").ok().map(Cow::Owned),
+            send_default_pii: false,
+            before_send: Some(Arc::new(|event| Some(scrub(event)))),
+            ..Default::default()
+        },
+    ));
+    guard.is_enabled().then_some(guard)
+}
+
+// Events must never carry user text: drop request bodies, user info and any
+// extra we did not attach ourselves.
+fn scrub(mut event: Event<'static>) -> Event<'static> {
+    event.request = None;
+    event.user = None;
+    event.extra.retain(|key, _| ALLOWED_EXTRAS.contains(&key.as_str()));
+    event
+}
+
+/// Give each request its own Sentry scope and report `5xx` responses.
+///
+/// Handlers add tags (model, batch size) to the request's scope; panics are
+/// reported by the panic integration with the same scope.
+pub(crate) async fn capture_server_errors(req: Request, next: Next) -> Response {
+    let hub = Arc::new(Hub::new_from_top(Hub::current()));
+    let method = req.method().clone();
+    let path = req.uri().path().to_string();
+    hub.configure_scope(|scope| {
+        scope.set_tag("http.method", &method);
+        scope.set_tag("http.path", &path);
+        if let Some(request_id) = req.headers().get("x-request-id").and_then(|id| id.to_str().ok()) {
+            scope.set_tag("request_id", request_id);
+        }
+    });
+
+    let response = next.run(req).bind_hub(hub.clone()).await;
+
+    if response.status().is_server_error() {
+        let reason = response.extensions().get::<ErrorReason>().map_or("unknown", |reason| reason.0);
+        hub.capture_event(Event {
+            message: Some(format!("{} {} returned {}", method, path, response.status())),
+            level: Level::Error,
+            tags: [("reason".to_string(), reason.to_string())].into(),
+            ..Default::default()
+        });
+    }
+    response
+}
+
+/// Attach request metadata to the current request's scope. Only sizes are
+/// recorded, never the input text.
+pub(crate) fn tag_embedding_request(model: &str, texts: &[String]) {
+    sentry::configure_scope(|scope| {
+        scope.set_tag("model", model);
+        scope.set_extra("batch_size", texts.len().into());
+        scope.set_extra("input_chars", texts.iter().map(|t| t.chars().count()).sum::<usize>().into());
+        scope.set_extra(
+            "max_input_chars",
+            texts.iter().map(|t| t.chars().count()).max().unwrap_or(0).into(),
+        );
+    });
+}
+
+/// Whether a Sentry client is active.
+pub(crate) fn enabled() -> bool {
+    Hub::current().client().is_some_and(|client| client.is_enabled())
+}
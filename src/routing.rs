@@ -0,0 +1,291 @@
+//! Routing `/v1/embeddings` requests to a model by the language of their inputs.
+//!
+//! With `SEMEMBED_LANGUAGE_ROUTES` set, a request that omits `model` or names
+//! the routing alias (`SEMEMBED_LANGUAGE_ROUTING_ALIAS`, `auto` by default)
+//! has the language of each input detected (see [`crate::language`]). The
+//! whole batch goes to the model mapped to the language most of its inputs
+//! are in; with `split: true`, each input goes to its own language's model.
+//! Inputs whose language can't be told confidently, or isn't mapped, go to the
+//! default model. Every embedding reports the model that produced it.
+//!
+//! The mapped models are loaded next to the primary one, like the
+//! `/v1/compare` models, and under a model budget on demand.
+
+use std::collections::{BTreeMap, HashMap};
+
+use axum::http::StatusCode;
+use futures_util::future::try_join_all;
+
+use crate::compare::{self, Lookup};
+use crate::embedder::TokenCount;
+use crate::error::ApiError;
+use crate::extract::Scheduling;
+use crate::language::{Detection, LANGUAGES};
+use crate::models::{self, InputKind};
+use crate::{
+    admit, count_model_tokens, echoed, shorten, AppState, Echo, EmbeddingData, EmbeddingItem, EmbeddingObject,
+    EncodingFormat, Truncation,
+};
+
+/// Which model serves each language, for requests that leave the model to
+/// the server.
+pub(crate) struct LanguageRoutes {
+    routes: HashMap<String, String>,
+    alias: String,
+    // Detections less sure than this count as undetected
+    min_confidence: f32,
+}
+
+/// Parse `lang=model,...`, e.g. `en=BAAI/bge-small-en-v1.5`.
+pub(crate) fn parse_routes(value: &str) -> anyhow::Result<HashMap<String, String>> {
+    let mut routes = HashMap::new();
+    for entry in value.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+        let Some((language, model)) = entry.split_once('=') else {
+            anyhow::bail!("invalid language route {:?} (expected language=model)", entry);
+        };
+        let (language, model) = (language.trim().to_ascii_lowercase(), model.trim());
+        if !LANGUAGES.contains(&language.as_str()) {
+            anyhow::bail!("unknown language {:?} (detected: {})", language, LANGUAGES.join(", "));
+        }
+        if models::model_spec(model).is_none() {
+            anyhow::bail!("unknown model {:?} for language {:?}", model, language);
+        }
+        if routes.insert(language.clone(), model.to_string()).is_some() {
+            anyhow::bail!("language {:?} is routed more than once", language);
+        }
+    }
+    Ok(routes)
+}
+
+impl LanguageRoutes {
+    /// `None` unless `SEMEMBED_LANGUAGE_ROUTES` is set.
+    pub fn from_env() -> anyhow::Result<Option<Self>> {
+        let routes = parse_routes(&std::env::var("SEMEMBED_LANGUAGE_ROUTES").unwrap_or_default())?;
+        if routes.is_empty() {
+            return Ok(None);
+        }
+        let min_confidence: f32 = crate::env_parse("SEMEMBED_LANGUAGE_MIN_CONFIDENCE")?.unwrap_or(0.5);
+        if !(0.0..=1.0).contains(&min_confidence) {
+            anyhow::bail!("SEMEMBED_LANGUAGE_MIN_CONFIDENCE must be between 0 and 1, got {}", min_confidence);
+        }
+        Ok(Some(Self {
+            routes,
+            alias: std::env::var("SEMEMBED_LANGUAGE_ROUTING_ALIAS")
+                .ok()
+                .map(|alias| alias.trim().to_string())
+                .filter(|alias| !alias.is_empty())
+                .unwrap_or_else(|| "auto".to_string()),
+            min_confidence,
+        }))
+    }
+
+    /// The name a request gives as `model` to be routed.
+    pub fn alias(&self) -> &str {
+        &self.alias
+    }
+
+    /// Every model a language is routed to.
+    pub fn models(&self) -> impl Iterator<Item = &str> {
+        self.routes.values().map(String::as_str)
+    }
+
+    /// The languages routed to a model other than `default`.
+    pub fn routed(&self, default: &str) -> Vec<(&str, &str)> {
+        let mut routed: Vec<(&str, &str)> = self
+            .routes
+            .iter()
+            .filter(|(_, model)| *model != default)
+            .map(|(language, model)| (language.as_str(), model.as_str()))
+            .collect();
+        routed.sort();
+        routed
+    }
+
+    /// Whether a request for `model` is routed by language.
+    pub fn applies(&self, model: Option<&str>) -> bool {
+        model.is_none_or(|model| model == self.alias)
+    }
+
+    /// The model for each input: the one its language maps to with `split`,
+    /// otherwise the majority language's for all of them; `default` where the
+    /// language isn't known or routed.
+    pub fn route<'a>(&'a self, detections: &[Option<Detection>], default: &'a str, split: bool) -> Vec<&'a str> {
+        let languages: Vec<Option<&str>> = detections
+            .iter()
+            .map(|detection| {
+                detection
+                    .filter(|detection| detection.confidence >= self.min_confidence)
+                    .map(|detection| detection.language)
+            })
+            .collect();
+        let model = |language: Option<&str>| {
+            language.and_then(|language| self.routes.get(language)).map_or(default, String::as_str)
+        };
+        if split {
+            return languages.into_iter().map(model).collect();
+        }
+        // The most common language, the first to reach that count on a tie
+        let mut counts: HashMap<&str, usize> = HashMap::new();
+        let mut majority: Option<(&str, usize)> = None;
+        for language in languages.iter().flatten() {
+            let count = counts.entry(language).or_insert(0);
+            *count += 1;
+            if majority.is_none_or(|(_, most)| *count > most) {
+                majority = Some((language, *count));
+            }
+        }
+        vec![model(majority.map(|(language, _)| language)); detections.len()]
+    }
+}
+
+/// The request's options that apply to every model it is routed to.
+pub(crate) struct Options<'a> {
+    pub dimensions: Option<usize>,
+    pub task: Option<&'a str>,
+    pub input_type: Option<InputKind>,
+    pub truncate: Option<bool>,
+    pub encoding_format: &'a EncodingFormat,
+    pub precision: Option<u32>,
+    pub return_token_details: bool,
+}
+
+// One model's share of a routed request, tokenized and ready to embed
+struct Group<'a> {
+    name: &'a str,
+    target: compare::Target<'a>,
+    indices: Vec<usize>,
+    texts: Vec<String>,
+    counted: Vec<TokenCount>,
+}
+
+/// Embed each input with the model it was routed to: every model's inputs at
+/// once, each model with its own prefix. Returns the embeddings in input order
+/// and the tokens they took.
+pub(crate) async fn embed(
+    state: &AppState,
+    texts: Vec<String>,
+    models: &[&str],
+    echoes: Option<&[Echo]>,
+    options: &Options<'_>,
+    scheduling: &Scheduling,
+) -> Result<(Vec<EmbeddingItem>, usize), ApiError> {
+    let mut assigned: BTreeMap<&str, Vec<(usize, String)>> = BTreeMap::new();
+    for (index, (text, model)) in texts.into_iter().zip(models).enumerate() {
+        assigned.entry(model).or_default().push((index, text));
+    }
+
+    let mut groups = Vec::with_capacity(assigned.len());
+    for (name, inputs) in assigned {
+        let target = match compare::lookup(state, name).await.map_err(|err| err.param("model"))? {
+            Lookup::Found(target) => target,
+            Lookup::Loading => {
+                return Err(ApiError::new(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "service_unavailable",
+                    format!("The model `{}` is still loading; retry shortly", name),
+                )
+                .code("model_loading")
+                .param("model")
+                .retry_after(5))
+            }
+            Lookup::Missing => {
+                return Err(ApiError::new(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "service_unavailable",
+                    format!("The model `{}` inputs are routed to did not load", name),
+                )
+                .code("model_not_loaded")
+                .param("model"))
+            }
+        };
+        let (spec, dimensions, embedder) = target.model(state);
+        if let Some(requested) = options.dimensions.filter(|&requested| requested == 0 || requested > dimensions) {
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "invalid_request_error",
+                format!(
+                    "dimensions must be between 1 and {} for model {}, which some inputs are routed to; got {}",
+                    dimensions, name, requested
+                ),
+            )
+            .param("dimensions")
+            .reason("invalid_dimensions"));
+        }
+        let selection = spec.select(options.task, options.input_type).map_err(|e| {
+            ApiError::new(StatusCode::BAD_REQUEST, "invalid_request_error", e.to_string())
+                .param("task")
+                .reason("invalid_task")
+        })?;
+        let (indices, texts): (Vec<usize>, Vec<String>) = inputs.into_iter().unzip();
+        let texts = match selection.prefix {
+            Some(prefix) => texts.into_iter().map(|text| format!("{}{}", prefix, text)).collect(),
+            None => texts,
+        };
+        let max_length = embedder.max_length();
+        let (texts, counted) = count_model_tokens(embedder.clone(), texts).await?;
+        if let Some(position) = counted.iter().position(|count| options.truncate == Some(false) && count.truncated) {
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "invalid_request_error",
+                format!(
+                    "Input {} is longer than the maximum of {} tokens of {}, which it is routed to, and truncation \
+                     is disabled",
+                    indices[position], max_length, name
+                ),
+            )
+            .param("input")
+            .code("input_too_long")
+            .reason("input_too_long"));
+        }
+        groups.push(Group {
+            name,
+            target,
+            indices,
+            texts,
+            counted,
+        });
+    }
+
+    let token_count: usize = groups.iter().flat_map(|group| &group.counted).map(|count| count.tokens).sum();
+    admit(state, scheduling, token_count)?;
+    // A slot on every model before any of them embeds; the request already
+    // holds the default model's
+    let _model_slots = groups
+        .iter()
+        .filter(|group| !matches!(group.target, compare::Target::Primary))
+        .map(|group| state.model_limits.acquire(group.name))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let embedded = try_join_all(groups.into_iter().map(|group| async move {
+        let embeddings = group.target.embed(state, group.texts, scheduling).await?;
+        Ok::<_, ApiError>((group.name, group.indices, embeddings, group.counted))
+    }))
+    .await?;
+
+    let mut objects: Vec<EmbeddingObject> = Vec::with_capacity(models.len());
+    for (name, indices, embeddings, counted) in embedded {
+        for ((index, embedding), count) in indices.into_iter().zip(embeddings).zip(counted) {
+            let embedding = match options.dimensions {
+                Some(dimensions) if dimensions < embedding.len() => shorten(embedding, dimensions),
+                _ => embedding,
+            };
+            let (id, metadata) = echoed(echoes, index);
+            objects.push(EmbeddingObject {
+                object: "embedding".to_string(),
+                embedding: EmbeddingData::encode(embedding, options.encoding_format, options.precision),
+                index,
+                id,
+                metadata,
+                model: Some(name.to_string()),
+                detected: None,
+                tokens: options.return_token_details.then_some(count.tokens),
+                truncated: options.return_token_details.then_some(count.truncated),
+                truncation: Truncation::details(&count, options.return_token_details),
+                shape: None,
+                token_output: None,
+            });
+        }
+    }
+    objects.sort_by_key(|object| object.index);
+    Ok((objects.into_iter().map(EmbeddingItem::Embedding).collect(), token_count))
+}
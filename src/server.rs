@@ -0,0 +1,288 @@
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use axum::Router;
+use hyper::body::Incoming;
+use hyper::server::conn::http1;
+use hyper_util::rt::{TokioExecutor, TokioIo, TokioTimer};
+use hyper_util::server::conn::auto::Builder;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tower::Service;
+use tracing::{debug, error};
+
+/// A bound listener the API can be served on.
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl fmt::Display for Listener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Listener::Tcp(listener) => match listener.local_addr() {
+                Ok(addr) => write!(f, "{}", addr),
+                Err(_) => f.write_str("tcp (unknown address)"),
+            },
+            #[cfg(unix)]
+            Listener::Unix(listener) => match listener.local_addr() {
+                Ok(addr) => match addr.as_pathname() {
+                    Some(path) => write!(f, "unix:{}", path.display()),
+                    None => f.write_str("unix (unnamed)"),
+                },
+                Err(_) => f.write_str("unix (unknown address)"),
+            },
+        }
+    }
+}
+
+/// Connection-level settings applied to every accepted connection.
+///
+/// The defaults reproduce `axum::serve`: HTTP/1 only, no idle or header
+/// timeouts, hyper's default HTTP/2 stream limit.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ServerOptions {
+    pub tcp_nodelay: bool,
+    /// Close connections that have had no request in flight for this long.
+    pub idle_timeout: Option<Duration>,
+    /// Close HTTP/1 connections that do not send complete request headers in time.
+    pub header_read_timeout: Option<Duration>,
+    /// Maximum concurrent HTTP/2 streams per connection.
+    pub max_concurrent_streams: Option<u32>,
+    /// Accept HTTP/2 prior-knowledge (h2c) connections alongside HTTP/1.
+    pub h2c: bool,
+}
+
+/// Accept connections on `listener` forever, serving `app` on each.
+pub async fn serve(listener: Listener, app: Router, options: ServerOptions) -> io::Result<()> {
+    loop {
+        match &listener {
+            Listener::Tcp(tcp) => match tcp.accept().await {
+                Ok((stream, _)) => {
+                    if options.tcp_nodelay {
+                        if let Err(e) = stream.set_nodelay(true) {
+                            debug!("Failed to set TCP_NODELAY: {}", e);
+                        }
+                    }
+                    spawn_connection(stream, app.clone(), options);
+                }
+                Err(e) => accept_error(e).await,
+            },
+            #[cfg(unix)]
+            Listener::Unix(unix) => match unix.accept().await {
+                Ok((stream, _)) => spawn_connection(stream, app.clone(), options),
+                Err(e) => accept_error(e).await,
+            },
+        }
+    }
+}
+
+fn spawn_connection<I>(io: I, app: Router, options: ServerOptions)
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let activity = Arc::new(Activity::new());
+    let service = {
+        let activity = activity.clone();
+        hyper::service::service_fn(move |req: hyper::Request<Incoming>| {
+            let in_flight = activity.begin();
+            let mut app = app.clone();
+            async move {
+                let response = app.call(req.map(axum::body::Body::new)).await;
+                drop(in_flight);
+                response
+            }
+        })
+    };
+
+    tokio::spawn(async move {
+        let io = TokioIo::new(io);
+        let result = if options.h2c {
+            let mut builder = Builder::new(TokioExecutor::new());
+            if let Some(timeout) = options.header_read_timeout {
+                builder
+                    .http1()
+                    .timer(TokioTimer::new())
+                    .header_read_timeout(timeout);
+            }
+            builder.http2().max_concurrent_streams(options.max_concurrent_streams);
+            let conn = builder.serve_connection_with_upgrades(io, service);
+            until_idle(conn, |conn| conn.graceful_shutdown(), &activity, options.idle_timeout).await
+        } else {
+            // HTTP/1 only, matching axum::serve without its `http2` feature. The
+            // auto builder's `http1_only` is ignored for connections served with
+            // upgrades, so this takes hyper's HTTP/1 builder instead
+            let mut builder = http1::Builder::new();
+            if let Some(timeout) = options.header_read_timeout {
+                builder.timer(TokioTimer::new()).header_read_timeout(timeout);
+            }
+            let conn = builder.serve_connection(io, service).with_upgrades();
+            until_idle(conn, |conn| conn.graceful_shutdown(), &activity, options.idle_timeout)
+                .await
+                .map_err(Into::into)
+        };
+        if let Err(e) = result {
+            debug!("Connection closed with error: {}", e);
+        }
+    });
+}
+
+// Drive a connection to completion, shutting it down gracefully once it has
+// been idle for `idle_timeout`
+async fn until_idle<C: Future>(
+    conn: C,
+    shutdown: impl FnOnce(Pin<&mut C>),
+    activity: &Activity,
+    idle_timeout: Option<Duration>,
+) -> C::Output {
+    tokio::pin!(conn);
+    match idle_timeout {
+        None => conn.await,
+        Some(timeout) => {
+            tokio::select! {
+                result = conn.as_mut() => result,
+                _ = activity.idle_for(timeout) => {
+                    debug!("Closing connection idle for {:?}", timeout);
+                    shutdown(conn.as_mut());
+                    conn.await
+                }
+            }
+        }
+    }
+}
+
+// Tracks requests in flight on one connection, so the idle timeout never
+// fires while a (possibly slow) inference request is being handled.
+struct Activity {
+    in_flight: AtomicUsize,
+    last_active: Mutex<Instant>,
+}
+
+struct InFlight(Arc<Activity>);
+
+impl Activity {
+    fn new() -> Self {
+        Self {
+            in_flight: AtomicUsize::new(0),
+            last_active: Mutex::new(Instant::now()),
+        }
+    }
+
+    fn begin(self: &Arc<Self>) -> InFlight {
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        InFlight(self.clone())
+    }
+
+    fn last_active(&self) -> Instant {
+        *self.last_active.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // Resolves once no request has been in flight for `timeout`
+    async fn idle_for(&self, timeout: Duration) {
+        loop {
+            let deadline = self.last_active() + timeout;
+            tokio::time::sleep_until(deadline.into()).await;
+            if self.in_flight.load(Ordering::SeqCst) == 0 && self.last_active().elapsed() >= timeout {
+                return;
+            }
+            if self.in_flight.load(Ordering::SeqCst) > 0 {
+                tokio::time::sleep(timeout).await;
+            }
+        }
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        *self.0.last_active.lock().unwrap_or_else(|e| e.into_inner()) = Instant::now();
+        self.0.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// Accept errors are usually transient (e.g. EMFILE); back off briefly instead
+// of spinning or bringing the listener down.
+async fn accept_error(e: io::Error) {
+    error!("Failed to accept connection: {}", e);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, http::StatusCode, routing::get};
+    use hyper::client::conn::{http1, http2};
+    use tokio::net::TcpStream;
+
+    // Serve a one-route app on a local port with these options
+    async fn start(options: ServerOptions) -> std::net::SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = Router::new().route("/", get(|| async { "ok" }));
+        tokio::spawn(serve(Listener::Tcp(listener), app, options));
+        addr
+    }
+
+    fn request() -> hyper::Request<Body> {
+        hyper::Request::get("/").header("host", "localhost").body(Body::empty()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn closes_idle_connections() {
+        let addr = start(ServerOptions {
+            idle_timeout: Some(Duration::from_millis(200)),
+            ..ServerOptions::default()
+        })
+        .await;
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let (mut sender, connection) = http1::handshake(TokioIo::new(stream)).await.unwrap();
+        let connection = tokio::spawn(connection);
+        assert_eq!(sender.send_request(request()).await.unwrap().status(), StatusCode::OK);
+
+        let closed = tokio::time::timeout(Duration::from_secs(2), connection).await;
+        assert!(closed.is_ok(), "the idle connection was not closed");
+    }
+
+    #[tokio::test]
+    async fn keeps_idle_connections_by_default() {
+        let addr = start(ServerOptions::default()).await;
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let (mut sender, connection) = http1::handshake(TokioIo::new(stream)).await.unwrap();
+        let connection = tokio::spawn(connection);
+        assert_eq!(sender.send_request(request()).await.unwrap().status(), StatusCode::OK);
+
+        assert!(tokio::time::timeout(Duration::from_millis(500), connection).await.is_err());
+        assert_eq!(sender.send_request(request()).await.unwrap().status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn serves_h2c_when_enabled() {
+        let addr = start(ServerOptions {
+            h2c: true,
+            ..ServerOptions::default()
+        })
+        .await;
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let (mut sender, connection) = http2::handshake(TokioExecutor::new(), TokioIo::new(stream)).await.unwrap();
+        tokio::spawn(connection);
+        let response = sender.send_request(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.version(), hyper::Version::HTTP_2);
+    }
+
+    #[tokio::test]
+    async fn refuses_h2c_by_default() {
+        let addr = start(ServerOptions::default()).await;
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let (mut sender, connection) = http2::handshake(TokioExecutor::new(), TokioIo::new(stream)).await.unwrap();
+        tokio::spawn(connection);
+        assert!(sender.send_request(request()).await.is_err());
+    }
+}
@@ -0,0 +1,292 @@
+//! Shadow traffic: embedding a sample of requests with a candidate model too.
+//!
+//! With `SEMEMBED_SHADOW_MODEL` set, a `SEMEMBED_SHADOW_SAMPLE_RATE` share of
+//! `/v1/embeddings` requests is embedded again with that model once the
+//! primary response is ready, and the two are compared: latency, dimensions,
+//! and the cosine similarity of each primary vector with its shadow (after
+//! truncating the longer one when the dimensions differ). The client never
+//! waits for or sees the shadow. It runs at low priority on the queue, at
+//! most `SEMEMBED_SHADOW_MAX_IN_FLIGHT` batches at a time; a sample arriving
+//! while that many are running is skipped.
+
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use prometheus::{CounterVec, Histogram, HistogramOpts, HistogramVec, IntGaugeVec, Opts, Registry};
+use serde::Serialize;
+use tokio::sync::Semaphore;
+use tokio_util::sync::CancellationToken;
+use tracing::warn;
+
+use crate::embedder::{EmbedError, Embedder};
+use crate::models::{InputKind, ModelSpec};
+use crate::queue::{Priority, Tenant};
+use crate::AppState;
+
+/// Sampling and capacity of shadow traffic.
+#[derive(Debug, Clone, Copy)]
+pub struct ShadowConfig {
+    /// Share of requests shadowed, from 0.0 to 1.0.
+    pub sample_rate: f64,
+    /// Shadow batches running or waiting for the model at once.
+    pub max_in_flight: usize,
+}
+
+// Shadow metrics, registered next to the server's
+struct ShadowMetrics {
+    requests: CounterVec,
+    latency_delta: Histogram,
+    similarity: HistogramVec,
+}
+
+impl ShadowMetrics {
+    fn register(registry: &Registry, primary: (&str, usize), shadow: (&str, usize)) -> anyhow::Result<Self> {
+        let requests = CounterVec::new(
+            Opts::new(
+                "semembed_shadow_requests_total",
+                "Sampled requests by shadow outcome (compared, skipped at capacity, or failed)",
+            ),
+            &["outcome"],
+        )?;
+        registry.register(Box::new(requests.clone()))?;
+
+        // Shadow minus primary; negative when the candidate is faster
+        let latency_delta = Histogram::with_opts(
+            HistogramOpts::new(
+                "semembed_shadow_latency_delta_seconds",
+                "Shadow inference time minus the primary's, per sampled request",
+            )
+            .buckets(vec![-1.0, -0.25, -0.1, -0.05, -0.01, 0.0, 0.01, 0.05, 0.1, 0.25, 1.0]),
+        )?;
+        registry.register(Box::new(latency_delta.clone()))?;
+
+        let similarity = HistogramVec::new(
+            HistogramOpts::new(
+                "semembed_shadow_cosine_similarity",
+                "Cosine similarity of each primary embedding with its shadow, by whether one was truncated to compare",
+            )
+            .buckets(vec![0.0, 0.5, 0.7, 0.8, 0.9, 0.95, 0.98, 0.99, 0.995, 0.999, 1.0]),
+            &["truncated"],
+        )?;
+        registry.register(Box::new(similarity.clone()))?;
+
+        let models = IntGaugeVec::new(
+            Opts::new("semembed_shadow_model_info", "The primary and shadow models compared, with their dimensions"),
+            &["role", "model", "dimensions"],
+        )?;
+        registry.register(Box::new(models.clone()))?;
+        for (role, (model, dimensions)) in [("primary", primary), ("shadow", shadow)] {
+            models.with_label_values(&[role, model, &dimensions.to_string()]).set(1);
+        }
+
+        Ok(Self {
+            requests,
+            latency_delta,
+            similarity,
+        })
+    }
+}
+
+// One comparison, as a line of the dump file. Inputs themselves aren't
+// written, only how many there were.
+#[derive(Serialize)]
+struct Comparison<'a> {
+    timestamp: u64,
+    primary_model: &'a str,
+    shadow_model: &'a str,
+    inputs: usize,
+    primary_ms: f64,
+    shadow_ms: f64,
+    primary_dimensions: usize,
+    shadow_dimensions: usize,
+    truncated: bool,
+    similarities: Vec<f32>,
+}
+
+/// The candidate model and where its comparisons go.
+pub(crate) struct Shadow {
+    spec: &'static ModelSpec,
+    dimensions: usize,
+    primary: &'static str,
+    embedder: Arc<Embedder>,
+    config: ShadowConfig,
+    in_flight: Arc<Semaphore>,
+    // splitmix64 state, for the coin flip of each request
+    seed: AtomicU64,
+    dump: Option<Mutex<File>>,
+    metrics: ShadowMetrics,
+}
+
+impl Shadow {
+    pub fn new(
+        spec: &'static ModelSpec,
+        dimensions: usize,
+        embedder: Arc<Embedder>,
+        primary: (&'static str, usize),
+        config: ShadowConfig,
+        dump: Option<&Path>,
+        registry: &Registry,
+    ) -> anyhow::Result<Self> {
+        let dump = dump
+            .map(|path| File::options().create(true).append(true).open(path).map(Mutex::new))
+            .transpose()?;
+        Ok(Self {
+            spec,
+            dimensions,
+            primary: primary.0,
+            embedder,
+            config,
+            in_flight: Arc::new(Semaphore::new(config.max_in_flight)),
+            seed: AtomicU64::new(std::process::id() as u64),
+            dump,
+            metrics: ShadowMetrics::register(registry, primary, (spec.name, dimensions))?,
+        })
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.spec.name
+    }
+
+    pub fn spec(&self) -> &'static ModelSpec {
+        self.spec
+    }
+
+    pub fn dimensions(&self) -> usize {
+        self.dimensions
+    }
+
+    pub fn embedder(&self) -> &Arc<Embedder> {
+        &self.embedder
+    }
+
+    /// Whether to shadow the next request.
+    pub fn sampled(&self) -> bool {
+        self.config.sample_rate > 0.0 && self.random() < self.config.sample_rate
+    }
+
+    // Uniform in [0, 1)
+    fn random(&self) -> f64 {
+        let mut z = self.seed.fetch_add(0x9e37_79b9_7f4a_7c15, Ordering::Relaxed).wrapping_add(0x9e37_79b9_7f4a_7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^= z >> 31;
+        (z >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Embed `texts` (as given, before the primary's prefix) with the shadow
+    /// model in the background and compare the result with `primary`.
+    pub fn compare(
+        state: Arc<AppState>,
+        texts: Vec<String>,
+        input_type: Option<InputKind>,
+        primary: Vec<Vec<f32>>,
+        primary_elapsed: Duration,
+    ) {
+        let Some(shadow) = state.shadow.get() else {
+            return;
+        };
+        let Ok(permit) = shadow.in_flight.clone().try_acquire_owned() else {
+            shadow.metrics.requests.with_label_values(&["skipped"]).inc();
+            return;
+        };
+        tokio::spawn(async move {
+            let _permit = permit;
+            let Some(shadow) = state.shadow.get() else {
+                return;
+            };
+            let texts: Vec<String> = match shadow.spec.prefix(input_type) {
+                Some(prefix) => texts.into_iter().map(|text| format!("{}{}", prefix, text)).collect(),
+                None => texts,
+            };
+            match shadow.embed(&state, texts).await {
+                Ok((embeddings, elapsed)) => {
+                    shadow.metrics.requests.with_label_values(&["compared"]).inc();
+                    // Off the async workers: the dump is a plain file
+                    let state = state.clone();
+                    tokio::task::spawn_blocking(move || {
+                        if let Some(shadow) = state.shadow.get() {
+                            shadow.record(&primary, &embeddings, primary_elapsed, elapsed);
+                        }
+                    });
+                }
+                Err(e) => {
+                    shadow.metrics.requests.with_label_values(&["failed"]).inc();
+                    warn!("Shadow embedding with {} failed: {}", shadow.name(), e);
+                }
+            }
+        });
+    }
+
+    // One low-priority turn on the queue, so shadow work waits behind client
+    // requests and takes a worker's place while it runs
+    async fn embed(&self, state: &AppState, texts: Vec<String>) -> Result<(Vec<Vec<f32>>, Duration), EmbedError> {
+        let turn = state.queue.acquire(Priority::Low, &Tenant::system(), texts.len()).await;
+        let embedder = self.embedder.clone();
+        tokio::task::spawn_blocking(move || {
+            let _turn = turn;
+            let started = Instant::now();
+            let embeddings = embedder.embed(texts.iter().map(String::as_str).collect(), &CancellationToken::new())?;
+            Ok((embeddings, started.elapsed()))
+        })
+        .await
+        .unwrap_or_else(|e| Err(EmbedError::Inference(e.into())))
+    }
+
+    fn record(&self, primary: &[Vec<f32>], shadow: &[Vec<f32>], primary_elapsed: Duration, elapsed: Duration) {
+        self.metrics
+            .latency_delta
+            .observe(elapsed.as_secs_f64() - primary_elapsed.as_secs_f64());
+        let primary_dimensions = primary.first().map_or(0, Vec::len);
+        let truncated = primary_dimensions != self.dimensions;
+        let label = if truncated { "true" } else { "false" };
+        let similarities: Vec<f32> = primary
+            .iter()
+            .zip(shadow)
+            .map(|(primary, shadow)| cosine(primary, shadow))
+            .collect();
+        for similarity in &similarities {
+            self.metrics.similarity.with_label_values(&[label]).observe(f64::from(*similarity));
+        }
+
+        let Some(dump) = &self.dump else {
+            return;
+        };
+        let comparison = Comparison {
+            timestamp: SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs()),
+            primary_model: self.primary,
+            shadow_model: self.name(),
+            inputs: similarities.len(),
+            primary_ms: primary_elapsed.as_secs_f64() * 1000.0,
+            shadow_ms: elapsed.as_secs_f64() * 1000.0,
+            primary_dimensions,
+            shadow_dimensions: self.dimensions,
+            truncated,
+            similarities,
+        };
+        let Ok(mut line) = serde_json::to_vec(&comparison) else {
+            return;
+        };
+        line.push(b'\n');
+        if let Err(e) = dump.lock().unwrap_or_else(PoisonError::into_inner).write_all(&line) {
+            warn!("Couldn't write to the shadow dump: {}", e);
+        }
+    }
+}
+
+/// Cosine similarity over the leading dimensions both vectors have, so a
+/// Matryoshka model compares with a shorter one at the shorter length.
+pub(crate) fn cosine(a: &[f32], b: &[f32]) -> f32 {
+    let len = a.len().min(b.len());
+    let (a, b) = (&a[..len], &b[..len]);
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norms = a.iter().map(|x| x * x).sum::<f32>().sqrt() * b.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norms == 0.0 {
+        0.0
+    } else {
+        dot / norms
+    }
+}
@@ -0,0 +1,195 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use prometheus::Gauge;
+
+use crate::AppState;
+
+// How often the shed probability is adjusted
+const INTERVAL: Duration = Duration::from_secs(1);
+// Requests whose latency the controller looks at: short, so it sees the
+// effect of its last adjustment rather than the whole latency window
+const RECENT: Duration = Duration::from_secs(5);
+// Added to the probability every interval the target is exceeded
+const INCREASE: f64 = 0.05;
+// The probability is multiplied by this every interval the target is met
+const DECREASE: f64 = 0.5;
+// Below this the probability snaps to zero, so recovery ends
+const FLOOR: f64 = 0.01;
+// Some low-priority traffic always gets through, so recovery is observed
+const MAX_PROBABILITY: f64 = 0.95;
+
+/// Rejects a share of low-priority requests while recent p95 latency is above
+/// a target, so the requests that are admitted stay fast under overload.
+///
+/// The share grows additively every interval the target is exceeded and
+/// shrinks multiplicatively every interval it is met (AIMD).
+pub struct LoadShedder {
+    target: Duration,
+    // f64 bits
+    probability: AtomicU64,
+    // splitmix64 state, for the coin flip of each request
+    seed: AtomicU64,
+    gauge: Gauge,
+}
+
+impl LoadShedder {
+    pub fn new(target: Duration, gauge: Gauge) -> Self {
+        gauge.set(0.0);
+        Self {
+            target,
+            probability: AtomicU64::new(0f64.to_bits()),
+            seed: AtomicU64::new(std::process::id() as u64),
+            gauge,
+        }
+    }
+
+    pub fn target(&self) -> Duration {
+        self.target
+    }
+
+    /// Seconds a rejected client should wait: by then the probability has
+    /// been adjusted at least once.
+    pub fn retry_after(&self) -> u64 {
+        INTERVAL.as_secs().max(1)
+    }
+
+    /// Share of low-priority requests currently rejected, from 0.0 to 1.0.
+    pub fn probability(&self) -> f64 {
+        f64::from_bits(self.probability.load(Ordering::Relaxed))
+    }
+
+    /// Whether to reject the next low-priority request.
+    pub fn shed(&self) -> bool {
+        let probability = self.probability();
+        probability > 0.0 && self.random() < probability
+    }
+
+    /// One step of the controller, given the p95 of recent requests (`None`
+    /// when none finished recently, which counts as meeting the target).
+    pub fn adjust(&self, p95: Option<Duration>) {
+        let probability = self.probability();
+        let next = if p95.is_some_and(|p95| p95 > self.target) {
+            (probability + INCREASE).min(MAX_PROBABILITY)
+        } else {
+            Some(probability * DECREASE).filter(|&next| next >= FLOOR).unwrap_or(0.0)
+        };
+        self.probability.store(next.to_bits(), Ordering::Relaxed);
+        self.gauge.set(next);
+    }
+
+    // Uniform in [0, 1)
+    fn random(&self) -> f64 {
+        let mut z = self.seed.fetch_add(0x9e37_79b9_7f4a_7c15, Ordering::Relaxed).wrapping_add(0x9e37_79b9_7f4a_7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^= z >> 31;
+        (z >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// Adjust the shed probability from recent latency for as long as the
+/// service runs.
+pub async fn control(state: Arc<AppState>) {
+    let Some(shedder) = &state.shedder else {
+        return;
+    };
+    let mut interval = tokio::time::interval(INTERVAL);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        let [p95] = state.latency.quantiles_within(RECENT, [0.95]);
+        shedder.adjust(p95);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shedder(target_ms: u64) -> LoadShedder {
+        LoadShedder::new(Duration::from_millis(target_ms), Gauge::new("shed_probability", "test").unwrap())
+    }
+
+    #[test]
+    fn probability_rises_additively_and_falls_multiplicatively() {
+        let shedder = shedder(100);
+        let slow = Some(Duration::from_millis(300));
+        shedder.adjust(slow);
+        shedder.adjust(slow);
+        assert!((shedder.probability() - 2.0 * INCREASE).abs() < 1e-9);
+        (0..100).for_each(|_| shedder.adjust(slow));
+        assert_eq!(shedder.probability(), MAX_PROBABILITY);
+        assert_eq!(shedder.gauge.get(), MAX_PROBABILITY);
+
+        shedder.adjust(Some(Duration::from_millis(20)));
+        assert!((shedder.probability() - MAX_PROBABILITY * DECREASE).abs() < 1e-9);
+        // No recent requests counts as meeting the target; it ends at zero
+        (0..10).for_each(|_| shedder.adjust(None));
+        assert_eq!(shedder.probability(), 0.0);
+        assert_eq!(shedder.gauge.get(), 0.0);
+        assert!(!(0..1000).any(|_| shedder.shed()));
+    }
+
+    #[test]
+    fn sheds_about_the_probability() {
+        let shedder = shedder(100);
+        (0..6).for_each(|_| shedder.adjust(Some(Duration::from_secs(1))));
+        let shed = (0..10_000).filter(|_| shedder.shed()).count();
+        assert!((2_700..3_300).contains(&shed), "{} of 10000 shed at 0.3", shed);
+    }
+
+    // A backend that serves `CAPACITY` requests an interval at its base
+    // latency and slows down steeply past that
+    const CAPACITY: f64 = 150.0;
+
+    fn p95(admitted: usize) -> Duration {
+        let load = admitted as f64 / CAPACITY;
+        Duration::from_secs_f64(0.05 * load.max(1.0).powi(3))
+    }
+
+    // Offers `low` low-priority and `high` high-priority requests an
+    // interval; high priority is never shed. Returns the p95 latency seen.
+    fn interval(shedder: &LoadShedder, low: usize, high: usize) -> Duration {
+        let admitted = (0..low).filter(|_| !shedder.shed()).count() + high;
+        let p95 = p95(admitted);
+        shedder.adjust(Some(p95));
+        p95
+    }
+
+    #[test]
+    fn controller_converges_under_overload_and_recovers() {
+        let shedder = shedder(100);
+        // 250 requests an interval against a capacity of 150: latency meets
+        // the target at up to 188, so about 30% of the low-priority ones have
+        // to go
+        assert!(p95(250) > shedder.target());
+        assert!(p95(188) <= shedder.target() && p95(189) > shedder.target());
+        for _ in 0..40 {
+            interval(&shedder, 200, 50);
+        }
+        let mut latencies = Duration::ZERO;
+        let mut probabilities = 0.0;
+        for _ in 0..200 {
+            latencies += interval(&shedder, 200, 50);
+            probabilities += shedder.probability();
+        }
+        // AIMD saws around the share needed rather than settling on it,
+        // keeping latency near the target instead of where overload puts it
+        let mean = probabilities / 200.0;
+        assert!((0.15..0.6).contains(&mean), "mean shed probability {}", mean);
+        let latency = latencies / 200;
+        assert!(latency < shedder.target() * 3 / 2, "mean p95 {:?}", latency);
+        assert!(latency < p95(250) * 2 / 3, "mean p95 {:?} against {:?} unshed", latency, p95(250));
+        assert!(shedder.probability() < MAX_PROBABILITY);
+
+        // The overload ends: shedding stops within a few intervals
+        let recovered = (0..20).position(|_| {
+            interval(&shedder, 60, 20);
+            shedder.probability() == 0.0
+        });
+        assert!(recovered.is_some_and(|steps| steps < 10), "{:?}", recovered);
+        assert_eq!(shedder.gauge.get(), 0.0);
+    }
+}
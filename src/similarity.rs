@@ -0,0 +1,39 @@
+use semembed::Distance;
+
+use crate::cluster::{dot, normalize};
+
+// Rows and columns scored per tile, so each tile's vectors stay in cache
+const TILE: usize = 64;
+
+/// The score of every row of `a` against every row of `b` under `metric`:
+/// one row per vector of `a`, one column per vector of `b`. Cosine scores
+/// normalize copies of the vectors first; Euclidean scores are distances, so
+/// lower is closer.
+///
+/// The matrix is filled a tile at a time, so only the vectors and the output
+/// are held, never more than one tile of intermediate work.
+pub fn matrix(mut a: Vec<Vec<f32>>, mut b: Vec<Vec<f32>>, metric: Distance) -> Vec<Vec<f32>> {
+    if metric == Distance::Cosine {
+        a.iter_mut().chain(b.iter_mut()).for_each(|vector| normalize(vector));
+    }
+    let score = |x: &[f32], y: &[f32]| match metric {
+        // Rounding can put identical unit vectors just above 1
+        Distance::Cosine => dot(x, y).clamp(-1.0, 1.0),
+        Distance::Dot => dot(x, y),
+        Distance::Euclidean => x.iter().zip(y).map(|(p, q)| (p - q) * (p - q)).sum::<f32>().sqrt(),
+    };
+
+    let mut rows = vec![vec![0.0; b.len()]; a.len()];
+    for row_start in (0..a.len()).step_by(TILE) {
+        let row_end = (row_start + TILE).min(a.len());
+        for col_start in (0..b.len()).step_by(TILE) {
+            let col_end = (col_start + TILE).min(b.len());
+            for (i, row) in rows.iter_mut().enumerate().take(row_end).skip(row_start) {
+                for j in col_start..col_end {
+                    row[j] = score(&a[i], &b[j]);
+                }
+            }
+        }
+    }
+    rows
+}
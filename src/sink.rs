@@ -0,0 +1,116 @@
+//! The interface embeddings are written into vector stores through.
+//!
+//! The service drives `/v1/index` and batch-job outputs through a
+//! [`VectorSink`]; each store it supports implements the trait, and so can a
+//! client embedding semembed's pipeline in its own program.
+
+use std::fmt;
+
+use futures_util::future::BoxFuture;
+
+use crate::Distance;
+
+/// One embedded document, as written into a collection.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Point {
+    /// The document's key; writing the same id again replaces it.
+    pub id: String,
+    pub vector: Vec<f32>,
+    /// The text the vector was embedded from, for stores that keep it.
+    pub text: Option<String>,
+    /// The document's metadata as the client sent it, for stores that keep
+    /// it alongside the vector.
+    pub metadata: Option<serde_json::Value>,
+}
+
+/// What writing a point did to the collection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Written {
+    Inserted,
+    Updated,
+    /// Written, by a store that doesn't say whether the id existed.
+    Upserted,
+}
+
+impl Written {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Written::Inserted => "inserted",
+            Written::Updated => "updated",
+            Written::Upserted => "upserted",
+        }
+    }
+}
+
+/// A failed sink operation. Retryable errors (an unreachable store, a
+/// timeout) may succeed if the same call is made again; fatal ones (a missing
+/// collection, a vector of the wrong size) won't until something changes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SinkError {
+    retryable: bool,
+    /// Machine-readable cause, such as `collection_not_found`.
+    pub code: &'static str,
+    pub message: String,
+}
+
+impl SinkError {
+    pub fn retryable(code: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            retryable: true,
+            code,
+            message: message.into(),
+        }
+    }
+
+    pub fn fatal(code: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            retryable: false,
+            code,
+            message: message.into(),
+        }
+    }
+
+    pub fn is_retryable(&self) -> bool {
+        self.retryable
+    }
+}
+
+impl fmt::Display for SinkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for SinkError {}
+
+/// A vector store embeddings can be written into.
+///
+/// Methods return boxed futures so sinks can be picked at runtime and held as
+/// `Arc<dyn VectorSink>`.
+pub trait VectorSink: Send + Sync {
+    /// Short name of the store, such as `pgvector`; used as a metric label.
+    fn kind(&self) -> &'static str;
+
+    /// Create `collection` for vectors of `dimensions`, compared by
+    /// `distance`, unless it already exists.
+    fn create_collection<'a>(
+        &'a self,
+        collection: &'a str,
+        dimensions: usize,
+        distance: Distance,
+    ) -> BoxFuture<'a, Result<(), SinkError>>;
+
+    /// Write `points` into `collection`, replacing any with the same id.
+    ///
+    /// The outer error fails the whole batch; otherwise there is one result
+    /// per point, in order, so a point the store rejects doesn't fail the
+    /// others.
+    fn upsert<'a>(
+        &'a self,
+        collection: &'a str,
+        points: &'a [Point],
+    ) -> BoxFuture<'a, Result<Vec<Result<Written, SinkError>>, SinkError>>;
+
+    /// Check that the store is reachable.
+    fn healthcheck(&self) -> BoxFuture<'_, Result<(), SinkError>>;
+}
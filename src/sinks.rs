@@ -0,0 +1,384 @@
+//! Writing embeddings into vector stores through [`VectorSink`].
+//!
+//! `SEMEMBED_SINK` picks the store `POST /v1/index` and batch jobs with a
+//! `collection` write into; store-specific endpoints such as
+//! `/v1/index/pgvector` go through the same path. Points are upserted in
+//! batches of `SEMEMBED_SINK_BATCH_SIZE`, and a batch failing with a
+//! retryable error is tried again up to `SEMEMBED_SINK_RETRIES` times.
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use axum::{extract::State, http::StatusCode, Json};
+use prometheus::{CounterVec, HistogramOpts, HistogramVec, Opts, Registry};
+use semembed::{Point, SinkError, VectorSink, Written};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::bulk::embed_texts;
+use crate::error::ApiError;
+use crate::extract::{ApiJson, Scheduling};
+use crate::models::InputKind;
+use crate::{check_metadata, AppState, ItemError, Summary};
+
+// Wait before the first retry of a batch, doubled for each one after
+const RETRY_BACKOFF: Duration = Duration::from_millis(200);
+
+/// Batching and retries of sink writes.
+#[derive(Debug, Clone, Copy)]
+pub struct SinkOptions {
+    pub batch_size: usize,
+    pub retries: u32,
+}
+
+// Sink metrics, labeled by the store's kind
+struct SinkMetrics {
+    points: CounterVec,
+    errors: CounterVec,
+    batch_duration: HistogramVec,
+}
+
+impl SinkMetrics {
+    fn register(registry: &Registry) -> anyhow::Result<Self> {
+        let points = CounterVec::new(
+            Opts::new(
+                "semembed_sink_points_total",
+                "Points written to vector stores, by sink and outcome (written or failed)",
+            ),
+            &["sink", "outcome"],
+        )?;
+        registry.register(Box::new(points.clone()))?;
+
+        let errors = CounterVec::new(
+            Opts::new("semembed_sink_errors_total", "Failed sink batch writes, by sink and kind (retryable or fatal)"),
+            &["sink", "kind"],
+        )?;
+        registry.register(Box::new(errors.clone()))?;
+
+        let batch_duration = HistogramVec::new(
+            HistogramOpts::new("semembed_sink_batch_duration_seconds", "Time to upsert one batch of points, by sink"),
+            &["sink"],
+        )?;
+        registry.register(Box::new(batch_duration.clone()))?;
+
+        Ok(Self {
+            points,
+            errors,
+            batch_duration,
+        })
+    }
+}
+
+/// The configured sink, if any, and how writes to any sink are made.
+pub(crate) struct Sinks {
+    default: Option<Arc<dyn VectorSink>>,
+    options: SinkOptions,
+    metrics: SinkMetrics,
+}
+
+impl Sinks {
+    pub fn new(
+        default: Option<Arc<dyn VectorSink>>,
+        options: SinkOptions,
+        registry: &Registry,
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
+            default,
+            options,
+            metrics: SinkMetrics::register(registry)?,
+        })
+    }
+
+    /// The sink selected by `SEMEMBED_SINK`.
+    pub fn default_sink(&self) -> Option<&Arc<dyn VectorSink>> {
+        self.default.as_ref()
+    }
+
+    /// Points written per upsert call.
+    pub fn batch_size(&self) -> usize {
+        self.options.batch_size.max(1)
+    }
+
+    /// Upsert `points` into `collection` in batches, retrying the retryable
+    /// failures of a batch; one result per point, in order.
+    pub async fn upsert(
+        &self,
+        sink: &dyn VectorSink,
+        collection: &str,
+        points: &[Point],
+    ) -> Result<Vec<Result<Written, SinkError>>, SinkError> {
+        let kind = sink.kind();
+        let mut results = Vec::with_capacity(points.len());
+        for batch in points.chunks(self.batch_size()) {
+            let mut attempt = 0;
+            let written = loop {
+                let started = Instant::now();
+                let result = sink.upsert(collection, batch).await;
+                self.metrics
+                    .batch_duration
+                    .with_label_values(&[kind])
+                    .observe(started.elapsed().as_secs_f64());
+                match result {
+                    Ok(written) => break written,
+                    Err(e) if e.is_retryable() && attempt < self.options.retries => {
+                        self.metrics.errors.with_label_values(&[kind, "retryable"]).inc();
+                        warn!("{} sink: retrying a batch of {} points: {}", kind, batch.len(), e);
+                        tokio::time::sleep(RETRY_BACKOFF * 2u32.saturating_pow(attempt)).await;
+                        attempt += 1;
+                    }
+                    Err(e) => {
+                        let label = if e.is_retryable() { "retryable" } else { "fatal" };
+                        self.metrics.errors.with_label_values(&[kind, label]).inc();
+                        return Err(e);
+                    }
+                }
+            };
+            for result in &written {
+                let outcome = if result.is_ok() { "written" } else { "failed" };
+                self.metrics.points.with_label_values(&[kind, outcome]).inc();
+            }
+            results.extend(written);
+        }
+        Ok(results)
+    }
+}
+
+/// The response to a sink error that fails a whole request: `503` while the
+/// store is unreachable, `404` for a missing collection, `400` otherwise.
+pub(crate) fn api_error(sink: &dyn VectorSink, e: SinkError) -> ApiError {
+    if e.is_retryable() {
+        warn!("{} sink unavailable: {}", sink.kind(), e);
+        return ApiError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "service_unavailable",
+            format!("The {} store is unavailable; retry shortly", sink.kind()),
+        )
+        .retry_after(1)
+        .reason(e.code);
+    }
+    let status = if e.code == "collection_not_found" { StatusCode::NOT_FOUND } else { StatusCode::BAD_REQUEST };
+    ApiError::new(status, "invalid_request_error", e.message).code(e.code).reason(e.code)
+}
+
+fn invalid(param: &'static str, message: impl Into<String>) -> ApiError {
+    ApiError::new(StatusCode::BAD_REQUEST, "invalid_request_error", message)
+        .param(param)
+        .reason("invalid_index_request")
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct Document {
+    id: serde_json::Value,
+    text: String,
+    // Written with the point, untouched
+    #[serde(default)]
+    metadata: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct IndexRequest {
+    collection: String,
+    documents: Vec<Document>,
+    model: Option<String>,
+    // Documents are passages unless told otherwise
+    input_type: Option<InputKind>,
+    dimensions: Option<usize>,
+    // Create the collection first if it doesn't exist
+    #[serde(default)]
+    create: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct IndexResponse {
+    object: &'static str,
+    data: Vec<RowResult>,
+    model: String,
+    collection: String,
+    sink: &'static str,
+    summary: Summary,
+}
+
+// One document's point: whether it was inserted or updated, or why neither
+#[derive(Debug, Serialize)]
+pub(crate) struct RowResult {
+    index: usize,
+    id: serde_json::Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<ItemError>,
+}
+
+/// Documents embedded and written by [`index_documents`].
+pub(crate) struct Indexed {
+    pub data: Vec<RowResult>,
+    pub model: String,
+    pub summary: Summary,
+}
+
+/// What to embed and where to write it.
+pub(crate) struct IndexJob<'a> {
+    pub sink: &'a dyn VectorSink,
+    pub collection: &'a str,
+    pub documents: Vec<Document>,
+    pub model: Option<String>,
+    pub input_type: Option<InputKind>,
+    pub dimensions: Option<usize>,
+    pub create: bool,
+}
+
+// Ids are written as text, so both string and integer keys work
+pub(crate) fn id_text(id: &serde_json::Value) -> Option<String> {
+    match id {
+        serde_json::Value::String(id) => Some(id.clone()),
+        serde_json::Value::Number(id) => Some(id.to_string()),
+        _ => None,
+    }
+}
+
+/// Embed a request's documents and upsert them through its sink, reporting
+/// per document whether it was written.
+pub(crate) async fn index_documents(
+    state: &AppState,
+    scheduling: &Scheduling,
+    job: IndexJob<'_>,
+) -> Result<Indexed, ApiError> {
+    let settings = state.settings();
+    let Some(resolved) = settings.resolver.resolve(job.model.as_deref()) else {
+        return Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            "model_not_found",
+            format!("The model `{}` does not exist", job.model.as_deref().unwrap_or_default()),
+        ));
+    };
+    let model = resolved.canonical.to_string();
+    let dimensions = match job.dimensions {
+        Some(requested) if requested == 0 || requested > state.metadata.dimensions => {
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "invalid_request_error",
+                format!("dimensions must be between 1 and {}, got {}", state.metadata.dimensions, requested),
+            )
+            .param("dimensions")
+            .reason("invalid_dimensions"));
+        }
+        requested => requested.unwrap_or(state.metadata.dimensions),
+    };
+    if job.documents.is_empty() {
+        return Err(invalid("documents", "documents cannot be an empty array").reason("empty_input"));
+    }
+    if job.documents.len() > state.limits.max_inputs {
+        return Err(invalid(
+            "documents",
+            format!(
+                "At most {} documents are accepted per request, got {}",
+                state.limits.max_inputs,
+                job.documents.len()
+            ),
+        )
+        .reason("too_many_inputs"));
+    }
+    check_metadata(state, "documents", job.documents.iter().map(|document| &document.metadata).enumerate())?;
+    if job.create {
+        job.sink
+            .create_collection(job.collection, dimensions, state.metadata.distance)
+            .await
+            .map_err(|e| api_error(job.sink, e))?;
+    }
+
+    let input_type = job.input_type.or(Some(InputKind::Passage));
+    let texts: Vec<String> = job.documents.iter().map(|document| document.text.clone()).collect();
+    let embedded = embed_texts(state, input_type, job.dimensions, scheduling, texts).await;
+
+    // Documents that embedded, in order; the rest already have their result
+    let mut data: Vec<RowResult> = Vec::with_capacity(job.documents.len());
+    let mut points = Vec::new();
+    let mut pending = Vec::new();
+    for (index, (document, embedding)) in job.documents.into_iter().zip(embedded).enumerate() {
+        let error = match (id_text(&document.id), embedding) {
+            (None, _) => Some(ItemError {
+                message: "id must be a string or a number".to_string(),
+                code: "invalid_id",
+            }),
+            (Some(_), Err(error)) => Some(error),
+            (Some(id), Ok((vector, _))) => {
+                points.push(Point {
+                    id,
+                    vector,
+                    text: Some(document.text),
+                    metadata: document.metadata,
+                });
+                pending.push(data.len());
+                None
+            }
+        };
+        data.push(RowResult {
+            index,
+            id: document.id,
+            status: None,
+            error,
+        });
+    }
+
+    let written = state
+        .sinks
+        .upsert(job.sink, job.collection, &points)
+        .await
+        .map_err(|e| api_error(job.sink, e))?;
+    for (position, result) in pending.into_iter().zip(written) {
+        match result {
+            Ok(written) => data[position].status = Some(written.as_str()),
+            Err(e) => {
+                data[position].error = Some(ItemError {
+                    message: e.message,
+                    code: e.code,
+                })
+            }
+        }
+    }
+
+    let failed = data.iter().filter(|row| row.error.is_some()).count();
+    Ok(Indexed {
+        summary: Summary {
+            succeeded: data.len() - failed,
+            failed,
+        },
+        data,
+        model,
+    })
+}
+
+/// `POST /v1/index`: embed `documents` and upsert them into `collection` of
+/// the configured sink.
+pub(crate) async fn index(
+    State(state): State<Arc<AppState>>,
+    scheduling: Scheduling,
+    ApiJson(req): ApiJson<IndexRequest>,
+) -> Result<Json<IndexResponse>, ApiError> {
+    let sink = state.sinks.default_sink().cloned().ok_or_else(|| {
+        ApiError::new(StatusCode::NOT_FOUND, "invalid_request_error", "No vector store sink is configured")
+            .reason("not_found")
+    })?;
+    let indexed = index_documents(
+        &state,
+        &scheduling,
+        IndexJob {
+            sink: sink.as_ref(),
+            collection: &req.collection,
+            documents: req.documents,
+            model: req.model,
+            input_type: req.input_type,
+            dimensions: req.dimensions,
+            create: req.create,
+        },
+    )
+    .await?;
+    Ok(Json(IndexResponse {
+        object: "list",
+        data: indexed.data,
+        model: indexed.model,
+        collection: req.collection,
+        sink: sink.kind(),
+        summary: indexed.summary,
+    }))
+}
@@ -0,0 +1,186 @@
+//! Serving HTTP while the models load.
+//!
+//! The listeners are bound and served before any model is downloaded, so an
+//! orchestrator can tell a server that is starting from one that crashed.
+//! Until the API is built a [`Gate`] answers every request from the
+//! [`ModelStatus`]: while loading, `/health/live` is 200, `/health` is 503
+//! with `"status": "loading"`, `/metrics` works, and the rest of the API is a
+//! 503 `model_loading` with `Retry-After`. If loading fails, both health
+//! checks are 503 with the error and the API is a 500 `model_load_failed`;
+//! with `SEMEMBED_LOAD_FAILURE_EXIT_SECS` the process exits that long after,
+//! for a restart to recover. Once the API is built the gate passes every
+//! request to it.
+
+use std::sync::{Arc, OnceLock, PoisonError, RwLock};
+
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    Json, Router,
+};
+use prometheus::{IntGaugeVec, Opts, Registry};
+use tower::Service;
+
+use crate::error::ApiError;
+use crate::{constant_time_eq, encode_metrics};
+
+// Seconds a client is asked to wait while the models load
+const LOADING_RETRY_AFTER: u64 = 5;
+
+/// Where loading the models is.
+#[derive(Debug, Clone)]
+pub(crate) enum ModelState {
+    Loading,
+    Ready,
+    /// Loading failed, with this error.
+    Failed(String),
+}
+
+impl ModelState {
+    fn as_str(&self) -> &'static str {
+        match self {
+            ModelState::Loading => "loading",
+            ModelState::Ready => "ready",
+            ModelState::Failed(_) => "failed",
+        }
+    }
+}
+
+/// The state of loading, shared by the gates of every listener and exported
+/// as `semembed_model_state`.
+pub(crate) struct ModelStatus {
+    state: RwLock<ModelState>,
+    gauge: IntGaugeVec,
+    registry: Registry,
+    // Required for /metrics while loading, as it is once the API is built
+    metrics_token: Option<String>,
+}
+
+impl ModelStatus {
+    pub fn new(registry: &Registry, metrics_token: Option<String>) -> anyhow::Result<Arc<Self>> {
+        let gauge = IntGaugeVec::new(
+            Opts::new("semembed_model_state", "1 for the state model loading is in (loading, ready or failed)"),
+            &["state"],
+        )?;
+        registry.register(Box::new(gauge.clone()))?;
+        let status = Self {
+            state: RwLock::new(ModelState::Loading),
+            gauge,
+            registry: registry.clone(),
+            metrics_token,
+        };
+        status.export(&ModelState::Loading);
+        Ok(Arc::new(status))
+    }
+
+    pub fn get(&self) -> ModelState {
+        self.state.read().unwrap_or_else(PoisonError::into_inner).clone()
+    }
+
+    pub fn set(&self, state: ModelState) {
+        self.export(&state);
+        *self.state.write().unwrap_or_else(PoisonError::into_inner) = state;
+    }
+
+    fn export(&self, state: &ModelState) {
+        for name in ["loading", "ready", "failed"] {
+            self.gauge.with_label_values(&[name]).set(i64::from(name == state.as_str()));
+        }
+    }
+}
+
+/// What one set of listeners serves: the status until `open` is given the
+/// router to pass requests to.
+pub(crate) struct Gate {
+    status: Arc<ModelStatus>,
+    app: OnceLock<Router>,
+    // Whether this gate answers /metrics, which may live on its own port
+    metrics: bool,
+}
+
+impl Gate {
+    pub fn new(status: Arc<ModelStatus>, metrics: bool) -> Arc<Self> {
+        Arc::new(Self {
+            status,
+            app: OnceLock::new(),
+            metrics,
+        })
+    }
+
+    /// The router the listeners serve.
+    pub fn router(self: &Arc<Self>) -> Router {
+        Router::new().fallback(answer).with_state(self.clone())
+    }
+
+    /// Pass every request to `app` from now on.
+    pub fn open(&self, app: Router) {
+        let _ = self.app.set(app);
+    }
+}
+
+async fn answer(State(gate): State<Arc<Gate>>, req: Request) -> Response {
+    if let Some(app) = gate.app.get() {
+        return match app.clone().call(req).await {
+            Ok(response) => response,
+            Err(never) => match never {},
+        };
+    }
+
+    let state = gate.status.get();
+    match (req.uri().path(), &state) {
+        ("/health/live", ModelState::Failed(error)) | ("/health", ModelState::Failed(error)) => {
+            status_body(StatusCode::SERVICE_UNAVAILABLE, &state, Some(error))
+        }
+        ("/health/live", _) => Json(serde_json::json!({"status": "alive"})).into_response(),
+        ("/health", _) => status_body(StatusCode::SERVICE_UNAVAILABLE, &state, None),
+        ("/metrics", _) if gate.metrics => metrics(&gate.status, &req),
+        (_, ModelState::Failed(error)) => ApiError::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "server_error",
+            format!("The model failed to load: {}", error),
+        )
+        .code("model_load_failed")
+        .reason("model_load_failed")
+        .into_response(),
+        _ => ApiError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "service_unavailable",
+            "The model is still loading; retry shortly",
+        )
+        .code("model_loading")
+        .reason("model_loading")
+        .retry_after(LOADING_RETRY_AFTER)
+        .into_response(),
+    }
+}
+
+fn status_body(status: StatusCode, state: &ModelState, error: Option<&str>) -> Response {
+    let mut body = serde_json::json!({"status": state.as_str()});
+    if let Some(error) = error {
+        body["error"] = error.into();
+    }
+    (status, Json(body)).into_response()
+}
+
+fn metrics(status: &ModelStatus, req: &Request) -> Response {
+    if let Some(expected) = &status.metrics_token {
+        let provided = req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        if !provided.is_some_and(|token| constant_time_eq(token.as_bytes(), expected.as_bytes())) {
+            let mut response = ApiError::new(
+                StatusCode::UNAUTHORIZED,
+                "invalid_request_error",
+                "A valid bearer token is required to access this endpoint",
+            )
+            .reason("unauthorized")
+            .into_response();
+            response.headers_mut().insert(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+            return response;
+        }
+    }
+    encode_metrics(&status.registry).into_response()
+}
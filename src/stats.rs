@@ -0,0 +1,89 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+/// Runtime summary returned by `/stats`: a digest of `/metrics` plus recent
+/// latency quantiles. Fields are only ever added, never renamed or removed.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Stats {
+    pub uptime_secs: u64,
+    pub requests: RequestStats,
+    /// Error responses since startup, by `reason` as in `semembed_errors_total`,
+    /// summed over endpoints.
+    pub errors: BTreeMap<String, u64>,
+    pub latency: LatencyStats,
+    pub cache: CacheStats,
+    /// Inference calls currently waiting for the model.
+    pub queue_depth: usize,
+    /// Padded tokens per inference sub-batch currently allowed.
+    #[serde(default)]
+    pub batch_token_budget: usize,
+    /// Cores each embedder replica is pinned to; empty when unpinned.
+    #[serde(default)]
+    pub placement: Vec<PlacementStats>,
+    /// Share of low-priority requests currently rejected by load shedding;
+    /// always 0 when shedding is disabled.
+    #[serde(default)]
+    pub shed_probability: f64,
+    pub models: Vec<ModelStats>,
+    pub memory: MemoryStats,
+}
+
+/// Requests, as counted by `semembed_requests_total` over every endpoint
+/// but the health probes, `/metrics` and `/stats`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RequestStats {
+    pub total: u64,
+    /// Requests that finished within the last `window_secs`.
+    pub recent: u64,
+    pub window_secs: u64,
+}
+
+/// Request latency quantiles over the recent window, in milliseconds; `None`
+/// when no request finished within it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LatencyStats {
+    pub p50_ms: Option<f64>,
+    pub p95_ms: Option<f64>,
+    pub p99_ms: Option<f64>,
+}
+
+/// The embedding cache since startup.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CacheStats {
+    pub enabled: bool,
+    pub entries: u64,
+    pub hits: u64,
+    pub misses: u64,
+    /// `hits / (hits + misses)`; `None` before the first lookup.
+    pub hit_rate: Option<f64>,
+}
+
+/// A model this instance serves.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ModelStats {
+    pub id: String,
+    pub dimensions: usize,
+}
+
+/// Process memory; `None` where the platform doesn't report it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MemoryStats {
+    pub resident_bytes: Option<u64>,
+    /// `SEMEMBED_MAX_RSS_BYTES` in bytes, when set.
+    #[serde(default)]
+    pub limit_bytes: Option<u64>,
+    /// Whether embedding requests are being rejected for being over the limit.
+    #[serde(default)]
+    pub overloaded: bool,
+}
+
+/// CPU placement of one embedder replica.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PlacementStats {
+    pub replica: usize,
+    /// Cores as a list of cores and ranges, e.g. `0-7,16-23`.
+    pub cores: String,
+    /// NUMA node of the cores, when placed with `SEMEMBED_NUMA_AWARE`.
+    pub numa_node: Option<usize>,
+}
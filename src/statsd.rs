@@ -0,0 +1,366 @@
+//! DogStatsD export of the Prometheus registry, for deployments on Datadog.
+//!
+//! Every `SEMEMBED_STATSD_FLUSH_SECS` the registry is read and what changed is
+//! sent over UDP to `SEMEMBED_STATSD_ADDR`, packed into datagrams of at most
+//! [`MAX_DATAGRAM`] bytes, so the traffic depends on the number of series and
+//! not on request rate. `/metrics` keeps working alongside.
+//!
+//! Names drop the `semembed_` prefix and a counter's `_total` and are put under
+//! `SEMEMBED_STATSD_PREFIX` (`semembed.requests` for
+//! `semembed_requests_total`); labels become `label:value` tags. Counters are
+//! sent as the increase since the last flush (`c`), gauges as their value
+//! (`g`), and histograms as distributions (`d`): each observation since the
+//! last flush is sent as the upper bound of its bucket, the precision
+//! Prometheus' own quantiles have. Past [`MAX_SAMPLES`] observations in a
+//! flush, a histogram's samples are thinned and sent with the matching sample
+//! rate so Datadog counts them back up.
+
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::io;
+use std::time::Duration;
+
+use prometheus::proto::{MetricFamily, MetricType};
+use prometheus::{CounterVec, Opts, Registry};
+use tokio::net::UdpSocket;
+use tracing::{info, warn};
+
+use crate::env_parse;
+
+// Fits a datagram in a 1500-byte Ethernet frame, as Datadog recommends
+const MAX_DATAGRAM: usize = 1432;
+// Observations of one histogram series sent per flush
+const MAX_SAMPLES: u64 = 256;
+// Values packed into one distribution line, keeping it within a datagram
+const PACKED_VALUES: usize = 64;
+
+/// Where DogStatsD metrics go, and how they're named.
+#[derive(Debug, Clone)]
+pub struct StatsdConfig {
+    addr: String,
+    prefix: String,
+    // Sent with every metric, e.g. `env:prod`
+    tags: Vec<String>,
+    flush: Duration,
+}
+
+impl StatsdConfig {
+    /// `None` unless `SEMEMBED_STATSD_ADDR` is set.
+    pub fn from_env() -> anyhow::Result<Option<Self>> {
+        let Some(addr) = non_empty("SEMEMBED_STATSD_ADDR") else {
+            return Ok(None);
+        };
+        Ok(Some(Self {
+            addr,
+            prefix: non_empty("SEMEMBED_STATSD_PREFIX").unwrap_or_else(|| "semembed".to_string()),
+            tags: std::env::var("SEMEMBED_STATSD_TAGS")
+                .unwrap_or_default()
+                .split(',')
+                .map(str::trim)
+                .filter(|tag| !tag.is_empty())
+                .map(sanitize)
+                .collect(),
+            flush: Duration::from_secs(env_parse::<u64>("SEMEMBED_STATSD_FLUSH_SECS")?.unwrap_or(10).max(1)),
+        }))
+    }
+}
+
+fn non_empty(name: &str) -> Option<String> {
+    std::env::var(name).ok().map(|value| value.trim().to_string()).filter(|value| !value.is_empty())
+}
+
+/// Sends what changed in a registry to a DogStatsD agent.
+pub struct StatsdExporter {
+    config: StatsdConfig,
+    socket: UdpSocket,
+    registry: Registry,
+    // Each counter and histogram bucket's cumulative value at the last flush
+    previous: HashMap<String, f64>,
+    datagrams: CounterVec,
+}
+
+impl StatsdExporter {
+    pub async fn new(config: StatsdConfig, registry: Registry) -> anyhow::Result<Self> {
+        let target = tokio::net::lookup_host(&config.addr)
+            .await?
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("SEMEMBED_STATSD_ADDR {:?} doesn't resolve", config.addr))?;
+        let local = if target.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+        let socket = UdpSocket::bind(local).await?;
+        socket.connect(target).await?;
+        let datagrams = CounterVec::new(
+            Opts::new("semembed_statsd_datagrams_total", "DogStatsD datagrams, by outcome (sent or failed)"),
+            &["outcome"],
+        )?;
+        registry.register(Box::new(datagrams.clone()))?;
+        info!("Sending DogStatsD metrics to {} every {:?}", target, config.flush);
+        Ok(Self {
+            config,
+            socket,
+            registry,
+            previous: HashMap::new(),
+            datagrams,
+        })
+    }
+
+    /// Flush every interval, for as long as the server runs.
+    pub async fn run(mut self) -> io::Result<()> {
+        let mut interval = tokio::time::interval(self.config.flush);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            let lines = self.lines(&self.registry.gather());
+            self.send(&lines).await;
+        }
+    }
+
+    // One DogStatsD line per changed series
+    fn lines(&mut self, families: &[MetricFamily]) -> Vec<String> {
+        let mut lines = Vec::new();
+        for family in families {
+            let name = family.get_name();
+            let short = name.strip_prefix("semembed_").unwrap_or(name);
+            for metric in family.get_metric() {
+                let mut tags = self.config.tags.clone();
+                tags.extend(
+                    metric
+                        .get_label()
+                        .iter()
+                        .map(|label| sanitize(&format!("{}:{}", label.get_name(), label.get_value()))),
+                );
+                let key = |suffix: &str| format!("{}{{{}}}{}", name, tags.join(","), suffix);
+                match family.get_field_type() {
+                    MetricType::COUNTER => {
+                        let stat = format!("{}.{}", self.config.prefix, short.strip_suffix("_total").unwrap_or(short));
+                        let delta = self.delta(key(""), metric.get_counter().get_value());
+                        if delta > 0.0 {
+                            lines.push(line(&stat, &format_value(delta), "c", None, &tags));
+                        }
+                    }
+                    MetricType::GAUGE => {
+                        let stat = format!("{}.{}", self.config.prefix, short);
+                        lines.push(line(&stat, &format_value(metric.get_gauge().get_value()), "g", None, &tags));
+                    }
+                    MetricType::HISTOGRAM => {
+                        let stat = format!("{}.{}", self.config.prefix, short);
+                        let histogram = metric.get_histogram();
+                        // The +Inf bucket's observations count as the largest bound
+                        let mut samples: Vec<(f64, u64)> = Vec::new();
+                        let mut below = 0.0;
+                        for bucket in histogram.get_bucket() {
+                            let bound = bucket.get_upper_bound();
+                            let cumulative = self.delta(key(&bound.to_string()), bucket.get_cumulative_count() as f64);
+                            samples.push((bound, (cumulative - below).max(0.0) as u64));
+                            below = cumulative.max(below);
+                        }
+                        let total = self.delta(key("+Inf"), histogram.get_sample_count() as f64);
+                        if let Some(last) = samples.last_mut() {
+                            last.1 += (total - below).max(0.0) as u64;
+                        }
+                        if let Some((values, rate)) = distribution(&samples) {
+                            for values in values.chunks(PACKED_VALUES) {
+                                lines.push(line(&stat, &values.join(":"), "d", rate, &tags));
+                            }
+                        }
+                    }
+                    // Not registered by semembed
+                    MetricType::SUMMARY | MetricType::UNTYPED => {}
+                }
+            }
+        }
+        lines
+    }
+
+    // The increase of a cumulative value since the last flush; all of it after
+    // a reset
+    fn delta(&mut self, key: String, value: f64) -> f64 {
+        let previous = self.previous.insert(key, value).unwrap_or(0.0);
+        if value >= previous {
+            value - previous
+        } else {
+            value
+        }
+    }
+
+    // Pack the lines into datagrams and send them; failures are counted and
+    // logged once per flush
+    async fn send(&self, lines: &[String]) {
+        let mut failed = None;
+        for datagram in pack(lines) {
+            match self.socket.send(datagram.as_bytes()).await {
+                Ok(_) => self.datagrams.with_label_values(&["sent"]).inc(),
+                Err(e) => {
+                    self.datagrams.with_label_values(&["failed"]).inc();
+                    failed.get_or_insert(e);
+                }
+            }
+        }
+        if let Some(e) = failed {
+            warn!("Failed to send DogStatsD metrics: {}", e);
+        }
+    }
+}
+
+// `stat:value|type|@rate|#tags`
+fn line(stat: &str, value: &str, kind: &str, rate: Option<f64>, tags: &[String]) -> String {
+    let mut line = format!("{}:{}|{}", stat, value, kind);
+    if let Some(rate) = rate {
+        let _ = write!(line, "|@{}", format_value(rate));
+    }
+    if !tags.is_empty() {
+        let _ = write!(line, "|#{}", tags.join(","));
+    }
+    line
+}
+
+// The observations as values for DogStatsD 1.1 packing (`v1:v2:...`),
+// thinned to MAX_SAMPLES with the sample rate that makes up for it
+fn distribution(samples: &[(f64, u64)]) -> Option<(Vec<String>, Option<f64>)> {
+    let total: u64 = samples.iter().map(|&(_, count)| count).sum();
+    if total == 0 {
+        return None;
+    }
+    let rate = (total > MAX_SAMPLES).then(|| MAX_SAMPLES as f64 / total as f64);
+    let values: Vec<String> = samples
+        .iter()
+        .flat_map(|&(bound, count)| {
+            let kept = match rate {
+                Some(rate) => (count as f64 * rate).round() as usize,
+                None => count as usize,
+            };
+            std::iter::repeat_n(format_value(bound), kept)
+        })
+        .collect();
+    (!values.is_empty()).then_some((values, rate))
+}
+
+// Whole numbers without a fraction, the rest as Rust prints them
+fn format_value(value: f64) -> String {
+    if value.fract() == 0.0 && value.abs() < 1e15 {
+        format!("{}", value as i64)
+    } else {
+        format!("{}", value)
+    }
+}
+
+// Characters that would break the line format, replaced
+fn sanitize(tag: &str) -> String {
+    tag.chars().map(|c| if matches!(c, ',' | '|' | '#' | '\n' | '\r') { '_' } else { c }).collect()
+}
+
+// Lines joined by newlines into datagrams of at most MAX_DATAGRAM bytes; a
+// longer line goes alone
+fn pack(lines: &[String]) -> Vec<String> {
+    let mut datagrams = Vec::new();
+    let mut current = String::new();
+    for line in lines {
+        if !current.is_empty() && current.len() + 1 + line.len() > MAX_DATAGRAM {
+            datagrams.push(std::mem::take(&mut current));
+        }
+        if !current.is_empty() {
+            current.push('\n');
+        }
+        current.push_str(line);
+    }
+    if !current.is_empty() {
+        datagrams.push(current);
+    }
+    datagrams
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prometheus::{Counter, HistogramOpts, HistogramVec, IntGauge};
+
+    async fn flush(exporter: &mut StatsdExporter, agent: &UdpSocket) -> Vec<String> {
+        let lines = exporter.lines(&exporter.registry.gather());
+        exporter.send(&lines).await;
+        let mut received = Vec::new();
+        let mut buffer = [0u8; 2048];
+        for _ in pack(&lines) {
+            let len = agent.recv(&mut buffer).await.unwrap();
+            received.extend(String::from_utf8_lossy(&buffer[..len]).lines().map(str::to_string));
+        }
+        received
+    }
+
+    #[tokio::test]
+    async fn metrics_reach_the_agent_in_dogstatsd_format() {
+        let agent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let registry = Registry::new();
+        let requests = Counter::new("semembed_requests_total", "requests").unwrap();
+        let depth = IntGauge::new("semembed_queue_depth", "depth").unwrap();
+        let latency = HistogramVec::new(
+            HistogramOpts::new("semembed_request_duration_seconds", "latency").buckets(vec![0.1, 1.0]),
+            &["endpoint"],
+        )
+        .unwrap();
+        registry.register(Box::new(requests.clone())).unwrap();
+        registry.register(Box::new(depth.clone())).unwrap();
+        registry.register(Box::new(latency.clone())).unwrap();
+        let config = StatsdConfig {
+            addr: agent.local_addr().unwrap().to_string(),
+            prefix: "semembed".to_string(),
+            tags: vec!["env:test".to_string()],
+            flush: Duration::from_secs(10),
+        };
+        let mut exporter = StatsdExporter::new(config, registry).await.unwrap();
+
+        requests.inc_by(3.0);
+        depth.set(7);
+        let embeddings = latency.with_label_values(&["/v1/embeddings"]);
+        embeddings.observe(0.05);
+        embeddings.observe(0.5);
+        embeddings.observe(5.0);
+        let mut lines = flush(&mut exporter, &agent).await;
+        lines.sort();
+        assert_eq!(
+            lines,
+            [
+                "semembed.queue_depth:7|g|#env:test",
+                "semembed.request_duration_seconds:0.1:1:1|d|#env:test,endpoint:/v1/embeddings",
+                "semembed.requests:3|c|#env:test",
+            ]
+        );
+
+        // Counters send what they gained since, and histograms only new
+        // observations; the first flush's datagram counts too
+        requests.inc();
+        let mut lines = flush(&mut exporter, &agent).await;
+        lines.sort();
+        assert_eq!(
+            lines,
+            [
+                "semembed.queue_depth:7|g|#env:test",
+                "semembed.requests:1|c|#env:test",
+                "semembed.statsd_datagrams:1|c|#env:test,outcome:sent",
+            ]
+        );
+    }
+
+    #[test]
+    fn busy_histograms_are_thinned_with_a_sample_rate() {
+        let (values, rate) = distribution(&[(0.1, 768), (1.0, 256)]).unwrap();
+        assert_eq!(rate, Some(0.25));
+        assert_eq!(values.iter().filter(|value| *value == "0.1").count(), 192);
+        assert_eq!(values.iter().filter(|value| *value == "1").count(), 64);
+        assert_eq!(line("a.b", "1:2", "d", rate, &[]), "a.b:1:2|d|@0.25");
+        assert!(distribution(&[(0.1, 0)]).is_none());
+    }
+
+    #[test]
+    fn lines_are_packed_into_bounded_datagrams() {
+        let lines: Vec<String> = (0..100).map(|n| format!("semembed.metric_{}:1|c|#env:test", n)).collect();
+        let datagrams = pack(&lines);
+        assert!(datagrams.len() > 1);
+        assert!(datagrams.iter().all(|datagram| datagram.len() <= MAX_DATAGRAM));
+        assert_eq!(datagrams.join("\n").lines().count(), 100);
+        // A line too long for a datagram still goes, alone
+        let long = "x".repeat(MAX_DATAGRAM + 1);
+        assert_eq!(pack(&["a:1|c".to_string(), long.clone()]), ["a:1|c".to_string(), long]);
+        assert_eq!(sanitize("model:a,b|c#d"), "model:a_b_c_d");
+        assert_eq!(format_value(2.0), "2");
+        assert_eq!(format_value(0.25), "0.25");
+    }
+}
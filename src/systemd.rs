@@ -0,0 +1,118 @@
+//! systemd integration: socket activation (`sd_listen_fds`) and readiness
+//! notification (`sd_notify`). Both are no-ops when not running under systemd.
+
+use anyhow::{bail, Context};
+#[cfg(unix)]
+use std::os::fd::{FromRawFd, RawFd};
+
+#[cfg(unix)]
+use socket2::{Socket, Type};
+use tracing::{debug, warn};
+
+use crate::server::Listener;
+
+#[cfg(unix)]
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// Listeners passed in by systemd socket activation, or `None` when the
+/// process was not socket-activated.
+///
+/// Follows the `sd_listen_fds(3)` protocol: `LISTEN_PID` must match our pid and
+/// `LISTEN_FDS` sockets start at fd 3. The variables are removed afterwards so
+/// child processes do not try to adopt the same descriptors.
+#[cfg(unix)]
+pub fn listeners() -> anyhow::Result<Option<Vec<Listener>>> {
+    let Ok(pid) = std::env::var("LISTEN_PID") else {
+        return Ok(None);
+    };
+    if pid.trim().parse::<u32>().ok() != Some(std::process::id()) {
+        debug!("LISTEN_PID={} is not for this process, ignoring socket activation", pid);
+        return Ok(None);
+    }
+    let count = std::env::var("LISTEN_FDS")
+        .context("LISTEN_PID is set but LISTEN_FDS is missing")?
+        .trim()
+        .parse::<RawFd>()
+        .context("invalid LISTEN_FDS")?;
+
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_FDNAMES");
+
+    if count < 1 {
+        bail!("socket activation passed no file descriptors (LISTEN_FDS={})", count);
+    }
+    (SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count)
+        .map(|fd| adopt(fd).with_context(|| format!("cannot use socket-activated fd {}", fd)))
+        .collect::<anyhow::Result<Vec<_>>>()
+        .map(Some)
+}
+
+#[cfg(not(unix))]
+pub fn listeners() -> anyhow::Result<Option<Vec<Listener>>> {
+    Ok(None)
+}
+
+#[cfg(unix)]
+fn adopt(fd: RawFd) -> anyhow::Result<Listener> {
+    // SAFETY: systemd hands these descriptors to this process, and nothing else
+    // in the process has taken ownership of them.
+    let socket = unsafe { Socket::from_raw_fd(fd) };
+    if socket.r#type()? != Type::STREAM {
+        bail!("only stream sockets are supported");
+    }
+    // Passed descriptors keep whatever flags systemd set; tokio needs non-blocking
+    socket.set_nonblocking(true)?;
+    socket.set_cloexec(true)?;
+
+    let local = socket.local_addr()?;
+    if local.as_socket().is_some() {
+        let listener: std::net::TcpListener = socket.into();
+        Ok(Listener::Tcp(tokio::net::TcpListener::from_std(listener)?))
+    } else if local.is_unix() {
+        let listener: std::os::unix::net::UnixListener = socket.into();
+        Ok(Listener::Unix(tokio::net::UnixListener::from_std(listener)?))
+    } else {
+        bail!("unsupported socket family")
+    }
+}
+
+/// Tell systemd the service is ready (`READY=1`), if `NOTIFY_SOCKET` is set.
+pub fn notify_ready() {
+    if let Err(e) = notify("READY=1") {
+        warn!("Failed to notify systemd of readiness: {}", e);
+    }
+}
+
+#[cfg(unix)]
+fn notify(state: &str) -> anyhow::Result<()> {
+    use std::os::unix::net::UnixDatagram;
+
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return Ok(());
+    };
+    let socket = UnixDatagram::unbound()?;
+    let path = path.to_string_lossy();
+    match path.strip_prefix('@') {
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        Some(name) => {
+            #[cfg(target_os = "android")]
+            use std::os::android::net::SocketAddrExt;
+            #[cfg(target_os = "linux")]
+            use std::os::linux::net::SocketAddrExt;
+
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name.as_bytes())?;
+            socket.send_to_addr(state.as_bytes(), &addr)?;
+        }
+        _ => {
+            socket.send_to(state.as_bytes(), path.as_ref())?;
+        }
+    }
+    debug!("Sent {} to systemd", state);
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn notify(_state: &str) -> anyhow::Result<()> {
+    Ok(())
+}
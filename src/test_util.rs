@@ -0,0 +1,332 @@
+//! An in-process stand-in for semembed, for integration tests of services
+//! that call it (`--features test-util`).
+//!
+//! [`spawn_test_server`] serves `POST /v1/embeddings` and `GET /health` on an
+//! ephemeral local port without loading a model: each input embeds to a
+//! deterministic unit vector derived from its text, so equal inputs get equal
+//! vectors across runs. Every embedding request is recorded for assertions,
+//! and failures or latency can be injected to exercise client retry paths.
+//!
+//! ```no_run
+//! # async fn example() -> std::io::Result<()> {
+//! use semembed::test_util::{spawn_test_server, TestConfig};
+//!
+//! let server = spawn_test_server(TestConfig::default()).await?;
+//! server.fail_next(2);
+//! // ... point the client under test at `server.base_url` ...
+//! assert_eq!(server.requests().len(), 3);
+//! server.shutdown().await;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+
+use axum::{
+    extract::State,
+    http::{header, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use base64::Engine;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+
+/// Settings of a [`TestServer`].
+#[derive(Debug, Clone)]
+pub struct TestConfig {
+    /// Model named in responses and `/health`.
+    pub model: String,
+    /// Length of the vectors returned when a request doesn't ask for fewer.
+    pub dimensions: usize,
+    /// Delay before every embedding response.
+    pub latency: Option<Duration>,
+}
+
+impl Default for TestConfig {
+    fn default() -> Self {
+        Self {
+            model: "BAAI/bge-small-en-v1.5".to_string(),
+            dimensions: 384,
+            latency: None,
+        }
+    }
+}
+
+/// An embedding request the test server received.
+#[derive(Debug, Clone)]
+pub struct RecordedRequest {
+    /// Value of the `Authorization` header, if any.
+    pub authorization: Option<String>,
+    /// The request body as sent.
+    pub body: Value,
+    /// The inputs, as one string each.
+    pub inputs: Vec<String>,
+    /// Status the server answered with.
+    pub status: u16,
+}
+
+#[derive(Default)]
+struct Faults {
+    failures: usize,
+    status: Option<StatusCode>,
+    latency: Option<Duration>,
+}
+
+struct Mock {
+    model: String,
+    dimensions: usize,
+    faults: Mutex<Faults>,
+    requests: Mutex<Vec<RecordedRequest>>,
+}
+
+/// Stops a [`TestServer`]; dropping it stops the server too.
+pub struct ShutdownHandle {
+    signal: Option<oneshot::Sender<()>>,
+    task: Option<JoinHandle<()>>,
+}
+
+impl ShutdownHandle {
+    /// Stop accepting connections and wait for the server to finish.
+    pub async fn shutdown(mut self) {
+        if let Some(signal) = self.signal.take() {
+            let _ = signal.send(());
+        }
+        if let Some(task) = self.task.take() {
+            let _ = task.await;
+        }
+    }
+}
+
+impl Drop for ShutdownHandle {
+    fn drop(&mut self) {
+        if let Some(signal) = self.signal.take() {
+            let _ = signal.send(());
+        }
+    }
+}
+
+/// A running test server. It stops when [`shutdown`](Self::shutdown) is
+/// awaited or when it is dropped.
+pub struct TestServer {
+    /// `http://127.0.0.1:<port>`, without a trailing slash.
+    pub base_url: String,
+    pub shutdown_handle: ShutdownHandle,
+    addr: SocketAddr,
+    mock: Arc<Mock>,
+}
+
+impl TestServer {
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Embedding requests received so far, in order.
+    pub fn requests(&self) -> Vec<RecordedRequest> {
+        self.mock.requests.lock().unwrap_or_else(PoisonError::into_inner).clone()
+    }
+
+    /// Forget the requests received so far.
+    pub fn clear_requests(&self) {
+        self.mock.requests.lock().unwrap_or_else(PoisonError::into_inner).clear();
+    }
+
+    /// Answer the next `count` embedding requests with `503` and
+    /// `Retry-After: 1`, then succeed again.
+    pub fn fail_next(&self, count: usize) {
+        self.fail_next_with(count, StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    /// Answer the next `count` embedding requests with `status`, then succeed
+    /// again.
+    pub fn fail_next_with(&self, count: usize, status: StatusCode) {
+        let mut faults = self.mock.faults.lock().unwrap_or_else(PoisonError::into_inner);
+        faults.failures = count;
+        faults.status = Some(status);
+    }
+
+    /// Delay every embedding response by `latency`, or stop delaying them.
+    pub fn set_latency(&self, latency: Option<Duration>) {
+        self.mock.faults.lock().unwrap_or_else(PoisonError::into_inner).latency = latency;
+    }
+
+    /// The vector the server returns for `text` at `dimensions`.
+    pub fn embedding(&self, text: &str, dimensions: usize) -> Vec<f32> {
+        embed(text, dimensions)
+    }
+
+    /// Stop the server and wait for it to finish.
+    pub async fn shutdown(self) {
+        self.shutdown_handle.shutdown().await;
+    }
+}
+
+/// Start a test server on an ephemeral port of the loopback interface.
+pub async fn spawn_test_server(config: TestConfig) -> std::io::Result<TestServer> {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    let mock = Arc::new(Mock {
+        model: config.model,
+        dimensions: config.dimensions.max(1),
+        faults: Mutex::new(Faults {
+            latency: config.latency,
+            ..Faults::default()
+        }),
+        requests: Mutex::new(Vec::new()),
+    });
+    let app = Router::new()
+        .route("/v1/embeddings", post(embeddings))
+        .route("/health", get(health))
+        .with_state(mock.clone());
+
+    let (signal, stopped) = oneshot::channel();
+    let task = tokio::spawn(async move {
+        let _ = axum::serve(listener, app)
+            .with_graceful_shutdown(async {
+                let _ = stopped.await;
+            })
+            .await;
+    });
+    Ok(TestServer {
+        base_url: format!("http://{}", addr),
+        shutdown_handle: ShutdownHandle {
+            signal: Some(signal),
+            task: Some(task),
+        },
+        addr,
+        mock,
+    })
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Input {
+    One(String),
+    Many(Vec<String>),
+}
+
+#[derive(Deserialize)]
+struct Request {
+    input: Input,
+    model: Option<String>,
+    dimensions: Option<usize>,
+    encoding_format: Option<String>,
+}
+
+async fn health(State(mock): State<Arc<Mock>>) -> Json<Value> {
+    Json(json!({ "status": "ok", "model": mock.model, "dimensions": mock.dimensions }))
+}
+
+async fn embeddings(
+    State(mock): State<Arc<Mock>>,
+    headers: axum::http::HeaderMap,
+    Json(body): Json<Value>,
+) -> Response {
+    let (latency, failure) = {
+        let mut faults = mock.faults.lock().unwrap_or_else(PoisonError::into_inner);
+        let failure = (faults.failures > 0).then(|| {
+            faults.failures -= 1;
+            faults.status.unwrap_or(StatusCode::SERVICE_UNAVAILABLE)
+        });
+        (faults.latency, failure)
+    };
+    if let Some(latency) = latency {
+        tokio::time::sleep(latency).await;
+    }
+
+    let request = serde_json::from_value::<Request>(body.clone());
+    let inputs = match &request {
+        Ok(Request { input: Input::One(text), .. }) => vec![text.clone()],
+        Ok(Request { input: Input::Many(texts), .. }) => texts.clone(),
+        Err(_) => Vec::new(),
+    };
+    let response = match (failure, request) {
+        (Some(status), _) => error(status, "injected failure"),
+        (None, Err(e)) => error(StatusCode::BAD_REQUEST, &e.to_string()),
+        (None, Ok(request)) => respond(&mock, request, &inputs),
+    };
+
+    mock.requests.lock().unwrap_or_else(PoisonError::into_inner).push(RecordedRequest {
+        authorization: headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string),
+        body,
+        inputs,
+        status: response.status().as_u16(),
+    });
+    response
+}
+
+fn respond(mock: &Mock, request: Request, inputs: &[String]) -> Response {
+    if inputs.is_empty() {
+        return error(StatusCode::BAD_REQUEST, "input must not be empty");
+    }
+    let dimensions = request.dimensions.unwrap_or(mock.dimensions).clamp(1, mock.dimensions);
+    let base64 = match request.encoding_format.as_deref() {
+        None | Some("float") => false,
+        Some("base64") => true,
+        Some(other) => return error(StatusCode::BAD_REQUEST, &format!("unsupported encoding_format {:?}", other)),
+    };
+    let data: Vec<Value> = inputs
+        .iter()
+        .enumerate()
+        .map(|(index, text)| {
+            let vector = embed(text, dimensions);
+            let embedding = if base64 {
+                let bytes: Vec<u8> = vector.iter().flat_map(|value| value.to_le_bytes()).collect();
+                json!(base64::engine::general_purpose::STANDARD.encode(bytes))
+            } else {
+                json!(vector)
+            };
+            json!({ "object": "embedding", "embedding": embedding, "index": index })
+        })
+        .collect();
+    // About four characters per token, as a stand-in for a tokenizer
+    let tokens: usize = inputs.iter().map(|text| text.len().div_ceil(4).max(1)).sum();
+    Json(json!({
+        "object": "list",
+        "data": data,
+        "model": request.model.unwrap_or_else(|| mock.model.clone()),
+        "usage": { "prompt_tokens": tokens, "total_tokens": tokens },
+    }))
+    .into_response()
+}
+
+// An error in semembed's OpenAI-compatible shape
+fn error(status: StatusCode, message: &str) -> Response {
+    let error_type = if status.is_client_error() { "invalid_request_error" } else { "server_error" };
+    let mut response = (
+        status,
+        Json(json!({ "error": { "message": message, "type": error_type, "param": null, "code": null } })),
+    )
+        .into_response();
+    if status == StatusCode::SERVICE_UNAVAILABLE || status == StatusCode::TOO_MANY_REQUESTS {
+        response.headers_mut().insert(header::RETRY_AFTER, HeaderValue::from(1));
+    }
+    response
+}
+
+// A unit vector seeded by the text's hash
+fn embed(text: &str, dimensions: usize) -> Vec<f32> {
+    let mut seed = u64::from_le_bytes(hmac_sha256::Hash::hash(text.as_bytes())[..8].try_into().unwrap());
+    let mut vector: Vec<f32> = (0..dimensions)
+        .map(|_| {
+            // splitmix64
+            seed = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+            let mut z = seed;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+            z ^= z >> 31;
+            (z >> 40) as f32 / (1u64 << 24) as f32 * 2.0 - 1.0
+        })
+        .collect();
+    let norm = vector.iter().map(|value| value * value).sum::<f32>().sqrt().max(f32::EPSILON);
+    vector.iter_mut().for_each(|value| *value /= norm);
+    vector
+}
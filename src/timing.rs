@@ -0,0 +1,132 @@
+//! `Server-Timing`: where a request's time went.
+//!
+//! With `SEMEMBED_SERVER_TIMING=true`, or on requests sending
+//! `X-Debug-Timing`, responses carry a `Server-Timing` header breaking the
+//! request down into queueing, tokenization, inference and response encoding,
+//! and the request's span gets the same numbers. Phases are measured with
+//! [`Measure`] guards taken where the work happens; without timing, taking
+//! one is a single branch and no clock read.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+
+use axum::{
+    extract::{Request, State},
+    http::HeaderValue,
+    middleware::Next,
+    response::Response,
+};
+
+use crate::AppState;
+
+/// A part of handling a request, as named in `Server-Timing`.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Phase {
+    /// Waiting for turns on the inference queue.
+    Queue,
+    Tokenize,
+    /// Running the model.
+    Infer,
+    /// Building and serializing the response body.
+    Encode,
+}
+
+impl Phase {
+    const ALL: [Phase; 4] = [Phase::Queue, Phase::Tokenize, Phase::Infer, Phase::Encode];
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Phase::Queue => "queue",
+            Phase::Tokenize => "tokenize",
+            Phase::Infer => "infer",
+            Phase::Encode => "encode",
+        }
+    }
+
+    // The request span's field for the phase
+    fn field(self) -> &'static str {
+        match self {
+            Phase::Queue => "queue_ms",
+            Phase::Tokenize => "tokenize_ms",
+            Phase::Infer => "infer_ms",
+            Phase::Encode => "encode_ms",
+        }
+    }
+}
+
+/// Time spent in each phase of one request, summed over its batches.
+#[derive(Debug, Default)]
+pub(crate) struct Timing {
+    nanos: [AtomicU64; 4],
+    // When the handler started on the response; encoding lasts until the
+    // body is serialized, after the handler has returned
+    encoding: OnceLock<Instant>,
+}
+
+impl Timing {
+    /// Mark the start of encoding the response.
+    pub fn encoding(&self) {
+        let _ = self.encoding.set(Instant::now());
+    }
+
+    fn add(&self, phase: Phase, elapsed: Duration) {
+        self.nanos[phase as usize].fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    fn millis(&self, phase: Phase) -> f64 {
+        self.nanos[phase as usize].load(Ordering::Relaxed) as f64 / 1e6
+    }
+}
+
+/// Measures a phase from when it was taken until it is dropped; does nothing
+/// for requests without timing.
+#[must_use]
+pub(crate) struct Measure(Option<(Arc<Timing>, Phase, Instant)>);
+
+impl Measure {
+    pub fn start(timing: Option<&Arc<Timing>>, phase: Phase) -> Self {
+        Self(timing.map(|timing| (timing.clone(), phase, Instant::now())))
+    }
+}
+
+impl Drop for Measure {
+    fn drop(&mut self) {
+        if let Some((timing, phase, started)) = &self.0 {
+            timing.add(*phase, started.elapsed());
+        }
+    }
+}
+
+/// Middleware timing requests when `SEMEMBED_SERVER_TIMING` is on or the
+/// client sends `X-Debug-Timing`; handlers find the [`Timing`] in the
+/// request's extensions (through `Scheduling`).
+pub(crate) async fn server_timing(State(state): State<Arc<AppState>>, mut req: Request, next: Next) -> Response {
+    if !state.server_timing && !req.headers().contains_key("x-debug-timing") {
+        return next.run(req).await;
+    }
+    let timing = Arc::new(Timing::default());
+    req.extensions_mut().insert(timing.clone());
+    let started = Instant::now();
+    let mut response = next.run(req).await;
+    if let Some(encoding) = timing.encoding.get() {
+        timing.add(Phase::Encode, encoding.elapsed());
+    }
+    let total = started.elapsed().as_secs_f64() * 1000.0;
+
+    let span = tracing::Span::current();
+    let mut entries = Vec::with_capacity(Phase::ALL.len() + 1);
+    for phase in Phase::ALL {
+        let millis = timing.millis(phase);
+        span.record(phase.field(), millis);
+        if millis > 0.0 {
+            entries.push(format!("{};dur={:.1}", phase.as_str(), millis));
+        }
+    }
+    span.record("total_ms", total);
+    entries.push(format!("total;dur={:.1}", total));
+    if let Ok(value) = HeaderValue::from_str(&entries.join(", ")) {
+        response.headers_mut().append("server-timing", value);
+    }
+    response
+}
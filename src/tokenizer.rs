@@ -0,0 +1,285 @@
+//! `GET /v1/models/{model}/tokenizer`: how a model tokenizes, so client-side
+//! chunkers can count tokens exactly as semembed does.
+//!
+//! The description covers the tokenizer's type, vocabulary, special tokens and
+//! the truncation and padding inference uses, with the prefixes and
+//! instruction semembed adds to inputs and the tokens they take. The complete
+//! `tokenizer.json` is served at `/v1/models/{model}/tokenizer.json` when
+//! `SEMEMBED_TOKENIZER_JSON` is on, since it runs to megabytes. Both carry a
+//! strong `ETag` of their content, and a matching `If-None-Match` gets `304`.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, PoisonError};
+
+use axum::{
+    body::Bytes,
+    extract::{Path, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use hmac_sha256::Hash;
+use serde::Serialize;
+use tokenizers::{ModelWrapper, PaddingParams, Tokenizer, TruncationParams};
+
+use crate::cacheable::not_modified;
+use crate::compare::{self, Lookup};
+use crate::error::{ApiError, ErrorResponse};
+use crate::models::{self, InputKind};
+use crate::AppState;
+
+/// Serialized `tokenizer.json` files, by model, with their ETags; a model's
+/// tokenizer never changes while it is served.
+#[derive(Default)]
+pub(crate) struct TokenizerFiles {
+    files: Mutex<HashMap<&'static str, Arc<(String, Bytes)>>>,
+}
+
+#[derive(Debug, Serialize)]
+struct TokenizerInfo {
+    object: &'static str,
+    model: &'static str,
+    // `wordpiece`, `bpe`, `unigram` or `wordlevel`
+    #[serde(rename = "type")]
+    kind: &'static str,
+    // Added tokens included
+    vocab_size: usize,
+    special_tokens: Vec<SpecialToken>,
+    // Longest input in tokens, special tokens included; the rest is truncated
+    max_tokens: usize,
+    // Special tokens every input gets, e.g. `[CLS]` and `[SEP]`
+    special_tokens_per_input: usize,
+    truncation: Option<TruncationParams>,
+    padding: Option<PaddingParams>,
+    default_input_type: &'static str,
+    prefixes: Prefixes,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tasks: Vec<TaskPrefix>,
+    instruction: Instruction,
+    // Where the full tokenizer.json is, when it is served
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tokenizer_json: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct SpecialToken {
+    id: u32,
+    content: String,
+}
+
+#[derive(Debug, Serialize)]
+struct Prefixes {
+    query: Option<Prefix>,
+    passage: Option<Prefix>,
+}
+
+#[derive(Debug, Serialize)]
+struct Prefix {
+    text: &'static str,
+    // What it adds to an input's token count
+    tokens: usize,
+}
+
+#[derive(Debug, Serialize)]
+struct TaskPrefix {
+    task: &'static str,
+    prefix: Prefix,
+}
+
+// A request's `instruction` replaces the query prefix with the template
+#[derive(Debug, Serialize)]
+struct Instruction {
+    template: String,
+    // Applied to queries that don't give one
+    default: Option<String>,
+}
+
+fn model_kind(tokenizer: &Tokenizer) -> &'static str {
+    match tokenizer.get_model() {
+        ModelWrapper::WordPiece(_) => "wordpiece",
+        ModelWrapper::BPE(_) => "bpe",
+        ModelWrapper::Unigram(_) => "unigram",
+        ModelWrapper::WordLevel(_) => "wordlevel",
+    }
+}
+
+// Tokens `text` adds to an input, without special tokens
+fn token_count(tokenizer: &Tokenizer, text: &str) -> usize {
+    tokenizer.encode(text, false).map_or(0, |encoding| encoding.len())
+}
+
+// Strong ETag of a response body
+fn etag(body: &[u8]) -> String {
+    let hash = Hash::hash(body);
+    let hex: String = hash[..16].iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!("\"{}\"", hex)
+}
+
+// The JSON body with its ETag, or `304` when the client has it
+fn cached(headers: &HeaderMap, etag: String, body: Bytes) -> Response {
+    if not_modified(headers, &etag) {
+        return (StatusCode::NOT_MODIFIED, [(header::ETAG, etag)]).into_response();
+    }
+    ([(header::CONTENT_TYPE, "application/json".to_string()), (header::ETAG, etag)], body).into_response()
+}
+
+// A model this server embeds with, by name or alias
+async fn find<'a>(state: &'a AppState, model: &str) -> Result<compare::Target<'a>, ApiError> {
+    match compare::lookup(state, model).await? {
+        Lookup::Found(target) => Ok(target),
+        Lookup::Loading => Err(ApiError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "service_unavailable",
+            format!("The model `{}` is still loading; retry shortly", model),
+        )
+        .code("model_loading")
+        .retry_after(5)),
+        Lookup::Missing => Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            "model_not_found",
+            format!("The model `{}` does not exist", model),
+        )),
+    }
+}
+
+/// `GET /v1/models/{model}/tokenizer`: the model's tokenizer settings and the
+/// prefixes semembed adds to its inputs.
+#[utoipa::path(
+    get,
+    path = "/v1/models/{model}/tokenizer",
+    operation_id = "retrieveTokenizer",
+    summary = "Describe how a model tokenizes, with the prefixes semembed adds to its inputs",
+    params(("model" = String, Path, description = "A loaded model or alias")),
+    responses(
+        (
+            status = 200,
+            description = "The tokenizer's type, vocabulary, special tokens, limits and prefixes",
+            body = serde_json::Value,
+        ),
+        (status = 304, description = "Unchanged since the `If-None-Match` ETag"),
+        (status = 404, description = "Unknown model", body = ErrorResponse),
+        (status = 503, description = "The model is still loading", body = ErrorResponse),
+    ),
+)]
+pub(crate) async fn describe(
+    State(state): State<Arc<AppState>>,
+    Path(model): Path<String>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    let target = find(&state, &model).await?;
+    let (spec, _, embedder) = target.model(&state);
+    let tokenizer = embedder.tokenizer();
+    let prefix = |text: Option<&'static str>| {
+        text.map(|text| Prefix {
+            text,
+            tokens: token_count(tokenizer, text),
+        })
+    };
+    let mut special_tokens: Vec<SpecialToken> = tokenizer
+        .get_added_tokens_decoder()
+        .into_iter()
+        .filter(|(_, token)| token.special)
+        .map(|(id, token)| SpecialToken {
+            id,
+            content: token.content,
+        })
+        .collect();
+    special_tokens.sort_by_key(|token| token.id);
+    let info = TokenizerInfo {
+        object: "tokenizer",
+        model: spec.name,
+        kind: model_kind(tokenizer),
+        vocab_size: tokenizer.get_vocab_size(true),
+        special_tokens,
+        max_tokens: embedder.max_length(),
+        special_tokens_per_input: tokenizer.encode("", true).map_or(0, |encoding| encoding.len()),
+        truncation: tokenizer.get_truncation().cloned(),
+        padding: tokenizer.get_padding().cloned(),
+        default_input_type: match spec.default_input {
+            InputKind::Query => "query",
+            InputKind::Passage => "passage",
+        },
+        prefixes: Prefixes {
+            query: prefix(spec.query_prefix),
+            passage: prefix(spec.passage_prefix),
+        },
+        tasks: spec
+            .tasks
+            .iter()
+            .map(|task| TaskPrefix {
+                task: task.name,
+                prefix: Prefix {
+                    text: task.prefix,
+                    tokens: token_count(tokenizer, task.prefix),
+                },
+            })
+            .collect(),
+        instruction: Instruction {
+            template: models::instruction_prefix("{instruction}"),
+            default: state.instructions.get(spec.name).cloned(),
+        },
+        tokenizer_json: state
+            .tokenizer_json
+            .then(|| format!("/v1/models/{}/tokenizer.json", spec.name.replace('/', "%2F"))),
+    };
+    let body = serde_json::to_string(&info).map_err(|e| {
+        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "internal_error", e.to_string())
+            .reason("tokenizer_serialization_failed")
+    })?;
+    Ok(cached(&headers, etag(body.as_bytes()), Bytes::from(body)))
+}
+
+/// `GET /v1/models/{model}/tokenizer.json`: the model's complete tokenizer
+/// file, with the truncation and padding inference uses.
+#[utoipa::path(
+    get,
+    path = "/v1/models/{model}/tokenizer.json",
+    operation_id = "downloadTokenizer",
+    summary = "The model's complete tokenizer file, when SEMEMBED_TOKENIZER_JSON is on",
+    params(("model" = String, Path, description = "A loaded model or alias")),
+    responses(
+        (status = 200, description = "The Hugging Face tokenizer.json", body = serde_json::Value),
+        (status = 304, description = "Unchanged since the `If-None-Match` ETag"),
+        (status = 404, description = "Unknown model, or serving tokenizer files is not enabled", body = ErrorResponse),
+        (status = 503, description = "The model is still loading", body = ErrorResponse),
+    ),
+)]
+pub(crate) async fn download(
+    State(state): State<Arc<AppState>>,
+    Path(model): Path<String>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    if !state.tokenizer_json {
+        return Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            "invalid_request_error",
+            "Serving tokenizer.json is not enabled",
+        )
+        .reason("not_found"));
+    }
+    let target = find(&state, &model).await?;
+    let (spec, _, embedder) = target.model(&state);
+    let known = state.tokenizer_files.files.lock().unwrap_or_else(PoisonError::into_inner).get(spec.name).cloned();
+    let file = match known {
+        Some(file) => file,
+        None => {
+            let embedder = embedder.clone();
+            let body = tokio::task::spawn_blocking(move || embedder.tokenizer().to_string(false))
+                .await
+                .map_err(|e| anyhow::anyhow!(e))
+                .and_then(|body| body.map_err(|e| anyhow::anyhow!(e)))
+                .map_err(|e| {
+                    ApiError::new(
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        "internal_error",
+                        format!("Failed to serialize the tokenizer: {}", e),
+                    )
+                    .reason("tokenizer_serialization_failed")
+                })?;
+            let file = Arc::new((etag(body.as_bytes()), Bytes::from(body)));
+            let mut files = state.tokenizer_files.files.lock().unwrap_or_else(PoisonError::into_inner);
+            files.insert(spec.name, file.clone());
+            file
+        }
+    };
+    Ok(cached(&headers, file.0.clone(), file.1.clone()))
+}
@@ -0,0 +1,130 @@
+//! W3C Trace Context, without a trace exporter.
+//!
+//! A request arriving with a valid `traceparent` header (as a service mesh
+//! injects) joins that trace: it gets a span id of its own, the trace and
+//! span ids are recorded on the request's tracing span and its access log
+//! line, error bodies carry the trace id, and outbound requests made for it
+//! send a child `traceparent` and the caller's `tracestate`. The response's
+//! `traceparent` names the request's span. A malformed header is ignored, as
+//! the specification asks, and the request is served as if none was sent.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use axum::{
+    extract::Request,
+    http::{HeaderMap, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+
+// The longest `tracestate` passed on; the specification asks for at least 512
+// characters to be propagated, and anything longer is dropped
+const MAX_TRACESTATE: usize = 512;
+
+/// The trace a request is part of.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct TraceContext {
+    /// 32 lowercase hex digits.
+    pub trace_id: String,
+    /// The request's own span, 16 lowercase hex digits; the caller's span is
+    /// its parent.
+    pub span_id: String,
+    flags: u8,
+    tracestate: Option<String>,
+}
+
+impl TraceContext {
+    /// The context of a request with a valid `traceparent`, in a new span.
+    pub fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        let mut traceparents = headers.get_all("traceparent").iter();
+        let (Some(traceparent), None) = (traceparents.next(), traceparents.next()) else {
+            return None;
+        };
+        let (trace_id, flags) = parse(traceparent.to_str().ok()?)?;
+        let tracestate = headers
+            .get_all("tracestate")
+            .iter()
+            .map(|value| value.to_str().ok())
+            .collect::<Option<Vec<_>>>()
+            .map(|values| values.join(","))
+            .filter(|state| !state.trim().is_empty() && state.len() <= MAX_TRACESTATE);
+        Some(Self {
+            trace_id: trace_id.to_string(),
+            span_id: span_id(),
+            flags,
+            tracestate,
+        })
+    }
+
+    /// The `traceparent` naming the request's span: sent on outbound requests
+    /// as their parent, and returned to the caller.
+    pub fn traceparent(&self) -> String {
+        format!("00-{}-{}-{:02x}", self.trace_id, self.span_id, self.flags)
+    }
+
+    /// Add the trace headers to an outbound request.
+    pub fn inject(&self, request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        let request = request.header("traceparent", self.traceparent());
+        match &self.tracestate {
+            Some(state) => request.header("tracestate", state),
+            None => request,
+        }
+    }
+}
+
+// The trace id and flags of a `traceparent`, if it is valid. Versions after
+// 00 may append fields, which are ignored; version ff is invalid.
+fn parse(value: &str) -> Option<(&str, u8)> {
+    let hex = |field: &str, len: usize| {
+        field.len() == len && field.bytes().all(|byte| byte.is_ascii_digit() || (b'a'..=b'f').contains(&byte))
+    };
+    let mut fields = value.splitn(5, '-');
+    let (version, trace_id, parent_id, flags) = (fields.next()?, fields.next()?, fields.next()?, fields.next()?);
+    let rest = fields.next();
+    if !hex(version, 2) || version == "ff" || (version == "00" && rest.is_some()) {
+        return None;
+    }
+    let zero = |field: &str| field.bytes().all(|byte| byte == b'0');
+    if !hex(trace_id, 32) || zero(trace_id) || !hex(parent_id, 16) || zero(parent_id) || !hex(flags, 2) {
+        return None;
+    }
+    Some((trace_id, u8::from_str_radix(flags, 16).ok()?))
+}
+
+// A random, non-zero span id (splitmix64)
+fn span_id() -> String {
+    static SEED: AtomicU64 = AtomicU64::new(0);
+    if SEED.load(Ordering::Relaxed) == 0 {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_nanos() as u64);
+        let _ = SEED.compare_exchange(0, now ^ u64::from(std::process::id()), Ordering::Relaxed, Ordering::Relaxed);
+    }
+    loop {
+        let mut z = SEED.fetch_add(0x9e37_79b9_7f4a_7c15, Ordering::Relaxed).wrapping_add(0x9e37_79b9_7f4a_7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^= z >> 31;
+        if z != 0 {
+            return format!("{:016x}", z);
+        }
+    }
+}
+
+/// Middleware putting the request's [`TraceContext`] in its extensions and
+/// span, and its `traceparent` on the response.
+pub(crate) async fn propagate(mut req: Request, next: Next) -> Response {
+    let Some(context) = TraceContext::from_headers(req.headers()) else {
+        return next.run(req).await;
+    };
+    let span = tracing::Span::current();
+    span.record("trace_id", context.trace_id.as_str());
+    span.record("span_id", context.span_id.as_str());
+    let traceparent = context.traceparent();
+    req.extensions_mut().insert(context);
+
+    let mut response = next.run(req).await;
+    if let Ok(value) = HeaderValue::from_str(&traceparent) {
+        response.headers_mut().insert("traceparent", value);
+    }
+    response
+}
@@ -0,0 +1,256 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use axum::extract::multipart::{Multipart, MultipartError};
+use axum::http::StatusCode;
+use serde::de::DeserializeOwned;
+
+use crate::error::ApiError;
+
+// File types accepted, listed in error messages
+const SUPPORTED_TYPES: &str = ".txt, .md or .csv (UTF-8 or latin-1 text)";
+
+// Bytes inspected when deciding whether a file is binary
+const SNIFF_BYTES: usize = 8192;
+
+/// Size limits for `/v1/embeddings/file`.
+#[derive(Debug, Clone, Copy)]
+pub struct UploadLimits {
+    /// Largest single file, in bytes.
+    pub max_file_bytes: usize,
+    /// All files of one request together, in bytes.
+    pub max_total_bytes: usize,
+}
+
+/// Kind of text file, from its extension (or content type when it has none).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileKind {
+    Text,
+    Markdown,
+    Csv,
+}
+
+/// One uploaded file.
+pub struct UploadedFile {
+    pub filename: String,
+    pub kind: FileKind,
+    pub bytes: Vec<u8>,
+}
+
+/// A parsed multipart form: the files, in upload order, and every other
+/// field as a string option.
+pub struct UploadForm {
+    pub files: Vec<UploadedFile>,
+    fields: HashMap<String, String>,
+}
+
+impl UploadForm {
+    /// Read the whole form, enforcing the per-file and total limits while
+    /// streaming so oversized uploads are rejected before they are buffered.
+    pub async fn read(mut multipart: Multipart, limits: UploadLimits) -> Result<Self, ApiError> {
+        let mut files = Vec::new();
+        let mut fields = HashMap::new();
+        let mut total = 0;
+        while let Some(mut field) = multipart.next_field().await.map_err(multipart_error)? {
+            let name = field.name().unwrap_or_default().to_string();
+            let Some(filename) = field.file_name().map(str::to_string) else {
+                let value = field.text().await.map_err(multipart_error)?;
+                fields.insert(name, value);
+                continue;
+            };
+            let kind = file_kind(&filename, field.content_type())?;
+
+            let mut bytes = Vec::new();
+            while let Some(chunk) = field.chunk().await.map_err(multipart_error)? {
+                if bytes.len() + chunk.len() > limits.max_file_bytes {
+                    return Err(too_large(format!(
+                        "File {} exceeds the maximum of {} bytes per file",
+                        filename, limits.max_file_bytes
+                    )));
+                }
+                total += chunk.len();
+                if total > limits.max_total_bytes {
+                    return Err(too_large(format!(
+                        "Uploaded files exceed the maximum of {} bytes in total",
+                        limits.max_total_bytes
+                    )));
+                }
+                bytes.extend_from_slice(&chunk);
+            }
+            files.push(UploadedFile { filename, kind, bytes });
+        }
+        Ok(Self { files, fields })
+    }
+
+    /// A scalar form field (number or boolean), if present.
+    pub fn field<T: FromStr>(&self, name: &'static str) -> Result<Option<T>, ApiError> {
+        self.fields
+            .get(name)
+            .map(|value| {
+                value.trim().parse().map_err(|_| invalid_field(name, value))
+            })
+            .transpose()
+    }
+
+    /// A form field holding one of the JSON API's enum values
+    /// (`encoding_format`, `input_type`), if present.
+    pub fn field_enum<T: DeserializeOwned>(&self, name: &'static str) -> Result<Option<T>, ApiError> {
+        self.fields
+            .get(name)
+            .map(|value| {
+                serde_json::from_value(serde_json::Value::String(value.trim().to_string()))
+                    .map_err(|_| invalid_field(name, value))
+            })
+            .transpose()
+    }
+
+    /// A free-text form field, if present and not blank.
+    pub fn text(&self, name: &str) -> Option<&str> {
+        self.fields.get(name).map(|value| value.trim()).filter(|value| !value.is_empty())
+    }
+}
+
+fn file_kind(filename: &str, content_type: Option<&str>) -> Result<FileKind, ApiError> {
+    let extension = filename.rsplit_once('.').map(|(_, extension)| extension.to_ascii_lowercase());
+    let kind = match extension.as_deref() {
+        Some("txt" | "text") => Some(FileKind::Text),
+        Some("md" | "markdown") => Some(FileKind::Markdown),
+        Some("csv") => Some(FileKind::Csv),
+        Some(_) => None,
+        // No extension: fall back to the part's content type
+        None => match content_type.map(|mime| mime.split(';').next().unwrap_or_default().trim()) {
+            Some("text/plain") => Some(FileKind::Text),
+            Some("text/markdown") => Some(FileKind::Markdown),
+            Some("text/csv") => Some(FileKind::Csv),
+            _ => None,
+        },
+    };
+    kind.ok_or_else(|| unsupported(format!("File {} is not a supported type; upload {}", filename, SUPPORTED_TYPES)))
+}
+
+/// Decode a text file: UTF-8 (a byte order mark is dropped), UTF-16 when it
+/// starts with a byte order mark, or latin-1 when `latin1` is set and the
+/// bytes aren't valid UTF-8. Binary content is rejected.
+pub fn decode(file: &UploadedFile, latin1: bool) -> Result<String, ApiError> {
+    let bytes = file.bytes.as_slice();
+    if let Some(rest) = bytes.strip_prefix(b"\xEF\xBB\xBF") {
+        return utf8(file, rest, latin1);
+    }
+    if let Some(rest) = bytes.strip_prefix(b"\xFF\xFE") {
+        return utf16(file, rest, u16::from_le_bytes);
+    }
+    if let Some(rest) = bytes.strip_prefix(b"\xFE\xFF") {
+        return utf16(file, rest, u16::from_be_bytes);
+    }
+    // Text files don't contain NUL bytes; PDFs, Office documents and images do
+    if bytes[..bytes.len().min(SNIFF_BYTES)].contains(&0) {
+        return Err(unsupported(format!(
+            "File {} looks like a binary file; upload {}",
+            file.filename, SUPPORTED_TYPES
+        )));
+    }
+    utf8(file, bytes, latin1)
+}
+
+fn utf8(file: &UploadedFile, bytes: &[u8], latin1: bool) -> Result<String, ApiError> {
+    match std::str::from_utf8(bytes) {
+        Ok(text) => Ok(text.to_string()),
+        // Latin-1 maps every byte to the code point of the same value
+        Err(_) if latin1 => Ok(bytes.iter().map(|&byte| char::from(byte)).collect()),
+        Err(e) => Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_request_error",
+            format!(
+                "File {} is not valid UTF-8 ({}); set latin1=true to decode it as latin-1",
+                file.filename, e
+            ),
+        )
+        .param("file")
+        .reason("invalid_encoding")),
+    }
+}
+
+fn utf16(file: &UploadedFile, bytes: &[u8], unit: fn([u8; 2]) -> u16) -> Result<String, ApiError> {
+    let units: Vec<u16> = bytes.chunks_exact(2).map(|pair| unit([pair[0], pair[1]])).collect();
+    String::from_utf16(&units).map_err(|_| {
+        ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_request_error",
+            format!("File {} is not valid UTF-16", file.filename),
+        )
+        .param("file")
+        .reason("invalid_encoding")
+    })
+}
+
+/// Values of one CSV column with their 1-based row numbers (the header row
+/// not counted). `column` is a header name, or a 0-based index when no header
+/// has that name. Empty cells are skipped.
+pub fn csv_column(filename: &str, text: &str, column: &str) -> Result<Vec<(usize, String)>, ApiError> {
+    let csv_error = |message: String| {
+        ApiError::new(StatusCode::BAD_REQUEST, "invalid_request_error", message)
+            .param("csv_column")
+            .reason("invalid_csv")
+    };
+
+    let mut reader = csv::ReaderBuilder::new().flexible(true).from_reader(text.as_bytes());
+    let headers = reader
+        .headers()
+        .map_err(|e| csv_error(format!("File {} is not valid CSV: {}", filename, e)))?;
+    let position = headers
+        .iter()
+        .position(|header| header.trim() == column)
+        .or_else(|| column.parse().ok().filter(|&index| index < headers.len()))
+        .ok_or_else(|| {
+            csv_error(format!(
+                "File {} has no column {:?} (columns: {})",
+                filename,
+                column,
+                headers.iter().collect::<Vec<_>>().join(", ")
+            ))
+        })?;
+
+    let mut values = Vec::new();
+    for (row, record) in reader.records().enumerate() {
+        let record = record.map_err(|e| csv_error(format!("File {} is not valid CSV: {}", filename, e)))?;
+        if let Some(value) = record.get(position).filter(|value| !value.trim().is_empty()) {
+            values.push((row + 1, value.to_string()));
+        }
+    }
+    Ok(values)
+}
+
+fn multipart_error(e: MultipartError) -> ApiError {
+    // Only the body limit surfaces as 413; everything else is a malformed form
+    if e.status() == StatusCode::PAYLOAD_TOO_LARGE {
+        return too_large(e.body_text());
+    }
+    ApiError::new(
+        StatusCode::BAD_REQUEST,
+        "invalid_request_error",
+        format!("Invalid multipart body: {}", e.body_text()),
+    )
+    .reason("invalid_body")
+}
+
+fn invalid_field(name: &'static str, value: &str) -> ApiError {
+    ApiError::new(
+        StatusCode::BAD_REQUEST,
+        "invalid_request_error",
+        format!("Invalid value {:?} for {}", value, name),
+    )
+    .param(name)
+    .reason("invalid_body")
+}
+
+fn too_large(message: String) -> ApiError {
+    ApiError::new(StatusCode::PAYLOAD_TOO_LARGE, "invalid_request_error", message)
+        .param("file")
+        .reason("body_too_large")
+}
+
+fn unsupported(message: String) -> ApiError {
+    ApiError::new(StatusCode::UNSUPPORTED_MEDIA_TYPE, "invalid_request_error", message)
+        .param("file")
+        .reason("unsupported_file_type")
+}
@@ -0,0 +1,647 @@
+//! Daily usage per API key, for exporting to billing.
+//!
+//! Every API call is counted against its tenant (the hash of its API key, as
+//! in fair scheduling) and the UTC day it arrived on: requests, tokens
+//! embedded and error responses. Keys aren't authenticated, so past
+//! `SEMEMBED_USAGE_MAX_KEYS` keys a day, keys without a token quota share one
+//! `other` row instead of adding their own. With `SEMEMBED_USAGE_FILE` the
+//! aggregates are written to a JSON file every `SEMEMBED_USAGE_FLUSH_SECS` and
+//! on shutdown, and read back at startup. `GET /v1/usage/export` streams them as
+//! CSV or JSON, and `POST /admin/usage/reset` forgets a period once exported.
+
+use std::collections::{BTreeMap, HashSet};
+use std::convert::Infallible;
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use axum::{
+    body::{Body, Bytes},
+    extract::{rejection::QueryRejection, Query, Request, State},
+    http::{header, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{error, info};
+
+use crate::error::ApiError;
+use crate::queue::Tenant;
+use crate::quota::Period;
+use crate::AppState;
+
+const SECS_PER_DAY: i64 = 86_400;
+
+/// The row that keys without a quota share once a day has `max_keys` keys.
+pub const OTHER_KEY: &str = "other";
+
+/// A calendar day in UTC, as days since 1970-01-01. Days never follow the
+/// server's local time zone, so a report reads the same wherever it ran.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Day(i64);
+
+impl Day {
+    /// The UTC day `time` falls on.
+    pub fn of(time: SystemTime) -> Self {
+        let secs = match time.duration_since(UNIX_EPOCH) {
+            Ok(since) => since.as_secs() as i64,
+            Err(before) => -(before.duration().as_secs() as i64),
+        };
+        Self(secs.div_euclid(SECS_PER_DAY))
+    }
+
+    pub fn today() -> Self {
+        Self::of(SystemTime::now())
+    }
+
+    /// The day after this one.
+    pub fn next(self) -> Self {
+        Self(self.0 + 1)
+    }
+
+    /// The first day of this day's month.
+    pub fn month_start(self) -> Self {
+        let (year, month, _) = self.civil();
+        Self::from_civil(year, month, 1)
+    }
+
+    /// The first day of the month after this day's.
+    pub fn next_month_start(self) -> Self {
+        let (year, month, _) = self.civil();
+        if month == 12 {
+            Self::from_civil(year + 1, 1, 1)
+        } else {
+            Self::from_civil(year, month + 1, 1)
+        }
+    }
+
+    /// Seconds from `now` until this day starts, or 0 once it has.
+    pub fn secs_until(self, now: SystemTime) -> u64 {
+        let now = now.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs() as i64);
+        (self.0 * SECS_PER_DAY - now).max(0) as u64
+    }
+
+    // Howard Hinnant's days_from_civil, for the proleptic Gregorian calendar
+    fn from_civil(year: i64, month: i64, day: i64) -> Self {
+        let year = if month <= 2 { year - 1 } else { year };
+        let era = year.div_euclid(400);
+        let year_of_era = year - era * 400;
+        let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        Self(era * 146_097 + day_of_era - 719_468)
+    }
+
+    // (year, month, day), the inverse of from_civil
+    fn civil(self) -> (i64, i64, i64) {
+        let days = self.0 + 719_468;
+        let era = days.div_euclid(146_097);
+        let day_of_era = days - era * 146_097;
+        let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let shifted_month = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+        let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+        let year = year_of_era + era * 400 + i64::from(month <= 2);
+        (year, month, day)
+    }
+}
+
+impl fmt::Display for Day {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (year, month, day) = self.civil();
+        write!(f, "{:04}-{:02}-{:02}", year, month, day)
+    }
+}
+
+/// Parses `YYYY-MM-DD`.
+impl FromStr for Day {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || anyhow::anyhow!("expected a date as YYYY-MM-DD, got {:?}", s);
+        let mut parts = s.trim().splitn(3, '-');
+        let mut next = |digits: usize| -> anyhow::Result<i64> {
+            let part = parts.next().filter(|part| part.len() == digits).ok_or_else(invalid)?;
+            part.parse().map_err(|_| invalid())
+        };
+        let (year, month, day) = (next(4)?, next(2)?, next(2)?);
+        let parsed = Self::from_civil(year, month, day);
+        // Out-of-range months and days (2025-02-30) don't survive the round trip
+        if !(1..=12).contains(&month) || parsed.civil() != (year, month, day) {
+            return Err(invalid());
+        }
+        Ok(parsed)
+    }
+}
+
+// One tenant's usage on one day
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Counts {
+    requests: u64,
+    tokens: u64,
+    errors: u64,
+}
+
+// A row of the export and of the usage file
+#[derive(Debug, Serialize, Deserialize)]
+struct Row {
+    date: String,
+    key: String,
+    requests: u64,
+    tokens: u64,
+    errors: u64,
+}
+
+// A runtime change to a key's token quota for the period starting on `start`
+#[derive(Debug, Serialize, Deserialize)]
+struct AdjustmentRow {
+    period: Period,
+    start: String,
+    key: String,
+    tokens: i64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct UsageFile {
+    days: Vec<Row>,
+    #[serde(default)]
+    quota_adjustments: Vec<AdjustmentRow>,
+}
+
+/// Usage aggregated by UTC day and tenant, optionally kept in a file.
+pub(crate) struct UsageLedger {
+    file: Option<PathBuf>,
+    days: Mutex<BTreeMap<(Day, String), Counts>>,
+    // Keys a day has rows for before the rest share `other`
+    max_keys: usize,
+    // Keys with a token quota, which always get their own row
+    quota_keys: Mutex<HashSet<String>>,
+    // Tokens added to (or taken from) a key's quota for one period, by the
+    // period and the day it started
+    adjustments: Mutex<BTreeMap<(Period, Day, String), i64>>,
+    // Whether there are changes the file doesn't have yet
+    dirty: AtomicBool,
+}
+
+impl UsageLedger {
+    /// A ledger kept in `file`, starting from what it holds, or in memory
+    /// only without one, with rows for up to `max_keys` keys a day.
+    pub fn open(file: Option<PathBuf>, max_keys: usize) -> anyhow::Result<Self> {
+        let mut days = BTreeMap::new();
+        let mut adjustments = BTreeMap::new();
+        if let Some(path) = &file {
+            match std::fs::read(path) {
+                Ok(bytes) => {
+                    let stored: UsageFile = serde_json::from_slice(&bytes)
+                        .with_context(|| format!("invalid usage file {}", path.display()))?;
+                    for row in stored.days {
+                        let day = row.date.parse().with_context(|| format!("invalid usage file {}", path.display()))?;
+                        days.insert(
+                            (day, row.key),
+                            Counts {
+                                requests: row.requests,
+                                tokens: row.tokens,
+                                errors: row.errors,
+                            },
+                        );
+                    }
+                    for row in stored.quota_adjustments {
+                        let start =
+                            row.start.parse().with_context(|| format!("invalid usage file {}", path.display()))?;
+                        adjustments.insert((row.period, start, row.key), row.tokens);
+                    }
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e).with_context(|| format!("couldn't read the usage file {}", path.display())),
+            }
+        }
+        Ok(Self {
+            file,
+            days: Mutex::new(days),
+            max_keys,
+            quota_keys: Mutex::new(HashSet::new()),
+            adjustments: Mutex::new(adjustments),
+            dirty: AtomicBool::new(false),
+        })
+    }
+
+    pub fn persistent(&self) -> bool {
+        self.file.is_some()
+    }
+
+    /// Replace the keys with a token quota, which are never folded into
+    /// `other` so their quotas see all of their usage.
+    pub fn set_quota_keys(&self, keys: Vec<String>) {
+        *self.quota_keys.lock().unwrap_or_else(PoisonError::into_inner) = keys.into_iter().collect();
+    }
+
+    fn update(&self, tenant: &Tenant, day: Day, update: impl FnOnce(&mut Counts)) {
+        let mut days = self.days.lock().unwrap_or_else(PoisonError::into_inner);
+        let key = self.key(&days, tenant, day);
+        update(days.entry((day, key)).or_default());
+        self.dirty.store(true, Ordering::Relaxed);
+    }
+
+    // The row `tenant` is counted in on `day`: its own, unless it has none yet,
+    // has no quota and the day is already at `max_keys`
+    fn key(&self, days: &BTreeMap<(Day, String), Counts>, tenant: &Tenant, day: Day) -> String {
+        let key = tenant.as_str().to_string();
+        if days.contains_key(&(day, key.clone()))
+            || self.quota_keys.lock().unwrap_or_else(PoisonError::into_inner).contains(&key)
+        {
+            return key;
+        }
+        let keys = days.range((day, String::new())..(day.next(), String::new())).count();
+        if keys < self.max_keys {
+            key
+        } else {
+            OTHER_KEY.to_string()
+        }
+    }
+
+    /// Count a request that arrived on `day`, and whether it failed.
+    pub fn record_request(&self, tenant: &Tenant, day: Day, error: bool) {
+        self.update(tenant, day, |counts| {
+            counts.requests += 1;
+            counts.errors += u64::from(error);
+        });
+    }
+
+    /// Count tokens embedded for `tenant` today.
+    pub fn add_tokens(&self, tenant: &Tenant, tokens: usize) {
+        self.update(tenant, Day::today(), |counts| counts.tokens += tokens as u64);
+    }
+
+    /// Tokens `tenant` embedded from `from` to `to`, both included.
+    pub fn tokens_between(&self, tenant: &Tenant, from: Day, to: Day) -> u64 {
+        let days = self.days.lock().unwrap_or_else(PoisonError::into_inner);
+        days.range((from, String::new())..)
+            .take_while(|((day, _), _)| *day <= to)
+            .filter(|((_, key), _)| key == tenant.as_str())
+            .map(|(_, counts)| counts.tokens)
+            .sum()
+    }
+
+    /// Tokens every key but those `listed` embedded from `from` to `to`, both
+    /// included.
+    pub fn tokens_except(&self, listed: &[String], from: Day, to: Day) -> u64 {
+        let days = self.days.lock().unwrap_or_else(PoisonError::into_inner);
+        days.range((from, String::new())..)
+            .take_while(|((day, _), _)| *day <= to)
+            .filter(|((_, key), _)| !listed.contains(key))
+            .map(|(_, counts)| counts.tokens)
+            .sum()
+    }
+
+    /// Tokens added to `tenant`'s quota for the `period` starting on `start`.
+    pub fn quota_adjustment(&self, tenant: &Tenant, period: Period, start: Day) -> i64 {
+        let adjustments = self.adjustments.lock().unwrap_or_else(PoisonError::into_inner);
+        adjustments.get(&(period, start, tenant.as_str().to_string())).copied().unwrap_or(0)
+    }
+
+    /// Replace the tokens added to `tenant`'s quota for the `period` starting
+    /// on `start`. Adjustments of periods that have ended are dropped.
+    pub fn set_quota_adjustment(&self, tenant: &Tenant, period: Period, start: Day, tokens: i64) {
+        let mut adjustments = self.adjustments.lock().unwrap_or_else(PoisonError::into_inner);
+        let today = Day::today();
+        adjustments.retain(|(period, start, _), _| period.start(today) <= *start);
+        adjustments.insert((period, start, tenant.as_str().to_string()), tokens);
+        self.dirty.store(true, Ordering::Relaxed);
+    }
+
+    // Rows from `from` to `to`, both included, by day and then key
+    fn rows(&self, from: Day, to: Day) -> Vec<Row> {
+        let days = self.days.lock().unwrap_or_else(PoisonError::into_inner);
+        days.iter()
+            .filter(|((day, _), _)| (from..=to).contains(day))
+            .map(|((day, key), counts)| Row {
+                date: day.to_string(),
+                key: key.clone(),
+                requests: counts.requests,
+                tokens: counts.tokens,
+                errors: counts.errors,
+            })
+            .collect()
+    }
+
+    /// Forget the days from `from` to `to`; returns the rows removed.
+    pub fn reset(&self, from: Day, to: Day) -> usize {
+        let mut days = self.days.lock().unwrap_or_else(PoisonError::into_inner);
+        let before = days.len();
+        days.retain(|(day, _), _| !(from..=to).contains(day));
+        self.dirty.store(true, Ordering::Relaxed);
+        before - days.len()
+    }
+
+    /// Write the aggregates to the file if they changed since the last
+    /// flush. The file is replaced whole, through a temporary next to it, so
+    /// a crash mid-write leaves the previous version.
+    pub fn flush(&self) -> anyhow::Result<()> {
+        let Some(path) = &self.file else {
+            return Ok(());
+        };
+        if !self.dirty.swap(false, Ordering::Relaxed) {
+            return Ok(());
+        }
+        let quota_adjustments = self
+            .adjustments
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .map(|((period, start, key), tokens)| AdjustmentRow {
+                period: *period,
+                start: start.to_string(),
+                key: key.clone(),
+                tokens: *tokens,
+            })
+            .collect();
+        let file = UsageFile {
+            days: self.rows(Day(i64::MIN), Day(i64::MAX)),
+            quota_adjustments,
+        };
+        let result = (|| {
+            let temporary = path.with_extension("tmp");
+            std::fs::write(&temporary, serde_json::to_vec(&file)?)?;
+            std::fs::rename(&temporary, path)?;
+            anyhow::Ok(())
+        })();
+        if result.is_err() {
+            // Try again on the next flush
+            self.dirty.store(true, Ordering::Relaxed);
+        }
+        result.with_context(|| format!("couldn't write the usage file {}", path.display()))
+    }
+}
+
+/// Write the ledger to its file every `every`.
+pub(crate) async fn persist(ledger: Arc<UsageLedger>, every: Duration) {
+    let mut ticks = tokio::time::interval(every);
+    ticks.tick().await;
+    loop {
+        ticks.tick().await;
+        flush(ledger.clone()).await;
+    }
+}
+
+/// Flush the ledger on the blocking pool, logging a failure.
+pub(crate) async fn flush(ledger: Arc<UsageLedger>) {
+    match tokio::task::spawn_blocking(move || ledger.flush()).await {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => error!("Usage flush failed: {:#}", e),
+        Err(e) => error!("Usage flush panicked: {}", e),
+    }
+}
+
+/// Middleware counting API calls (`POST`s outside `/admin`, and
+/// `GET /v1/embeddings`) and their error responses against the caller's
+/// tenant and the day the call arrived.
+pub(crate) async fn count(State(state): State<Arc<AppState>>, req: Request, next: Next) -> Response {
+    let call = match *req.method() {
+        Method::POST => !req.uri().path().starts_with("/admin/"),
+        Method::GET => req.uri().path() == "/v1/embeddings",
+        _ => false,
+    };
+    if !call {
+        return next.run(req).await;
+    }
+    // As Scheduling identifies it, but for every call, including rejected ones
+    let tenant = Tenant::from_headers(req.headers());
+    let day = Day::today();
+    let response = next.run(req).await;
+    let failed = response.status().is_client_error() || response.status().is_server_error();
+    state.usage.record_request(&tenant, day, failed);
+    response
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum ExportFormat {
+    #[default]
+    Json,
+    Csv,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct ExportParams {
+    from: Option<String>,
+    to: Option<String>,
+    #[serde(default)]
+    format: ExportFormat,
+}
+
+fn invalid_query(param: &'static str, message: impl Into<String>) -> ApiError {
+    ApiError::new(StatusCode::BAD_REQUEST, "invalid_request_error", message)
+        .param(param)
+        .reason("invalid_query")
+}
+
+fn query<T>(params: Result<Query<T>, QueryRejection>) -> Result<T, ApiError> {
+    params.map(|Query(params)| params).map_err(|rejection| {
+        ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_request_error",
+            format!("Invalid query string: {}", rejection.body_text()),
+        )
+        .reason("invalid_query")
+    })
+}
+
+fn parse_day(param: &'static str, value: Option<&str>) -> Result<Option<Day>, ApiError> {
+    value
+        .map(|value| value.parse::<Day>().map_err(|e| invalid_query(param, format!("{}: {}", param, e))))
+        .transpose()
+}
+
+// The inclusive period of `from` and `to`; open ends reach the first and the
+// current day
+fn period(from: Option<&str>, to: Option<&str>) -> Result<(Day, Day), ApiError> {
+    let from = parse_day("from", from)?.unwrap_or(Day(i64::MIN));
+    let to = parse_day("to", to)?.unwrap_or_else(Day::today);
+    if from > to {
+        return Err(invalid_query("from", format!("from ({}) is after to ({})", from, to)));
+    }
+    Ok((from, to))
+}
+
+/// `GET /v1/usage/export?from=&to=&format=csv|json`: daily usage per key
+/// from `from` to `to` (UTC dates, both included), streamed row by row.
+pub(crate) async fn export(
+    State(state): State<Arc<AppState>>,
+    params: Result<Query<ExportParams>, QueryRejection>,
+) -> Result<Response, ApiError> {
+    let params = query(params)?;
+    let (from, to) = period(params.from.as_deref(), params.to.as_deref())?;
+    let rows = state.usage.rows(from, to);
+    let count = rows.len();
+    let (content_type, head, tail) = match params.format {
+        ExportFormat::Csv => ("text/csv; charset=utf-8", "date,key,requests,tokens,errors\n", ""),
+        ExportFormat::Json => ("application/json", "{\"object\":\"list\",\"data\":[", "]}"),
+    };
+    let format = params.format;
+    let body = rows.into_iter().enumerate().map(move |(index, row)| {
+        let chunk = match format {
+            ExportFormat::Csv => {
+                format!("{},{},{},{},{}\n", row.date, row.key, row.requests, row.tokens, row.errors)
+            }
+            ExportFormat::Json => {
+                let separator = if index + 1 < count { "," } else { "" };
+                format!("{}{}", serde_json::to_string(&row).unwrap_or_default(), separator)
+            }
+        };
+        Ok::<_, Infallible>(Bytes::from(chunk))
+    });
+    let chunks = std::iter::once(Ok(Bytes::from_static(head.as_bytes())))
+        .chain(body)
+        .chain(std::iter::once(Ok(Bytes::from_static(tail.as_bytes()))));
+    Ok((
+        [(header::CONTENT_TYPE, content_type)],
+        Body::from_stream(futures_util::stream::iter(chunks)),
+    )
+        .into_response())
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct ResetParams {
+    from: Option<String>,
+    to: Option<String>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct ResetResponse {
+    object: &'static str,
+    from: String,
+    to: String,
+    removed: usize,
+}
+
+/// `POST /admin/usage/reset?from=&to=`: forget the usage of a period, once
+/// it has been exported. Both ends are required, so a stray call can't wipe
+/// everything.
+pub(crate) async fn reset(
+    State(state): State<Arc<AppState>>,
+    params: Result<Query<ResetParams>, QueryRejection>,
+) -> Result<Json<ResetResponse>, ApiError> {
+    let params = query(params)?;
+    if params.from.is_none() || params.to.is_none() {
+        let param = if params.from.is_none() { "from" } else { "to" };
+        return Err(invalid_query(param, "Resetting usage needs both from and to"));
+    }
+    let (from, to) = period(params.from.as_deref(), params.to.as_deref())?;
+    let removed = state.usage.reset(from, to);
+    info!("Usage from {} to {} reset ({} rows)", from, to, removed);
+    flush(state.usage.clone()).await;
+    Ok(Json(ResetResponse {
+        object: "usage_reset",
+        from: from.to_string(),
+        to: to.to_string(),
+        removed,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    fn day(date: &str) -> Day {
+        date.parse().unwrap()
+    }
+
+    fn summary(rows: Vec<Row>) -> Vec<String> {
+        rows.into_iter()
+            .map(|row| format!("{} {} {} {} {}", row.date, row.key, row.requests, row.tokens, row.errors))
+            .collect()
+    }
+
+    #[test]
+    fn days_are_utc_calendar_dates() {
+        // 2024-03-31T23:59:59Z and a second later
+        assert_eq!(Day::of(at(1_711_929_599)).to_string(), "2024-03-31");
+        assert_eq!(Day::of(at(1_711_929_600)).to_string(), "2024-04-01");
+        assert_eq!(Day::of(UNIX_EPOCH - Duration::from_secs(1)).to_string(), "1969-12-31");
+        assert_eq!(day("2024-02-29").next().to_string(), "2024-03-01");
+        assert_eq!(day("2024-12-15").month_start().to_string(), "2024-12-01");
+        assert_eq!(day("2024-12-15").next_month_start().to_string(), "2025-01-01");
+        assert_eq!(day("2024-04-01").secs_until(at(1_711_929_599)), 1);
+        assert_eq!(day("2024-04-01").secs_until(at(1_711_929_601)), 0);
+        for invalid in ["2025-02-29", "2025-13-01", "2025-00-10", "2025-1-01", "01-01-2025", "yesterday"] {
+            assert!(invalid.parse::<Day>().is_err(), "{:?} parsed", invalid);
+        }
+    }
+
+    #[test]
+    fn traffic_across_midnight_is_split_by_day() {
+        let ledger = UsageLedger::open(None, 100).unwrap();
+        let (a, b) = (Tenant::from_id("team-a"), Tenant::from_id("team-b"));
+        let before = Day::of(at(1_711_929_599));
+        let after = Day::of(at(1_711_929_600));
+        ledger.record_request(&a, before, false);
+        ledger.update(&a, before, |counts| counts.tokens += 100);
+        ledger.record_request(&a, after, true);
+        ledger.record_request(&b, after, false);
+        ledger.update(&b, after, |counts| counts.tokens += 7);
+        ledger.record_request(&a, after, false);
+        ledger.update(&a, after, |counts| counts.tokens += 20);
+
+        assert_eq!(summary(ledger.rows(before, before)), ["2024-03-31 team-a 1 100 0"]);
+        assert_eq!(
+            summary(ledger.rows(before, after)),
+            ["2024-03-31 team-a 1 100 0", "2024-04-01 team-a 2 20 1", "2024-04-01 team-b 1 7 0"]
+        );
+        assert_eq!(ledger.tokens_between(&a, before, after), 120);
+        assert_eq!(ledger.tokens_between(&a, after, after), 20);
+        assert_eq!(ledger.tokens_except(&["team-a".to_string()], before, after), 7);
+
+        assert_eq!(ledger.reset(before, before), 1);
+        assert_eq!(summary(ledger.rows(before, after)).len(), 2);
+    }
+
+    #[test]
+    fn keys_past_the_daily_limit_share_a_row() {
+        let ledger = UsageLedger::open(None, 2).unwrap();
+        ledger.set_quota_keys(vec!["quota".to_string()]);
+        let today = day("2025-03-01");
+        for key in ["a", "b", "c", "d", "a", "quota"] {
+            ledger.record_request(&Tenant::from_id(key), today, false);
+        }
+        assert_eq!(
+            summary(ledger.rows(today, today)),
+            ["2025-03-01 a 2 0 0", "2025-03-01 b 1 0 0", "2025-03-01 other 2 0 0", "2025-03-01 quota 1 0 0"]
+        );
+        // Each day starts with room again
+        ledger.record_request(&Tenant::from_id("c"), today.next(), false);
+        assert_eq!(summary(ledger.rows(today.next(), today.next())), ["2025-03-02 c 1 0 0"]);
+    }
+
+    #[test]
+    fn usage_survives_a_restart() {
+        let path = std::env::temp_dir().join(format!("semembed-usage-{}.json", std::process::id()));
+        let ledger = UsageLedger::open(Some(path.clone()), 100).unwrap();
+        let tenant = Tenant::from_id("team-a");
+        ledger.record_request(&tenant, day("2025-01-31"), false);
+        ledger.update(&tenant, day("2025-01-31"), |counts| counts.tokens += 42);
+        ledger.set_quota_adjustment(&tenant, Period::Monthly, Day::today().month_start(), 1000);
+        ledger.flush().unwrap();
+
+        let reopened = UsageLedger::open(Some(path.clone()), 100).unwrap();
+        assert_eq!(summary(reopened.rows(day("2025-01-01"), day("2025-12-31"))), ["2025-01-31 team-a 1 42 0"]);
+        assert_eq!(reopened.quota_adjustment(&tenant, Period::Monthly, Day::today().month_start()), 1000);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn periods_run_forward() {
+        assert_eq!(period(Some("2025-01-01"), Some("2025-01-31")).unwrap(), (day("2025-01-01"), day("2025-01-31")));
+        assert_eq!(period(None, Some("2025-01-31")).unwrap().0, Day(i64::MIN));
+        assert!(period(Some("2025-02-01"), Some("2025-01-31")).is_err());
+        assert!(period(Some("2025-02-30"), None).is_err());
+    }
+}
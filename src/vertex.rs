@@ -0,0 +1,348 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::Response,
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use crate::error::{map_error_body, ApiError};
+use crate::extract::{ApiJson, Scheduling};
+use crate::models::InputKind;
+use crate::preprocess::PreprocessOverrides;
+use crate::{
+    create_embeddings, AppState, EmbeddingData, EmbeddingItem, EmbeddingRequest, EncodingFormat, Floats, InputType,
+    OutputKind,
+};
+
+// Vertex AI's text embedding `predict` request
+#[derive(Debug, Deserialize)]
+pub(crate) struct PredictRequest {
+    instances: Vec<Instance>,
+    #[serde(default)]
+    parameters: Parameters,
+}
+
+#[derive(Debug, Deserialize)]
+struct Instance {
+    content: String,
+    task_type: Option<TaskType>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Parameters {
+    output_dimensionality: Option<usize>,
+    // Vertex truncates long inputs unless told not to, like `truncate`
+    auto_truncate: Option<bool>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+enum TaskType {
+    RetrievalQuery,
+    RetrievalDocument,
+    SemanticSimilarity,
+    Classification,
+    Clustering,
+    QuestionAnswering,
+    FactVerification,
+    CodeRetrievalQuery,
+}
+
+impl TaskType {
+    // Queries get the query prefix, documents the passage prefix; symmetric
+    // tasks get the model's default, as when input_type is left out
+    fn input_kind(self) -> Option<InputKind> {
+        match self {
+            Self::RetrievalQuery | Self::QuestionAnswering | Self::FactVerification | Self::CodeRetrievalQuery => {
+                Some(InputKind::Query)
+            }
+            Self::RetrievalDocument => Some(InputKind::Passage),
+            Self::SemanticSimilarity | Self::Classification | Self::Clustering => None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct PredictResponse {
+    predictions: Vec<Prediction>,
+    metadata: PredictMetadata,
+}
+
+#[derive(Debug, Serialize)]
+struct Prediction {
+    embeddings: PredictionEmbeddings,
+}
+
+#[derive(Debug, Serialize)]
+struct PredictionEmbeddings {
+    values: Floats,
+    statistics: Statistics,
+}
+
+#[derive(Debug, Serialize)]
+struct Statistics {
+    token_count: usize,
+    truncated: bool,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PredictMetadata {
+    billable_character_count: usize,
+}
+
+/// Vertex AI's `POST /v1/projects/{p}/locations/{l}/publishers/google/models/{model}:predict`.
+/// The model (by name or alias) comes from the path; the project and location
+/// are only logged. Instances are embedded like `/v1/embeddings` inputs, one
+/// request per task type so each gets its prefix.
+pub(crate) async fn predict(
+    State(state): State<Arc<AppState>>,
+    Path((project, location, model)): Path<(String, String, String)>,
+    scheduling: Scheduling,
+    ApiJson(req): ApiJson<PredictRequest>,
+) -> Result<Json<PredictResponse>, ApiError> {
+    let Some(model) = model.strip_suffix(":predict") else {
+        return Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            "invalid_request_error",
+            format!("Unknown method {:?}; only :predict is supported", model),
+        )
+        .reason("not_found"));
+    };
+    debug!("Vertex predict for project {} in {}", project, location);
+    // Checked up front, since the instances may be split over several requests
+    if req.instances.is_empty() || req.instances.len() > state.limits.max_inputs {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "invalid_request_error",
+            format!(
+                "instances must contain between 1 and {} items, got {}",
+                state.limits.max_inputs,
+                req.instances.len()
+            ),
+        )
+        .param("instances")
+        .reason("invalid_instances"));
+    }
+
+    let billable_character_count = req
+        .instances
+        .iter()
+        .map(|instance| instance.content.chars().filter(|c| !c.is_whitespace()).count())
+        .sum();
+
+    let mut predictions: Vec<Option<Prediction>> = req.instances.iter().map(|_| None).collect();
+    let mut groups: Vec<(Option<InputKind>, Vec<usize>, Vec<String>)> = Vec::new();
+    for (index, instance) in req.instances.into_iter().enumerate() {
+        let kind = instance.task_type.and_then(TaskType::input_kind);
+        match groups.iter_mut().find(|(group, _, _)| *group == kind) {
+            Some((_, indices, texts)) => {
+                indices.push(index);
+                texts.push(instance.content);
+            }
+            None => groups.push((kind, vec![index], vec![instance.content])),
+        }
+    }
+
+    for (kind, indices, texts) in groups {
+        let request = EmbeddingRequest {
+            input: InputType::Batch(texts),
+            model: Some(model.to_string()),
+            encoding_format: EncodingFormat::Float,
+            preprocess: PreprocessOverrides::default(),
+            input_type: kind,
+            dimensions: req.parameters.output_dimensionality,
+            return_token_details: true,
+            user: None,
+            output: OutputKind::Dense,
+            partial: false,
+            instruction: None,
+            precision: None,
+            task: None,
+            truncate: req.parameters.auto_truncate,
+            late_chunking: None,
+            echo: false,
+            allow_duplicate_ids: false,
+            truncation_side: None,
+            split: false,
+            detect_language: false,
+            dtype: None,
+        };
+        let Json(response) = create_embeddings(State(state.clone()), scheduling.clone(), ApiJson(request)).await?;
+        for (item, index) in response.data.into_iter().zip(indices) {
+            let EmbeddingItem::Embedding(object) = item else {
+                continue;
+            };
+            let EmbeddingData::Float(values) = object.embedding else {
+                continue;
+            };
+            predictions[index] = Some(Prediction {
+                embeddings: PredictionEmbeddings {
+                    values,
+                    statistics: Statistics {
+                        token_count: object.tokens.unwrap_or(0),
+                        truncated: object.truncated.unwrap_or(false),
+                    },
+                },
+            });
+        }
+    }
+
+    Ok(Json(PredictResponse {
+        predictions: predictions.into_iter().flatten().collect(),
+        metadata: PredictMetadata {
+            billable_character_count,
+        },
+    }))
+}
+
+// Google's error body: the HTTP status as `code` plus its canonical name
+#[derive(Debug, Serialize)]
+struct GoogleErrorResponse {
+    error: GoogleError,
+}
+
+#[derive(Debug, Serialize)]
+struct GoogleError {
+    code: u16,
+    message: String,
+    status: &'static str,
+}
+
+/// Rewrite the OpenAI-style error bodies of the Vertex routes into Google's
+/// shape.
+pub(crate) async fn vertex_errors(req: Request, next: Next) -> Response {
+    map_error_body(next.run(req).await, |status, error| GoogleErrorResponse {
+        error: GoogleError {
+            code: status.as_u16(),
+            message: error.message,
+            status: canonical_status(status),
+        },
+    })
+    .await
+}
+
+fn canonical_status(status: StatusCode) -> &'static str {
+    match status {
+        StatusCode::BAD_REQUEST | StatusCode::PAYLOAD_TOO_LARGE | StatusCode::UNSUPPORTED_MEDIA_TYPE => {
+            "INVALID_ARGUMENT"
+        }
+        StatusCode::UNAUTHORIZED => "UNAUTHENTICATED",
+        StatusCode::FORBIDDEN => "PERMISSION_DENIED",
+        StatusCode::NOT_FOUND => "NOT_FOUND",
+        StatusCode::CONFLICT => "ABORTED",
+        StatusCode::TOO_MANY_REQUESTS => "RESOURCE_EXHAUSTED",
+        StatusCode::NOT_IMPLEMENTED => "UNIMPLEMENTED",
+        StatusCode::SERVICE_UNAVAILABLE => "UNAVAILABLE",
+        StatusCode::GATEWAY_TIMEOUT => "DEADLINE_EXCEEDED",
+        _ if status.is_server_error() => "INTERNAL",
+        _ => "UNKNOWN",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use axum::{body::Body, middleware, routing::post, Router};
+    use serde_json::{json, Value};
+    use tower::ServiceExt;
+
+    // The request from Vertex AI's text embeddings API reference, `title`
+    // included, which semembed ignores
+    #[test]
+    fn parses_vertex_requests() {
+        let req: PredictRequest = serde_json::from_value(json!({
+            "instances": [
+                {"task_type": "RETRIEVAL_DOCUMENT", "title": "document title", "content": "I would like embeddings for this text!"},
+                {"task_type": "RETRIEVAL_QUERY", "content": "embeddings for this text"},
+                {"content": "no task type"}
+            ],
+            "parameters": {"autoTruncate": false, "outputDimensionality": 256}
+        }))
+        .unwrap();
+        assert_eq!(req.instances.len(), 3);
+        assert_eq!(req.instances[0].content, "I would like embeddings for this text!");
+        let kinds: Vec<_> = req.instances.iter().map(|instance| instance.task_type.and_then(TaskType::input_kind)).collect();
+        assert_eq!(kinds, [Some(InputKind::Passage), Some(InputKind::Query), None]);
+        assert_eq!(req.parameters.output_dimensionality, Some(256));
+        assert_eq!(req.parameters.auto_truncate, Some(false));
+
+        let req: PredictRequest = serde_json::from_value(json!({"instances": [{"content": "a"}]})).unwrap();
+        assert_eq!(req.parameters.output_dimensionality, None);
+        assert!(serde_json::from_value::<PredictRequest>(json!({"instances": [{"content": "a", "task_type": "SUMMARIZE"}]})).is_err());
+    }
+
+    #[test]
+    fn task_types_select_prefixes() {
+        let kind = |name: &str| serde_json::from_value::<TaskType>(json!(name)).unwrap().input_kind();
+        for query in ["RETRIEVAL_QUERY", "QUESTION_ANSWERING", "FACT_VERIFICATION", "CODE_RETRIEVAL_QUERY"] {
+            assert_eq!(kind(query), Some(InputKind::Query), "{}", query);
+        }
+        assert_eq!(kind("RETRIEVAL_DOCUMENT"), Some(InputKind::Passage));
+        for symmetric in ["SEMANTIC_SIMILARITY", "CLASSIFICATION", "CLUSTERING"] {
+            assert_eq!(kind(symmetric), None, "{}", symmetric);
+        }
+    }
+
+    // The response shape of the same reference
+    #[test]
+    fn responses_take_vertex_shape() {
+        let response = PredictResponse {
+            predictions: vec![Prediction {
+                embeddings: PredictionEmbeddings {
+                    values: Floats(vec![0.25, -0.5], None),
+                    statistics: Statistics {
+                        token_count: 8,
+                        truncated: false,
+                    },
+                },
+            }],
+            metadata: PredictMetadata {
+                billable_character_count: 32,
+            },
+        };
+        assert_eq!(
+            serde_json::to_value(response).unwrap(),
+            json!({
+                "predictions": [
+                    {"embeddings": {"values": [0.25, -0.5], "statistics": {"token_count": 8, "truncated": false}}}
+                ],
+                "metadata": {"billableCharacterCount": 32}
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn errors_take_googles_shape() {
+        let app = Router::new()
+            .route(
+                "/",
+                post(|| async {
+                    Err::<(), _>(
+                        ApiError::new(StatusCode::BAD_REQUEST, "invalid_request_error", "instances must not be empty")
+                            .param("instances"),
+                    )
+                }),
+            )
+            .layer(middleware::from_fn(vertex_errors));
+        let response = app.oneshot(Request::post("/").body(Body::empty()).unwrap()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(
+            body,
+            json!({"error": {"code": 400, "message": "instances must not be empty", "status": "INVALID_ARGUMENT"}})
+        );
+
+        assert_eq!(canonical_status(StatusCode::TOO_MANY_REQUESTS), "RESOURCE_EXHAUSTED");
+        assert_eq!(canonical_status(StatusCode::SERVICE_UNAVAILABLE), "UNAVAILABLE");
+        assert_eq!(canonical_status(StatusCode::BAD_GATEWAY), "INTERNAL");
+    }
+}